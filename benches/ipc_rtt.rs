@@ -0,0 +1,107 @@
+//! Round-trip latency of a measurement-sized message over the unix socket and shared-memory
+//! backends, against a peer thread which echoes it back:
+//!
+//! ```text
+//! cargo bench --features bench --bench ipc_rtt
+//! ```
+
+use portus::ipc::{Blocking, IpcRecv, IpcSend, Nonblocking};
+use portus::serialize::{self, measure};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const ROUND_TRIPS: usize = 100_000;
+
+// Receive into `buf`, retrying while `sk` has nothing for us.
+fn recv_one<S: IpcRecv>(sk: &S, buf: &mut [u8]) -> (usize, S::Addr) {
+    loop {
+        let (len, from) = sk.recv(buf).expect("recv");
+        if len > 0 {
+            return (len, from);
+        }
+    }
+}
+
+// Echo `ROUND_TRIPS` messages, plus a warm-up one, back to whoever sent them.
+fn echo<S: IpcSend + IpcRecv>(sk: S) {
+    let mut buf = [0u8; 1024];
+    for _ in 0..=ROUND_TRIPS {
+        let (len, from) = recv_one(&sk, &mut buf);
+        sk.send(&buf[..len], &from).expect("echo");
+    }
+}
+
+// Send `msg` to `to` and wait for it to come back, `ROUND_TRIPS` times after one warm-up round
+// trip, and report the median and tail round-trip times.
+fn bench<S: IpcSend + IpcRecv>(name: &str, sk: S, to: S::Addr, msg: &[u8]) {
+    let mut buf = [0u8; 1024];
+    let mut rtts = Vec::with_capacity(ROUND_TRIPS);
+    for i in 0..=ROUND_TRIPS {
+        let start = Instant::now();
+        sk.send(msg, &to).expect("send");
+        let (len, _) = recv_one(&sk, &mut buf);
+        let rtt = start.elapsed();
+        assert_eq!(&buf[..len], msg);
+        if i > 0 {
+            rtts.push(rtt);
+        }
+    }
+
+    rtts.sort_unstable();
+    let at = |q: f64| rtts[((rtts.len() - 1) as f64 * q) as usize];
+    let us = |d: Duration| d.as_nanos() as f64 / 1e3;
+    println!(
+        "{:<24} {:>8.2} us p50 {:>8.2} us p99 {:>8.2} us p99.9",
+        name,
+        us(at(0.5)),
+        us(at(0.99)),
+        us(at(0.999)),
+    );
+}
+
+macro_rules! unix_bench {
+    ($name: expr, $dir: expr, $msg: expr, $mode: ident) => {{
+        let rx = $dir.join("bench_rx");
+        let tx = $dir.join("bench_tx");
+        let sk =
+            portus::ipc::unix::Socket::<$mode>::new_with_paths(&rx, $dir).expect("unix socket");
+        let peer =
+            portus::ipc::unix::Socket::<Blocking>::new_with_paths(&tx, $dir).expect("unix socket");
+        let echoer = thread::spawn(move || echo(peer));
+        bench($name, sk, tx.into(), $msg);
+        echoer.join().expect("join echo thread");
+    }};
+}
+
+#[cfg(target_os = "linux")]
+macro_rules! shm_bench {
+    ($name: expr, $dir: expr, $msg: expr, $mode: ident) => {{
+        let path = $dir.join("bench_shm");
+        let sk = portus::ipc::shm::Socket::<$mode>::new(&path).expect("shm socket");
+        let peer = portus::ipc::shm::Socket::<Blocking>::connect(&path).expect("shm socket");
+        let echoer = thread::spawn(move || echo(peer));
+        bench($name, sk, (), $msg);
+        echoer.join().expect("join echo thread");
+        std::fs::remove_file(path).unwrap_or(());
+    }};
+}
+
+fn main() {
+    let msg = serialize::serialize(&measure::Msg::new(1, 7, vec![14480, 1448, 42, 1]))
+        .expect("serialize");
+    let dir = std::env::temp_dir().join(format!("portus-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("bench dir");
+    let dir: &Path = &dir;
+
+    unix_bench!("unix blocking", dir, &msg, Blocking);
+    unix_bench!("unix nonblocking", dir, &msg, Nonblocking);
+
+    #[cfg(target_os = "linux")]
+    {
+        shm_bench!("shm blocking", dir, &msg, Blocking);
+        shm_bench!("shm nonblocking", dir, &msg, Nonblocking);
+    }
+
+    std::fs::remove_dir_all(dir).unwrap_or(());
+}
@@ -0,0 +1,107 @@
+//! Allocations and time per measurement message parsed, as CCP receives them, and parsed lazily
+//! (see `measure::Msg::lazy`):
+//!
+//! ```text
+//! cargo bench --features bench --bench parse
+//! ```
+
+use portus::serialize::{self, measure, measure_batch, Msg};
+use std::cell::Cell;
+use std::time::Instant;
+
+const PARSES: u32 = 1_000_000;
+
+// Counts the allocations each thread makes.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCS.with(|a| a.set(a.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn allocs() -> u64 {
+    ALLOCS.with(Cell::get)
+}
+
+// Parse `buf` `PARSES` times, and report allocations, time, and throughput per parse.
+fn bench(name: &str, buf: &[u8]) {
+    bench_with(name, buf, |buf| {
+        let msg = Msg::from_buf(buf).expect("parse");
+        std::hint::black_box(msg);
+    })
+}
+
+// Like `bench`, parsing with `parse`.
+fn bench_with<F: Fn(&[u8])>(name: &str, buf: &[u8], parse: F) {
+    let before = allocs();
+    let start = Instant::now();
+    for _ in 0..PARSES {
+        parse(std::hint::black_box(buf));
+    }
+    let elapsed = start.elapsed();
+    let ns = elapsed.as_nanos() as f64 / f64::from(PARSES);
+    println!(
+        "{:<24} {:>6.2} allocs/parse {:>8.1} ns/parse {:>8.1} MB/s",
+        name,
+        (allocs() - before) as f64 / f64::from(PARSES),
+        ns,
+        buf.len() as f64 * 1e3 / ns,
+    );
+}
+
+fn measurement(sid: u64, types: Option<Vec<measure::FieldType>>) -> measure::Msg {
+    measure::Msg {
+        sid,
+        program_uid: 7,
+        num_fields: 4,
+        fields: vec![14480, 1448, 42, 1],
+        types,
+        timestamp: None,
+        strings: vec![],
+    }
+}
+
+fn main() {
+    let measure = serialize::serialize(&measurement(1, None)).expect("serialize");
+    bench("measure", &measure);
+
+    let typed = serialize::serialize(&measurement(
+        1,
+        Some(vec![
+            measure::FieldType::Num,
+            measure::FieldType::Num32,
+            measure::FieldType::Int,
+            measure::FieldType::Bool,
+        ]),
+    ))
+    .expect("serialize");
+    bench("measure (typed)", &typed);
+
+    let batch = serialize::serialize(&measure_batch::Msg {
+        measurements: (0..32).map(|sid| measurement(sid, None)).collect(),
+    })
+    .expect("serialize");
+    bench("measure_batch of 32", &batch);
+
+    // an algorithm which reads two fields of a long report
+    let long =
+        serialize::serialize(&measure::Msg::new(1, 7, (0..32).collect())).expect("serialize");
+    bench("measure of 32", &long);
+    bench_with("measure of 32, 2 read", &long, |buf| {
+        let m = measure::Msg::lazy(buf).expect("parse");
+        std::hint::black_box((m.fields.get(0), m.fields.get(1)));
+    });
+}
@@ -0,0 +1,107 @@
+//! Allocations and time per measurement message a `Backend` receives and parses, once its
+//! buffers are warm:
+//!
+//! ```text
+//! cargo bench --features bench --bench recv_allocs
+//! ```
+
+use portus::ipc::{Backend, IpcRecv, IpcSend};
+use portus::serialize::{self, measure, Msg};
+use std::cell::Cell;
+use std::sync::{atomic, Arc};
+use std::time::Instant;
+
+const RECVS: u32 = 1_000_000;
+
+// Counts the allocations each thread makes.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCS.with(|a| a.set(a.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn allocs() -> u64 {
+    ALLOCS.with(Cell::get)
+}
+
+// Receives the same message every time.
+struct RepeatIpc(Vec<u8>);
+
+impl IpcSend for RepeatIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("repeat")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> portus::Result<()> {
+        Ok(())
+    }
+}
+
+impl IpcRecv for RepeatIpc {
+    fn recv(&self, msg: &mut [u8]) -> portus::Result<(usize, Self::Addr)> {
+        msg[..self.0.len()].copy_from_slice(&self.0);
+        Ok((self.0.len(), ()))
+    }
+
+    fn close(&mut self) -> portus::Result<()> {
+        Ok(())
+    }
+}
+
+// Receive `msg` `RECVS` times after one warm-up receive, and report allocations and time per
+// message.
+fn bench(name: &str, msg: Vec<u8>) {
+    let mut buf = [0u8; 1024];
+    let mut b = Backend::new(
+        RepeatIpc(msg),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    let mut recv = || match b.try_next() {
+        Ok(Some((m @ Msg::Ms(_), ()))) => {
+            std::hint::black_box(m);
+        }
+        other => panic!("expected a measurement, got {:?}", other),
+    };
+
+    recv();
+    let before = allocs();
+    let start = Instant::now();
+    for _ in 0..RECVS {
+        recv();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>6.2} allocs/msg {:>8.1} ns/msg",
+        name,
+        (allocs() - before) as f64 / f64::from(RECVS),
+        elapsed.as_nanos() as f64 / f64::from(RECVS),
+    );
+}
+
+fn main() {
+    let m = measure::Msg::new(1, 7, vec![14480, 1448, 42, 1]);
+    bench("measure", serialize::serialize(&m).expect("serialize"));
+
+    let long = measure::Msg::new(1, 7, (0..32).collect());
+    bench(
+        "measure of 32",
+        serialize::serialize(&long).expect("serialize"),
+    );
+}
@@ -0,0 +1,173 @@
+//! Allocations and time per control message a flow sends, once its buffers are warm:
+//!
+//! ```text
+//! cargo bench --features bench --bench send_allocs
+//! ```
+
+use portus::ipc::{BackendBuilder, IpcRecv, IpcSend};
+use portus::lang::{Reg, Scope, Type};
+use portus::serialize;
+use portus::{CongAlg, Datapath, DatapathInfo, DatapathTrait, Flow, Report};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+const SENDS: u32 = 100_000;
+
+// Counts the allocations each thread makes.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: Cell<u64> = const { Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCS.with(|a| a.set(a.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+fn allocs() -> u64 {
+    ALLOCS.with(Cell::get)
+}
+
+// Delivers a create message, then nothing, and drops what it is sent.
+struct SinkIpc(Mutex<Option<Vec<u8>>>);
+
+impl IpcSend for SinkIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("sink")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> portus::Result<()> {
+        Ok(())
+    }
+}
+
+impl IpcRecv for SinkIpc {
+    fn recv(&self, msg: &mut [u8]) -> portus::Result<(usize, Self::Addr)> {
+        match self.0.lock().unwrap().take() {
+            Some(buf) => {
+                msg[..buf.len()].copy_from_slice(&buf);
+                Ok((buf.len(), ()))
+            }
+            None => Ok((0, ())),
+        }
+    }
+
+    fn close(&mut self) -> portus::Result<()> {
+        Ok(())
+    }
+}
+
+type Slot = Arc<Mutex<Option<(Datapath<SinkIpc>, Scope)>>>;
+
+// Hands its one flow's datapath out to the benchmark.
+struct BenchAlg(Slot);
+
+struct NopFlow;
+
+impl Flow for NopFlow {
+    fn on_report(&mut self, _sock_id: u64, _m: Report) {}
+}
+
+impl CongAlg<SinkIpc> for BenchAlg {
+    type Flow = NopFlow;
+
+    fn name() -> &'static str {
+        "bench"
+    }
+
+    fn datapath_programs(&self) -> HashMap<&'static str, String> {
+        let mut programs = HashMap::new();
+        programs.insert(
+            "bench",
+            String::from(
+                "(def (Report (volatile acked 0)) (target 0))
+                (when true
+                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                    (report)
+                )",
+            ),
+        );
+        programs
+    }
+
+    fn new_flow(&self, mut control: Datapath<SinkIpc>, _info: DatapathInfo) -> NopFlow {
+        let sc = control.set_program("bench", None).expect("set program");
+        *self.0.lock().unwrap() = Some((control, sc));
+        NopFlow
+    }
+}
+
+// Run `f` `SENDS` times after one warm-up call, and report allocations and time per call.
+fn bench(name: &str, mut f: impl FnMut()) {
+    f();
+    let before = allocs();
+    let start = Instant::now();
+    for _ in 0..SENDS {
+        f();
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:<24} {:>6.2} allocs/send {:>8.1} ns/send",
+        name,
+        (allocs() - before) as f64 / f64::from(SENDS),
+        elapsed.as_nanos() as f64 / f64::from(SENDS),
+    );
+}
+
+fn main() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let slot = Slot::default();
+    let sock = SinkIpc(Mutex::new(Some(create)));
+    portus::RunBuilder::new(BackendBuilder { sock })
+        .default_alg(BenchAlg(slot.clone()))
+        .pumped()
+        .run(|pump| {
+            pump.dispatch_ready()?;
+            let (dp, sc) = slot.lock().unwrap().take().expect("flow created");
+            let update = [("target", 2), ("Cwnd", 14480)];
+
+            let msg = serialize::update_field::Msg {
+                sid: 1,
+                num_fields: 1,
+                fields: vec![(Reg::Implicit(4, Type::Num(None)), 14480)],
+                widths: None,
+            };
+            bench("serialize", || {
+                serialize::serialize(&msg).expect("serialize");
+            });
+            let mut buf = Vec::new();
+            bench("serialize_into", || {
+                buf.clear();
+                serialize::serialize_into(&msg, &mut buf).expect("serialize");
+            });
+            bench("update_field", || {
+                dp.update_field(&sc, &update).expect("update field");
+            });
+            Ok(())
+        })
+        .expect("run");
+}
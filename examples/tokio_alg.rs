@@ -0,0 +1,123 @@
+//! Run a trivial congestion control algorithm under `#[tokio::main]`, against a fake datapath
+//! which reports a single measurement.
+//!
+//! `cargo run --example tokio_alg --features tokio`
+
+use portus::ipc::{tokio::Socket, BackendBuilder, Ipc};
+use portus::lang::Scope;
+use portus::serialize::{self, create, measure, ready};
+use portus::{CongAlg, Datapath, DatapathInfo, DatapathTrait, Flow, Report, RunBuilder};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{atomic, Arc};
+
+const CCP_ADDR: &str = "portus-tokio-example";
+const DP_ADDR: &str = "portus-tokio-example-dp";
+const CHANGEPROG: u8 = 4;
+
+struct AckCounter {
+    running: Arc<atomic::AtomicBool>,
+}
+
+impl<I: Ipc> CongAlg<I> for AckCounter {
+    type Flow = AckCounterFlow;
+
+    fn name() -> &'static str {
+        "ack-counter"
+    }
+
+    fn datapath_programs(&self) -> HashMap<&'static str, String> {
+        let mut h = HashMap::default();
+        h.insert(
+            "AckCounter",
+            "
+            (def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )"
+            .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: Datapath<I>, info: DatapathInfo) -> Self::Flow {
+        println!("new flow {}", info.sock_id);
+        AckCounterFlow {
+            sc: control.set_program("AckCounter", None).unwrap(),
+            running: self.running.clone(),
+        }
+    }
+}
+
+struct AckCounterFlow {
+    sc: Scope,
+    running: Arc<atomic::AtomicBool>,
+}
+
+impl Flow for AckCounterFlow {
+    fn on_report(&mut self, sock_id: u64, m: Report) {
+        let acked = m.get_field("Report.acked", &self.sc).unwrap();
+        println!("flow {} acked {} bytes", sock_id, acked);
+
+        // one report is enough for this example
+        self.running.store(false, atomic::Ordering::SeqCst);
+    }
+}
+
+// Pretend to be a datapath with one flow, which reports once.
+async fn fake_datapath() -> portus::Result<()> {
+    let sk = Socket::new(DP_ADDR)?;
+    let ccp = Path::new(CCP_ADDR);
+    sk.send_msg(&serialize::serialize(&ready::Msg { id: 0 })?, ccp)
+        .await?;
+
+    let cr = create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    };
+    sk.send_msg(&serialize::serialize(&cr)?, ccp).await?;
+
+    // CCP installs its programs, then tells us which one the new flow should run. Reports must
+    // carry that program's uid, which is the first field after the header.
+    let mut buf = [0u8; 1024];
+    let program_uid = loop {
+        let (len, _) = sk.recv_msg(&mut buf).await?;
+        if len >= 12 && buf[0] == CHANGEPROG {
+            break u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        }
+    };
+
+    let ms = measure::Msg {
+        sid: 1,
+        program_uid,
+        num_fields: 1,
+        fields: vec![1448],
+        types: None,
+        timestamp: None,
+        strings: vec![],
+    };
+    sk.send_msg(&serialize::serialize(&ms)?, ccp).await?;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> portus::Result<()> {
+    let running = Arc::new(atomic::AtomicBool::new(true));
+    let sk = Socket::new(CCP_ADDR)?;
+    let ccp = RunBuilder::new(BackendBuilder { sock: sk })
+        .default_alg(AckCounter {
+            running: running.clone(),
+        })
+        .with_stop_handle(running);
+
+    let dp = tokio::spawn(fake_datapath());
+    ccp.run_async().await?;
+    dp.await.expect("fake datapath panicked")
+}
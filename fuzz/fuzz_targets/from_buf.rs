@@ -0,0 +1,42 @@
+//! Parse whatever the datapath might send, and serialize what parses back, as CCP would to log
+//! or forward it.
+//!
+//! ```text
+//! cargo +nightly fuzz run from_buf
+//! ```
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use portus::serialize::{serialize, Msg};
+
+fuzz_target!(|data: &[u8]| {
+    let mut off = 0;
+    while off < data.len() {
+        let (msg, len) = match Msg::from_buf(&data[off..]) {
+            Ok(parsed) => parsed,
+            Err(_) => return,
+        };
+        let _ = msg.to_string();
+        let _ = match msg {
+            Msg::Cr(m) => serialize(&m),
+            Msg::Ms(m) => serialize(&m),
+            Msg::MsBatch(m) => serialize(&m),
+            Msg::Ins(m) => serialize(&m),
+            Msg::Uf(m) => serialize(&m),
+            Msg::Cp(m) => serialize(&m),
+            Msg::Rdy(m) => serialize(&m),
+            Msg::Hb(m) => serialize(&m),
+            Msg::Caps(m) => serialize(&m),
+            Msg::Ver(m) => serialize(&m),
+            Msg::Err(m) => serialize(&m),
+            Msg::InsAck(m) => serialize(&m),
+            Msg::Un(m) => serialize(&m),
+            Msg::Td(m) => serialize(&m),
+            Msg::Fin(m) => serialize(&m),
+            Msg::Other(_) => Ok(vec![]),
+        };
+
+        assert!(len > 0, "parsed an empty message");
+        off += len;
+    }
+});
@@ -0,0 +1,46 @@
+//! Parse any body as each type of message. `Msg::from_buf` only hands a message to the parser
+//! for its type, so this also gives each parser bodies which `from_buf` would not.
+//!
+//! The first byte picks the type; the rest is a message, whose type is replaced with one no
+//! message has so that `from_buf` leaves its body alone.
+//!
+//! ```text
+//! cargo +nightly fuzz run from_raw_msg
+//! ```
+
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use portus::serialize::*;
+
+const UNKNOWN: u8 = 254;
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+
+    let mut buf = data[1..].to_vec();
+    buf[0] = UNKNOWN;
+    let raw = match Msg::from_buf(&buf) {
+        Ok((Msg::Other(raw), _)) => raw,
+        _ => return,
+    };
+
+    let _ = match data[0] % 15 {
+        0 => create::Msg::from_raw_msg(raw).map(drop),
+        1 => measure::Msg::from_raw_msg(raw).map(drop),
+        2 => install::Msg::from_raw_msg(raw).map(drop),
+        3 => update_field::Msg::from_raw_msg(raw).map(drop),
+        4 => changeprog::Msg::from_raw_msg(raw).map(drop),
+        5 => ready::Msg::from_raw_msg(raw).map(drop),
+        6 => heartbeat::Msg::from_raw_msg(raw).map(drop),
+        7 => capabilities::Msg::from_raw_msg(raw).map(drop),
+        8 => version::Msg::from_raw_msg(raw).map(drop),
+        9 => error::Msg::from_raw_msg(raw).map(drop),
+        10 => install_ack::Msg::from_raw_msg(raw).map(drop),
+        11 => uninstall::Msg::from_raw_msg(raw).map(drop),
+        12 => teardown::Msg::from_raw_msg(raw).map(drop),
+        13 => measure_batch::Msg::from_raw_msg(raw).map(drop),
+        _ => fin::Msg::from_raw_msg(raw).map(drop),
+    };
+});
@@ -13,7 +13,7 @@ pub struct PyFlow<'py> {
 }
 
 impl<'py> Flow for PyFlow<'py> {
-    fn on_report(&mut self, sock_id: u32, m: Report) {
+    fn on_report(&mut self, sock_id: u64, m: Report) {
         let py = self.py;
 
         tracing::debug!(?sock_id, "Got report");
@@ -133,6 +133,8 @@ impl<'py, T: Ipc> CongAlg<T> for PyCongAlg<'py> {
                 src_port: info.src_port,
                 dst_ip: info.dst_ip,
                 dst_port: info.dst_port,
+                peer: info.peer,
+                requested_alg: info.requested_alg,
             },
         )
         .unwrap_or_else(|e| {
@@ -33,7 +33,7 @@ use cong_alg::*;
 #[pyclass(weakref, dict)]
 pub struct DatapathInfo {
     #[pyo3(get)]
-    pub sock_id: u32,
+    pub sock_id: u64,
     #[pyo3(get)]
     pub init_cwnd: u32,
     #[pyo3(get)]
@@ -46,6 +46,10 @@ pub struct DatapathInfo {
     pub dst_ip: u32,
     #[pyo3(get)]
     pub dst_port: u32,
+    #[pyo3(get)]
+    pub peer: String,
+    #[pyo3(get)]
+    pub requested_alg: Option<String>,
 }
 
 #[pyclass(weakref, dict)]
@@ -113,7 +117,7 @@ fn get_fields(list: &PyList) -> Vec<(&str, u32)> {
 struct PyDatapath {
     backend: Box<dyn DatapathTrait>,
     sc: Option<Rc<Scope>>,
-    sock_id: u32,
+    sock_id: u64,
 }
 
 #[pymethods]
@@ -130,7 +134,7 @@ impl PyDatapath {
             }
         };
         match self.backend.update_field(sc, &[(reg_name.as_str(), val)]) {
-            Ok(()) => Ok(()),
+            Ok(_) => Ok(()),
             Err(e) => raise!(PyException, format!("Failed to update field, err: {:?}", e)),
         }
     }
@@ -150,7 +154,7 @@ impl PyDatapath {
         let ret = self.backend.update_field(sc, &get_fields(fields)[..]);
 
         match ret {
-            Ok(()) => Ok(()),
+            Ok(_) => Ok(()),
             Err(e) => raise!(
                 PyException,
                 format!("Failed to update fields, err: {:?}", e)
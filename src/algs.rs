@@ -72,7 +72,7 @@ pub fn ipc_valid(v: String) -> std::result::Result<(), String> {
 ///     }
 /// }
 /// impl Flow for MyCongestionControlAlgorithm {
-///     fn on_report(&mut self, sock_id: u32, m: Report) {
+///     fn on_report(&mut self, sock_id: u64, m: Report) {
 ///         println!("minrtt: {:?}", m.get_field("Report.minrtt", &self.0).unwrap());
 ///     }
 /// }
@@ -26,6 +26,7 @@ fn main() {
         num_events: bin.events.len() as u32,
         num_instrs: bin.instrs.len() as u32,
         instrs: bin,
+        names: None,
     };
 
     let buf = serialize::serialize(&msg).unwrap();
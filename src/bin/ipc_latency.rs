@@ -1,5 +1,5 @@
 use clap::Arg;
-use portus::ipc::{Backend, BackendSender, Blocking, Ipc, Nonblocking};
+use portus::ipc::{Backend, BackendSender, Blocking, Ipc, IpcRecv, IpcSend, Nonblocking};
 use std::convert::TryInto;
 use std::sync::{atomic, Arc};
 use std::thread;
@@ -14,8 +14,12 @@ struct TimeMsg(time::OffsetDateTime);
 
 use std::io::prelude::*;
 impl portus::serialize::AsRawMsg for TimeMsg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
-        (0xff, portus::serialize::HDR_LENGTH + 8 + 4, 0)
+    fn typ() -> u8 {
+        0xff
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (0xff, portus::serialize::HDR_LENGTH + 16, 0)
     }
 
     fn get_u32s<W: Write>(&self, _: &mut W) -> portus::Result<()> {
@@ -45,7 +49,11 @@ struct NlTimeMsg {
     kern_st: time::OffsetDateTime,
 }
 impl portus::serialize::AsRawMsg for NlTimeMsg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        0xff - 1
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (0xff - 1, portus::serialize::HDR_LENGTH + 16 + 8, 0)
     }
 
@@ -273,7 +281,7 @@ macro_rules! unix_bench {
                     .expect("unix ipc initialization");
                 ready_rx.recv().expect("sync");
                 tx.send(bench(
-                    unix.sender(std::path::PathBuf::from("/tmp/ccp/bench_tx")),
+                    unix.sender(std::path::PathBuf::from("/tmp/ccp/bench_tx").into()),
                     unix,
                     iter,
                 ))
@@ -301,12 +309,140 @@ macro_rules! unix_bench {
 unix_bench!(unix_blocking, Blocking);
 unix_bench!(unix_nonblocking, Nonblocking);
 
+// How long handling each message takes in the throughput benchmark, as a slow algorithm might.
+const TPUT_WORK: std::time::Duration = std::time::Duration::from_micros(5);
+
+// Sustained throughput in messages per second: one thread sends `iter` messages as fast as it
+// can, while the receiver spends `TPUT_WORK` on each, reading either inline or on a receive
+// thread.
+fn unix_throughput(iter: u32, receive_thread: bool) -> f64 {
+    use portus::ipc::{queue::Overflow, unix};
+
+    let (ready_tx, ready_rx) = mpsc::channel::<bool>();
+    let sender = thread::spawn(move || {
+        let sk = unix::Socket::<Blocking>::new("bench_tput_tx").expect("sk init");
+        let msg = portus::serialize::serialize(&TimeMsg(time::OffsetDateTime::now_utc()))
+            .expect("serialize");
+        let to = std::path::PathBuf::from("/tmp/ccp/bench_tput_rx").into();
+        ready_rx.recv().expect("sync");
+        for _ in 0..iter {
+            // unix datagram sends wait for room, so none are lost
+            sk.send(&msg[..], &to).expect("send");
+        }
+    });
+
+    let mut receive_buf = [0u8; 1024];
+    let sk = unix::Socket::<Blocking>::new("bench_tput_rx").expect("sk init");
+    let mut b = Backend::new(
+        sk,
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut receive_buf[..],
+    );
+    if receive_thread {
+        b.spawn_receiver(1024, Overflow::Block)
+            .expect("spawn receive thread");
+    }
+
+    ready_tx.send(true).expect("sync");
+    let start = std::time::Instant::now();
+    for _ in 0..iter {
+        b.next().expect("receive");
+        let handled = std::time::Instant::now();
+        while handled.elapsed() < TPUT_WORK {}
+    }
+
+    let elapsed = start.elapsed();
+    sender.join().expect("join sender thread");
+    f64::from(iter) / elapsed.as_secs_f64()
+}
+
+// Counts the receive calls made on a socket, each one system call for unix datagram sockets.
+struct CountRecvs<S>(S, Arc<atomic::AtomicUsize>);
+
+impl<S: IpcSend> IpcSend for CountRecvs<S> {
+    type Addr = S::Addr;
+
+    fn name() -> String {
+        S::name()
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> portus::Result<()> {
+        self.0.send(msg, to)
+    }
+}
+
+impl<S: IpcRecv> IpcRecv for CountRecvs<S> {
+    fn recv(&self, msg: &mut [u8]) -> portus::Result<(usize, Self::Addr)> {
+        self.1.fetch_add(1, atomic::Ordering::SeqCst);
+        self.0.recv(msg)
+    }
+
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<portus::ipc::Received<Self::Addr>>,
+    ) -> portus::Result<()> {
+        self.1.fetch_add(1, atomic::Ordering::SeqCst);
+        self.0.recv_many(arena, slot_len, got)
+    }
+
+    fn close(&mut self) -> portus::Result<()> {
+        self.0.close()
+    }
+}
+
+// Receive `iter` messages, sent as fast as possible, reading up to `batch` at a time. Returns
+// the messages, how many receive calls it took, and the rate in messages per second.
+fn unix_recv_batch(iter: u32, batch: usize) -> (Vec<Vec<u8>>, usize, f64) {
+    use portus::ipc::unix;
+
+    let (ready_tx, ready_rx) = mpsc::channel::<bool>();
+    let sender = thread::spawn(move || {
+        let sk = unix::Socket::<Blocking>::new("bench_batch_tx").expect("sk init");
+        let to = std::path::PathBuf::from("/tmp/ccp/bench_batch_rx").into();
+        ready_rx.recv().expect("sync");
+        for i in 0..iter {
+            let at = time::OffsetDateTime::from_unix_timestamp_nanos(i128::from(i));
+            let msg = portus::serialize::serialize(&TimeMsg(at)).expect("serialize");
+            // unix datagram sends wait for room, so none are lost
+            sk.send(&msg[..], &to).expect("send");
+        }
+    });
+
+    let mut receive_buf = [0u8; 4096];
+    let sk = unix::Socket::<Blocking>::new("bench_batch_rx").expect("sk init");
+    let recvs = Arc::new(atomic::AtomicUsize::new(0));
+    let mut b = Backend::new(
+        CountRecvs(sk, recvs.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut receive_buf[..],
+    );
+    b.set_recv_batch(batch, batch * 64).expect("set batch");
+
+    ready_tx.send(true).expect("sync");
+    let start = std::time::Instant::now();
+    let mut msgs = Vec::with_capacity(iter as usize);
+    while msgs.len() < iter as usize {
+        if let Some((msg, _)) = b.try_recv().expect("receive") {
+            msgs.push(msg.into_vec());
+        }
+    }
+
+    let elapsed = start.elapsed();
+    sender.join().expect("join sender thread");
+    let recvs = recvs.load(atomic::Ordering::SeqCst);
+    (msgs, recvs, f64::from(iter) / elapsed.as_secs_f64())
+}
+
 arg_enum! {
     #[derive(PartialEq, Debug)]
     pub enum IpcType {
         Nl,
         Unix,
         Kp,
+        UnixTput,
+        UnixBatch,
     }
 }
 
@@ -381,6 +517,31 @@ fn main() {
         }
     }
 
+    if imps.contains(&IpcType::UnixTput) {
+        // many more messages than round trips, to measure a sustained rate
+        let msgs = trials * 1000;
+        println!(
+            "unix-tput inline {:.0} msgs/s",
+            unix_throughput(msgs, false)
+        );
+        println!("unix-tput thread {:.0} msgs/s", unix_throughput(msgs, true));
+    }
+
+    if imps.contains(&IpcType::UnixBatch) {
+        let msgs = trials * 1000;
+        let (single, single_recvs, single_rate) = unix_recv_batch(msgs, 1);
+        let (batched, batched_recvs, batched_rate) = unix_recv_batch(msgs, 32);
+        println!(
+            "unix-batch single {} recvs {:.0} msgs/s",
+            single_recvs, single_rate
+        );
+        println!(
+            "unix-batch batch32 {} recvs {:.0} msgs/s",
+            batched_recvs, batched_rate
+        );
+        assert!(single == batched, "batched receive changed the messages");
+    }
+
     if imps.contains(&IpcType::Nl) {
         nl_exp(trials);
     }
@@ -3,7 +3,7 @@ use std::fmt;
 /// CCP custom `Result` type, using `Error` as the `Err` type.
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 /// CCP custom error type.
 pub struct Error(pub String);
 
@@ -67,3 +67,108 @@ impl std::fmt::Display for FieldNotFoundError {
         write!(f, "the requested field was not found in this scope")
     }
 }
+/// The IPC backend found that the datapath no longer exists, rather than failing for some other
+/// reason. Check for it with `err == Error::from(DatapathGoneError)`.
+#[derive(Debug, Clone)]
+pub struct DatapathGoneError;
+impl std::error::Error for DatapathGoneError {
+    fn description(&self) -> &str {
+        "the datapath has gone away (e.g., its kernel module was unloaded)"
+    }
+}
+impl std::fmt::Display for DatapathGoneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "the datapath has gone away (e.g., its kernel module was unloaded)"
+        )
+    }
+}
+/// A send would have blocked, and holding the message for later would have taken the `Backend`'s
+/// pending sends over their byte budget (see `Backend::set_pending_budget`). Check for it with
+/// `err == Error::from(SendQueueFullError)`.
+#[derive(Debug, Clone)]
+pub struct SendQueueFullError;
+impl std::error::Error for SendQueueFullError {
+    fn description(&self) -> &str {
+        "the pending send queue is full"
+    }
+}
+impl std::fmt::Display for SendQueueFullError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the pending send queue is full")
+    }
+}
+/// A message from the datapath could not be parsed, so the `Backend` skipped it (see
+/// `Backend::on_parse_error`). Check for it with `err == Error::from(MalformedMsgError)`.
+#[derive(Debug, Clone)]
+pub struct MalformedMsgError;
+impl std::error::Error for MalformedMsgError {
+    fn description(&self) -> &str {
+        "a message from the datapath could not be parsed"
+    }
+}
+impl std::fmt::Display for MalformedMsgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a message from the datapath could not be parsed")
+    }
+}
+/// A send went over the `Backend`'s send rate limit, and the limit's policy is to reject such
+/// sends (see `Backend::set_send_rate_limit`). Check for it with
+/// `err == Error::from(RateLimitedError)`.
+#[derive(Debug, Clone)]
+pub struct RateLimitedError;
+impl std::error::Error for RateLimitedError {
+    fn description(&self) -> &str {
+        "the send rate limit was exceeded"
+    }
+}
+impl std::fmt::Display for RateLimitedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the send rate limit was exceeded")
+    }
+}
+/// A message failed its checksum, or arrived without one when portus was built with the
+/// `checksum` feature (see `serialize::checksum`). Check for it with
+/// `err == Error::from(CorruptMsgError)`.
+#[derive(Debug, Clone)]
+pub struct CorruptMsgError;
+impl std::error::Error for CorruptMsgError {
+    fn description(&self) -> &str {
+        "a message failed its checksum"
+    }
+}
+impl std::fmt::Display for CorruptMsgError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a message failed its checksum")
+    }
+}
+/// A message was parsed as one of a type it does not have (see `serialize::deserialize`). Check
+/// for it with `err == Error::from(WrongMsgTypeError)`.
+#[derive(Debug, Clone)]
+pub struct WrongMsgTypeError;
+impl std::error::Error for WrongMsgTypeError {
+    fn description(&self) -> &str {
+        "the message is not of the requested type"
+    }
+}
+impl std::fmt::Display for WrongMsgTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the message is not of the requested type")
+    }
+}
+/// A report field was asked for as a type it does not have, in the program's `Scope` or as the
+/// datapath sent it (see `Report::get_field_i64`). Check for it with
+/// `err == Error::from(FieldTypeError)`.
+#[derive(Debug, Clone)]
+pub struct FieldTypeError;
+impl std::error::Error for FieldTypeError {
+    fn description(&self) -> &str {
+        "the requested field does not have the requested type"
+    }
+}
+impl std::fmt::Display for FieldTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the requested field does not have the requested type")
+    }
+}
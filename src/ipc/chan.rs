@@ -1,3 +1,16 @@
+//! Sockets which pass messages over in-process channels, e.g. to test an algorithm against a
+//! fake datapath without creating real sockets.
+//!
+//! ```
+//! use portus::ipc::{chan, Blocking, IpcRecv, IpcSend};
+//!
+//! let (ccp, datapath) = chan::Socket::<Blocking>::pair();
+//! datapath.send(b"hello", &()).unwrap();
+//! let mut buf = [0u8; 16];
+//! let (len, _) = ccp.recv(&mut buf).unwrap();
+//! assert_eq!(&buf[..len], b"hello");
+//! ```
+
 use crossbeam::channel;
 
 use super::Error;
@@ -7,6 +20,7 @@ use std::marker::PhantomData;
 pub struct Socket<T> {
     send: Option<channel::Sender<Vec<u8>>>,
     recv: Option<channel::Receiver<Vec<u8>>>,
+    recv_timeout: std::time::Duration,
     _phantom: PhantomData<T>,
 }
 
@@ -15,10 +29,18 @@ impl<T> Socket<T> {
         Socket {
             send: Some(to_ccp),
             recv: Some(from_ccp),
+            recv_timeout: std::time::Duration::from_secs(1),
             _phantom: PhantomData::<T>,
         }
     }
 
+    /// Two sockets connected to each other: each receives what the other sends.
+    pub fn pair() -> (Self, Self) {
+        let (s1, r1) = channel::unbounded();
+        let (s2, r2) = channel::unbounded();
+        (Socket::new(s1, r2), Socket::new(s2, r1))
+    }
+
     fn __name() -> String {
         String::from("channel")
     }
@@ -40,7 +62,7 @@ impl<T> Socket<T> {
 }
 
 use super::Blocking;
-impl super::Ipc for Socket<Blocking> {
+impl super::IpcSend for Socket<Blocking> {
     type Addr = ();
 
     fn name() -> String {
@@ -50,13 +72,19 @@ impl super::Ipc for Socket<Blocking> {
     fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
         self.__send(msg)
     }
+}
 
+impl super::IpcRecv for Socket<Blocking> {
     fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
         let r = self
             .recv
             .as_ref()
             .ok_or_else(|| Error(String::from("Receive channel side missing")))?;
-        let buf = r.recv_timeout(std::time::Duration::from_secs(1))?;
+        let buf = match r.recv_timeout(self.recv_timeout) {
+            Ok(buf) => buf,
+            Err(channel::RecvTimeoutError::Timeout) => return Ok((0, ())),
+            Err(e) => return Err(Error::from(e)),
+        };
         msg[..buf.len()].copy_from_slice(&buf);
         Ok((buf.len(), ()))
     }
@@ -64,10 +92,15 @@ impl super::Ipc for Socket<Blocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
 }
 
 use super::Nonblocking;
-impl super::Ipc for Socket<Nonblocking> {
+impl super::IpcSend for Socket<Nonblocking> {
     type Addr = ();
 
     fn name() -> String {
@@ -77,13 +110,19 @@ impl super::Ipc for Socket<Nonblocking> {
     fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
         self.__send(msg)
     }
+}
 
+impl super::IpcRecv for Socket<Nonblocking> {
     fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
         let r = self
             .recv
             .as_ref()
             .ok_or_else(|| Error(String::from("Receive channel side missing")))?;
-        let buf = r.try_recv()?;
+        let buf = match r.try_recv() {
+            Ok(buf) => buf,
+            Err(channel::TryRecvError::Empty) => return Ok((0, ())),
+            Err(e) => return Err(Error::from(e)),
+        };
         msg[..buf.len()].copy_from_slice(&buf);
         Ok((buf.len(), ()))
     }
@@ -91,12 +130,17 @@ impl super::Ipc for Socket<Nonblocking> {
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::Socket;
-    use crate::ipc::{Blocking, Ipc};
+    use crate::ipc::{Blocking, IpcRecv, IpcSend, Nonblocking};
     use crossbeam::channel;
     use std::thread;
 
@@ -119,4 +163,19 @@ mod tests {
         ipc.send(&buf[..l], &()).unwrap();
         rx.recv().unwrap();
     }
+
+    #[test]
+    fn pair() {
+        let (a, b) = Socket::<Nonblocking>::pair();
+        let mut buf = [0u8; 8];
+        assert_eq!(b.recv(&mut buf).unwrap().0, 0);
+
+        a.send(&[1, 2], &()).unwrap();
+        b.send(&[3], &()).unwrap();
+        let (l, _) = b.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..l], &[1, 2]);
+        let (l, _) = a.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..l], &[3]);
+        assert_eq!(a.recv(&mut buf).unwrap().0, 0);
+    }
 }
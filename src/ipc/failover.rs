@@ -0,0 +1,357 @@
+//! Fail over from a datapath which stops responding to a standby, e.g. when running redundant
+//! datapath agents.
+//!
+//! A failover `Socket` wraps an ordered list of sockets, one per datapath agent, and talks to one
+//! of them at a time, starting with the first. Once sends to it fail `max_send_errors` times in a
+//! row, or it leaves `max_missed_heartbeats` heartbeats in a row unanswered, the socket switches
+//! to the next one in the list, wrapping around after the last.
+//!
+//! Flows carry on across the switch: the execution loop installs the datapath programs on the
+//! new datapath, and calls [`Flow::on_failover`](../../trait.Flow.html#method.on_failover) on
+//! each flow so that it can set its program again. The standby must know the flows by the same
+//! addresses and socket ids as the datapath it replaces.
+//!
+//! ```rust,no_run
+//! use portus::ipc::failover::{FailoverPolicy, Socket};
+//! use portus::ipc::unix::{self, SocketKind};
+//! use portus::ipc::{BackendBuilder, Blocking};
+//!
+//! let sock = Socket::new(
+//!     vec![
+//!         unix::Socket::<Blocking>::new_with_kind("portus-primary", SocketKind::SeqPacket)
+//!             .unwrap(),
+//!         unix::Socket::<Blocking>::new_with_kind("portus-standby", SocketKind::SeqPacket)
+//!             .unwrap(),
+//!     ],
+//!     FailoverPolicy::default(),
+//! )
+//! .unwrap();
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::{Error, Ipc, IpcRecv, IpcSend, Received, Result};
+use crate::serialize::{
+    checksum::CHECKSUMMED, heartbeat::HEARTBEAT, sequence::SEQUENCED, u16_from_u8s,
+};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// When a failover `Socket` gives up on the datapath it is talking to.
+///
+/// Heartbeats are only sent if the `Backend` has them turned on (see
+/// [`Backend::set_heartbeat`](../struct.Backend.html#method.set_heartbeat)). Any message from the
+/// datapath counts as an answer. `max_missed_heartbeats` should be fewer than the `Backend`'s
+/// `max_missed`, or the `Backend` gives up on the datapath first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FailoverPolicy {
+    pub max_send_errors: u32,
+    pub max_missed_heartbeats: u32,
+}
+
+impl Default for FailoverPolicy {
+    fn default() -> Self {
+        FailoverPolicy {
+            max_send_errors: 3,
+            max_missed_heartbeats: 3,
+        }
+    }
+}
+
+pub struct Socket<T> {
+    socks: Vec<T>,
+    active: AtomicUsize,
+    policy: FailoverPolicy,
+    // failed sends in a row to the active socket, and heartbeats sent to it since it last sent
+    // a message
+    send_errors: AtomicU32,
+    missed_heartbeats: AtomicU32,
+    failovers: AtomicU64,
+}
+
+fn is_heartbeat(msg: &[u8]) -> bool {
+    msg.len() >= 2 && u16_from_u8s(&msg[0..2]) & !(CHECKSUMMED | SEQUENCED) == u16::from(HEARTBEAT)
+}
+
+impl<T: Ipc> Socket<T> {
+    /// Talk to the datapath behind the first of `socks`, and fail over to the others in order.
+    pub fn new(socks: Vec<T>, policy: FailoverPolicy) -> Result<Self> {
+        if socks.is_empty() {
+            return Err(Error(String::from(
+                "a failover socket needs at least one socket",
+            )));
+        }
+
+        Ok(Socket {
+            socks,
+            active: AtomicUsize::new(0),
+            policy,
+            send_errors: AtomicU32::new(0),
+            missed_heartbeats: AtomicU32::new(0),
+            failovers: AtomicU64::new(0),
+        })
+    }
+
+    /// The index of the socket currently in use.
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::SeqCst)
+    }
+
+    // Switch from socket `from` to the next one, unless another thread already has.
+    fn fail_over(&self, from: usize, why: &str) {
+        let to = (from + 1) % self.socks.len();
+        if self
+            .active
+            .compare_exchange(from, to, Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return;
+        }
+
+        warn!(from, to, why, "datapath stopped responding, failing over");
+        self.send_errors.store(0, Ordering::SeqCst);
+        self.missed_heartbeats.store(0, Ordering::SeqCst);
+        self.failovers.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl<T: Ipc> IpcSend for Socket<T> {
+    type Addr = T::Addr;
+
+    fn name() -> String {
+        format!("failover({})", T::name())
+    }
+
+    /// A send which fails is not retried on the next socket, even if it makes this socket fail
+    /// over.
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        let heartbeat = is_heartbeat(msg);
+        if heartbeat
+            && self.missed_heartbeats.load(Ordering::SeqCst) >= self.policy.max_missed_heartbeats
+        {
+            self.fail_over(self.active(), "heartbeats went unanswered");
+        }
+
+        let active = self.active();
+        if heartbeat {
+            self.missed_heartbeats.fetch_add(1, Ordering::SeqCst);
+        }
+
+        match self.socks[active].send(msg, to) {
+            Ok(()) => {
+                self.send_errors.store(0, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(e) => {
+                let errors = self.send_errors.fetch_add(1, Ordering::SeqCst) + 1;
+                if errors >= self.policy.max_send_errors {
+                    self.fail_over(active, "sends failed");
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.socks
+            .iter_mut()
+            .try_for_each(|s| s.set_send_timeout(timeout))
+    }
+}
+
+impl<T: Ipc> IpcRecv for Socket<T> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        let (len, from, _) = self.recv_timestamped(msg)?;
+        Ok((len, from))
+    }
+
+    /// Receives from the socket currently in use.
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        let active = self.active();
+        let (len, from, at) = self.socks[active].recv_timestamped(msg)?;
+        if len > 0 && self.active() == active {
+            self.missed_heartbeats.store(0, Ordering::SeqCst);
+        }
+
+        Ok((len, from, at))
+    }
+
+    /// Receives from the socket currently in use.
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<Received<Self::Addr>>,
+    ) -> Result<()> {
+        let active = self.active();
+        let before = got.len();
+        self.socks[active].recv_many(arena, slot_len, got)?;
+        if got.len() > before && self.active() == active {
+            self.missed_heartbeats.store(0, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    fn is_stream() -> bool {
+        T::is_stream()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        // close them all, even if one fails
+        self.socks
+            .iter_mut()
+            .map(IpcRecv::close)
+            .fold(Ok(()), Result::and)
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.socks
+            .iter_mut()
+            .try_for_each(|s| s.set_recv_timeout(timeout))
+    }
+
+    fn gaps(&self) -> u64 {
+        self.socks.iter().map(IpcRecv::gaps).sum()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.socks.iter().map(IpcRecv::reconnects).sum()
+    }
+
+    fn failovers(&self) -> u64 {
+        self.failovers.load(Ordering::SeqCst)
+    }
+
+    /// The fd of the socket currently in use, so it changes when the socket fails over.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.socks[self.active()].raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FailoverPolicy, Socket};
+    use crate::ipc::{IpcRecv, IpcSend};
+    use crate::serialize;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct MockIpc {
+        failing: AtomicBool,
+        sent: Mutex<Vec<Vec<u8>>>,
+        pending: Mutex<Vec<Vec<u8>>>,
+    }
+
+    impl IpcSend for MockIpc {
+        type Addr = ();
+
+        fn name() -> String {
+            String::from("mock")
+        }
+
+        fn send(&self, msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(crate::Error::from(crate::DatapathGoneError));
+            }
+
+            self.sent.lock().unwrap().push(msg.to_vec());
+            Ok(())
+        }
+    }
+
+    impl IpcRecv for MockIpc {
+        fn recv(&self, msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+            match self.pending.lock().unwrap().pop() {
+                Some(buf) => {
+                    msg[..buf.len()].copy_from_slice(&buf);
+                    Ok((buf.len(), ()))
+                }
+                None => Ok((0, ())),
+            }
+        }
+
+        fn close(&mut self) -> crate::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn heartbeat(seq: u32) -> Vec<u8> {
+        serialize::serialize(&serialize::heartbeat::Msg { seq }).expect("serialize heartbeat")
+    }
+
+    #[test]
+    fn send_errors() {
+        let sk = Socket::new(
+            vec![MockIpc::default(), MockIpc::default()],
+            FailoverPolicy {
+                max_send_errors: 2,
+                max_missed_heartbeats: 10,
+            },
+        )
+        .expect("failover socket");
+        sk.send(b"one", &()).expect("send to primary");
+        sk.socks[0].failing.store(true, Ordering::SeqCst);
+        assert!(sk.send(b"two", &()).is_err());
+        assert_eq!(sk.active(), 0);
+        assert!(sk.send(b"three", &()).is_err());
+        assert_eq!(sk.active(), 1);
+        assert_eq!(sk.failovers(), 1);
+
+        sk.send(b"four", &()).expect("send to standby");
+        assert_eq!(*sk.socks[0].sent.lock().unwrap(), vec![b"one".to_vec()]);
+        assert_eq!(*sk.socks[1].sent.lock().unwrap(), vec![b"four".to_vec()]);
+    }
+
+    #[test]
+    fn missed_heartbeats() {
+        let sk = Socket::new(
+            vec![MockIpc::default(), MockIpc::default()],
+            FailoverPolicy {
+                max_send_errors: 10,
+                max_missed_heartbeats: 2,
+            },
+        )
+        .expect("failover socket");
+        let mut buf = [0u8; 64];
+
+        // an answer resets the count
+        sk.send(&heartbeat(0), &()).expect("send heartbeat");
+        sk.send(&heartbeat(1), &()).expect("send heartbeat");
+        sk.socks[0].pending.lock().unwrap().push(heartbeat(1));
+        assert!(sk.recv(&mut buf).expect("recv").0 > 0);
+        sk.send(&heartbeat(2), &()).expect("send heartbeat");
+        sk.send(&heartbeat(3), &()).expect("send heartbeat");
+        assert_eq!(sk.active(), 0);
+
+        // other messages don't count
+        sk.send(b"not a heartbeat", &()).expect("send");
+        assert_eq!(sk.active(), 0);
+
+        sk.send(&heartbeat(4), &()).expect("send heartbeat");
+        assert_eq!(sk.active(), 1);
+        assert_eq!(sk.socks[0].sent.lock().unwrap().len(), 5);
+        assert_eq!(*sk.socks[1].sent.lock().unwrap(), vec![heartbeat(4)]);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let sk = Socket::new(
+            vec![MockIpc::default(), MockIpc::default()],
+            FailoverPolicy {
+                max_send_errors: 1,
+                max_missed_heartbeats: 10,
+            },
+        )
+        .expect("failover socket");
+        sk.socks[0].failing.store(true, Ordering::SeqCst);
+        sk.socks[1].failing.store(true, Ordering::SeqCst);
+        assert!(sk.send(b"one", &()).is_err());
+        assert!(sk.send(b"two", &()).is_err());
+        assert_eq!(sk.active(), 0);
+        assert_eq!(sk.failovers(), 2);
+    }
+}
@@ -3,15 +3,40 @@ use std::fs::OpenOptions;
 use std::marker::PhantomData;
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::AsRawFd;
+use std::path::Path;
 
 use super::Error;
 use super::Result;
+use crate::DatapathGoneError;
+use nix::errno::Errno;
+use nix::poll::PollFlags;
+
+/// The character device created by the ccp-kernel module.
+pub const DEFAULT_DEV_PATH: &str = "/dev/ccpkp";
 
 pub struct Socket<T> {
     fd: File,
+    // how long recv polls for a message, in milliseconds; 0 for nonblocking sockets
+    recv_timeout_ms: libc::c_int,
     _phantom: PhantomData<T>,
 }
 
+// Errors which mean that the device itself is gone, i.e. the kernel module was unloaded.
+fn is_gone(e: Errno) -> bool {
+    matches!(
+        e,
+        Errno::ENODEV | Errno::ENXIO | Errno::EIO | Errno::ESHUTDOWN | Errno::EPIPE
+    )
+}
+
+fn map_err(e: Errno) -> Error {
+    if is_gone(e) {
+        Error::from(DatapathGoneError)
+    } else {
+        Error::from(e)
+    }
+}
+
 impl<T> Socket<T> {
     fn mk_opts() -> std::fs::OpenOptions {
         let mut options = OpenOptions::new();
@@ -19,16 +44,27 @@ impl<T> Socket<T> {
         options
     }
 
-    fn open(options: std::fs::OpenOptions) -> Result<Self> {
-        let file = options.open("/dev/ccpkp")?;
+    fn open<P: AsRef<Path>>(
+        path: P,
+        options: std::fs::OpenOptions,
+        recv_timeout_ms: libc::c_int,
+    ) -> Result<Self> {
+        let file = options.open(path.as_ref()).map_err(|e| {
+            Error(format!(
+                "could not open {:?} ({}); is the ccp kernel module loaded?",
+                path.as_ref(),
+                e
+            ))
+        })?;
         Ok(Socket {
             fd: file,
+            recv_timeout_ms,
             _phantom: PhantomData,
         })
     }
 }
 
-impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
     type Addr = ();
 
     fn name() -> String {
@@ -38,37 +74,96 @@ impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
     fn send(&self, buf: &[u8], _to: &Self::Addr) -> Result<()> {
         nix::unistd::write(self.fd.as_raw_fd(), buf)
             .map(|_| ())
-            .map_err(Error::from)
+            .map_err(map_err)
     }
+}
 
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
     fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
-        let pollfd = nix::poll::PollFd::new(self.fd.as_raw_fd(), nix::poll::PollFlags::POLLIN);
-        let ok = nix::poll::poll(&mut [pollfd], 1000)?;
+        let mut fds = [nix::poll::PollFd::new(
+            self.fd.as_raw_fd(),
+            PollFlags::POLLIN,
+        )];
+        let ok = match nix::poll::poll(&mut fds, self.recv_timeout_ms) {
+            Ok(ok) => ok,
+            Err(Errno::EINTR) => return Ok((0, ())),
+            Err(e) => return Err(Error::from(e)),
+        };
         if ok < 0 {
             return Err(Error::from(std::io::Error::from_raw_os_error(ok)));
+        } else if ok == 0 {
+            return Ok((0, ()));
         }
 
-        let len = nix::unistd::read(self.fd.as_raw_fd(), msg).map_err(Error::from)?;
-        Ok((len, ()))
+        // read out anything still buffered before reporting the hangup
+        let revents = fds[0].revents().unwrap_or_else(PollFlags::empty);
+        if !revents.contains(PollFlags::POLLIN)
+            && revents.intersects(PollFlags::POLLHUP | PollFlags::POLLERR | PollFlags::POLLNVAL)
+        {
+            return Err(Error::from(DatapathGoneError));
+        }
+
+        match nix::unistd::read(self.fd.as_raw_fd(), msg) {
+            Ok(len) => Ok((len, ())),
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => Ok((0, ())),
+            Err(e) => Err(map_err(e)),
+        }
+    }
+
+    /// A read from the device can return part of a message.
+    fn is_stream() -> bool {
+        true
     }
 
     fn close(&mut self) -> Result<()> {
         Ok(())
     }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        // nonblocking sockets never wait
+        if self.recv_timeout_ms > 0 {
+            self.recv_timeout_ms =
+                timeout.as_millis().clamp(1, libc::c_int::MAX as u128) as libc::c_int;
+        }
+
+        Ok(())
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd.as_raw_fd()
+    }
 }
 
 use super::Blocking;
 impl Socket<Blocking> {
+    /// Open the character device at `DEFAULT_DEV_PATH`.
     pub fn new() -> Result<Self> {
-        Self::open(Self::mk_opts())
+        Self::with_path(DEFAULT_DEV_PATH)
+    }
+
+    /// Open the character device at `path`.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path, Self::mk_opts(), 1000)
     }
 }
 
 use super::Nonblocking;
 impl Socket<Nonblocking> {
+    /// Open the character device at `DEFAULT_DEV_PATH`.
     pub fn new() -> Result<Self> {
+        Self::with_path(DEFAULT_DEV_PATH)
+    }
+
+    /// Open the character device at `path`.
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Result<Self> {
         let mut options = Self::mk_opts();
         options.custom_flags(libc::O_NONBLOCK);
-        Self::open(options)
+        Self::open(path, options, 0)
     }
 }
@@ -3,42 +3,368 @@
 
 use super::Error;
 use super::Result;
-use std::rc::{Rc, Weak};
-use std::sync::{atomic, Arc};
-use tracing::{info, trace};
+use std::collections::{HashMap, HashSet};
+#[cfg(unix)]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{atomic, Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, warn};
 
 /// Thread-channel implementation
 pub mod chan;
+/// Fail over from an unresponsive datapath to a standby
+pub mod failover;
 #[cfg(all(target_os = "linux"))]
 /// Character device implementation
 pub mod kp;
+#[cfg(unix)]
+/// Receive from several sockets at once
+pub mod multi;
 #[cfg(all(target_os = "linux"))]
 /// Netlink socket implementation
 pub mod netlink;
+#[cfg(all(unix, feature = "mio"))]
+/// Wait for messages on several nonblocking backends from one thread
+pub mod poll;
+/// Receive into a bounded queue on a separate thread
+pub mod queue;
+/// Record the messages a socket sends and receives to a file
+pub mod record;
+/// Play back a recorded session
+pub mod replay;
+#[cfg(target_os = "linux")]
+/// Shared-memory ring buffer implementation, for low-latency datapaths on the same host
+pub mod shm;
+#[cfg(unix)]
+/// TCP socket implementation, for datapaths on a remote host
+pub mod tcp;
+#[cfg(all(unix, feature = "tokio"))]
+/// Unix domain socket implementation for use within a tokio runtime
+pub mod tokio;
+#[cfg(unix)]
+/// UDP socket implementation, with sequence numbers to detect lost or reordered datagrams
+pub mod udp;
+#[cfg(unix)]
 /// Unix domain socket implementation
 pub mod unix;
+#[cfg(target_os = "linux")]
+/// vsock implementation, for datapaths inside a VM
+pub mod vsock;
+#[cfg(windows)]
+/// Named pipe implementation, for datapaths on Windows
+pub mod winpipe;
 
-/// IPC mechanisms must implement this trait.
+/// IPC mechanisms implement `IpcSend` and `IpcRecv`, and so this trait.
 ///
 /// This API enables both connection-oriented (send/recv) and connectionless (sendto/recvfrom)
 /// sockets, but currently only unix sockets support connectionless sockets. When using unix
 /// sockets, you must provide a valid `Addr` to `send()` and you will also receive a valid
 /// `Addr` as a return value from `recv`. When using connection-oriented ipc mechanisms, these
 /// values are ignored and should just be the nil value `()`.
-pub trait Ipc: 'static + Send {
+pub trait Ipc: IpcSend + IpcRecv {}
+
+impl<T: IpcSend + IpcRecv> Ipc for T {}
+
+/// The sending half of an IPC mechanism.
+///
+/// `send` takes `&self`, so if the socket is also `Sync`, one thread can send while another is
+/// blocked in `recv`.
+pub trait IpcSend: 'static + Send {
     type Addr: Clone + Default + std::cmp::Eq + std::hash::Hash + std::fmt::Debug;
     /// Returns the name of this IPC mechanism (e.g. "netlink" for Linux netlink sockets)
     fn name() -> String;
-    /// Blocking send
+    /// Blocking send.
+    ///
+    /// If the send waited longer than the send timeout (or, for nonblocking sockets, would have
+    /// had to wait at all), returns `Error("send timed out")`.
     fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()>;
+    /// Send each of `msgs` to `to`, in order, as separate messages but with as few system calls
+    /// as the socket allows.
+    ///
+    /// Returns how many of `msgs` were sent. If that is not all of them, the `Err` is why the
+    /// first message not sent failed, and none after it were sent either.
+    ///
+    /// The default implementation calls `send` for each message.
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        send_each(self, msgs, to)
+    }
+    /// Set how long a blocking `send` waits for the receiver to make room for a message before
+    /// failing. By default, sends wait as long as it takes. Nonblocking sockets never wait, so
+    /// this does not affect them.
+    ///
+    /// The default implementation returns an error.
+    fn set_send_timeout(&mut self, _timeout: std::time::Duration) -> Result<()> {
+        Err(Error(format!(
+            "{} sockets do not support setting a send timeout",
+            Self::name()
+        )))
+    }
+}
+
+/// The receiving half of an IPC mechanism, which receives from the addresses its `IpcSend`
+/// half sends to.
+pub trait IpcRecv: IpcSend {
     /// Blocking listen.
     ///
     /// Returns how many bytes were read, and (if using unix sockets) the address of the sender.
+    /// If no message arrived before the socket's timeout (or, for nonblocking sockets, none was
+    /// waiting), returns 0 bytes read. An `Err` means the socket can no longer be used, e.g.
+    /// because the peer disconnected.
     ///
     /// Important: should not allocate!
     fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)>;
+    /// Like `recv`, and also return when the message arrived at the socket, if the socket can
+    /// tell, e.g. from a kernel receive timestamp.
+    ///
+    /// The default implementation calls `recv`, and returns no time.
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        let (len, from) = self.recv(msg)?;
+        Ok((len, from, None))
+    }
+    /// Receive up to `arena.len() / slot_len` messages, with as few system calls as the socket
+    /// allows, the `i`th into the `i`th `slot_len` bytes of `arena`. Adds each message received
+    /// to `got`, in order, saying where in `arena` it is. Like `recv`, this waits for the first
+    /// message, but not for any after it, and receives none if the wait times out.
+    ///
+    /// The default implementation calls `recv_timestamped` once, into the first slot.
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<Received<Self::Addr>>,
+    ) -> Result<()> {
+        recv_one(self, arena, slot_len, got)
+    }
+    /// Whether `recv` reads from a byte stream, and so can return part of a message, or the end
+    /// of one and the start of the next. The `Backend` then puts messages back together using the
+    /// length in their header.
+    ///
+    /// The default implementation returns `false`: each `recv` returns whole messages.
+    fn is_stream() -> bool {
+        false
+    }
     /// Close the underlying sockets
     fn close(&mut self) -> Result<()>;
+    /// Set how long a blocking `recv` waits for a message before returning 0 bytes read. The
+    /// default is 1 second. Nonblocking sockets never wait, so this does not affect them.
+    ///
+    /// The default implementation returns an error.
+    fn set_recv_timeout(&mut self, _timeout: std::time::Duration) -> Result<()> {
+        Err(Error(format!(
+            "{} sockets do not support setting a receive timeout",
+            Self::name()
+        )))
+    }
+    /// The number of incoming messages this socket has detected as lost or out of order.
+    ///
+    /// Only meaningful for unreliable transports; the default implementation returns 0.
+    fn gaps(&self) -> u64 {
+        0
+    }
+    /// The number of times this socket has lost the datapath and found it again, e.g. because
+    /// the datapath process restarted. The datapath loses its state when this happens, so
+    /// existing flows are stale.
+    ///
+    /// The default implementation returns 0, for sockets that do not reconnect.
+    fn reconnects(&self) -> u64 {
+        0
+    }
+    /// The number of times this socket has given up on the datapath it was using and switched to
+    /// a standby (see [`failover`](./failover/index.html)). Unlike a reconnect, the flows carry on
+    /// with the standby, once the datapath programs are installed there.
+    ///
+    /// The default implementation returns 0, for sockets that do not fail over.
+    fn failovers(&self) -> u64 {
+        0
+    }
+    /// The file descriptor which becomes readable when a message arrives, to wait for messages
+    /// in another event loop (see `Backend::poll_fd`).
+    ///
+    /// The default implementation returns `None`, for sockets without one.
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+/// `IpcSend::send_many` by calling `send` for each message.
+pub(crate) fn send_each<S: IpcSend + ?Sized>(
+    sk: &S,
+    msgs: &[&[u8]],
+    to: &S::Addr,
+) -> (usize, Result<()>) {
+    for (i, msg) in msgs.iter().enumerate() {
+        if let Err(e) = sk.send(msg, to) {
+            return (i, Err(e));
+        }
+    }
+
+    (msgs.len(), Ok(()))
+}
+
+/// A message `IpcRecv::recv_many` received.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Received<A> {
+    /// Where the message starts in the arena.
+    pub start: usize,
+    pub len: usize,
+    pub from: A,
+    pub at: Option<SystemTime>,
+}
+
+/// `IpcRecv::recv_many` by calling `recv_timestamped` once.
+pub(crate) fn recv_one<S: IpcRecv + ?Sized>(
+    sk: &S,
+    arena: &mut [u8],
+    slot_len: usize,
+    got: &mut Vec<Received<S::Addr>>,
+) -> Result<()> {
+    let (len, from, at) = sk.recv_timestamped(&mut arena[..slot_len])?;
+    if len > 0 {
+        got.push(Received {
+            start: 0,
+            len,
+            from,
+            at,
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+/// Whether a failed read just means no message was available yet (the read timed out, would have
+/// blocked, or was interrupted), rather than that the socket is broken.
+pub(crate) fn is_transient(e: &std::io::Error) -> bool {
+    use std::io::ErrorKind::*;
+    matches!(e.kind(), WouldBlock | TimedOut | Interrupted)
+}
+
+#[cfg(target_os = "linux")]
+/// The receive time in a `SO_TIMESTAMPNS` control message.
+pub(crate) fn kernel_timestamp(c: nix::sys::socket::ControlMessageOwned) -> Option<SystemTime> {
+    match c {
+        nix::sys::socket::ControlMessageOwned::ScmTimestampns(ts) => {
+            Some(SystemTime::UNIX_EPOCH + Duration::new(ts.tv_sec() as u64, ts.tv_nsec() as u32))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Receive into each `slot_len` bytes of `arena` with one `recvmmsg` call, which waits for the
+/// first message (unless `flags` has `MSG_DONTWAIT`) but not for the rest. Calls `each` with
+/// the index of each slot filled, in order, and what `recvmsg` would have returned for it, given
+/// room for `cmsg_space` bytes of control messages.
+pub(crate) fn recv_mmsg<F>(
+    fd: RawFd,
+    arena: &mut [u8],
+    slot_len: usize,
+    cmsg_space: usize,
+    flags: nix::sys::socket::MsgFlags,
+    mut each: F,
+) -> nix::Result<()>
+where
+    F: FnMut(usize, nix::sys::socket::RecvMsg<'_>),
+{
+    use nix::sys::socket::{recvmmsg, MsgFlags, RecvMmsgData};
+    use nix::sys::uio::IoVec;
+    // nix does not know MSG_WAITFORONE
+    let flags = flags | unsafe { MsgFlags::from_bits_unchecked(libc::MSG_WAITFORONE) };
+    let mut cmsgs: Vec<Vec<u8>> = arena
+        .chunks_exact(slot_len)
+        .map(|_| Vec::with_capacity(cmsg_space))
+        .collect();
+    let mut data: Vec<_> = arena
+        .chunks_exact_mut(slot_len)
+        .zip(cmsgs.iter_mut())
+        .map(|(slot, cmsg)| RecvMmsgData {
+            iov: [IoVec::from_mut_slice(slot)],
+            cmsg_buffer: Some(cmsg),
+        })
+        .collect();
+    for (i, r) in recvmmsg(fd, &mut data, flags, None)?
+        .into_iter()
+        .enumerate()
+    {
+        each(i, r);
+    }
+
+    Ok(())
+}
+
+/// The error a send returns when it ran out of time to wait for the receiver.
+pub(crate) fn send_timed_out() -> Error {
+    Error(String::from("send timed out"))
+}
+
+/// The error for a failed send: a send which timed out or would have blocked is reported as
+/// `send_timed_out()`.
+pub(crate) fn send_error(e: std::io::Error) -> Error {
+    use std::io::ErrorKind::*;
+    match e.kind() {
+        WouldBlock | TimedOut => send_timed_out(),
+        _ => Error::from(e),
+    }
+}
+
+/// The size of the receive buffer `run()` and `spawn()` give the `Backend`, unless set with
+/// [`RunBuilder::with_receive_buf_size`](../struct.RunBuilder.html#method.with_receive_buf_size).
+pub const DEFAULT_RECV_BUF_SIZE: usize = 1024;
+
+// How long a `Backend` waiting for messages polls an idle nonblocking socket before reading again.
+const IDLE_POLL: Duration = Duration::from_millis(10);
+
+// How often a `Backend` logs messages it could not parse; it counts the rest in between.
+const PARSE_ERROR_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// How many buffers from dropped `RecvBuf`s a `Backend` keeps for `try_recv` to reuse.
+const RECV_POOL_SIZE: usize = 16;
+
+/// How soon a message goes out relative to others waiting in a `Backend`'s send queue (see
+/// [`Backend::spawn_sender`](./struct.Backend.html#method.spawn_sender)). Without a send
+/// queue, every message is sent right away.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Priority {
+    /// Sent before any `Normal` message still waiting, e.g. to cut the window promptly even
+    /// while the socket is congested.
+    Urgent,
+    Normal,
+}
+
+/// How fast a `Backend`'s senders may send (see
+/// [`Backend::set_send_rate_limit`](./struct.Backend.html#method.set_send_rate_limit)): on
+/// average `msgs_per_sec` messages a second, in bursts of up to `burst` messages after a pause.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SendRateLimit {
+    pub msgs_per_sec: u32,
+    pub burst: u32,
+    pub policy: RateLimitPolicy,
+    /// Let messages sent with `Priority::Urgent` through regardless of the limit, without using
+    /// it up. Flows' control messages are `Priority::Urgent` unless set otherwise with
+    /// [`Datapath::set_priority`](../struct.Datapath.html#method.set_priority).
+    pub exempt_urgent: bool,
+}
+
+/// What a `BackendSender` does with a message sent faster than its `SendRateLimit` allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitPolicy {
+    /// Wait until the message is within the limit, then send it.
+    Delay,
+    /// Fail the send with `RateLimitedError`.
+    Reject,
+}
+
+/// What happened to a message a `BackendSender` accepted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SendStatus {
+    /// The socket took the message.
+    Sent,
+    /// The message is waiting to be sent: in the `Backend`'s send queue (see
+    /// [`Backend::spawn_sender`](./struct.Backend.html#method.spawn_sender)), or held because
+    /// the socket would have blocked (see
+    /// [`Backend::set_pending_budget`](./struct.Backend.html#method.set_pending_budget)).
+    Queued,
 }
 
 /// Marker type specifying that the IPC socket should make blocking calls to the underlying socket
@@ -62,40 +388,545 @@ impl<T: Ipc> BackendBuilder<T> {
     }
 }
 
+/// Counts of the messages a `Backend` and its `BackendSender`s have handled, for debugging.
+///
+/// Cloning takes a snapshot of the counts.
+#[derive(Debug, Default)]
+pub struct BackendStats {
+    sent: atomic::AtomicU64,
+    received: atomic::AtomicU64,
+    send_errors: atomic::AtomicU64,
+    recv_errors: atomic::AtomicU64,
+    unknown_msgs: atomic::AtomicU64,
+    gaps: atomic::AtomicU64,
+    malformed: atomic::AtomicU64,
+    rate_limited: atomic::AtomicU64,
+    corrupt: atomic::AtomicU64,
+}
+
+impl BackendStats {
+    /// Messages sent successfully.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Messages received and parsed, including those of unknown type.
+    pub fn received(&self) -> u64 {
+        self.received.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Sends that failed.
+    pub fn send_errors(&self) -> u64 {
+        self.send_errors.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Receives that failed, either because the socket failed or because the message was too
+    /// long for the receive buffer.
+    pub fn recv_errors(&self) -> u64 {
+        self.recv_errors.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Messages received with a type portus does not know, which the runtime drops.
+    pub fn unknown_msgs(&self) -> u64 {
+        self.unknown_msgs.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Incoming messages the socket detected as lost or out of order (see `IpcRecv::gaps`), e.g.
+    /// reports the kernel dropped because CCP did not keep up.
+    pub fn gaps(&self) -> u64 {
+        self.gaps.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Messages received which could not be parsed, e.g. because they were cut short or their
+    /// body did not match their type, and which the `Backend` skipped.
+    pub fn malformed(&self) -> u64 {
+        self.malformed.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Sends rejected for going over the send rate limit (see `Backend::set_send_rate_limit`).
+    /// These do not count as `send_errors`.
+    pub fn rate_limited(&self) -> u64 {
+        self.rate_limited.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Messages received which failed their checksum (see `serialize::checksum`), and which the
+    /// `Backend` skipped. These do not count as `malformed`.
+    pub fn corrupt(&self) -> u64 {
+        self.corrupt.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Set all the counts back to 0.
+    pub fn reset(&self) {
+        for c in self.counters() {
+            c.store(0, atomic::Ordering::Relaxed);
+        }
+    }
+
+    fn counters(&self) -> [&atomic::AtomicU64; 9] {
+        [
+            &self.sent,
+            &self.received,
+            &self.send_errors,
+            &self.recv_errors,
+            &self.unknown_msgs,
+            &self.gaps,
+            &self.malformed,
+            &self.rate_limited,
+            &self.corrupt,
+        ]
+    }
+
+    fn incr(c: &atomic::AtomicU64) {
+        c.fetch_add(1, atomic::Ordering::Relaxed);
+    }
+}
+
+impl Clone for BackendStats {
+    fn clone(&self) -> Self {
+        let [sent, received, send_errors, recv_errors, unknown_msgs, gaps, malformed, rate_limited, corrupt] =
+            self.counters()
+                .map(|c| atomic::AtomicU64::new(c.load(atomic::Ordering::Relaxed)));
+        BackendStats {
+            sent,
+            received,
+            send_errors,
+            recv_errors,
+            unknown_msgs,
+            gaps,
+            malformed,
+            rate_limited,
+            corrupt,
+        }
+    }
+}
+
+type BufPool = Arc<Mutex<Vec<Vec<u8>>>>;
+
+/// A copy of a message's bytes, from `Backend::try_recv`.
+///
+/// When dropped, its buffer goes back to the `Backend` to copy a later message into, so that
+/// reading messages this way stops allocating once the `Backend` has a few buffers to reuse. To
+/// keep the bytes, take them with `into_vec()`, which does not copy them again.
+#[derive(Debug)]
+pub struct RecvBuf {
+    buf: Vec<u8>,
+    pool: Weak<Mutex<Vec<Vec<u8>>>>,
+}
+
+impl RecvBuf {
+    pub fn into_vec(mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl std::ops::Deref for RecvBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf
+    }
+}
+
+impl Drop for RecvBuf {
+    fn drop(&mut self) {
+        let pool = match Weak::upgrade(&self.pool) {
+            Some(p) if self.buf.capacity() > 0 => p,
+            _ => return,
+        };
+
+        let mut pool = pool.lock().unwrap_or_else(|e| e.into_inner());
+        if pool.len() < RECV_POOL_SIZE {
+            pool.push(std::mem::take(&mut self.buf));
+        }
+    }
+}
+
 /// A send-only handle to the underlying IPC socket.
-pub struct BackendSender<T: Ipc>(Weak<T>, T::Addr);
+///
+/// This is `Send` and `Sync` if the socket is, so it can be used from other threads than the one
+/// receiving on the `Backend`.
+pub struct BackendSender<T: IpcSend> {
+    sock: Weak<T>,
+    dest: T::Addr,
+    stats: Arc<BackendStats>,
+    outbox: Weak<queue::Outbox<T::Addr>>,
+    pending: Arc<queue::Pending<T::Addr>>,
+    limiter: Arc<queue::Limiter>,
+    // the shortest message to compress, or 0 not to compress any
+    compress_threshold: Arc<atomic::AtomicUsize>,
+    seqs: Arc<SendSeqs<T::Addr>>,
+}
 
-impl<T: Ipc> BackendSender<T> {
-    /// Blocking send.
+// The next sequence number to stamp on the messages to each peer, if the `Backend`'s senders
+// stamp them (see `Backend::set_sequence_numbers`).
+struct SendSeqs<A>(Mutex<Option<HashMap<A, u16>>>);
+
+impl<A> Default for SendSeqs<A> {
+    fn default() -> Self {
+        SendSeqs(Mutex::new(None))
+    }
+}
+
+impl<A: Clone + Eq + std::hash::Hash> SendSeqs<A> {
+    fn set(&self, on: bool) {
+        let mut seqs = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        match (on, seqs.is_some()) {
+            (true, false) => *seqs = Some(HashMap::new()),
+            (false, true) => *seqs = None,
+            _ => (),
+        }
+    }
+
+    fn on(&self) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+    }
+
+    // `msg` with the next sequence number for `to`, if stamping is on and `msg` can have one.
+    fn stamp(&self, msg: &[u8], to: &A) -> Option<Vec<u8>> {
+        let mut seqs = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let next = seqs.as_mut()?.entry(to.clone()).or_insert(0);
+        let stamped = crate::serialize::sequence::stamp(msg, *next)?;
+        *next = next.wrapping_add(1);
+        Some(stamped)
+    }
+}
+
+impl<T: IpcSend> BackendSender<T> {
+    /// Blocking send, at `Priority::Normal`.
     pub fn send_msg(&self, msg: &[u8]) -> Result<()> {
-        let s = Weak::upgrade(&self.0)
-            .ok_or_else(|| Error(String::from("Send on closed IPC socket!")))?;
-        s.send(msg, &self.1).map_err(Error::from)
+        self.send_msg_with_priority(msg, Priority::Normal)
+            .map(|_| ())
+    }
+
+    /// Blocking send. If the `Backend` has a send queue (see `Backend::spawn_sender`), this
+    /// only waits for room in the queue for `priority`, and the send queue's thread counts the
+    /// message in `BackendStats` once it is sent.
+    ///
+    /// If the `Backend` holds sends that would block (see `Backend::set_pending_budget`), such a
+    /// message is held rather than lost, and counted once the `Backend` sends it. Either way the
+    /// message is `SendStatus::Queued`.
+    ///
+    /// If the `Backend` has a send rate limit (see `Backend::set_send_rate_limit`), a message
+    /// over the limit first waits for it, or fails with `RateLimitedError`.
+    pub fn send_msg_with_priority(&self, msg: &[u8], priority: Priority) -> Result<SendStatus> {
+        if let Err(e) = self.limiter.take(priority) {
+            BackendStats::incr(&self.stats.rate_limited);
+            return Err(e);
+        }
+
+        let stamped = self.seqs.stamp(msg, &self.dest);
+        let msg = stamped.as_deref().unwrap_or(msg);
+
+        #[cfg(feature = "compression")]
+        let compressed = self.compress(msg);
+        #[cfg(feature = "compression")]
+        let msg = compressed.as_deref().unwrap_or(msg);
+
+        if let Some(outbox) = Weak::upgrade(&self.outbox) {
+            return outbox
+                .push(msg, self.dest.clone(), priority)
+                .map(|_| SendStatus::Queued);
+        }
+
+        let res = Weak::upgrade(&self.sock)
+            .ok_or_else(|| Error(String::from("Send on closed IPC socket!")))
+            .and_then(|s| self.pending.send(&*s, msg, &self.dest, &self.stats));
+        match res {
+            Ok(SendStatus::Sent) => BackendStats::incr(&self.stats.sent),
+            Ok(SendStatus::Queued) => (),
+            Err(_) => BackendStats::incr(&self.stats.send_errors),
+        }
+
+        res
+    }
+
+    /// Send `msgs` together at `Priority::Normal`, with as few system calls as the socket allows
+    /// (see `IpcSend::send_many`).
+    ///
+    /// Returns how many were sent. If that is not all of them, the `Err` is why the first message
+    /// not sent failed, and none after it were sent either.
+    pub fn send_msgs(&self, msgs: &[&[u8]]) -> (usize, Result<()>) {
+        self.send_msgs_with_priority(msgs, Priority::Normal)
     }
+
+    /// Like `send_msgs`, but if the `Backend` has a send queue, queue each message at
+    /// `priority` instead, as `send_msg_with_priority` does. Messages held because the socket
+    /// would have blocked count as sent.
+    pub fn send_msgs_with_priority(
+        &self,
+        msgs: &[&[u8]],
+        priority: Priority,
+    ) -> (usize, Result<()>) {
+        if Weak::strong_count(&self.outbox) > 0
+            || self.pending.holding()
+            || self.limiter.limiting()
+            || self.compress_threshold.load(atomic::Ordering::Relaxed) > 0
+            || self.seqs.on()
+        {
+            for (i, msg) in msgs.iter().enumerate() {
+                if let Err(e) = self.send_msg_with_priority(msg, priority) {
+                    return (i, Err(e));
+                }
+            }
+
+            return (msgs.len(), Ok(()));
+        }
+
+        let (sent, res) = match Weak::upgrade(&self.sock) {
+            Some(s) => s.send_many(msgs, &self.dest),
+            None => (0, Err(Error(String::from("Send on closed IPC socket!")))),
+        };
+        self.stats
+            .sent
+            .fetch_add(sent as u64, atomic::Ordering::Relaxed);
+        if res.is_err() {
+            BackendStats::incr(&self.stats.send_errors);
+        }
+
+        (sent, res)
+    }
+
+    #[cfg(feature = "compression")]
+    fn compress(&self, msg: &[u8]) -> Option<Vec<u8>> {
+        match self.compress_threshold.load(atomic::Ordering::Relaxed) {
+            0 => None,
+            shortest if msg.len() < shortest => None,
+            _ => crate::serialize::compress::compress(msg),
+        }
+    }
+
     pub fn clone_with_dest(&self, to: T::Addr) -> Self {
-        BackendSender(self.0.clone(), to)
+        BackendSender {
+            dest: to,
+            ..self.clone()
+        }
     }
 }
 
-impl<T: Ipc> Clone for BackendSender<T> {
+impl<T: IpcSend> Clone for BackendSender<T> {
     fn clone(&self) -> Self {
-        BackendSender(self.0.clone(), self.1.clone())
+        BackendSender {
+            sock: self.sock.clone(),
+            dest: self.dest.clone(),
+            stats: self.stats.clone(),
+            outbox: self.outbox.clone(),
+            pending: self.pending.clone(),
+            limiter: self.limiter.clone(),
+            compress_threshold: self.compress_threshold.clone(),
+            seqs: self.seqs.clone(),
+        }
+    }
+}
+
+/// A handle to stop a `Backend` from another thread, from
+/// [`Backend::shutdown_handle`](./struct.Backend.html#method.shutdown_handle).
+#[cfg(unix)]
+#[derive(Clone)]
+pub struct BackendShutdown {
+    continue_listening: Arc<atomic::AtomicBool>,
+    // `None` once the `Backend` is dropped, so we never shut down a reused fd
+    fd: Arc<Mutex<Option<RawFd>>>,
+}
+
+#[cfg(unix)]
+impl BackendShutdown {
+    /// Stop the `Backend`: its `next()` returns `None` promptly, even if it is blocked waiting
+    /// for a message, rather than once the receive timeout passes.
+    pub fn shutdown(&self) {
+        self.continue_listening
+            .store(false, atomic::Ordering::SeqCst);
+        let fd = self.fd.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(fd) = *fd {
+            // wakes up a blocked recv. Not every fd is a socket, so ignore failures: the
+            // `Backend` still stops at its next receive timeout.
+            nix::sys::socket::shutdown(fd, nix::sys::socket::Shutdown::Both).unwrap_or(());
+        }
     }
 }
 
 /// Backend will yield incoming IPC messages forever via `next()`.
 /// It owns the socket; `BackendSender` holds weak references.
 /// The atomic bool is a way to stop iterating.
+///
+/// Messages are read into `receive_buf`, so it must be large enough to hold the largest message
+/// the datapath sends; a longer message is reported as an error rather than parsed.
 pub struct Backend<'a, T: Ipc> {
-    sock: Rc<T>,
+    sock: Arc<T>,
     continue_listening: Arc<atomic::AtomicBool>,
     receive_buf: &'a mut [u8],
     tot_read: usize,
     read_until: usize,
+    // where in `receive_buf` the message `next_msg` last returned starts
+    msg_start: usize,
     last_recv_addr: T::Addr,
+    // when the message(s) in `receive_buf` arrived
+    recv_time: SystemTime,
+    // for stream sockets, how much more of a message too long for `receive_buf` to skip
+    skip: usize,
+    recv_err: Option<Error>,
+    stats: Arc<BackendStats>,
+    // the socket's gaps count the last time it was added to `stats`
+    gaps_seen: u64,
+    #[cfg(unix)]
+    shutdown_fd: Arc<Mutex<Option<RawFd>>>,
+    heartbeat: Option<Heartbeat<T::Addr>>,
+    reader: Option<queue::Reader<T::Addr>>,
+    writer: Option<queue::Writer<T::Addr>>,
+    pending: Arc<queue::Pending<T::Addr>>,
+    limiter: Arc<queue::Limiter>,
+    compress_threshold: Arc<atomic::AtomicUsize>,
+    // the last message `next_msg` returned, decompressed, if it arrived compressed
+    decompressed: Vec<u8>,
+    msg_decompressed: bool,
+    // our version message, to send to each peer on first contact, if we announce it
+    version_msg: Option<Vec<u8>>,
+    announced: HashSet<T::Addr>,
+    peer_versions: HashMap<T::Addr, version::Msg>,
+    send_seqs: Arc<SendSeqs<T::Addr>>,
+    recv_seqs: RecvSeqs<'a, T::Addr>,
+    on_msg: Option<MsgHandler<'a, T::Addr>>,
+    parse_errors: ParseErrors<'a>,
+    parse_mode: crate::serialize::ParseMode,
+    batch: Option<RecvBatch<T::Addr>>,
+    // buffers for `try_recv` to copy messages into
+    recv_pool: BufPool,
+    busy_poll: bool,
+    // how long the last read which returned nothing waited for a message
+    idle_read: Duration,
+}
+
+type MsgHandler<'a, A> = Box<dyn FnMut(&[u8], &A) + Send + 'a>;
+
+type GapHandler<'a, A> = Box<dyn FnMut(&A, u64) + Send + 'a>;
+
+// Checks the sequence numbers of the messages from each peer which has them (see
+// `Backend::set_sequence_numbers`), and reports the messages missing.
+struct RecvSeqs<'a, A> {
+    // the sequence number of the last message from each peer
+    last: HashMap<A, u16>,
+    // the messages missing so far
+    gaps: u64,
+    handler: Option<GapHandler<'a, A>>,
+}
+
+impl<'a, A> Default for RecvSeqs<'a, A> {
+    fn default() -> Self {
+        RecvSeqs {
+            last: HashMap::new(),
+            gaps: 0,
+            handler: None,
+        }
+    }
+}
+
+impl<'a, A: Clone + Eq + std::hash::Hash + std::fmt::Debug> RecvSeqs<'a, A> {
+    // Compare `seq`, the sequence number of a message just received from `from`, with the last
+    // one from it, and return how many messages are missing in between, counting one which
+    // arrives out of order as missing.
+    fn check(&mut self, from: &A, seq: u16) -> u64 {
+        let missing = match self.last.get(from) {
+            None => 0,
+            Some(&last) => match crate::serialize::sequence::missed(last, seq) {
+                Some(missed) => u64::from(missed),
+                None => {
+                    debug!(?from, seq, last, "message out of order");
+                    // keep waiting for the messages after the last one
+                    self.report(from, 1);
+                    return 1;
+                }
+            },
+        };
+
+        if missing > 0 {
+            debug!(?from, seq, missing, "messages missing");
+            self.report(from, missing);
+        }
+        self.last.insert(from.clone(), seq);
+        missing
+    }
+
+    fn report(&mut self, from: &A, missing: u64) {
+        self.gaps += missing;
+        if let Some(handler) = self.handler.as_mut() {
+            handler(from, missing);
+        }
+    }
+}
+
+// Messages `IpcRecv::recv_many` read together into the first `arena_len` bytes of
+// `receive_buf`, and which of them `next_msg` is up to.
+struct RecvBatch<A> {
+    arena_len: usize,
+    slot_len: usize,
+    got: Vec<Received<A>>,
+    next: usize,
+}
+
+impl<A: Clone + Default> RecvBatch<A> {
+    // Read the next batch, and return its total length, and the first message's sender and
+    // arrival time, as `recv_timestamped` would for a single message.
+    fn recv<S: IpcRecv<Addr = A> + ?Sized>(
+        &mut self,
+        sk: &S,
+        buf: &mut [u8],
+    ) -> Result<(usize, A, Option<SystemTime>)> {
+        self.got.clear();
+        self.next = 0;
+        sk.recv_many(&mut buf[..self.arena_len], self.slot_len, &mut self.got)?;
+        self.got.retain(|m| m.len > 0);
+        Ok(match self.got.first() {
+            Some(m) => (self.got.iter().map(|m| m.len).sum(), m.from.clone(), m.at),
+            None => (0, Default::default(), None),
+        })
+    }
+}
+
+type ParseErrorHandler<'a> = Box<dyn FnMut(&[u8], &Error) + Send + 'a>;
+
+// Reports messages which could not be parsed: to the handler registered with `on_parse_error`,
+// and to the log, at most once per `PARSE_ERROR_LOG_INTERVAL`.
+#[derive(Default)]
+struct ParseErrors<'a> {
+    handler: Option<ParseErrorHandler<'a>>,
+    last_logged: Option<Instant>,
+    // messages not logged since `last_logged`
+    unlogged: u64,
 }
 
-use crate::serialize::Msg;
+impl<'a> ParseErrors<'a> {
+    fn report(&mut self, msg: &[u8], err: &Error) {
+        if let Some(handler) = self.handler.as_mut() {
+            handler(msg, err);
+        }
+
+        if matches!(self.last_logged, Some(t) if t.elapsed() < PARSE_ERROR_LOG_INTERVAL) {
+            self.unlogged += 1;
+            return;
+        }
+
+        warn!(
+            err = %err.0,
+            len = msg.len(),
+            suppressed = self.unlogged,
+            "could not parse message from datapath, skipping it"
+        );
+        self.last_logged = Some(Instant::now());
+        self.unlogged = 0;
+    }
+}
+
+// When the last heartbeat was sent, how many have gone unanswered since the datapath last
+// echoed one, and where to send them: the datapath we last heard from.
+struct Heartbeat<A> {
+    interval: Duration,
+    max_missed: u32,
+    last_sent: Instant,
+    unanswered: u32,
+    seq: u32,
+    peer: A,
+}
+
+use crate::serialize::{compress, version, Msg, ParseError};
 impl<'a, T: Ipc> Backend<'a, T> {
     pub fn new(
         sock: T,
@@ -103,17 +934,290 @@ impl<'a, T: Ipc> Backend<'a, T> {
         receive_buf: &'a mut [u8],
     ) -> Backend<'a, T> {
         Backend {
-            sock: Rc::new(sock),
+            sock: Arc::new(sock),
             continue_listening,
             receive_buf,
             tot_read: 0,
             read_until: 0,
+            msg_start: 0,
             last_recv_addr: Default::default(),
+            recv_time: SystemTime::UNIX_EPOCH,
+            skip: 0,
+            recv_err: None,
+            stats: Default::default(),
+            gaps_seen: 0,
+            #[cfg(unix)]
+            shutdown_fd: Default::default(),
+            heartbeat: None,
+            reader: None,
+            writer: None,
+            pending: Default::default(),
+            limiter: Default::default(),
+            compress_threshold: Default::default(),
+            decompressed: vec![],
+            msg_decompressed: false,
+            version_msg: None,
+            announced: Default::default(),
+            peer_versions: Default::default(),
+            send_seqs: Default::default(),
+            recv_seqs: Default::default(),
+            on_msg: None,
+            parse_errors: Default::default(),
+            batch: None,
+            recv_pool: Default::default(),
+            busy_poll: false,
+            idle_read: Duration::ZERO,
+            parse_mode: crate::serialize::DEFAULT_PARSE_MODE,
+        }
+    }
+
+    /// Receive on a separate thread, which does nothing but read messages from the socket into a
+    /// queue of `capacity` messages, handling a full queue according to `overflow`. `next()`
+    /// then takes messages from the queue, so the socket's buffer keeps draining while the
+    /// caller handles a message, e.g. while an algorithm is slow to handle a report.
+    ///
+    /// Messages the queue drops count towards `gaps()`. The thread stops when the `Backend` is
+    /// dropped, within the socket's receive timeout.
+    pub fn spawn_receiver(&mut self, capacity: usize, overflow: queue::Overflow) -> Result<()>
+    where
+        T: Sync,
+        T::Addr: Send,
+    {
+        if self.reader.is_some() {
+            return Err(Error(String::from("the receive thread is already running")));
+        }
+
+        self.reader = Some(queue::Reader::spawn(self.sock.clone(), capacity, overflow)?);
+        Ok(())
+    }
+
+    /// Send on a separate thread, from two queues of `capacity` messages each: messages sent
+    /// with `Priority::Urgent` go out before any `Priority::Normal` message still waiting. The
+    /// `Backend`'s senders then return once their message is queued, and only wait if its queue
+    /// is full.
+    ///
+    /// Only senders created after this call use the queues. When the `Backend` is dropped, the
+    /// thread sends what is left in them before the socket is closed.
+    pub fn spawn_sender(&mut self, capacity: usize) -> Result<()>
+    where
+        T: Sync,
+        T::Addr: Send,
+    {
+        if self.writer.is_some() {
+            return Err(Error(String::from("the send thread is already running")));
+        }
+
+        self.writer = Some(queue::Writer::spawn(
+            self.sock.clone(),
+            capacity,
+            self.stats.clone(),
+        )?);
+        Ok(())
+    }
+
+    /// Send the datapath a heartbeat message every `interval`, which it should echo back. Once
+    /// `max_missed` heartbeats in a row have gone unanswered for another `interval`, `next()`
+    /// stops returning messages and `take_error()` returns `DatapathGoneError`.
+    ///
+    /// Heartbeats are sent while waiting for messages, so the socket's receive timeout should be
+    /// shorter than `interval`. They go to the datapath the `Backend` last received a message
+    /// from.
+    pub fn set_heartbeat(&mut self, interval: Duration, max_missed: u32) {
+        self.heartbeat = Some(Heartbeat {
+            interval,
+            max_missed,
+            last_sent: Instant::now(),
+            unanswered: 0,
+            seq: 0,
+            peer: self.last_recv_addr.clone(),
+        });
+    }
+
+    /// Hold on to messages the `Backend`'s senders cannot send without blocking, e.g. on a
+    /// `Nonblocking` socket whose peer is not keeping up, rather than failing the send. Held
+    /// messages are sent in order, ahead of any later message, as the `Backend` waits for
+    /// messages (or on `flush_pending()`) and the socket has room again.
+    ///
+    /// Once the held messages would take more than `bytes`, a send which would block fails with
+    /// `SendQueueFullError` instead. A budget of 0, the default, turns this off, and such a send
+    /// fails as it would without a `Backend`. This has no effect on messages sent through a send
+    /// queue (see `spawn_sender`).
+    pub fn set_pending_budget(&mut self, bytes: usize) {
+        self.pending.set_budget(bytes);
+    }
+
+    /// Limit how fast the `Backend`'s senders send, e.g. so that an algorithm which updates its
+    /// flows' programs on every report cannot flood the datapath. A message over the limit waits
+    /// for it or fails, according to `limit.policy`; rejected sends count towards
+    /// `BackendStats::rate_limited`. `None`, the default, sends as fast as the socket takes them.
+    ///
+    /// This applies to all of the `Backend`'s senders, including those created before, but not
+    /// to the heartbeats the `Backend` sends itself. Setting a limit starts it with a full burst.
+    pub fn set_send_rate_limit(&mut self, limit: Option<SendRateLimit>) -> Result<()> {
+        if let Some(l) = limit {
+            if l.msgs_per_sec == 0 || l.burst == 0 {
+                return Err(Error(format!(
+                    "invalid send rate limit: {} messages/s in bursts of {}",
+                    l.msgs_per_sec, l.burst
+                )));
+            }
+        }
+
+        self.limiter.set(limit);
+        Ok(())
+    }
+
+    /// Compress the messages the `Backend`'s senders send which are at least `threshold` bytes
+    /// long, where that makes them shorter (see [`serialize::compress`](../serialize/compress/index.html)),
+    /// e.g. to install large programs over a remote socket. `None`, the default, sends messages
+    /// as they are. The datapath must be able to decompress them.
+    ///
+    /// Compressed messages from the datapath are decompressed whether or not this is set. This
+    /// applies to all of the `Backend`'s senders, including those created before.
+    #[cfg(feature = "compression")]
+    pub fn set_compression(&mut self, threshold: Option<usize>) {
+        self.compress_threshold
+            .store(threshold.map_or(0, |t| t.max(1)), atomic::Ordering::Relaxed);
+    }
+
+    /// Send each peer the version of the messages this portus speaks (see
+    /// [`serialize::version`](../serialize/version/index.html)) when the first message from it
+    /// arrives, and again when it sends a ready message, i.e. has restarted. Off by default, since
+    /// older datapaths do not know the version message.
+    ///
+    /// The versions peers send are recorded whether or not this is set; see `peer_version()`.
+    pub fn set_announce_version(&mut self, announce: bool) -> Result<()> {
+        self.version_msg = if announce {
+            Some(crate::serialize::serialize(&version::Msg::current())?)
+        } else {
+            None
+        };
+
+        Ok(())
+    }
+
+    /// Stamp the messages the `Backend`'s senders send with sequence numbers (see
+    /// [`serialize::sequence`](../serialize/sequence/index.html)), numbered from 0 for each peer,
+    /// so that the peer can tell when messages go missing, e.g. over a datagram socket. Off by
+    /// default, since older datapaths do not understand them. This applies to all of the
+    /// `Backend`'s senders, including those created before, but not to the heartbeats and
+    /// version messages the `Backend` sends itself.
+    ///
+    /// Sequence numbers on messages from peers are checked whether or not this is set: each
+    /// message missing, or arriving out of order, counts towards `gaps()` and is reported to the
+    /// handler registered with `on_gap()`.
+    pub fn set_sequence_numbers(&mut self, on: bool) {
+        self.send_seqs.set(on);
+    }
+
+    /// Call `handler` with the sender and the number of messages missing each time a message
+    /// from a peer skips sequence numbers, or with 1 for a message which arrives out of order,
+    /// replacing any handler registered before (see `set_sequence_numbers`).
+    pub fn on_gap<F>(&mut self, handler: F)
+    where
+        F: FnMut(&T::Addr, u64) + Send + 'a,
+    {
+        self.recv_seqs.handler = Some(Box::new(handler));
+    }
+
+    /// With a socket whose reads return right away when no message is waiting, e.g. a
+    /// `Nonblocking` one, `next()` (and `run`'s execution loop) wait for the socket's fd to
+    /// become readable between reads, polling it for a few milliseconds at a time so that they
+    /// still notice being stopped. With `busy` set, they read again straight away instead, which
+    /// takes a core but reacts to a message slightly sooner.
+    ///
+    /// Sockets without an fd (see `IpcRecv::raw_fd`) are always read again straight away.
+    pub fn set_busy_poll(&mut self, busy: bool) {
+        self.busy_poll = busy;
+    }
+
+    /// Read up to `max_msgs` messages from the socket at a time, e.g. with one `recvmmsg` call
+    /// (see `IpcRecv::recv_many`), into the first `arena_len` bytes of the receive buffer, split
+    /// evenly between them. `next()` then returns them one at a time, parsed where they were
+    /// read. This saves system calls when the datapath sends many small messages, e.g. reports
+    /// at a high rate.
+    ///
+    /// Each message must fit in `arena_len / max_msgs` bytes; longer ones are reported as
+    /// truncated. A `max_msgs` of 1 goes back to reading one message at a time. This fails for
+    /// stream sockets, which read as much as there is room for anyway, and once messages have
+    /// been read but not returned yet. With a receive thread (see `spawn_receiver`), messages
+    /// are read one at a time regardless.
+    pub fn set_recv_batch(&mut self, max_msgs: usize, arena_len: usize) -> Result<()> {
+        if T::is_stream() {
+            return Err(Error(String::from(
+                "stream sockets cannot read messages in batches",
+            )));
+        }
+
+        if max_msgs == 0
+            || arena_len > self.receive_buf.len()
+            || arena_len / max_msgs < crate::serialize::HDR_LENGTH as usize
+        {
+            return Err(Error(format!(
+                "cannot split {} of the receive buffer's {} bytes between {} messages",
+                arena_len,
+                self.receive_buf.len(),
+                max_msgs
+            )));
         }
+
+        let batched = self.batch.as_ref().map_or(0, |b| b.got.len() - b.next);
+        if self.read_until < self.tot_read || batched > 0 {
+            return Err(Error(String::from(
+                "messages have been read which have not been returned yet",
+            )));
+        }
+
+        self.batch = if max_msgs == 1 {
+            None
+        } else {
+            Some(RecvBatch {
+                arena_len,
+                slot_len: arena_len / max_msgs,
+                got: Vec::with_capacity(max_msgs),
+                next: 0,
+            })
+        };
+        Ok(())
+    }
+
+    /// The total length of the messages held until the socket has room.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.bytes()
+    }
+
+    /// Send what held messages the socket has room for now, and return how many are still held.
+    /// `next()` and `try_next()` do this each time they read from the socket.
+    pub fn flush_pending(&self) -> usize {
+        self.pending.flush(&*self.sock, &self.stats)
     }
 
     pub fn sender(&self, to: T::Addr) -> BackendSender<T> {
-        BackendSender(Rc::downgrade(&self.sock), to)
+        BackendSender {
+            sock: Arc::downgrade(&self.sock),
+            dest: to,
+            stats: self.stats.clone(),
+            outbox: self
+                .writer
+                .as_ref()
+                .map_or_else(Weak::new, queue::Writer::outbox),
+            pending: self.pending.clone(),
+            limiter: self.limiter.clone(),
+            compress_threshold: self.compress_threshold.clone(),
+            seqs: self.send_seqs.clone(),
+        }
+    }
+
+    /// Return a sender which sends each message to the datapath its sock_id belongs to (for
+    /// connection-oriented sockets, the only datapath), while this `Backend` keeps receiving.
+    /// Use `BackendSender::clone_with_dest` to send to a particular datapath instead.
+    pub fn split(&self) -> BackendSender<T> {
+        self.sender(Default::default())
+    }
+
+    /// Counts of the messages sent and received through this `Backend` and its senders.
+    pub fn stats(&self) -> &BackendStats {
+        &self.stats
     }
 
     /// Return a copy of the flag variable that indicates that the
@@ -122,29 +1226,355 @@ impl<'a, T: Ipc> Backend<'a, T> {
         Arc::clone(&(self.continue_listening))
     }
 
+    /// The number of incoming messages the socket, or their sequence numbers (see
+    /// `set_sequence_numbers`), showed to be lost or out of order, or the receive thread's queue
+    /// has dropped.
+    pub fn gaps(&self) -> u64 {
+        self.sock.gaps()
+            + self.reader.as_ref().map_or(0, queue::Reader::dropped)
+            + self.recv_seqs.gaps
+    }
+
+    /// The version of the messages `addr` speaks, once it has sent a version message.
+    pub fn peer_version(&self, addr: &T::Addr) -> Option<version::Msg> {
+        self.peer_versions.get(addr).copied()
+    }
+
+    /// The number of times the socket has reconnected to the datapath.
+    pub fn reconnects(&self) -> u64 {
+        self.sock.reconnects()
+    }
+
+    /// The number of times the socket has failed over to a standby datapath.
+    pub fn failovers(&self) -> u64 {
+        self.sock.failovers()
+    }
+
+    /// The file descriptor to poll for messages arriving, e.g. with epoll in an application's
+    /// own event loop, if the socket has one (see `IpcRecv::raw_fd`). Once it is readable, read
+    /// messages with `try_next()` or `try_recv()` until they return `Ok(None)`, or hand them to
+    /// the flows with [`CCPPump::dispatch_ready`](../struct.CCPPump.html#method.dispatch_ready).
+    /// The socket should be `Nonblocking`, so that these return as soon as it is drained.
+    ///
+    /// Returns `None` while a receive thread (see `spawn_receiver`) is reading from the socket.
+    #[cfg(unix)]
+    pub fn poll_fd(&self) -> Option<RawFd> {
+        match self.reader {
+            Some(_) => None,
+            None => self.sock.raw_fd(),
+        }
+    }
+
+    /// Like `try_next()`, but return a copy of the message's bytes rather than parsing them. The
+    /// copy reuses the buffer of a `RecvBuf` returned earlier and since dropped, if there is one.
+    pub fn try_recv(&mut self) -> Result<Option<(RecvBuf, T::Addr)>> {
+        let from = match self.next_msg(false)? {
+            Some((_, from, _)) => from,
+            None => return Ok(None),
+        };
+
+        let mut buf = self
+            .recv_pool
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .pop()
+            .unwrap_or_default();
+        buf.clear();
+        buf.extend_from_slice(self.msg_bytes());
+        Ok(Some((
+            RecvBuf {
+                buf,
+                pool: Arc::downgrade(&self.recv_pool),
+            },
+            from,
+        )))
+    }
+
+    /// If `next()` stopped returning messages because the socket failed, return that error.
+    pub fn take_error(&mut self) -> Option<Error> {
+        self.recv_err.take()
+    }
+
     /// Get the next IPC message.
     // This is similar to `impl Iterator`, but the returned value is tied to the lifetime
     // of `self`, so we cannot implement that trait.
     pub fn next(&mut self) -> Option<(Msg<'_>, T::Addr)> {
+        let (msg, from, _) = self.next_msg(true).ok()??;
+        Some((msg, from))
+    }
+
+    /// Get the next IPC message, or `Ok(None)` if none arrived before the socket's receive
+    /// timeout (see [`Ipc::set_recv_timeout`]). This lets the caller do periodic work while
+    /// waiting for messages.
+    ///
+    /// Returns an error once `next()` would return `None`. A message which cannot be parsed is
+    /// skipped and returns `MalformedMsgError` (see `on_parse_error()`); calling this again
+    /// carries on with the messages after it.
+    pub fn try_next(&mut self) -> Result<Option<(Msg<'_>, T::Addr)>> {
+        Ok(self.next_msg(false)?.map(|(msg, from, _)| (msg, from)))
+    }
+
+    /// Like `try_next()`, and also return when the message arrived: the socket's receive
+    /// timestamp (see [`IpcRecv::recv_timestamped`]) if it has one, and otherwise when the
+    /// message was read from the socket. Messages read together share a time.
+    pub fn try_next_at(&mut self) -> Result<Option<(Msg<'_>, T::Addr, SystemTime)>> {
+        self.next_msg(false)
+    }
+
+    /// Call `handler` with each message which cannot be parsed, along with why, replacing any
+    /// handler registered before. Such messages are skipped, counted in
+    /// `BackendStats::malformed`, and logged, at most once a second so that a datapath sending
+    /// garbage does not flood the log.
+    pub fn on_parse_error<F>(&mut self, handler: F)
+    where
+        F: FnMut(&[u8], &Error) + Send + 'a,
+    {
+        self.parse_errors.handler = Some(Box::new(handler));
+    }
+
+    /// Parse the messages received in `mode`, `serialize::DEFAULT_PARSE_MODE` by default. In
+    /// `ParseMode::Strict`, messages which are not exactly as their types say are malformed, and
+    /// skipped as if they could not be parsed at all (see `on_parse_error`).
+    pub fn set_parse_mode(&mut self, mode: crate::serialize::ParseMode) {
+        self.parse_mode = mode;
+    }
+
+    /// Have `dispatch_ready()` pass each message to `handler`, along with who sent it, replacing
+    /// any handler registered before. This is for applications which wait for the socket to
+    /// become readable in their own event loop, rather than having the `Backend` wait in
+    /// `next()`.
+    pub fn on_msg<F>(&mut self, handler: F)
+    where
+        F: FnMut(&[u8], &T::Addr) + Send + 'a,
+    {
+        self.on_msg = Some(Box::new(handler));
+    }
+
+    /// Pass each message which can be read now to the handler registered with `on_msg()`, and
+    /// return how many there were. With a `Nonblocking` socket, this returns as soon as no more
+    /// messages are waiting; a blocking socket waits up to its receive timeout for one first.
+    ///
+    /// Messages are checked and counted as `next()` would, so an error, e.g. for a message too
+    /// long for the receive buffer, stops the dispatch after the messages before it. Messages
+    /// which cannot be parsed are skipped.
+    pub fn dispatch_ready(&mut self) -> Result<usize> {
+        let mut handler = self
+            .on_msg
+            .take()
+            .ok_or_else(|| Error(String::from("no message handler, see on_msg()")))?;
+        let mut dispatched = 0;
+        let res = loop {
+            match self.next_msg(false).map(|m| m.map(|(_, from, _)| from)) {
+                Ok(Some(from)) => {
+                    handler(self.msg_bytes(), &from);
+                    dispatched += 1;
+                }
+                Ok(None) => break Ok(dispatched),
+                Err(e) if e == Error::from(crate::MalformedMsgError) => (),
+                Err(e) => break Err(e),
+            }
+        };
+
+        self.on_msg = Some(handler);
+        res
+    }
+
+    fn next_msg(&mut self, wait: bool) -> Result<Option<(Msg<'_>, T::Addr, SystemTime)>> {
         // if we have leftover buffer from the last read, parse another message.
-        if self.read_until < self.tot_read {
-            let (msg, consumed) = Msg::from_buf(&self.receive_buf[self.read_until..]).ok()?;
-            self.read_until += consumed;
-            Some((msg, self.last_recv_addr.clone()))
+        if T::is_stream() {
+            if !self.read_frame(wait)? {
+                return Ok(None);
+            }
+        } else if self.read_until >= self.tot_read && !self.next_batched() {
+            let read = self.get_next_read(0, wait)?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            if !self.next_batched() {
+                self.tot_read = read;
+                self.read_until = 0;
+            }
+        }
+
+        // a datagram longer than the buffer (or its slot of a batch) was cut short by the read,
+        // so don't try to parse it
+        let room = match self.batch {
+            Some(ref b) if self.reader.is_none() => b.slot_len,
+            _ => self.receive_buf.len(),
+        };
+        let buf = &self.receive_buf[self.read_until..self.tot_read];
+        if let Some(need) = crate::serialize::claimed_len(buf) {
+            if need > room {
+                self.read_until = self.tot_read;
+                BackendStats::incr(&self.stats.recv_errors);
+                return Err(Error(format!("message truncated, need {} bytes", need)));
+            }
+        }
+
+        // `Msg::from_buf` takes a message with an invalid header for one of unknown type, so
+        // check the header first
+        let buf = &self.receive_buf[self.read_until..self.tot_read];
+        let frame_len = crate::serialize::frame_len(buf);
+        self.msg_decompressed = frame_len.is_ok() && compress::is_compressed(buf);
+        let mode = self.parse_mode;
+        let parsed = if self.msg_decompressed {
+            match compress::decompress(buf, &mut self.decompressed) {
+                Ok(len) => Msg::from_buf_with(&self.decompressed, mode).map(|(msg, _)| (msg, len)),
+                Err(e) => Err(e),
+            }
+        } else {
+            frame_len
+                .clone()
+                .and_then(|_| Msg::from_buf_with(buf, mode))
+        };
+        let (msg, consumed) = match parsed {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                // without a valid header, there is no telling where the next message starts
+                let len = frame_len.unwrap_or(buf.len());
+                self.read_until += len;
+                if e == Error::from(crate::CorruptMsgError) {
+                    BackendStats::incr(&self.stats.corrupt);
+                } else {
+                    BackendStats::incr(&self.stats.malformed);
+                }
+                self.parse_errors.report(&buf[..len], &e);
+                return Err(Error::from(crate::MalformedMsgError));
+            }
+        };
+        self.msg_start = self.read_until;
+        self.read_until += consumed;
+        BackendStats::incr(&self.stats.received);
+        if let Msg::Rdy(_) = msg {
+            // a restarted peer numbers its messages from 0 again
+            self.recv_seqs.last.remove(&self.last_recv_addr);
+        }
+        let seq = if self.msg_decompressed {
+            crate::serialize::sequence::seq(&self.decompressed)
         } else {
-            self.tot_read = self.get_next_read().ok()?;
+            crate::serialize::sequence::seq(&self.receive_buf[self.msg_start..self.read_until])
+        };
+        if let Some(seq) = seq {
+            let missing = self.recv_seqs.check(&self.last_recv_addr, seq);
+            self.gaps_seen += missing;
+            self.stats
+                .gaps
+                .fetch_add(missing, atomic::Ordering::Relaxed);
+        }
+        match msg {
+            Msg::Other(_) => BackendStats::incr(&self.stats.unknown_msgs),
+            Msg::Hb(_) => {
+                if let Some(hb) = self.heartbeat.as_mut() {
+                    hb.unanswered = 0;
+                }
+            }
+            Msg::Rdy(_) => {
+                self.announced.remove(&self.last_recv_addr);
+            }
+            Msg::Ver(v) => {
+                self.peer_versions.insert(self.last_recv_addr.clone(), v);
+            }
+            _ => (),
+        }
+
+        if let Some(ref buf) = self.version_msg {
+            if self.announced.insert(self.last_recv_addr.clone()) {
+                match self.sock.send(&buf[..], &self.last_recv_addr) {
+                    Ok(()) => BackendStats::incr(&self.stats.sent),
+                    Err(e) => {
+                        BackendStats::incr(&self.stats.send_errors);
+                        warn!(err = %e.0, "failed to send protocol version");
+                    }
+                }
+            }
+        }
+
+        Ok(Some((msg, self.last_recv_addr.clone(), self.recv_time)))
+    }
+
+    // The message `next_msg` last returned, decompressed if it arrived compressed.
+    fn msg_bytes(&self) -> &[u8] {
+        if self.msg_decompressed {
+            &self.decompressed
+        } else {
+            &self.receive_buf[self.msg_start..self.read_until]
+        }
+    }
+
+    // Move on to the next message of the last batch read, if there is one left.
+    fn next_batched(&mut self) -> bool {
+        let m = match self.batch {
+            Some(ref mut b) if b.next < b.got.len() => {
+                b.next += 1;
+                &b.got[b.next - 1]
+            }
+            _ => return false,
+        };
+
+        self.read_until = m.start;
+        self.tot_read = m.start + m.len;
+        self.last_recv_addr = m.from.clone();
+        if let Some(at) = m.at {
+            self.recv_time = at;
+        }
+        if let Some(hb) = self.heartbeat.as_mut() {
+            hb.peer = m.from.clone();
+        }
+
+        true
+    }
+
+    // For stream sockets: read until what is left of `receive_buf` to parse starts with a whole
+    // message, going by the length in its header, for as long as it is incomplete (see
+    // `ParseError::Incomplete`). A message malformed otherwise is left for `next_msg` to find.
+    // Returns false if the read timed out first, which only happens if `wait` is false; what was
+    // read so far stays buffered.
+    fn read_frame(&mut self, wait: bool) -> Result<bool> {
+        loop {
+            let buffered = self.tot_read - self.read_until;
+            let buf = &self.receive_buf[self.read_until..self.tot_read];
+            let needed = match crate::serialize::frame_len(buf) {
+                Ok(_) => return Ok(true),
+                Err(e) => match ParseError::of(&e) {
+                    Some(ParseError::Incomplete { needed }) => needed,
+                    _ => return Ok(true),
+                },
+            };
+            if buffered + needed > self.receive_buf.len() {
+                // drop the message, including the part not read yet, to find the next one
+                self.skip = needed;
+                self.read_until = self.tot_read;
+                BackendStats::incr(&self.stats.recv_errors);
+                return Err(Error(format!(
+                    "message truncated, need {} bytes",
+                    buffered + needed
+                )));
+            }
+
+            // make room after the partial message for the rest of it
+            self.receive_buf
+                .copy_within(self.read_until..self.tot_read, 0);
+            self.tot_read = buffered;
             self.read_until = 0;
-            let (msg, consumed) =
-                Msg::from_buf(&self.receive_buf[self.read_until..self.tot_read]).ok()?;
-            self.read_until += consumed;
+            let read = self.get_next_read(self.tot_read, wait)?;
+            if read == 0 {
+                return Ok(false);
+            }
 
-            Some((msg, self.last_recv_addr.clone()))
+            let skipped = std::cmp::min(self.skip, read);
+            self.skip -= skipped;
+            let start = self.tot_read;
+            self.receive_buf
+                .copy_within((start + skipped)..(start + read), start);
+            self.tot_read += read - skipped;
         }
     }
 
     // calls IPC repeatedly to read one or more messages.
-    // Returns a slice into self.receive_buf covering the read data
-    fn get_next_read(&mut self) -> Result<usize> {
+    // Returns how many bytes of self.receive_buf, from `at` on, were filled by the read; if
+    // `wait` is false, this is 0 if the read timed out.
+    fn get_next_read(&mut self, at: usize, wait: bool) -> Result<usize> {
         loop {
             // if continue_loop has been set to false, stop iterating
             if !self.continue_listening.load(atomic::Ordering::SeqCst) {
@@ -152,14 +1582,37 @@ impl<'a, T: Ipc> Backend<'a, T> {
                 return Err(Error(String::from("Done")));
             }
 
-            let (read, addr) = match self.sock.recv(self.receive_buf) {
+            if let Err(e) = self.send_heartbeat() {
+                self.recv_err = Some(e.clone());
+                return Err(e);
+            }
+
+            self.flush_pending();
+
+            let started = Instant::now();
+            let received = match (&self.reader, &mut self.batch) {
+                (Some(r), _) => r.recv(&mut self.receive_buf[at..], None),
+                (None, Some(b)) => b.recv(&*self.sock, &mut self.receive_buf[..]),
+                (None, None) => self.sock.recv_timestamped(&mut self.receive_buf[at..]),
+            };
+            let (read, addr, at) = match received {
                 Ok(r) => r,
-                Err(Error(e)) => {
-                    trace!(err = %format!("{:#?}", e), "recv failed" );
-                    continue;
+                Err(e) => {
+                    warn!(err = %e.0, "recv failed, stopping");
+                    BackendStats::incr(&self.stats.recv_errors);
+                    self.recv_err = Some(e.clone());
+                    return Err(e);
                 }
             };
 
+            let gaps = self.gaps();
+            if gaps > self.gaps_seen {
+                self.stats
+                    .gaps
+                    .fetch_add(gaps - self.gaps_seen, atomic::Ordering::Relaxed);
+                self.gaps_seen = gaps;
+            }
+
             // NOTE This may seem precarious, but is safe
             // In the case that `recv` returns a buffer containing multiple messages,
             // `next()` will continue to hit the first `if` branch (and thus will not
@@ -167,28 +1620,135 @@ impl<'a, T: Ipc> Backend<'a, T> {
             // have been returned. So it is not possible for recvs to interleave and
             // interfere with the last_recv_addr value.
             self.last_recv_addr = addr;
+            if read > 0 {
+                self.recv_time = at.unwrap_or_else(SystemTime::now);
+            }
+
+            if let Some(hb) = self.heartbeat.as_mut() {
+                if read > 0 {
+                    hb.peer = self.last_recv_addr.clone();
+                }
+            }
 
             if read == 0 {
-                continue;
+                self.idle_read = started.elapsed();
+                if wait {
+                    self.idle(None);
+                    continue;
+                }
             }
 
             return Ok(read);
         }
     }
+
+    // After a read which returned nothing without waiting for a message, e.g. on a `Nonblocking`
+    // socket, wait up to `IDLE_POLL` (and no longer than `limit`) for the socket to become
+    // readable, so that reading again in a loop does not spin.
+    pub(crate) fn idle(&self, limit: Option<Duration>) {
+        if self.busy_poll || self.reader.is_some() || self.idle_read >= IDLE_POLL {
+            return;
+        }
+
+        let wait = limit.map_or(IDLE_POLL, |l| std::cmp::min(l, IDLE_POLL));
+        self.wait_readable(wait.saturating_sub(self.idle_read));
+    }
+
+    #[cfg(unix)]
+    fn wait_readable(&self, wait: Duration) {
+        if let Some(fd) = self.sock.raw_fd() {
+            let pollfd = nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLIN);
+            // a failed poll just means reading again sooner
+            nix::poll::poll(&mut [pollfd], wait.as_millis() as libc::c_int).unwrap_or(0);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn wait_readable(&self, _wait: Duration) {}
+
+    // If heartbeats are on and one is due, send it, or fail if too many have gone unanswered.
+    fn send_heartbeat(&mut self) -> Result<()> {
+        let hb = match self.heartbeat.as_mut() {
+            Some(hb) if hb.last_sent.elapsed() >= hb.interval => hb,
+            _ => return Ok(()),
+        };
+
+        if hb.unanswered >= hb.max_missed {
+            warn!(
+                missed = hb.unanswered,
+                "datapath stopped answering heartbeats"
+            );
+            return Err(Error::from(crate::DatapathGoneError));
+        }
+
+        let buf = crate::serialize::serialize(&crate::serialize::heartbeat::Msg { seq: hb.seq })?;
+        hb.seq = hb.seq.wrapping_add(1);
+        hb.unanswered += 1;
+        hb.last_sent = Instant::now();
+        // a heartbeat which cannot be sent just goes unanswered
+        if let Err(e) = self.sock.send(&buf[..], &hb.peer) {
+            debug!(err = %e.0, "failed to send heartbeat");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+impl<'a, T: Ipc + AsRawFd> Backend<'a, T> {
+    /// Return a handle which stops this `Backend` from another thread, e.g. one which owns the
+    /// thread calling `next()` in a loop.
+    pub fn shutdown_handle(&self) -> BackendShutdown {
+        *self.shutdown_fd.lock().unwrap_or_else(|e| e.into_inner()) = Some(self.sock.as_raw_fd());
+        BackendShutdown {
+            continue_listening: self.clone_atomic_bool(),
+            fd: Arc::clone(&self.shutdown_fd),
+        }
+    }
+
+    /// Stop listening and shut down the socket; `next()` will return `None`.
+    pub fn shutdown(&self) {
+        self.shutdown_handle().shutdown()
+    }
+}
+
+#[cfg(unix)]
+impl<'a, T: Ipc + AsRawFd> AsRawFd for Backend<'a, T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sock.as_raw_fd()
+    }
 }
 
 impl<'a, T: Ipc> Drop for Backend<'a, T> {
     fn drop(&mut self) {
-        Rc::get_mut(&mut self.sock)
-            .ok_or_else(|| {
-                Error(String::from(
-                    "Could not get exclusive ref to socket to close",
-                ))
-            })
-            .and_then(Ipc::close)
-            .unwrap_or_else(|_| ());
+        // the fd is closed when `self.sock` is dropped; don't let a `BackendShutdown` touch it
+        // after that
+        #[cfg(unix)]
+        {
+            *self.shutdown_fd.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        }
+        // the receive thread has to let go of the socket before it can be closed
+        if let Some(mut r) = self.reader.take() {
+            r.stop();
+        }
+
+        // as does the send thread, once it has sent everything queued
+        if let Some(w) = self.writer.take() {
+            w.stop();
+        }
+
+        let held = self.flush_pending();
+        if held > 0 {
+            debug!(held, "dropping pending sends");
+        }
+
+        if let Some(s) = Arc::get_mut(&mut self.sock) {
+            if let Err(e) = IpcRecv::close(s) {
+                debug!(err = %e.0, "failed to close socket");
+            }
+        }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, unix))]
 pub mod test;
@@ -0,0 +1,273 @@
+//! Receive from several IPC sockets at once, e.g. a netlink socket for kernel flows and a unix
+//! socket for userspace ones, so that one set of algorithms can serve all of them.
+//!
+//! A `Multi` is itself an `Ipc`, whose addresses are the index of the socket a message arrived on
+//! together with the address it had on that socket. Flows are keyed by address, so flows with the
+//! same socket id on different sockets stay separate, and replies go out the socket the flow
+//! arrived on.
+//!
+//! To combine different kinds of sockets, wrap them in `Either`:
+//!
+//! ```rust,no_run
+//! use portus::ipc::multi::{Either, Multi};
+//! use portus::ipc::{netlink, unix, BackendBuilder, Blocking};
+//!
+//! let sock = Multi::new(vec![
+//!     Either::Left(netlink::Socket::<Blocking>::new().unwrap()),
+//!     Either::Right(unix::Socket::<Blocking>::new("portus").unwrap()),
+//! ]);
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::{Error, Ipc, IpcRecv, IpcSend, Result};
+use nix::poll::{PollFd, PollFlags};
+use std::cell::{Cell, RefCell};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, SystemTime};
+
+pub struct Multi<I> {
+    socks: Vec<I>,
+    // reused across calls to `recv`, so it does not allocate
+    fds: RefCell<Vec<PollFd>>,
+    // which socket to check first, so a busy socket cannot starve the others
+    next: Cell<usize>,
+    recv_timeout_ms: libc::c_int,
+}
+
+impl<I: Ipc + AsRawFd> Multi<I> {
+    pub fn new(socks: Vec<I>) -> Self {
+        let fds = RefCell::new(Vec::with_capacity(socks.len()));
+        Multi {
+            socks,
+            fds,
+            next: Cell::new(0),
+            recv_timeout_ms: 1000,
+        }
+    }
+}
+
+impl<I: Ipc + AsRawFd> IpcSend for Multi<I> {
+    type Addr = (usize, I::Addr);
+
+    fn name() -> String {
+        format!("multi-{}", I::name())
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        self.socks
+            .get(to.0)
+            .ok_or_else(|| Error(format!("no socket with index {}", to.0)))?
+            .send(msg, &to.1)
+    }
+
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        match self.socks.get(to.0) {
+            Some(s) => s.send_many(msgs, &to.1),
+            None => (0, Err(Error(format!("no socket with index {}", to.0)))),
+        }
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.socks
+            .iter_mut()
+            .try_for_each(|s| s.set_send_timeout(timeout))
+    }
+}
+
+impl<I: Ipc + AsRawFd> IpcRecv for Multi<I> {
+    /// Waits up to the receive timeout for any of the sockets to become readable, and receives
+    /// from one of them. Fails if any of the sockets does.
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        let (len, from, _) = self.recv_timestamped(msg)?;
+        Ok((len, from))
+    }
+
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        let mut fds = self.fds.borrow_mut();
+        fds.clear();
+        fds.extend(
+            self.socks
+                .iter()
+                .map(|s| PollFd::new(s.as_raw_fd(), PollFlags::POLLIN)),
+        );
+        match nix::poll::poll(&mut fds[..], self.recv_timeout_ms) {
+            Ok(0) | Err(nix::errno::Errno::EINTR) => return Ok((0, Default::default(), None)),
+            Ok(_) => (),
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        let n = self.socks.len();
+        let start = self.next.get();
+        for i in (start..n).chain(0..start) {
+            if fds[i].revents().is_some_and(|r| !r.is_empty()) {
+                self.next.set((i + 1) % n);
+                let (len, addr, at) = self.socks[i].recv_timestamped(msg)?;
+                return Ok((len, (i, addr), at));
+            }
+        }
+
+        Ok((0, Default::default(), None))
+    }
+
+    /// Partial messages from different streams are not kept apart, so at most one of the
+    /// sockets should be a stream.
+    fn is_stream() -> bool {
+        I::is_stream()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.socks.iter_mut().try_for_each(IpcRecv::close)
+    }
+
+    /// Sets how long `recv` waits for any of the sockets, which are not waited on individually.
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        Ok(())
+    }
+
+    fn gaps(&self) -> u64 {
+        self.socks.iter().map(IpcRecv::gaps).sum()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.socks.iter().map(IpcRecv::reconnects).sum()
+    }
+
+    fn failovers(&self) -> u64 {
+        self.socks.iter().map(IpcRecv::failovers).sum()
+    }
+}
+
+/// One of two kinds of socket, so that both can be used in one `Multi`.
+pub enum Either<L, R> {
+    Left(L),
+    Right(R),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum EitherAddr<L, R> {
+    Left(L),
+    Right(R),
+}
+
+impl<L: Default, R> Default for EitherAddr<L, R> {
+    fn default() -> Self {
+        EitherAddr::Left(L::default())
+    }
+}
+
+impl<L: Ipc, R: Ipc> IpcSend for Either<L, R> {
+    type Addr = EitherAddr<L::Addr, R::Addr>;
+
+    fn name() -> String {
+        format!("{}-or-{}", L::name(), R::name())
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        match (self, to) {
+            (Either::Left(l), EitherAddr::Left(to)) => l.send(msg, to),
+            (Either::Right(r), EitherAddr::Right(to)) => r.send(msg, to),
+            (Either::Left(_), EitherAddr::Right(_)) => Err(Error(format!(
+                "cannot send to a {} address on a {} socket",
+                R::name(),
+                L::name()
+            ))),
+            (Either::Right(_), EitherAddr::Left(_)) => Err(Error(format!(
+                "cannot send to a {} address on a {} socket",
+                L::name(),
+                R::name()
+            ))),
+        }
+    }
+
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        match (self, to) {
+            (Either::Left(l), EitherAddr::Left(to)) => l.send_many(msgs, to),
+            (Either::Right(r), EitherAddr::Right(to)) => r.send_many(msgs, to),
+            // fails just as `send` does
+            _ => super::send_each(self, msgs, to),
+        }
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        match self {
+            Either::Left(l) => l.set_send_timeout(timeout),
+            Either::Right(r) => r.set_send_timeout(timeout),
+        }
+    }
+}
+
+impl<L: Ipc, R: Ipc> IpcRecv for Either<L, R> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        match self {
+            Either::Left(l) => l.recv(msg).map(|(len, a)| (len, EitherAddr::Left(a))),
+            Either::Right(r) => r.recv(msg).map(|(len, a)| (len, EitherAddr::Right(a))),
+        }
+    }
+
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        match self {
+            Either::Left(l) => l
+                .recv_timestamped(msg)
+                .map(|(len, a, at)| (len, EitherAddr::Left(a), at)),
+            Either::Right(r) => r
+                .recv_timestamped(msg)
+                .map(|(len, a, at)| (len, EitherAddr::Right(a), at)),
+        }
+    }
+
+    fn is_stream() -> bool {
+        L::is_stream() || R::is_stream()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self {
+            Either::Left(l) => l.close(),
+            Either::Right(r) => r.close(),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        match self {
+            Either::Left(l) => l.set_recv_timeout(timeout),
+            Either::Right(r) => r.set_recv_timeout(timeout),
+        }
+    }
+
+    fn gaps(&self) -> u64 {
+        match self {
+            Either::Left(l) => l.gaps(),
+            Either::Right(r) => r.gaps(),
+        }
+    }
+
+    fn reconnects(&self) -> u64 {
+        match self {
+            Either::Left(l) => l.reconnects(),
+            Either::Right(r) => r.reconnects(),
+        }
+    }
+
+    fn failovers(&self) -> u64 {
+        match self {
+            Either::Left(l) => l.failovers(),
+            Either::Right(r) => r.failovers(),
+        }
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        match self {
+            Either::Left(l) => l.raw_fd(),
+            Either::Right(r) => r.raw_fd(),
+        }
+    }
+}
+
+impl<L: AsRawFd, R: AsRawFd> AsRawFd for Either<L, R> {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Either::Left(l) => l.as_raw_fd(),
+            Either::Right(r) => r.as_raw_fd(),
+        }
+    }
+}
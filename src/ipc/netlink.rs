@@ -1,51 +1,209 @@
 use super::Error;
 use super::Result;
+use crate::serialize::{u16_from_u8s, u16_to_u8s, u32_to_u8s};
 use libc::c_int;
 use nix::sys::socket;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::{debug, info, warn};
 
-pub struct Socket<T>(c_int, PhantomData<T>);
+pub struct Socket<T> {
+    fd: c_int,
+    partial: Mutex<Partial>,
+    // held while sending the fragments of one message, so that senders on other threads
+    // cannot interleave theirs
+    send_lock: Mutex<()>,
+    // how large the receive buffer may grow when the kernel drops messages for lack of room
+    max_rcvbuf: usize,
+    overruns: AtomicU64,
+    // the portid messages to the datapath are sent to
+    dest_portid: u32,
+    _phantom: PhantomData<T>,
+}
+
+// The payloads of the fragments received so far of a message that is not yet complete.
+#[derive(Default)]
+struct Partial {
+    payload: Vec<u8>,
+    // the message outgrew the receive buffer, so its remaining fragments are dropped too
+    discarding: bool,
+}
+
+impl Partial {
+    fn is_empty(&self) -> bool {
+        self.payload.is_empty() && !self.discarding
+    }
+
+    // Add the payload of a fragment which is not the last of its message, unless the message
+    // no longer fits in `cap` bytes: then drop it, along with the rest of its fragments.
+    fn push(&mut self, payload: &[u8], cap: usize) {
+        if self.discarding {
+            return;
+        }
+
+        let len = self.payload.len() + payload.len();
+        if len > cap {
+            warn!(
+                len,
+                cap, "dropping fragmented netlink message larger than the receive buffer"
+            );
+            self.payload.clear();
+            self.discarding = true;
+            return;
+        }
+
+        self.payload.extend_from_slice(payload);
+    }
+}
 
 const NL_CFG_F_NONROOT_RECV: c_int = 1;
 const NL_CFG_F_NONROOT_SEND: c_int = 1 << 1;
 const NLMSG_HDRSIZE: usize = 0x10;
+// Largest netlink message, header included, sent or received in one piece. Larger messages,
+// in practice install_fold messages for programs with many Report variables, are split into
+// fragments. Every fragment but the last carries NLM_F_MULTI in its flags.
+const NLMSG_MAXSIZE: usize = 1024;
+const NLM_F_MULTI: u16 = 0x2;
+
+/// The netlink protocol the ccp-kernel module uses.
+pub const DEFAULT_PROTOCOL: c_int = libc::NETLINK_USERSOCK;
+/// The largest the receive buffer grows to by default, see `Socket::set_max_rcvbuf`.
+pub const DEFAULT_MAX_RCVBUF: usize = 8 << 20;
+/// The multicast group the ccp-kernel module sends to.
+pub const DEFAULT_GROUP: u32 = 22;
+// netlink protocols have at most 32 multicast groups, numbered from 1
+const MAX_GROUP: u32 = 32;
+
+/// How a netlink `Socket` binds, and where it sends, e.g. to run several CCP agents on one
+/// machine, each with its own kernel module.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketOptions {
+    pub protocol: c_int,
+    /// The multicast group to receive messages sent to, see `Socket::with_group`.
+    pub group: u32,
+    /// The portid to bind to. `None` binds to the process id, as `Socket::new` does, which only
+    /// one socket per protocol can do; `Some(0)` lets the kernel pick an unused portid.
+    pub portid: Option<u32>,
+    /// The portid to send messages to the datapath to. The ccp-kernel module listens on the
+    /// kernel's own portid, 0.
+    pub dest_portid: u32,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        SocketOptions {
+            protocol: DEFAULT_PROTOCOL,
+            group: DEFAULT_GROUP,
+            portid: None,
+            dest_portid: 0,
+        }
+    }
+}
 
 impl<T> Socket<T> {
-    fn __new() -> Result<Self> {
-        let fd = if let Ok(fd) = socket::socket(
-            nix::sys::socket::AddressFamily::Netlink,
-            nix::sys::socket::SockType::Raw,
-            nix::sys::socket::SockFlag::empty(),
-            nix::sys::socket::SockProtocol::NetlinkUserSock,
-        ) {
-            fd
-        } else {
-            socket::socket(
-                nix::sys::socket::AddressFamily::Netlink,
-                nix::sys::socket::SockType::Raw,
-                nix::sys::socket::SockFlag::from_bits_truncate(NL_CFG_F_NONROOT_RECV)
-                    | nix::sys::socket::SockFlag::from_bits_truncate(NL_CFG_F_NONROOT_SEND),
-                nix::sys::socket::SockProtocol::NetlinkUserSock,
-            )?
+    fn __new(protocol: c_int, portid: u32, dest_portid: u32) -> Result<Self> {
+        let mut fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, protocol) };
+        if fd < 0 {
+            fd = unsafe {
+                libc::socket(
+                    libc::AF_NETLINK,
+                    libc::SOCK_RAW | NL_CFG_F_NONROOT_RECV | NL_CFG_F_NONROOT_SEND,
+                    protocol,
+                )
+            };
+        }
+
+        if fd < 0 {
+            return Err(Error(format!(
+                "could not create netlink socket for protocol {}: {}",
+                protocol,
+                nix::Error::last()
+            )));
+        }
+
+        let mut s = Socket {
+            fd,
+            partial: Mutex::new(Partial::default()),
+            send_lock: Mutex::new(()),
+            max_rcvbuf: DEFAULT_MAX_RCVBUF,
+            overruns: AtomicU64::new(0),
+            dest_portid,
+            _phantom: PhantomData,
         };
 
-        let pid = unsafe { libc::getpid() };
+        if let Err(e) = socket::bind(fd, &nix::sys::socket::SockAddr::new_netlink(portid, 0)) {
+            s.__close().unwrap_or(());
+            if e == nix::errno::Errno::EADDRINUSE {
+                return Err(Error(format!(
+                    "netlink portid {} of protocol {} is already in use, e.g. by another CCP agent",
+                    portid, protocol
+                )));
+            }
+
+            return Err(Error::from(e));
+        }
 
-        socket::bind(fd, &nix::sys::socket::SockAddr::new_netlink(pid as u32, 0))?;
+        // so that `recv_timestamped` can tell when each message arrived
+        if let Err(e) = socket::setsockopt(fd, socket::sockopt::ReceiveTimestampns, &true) {
+            s.__close().unwrap_or(());
+            return Err(Error::from(e));
+        }
 
-        Ok(Socket(fd, PhantomData))
+        Ok(s)
     }
 
+    /// Listen to the ccp-kernel module's default multicast group.
     pub fn new() -> Result<Self> {
-        let s = Self::__new()?;
-        let opt = 22;
+        Self::with_group(DEFAULT_PROTOCOL, DEFAULT_GROUP)
+    }
+
+    /// Use netlink `protocol`, and only receive messages sent to multicast `group`, e.g. to run
+    /// two CCP agents side by side with kernel modules configured to use different groups.
+    ///
+    /// The group only affects what this socket receives. Messages to the datapath are sent to
+    /// the kernel itself, which tells flows apart by the sock_id in each message, so patterns
+    /// reach the kernel module whatever the group.
+    pub fn with_group(protocol: c_int, group: u32) -> Result<Self> {
+        Self::with_options(SocketOptions {
+            protocol,
+            group,
+            ..Default::default()
+        })
+    }
+
+    /// Bind and send as `opts` says. Fails if another socket is already bound to the portid.
+    pub fn with_options(opts: SocketOptions) -> Result<Self> {
+        let SocketOptions {
+            protocol,
+            group,
+            portid,
+            dest_portid,
+        } = opts;
+        if !(1..=MAX_GROUP).contains(&group) {
+            return Err(Error(format!(
+                "invalid netlink multicast group {}: groups are numbered 1 to {}",
+                group, MAX_GROUP
+            )));
+        }
+
+        let portid = portid.unwrap_or_else(|| unsafe { libc::getpid() } as u32);
+        let mut s = Self::__new(protocol, portid, dest_portid)?;
         use std::mem;
-        s.setsockopt(
-            270,
+        let joined = s.setsockopt(
+            libc::SOL_NETLINK,
             libc::NETLINK_ADD_MEMBERSHIP,
-            &opt as *const i32 as *const libc::c_void,
-            mem::size_of::<c_int>() as u32,
-        )?;
+            &group as *const u32 as *const libc::c_void,
+            mem::size_of::<u32>() as u32,
+        );
+        if let Err(e) = joined {
+            s.__close().unwrap_or(());
+            return Err(Error(format!(
+                "could not join netlink multicast group {} of protocol {}: {}",
+                group, protocol, e.0
+            )));
+        }
 
         let to = libc::timespec {
             tv_sec: 1 as libc::time_t,
@@ -61,6 +219,13 @@ impl<T> Socket<T> {
         Ok(s)
     }
 
+    /// When the kernel drops messages because the receive buffer is full, e.g. under per-ACK
+    /// reporting, the socket doubles the buffer's size, up to `max` bytes. Growing the buffer
+    /// past the `net.core.rmem_max` sysctl needs `CAP_NET_ADMIN`.
+    pub fn set_max_rcvbuf(&mut self, max: usize) {
+        self.max_rcvbuf = max;
+    }
+
     fn setsockopt(
         &self,
         level: c_int,
@@ -68,7 +233,7 @@ impl<T> Socket<T> {
         val: *const libc::c_void,
         sz: u32,
     ) -> Result<()> {
-        let res = unsafe { libc::setsockopt(self.0, level, option as c_int, val, sz) };
+        let res = unsafe { libc::setsockopt(self.fd, level, option as c_int, val, sz) };
 
         if res == -1 {
             return Err(Error::from(nix::Error::last()));
@@ -77,18 +242,179 @@ impl<T> Socket<T> {
         Ok(())
     }
 
-    fn __recv(&self, buf: &mut [u8], flags: nix::sys::socket::MsgFlags) -> Result<usize> {
-        let mut nl_buf = [0u8; 1024];
-        let end = socket::recvmsg(
-            self.0,
+    fn __set_recv_timeout(&self, timeout: std::time::Duration) -> Result<()> {
+        let to = libc::timeval {
+            tv_sec: timeout.as_secs() as libc::time_t,
+            tv_usec: libc::suseconds_t::from(timeout.subsec_micros()),
+        };
+
+        self.setsockopt(
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &to as *const libc::timeval as *const libc::c_void,
+            std::mem::size_of::<libc::timeval>() as u32,
+        )
+    }
+
+    // Receive into `buf`, and return how much was received and, from the kernel's timestamp,
+    // when the last part of it arrived.
+    fn __recv(
+        &self,
+        buf: &mut [u8],
+        flags: nix::sys::socket::MsgFlags,
+    ) -> Result<(usize, Option<SystemTime>)> {
+        let mut nl_buf = [0u8; NLMSG_MAXSIZE];
+        let mut cmsgs = nix::cmsg_space!(nix::sys::time::TimeSpec);
+        let (end, at) = match socket::recvmsg(
+            self.fd,
             &[nix::sys::uio::IoVec::from_mut_slice(&mut nl_buf[..])],
-            None,
+            Some(&mut cmsgs),
             flags,
-        )
-        .map(|r| r.bytes)
-        .map_err(Error::from)?;
-        buf[..(end - NLMSG_HDRSIZE)].copy_from_slice(&nl_buf[NLMSG_HDRSIZE..end]);
-        Ok(end - NLMSG_HDRSIZE)
+        ) {
+            Ok(r) => (r.bytes, r.cmsgs().find_map(super::kernel_timestamp)),
+            Err(e) => return self.recv_error(e).map(|n| (n, None)),
+        };
+
+        let mut partial = self
+            .partial
+            .lock()
+            .map_err(|_| Error(String::from("netlink reassembly buffer poisoned")))?;
+        Ok((reassemble(&mut partial, &nl_buf[..end], buf)?, at))
+    }
+
+    // Receive with one `recvmmsg` call, see `IpcRecv::recv_many`. Messages which fit in one
+    // netlink message are left where they were received, after its header; fragmented ones are
+    // put back together in the slot of their last fragment.
+    fn __recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<super::Received<()>>,
+        flags: nix::sys::socket::MsgFlags,
+    ) -> Result<()> {
+        let mut received = Vec::with_capacity(arena.len() / slot_len);
+        let res = super::recv_mmsg(
+            self.fd,
+            arena,
+            slot_len,
+            nix::cmsg_space!(nix::sys::time::TimeSpec).capacity(),
+            flags,
+            |_, r| received.push((r.bytes, r.cmsgs().find_map(super::kernel_timestamp))),
+        );
+        if let Err(e) = res {
+            return self.recv_error(e).map(|_| ());
+        }
+
+        let mut partial = self
+            .partial
+            .lock()
+            .map_err(|_| Error(String::from("netlink reassembly buffer poisoned")))?;
+        for (i, (end, at)) in received.into_iter().enumerate() {
+            let slot = &mut arena[i * slot_len..(i + 1) * slot_len];
+            if end < NLMSG_HDRSIZE {
+                continue;
+            }
+
+            let flags = u16_from_u8s(&slot[6..8]);
+            let (start, len) = if flags & NLM_F_MULTI == 0 && partial.is_empty() {
+                (NLMSG_HDRSIZE, end - NLMSG_HDRSIZE)
+            } else {
+                if flags & NLM_F_MULTI != 0 {
+                    partial.push(&slot[NLMSG_HDRSIZE..end], slot_len);
+                    continue;
+                }
+
+                if partial.discarding {
+                    // the last fragment of a message already dropped
+                    partial.discarding = false;
+                    continue;
+                }
+
+                partial.payload.extend_from_slice(&slot[NLMSG_HDRSIZE..end]);
+                let len = partial.payload.len();
+                if len > slot_len {
+                    debug!(
+                        len,
+                        slot_len, "dropping reassembled message larger than batch slot"
+                    );
+                    partial.payload.clear();
+                    continue;
+                }
+
+                slot[..len].copy_from_slice(&partial.payload[..]);
+                partial.payload.clear();
+                (0, len)
+            };
+
+            got.push(super::Received {
+                start: i * slot_len + start,
+                len,
+                from: (),
+                at,
+            });
+        }
+
+        Ok(())
+    }
+
+    // Whether to keep listening after a failed recv.
+    fn recv_error(&self, e: nix::errno::Errno) -> Result<usize> {
+        use nix::errno::Errno;
+        match e {
+            Errno::EAGAIN | Errno::EINTR => Ok(0),
+            Errno::ENOBUFS => {
+                self.overrun();
+                Ok(0)
+            }
+            e => Err(Error::from(e)),
+        }
+    }
+
+    // The kernel dropped messages because the receive buffer was full. Count the overrun, and
+    // grow the buffer so it happens less.
+    fn overrun(&self) {
+        self.overruns.fetch_add(1, Ordering::SeqCst);
+        // the rest of a fragmented message may be among those dropped, so don't join the next
+        // message onto its first fragments
+        let mut partial = self.partial.lock().unwrap_or_else(|e| e.into_inner());
+        if !partial.is_empty() {
+            debug!(
+                len = partial.payload.len(),
+                "dropping fragmented netlink message after overrun"
+            );
+            *partial = Partial::default();
+            self.overruns.fetch_add(1, Ordering::SeqCst);
+        }
+        drop(partial);
+
+        // the kernel reports double the size that was set, to account for its bookkeeping
+        let cur = match socket::getsockopt(self.fd, socket::sockopt::RcvBuf) {
+            Ok(b) => b / 2,
+            Err(e) => {
+                warn!(err = %e, "netlink messages dropped, could not get receive buffer size");
+                return;
+            }
+        };
+
+        let grown = std::cmp::min(cur.saturating_mul(2), self.max_rcvbuf);
+        if grown <= cur {
+            warn!(
+                bytes = cur,
+                "netlink messages dropped, receive buffer at its maximum size"
+            );
+            return;
+        }
+
+        // SO_RCVBUFFORCE ignores net.core.rmem_max, but is only allowed with CAP_NET_ADMIN
+        let set = socket::setsockopt(self.fd, socket::sockopt::RcvBufForce, &grown)
+            .or_else(|_| socket::setsockopt(self.fd, socket::sockopt::RcvBuf, &grown));
+        match set {
+            Ok(_) => info!(
+                bytes = grown,
+                "netlink messages dropped, grew receive buffer"
+            ),
+            Err(e) => warn!(err = %e, "netlink messages dropped, could not grow receive buffer"),
+        }
     }
 
     // netlink header format (RFC 3549)
@@ -104,30 +430,27 @@ impl<T> Socket<T> {
     // |                      Process ID (PID)                       |
     // +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     fn __send(&self, buf: &[u8]) -> Result<()> {
-        let len = NLMSG_HDRSIZE + buf.len();
-        let mut msg = Vec::<u8>::with_capacity(len);
-        msg.resize(4, 0u8);
-        // write the netlink header
-        super::super::serialize::u32_to_u8s(&mut msg[0..4], len as u32);
-        // rest is 0s
-        msg.extend_from_slice(&[0u8; 12]);
-        // payload
-        msg.extend_from_slice(buf);
-
-        // send
-        socket::sendmsg(
-            self.0,
-            &[nix::sys::uio::IoVec::from_slice(&msg[..])],
-            &[],
-            nix::sys::socket::MsgFlags::empty(),
-            None,
-        )
-        .map(|_| ())
-        .map_err(Error::from)
+        let _sending = self
+            .send_lock
+            .lock()
+            .map_err(|_| Error(String::from("netlink send lock poisoned")))?;
+        let dest = socket::SockAddr::new_netlink(self.dest_portid, 0);
+        for msg in fragment(buf) {
+            socket::sendmsg(
+                self.fd,
+                &[nix::sys::uio::IoVec::from_slice(&msg[..])],
+                &[],
+                nix::sys::socket::MsgFlags::empty(),
+                Some(&dest),
+            )
+            .map_err(Error::from)?;
+        }
+
+        Ok(())
     }
 
     fn __close(&mut self) -> Result<()> {
-        let ok = unsafe { libc::close(self.0) as i32 };
+        let ok = unsafe { libc::close(self.fd) as i32 };
         if ok < 0 {
             Err(Error(format!("could not close netlink socket: {}", ok)))
         } else {
@@ -136,46 +459,358 @@ impl<T> Socket<T> {
     }
 }
 
+// Split `buf` into netlink messages of at most NLMSG_MAXSIZE bytes each.
+fn fragment(buf: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = if buf.is_empty() {
+        vec![buf]
+    } else {
+        buf.chunks(NLMSG_MAXSIZE - NLMSG_HDRSIZE).collect()
+    };
+
+    let last = chunks.len() - 1;
+    chunks
+        .into_iter()
+        .enumerate()
+        .map(|(i, chunk)| {
+            let len = NLMSG_HDRSIZE + chunk.len();
+            let mut msg = vec![0u8; NLMSG_HDRSIZE];
+            u32_to_u8s(&mut msg[0..4], len as u32);
+            if i != last {
+                u16_to_u8s(&mut msg[6..8], NLM_F_MULTI);
+            }
+            // type, sequence number and pid are 0
+            msg.extend_from_slice(chunk);
+            msg
+        })
+        .collect()
+}
+
+// Add the netlink message `nl_msg` to the fragments received so far. Once the message is
+// complete, copy it into `buf` and return its length; until then, or if it does not fit in `buf`,
+// return 0.
+fn reassemble(partial: &mut Partial, nl_msg: &[u8], buf: &mut [u8]) -> Result<usize> {
+    if nl_msg.len() < NLMSG_HDRSIZE {
+        return Ok(0);
+    }
+
+    let flags = u16_from_u8s(&nl_msg[6..8]);
+    let payload = &nl_msg[NLMSG_HDRSIZE..];
+    if flags & NLM_F_MULTI != 0 {
+        partial.push(payload, buf.len());
+        return Ok(0);
+    }
+
+    if partial.discarding {
+        // the last fragment of a message already dropped
+        partial.discarding = false;
+        return Ok(0);
+    }
+
+    if partial.payload.is_empty() {
+        if payload.len() > buf.len() {
+            return Err(Error(format!(
+                "message truncated, need {} bytes",
+                payload.len()
+            )));
+        }
+
+        buf[..payload.len()].copy_from_slice(payload);
+        return Ok(payload.len());
+    }
+
+    partial.payload.extend_from_slice(payload);
+    let len = partial.payload.len();
+    if len > buf.len() {
+        debug!(
+            len,
+            buf_len = buf.len(),
+            "dropping reassembled message larger than buffer"
+        );
+        partial.payload.clear();
+        return Ok(0);
+    }
+
+    buf[..len].copy_from_slice(&partial.payload[..]);
+    partial.payload.clear();
+    Ok(len)
+}
+
+impl<T> std::os::unix::io::AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.fd
+    }
+}
+
 use super::Blocking;
-impl super::Ipc for Socket<Blocking> {
+impl super::IpcSend for Socket<Blocking> {
     type Addr = ();
 
     fn name() -> String {
         String::from("netlink")
     }
 
+    fn send(&self, buf: &[u8], _to: &Self::Addr) -> Result<()> {
+        self.__send(buf)
+    }
+}
+
+impl super::IpcRecv for Socket<Blocking> {
     fn recv(&self, buf: &mut [u8]) -> Result<(usize, Self::Addr)> {
         self.__recv(buf, nix::sys::socket::MsgFlags::empty())
-            .map(|s| (s, ()))
+            .map(|(s, _)| (s, ()))
     }
 
-    fn send(&self, buf: &[u8], _to: &Self::Addr) -> Result<()> {
-        self.__send(buf)
+    fn recv_timestamped(&self, buf: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        self.__recv(buf, nix::sys::socket::MsgFlags::empty())
+            .map(|(s, at)| (s, (), at))
+    }
+
+    /// Receives with one `recvmmsg` call if each slot can hold the largest netlink message the
+    /// socket receives, 1024 bytes, and otherwise one message at a time.
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<super::Received<Self::Addr>>,
+    ) -> Result<()> {
+        if slot_len < NLMSG_MAXSIZE {
+            return super::recv_one(self, arena, slot_len, got);
+        }
+
+        self.__recv_many(arena, slot_len, got, nix::sys::socket::MsgFlags::empty())
     }
 
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.__set_recv_timeout(timeout)
+    }
+
+    // the kernel does not say how many messages an overrun dropped, so count one for each
+    fn gaps(&self) -> u64 {
+        self.overruns.load(Ordering::SeqCst)
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(self))
+    }
 }
 
 use super::Nonblocking;
-impl super::Ipc for Socket<Nonblocking> {
+impl super::IpcSend for Socket<Nonblocking> {
     type Addr = ();
 
     fn name() -> String {
         String::from("netlink")
     }
 
+    fn send(&self, buf: &[u8], _to: &Self::Addr) -> Result<()> {
+        self.__send(buf)
+    }
+}
+
+impl super::IpcRecv for Socket<Nonblocking> {
     fn recv(&self, buf: &mut [u8]) -> Result<(usize, Self::Addr)> {
         self.__recv(buf, nix::sys::socket::MsgFlags::MSG_DONTWAIT)
-            .map(|s| (s, ()))
+            .map(|(s, _)| (s, ()))
     }
 
-    fn send(&self, buf: &[u8], _to: &Self::Addr) -> Result<()> {
-        self.__send(buf)
+    fn recv_timestamped(&self, buf: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        self.__recv(buf, nix::sys::socket::MsgFlags::MSG_DONTWAIT)
+            .map(|(s, at)| (s, (), at))
+    }
+
+    /// Receives with one `recvmmsg` call if each slot can hold the largest netlink message the
+    /// socket receives, 1024 bytes, and otherwise one message at a time.
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<super::Received<Self::Addr>>,
+    ) -> Result<()> {
+        if slot_len < NLMSG_MAXSIZE {
+            return super::recv_one(self, arena, slot_len, got);
+        }
+
+        self.__recv_many(
+            arena,
+            slot_len,
+            got,
+            nix::sys::socket::MsgFlags::MSG_DONTWAIT,
+        )
     }
 
     fn close(&mut self) -> Result<()> {
         self.__close()
     }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.__set_recv_timeout(timeout)
+    }
+
+    // the kernel does not say how many messages an overrun dropped, so count one for each
+    fn gaps(&self) -> u64 {
+        self.overruns.load(Ordering::SeqCst)
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(std::os::unix::io::AsRawFd::as_raw_fd(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{fragment, reassemble, Partial, NLMSG_MAXSIZE};
+    use crate::lang::{Bin, Prog};
+    use crate::serialize::install;
+
+    #[test]
+    fn fragment_install_roundtrip() {
+        // 15 is the most Report variables a program can have, so make each update long
+        let vars = 15;
+        let defs: String = (0..vars)
+            .map(|i| format!("(volatile v{} 0)", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let sum = (0..12).fold(String::from("Ack.bytes_acked"), |e, i| {
+            format!("(+ {} {})", e, i)
+        });
+        let binds: String = (0..vars)
+            .map(|i| format!("(bind Report.v{} (+ Report.v{} {}))", i, i, sum))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let src = format!("(def (Report {}))\n(when true\n{}\n)", defs, binds);
+        let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert!(b.instrs.len() >= 200, "{} instructions", b.instrs.len());
+
+        let m = install::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_events: b.events.len() as u32,
+            num_instrs: b.instrs.len() as u32,
+            instrs: b,
+            names: None,
+        };
+        let bytes = crate::serialize::serialize(&m).expect("serialize");
+        assert!(bytes.len() > NLMSG_MAXSIZE);
+
+        let frames = fragment(&bytes);
+        assert!(frames.len() > 1);
+        assert!(frames.iter().all(|f| f.len() <= NLMSG_MAXSIZE));
+
+        let mut partial = Partial::default();
+        let mut buf = [0u8; 8192];
+        let (last, rest) = frames.split_last().unwrap();
+        for f in rest {
+            assert_eq!(reassemble(&mut partial, f, &mut buf), Ok(0));
+        }
+
+        let len = reassemble(&mut partial, last, &mut buf).expect("reassemble");
+        assert_eq!(&buf[..len], &bytes[..]);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn unfinished_message_capped() {
+        let mut partial = Partial::default();
+        let mut buf = [0u8; 2048];
+        let frames = fragment(&[7u8; 4096]);
+        let (last, rest) = frames.split_last().unwrap();
+        for f in rest {
+            assert_eq!(reassemble(&mut partial, f, &mut buf), Ok(0));
+            assert!(partial.payload.len() <= buf.len());
+        }
+
+        // the rest of the dropped message is dropped too
+        assert_eq!(reassemble(&mut partial, last, &mut buf), Ok(0));
+        assert!(partial.is_empty());
+
+        // and the next message arrives whole
+        let frames = fragment(&[8u8; 1500]);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(reassemble(&mut partial, &frames[0], &mut buf), Ok(0));
+        let len = reassemble(&mut partial, &frames[1], &mut buf).expect("reassemble");
+        assert_eq!(&buf[..len], &[8u8; 1500][..]);
+    }
+
+    #[test]
+    fn enobufs_grows_rcvbuf() {
+        use super::Socket;
+        use crate::ipc::{Blocking, IpcRecv};
+        use nix::sys::socket::{getsockopt, sockopt::RcvBuf};
+        use std::os::unix::io::AsRawFd;
+
+        // netlink sockets may not be available, e.g. in a container
+        let mut sk = match Socket::<Blocking>::new() {
+            Ok(sk) => sk,
+            Err(_) => return,
+        };
+        let fd = sk.as_raw_fd();
+        let rcvbuf = || getsockopt(fd, RcvBuf).expect("get rcvbuf") / 2;
+        let initial = rcvbuf();
+        sk.set_max_rcvbuf(initial * 3);
+
+        // as if recv failed because the kernel dropped messages
+        let enobufs = |sk: &Socket<Blocking>| sk.recv_error(nix::errno::Errno::ENOBUFS);
+        assert_eq!(enobufs(&sk).expect("keep listening"), 0);
+        assert_eq!(rcvbuf(), initial * 2);
+        enobufs(&sk).expect("keep listening");
+        assert_eq!(rcvbuf(), initial * 3);
+        // at the ceiling
+        enobufs(&sk).expect("keep listening");
+        assert_eq!(rcvbuf(), initial * 3);
+        assert_eq!(sk.gaps(), 3);
+
+        assert!(sk.recv_error(nix::errno::Errno::EBADF).is_err());
+    }
+
+    #[test]
+    fn enobufs_drops_partial_message() {
+        use super::Socket;
+        use crate::ipc::{Blocking, IpcRecv};
+
+        // netlink sockets may not be available, e.g. in a container
+        let sk = match Socket::<Blocking>::new() {
+            Ok(sk) => sk,
+            Err(_) => return,
+        };
+
+        let frames = fragment(&[7u8; 1500]);
+        let mut buf = [0u8; 2048];
+        {
+            let mut partial = sk.partial.lock().unwrap();
+            assert_eq!(reassemble(&mut partial, &frames[0], &mut buf), Ok(0));
+        }
+
+        sk.recv_error(nix::errno::Errno::ENOBUFS)
+            .expect("keep listening");
+        assert!(sk.partial.lock().unwrap().is_empty());
+        // the overrun, and the message it cut short
+        assert_eq!(sk.gaps(), 2);
+    }
+
+    #[test]
+    fn small_message_not_fragmented() {
+        let frames = fragment(b"hello");
+        assert_eq!(frames.len(), 1);
+
+        let mut partial = Partial::default();
+        let mut buf = [0u8; 64];
+        let len = reassemble(&mut partial, &frames[0], &mut buf).expect("reassemble");
+        assert_eq!(&buf[..len], b"hello");
+    }
+
+    #[test]
+    fn message_larger_than_buffer() {
+        let frames = fragment(&[7u8; 600]);
+        assert_eq!(frames.len(), 1);
+
+        let mut partial = Partial::default();
+        let mut buf = [0u8; 64];
+        let err = reassemble(&mut partial, &frames[0], &mut buf).expect_err("too long");
+        assert_eq!(err.0, "message truncated, need 600 bytes");
+    }
 }
@@ -0,0 +1,96 @@
+//! Service several backends, possibly of different IPC types, from one thread.
+//!
+//! Register each `Backend` with a `Poller`, then call `Poller::wait()` to learn which of them
+//! have messages waiting and drain those with `Backend::try_next()`.
+//!
+//! Readiness is edge-triggered: after `wait()` reports a backend, keep calling `try_next()`
+//! until it returns `Ok(None)`, or the remaining messages will not be reported again. The
+//! sockets must be `Nonblocking`, so that `try_next()` returns as soon as the socket is drained.
+//!
+//! ```rust,no_run
+//! use portus::ipc::{poll::Poller, unix, udp, Backend, Nonblocking};
+//! use std::sync::{atomic, Arc};
+//!
+//! let mut ubuf = [0u8; 1024];
+//! let mut u = Backend::new(
+//!     unix::Socket::<Nonblocking>::new("portus").unwrap(),
+//!     Arc::new(atomic::AtomicBool::new(true)),
+//!     &mut ubuf[..],
+//! );
+//! let mut dbuf = [0u8; 1024];
+//! let mut d = Backend::new(
+//!     udp::Socket::<Nonblocking>::new("0.0.0.0:4242").unwrap(),
+//!     Arc::new(atomic::AtomicBool::new(true)),
+//!     &mut dbuf[..],
+//! );
+//!
+//! let mut poller = Poller::new().unwrap();
+//! let ut = poller.register(&u).unwrap();
+//! let dt = poller.register(&d).unwrap();
+//! loop {
+//!     for t in poller.wait(None).unwrap() {
+//!         if t == ut {
+//!             while let Some((msg, addr)) = u.try_next().unwrap() { /* ... */ }
+//!         } else if t == dt {
+//!             while let Some((msg, addr)) = d.try_next().unwrap() { /* ... */ }
+//!         }
+//!     }
+//! }
+//! ```
+
+use super::{Error, Result};
+use mio::unix::SourceFd;
+use mio::{Events, Interest, Token};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
+
+/// Identifies a registered backend in the results of `Poller::wait()`.
+pub type PollToken = usize;
+
+pub struct Poller {
+    poll: mio::Poll,
+    events: Events,
+    registered: Vec<RawFd>,
+}
+
+impl Poller {
+    pub fn new() -> Result<Self> {
+        Ok(Poller {
+            poll: mio::Poll::new()?,
+            events: Events::with_capacity(64),
+            registered: Vec::new(),
+        })
+    }
+
+    /// Watch `backend` (or anything else with a file descriptor) for incoming messages.
+    pub fn register<S: AsRawFd>(&mut self, backend: &S) -> Result<PollToken> {
+        let fd = backend.as_raw_fd();
+        if self.registered.contains(&fd) {
+            return Err(Error(format!("fd {} is already registered", fd)));
+        }
+
+        let token = self.registered.len();
+        self.poll
+            .registry()
+            .register(&mut SourceFd(&fd), Token(token), Interest::READABLE)?;
+        self.registered.push(fd);
+        Ok(token)
+    }
+
+    /// Wait up to `timeout` (or forever, if `None`) for registered backends to become readable,
+    /// and return their tokens, each once and in the order they were registered. Returns no
+    /// tokens if the timeout passed, or the wait was interrupted by a signal.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Vec<PollToken>> {
+        match self.poll.poll(&mut self.events, timeout) {
+            Ok(()) => (),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => return Ok(vec![]),
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        let mut ready: Vec<PollToken> = self.events.iter().map(|e| e.token().0).collect();
+        // an fd can show up in more than one event
+        ready.sort_unstable();
+        ready.dedup();
+        Ok(ready)
+    }
+}
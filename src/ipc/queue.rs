@@ -0,0 +1,674 @@
+//! Receive on a separate thread into a bounded queue, so that a slow algorithm neither lets
+//! messages pile up without limit nor leaves the kernel to drop them unnoticed.
+//!
+//! The send side of a `Backend` can likewise be queued, with
+//! [`Backend::spawn_sender`](../struct.Backend.html#method.spawn_sender).
+//!
+//! ```no_run
+//! use portus::ipc::{queue, unix, BackendBuilder, Blocking};
+//!
+//! let sock = queue::Socket::new(
+//!     unix::Socket::<Blocking>::new("portus").unwrap(),
+//!     1024,
+//!     queue::Overflow::DropOldest,
+//! )
+//! .unwrap();
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::{
+    BackendStats, Error, IpcRecv, IpcSend, Priority, RateLimitPolicy, Result, SendRateLimit,
+    SendStatus,
+};
+use crossbeam::channel;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, warn};
+
+// How often a reader blocked on a full queue checks whether the socket is closing.
+const BLOCK_POLL: Duration = Duration::from_millis(100);
+// The reader thread's buffer, as large as any datagram. A message too long for the `Backend`'s
+// buffer is cut off when it is taken from the queue, as a datagram socket would.
+const READ_BUF_SIZE: usize = 1 << 16;
+
+/// What to do with a message that arrives when the queue is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the message that just arrived.
+    DropNewest,
+    /// Drop the oldest queued message to make room.
+    DropOldest,
+    /// Stop reading from the socket until there is room. Messages then back up in the socket's
+    /// own buffer, where the transport may drop them or make the datapath wait.
+    Block,
+}
+
+// A message, who sent it, and when it arrived.
+type Item<A> = Result<(Vec<u8>, A, SystemTime)>;
+
+// The thread filling a queue from a socket, and the receiving end of the queue. The thread also
+// signals on `timeouts` when the socket's receive times out, so that the queue's receive can
+// wait just as long.
+pub(super) struct Reader<A> {
+    rx: channel::Receiver<Item<A>>,
+    timeouts: channel::Receiver<()>,
+    dropped: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<()>>,
+    // whether the socket is a stream, whose reads must not be cut off
+    stream: bool,
+    // the part of a read from a stream socket which did not fit in the last `recv`'s buffer
+    rest: Mutex<Option<(Vec<u8>, A, SystemTime)>>,
+}
+
+impl<A: Send + 'static> Reader<A> {
+    // Start receiving from `sk` into a queue of `capacity` messages, handling a full queue
+    // according to `overflow`.
+    pub(super) fn spawn<S>(sk: Arc<S>, capacity: usize, overflow: Overflow) -> Result<Self>
+    where
+        S: IpcRecv<Addr = A> + Sync,
+    {
+        if capacity == 0 {
+            return Err(Error(String::from(
+                "receive queue capacity must be positive",
+            )));
+        }
+
+        let (tx, rx) = channel::bounded(capacity);
+        let (timeouts_tx, timeouts) = channel::bounded(1);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let (oldest, dropped, stop) = (rx.clone(), dropped.clone(), stop.clone());
+            thread::Builder::new()
+                .name(format!("portus-queue-{}", S::name()))
+                .spawn(move || read(&*sk, &tx, &oldest, &timeouts_tx, overflow, &dropped, &stop))
+                .map_err(|e| Error(format!("could not start receive queue thread: {}", e)))?
+        };
+
+        Ok(Reader {
+            rx,
+            timeouts,
+            dropped,
+            stop,
+            thread: Some(thread),
+            stream: S::is_stream(),
+            rest: Mutex::new(None),
+        })
+    }
+}
+
+impl<A: Clone + Default> Reader<A> {
+    // Take the next message from the queue, waiting up to `timeout` (or, if `None`, until the
+    // socket's own receive times out), with when the thread received it.
+    pub(super) fn recv(
+        &self,
+        msg: &mut [u8],
+        timeout: Option<Duration>,
+    ) -> Result<(usize, A, Option<SystemTime>)> {
+        let rest = self.rest.lock().unwrap_or_else(|e| e.into_inner()).take();
+        if let Some(rest) = rest {
+            return Ok(self.copy_out(rest, msg));
+        }
+
+        let item = match (self.rx.try_recv(), timeout) {
+            (Ok(item), _) => Ok(item),
+            (Err(_), Some(t)) => channel::select! {
+                recv(self.rx) -> item => item,
+                recv(self.timeouts) -> _ => return Ok((0, Default::default(), None)),
+                default(t) => return Ok((0, Default::default(), None)),
+            },
+            (Err(_), None) => channel::select! {
+                recv(self.rx) -> item => item,
+                recv(self.timeouts) -> _ => return Ok((0, Default::default(), None)),
+            },
+        };
+        let item = item.map_err(|_| Error(String::from("receive queue thread stopped")))??;
+        Ok(self.copy_out(item, msg))
+    }
+
+    // Copy what fits of a message into `msg`. Like a datagram socket, cut off the rest, unless
+    // the socket is a stream: then keep it for the next `recv`.
+    fn copy_out(
+        &self,
+        (buf, from, at): (Vec<u8>, A, SystemTime),
+        msg: &mut [u8],
+    ) -> (usize, A, Option<SystemTime>) {
+        let len = std::cmp::min(buf.len(), msg.len());
+        msg[..len].copy_from_slice(&buf[..len]);
+        if self.stream && len < buf.len() {
+            *self.rest.lock().unwrap_or_else(|e| e.into_inner()) =
+                Some((buf[len..].to_vec(), from.clone(), at));
+        }
+
+        (len, from, Some(at))
+    }
+}
+
+impl<A> Reader<A> {
+    // The number of messages dropped because the queue was full.
+    pub(super) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    // Stop the thread, and wait for it to let go of the socket.
+    pub(super) fn stop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap_or(());
+        }
+    }
+}
+
+impl<A> Drop for Reader<A> {
+    fn drop(&mut self) {
+        // don't wait for the thread: it stops by itself within the socket's receive timeout
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+type Outgoing<A> = (Vec<u8>, A);
+
+// The sending ends of a `Writer`'s queues. `BackendSender`s hold weak references, so the
+// queues close once the `Writer` lets go.
+pub(super) struct Outbox<A> {
+    urgent: channel::Sender<Outgoing<A>>,
+    normal: channel::Sender<Outgoing<A>>,
+}
+
+impl<A> Outbox<A> {
+    // Queue `msg`, waiting for room if the queue for `priority` is full.
+    pub(super) fn push(&self, msg: &[u8], to: A, priority: Priority) -> Result<()> {
+        let q = match priority {
+            Priority::Urgent => &self.urgent,
+            Priority::Normal => &self.normal,
+        };
+
+        q.send((msg.to_vec(), to))
+            .map_err(|_| Error(String::from("send queue thread stopped")))
+    }
+}
+
+// The thread sending the messages queued in an `Outbox`, urgent ones first.
+pub(super) struct Writer<A> {
+    outbox: Arc<Outbox<A>>,
+    thread: thread::JoinHandle<()>,
+}
+
+impl<A: Send + 'static> Writer<A> {
+    // Start sending on `sk` from two queues of `capacity` messages each, counting the messages
+    // in `stats`.
+    pub(super) fn spawn<S>(sk: Arc<S>, capacity: usize, stats: Arc<BackendStats>) -> Result<Self>
+    where
+        S: IpcSend<Addr = A> + Sync,
+    {
+        if capacity == 0 {
+            return Err(Error(String::from("send queue capacity must be positive")));
+        }
+
+        let (urgent, urgent_rx) = channel::bounded(capacity);
+        let (normal, normal_rx) = channel::bounded(capacity);
+        let thread = thread::Builder::new()
+            .name(format!("portus-send-{}", S::name()))
+            .spawn(move || write(&*sk, &urgent_rx, &normal_rx, &stats))
+            .map_err(|e| Error(format!("could not start send queue thread: {}", e)))?;
+        Ok(Writer {
+            outbox: Arc::new(Outbox { urgent, normal }),
+            thread,
+        })
+    }
+}
+
+impl<A> Writer<A> {
+    pub(super) fn outbox(&self) -> std::sync::Weak<Outbox<A>> {
+        Arc::downgrade(&self.outbox)
+    }
+
+    // Close the queues, and wait for the thread to send what is left in them.
+    pub(super) fn stop(self) {
+        drop(self.outbox);
+        self.thread.join().unwrap_or(());
+    }
+}
+
+// Send queued messages on `sk`, preferring `urgent` ones, until both queues are closed and empty.
+fn write<S: IpcSend>(
+    sk: &S,
+    urgent: &channel::Receiver<Outgoing<S::Addr>>,
+    normal: &channel::Receiver<Outgoing<S::Addr>>,
+    stats: &BackendStats,
+) {
+    loop {
+        let item = match urgent.try_recv() {
+            Ok(item) => Ok(item),
+            Err(_) => channel::select! {
+                recv(urgent) -> item => item,
+                recv(normal) -> item => item,
+            }
+            // one queue is closed and empty, but the other may not be empty yet
+            .or_else(|_| urgent.try_recv())
+            .or_else(|_| normal.try_recv()),
+        };
+        let (msg, to) = match item {
+            Ok(item) => item,
+            Err(_) => return,
+        };
+
+        match sk.send(&msg, &to) {
+            Ok(_) => BackendStats::incr(&stats.sent),
+            Err(e) => {
+                BackendStats::incr(&stats.send_errors);
+                warn!(err = ?e, ?to, "queued send failed");
+            }
+        }
+    }
+}
+
+// Messages a send could not send without blocking, oldest first, held until the `Backend`
+// retries them (see `Backend::set_pending_budget`). A budget of 0 turns holding messages off.
+#[derive(Default)]
+pub(super) struct Pending<A> {
+    frames: Mutex<PendingFrames<A>>,
+    budget: AtomicUsize,
+}
+
+struct PendingFrames<A> {
+    q: VecDeque<Outgoing<A>>,
+    bytes: usize,
+}
+
+impl<A> Default for PendingFrames<A> {
+    fn default() -> Self {
+        PendingFrames {
+            q: VecDeque::new(),
+            bytes: 0,
+        }
+    }
+}
+
+impl<A: Clone> Pending<A> {
+    pub(super) fn set_budget(&self, bytes: usize) {
+        self.budget.store(bytes, Ordering::SeqCst);
+    }
+
+    // Whether sends have to go through `send`: holding messages is on, or some are still held.
+    pub(super) fn holding(&self) -> bool {
+        self.budget.load(Ordering::SeqCst) > 0
+            || !self
+                .frames
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .q
+                .is_empty()
+    }
+
+    pub(super) fn bytes(&self) -> usize {
+        self.frames.lock().unwrap_or_else(|e| e.into_inner()).bytes
+    }
+
+    // Send `msg` on `sk`, or if it would block, hold on to it for `flush` to send later. Messages
+    // already held go first, so the datapath gets them all in order. A message sent right away is
+    // counted in `stats`; one held is counted once `flush` sends it.
+    pub(super) fn send<S: IpcSend<Addr = A>>(
+        &self,
+        sk: &S,
+        msg: &[u8],
+        to: &A,
+        stats: &BackendStats,
+    ) -> Result<SendStatus> {
+        let mut frames = self.frames.lock().unwrap_or_else(|e| e.into_inner());
+        if !frames.q.is_empty() {
+            flush(sk, &mut frames, stats);
+        }
+
+        if frames.q.is_empty() {
+            match sk.send(msg, to) {
+                Ok(_) => return Ok(SendStatus::Sent),
+                Err(e) if e != super::send_timed_out() => return Err(e),
+                Err(_) => (),
+            }
+        }
+
+        let budget = self.budget.load(Ordering::SeqCst);
+        if budget == 0 {
+            return Err(super::send_timed_out());
+        }
+
+        if frames.bytes + msg.len() > budget {
+            return Err(Error::from(crate::SendQueueFullError));
+        }
+
+        frames.bytes += msg.len();
+        frames.q.push_back((msg.to_vec(), to.clone()));
+        Ok(SendStatus::Queued)
+    }
+
+    // Send as many held messages as the socket takes without blocking, and return how many are
+    // still held.
+    pub(super) fn flush<S: IpcSend<Addr = A>>(&self, sk: &S, stats: &BackendStats) -> usize {
+        let mut frames = self.frames.lock().unwrap_or_else(|e| e.into_inner());
+        flush(sk, &mut frames, stats);
+        frames.q.len()
+    }
+}
+
+// Send held messages in order until one would block. A message which fails otherwise is dropped,
+// as a direct send of it would have been.
+fn flush<S: IpcSend>(sk: &S, frames: &mut PendingFrames<S::Addr>, stats: &BackendStats) {
+    while let Some((msg, to)) = frames.q.front() {
+        match sk.send(msg, to) {
+            Ok(_) => BackendStats::incr(&stats.sent),
+            Err(e) if e == super::send_timed_out() => return,
+            Err(e) => {
+                BackendStats::incr(&stats.send_errors);
+                warn!(err = ?e, ?to, "pending send failed");
+            }
+        }
+
+        frames.bytes -= msg.len();
+        frames.q.pop_front();
+    }
+}
+
+// The token bucket behind `Backend::set_send_rate_limit`, shared by the `Backend`'s senders. It
+// starts full, holds at most `burst` tokens, and gains `msgs_per_sec` of them a second.
+#[derive(Default)]
+pub(super) struct Limiter {
+    bucket: Mutex<Option<Bucket>>,
+}
+
+struct Bucket {
+    limit: SendRateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Limiter {
+    pub(super) fn set(&self, limit: Option<SendRateLimit>) {
+        *self.bucket.lock().unwrap_or_else(|e| e.into_inner()) = limit.map(|limit| Bucket {
+            limit,
+            tokens: f64::from(limit.burst),
+            last: Instant::now(),
+        });
+    }
+
+    pub(super) fn limiting(&self) -> bool {
+        self.bucket
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_some()
+    }
+
+    // Take a token for a message sent at `priority`. If there is none, wait for one or fail with
+    // `RateLimitedError`, according to the limit's policy.
+    pub(super) fn take(&self, priority: Priority) -> Result<()> {
+        let wait = {
+            let mut bucket = self.bucket.lock().unwrap_or_else(|e| e.into_inner());
+            let b = match bucket.as_mut() {
+                Some(b) if !(b.limit.exempt_urgent && priority == Priority::Urgent) => b,
+                _ => return Ok(()),
+            };
+
+            let now = Instant::now();
+            let rate = f64::from(b.limit.msgs_per_sec);
+            b.tokens = f64::min(
+                b.tokens + now.duration_since(b.last).as_secs_f64() * rate,
+                f64::from(b.limit.burst),
+            );
+            b.last = now;
+            if b.tokens >= 1.0 {
+                b.tokens -= 1.0;
+                return Ok(());
+            }
+
+            if b.limit.policy == RateLimitPolicy::Reject {
+                return Err(Error::from(crate::RateLimitedError));
+            }
+
+            // take the token now, so that senders waiting at the same time are spaced out too
+            b.tokens -= 1.0;
+            Duration::from_secs_f64(-b.tokens / rate)
+        };
+
+        thread::sleep(wait);
+        Ok(())
+    }
+}
+
+/// Passes sends through to the wrapped socket, and receives from a queue of at most `capacity`
+/// messages, which a thread fills from the wrapped socket.
+///
+/// Dropped messages are counted in `dropped()`, and in `gaps()` along with the wrapped socket's
+/// own. The wrapped socket's receive timeout sets how long `close()` may wait for the thread
+/// to stop.
+pub struct Socket<S: IpcSend> {
+    inner: Arc<S>,
+    reader: Reader<S::Addr>,
+    recv_timeout: Duration,
+}
+
+impl<S> Socket<S>
+where
+    S: IpcRecv + Sync,
+    S::Addr: Send,
+{
+    /// Start receiving from `inner` into a queue of `capacity` messages, handling a full queue
+    /// according to `overflow`.
+    pub fn new(inner: S, capacity: usize, overflow: Overflow) -> Result<Self> {
+        let inner = Arc::new(inner);
+        Ok(Socket {
+            reader: Reader::spawn(inner.clone(), capacity, overflow)?,
+            inner,
+            recv_timeout: Duration::from_secs(1),
+        })
+    }
+}
+
+impl<S: IpcSend> Socket<S> {
+    /// The number of messages dropped because the queue was full.
+    pub fn dropped(&self) -> u64 {
+        self.reader.dropped()
+    }
+}
+
+// Move messages from `sk` into the queue until `stop` is set or `sk` fails.
+fn read<S: IpcRecv>(
+    sk: &S,
+    tx: &channel::Sender<Item<S::Addr>>,
+    oldest: &channel::Receiver<Item<S::Addr>>,
+    timeouts: &channel::Sender<()>,
+    overflow: Overflow,
+    dropped: &AtomicU64,
+    stop: &AtomicBool,
+) {
+    let mut buf = vec![0u8; READ_BUF_SIZE];
+    while !stop.load(Ordering::SeqCst) {
+        let mut item = match sk.recv_timestamped(&mut buf) {
+            Ok((0, _, _)) => {
+                // at most one is pending, so this fails if the last has not been seen yet
+                timeouts.try_send(()).unwrap_or(());
+                continue;
+            }
+            // the message waits in the queue, so now is closer to when it arrived than when it
+            // is taken out
+            Ok((len, from, at)) => Ok((
+                buf[..len].to_vec(),
+                from,
+                at.unwrap_or_else(SystemTime::now),
+            )),
+            Err(e) => {
+                // pass on the failure, then stop
+                tx.send(Err(e)).unwrap_or(());
+                return;
+            }
+        };
+
+        loop {
+            item = match (tx.try_send(item), overflow) {
+                (Ok(_), _) | (Err(channel::TrySendError::Disconnected(_)), _) => break,
+                (Err(channel::TrySendError::Full(_)), Overflow::DropNewest) => {
+                    let n = dropped.fetch_add(1, Ordering::SeqCst) + 1;
+                    debug!(dropped = n, "receive queue full, dropping newest message");
+                    break;
+                }
+                (Err(channel::TrySendError::Full(i)), Overflow::DropOldest) => {
+                    // the consumer may have made room in the meantime
+                    if oldest.try_recv().is_ok() {
+                        let n = dropped.fetch_add(1, Ordering::SeqCst) + 1;
+                        debug!(dropped = n, "receive queue full, dropping oldest message");
+                    }
+
+                    i
+                }
+                (Err(channel::TrySendError::Full(i)), Overflow::Block) => {
+                    match tx.send_timeout(i, BLOCK_POLL) {
+                        Ok(_) | Err(channel::SendTimeoutError::Disconnected(_)) => break,
+                        Err(channel::SendTimeoutError::Timeout(_))
+                            if stop.load(Ordering::SeqCst) =>
+                        {
+                            return
+                        }
+                        Err(channel::SendTimeoutError::Timeout(i)) => i,
+                    }
+                }
+            };
+        }
+    }
+}
+
+impl<S> IpcSend for Socket<S>
+where
+    S: IpcRecv + Sync,
+    S::Addr: Send,
+{
+    type Addr = S::Addr;
+
+    fn name() -> String {
+        format!("queue({})", S::name())
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        self.inner.send(msg, to)
+    }
+
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        self.inner.send_many(msgs, to)
+    }
+}
+
+impl<S> IpcRecv for Socket<S>
+where
+    S: IpcRecv + Sync,
+    S::Addr: Send,
+{
+    /// Unlike most sockets, the thread filling the queue allocates each message it receives.
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        let (len, from, _) = self.reader.recv(msg, Some(self.recv_timeout))?;
+        Ok((len, from))
+    }
+
+    /// The time is when the thread received the message.
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        self.reader.recv(msg, Some(self.recv_timeout))
+    }
+
+    fn is_stream() -> bool {
+        S::is_stream()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.reader.stop();
+        Arc::get_mut(&mut self.inner)
+            .ok_or_else(|| Error(String::from("receive queue thread still has the socket")))?
+            .close()
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+
+    fn gaps(&self) -> u64 {
+        self.inner.gaps() + self.dropped()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.inner.reconnects()
+    }
+
+    fn failovers(&self) -> u64 {
+        self.inner.failovers()
+    }
+}
+
+#[cfg(unix)]
+impl<S: IpcSend + std::os::unix::io::AsRawFd> std::os::unix::io::AsRawFd for Socket<S> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Overflow, Socket};
+    use crate::ipc::{chan, Blocking, IpcRecv};
+    use crossbeam::channel;
+    use std::time::{Duration, Instant};
+
+    // Queue messages 1 to 5 with room for 2, without receiving any until the reader thread has
+    // dealt with all it can, then return the messages received and the number dropped.
+    fn fill(overflow: Overflow) -> (Vec<u8>, u64) {
+        let (to_dp, _from_ccp) = channel::unbounded();
+        let (to_ccp, from_dp) = channel::unbounded();
+        let mut sk = Socket::new(chan::Socket::<Blocking>::new(to_dp, from_dp), 2, overflow)
+            .expect("start queue");
+        for i in 1..=5u8 {
+            to_ccp.send(vec![i]).unwrap();
+        }
+
+        // the reader has taken everything from the socket, or is blocked holding one message
+        let settled = |sk: &Socket<_>| match overflow {
+            Overflow::Block => to_ccp.len() == 2,
+            _ => sk.dropped() == 3,
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !settled(&sk) {
+            assert!(Instant::now() < deadline, "reader did not fill the queue");
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        std::thread::sleep(Duration::from_millis(50));
+
+        sk.set_recv_timeout(Duration::from_millis(100)).unwrap();
+        let mut got = vec![];
+        let mut buf = [0u8; 8];
+        loop {
+            match sk.recv(&mut buf).expect("recv") {
+                (0, _) => break,
+                (_, _) => got.push(buf[0]),
+            }
+        }
+
+        let dropped = sk.dropped();
+        assert_eq!(sk.gaps(), dropped);
+        sk.close().expect("close");
+        (got, dropped)
+    }
+
+    #[test]
+    fn drop_newest() {
+        assert_eq!(fill(Overflow::DropNewest), (vec![1, 2], 3));
+    }
+
+    #[test]
+    fn drop_oldest() {
+        assert_eq!(fill(Overflow::DropOldest), (vec![4, 5], 3));
+    }
+
+    #[test]
+    fn block() {
+        assert_eq!(fill(Overflow::Block), (vec![1, 2, 3, 4, 5], 0));
+    }
+}
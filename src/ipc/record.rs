@@ -0,0 +1,271 @@
+//! Record every message a socket sends and receives to a file, e.g. to capture the message
+//! stream behind a bug report and run it again later with [`replay`](../replay/index.html).
+//!
+//! ```no_run
+//! use portus::ipc::{record, unix, BackendBuilder, Blocking};
+//!
+//! let sock = record::Socket::new(
+//!     unix::Socket::<Blocking>::new("portus").unwrap(),
+//!     "/tmp/portus-session.rec",
+//! )
+//! .unwrap();
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::{Error, IpcRecv, IpcSend, Received, Result};
+use crate::serialize::{u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s};
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+use tracing::warn;
+
+// Each message is preceded by whether it was sent or received, the time since recording started
+// in nanoseconds, and its length.
+//
+// |-----------|-----------|--------|---------|
+// | Direction | Time      | Length | Message |
+// | u8        | u64       | u32    |         |
+// |-----------|-----------|--------|---------|
+const FRAME_HDR_LEN: usize = 13;
+
+/// Whether a recorded message was sent to or received from the datapath.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A recorded message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Frame {
+    pub dir: Direction,
+    /// When the message was sent or received, since the recording started.
+    pub at: Duration,
+    pub msg: Vec<u8>,
+}
+
+/// Read back the messages recorded in `path`, in the order they were recorded.
+pub fn read_frames<P: AsRef<Path>>(path: P) -> Result<Vec<Frame>> {
+    let mut buf = vec![];
+    File::open(path.as_ref())
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .map_err(|e| {
+            Error(format!(
+                "could not read recording {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+
+    let mut frames = vec![];
+    let mut rest = &buf[..];
+    while !rest.is_empty() {
+        if rest.len() < FRAME_HDR_LEN {
+            return Err(Error(String::from(
+                "recording ends in a partial frame header",
+            )));
+        }
+
+        let dir = match rest[0] {
+            0 => Direction::Sent,
+            1 => Direction::Received,
+            d => return Err(Error(format!("invalid direction {} in recording", d))),
+        };
+        let at = Duration::from_nanos(u64_from_u8s(&rest[1..9]));
+        let len = u32_from_u8s(&rest[9..13]) as usize;
+        rest = &rest[FRAME_HDR_LEN..];
+        if rest.len() < len {
+            return Err(Error(String::from("recording ends in a partial message")));
+        }
+
+        frames.push(Frame {
+            dir,
+            at,
+            msg: rest[..len].to_vec(),
+        });
+        rest = &rest[len..];
+    }
+
+    Ok(frames)
+}
+
+/// Passes everything through to the wrapped socket, and writes each message sent or received
+/// to a file.
+///
+/// Each message is flushed to the file as it is recorded, so the recording survives the process
+/// being killed. If writing to the file fails, the message is still delivered and the failure is
+/// logged.
+pub struct Socket<S> {
+    inner: S,
+    out: Mutex<BufWriter<File>>,
+    start: Instant,
+}
+
+impl<S> Socket<S> {
+    /// Record `inner`'s messages to `path`, replacing the file if it exists.
+    pub fn new<P: AsRef<Path>>(inner: S, path: P) -> Result<Self> {
+        let f = File::create(path.as_ref()).map_err(|e| {
+            Error(format!(
+                "could not create recording {}: {}",
+                path.as_ref().display(),
+                e
+            ))
+        })?;
+
+        Ok(Socket {
+            inner,
+            out: Mutex::new(BufWriter::new(f)),
+            start: Instant::now(),
+        })
+    }
+
+    fn write_frame(&self, dir: Direction, msg: &[u8]) {
+        let mut hdr = [0u8; FRAME_HDR_LEN];
+        hdr[0] = match dir {
+            Direction::Sent => 0,
+            Direction::Received => 1,
+        };
+        u64_to_u8s(&mut hdr[1..9], self.start.elapsed().as_nanos() as u64);
+        u32_to_u8s(&mut hdr[9..13], msg.len() as u32);
+
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        let written = out
+            .write_all(&hdr[..])
+            .and_then(|_| out.write_all(msg))
+            .and_then(|_| out.flush());
+        if let Err(e) = written {
+            warn!(err = %e, ?dir, "could not record message");
+        }
+    }
+}
+
+impl<S: IpcSend> IpcSend for Socket<S> {
+    type Addr = S::Addr;
+
+    fn name() -> String {
+        format!("record({})", S::name())
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        self.inner.send(msg, to)?;
+        self.write_frame(Direction::Sent, msg);
+        Ok(())
+    }
+
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        let (sent, res) = self.inner.send_many(msgs, to);
+        for msg in &msgs[..sent] {
+            self.write_frame(Direction::Sent, msg);
+        }
+
+        (sent, res)
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_send_timeout(timeout)
+    }
+}
+
+impl<S: IpcRecv> IpcRecv for Socket<S> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        let (len, from, _) = self.recv_timestamped(msg)?;
+        Ok((len, from))
+    }
+
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        let (len, from, at) = self.inner.recv_timestamped(msg)?;
+        if len > 0 {
+            self.write_frame(Direction::Received, &msg[..len]);
+        }
+
+        Ok((len, from, at))
+    }
+
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<Received<Self::Addr>>,
+    ) -> Result<()> {
+        let before = got.len();
+        self.inner.recv_many(arena, slot_len, got)?;
+        for m in &got[before..] {
+            self.write_frame(Direction::Received, &arena[m.start..m.start + m.len]);
+        }
+
+        Ok(())
+    }
+
+    fn is_stream() -> bool {
+        S::is_stream()
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.out
+            .get_mut()
+            .unwrap_or_else(|e| e.into_inner())
+            .flush()?;
+        self.inner.close()
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.inner.set_recv_timeout(timeout)
+    }
+
+    fn gaps(&self) -> u64 {
+        self.inner.gaps()
+    }
+
+    fn reconnects(&self) -> u64 {
+        self.inner.reconnects()
+    }
+
+    fn failovers(&self) -> u64 {
+        self.inner.failovers()
+    }
+
+    #[cfg(unix)]
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        self.inner.raw_fd()
+    }
+}
+
+#[cfg(unix)]
+impl<S: std::os::unix::io::AsRawFd> std::os::unix::io::AsRawFd for Socket<S> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frames, Direction, Socket};
+    use crate::ipc::{chan, Blocking, IpcRecv, IpcSend};
+    use crossbeam::channel;
+
+    #[test]
+    fn records_both_directions() {
+        let path = std::env::temp_dir().join("portus-test-record-basic");
+        let (s1, r1) = channel::unbounded();
+        let (s2, r2) = channel::unbounded();
+        let sk = Socket::new(chan::Socket::<Blocking>::new(s1, r2), &path).expect("record");
+
+        s2.send(vec![1, 2, 3]).unwrap();
+        let mut buf = [0u8; 8];
+        let (len, _) = sk.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], &[1, 2, 3]);
+        sk.send(&[4, 5], &()).expect("send");
+        assert_eq!(r1.recv().unwrap(), vec![4, 5]);
+
+        let frames = read_frames(&path).expect("read recording");
+        std::fs::remove_file(&path).unwrap_or(());
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].dir, Direction::Received);
+        assert_eq!(frames[0].msg, vec![1, 2, 3]);
+        assert_eq!(frames[1].dir, Direction::Sent);
+        assert_eq!(frames[1].msg, vec![4, 5]);
+        assert!(frames[0].at <= frames[1].at);
+    }
+}
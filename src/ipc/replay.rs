@@ -0,0 +1,99 @@
+//! Play back a session captured with [`record`](../record/index.html), e.g. to run an
+//! algorithm against the exact message stream from a bug report.
+//!
+//! The replay socket delivers each recorded received message in order, and discards whatever
+//! CCP sends. Once it runs out of messages, `recv` fails with `DatapathGoneError`, so `run()`
+//! returns after closing the replayed flows.
+//!
+//! ```no_run
+//! use portus::ipc::{replay, BackendBuilder};
+//!
+//! let sock = replay::Socket::new("/tmp/portus-session.rec", false).unwrap();
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::record::{read_frames, Direction, Frame};
+use super::{Error, Result};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+pub struct Socket {
+    // only the received messages
+    frames: Vec<Frame>,
+    next: AtomicUsize,
+    fast: bool,
+    start: Instant,
+    recv_timeout: Duration,
+}
+
+impl Socket {
+    /// Replay the messages received in the recording at `path`.
+    ///
+    /// Messages are delivered with the timing they were originally received with, relative to
+    /// when this socket is created, or as fast as they are read if `fast` is set.
+    pub fn new<P: AsRef<Path>>(path: P, fast: bool) -> Result<Self> {
+        let frames = read_frames(path)?
+            .into_iter()
+            .filter(|f| f.dir == Direction::Received)
+            .collect();
+        Ok(Socket {
+            frames,
+            next: AtomicUsize::new(0),
+            fast,
+            start: Instant::now(),
+            recv_timeout: Duration::from_secs(1),
+        })
+    }
+}
+
+impl super::IpcSend for Socket {
+    /// Recordings do not keep addresses: replayed messages all come from the same datapath.
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("replay")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl super::IpcRecv for Socket {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        let next = self.next.load(Ordering::SeqCst);
+        let f = match self.frames.get(next) {
+            Some(f) => f,
+            None => return Err(Error::from(crate::DatapathGoneError)),
+        };
+
+        if !self.fast {
+            let now = Instant::now();
+            let due = self.start + f.at;
+            if due > now {
+                if due - now > self.recv_timeout {
+                    std::thread::sleep(self.recv_timeout);
+                    return Ok((0, ()));
+                }
+
+                std::thread::sleep(due - now);
+            }
+        }
+
+        // like a datagram socket, cut off a message that does not fit
+        let len = std::cmp::min(f.msg.len(), msg.len());
+        msg[..len].copy_from_slice(&f.msg[..len]);
+        self.next.store(next + 1, Ordering::SeqCst);
+        Ok((len, ()))
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+}
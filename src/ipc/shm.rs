@@ -0,0 +1,622 @@
+//! A pair of single-producer/single-consumer ring buffers in a shared memory mapping.
+//!
+//! The creator of the mapping (CCP, via `new` or `with_capacity`) sends on the first ring and
+//! receives on the second; the other end (the datapath, via `connect`) does the opposite.
+//!
+//! Mapping layout:
+//!
+//! |-------------|-----------------|-----------------|---------------|---------------|
+//! | File header | Ring 0 header   | Ring 1 header   | Ring 0 data   | Ring 1 data   |
+//! | 64 B        | 128 B           | 128 B           | capacity B    | capacity B    |
+//! |-------------|-----------------|-----------------|---------------|---------------|
+//!
+//! Each message in a ring is preceded by its length as a little-endian u32. The ring indices
+//! count bytes and wrap around at `u32::MAX`, so a frame may be split across the end of the data
+//! region.
+
+use super::{Error, Result};
+use crate::serialize::{u32_from_u8s, u32_to_u8s};
+use std::fs::{File, OpenOptions};
+use std::marker::PhantomData;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const SHM_MAGIC: u32 = 0x4343_5052; // "CCPR"
+const FRAME_HDR_LEN: usize = 4;
+const FILE_HDR_LEN: usize = 64;
+const RING_HDR_LEN: usize = 128;
+
+/// Default size of each ring's data region.
+pub const DEFAULT_RING_CAPACITY: usize = 1 << 16;
+
+// how long a send waits for space in the ring, and by default a blocking recv waits for a message.
+const TIMEOUT: Duration = Duration::from_secs(1);
+// how many times to poll the ring before sleeping in the kernel.
+const SPIN_LIMIT: usize = 1000;
+
+#[repr(C)]
+struct FileHdr {
+    magic: AtomicU32,
+    capacity: AtomicU32,
+}
+
+// The producer and consumer indices are on separate cache lines.
+#[repr(C)]
+struct RingHdr {
+    // written by the producer
+    tail: AtomicU32,
+    // set by the consumer while it sleeps on `tail`
+    waiting: AtomicU32,
+    // set by the producer once it will send no more
+    closed: AtomicU32,
+    _pad0: [u8; 52],
+    // written by the consumer
+    head: AtomicU32,
+    _pad1: [u8; 60],
+}
+
+struct Ring {
+    hdr: *const RingHdr,
+    data: *mut u8,
+    capacity: usize,
+}
+
+impl Ring {
+    fn hdr(&self) -> &RingHdr {
+        unsafe { &*self.hdr }
+    }
+
+    fn used(&self) -> usize {
+        let hdr = self.hdr();
+        hdr.tail
+            .load(Ordering::Acquire)
+            .wrapping_sub(hdr.head.load(Ordering::Acquire)) as usize
+    }
+
+    fn copy_in(&self, pos: u32, src: &[u8]) {
+        let off = pos as usize & (self.capacity - 1);
+        let first = std::cmp::min(src.len(), self.capacity - off);
+        unsafe {
+            std::ptr::copy_nonoverlapping(src.as_ptr(), self.data.add(off), first);
+            std::ptr::copy_nonoverlapping(src[first..].as_ptr(), self.data, src.len() - first);
+        }
+    }
+
+    fn copy_out(&self, pos: u32, dst: &mut [u8]) {
+        let off = pos as usize & (self.capacity - 1);
+        let first = std::cmp::min(dst.len(), self.capacity - off);
+        let rest = dst.len() - first;
+        unsafe {
+            std::ptr::copy_nonoverlapping(self.data.add(off), dst.as_mut_ptr(), first);
+            std::ptr::copy_nonoverlapping(self.data, dst[first..].as_mut_ptr(), rest);
+        }
+    }
+
+    // Write one frame. Returns false if there is not enough space in the ring for it.
+    fn push(&self, msg: &[u8]) -> bool {
+        let hdr = self.hdr();
+        let need = FRAME_HDR_LEN + msg.len();
+        if self.capacity - self.used() < need {
+            return false;
+        }
+
+        let tail = hdr.tail.load(Ordering::Relaxed);
+        let mut len_buf = [0u8; FRAME_HDR_LEN];
+        u32_to_u8s(&mut len_buf, msg.len() as u32);
+        self.copy_in(tail, &len_buf);
+        self.copy_in(tail.wrapping_add(FRAME_HDR_LEN as u32), msg);
+        hdr.tail
+            .store(tail.wrapping_add(need as u32), Ordering::SeqCst);
+        if hdr.waiting.load(Ordering::SeqCst) != 0 {
+            futex_wake(&hdr.tail);
+        }
+
+        true
+    }
+
+    // Read one frame into `msg`, if there is one.
+    // Frames too large for `msg` are dropped, and reported as an empty read.
+    // The peer writes the indices and frame lengths, so a frame which claims more than the ring
+    // holds is an error: nothing after it can be trusted.
+    fn pop(&self, msg: &mut [u8]) -> Result<Option<usize>> {
+        let hdr = self.hdr();
+        let used = self.used();
+        if used > self.capacity {
+            return Err(Error(format!(
+                "corrupt shm ring: {} bytes used of {}",
+                used, self.capacity
+            )));
+        }
+
+        if used < FRAME_HDR_LEN {
+            return Ok(None);
+        }
+
+        let head = hdr.head.load(Ordering::Relaxed);
+        let mut len_buf = [0u8; FRAME_HDR_LEN];
+        self.copy_out(head, &mut len_buf);
+        let len = u32_from_u8s(&len_buf) as usize;
+        if len > used - FRAME_HDR_LEN {
+            return Err(Error(format!(
+                "corrupt shm ring: frame of {} bytes, but only {} bytes follow it",
+                len,
+                used - FRAME_HDR_LEN
+            )));
+        }
+
+        if len <= msg.len() {
+            self.copy_out(head.wrapping_add(FRAME_HDR_LEN as u32), &mut msg[..len]);
+        } else {
+            warn!(
+                frame_len = len,
+                buf_len = msg.len(),
+                "dropping shm frame larger than receive buffer"
+            );
+        }
+
+        hdr.head.store(
+            head.wrapping_add((FRAME_HDR_LEN + len) as u32),
+            Ordering::Release,
+        );
+        if len <= msg.len() {
+            Ok(Some(len))
+        } else {
+            Ok(Some(0))
+        }
+    }
+
+    // Sleep until the producer advances `tail` past `seen`, or until `timeout` passes.
+    fn wait(&self, seen: u32, timeout: Duration) {
+        let hdr = self.hdr();
+        hdr.waiting.store(1, Ordering::SeqCst);
+        if hdr.tail.load(Ordering::SeqCst) == seen {
+            futex_wait(&hdr.tail, seen, timeout);
+        }
+
+        hdr.waiting.store(0, Ordering::SeqCst);
+    }
+}
+
+fn futex_wait(word: &AtomicU32, expected: u32, timeout: Duration) {
+    let ts = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: libc::c_long::from(timeout.subsec_nanos()),
+    };
+    // The mapping is shared between processes, so this cannot be a FUTEX_PRIVATE_FLAG futex.
+    // Spurious wakeups, EINTR, and EAGAIN (the value already changed) are all handled by the
+    // caller rechecking the ring.
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAIT,
+            expected,
+            &ts as *const libc::timespec,
+            std::ptr::null::<u32>(),
+            0,
+        );
+    }
+}
+
+fn futex_wake(word: &AtomicU32) {
+    unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word as *const AtomicU32,
+            libc::FUTEX_WAKE,
+            1,
+            std::ptr::null::<libc::timespec>(),
+            std::ptr::null::<u32>(),
+            0,
+        );
+    }
+}
+
+pub struct Socket<T> {
+    _file: File,
+    map: *mut libc::c_void,
+    map_len: usize,
+    send_ring: Ring,
+    recv_ring: Ring,
+    // other threads in this process may hold a `BackendSender`; keep each ring single-producer
+    // and single-consumer.
+    send_lock: Mutex<()>,
+    recv_lock: Mutex<()>,
+    recv_timeout: Duration,
+    _phantom: PhantomData<T>,
+}
+
+// The rings are only touched through the lock-protected operations above.
+unsafe impl<T> Send for Socket<T> {}
+unsafe impl<T> Sync for Socket<T> {}
+
+impl<T> Socket<T> {
+    fn map(file: File, capacity: usize, creator: bool) -> Result<Self> {
+        let map_len = FILE_HDR_LEN + 2 * RING_HDR_LEN + 2 * capacity;
+        let map = unsafe {
+            nix::sys::mman::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                nix::sys::mman::ProtFlags::PROT_READ | nix::sys::mman::ProtFlags::PROT_WRITE,
+                nix::sys::mman::MapFlags::MAP_SHARED,
+                file.as_raw_fd(),
+                0,
+            )?
+        };
+
+        let base = map as *mut u8;
+        let ring = |i: usize| unsafe {
+            Ring {
+                hdr: base.add(FILE_HDR_LEN + i * RING_HDR_LEN) as *const RingHdr,
+                data: base.add(FILE_HDR_LEN + 2 * RING_HDR_LEN + i * capacity),
+                capacity,
+            }
+        };
+
+        let (send_ring, recv_ring) = if creator {
+            (ring(0), ring(1))
+        } else {
+            (ring(1), ring(0))
+        };
+
+        Ok(Socket {
+            _file: file,
+            map,
+            map_len,
+            send_ring,
+            recv_ring,
+            send_lock: Mutex::new(()),
+            recv_lock: Mutex::new(()),
+            recv_timeout: TIMEOUT,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn file_hdr(&self) -> &FileHdr {
+        unsafe { &*(self.map as *const FileHdr) }
+    }
+
+    fn create<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        if !capacity.is_power_of_two() || !(2 * FRAME_HDR_LEN..=1 << 31).contains(&capacity) {
+            return Err(Error(format!(
+                "shm ring capacity must be a power of two between 8 and 2^31 bytes, got {}",
+                capacity
+            )));
+        }
+
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path.as_ref())
+            .map_err(|e| Error(format!("shm create {:?} failed: {}", path.as_ref(), e)))?;
+        // zero-filled, so both rings start out empty
+        file.set_len((FILE_HDR_LEN + 2 * RING_HDR_LEN + 2 * capacity) as u64)?;
+
+        let sk = Self::map(file, capacity, true)?;
+        let hdr = sk.file_hdr();
+        hdr.capacity.store(capacity as u32, Ordering::Relaxed);
+        hdr.magic.store(SHM_MAGIC, Ordering::Release);
+        Ok(sk)
+    }
+
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())
+            .map_err(|e| Error(format!("shm open {:?} failed: {}", path.as_ref(), e)))?;
+
+        let mut hdr = [0u8; 8];
+        nix::sys::uio::pread(file.as_raw_fd(), &mut hdr, 0)?;
+        if u32_from_u8s(&hdr[0..4]) != SHM_MAGIC {
+            return Err(Error(format!(
+                "{:?} is not an initialized shm ring",
+                path.as_ref()
+            )));
+        }
+
+        let capacity = u32_from_u8s(&hdr[4..8]) as usize;
+        let len = file.metadata()?.len() as usize;
+        if !capacity.is_power_of_two() || len < FILE_HDR_LEN + 2 * RING_HDR_LEN + 2 * capacity {
+            return Err(Error(format!(
+                "shm ring {:?} has invalid capacity {}",
+                path.as_ref(),
+                capacity
+            )));
+        }
+
+        Self::map(file, capacity, false)
+    }
+
+    fn __name() -> String {
+        String::from("shm")
+    }
+
+    fn __send(&self, msg: &[u8]) -> Result<()> {
+        if FRAME_HDR_LEN + msg.len() > self.send_ring.capacity {
+            return Err(Error(format!(
+                "message of {} bytes does not fit in {} byte shm ring",
+                msg.len(),
+                self.send_ring.capacity
+            )));
+        }
+
+        let _g = self
+            .send_lock
+            .lock()
+            .map_err(|_| Error(String::from("shm send lock poisoned")))?;
+        let start = Instant::now();
+        while !self.send_ring.push(msg) {
+            if self.recv_ring.hdr().closed.load(Ordering::Acquire) != 0 {
+                return Err(Error(String::from("shm peer closed")));
+            }
+
+            if start.elapsed() > TIMEOUT {
+                return Err(Error(String::from("shm send ring full")));
+            }
+
+            std::thread::yield_now();
+        }
+
+        Ok(())
+    }
+
+    // `block`: whether to wait up to `recv_timeout` for a message to arrive.
+    fn __recv(&self, msg: &mut [u8], block: bool) -> Result<(usize, ())> {
+        let _g = self
+            .recv_lock
+            .lock()
+            .map_err(|_| Error(String::from("shm recv lock poisoned")))?;
+        let start = Instant::now();
+        let mut spins = 0;
+        loop {
+            if let Some(len) = self.recv_ring.pop(msg)? {
+                return Ok((len, ()));
+            }
+
+            if self.recv_ring.hdr().closed.load(Ordering::Acquire) != 0 {
+                return Err(Error(String::from("shm peer closed")));
+            }
+
+            let elapsed = start.elapsed();
+            if !block || elapsed > self.recv_timeout {
+                return Ok((0, ()));
+            }
+
+            if spins < SPIN_LIMIT {
+                spins += 1;
+                std::hint::spin_loop();
+                continue;
+            }
+
+            let seen = self.recv_ring.hdr().tail.load(Ordering::SeqCst);
+            self.recv_ring.wait(seen, self.recv_timeout - elapsed);
+        }
+    }
+
+    fn __close(&mut self) -> Result<()> {
+        let hdr = self.send_ring.hdr();
+        hdr.closed.store(1, Ordering::Release);
+        futex_wake(&hdr.tail);
+        Ok(())
+    }
+}
+
+impl<T> Drop for Socket<T> {
+    fn drop(&mut self) {
+        unsafe {
+            nix::sys::mman::munmap(self.map, self.map_len).unwrap_or(());
+        }
+    }
+}
+
+use super::Blocking;
+impl super::IpcSend for Socket<Blocking> {
+    type Addr = ();
+
+    fn name() -> String {
+        Self::__name()
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        self.__send(msg)
+    }
+}
+
+impl super::IpcRecv for Socket<Blocking> {
+    /// Polls the ring briefly, then sleeps on a futex until the peer sends a message.
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        self.__recv(msg, true)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.__close()
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Socket<Blocking> {
+    /// Create (or truncate) the shared memory file at `path` (e.g. "/dev/shm/ccp") with rings of
+    /// `DEFAULT_RING_CAPACITY` bytes.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create(path, DEFAULT_RING_CAPACITY)
+    }
+
+    /// Create (or truncate) the shared memory file at `path` with rings of `capacity` bytes,
+    /// which must be a power of two.
+    pub fn with_capacity<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        Self::create(path, capacity)
+    }
+
+    /// Attach to the other end of rings created by `new` or `with_capacity`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path)
+    }
+}
+
+use super::Nonblocking;
+impl super::IpcSend for Socket<Nonblocking> {
+    type Addr = ();
+
+    fn name() -> String {
+        Self::__name()
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        self.__send(msg)
+    }
+}
+
+impl super::IpcRecv for Socket<Nonblocking> {
+    /// Returns immediately if no message is waiting, so `Backend` spins on the ring.
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        self.__recv(msg, false)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        self.__close()
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+}
+
+impl Socket<Nonblocking> {
+    /// Create (or truncate) the shared memory file at `path` (e.g. "/dev/shm/ccp") with rings of
+    /// `DEFAULT_RING_CAPACITY` bytes.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create(path, DEFAULT_RING_CAPACITY)
+    }
+
+    /// Create (or truncate) the shared memory file at `path` with rings of `capacity` bytes,
+    /// which must be a power of two.
+    pub fn with_capacity<P: AsRef<Path>>(path: P, capacity: usize) -> Result<Self> {
+        Self::create(path, capacity)
+    }
+
+    /// Attach to the other end of rings created by `new` or `with_capacity`.
+    pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use crate::ipc::{Blocking, IpcRecv, IpcSend, Nonblocking};
+
+    fn path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("portus-test-shm-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn wraparound() {
+        let p = path("wrap");
+        // 7 bytes per frame does not divide the ring, so frames straddle the end.
+        let a = Socket::<Nonblocking>::with_capacity(&p, 16).expect("create");
+        let b = Socket::<Nonblocking>::connect(&p).expect("connect");
+        let mut buf = [0u8; 16];
+        for i in 0..20u8 {
+            a.send(&[i, i, i], &()).expect("send");
+            let (len, _) = b.recv(&mut buf).expect("recv");
+            assert_eq!(&buf[..len], &[i, i, i]);
+        }
+
+        assert_eq!(b.recv(&mut buf).expect("recv").0, 0);
+        std::fs::remove_file(p).unwrap();
+    }
+
+    #[test]
+    fn full_and_oversized() {
+        let p = path("full");
+        let a = Socket::<Nonblocking>::with_capacity(&p, 16).expect("create");
+        let b = Socket::<Nonblocking>::connect(&p).expect("connect");
+        assert!(a.send(&[0u8; 13], &()).is_err());
+
+        a.send(&[1u8; 8], &()).expect("send");
+        let mut buf = [0u8; 4];
+        // the frame does not fit in the receive buffer, so it is dropped
+        assert_eq!(b.recv(&mut buf).expect("recv").0, 0);
+        a.send(&[2u8; 4], &()).expect("send");
+        assert_eq!(b.recv(&mut buf).expect("recv").0, 4);
+        assert_eq!(buf, [2u8; 4]);
+        std::fs::remove_file(p).unwrap();
+    }
+
+    #[test]
+    fn bogus_frame_len() {
+        use std::sync::atomic::Ordering;
+
+        let p = path("bogus");
+        let a = Socket::<Nonblocking>::with_capacity(&p, 16).expect("create");
+        let b = Socket::<Nonblocking>::connect(&p).expect("connect");
+        let mut buf = [0u8; 1024];
+        // a frame header claiming more bytes than follow it, or than the ring holds
+        for claimed in &[3u32, 1000, u32::MAX] {
+            let ring = &a.send_ring;
+            let tail = ring.hdr().tail.load(Ordering::Relaxed);
+            let mut len_buf = [0u8; super::FRAME_HDR_LEN];
+            crate::serialize::u32_to_u8s(&mut len_buf, *claimed);
+            ring.copy_in(tail, &len_buf);
+            ring.copy_in(tail.wrapping_add(super::FRAME_HDR_LEN as u32), &[7, 7]);
+            ring.hdr().tail.store(
+                tail.wrapping_add(super::FRAME_HDR_LEN as u32 + 2),
+                Ordering::SeqCst,
+            );
+            assert!(b.recv(&mut buf).is_err(), "{}", claimed);
+            // nothing was consumed: the ring stays corrupt
+            assert!(b.recv(&mut buf).is_err(), "{}", claimed);
+            ring.hdr().tail.store(tail, Ordering::SeqCst);
+        }
+
+        // indices further apart than the ring is long
+        a.send_ring.hdr().tail.store(1000, Ordering::SeqCst);
+        assert!(b.recv(&mut buf).is_err());
+        std::fs::remove_file(p).unwrap();
+    }
+
+    #[test]
+    fn blocking_wakeup_and_close() {
+        let p = path("block");
+        let mut a = Socket::<Blocking>::new(&p).expect("create");
+        let b = Socket::<Blocking>::connect(&p).expect("connect");
+        let t = std::thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let (len, _) = b.recv(&mut buf).expect("recv");
+            assert_eq!(&buf[..len], b"hello");
+            // the peer closes after sending
+            loop {
+                match b.recv(&mut buf) {
+                    Ok((0, _)) => continue,
+                    Ok(_) => unreachable!(),
+                    Err(_) => break,
+                }
+            }
+        });
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        a.send(b"hello", &()).expect("send");
+        a.close().expect("close");
+        t.join().expect("join receiver");
+        std::fs::remove_file(p).unwrap();
+    }
+
+    #[test]
+    fn bad_capacity() {
+        let p = path("cap");
+        assert!(Socket::<Blocking>::with_capacity(&p, 100).is_err());
+        assert!(Socket::<Blocking>::connect(&p).is_err());
+    }
+}
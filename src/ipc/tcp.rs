@@ -0,0 +1,234 @@
+use super::{Error, Result};
+use crate::serialize::{u32_from_u8s, u32_to_u8s};
+use crate::DatapathGoneError;
+use nix::errno::Errno;
+use std::io::prelude::*;
+use std::marker::PhantomData;
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Each message on the stream is preceded by its length as a little-endian u32.
+//
+// |-----------|-------------------|
+// | Len (B)   | Message           |
+// | u32       | Len bytes         |
+// |-----------|-------------------|
+const FRAME_HDR_LEN: usize = 4;
+
+// don't raise SIGPIPE if the peer has gone away; the error is returned instead.
+#[cfg(target_os = "linux")]
+pub(super) const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(target_os = "linux"))]
+pub(super) const SEND_FLAGS: libc::c_int = 0;
+
+// Pop the first complete frame off `pending` into `msg`, if there is one.
+// A frame too large for `msg` is an error as soon as its header arrives, rather than once the
+// peer has sent, and `pending` has buffered, however much it claims. The stream cannot be read
+// past it, so the error stays until the socket is closed.
+pub(super) fn take_frame(pending: &mut Vec<u8>, msg: &mut [u8]) -> Result<Option<usize>> {
+    if pending.len() < FRAME_HDR_LEN {
+        return Ok(None);
+    }
+
+    let len = u32_from_u8s(&pending[..FRAME_HDR_LEN]) as usize;
+    if len > msg.len() {
+        return Err(Error(format!(
+            "frame of {} bytes does not fit in {} byte buffer",
+            len,
+            msg.len()
+        )));
+    }
+
+    if pending.len() < FRAME_HDR_LEN + len {
+        return Ok(None);
+    }
+
+    msg[..len].copy_from_slice(&pending[FRAME_HDR_LEN..(FRAME_HDR_LEN + len)]);
+    pending.drain(..(FRAME_HDR_LEN + len));
+    Ok(Some(len))
+}
+
+// Write `msg` to the stream socket `fd` as one frame, giving up if none of it could be written
+// within `timeout`.
+pub(super) fn send_frame(fd: RawFd, msg: &[u8], timeout: Option<Duration>) -> Result<()> {
+    let mut frame = Vec::with_capacity(FRAME_HDR_LEN + msg.len());
+    frame.resize(FRAME_HDR_LEN, 0u8);
+    u32_to_u8s(&mut frame[..FRAME_HDR_LEN], msg.len() as u32);
+    frame.extend_from_slice(msg);
+    send_all(fd, &frame, timeout)
+}
+
+// Write all of `frame` to the stream socket `fd`, giving up if none of it could be written within
+// `timeout`.
+pub(super) fn send_all(fd: RawFd, frame: &[u8], timeout: Option<Duration>) -> Result<()> {
+    let deadline = timeout.map(|t| Instant::now() + t);
+
+    // loop until the whole frame is written, so that a short write never leaves half a
+    // frame on the stream.
+    let mut sent = 0;
+    while sent < frame.len() {
+        let rest = &frame[sent..];
+        let res = unsafe {
+            libc::send(
+                fd,
+                rest.as_ptr() as *const libc::c_void,
+                rest.len(),
+                SEND_FLAGS,
+            )
+        };
+
+        if res > 0 {
+            sent += res as usize;
+            continue;
+        } else if res == 0 {
+            return Err(Error::from(DatapathGoneError));
+        }
+
+        match Errno::last() {
+            Errno::EINTR => continue,
+            Errno::EAGAIN => {
+                // once part of the frame is written, the rest must follow, or the stream is
+                // corrupted; so the timeout only applies until the frame has started.
+                let wait_ms = match deadline {
+                    Some(d) if sent == 0 => match d.checked_duration_since(Instant::now()) {
+                        Some(left) if !left.is_zero() => {
+                            left.as_millis().clamp(1, 1000) as libc::c_int
+                        }
+                        _ => return Err(super::send_timed_out()),
+                    },
+                    _ => 1000,
+                };
+                let pollfd = nix::poll::PollFd::new(fd, nix::poll::PollFlags::POLLOUT);
+                nix::poll::poll(&mut [pollfd], wait_ms)?;
+            }
+            // the peer went away, e.g. the remote host or VM rebooted
+            Errno::ECONNRESET | Errno::EPIPE | Errno::ENOTCONN => {
+                return Err(Error::from(DatapathGoneError))
+            }
+            e => return Err(Error(format!("send failed: {}", e))),
+        }
+    }
+
+    Ok(())
+}
+
+pub struct Socket<T> {
+    sk: TcpStream,
+    // bytes read off the stream which do not yet form a complete frame
+    pending: Mutex<Vec<u8>>,
+    send_timeout: Option<Duration>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new(sk: TcpStream) -> Result<Self> {
+        sk.set_nodelay(true)?;
+        sk.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        Ok(Socket {
+            sk,
+            pending: Mutex::new(Vec::new()),
+            send_timeout: None,
+            _phantom: PhantomData,
+        })
+    }
+
+    fn connect<A: ToSocketAddrs>(addr: A) -> Result<TcpStream> {
+        TcpStream::connect(addr).map_err(|e| Error(format!("tcp connect failed: {}", e)))
+    }
+
+    fn __recv(&self, msg: &mut [u8]) -> Result<(usize, ())> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error(String::from("tcp receive buffer poisoned")))?;
+        loop {
+            if let Some(len) = take_frame(&mut pending, msg)? {
+                return Ok((len, ()));
+            }
+
+            // use the caller's buffer as scratch space for the read
+            match (&self.sk).read(msg) {
+                Ok(0) => return Err(Error(String::from("tcp connection closed by peer"))),
+                Ok(n) => pending.extend_from_slice(&msg[..n]),
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+                Err(e) if super::is_transient(&e) => return Ok((0, ())),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("tcp")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        send_frame(self.sk.as_raw_fd(), msg, self.send_timeout)
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.sk.set_write_timeout(Some(timeout))?;
+        self.send_timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        self.__recv(msg)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.sk.shutdown(Shutdown::Both) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotConnected => Err(Error::from(e)),
+            _ => Ok(()),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.sk.set_read_timeout(Some(timeout)).map_err(Error::from)
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sk.as_raw_fd()
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Connect to a datapath listening on `addr` (e.g. "10.0.0.2:4242").
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Socket::__new(Self::connect(addr)?)
+    }
+
+    /// Use an already-established connection, e.g. one returned by `TcpListener::accept()`.
+    pub fn from_stream(sk: TcpStream) -> Result<Self> {
+        Socket::__new(sk)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Connect to a datapath listening on `addr` (e.g. "10.0.0.2:4242").
+    pub fn new<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::from_stream(Self::connect(addr)?)
+    }
+
+    /// Use an already-established connection, e.g. one returned by `TcpListener::accept()`.
+    pub fn from_stream(sk: TcpStream) -> Result<Self> {
+        let sk = Socket::__new(sk)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+}
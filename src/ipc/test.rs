@@ -1,5 +1,5 @@
 use super::Blocking;
-use super::Ipc;
+use super::{IpcRecv, IpcSend};
 use crate::serialize;
 use crate::serialize::Msg;
 use crate::test_helper::TestMsg;
@@ -14,9 +14,14 @@ impl FakeIpc {
     pub fn new() -> Self {
         FakeIpc(Arc::new(Mutex::new(Vec::new())))
     }
+
+    // What has been sent, one message after another.
+    pub(crate) fn sent(&self) -> Vec<u8> {
+        self.0.lock().unwrap().clone()
+    }
 }
 
-impl Ipc for FakeIpc {
+impl IpcSend for FakeIpc {
     type Addr = ();
 
     fn name() -> String {
@@ -28,7 +33,9 @@ impl Ipc for FakeIpc {
         (*x).extend(msg);
         Ok(())
     }
+}
 
+impl IpcRecv for FakeIpc {
     // return the number of bytes read if successful.
     fn recv(&self, msg: &mut [u8]) -> super::Result<(usize, Self::Addr)> {
         use std::cmp;
@@ -55,7 +62,7 @@ fn test_unix() {
         let b2 = super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
         let test_msg = TestMsg(String::from("hello, world"));
         let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
-        b2.sender(std::path::PathBuf::from("portus-test-unix-2"))
+        b2.sender(std::path::PathBuf::from("portus-test-unix-2").into())
             .send_msg(&test_msg_buf[..])
             .expect("send message");
     });
@@ -110,3 +117,2111 @@ fn test_chan() {
 
     c2.join().expect("join sender thread");
 }
+
+#[test]
+fn test_recv_truncated() {
+    // enough instructions that the install message does not fit in the default buffer
+    let fields: Vec<String> = (0..15).map(|i| format!("(volatile f{} 0)", i)).collect();
+    let binds: Vec<String> = (0..80)
+        .map(|i| format!("(bind Report.f{} {})", i % 15, i))
+        .collect();
+    let prog = format!(
+        "(def (Report {}))\n(when true\n{}\n)",
+        fields.join(" "),
+        binds.join("\n")
+    );
+    let (bin, sc) = crate::lang::compile(prog.as_bytes(), &[]).expect("compile");
+    let msg = serialize::install::Msg {
+        sid: 1,
+        program_uid: sc.program_uid,
+        num_events: bin.events.len() as u32,
+        num_instrs: bin.instrs.len() as u32,
+        instrs: bin,
+        names: None,
+    };
+    let buf = serialize::serialize(&msg).expect("serialize install msg");
+    assert!(buf.len() > super::DEFAULT_RECV_BUF_SIZE);
+
+    let sk = FakeIpc::new();
+    sk.send(&buf, &()).expect("send");
+    let mut rbuf = [0u8; super::DEFAULT_RECV_BUF_SIZE];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    match b.try_next() {
+        Err(e) => assert_eq!(e.0, format!("message truncated, need {} bytes", buf.len())),
+        Ok(m) => panic!("expected truncation error, got {:?}", m.map(|(m, _)| m)),
+    }
+
+    assert_eq!(b.stats().recv_errors(), 1);
+    assert_eq!(b.stats().received(), 0);
+}
+
+// A byte stream that returns at most `chunk` bytes per read, like a character device.
+struct StreamIpc {
+    buf: Mutex<std::collections::VecDeque<u8>>,
+    chunk: usize,
+}
+
+impl IpcSend for StreamIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("stream")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<(), super::Error> {
+        self.buf.lock().unwrap().extend(msg);
+        Ok(())
+    }
+}
+
+impl IpcRecv for StreamIpc {
+    fn recv(&self, msg: &mut [u8]) -> super::Result<(usize, Self::Addr)> {
+        let mut x = self.buf.lock().unwrap();
+        let w = std::cmp::min(std::cmp::min(msg.len(), self.chunk), x.len());
+        for (i, b) in x.drain(..w).enumerate() {
+            msg[i] = b;
+        }
+
+        Ok((w, ()))
+    }
+
+    fn is_stream() -> bool {
+        true
+    }
+
+    fn close(&mut self) -> Result<(), super::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_stream_one_byte_reads() {
+    // portus does not parse install messages, which it only sends, so use a create message
+    let msg = serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    };
+    let buf = serialize::serialize(&msg).expect("serialize create msg");
+
+    let sk = StreamIpc {
+        buf: Mutex::new(buf.iter().cloned().collect()),
+        chunk: 1,
+    };
+    let mut rbuf = [0u8; super::DEFAULT_RECV_BUF_SIZE];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    let (expected, _) = Msg::from_buf(&buf[..]).expect("parse whole message");
+    match b.try_next().expect("receive message") {
+        Some((m, ())) => assert_eq!(m, expected),
+        None => panic!("expected create message"),
+    }
+
+    assert_eq!(b.stats().received(), 1);
+    assert!(b.try_next().expect("receive").is_none());
+}
+
+#[test]
+fn test_stream_split_messages() {
+    use crate::serialize::AsRawMsg;
+    let words = ["hello", "portus", "a longer third message"];
+    let msgs: Vec<Vec<u8>> = words
+        .iter()
+        .map(|s| serialize::serialize(&TestMsg(String::from(*s))).expect("serialize test msg"))
+        .collect();
+
+    // reads that end partway through one message and start partway through the next
+    let sk = StreamIpc {
+        buf: Mutex::new(msgs.iter().flatten().cloned().collect()),
+        chunk: 7,
+    };
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    for w in &words {
+        match b.try_next().expect("receive message") {
+            Some((Msg::Other(raw), ())) => {
+                assert_eq!(TestMsg::from_raw_msg(raw).expect("parse test msg").0, *w)
+            }
+            m => panic!("expected unknown message, got {:?}", m.map(|(m, _)| m)),
+        }
+    }
+
+    assert_eq!(b.stats().received(), 3);
+}
+
+#[test]
+fn test_backend_stats() {
+    let sk = FakeIpc::new();
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    let test_msg = TestMsg(String::from("hello, world"));
+    let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+    b.sender(())
+        .send_msg(&test_msg_buf[..])
+        .expect("send message");
+    match b.try_next().expect("receive message") {
+        Some((Msg::Other(_), ())) => (),
+        m => panic!("expected unknown message, got {:?}", m.map(|(m, _)| m)),
+    }
+
+    let stats = b.stats().clone();
+    assert_eq!(stats.sent(), 1);
+    assert_eq!(stats.received(), 1);
+    assert_eq!(stats.unknown_msgs(), 1);
+    assert_eq!(stats.send_errors(), 0);
+    assert_eq!(stats.recv_errors(), 0);
+    assert_eq!(stats.gaps(), 0);
+
+    b.stats().reset();
+    assert_eq!(b.stats().sent(), 0);
+    assert_eq!(b.stats().received(), 0);
+    assert_eq!(b.stats().unknown_msgs(), 0);
+    // the clone is a snapshot, so it is unaffected
+    assert_eq!(stats.sent(), 1);
+}
+
+#[test]
+fn test_backend_malformed_msgs() {
+    use super::{chan, Nonblocking};
+    use crossbeam::channel;
+
+    let (s1, _r1) = channel::unbounded();
+    let (s2, r2) = channel::unbounded();
+    let sk = chan::Socket::<Nonblocking>::new(s1, r2);
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    let bad = Arc::new(Mutex::new(vec![]));
+    let bad1 = bad.clone();
+    b.on_parse_error(move |msg, err| bad1.lock().unwrap().push((msg.to_vec(), err.clone())));
+
+    let test_msg =
+        serialize::serialize(&TestMsg(String::from("hello, world"))).expect("serialize test msg");
+    // cut off in the header, and in the body
+    let short_hdr = test_msg[..6].to_vec();
+    let short_body = test_msg[..test_msg.len() - 2].to_vec();
+    // a capabilities message needs a longer body than a heartbeat's
+    let mut wrong_type =
+        serialize::serialize(&serialize::heartbeat::Msg { seq: 1 }).expect("serialize heartbeat");
+    wrong_type[0] = serialize::capabilities::CAPABILITIES;
+    let wrong_type = serialize::checksum::fix_up(wrong_type);
+    for m in &[&test_msg, &short_hdr, &short_body, &wrong_type, &test_msg] {
+        s2.send(m.to_vec()).unwrap();
+    }
+
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Other(_), ())))));
+    for _ in 0..3 {
+        match b.try_next() {
+            Err(e) => assert_eq!(e, super::Error::from(crate::MalformedMsgError)),
+            Ok(m) => panic!("expected a parse error, got {:?}", m.map(|(m, _)| m)),
+        }
+    }
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Other(_), ())))));
+    assert!(matches!(b.try_next(), Ok(None)));
+
+    let bad = bad.lock().unwrap();
+    let frames: Vec<_> = bad.iter().map(|(msg, _)| msg.clone()).collect();
+    assert_eq!(frames, vec![short_hdr, short_body, wrong_type]);
+    assert_eq!(
+        serialize::ParseError::of(&bad[2].1),
+        Some(serialize::ParseError::BadLength {
+            declared: 12,
+            actual: 4,
+        })
+    );
+    let stats = b.stats();
+    assert_eq!(stats.malformed(), 3);
+    assert_eq!(stats.received(), 2);
+    assert_eq!(stats.unknown_msgs(), 2);
+    assert_eq!(stats.recv_errors(), 0);
+}
+
+#[test]
+fn test_backend_corrupt_msgs() {
+    use super::{chan, Nonblocking};
+    use crate::serialize::checksum;
+    use crossbeam::channel;
+
+    let (s1, _r1) = channel::unbounded();
+    let (s2, r2) = channel::unbounded();
+    let sk = chan::Socket::<Nonblocking>::new(s1, r2);
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    let bad = Arc::new(Mutex::new(vec![]));
+    let bad1 = bad.clone();
+    b.on_parse_error(move |_, err| bad1.lock().unwrap().push(err.clone()));
+
+    let mut good =
+        serialize::serialize(&TestMsg(String::from("hello, world"))).expect("serialize test msg");
+    if !checksum::is_checksummed(&good) {
+        checksum::append(&mut good).expect("append checksum");
+    }
+    let mut corrupt = good.clone();
+    corrupt[10] ^= 0x10;
+    for m in &[&corrupt, &good] {
+        s2.send(m.to_vec()).unwrap();
+    }
+
+    match b.try_next() {
+        Err(e) => assert_eq!(e, super::Error::from(crate::MalformedMsgError)),
+        Ok(m) => panic!("expected a parse error, got {:?}", m.map(|(m, _)| m)),
+    }
+    match b.try_next() {
+        Ok(Some((Msg::Other(r), ()))) => assert_eq!(r.get_bytes().unwrap(), b"hello, world"),
+        m => panic!(
+            "expected the good message, got {:?}",
+            m.map(|m| m.map(|(m, _)| m))
+        ),
+    }
+
+    assert_eq!(
+        *bad.lock().unwrap(),
+        vec![super::Error::from(crate::CorruptMsgError)]
+    );
+    let stats = b.stats();
+    assert_eq!(stats.corrupt(), 1);
+    assert_eq!(stats.malformed(), 0);
+    assert_eq!(stats.received(), 1);
+}
+
+#[test]
+fn test_backend_sequence_gaps() {
+    use super::{chan, Nonblocking};
+    use crossbeam::channel;
+
+    let (s1, r1) = channel::unbounded();
+    let (_s, r) = channel::unbounded();
+    let mut sbuf = [0u8; 1024];
+    let mut sender = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(s1, r),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut sbuf[..],
+    );
+    sender.set_sequence_numbers(true);
+    for i in 0..4 {
+        let msg = serialize::serialize(&TestMsg(format!("hello {}", i))).expect("serialize");
+        sender.sender(()).send_msg(&msg[..]).expect("send");
+    }
+
+    // the transport loses the second message
+    let (s2, r2) = channel::unbounded();
+    let (s, _r) = channel::unbounded();
+    for (i, m) in r1.try_iter().enumerate() {
+        if i != 1 {
+            s2.send(m).unwrap();
+        }
+    }
+
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(s, r2),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut rbuf[..],
+    );
+    let gaps = Arc::new(Mutex::new(vec![]));
+    let gaps1 = gaps.clone();
+    b.on_gap(move |_, n| gaps1.lock().unwrap().push(n));
+
+    for i in &[0, 2, 3] {
+        match b.try_next() {
+            Ok(Some((Msg::Other(r), ()))) => {
+                assert_eq!(r.get_bytes().unwrap(), format!("hello {}", i).as_bytes())
+            }
+            m => panic!(
+                "expected message {}, got {:?}",
+                i,
+                m.map(|m| m.map(|(m, _)| m))
+            ),
+        }
+    }
+
+    assert_eq!(*gaps.lock().unwrap(), vec![1]);
+    assert_eq!(b.gaps(), 1);
+    assert_eq!(b.stats().gaps(), 1);
+}
+
+#[test]
+fn test_backend_sequence_wraparound() {
+    use super::{chan, Nonblocking};
+    use crate::serialize::sequence;
+    use crossbeam::channel;
+
+    let (s1, _r1) = channel::unbounded();
+    let (s2, r2) = channel::unbounded();
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(s1, r2),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut rbuf[..],
+    );
+
+    // 1 is lost after the sequence number wraps
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize");
+    for seq in &[u16::MAX - 1, u16::MAX, 0, 2] {
+        s2.send(sequence::stamp(&msg, *seq).expect("stamp"))
+            .unwrap();
+    }
+    for _ in 0..4 {
+        match b.try_next() {
+            Ok(Some((Msg::Other(r), ()))) => assert_eq!(r.get_bytes().unwrap(), b"hello"),
+            m => panic!("expected a message, got {:?}", m.map(|m| m.map(|(m, _)| m))),
+        }
+    }
+
+    assert_eq!(b.gaps(), 1);
+    assert_eq!(b.stats().gaps(), 1);
+}
+
+// Counts the allocations each thread makes, so that a test can check a loop does not allocate.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCS: std::cell::Cell<u64> = const { std::cell::Cell::new(0) };
+}
+
+unsafe impl std::alloc::GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        ALLOCS.with(|a| a.set(a.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOC: CountingAlloc = CountingAlloc;
+
+pub(crate) fn allocs() -> u64 {
+    ALLOCS.with(std::cell::Cell::get)
+}
+
+// Receives the same message every time.
+pub(crate) struct RepeatIpc(pub(crate) Vec<u8>);
+
+impl IpcSend for RepeatIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("repeat")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> Result<(), super::Error> {
+        Ok(())
+    }
+}
+
+impl IpcRecv for RepeatIpc {
+    fn recv(&self, msg: &mut [u8]) -> super::Result<(usize, Self::Addr)> {
+        msg[..self.0.len()].copy_from_slice(&self.0);
+        Ok((self.0.len(), ()))
+    }
+
+    fn close(&mut self) -> Result<(), super::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backend_recv_no_allocs() {
+    let msg = serialize::serialize(&TestMsg(String::from("hello, world"))).expect("serialize");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(
+        RepeatIpc(msg.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+
+    // the first message's buffer is allocated, and then reused
+    let (first, _) = b.try_recv().expect("recv").expect("message");
+    assert_eq!(&first[..], &msg[..]);
+    drop(first);
+    let before = allocs();
+    for _ in 0..1000 {
+        let (got, _) = b.try_recv().expect("recv").expect("message");
+        assert_eq!(got.len(), msg.len());
+        assert!(matches!(b.try_next(), Ok(Some((Msg::Other(_), ())))));
+    }
+    assert_eq!(allocs() - before, 0);
+
+    // a message kept with `into_vec` takes its buffer along
+    let kept = b.try_recv().expect("recv").expect("message").0.into_vec();
+    assert_eq!(kept, msg);
+    let before = allocs();
+    b.try_recv().expect("recv").expect("message");
+    assert_eq!(allocs() - before, 1);
+}
+
+// A measurement is parsed where it was received: copying its fields out is its only allocation.
+#[test]
+fn test_backend_measure_one_alloc() {
+    let m = serialize::measure::Msg::new(1, 7, vec![14480, 1448, 42, 1]);
+    let msg = serialize::serialize(&m).expect("serialize");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(
+        RepeatIpc(msg),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+
+    let before = allocs();
+    for _ in 0..1000 {
+        match b.try_next() {
+            Ok(Some((Msg::Ms(got), ()))) => assert_eq!(got, m),
+            other => panic!("expected a measurement, got {:?}", other),
+        }
+    }
+    assert_eq!(allocs() - before, 1000);
+}
+
+#[test]
+fn test_backend_dispatch_ready() {
+    use super::{chan, Nonblocking};
+    use crossbeam::channel;
+
+    let (s1, _r1) = channel::unbounded();
+    let (s2, r2) = channel::unbounded();
+    let sk = chan::Socket::<Nonblocking>::new(s1, r2);
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    assert!(b.dispatch_ready().is_err());
+
+    let got = Arc::new(Mutex::new(vec![]));
+    let got1 = got.clone();
+    b.on_msg(move |msg, _| got1.lock().unwrap().push(msg.to_vec()));
+    assert_eq!(b.dispatch_ready().expect("dispatch"), 0);
+
+    let msgs: Vec<Vec<u8>> = ["hello", "portus"]
+        .iter()
+        .map(|s| serialize::serialize(&TestMsg(String::from(*s))).expect("serialize test msg"))
+        .collect();
+    for m in &msgs {
+        s2.send(m.clone()).unwrap();
+    }
+
+    assert_eq!(b.dispatch_ready().expect("dispatch"), 2);
+    assert_eq!(*got.lock().unwrap(), msgs);
+    assert_eq!(b.stats().received(), 2);
+}
+
+#[test]
+fn test_backend_receive_thread() {
+    use super::{chan, queue::Overflow};
+    use crossbeam::channel;
+
+    let (to_dp, _from_ccp) = channel::unbounded();
+    let (to_ccp, from_dp) = channel::unbounded();
+    let sk = chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    b.spawn_receiver(4, Overflow::Block)
+        .expect("spawn receiver");
+    assert!(b.spawn_receiver(4, Overflow::Block).is_err());
+
+    for i in 0..10 {
+        let msg = serialize::serialize(&TestMsg(format!("hello {}", i))).expect("serialize");
+        to_ccp.send(msg).expect("send");
+    }
+
+    for i in 0..10 {
+        match b.next().expect("receive message") {
+            (Msg::Other(r), _) => {
+                assert_eq!(r.get_bytes().unwrap(), format!("hello {}", i).as_bytes())
+            }
+            _ => unreachable!(),
+        }
+    }
+    assert_eq!(b.gaps(), 0);
+
+    // the thread lets go of the socket, so it is dropped along with the backend
+    drop(b);
+    assert!(to_ccp.send(vec![]).is_err());
+}
+
+// Takes 20ms to send each message, like a peer that is slow to drain its socket.
+struct SlowIpc(Arc<Mutex<Vec<Vec<u8>>>>);
+
+impl IpcSend for SlowIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("slow")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<(), super::Error> {
+        self.0.lock().unwrap().push(msg.to_vec());
+        thread::sleep(std::time::Duration::from_millis(20));
+        Ok(())
+    }
+}
+
+impl IpcRecv for SlowIpc {
+    fn recv(&self, _msg: &mut [u8]) -> super::Result<(usize, Self::Addr)> {
+        Ok((0, ()))
+    }
+
+    fn close(&mut self) -> Result<(), super::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backend_send_priority() {
+    use super::Priority;
+    use std::time::{Duration, Instant};
+
+    let sent = Arc::new(Mutex::new(vec![]));
+    let mut buf = [0u8; 64];
+    let mut b = super::Backend::new(
+        SlowIpc(sent.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    b.spawn_sender(16).expect("start send thread");
+    assert!(b.spawn_sender(16).is_err());
+    let sender = b.sender(());
+    let wait_for = |done: &dyn Fn() -> bool| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "send thread did not send");
+            thread::sleep(Duration::from_millis(1));
+        }
+    };
+
+    // while the first message is being sent, the rest queue up
+    sender.send_msg(&[1]).expect("queue");
+    wait_for(&|| !sent.lock().unwrap().is_empty());
+    for (m, p) in &[
+        (2, Priority::Normal),
+        (100, Priority::Urgent),
+        (3, Priority::Normal),
+        (101, Priority::Urgent),
+        (4, Priority::Normal),
+    ] {
+        sender.send_msg_with_priority(&[*m], *p).expect("queue");
+    }
+    wait_for(&|| b.stats().sent() == 6);
+
+    let order: Vec<u8> = sent.lock().unwrap().iter().map(|m| m[0]).collect();
+    assert_eq!(order, vec![1, 100, 101, 2, 3, 4]);
+
+    // dropping the `Backend` sends what is still queued
+    sender.send_msg(&[5]).expect("queue");
+    sender.send_msg(&[6]).expect("queue");
+    drop(b);
+    assert_eq!(sent.lock().unwrap().len(), 8);
+}
+
+// Records when each message is sent.
+struct TimedIpc(Arc<Mutex<Vec<std::time::Instant>>>);
+
+impl IpcSend for TimedIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("timed")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> Result<(), super::Error> {
+        self.0.lock().unwrap().push(std::time::Instant::now());
+        Ok(())
+    }
+}
+
+impl IpcRecv for TimedIpc {
+    fn recv(&self, _msg: &mut [u8]) -> super::Result<(usize, Self::Addr)> {
+        Ok((0, ()))
+    }
+
+    fn close(&mut self) -> Result<(), super::Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_backend_send_rate_limit_reject() {
+    use super::{Priority, RateLimitPolicy, SendRateLimit};
+
+    let sent = Arc::new(Mutex::new(vec![]));
+    let mut buf = [0u8; 64];
+    let mut b = super::Backend::new(
+        TimedIpc(sent.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    let sender = b.sender(());
+    let limit = SendRateLimit {
+        msgs_per_sec: 1,
+        burst: 5,
+        policy: RateLimitPolicy::Reject,
+        exempt_urgent: true,
+    };
+    assert!(b
+        .set_send_rate_limit(Some(SendRateLimit { burst: 0, ..limit }))
+        .is_err());
+    b.set_send_rate_limit(Some(limit)).expect("set rate limit");
+
+    // senders created before the limit was set are limited too
+    let mut rejected = 0;
+    for i in 0..20 {
+        match sender.send_msg(&[i]) {
+            Ok(()) => (),
+            Err(e) => {
+                assert_eq!(e, super::Error::from(crate::RateLimitedError));
+                rejected += 1;
+            }
+        }
+    }
+    assert_eq!(rejected, 15);
+    assert_eq!(sent.lock().unwrap().len(), 5);
+
+    // urgent messages are exempt, and a batch is limited message by message
+    for i in 0..3 {
+        sender
+            .send_msg_with_priority(&[i], Priority::Urgent)
+            .expect("urgent send");
+    }
+    let (n, res) = sender.send_msgs(&[&[1], &[2]]);
+    assert_eq!(n, 0);
+    assert_eq!(res, Err(super::Error::from(crate::RateLimitedError)));
+    assert_eq!(sent.lock().unwrap().len(), 8);
+    assert_eq!(b.stats().rate_limited(), 16);
+    assert_eq!(b.stats().send_errors(), 0);
+    assert_eq!(b.stats().sent(), 8);
+
+    b.set_send_rate_limit(None).expect("remove rate limit");
+    assert_eq!(sender.send_msgs(&[&[1], &[2]]), (2, Ok(())));
+}
+
+#[test]
+fn test_backend_send_rate_limit_delay() {
+    use super::{RateLimitPolicy, SendRateLimit};
+    use std::time::{Duration, Instant};
+
+    let sent = Arc::new(Mutex::new(vec![]));
+    let mut buf = [0u8; 64];
+    let mut b = super::Backend::new(
+        TimedIpc(sent.clone()),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    b.set_send_rate_limit(Some(SendRateLimit {
+        msgs_per_sec: 200,
+        burst: 2,
+        policy: RateLimitPolicy::Delay,
+        exempt_urgent: false,
+    }))
+    .expect("set rate limit");
+    let sender = b.sender(());
+
+    // the burst goes out right away, and the rest 5ms apart
+    let start = Instant::now();
+    for i in 0..12 {
+        sender.send_msg(&[i]).expect("send");
+    }
+    let sent = sent.lock().unwrap();
+    assert_eq!(sent.len(), 12);
+    assert!(sent[1] - start < Duration::from_millis(5));
+    for (i, at) in sent.iter().enumerate().skip(2) {
+        assert!(
+            *at - start >= Duration::from_millis(5 * (i as u64 - 1)) - Duration::from_micros(500),
+            "message {} sent after {:?}",
+            i,
+            *at - start
+        );
+    }
+    assert!(start.elapsed() < Duration::from_secs(2));
+    assert_eq!(b.stats().rate_limited(), 0);
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn test_backend_compression() {
+    use super::{chan, Nonblocking};
+    use crate::serialize::compress::is_compressed;
+    use crossbeam::channel;
+
+    let (to_wire, wire) = channel::unbounded();
+    let (_s, r) = channel::unbounded();
+    let mut sbuf = [0u8; 64];
+    let mut sender = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(to_wire, r),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut sbuf[..],
+    );
+    sender.set_compression(Some(256));
+
+    let (from_wire, r) = channel::unbounded();
+    let (s, _r) = channel::unbounded();
+    let mut rbuf = [0u8; 1024];
+    let mut receiver = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(s, r),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut rbuf[..],
+    );
+
+    let long = serialize::serialize(&TestMsg("hello ".repeat(100))).expect("serialize test msg");
+    let short = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize test msg");
+    let sk = sender.sender(());
+    sk.send_msg(&long).expect("send long");
+    sk.send_msg(&short).expect("send short");
+
+    // only the long message is compressed
+    let sent: Vec<_> = wire.try_iter().collect();
+    assert_eq!(sent.len(), 2);
+    assert!(is_compressed(&sent[0]));
+    assert!(sent[0].len() < long.len() / 4, "{} bytes", sent[0].len());
+    assert_eq!(sent[1], short);
+
+    for m in sent {
+        from_wire.send(m).unwrap();
+    }
+    let (got, _) = receiver.try_recv().expect("recv").expect("message");
+    assert_eq!(&got[..], &long[..]);
+    drop(got);
+    match receiver.try_next() {
+        Ok(Some((Msg::Other(r), ()))) => assert_eq!(r.get_bytes().unwrap(), b"hello"),
+        m => panic!(
+            "expected the short message, got {:?}",
+            m.map(|m| m.map(|(m, _)| m))
+        ),
+    }
+    assert_eq!(receiver.stats().received(), 2);
+    assert_eq!(receiver.stats().malformed(), 0);
+}
+
+#[test]
+fn test_backend_version() {
+    use super::{chan, Nonblocking};
+    use crate::serialize::{ready, version};
+    use crossbeam::channel;
+
+    let (s1, wire) = channel::unbounded();
+    let (peer, r2) = channel::unbounded();
+    let mut buf = [0u8; 64];
+    let mut b = super::Backend::new(
+        chan::Socket::<Nonblocking>::new(s1, r2),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    b.set_announce_version(true).expect("announce version");
+    assert_eq!(b.peer_version(&()), None);
+
+    let theirs = version::Msg { major: 1, minor: 3 };
+    let hello = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize test msg");
+    peer.send(hello.clone()).unwrap();
+    peer.send(serialize::serialize(&theirs).expect("serialize version"))
+        .unwrap();
+    peer.send(hello).unwrap();
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Other(_), ())))));
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Ver(v), ())))  if v == theirs));
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Other(_), ())))));
+    assert_eq!(b.peer_version(&()), Some(theirs));
+
+    // announced once, on first contact
+    let ours = serialize::serialize(&version::Msg::current()).expect("serialize version");
+    assert_eq!(wire.try_iter().collect::<Vec<_>>(), vec![ours.clone()]);
+
+    // and again once the peer restarts
+    peer.send(serialize::serialize(&ready::Msg { id: 0 }).expect("serialize ready"))
+        .unwrap();
+    assert!(matches!(b.try_next(), Ok(Some((Msg::Rdy(_), ())))));
+    assert_eq!(wire.try_iter().collect::<Vec<_>>(), vec![ours]);
+    assert_eq!(b.stats().sent(), 2);
+}
+
+#[test]
+fn test_tcp() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind listener");
+    let addr = listener.local_addr().expect("listener addr");
+
+    let c2 = thread::spawn(move || {
+        let (stream, _) = listener.accept().expect("accept");
+        let sk2 = super::tcp::Socket::<Blocking>::from_stream(stream).expect("init socket");
+        let mut buf = [0u8; 1024];
+        let b2 = super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+        let test_msg = TestMsg(String::from("hello, world"));
+        let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+        b2.sender(())
+            .send_msg(&test_msg_buf[..])
+            .expect("send message");
+    });
+
+    let sk1 = super::tcp::Socket::<Blocking>::new(addr).expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    match b1.next().expect("receive message") {
+        (Msg::Other(r), ()) => {
+            assert_eq!(r.typ, 0xff);
+            assert_eq!(r.len, serialize::HDR_LENGTH + "hello, world".len() as u32);
+            assert_eq!(r.get_bytes().unwrap(), "hello, world".as_bytes());
+        }
+        _ => unreachable!(),
+    }
+
+    // the peer's backend closed the connection when it was dropped
+    c2.join().expect("join sender thread");
+    assert!(b1.next().is_none());
+}
+
+#[test]
+fn test_tcp_partial_frames() {
+    use std::io::Write;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind listener");
+    let addr = listener.local_addr().expect("listener addr");
+
+    let c2 = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("accept");
+        stream.set_nodelay(true).expect("set nodelay");
+        let mut frames = vec![];
+        for s in &["foo", "bar"] {
+            let msg = serialize::serialize(&TestMsg(String::from(*s))).expect("serialize");
+            frames.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+            frames.extend(msg);
+        }
+
+        // dribble both frames out one byte at a time
+        for b in frames {
+            stream.write_all(&[b]).expect("write byte");
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+    });
+
+    let sk1 = super::tcp::Socket::<Blocking>::new(addr).expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    for expected in &["foo", "bar"] {
+        match b1.next().expect("receive message") {
+            (Msg::Other(r), ()) => assert_eq!(r.get_bytes().unwrap(), expected.as_bytes()),
+            _ => unreachable!(),
+        }
+    }
+
+    c2.join().expect("join sender thread");
+}
+
+#[test]
+fn test_tcp_oversized_frame() {
+    use super::IpcRecv;
+    use std::io::Write;
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind listener");
+    let addr = listener.local_addr().expect("listener addr");
+    let (done_tx, done_rx) = std::sync::mpsc::channel::<()>();
+
+    let c2 = thread::spawn(move || {
+        let (mut stream, _) = listener.accept().expect("accept");
+        // a header claiming 4 GiB, and only a little of it
+        stream
+            .write_all(&u32::MAX.to_le_bytes())
+            .expect("write header");
+        stream.write_all(&[0u8; 16]).expect("write body");
+        // keep the connection open until the receiver has given up on the frame
+        done_rx.recv().expect("wait for receiver");
+    });
+
+    let sk1 = super::tcp::Socket::<Blocking>::new(addr).expect("init socket");
+    let mut buf = [0u8; 1024];
+    let err = sk1.recv(&mut buf).expect_err("oversized frame");
+    assert!(err.0.contains("4294967295"), "{}", err.0);
+    // nothing past it can be read
+    assert!(sk1.recv(&mut buf).is_err());
+    done_tx.send(()).expect("release sender");
+    c2.join().expect("join sender thread");
+}
+
+#[test]
+fn test_tcp_send_after_disconnect() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind listener");
+    let addr = listener.local_addr().expect("listener addr");
+    let sk1 = super::tcp::Socket::<Blocking>::new(addr).expect("init socket");
+    let (stream, _) = listener.accept().expect("accept");
+    drop(stream);
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    assert!(b1.next().is_none());
+
+    // the first send after the peer goes away can still succeed, but it resets the connection.
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize");
+    let sender = b1.sender(());
+    let res = (0..10).try_for_each(|_| {
+        sender.send_msg(&msg[..])?;
+        thread::sleep(std::time::Duration::from_millis(10));
+        Ok::<(), super::Error>(())
+    });
+    assert!(res.is_err());
+}
+
+#[test]
+fn test_udp() {
+    use crate::lang::{Reg, Type};
+    let sk1 = super::udp::Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+    let sk2 = super::udp::Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+    let addr1 = sk1.local_addr().expect("socket addr");
+    let addr2 = sk2.local_addr().expect("socket addr");
+
+    let c2 = thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        let mut b2 =
+            super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+        let (sid, from) = match b2.next().expect("receive message") {
+            (Msg::Cp(m), from) => {
+                assert_eq!(m.program_uid, 7);
+                (m.sid, from)
+            }
+            _ => unreachable!(),
+        };
+
+        assert_eq!(from, Some(addr1));
+        let measure = serialize::measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 0x1_0000_0000],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        };
+        let buf = serialize::serialize(&measure).expect("serialize measure msg");
+        b2.sender(from).send_msg(&buf[..]).expect("send message");
+        assert_eq!(b2.gaps(), 0);
+    });
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let changeprog = serialize::changeprog::Msg {
+        sid: 15,
+        program_uid: 7,
+        num_fields: 1,
+        fields: vec![(Reg::Implicit(4, Type::Num(None)), 100)],
+    };
+    let msg_buf = serialize::serialize(&changeprog).expect("serialize changeprog msg");
+    b1.sender(Some(addr2))
+        .send_msg(&msg_buf[..])
+        .expect("send message");
+
+    match b1.next().expect("receive message") {
+        (Msg::Ms(m), from) => {
+            assert_eq!(from, Some(addr2));
+            assert_eq!(m.sid, 15);
+            assert_eq!(m.program_uid, 7);
+            assert_eq!(m.fields, vec![42, 0x1_0000_0000]);
+        }
+        _ => unreachable!(),
+    }
+
+    c2.join().expect("join sender thread");
+    assert_eq!(b1.gaps(), 0);
+}
+
+#[test]
+fn test_udp_gaps() {
+    let sk1 = super::udp::Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+    let addr1 = sk1.local_addr().expect("socket addr");
+    let peer = std::net::UdpSocket::bind("127.0.0.1:0").expect("bind peer");
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize");
+
+    // datagram 2 never arrives
+    for seq in &[0u32, 1, 3] {
+        let mut dgram = seq.to_le_bytes().to_vec();
+        dgram.extend_from_slice(&msg[..]);
+        peer.send_to(&dgram[..], addr1).expect("send datagram");
+    }
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    for _ in 0..3 {
+        match b1.next().expect("receive message") {
+            (Msg::Other(r), _) => assert_eq!(r.get_bytes().unwrap(), "hello".as_bytes()),
+            _ => unreachable!(),
+        }
+    }
+
+    assert_eq!(b1.gaps(), 1);
+    assert_eq!(b1.stats().gaps(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_kp_missing_device() {
+    let res = super::kp::Socket::<Blocking>::with_path("/nonexistent/ccpkp");
+    assert!(res.is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_netlink_invalid_group() {
+    use super::netlink::{Socket, DEFAULT_PROTOCOL};
+
+    for group in &[0, 33] {
+        let err = Socket::<Blocking>::with_group(DEFAULT_PROTOCOL, *group)
+            .err()
+            .expect("invalid group");
+        assert!(
+            err.0
+                .contains(&format!("invalid netlink multicast group {}", group)),
+            "{}",
+            err.0
+        );
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_netlink_portids() {
+    use super::netlink::{Socket, SocketOptions};
+
+    let opts = |portid| SocketOptions {
+        portid: Some(portid),
+        ..Default::default()
+    };
+    // netlink sockets may not be available, e.g. in a container
+    let _s1 = match Socket::<Blocking>::with_options(opts(0x7c_c001)) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let _s2 = Socket::<Blocking>::with_options(opts(0x7c_c002)).expect("second portid");
+    let err = Socket::<Blocking>::with_options(opts(0x7c_c001))
+        .err()
+        .expect("portid in use");
+    assert!(err.0.contains("already in use"), "{}", err.0);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_vsock() {
+    // needs the vsock_loopback transport; not all kernels (or VMs) provide it.
+    let probe = super::vsock::Socket::<Blocking>::new(super::vsock::CID_LOCAL, 1);
+    if let Err(super::Error(e)) = probe {
+        if !e.contains("Connection refused") && !e.contains("Connection reset") {
+            eprintln!("skipping vsock test, no loopback transport: {}", e);
+            return;
+        }
+    }
+
+    let port = 0x4343_0000 + std::process::id() % 0xffff;
+    let (tx, rx) = crossbeam::channel::unbounded();
+    let c2 = thread::spawn(move || {
+        let listener = thread::spawn(move || super::vsock::Socket::<Blocking>::accept(port));
+        // wait for the listener to bind
+        let sk2 = loop {
+            match super::vsock::Socket::<Blocking>::new(super::vsock::CID_LOCAL, port) {
+                Ok(sk) => break sk,
+                Err(_) => thread::sleep(std::time::Duration::from_millis(10)),
+            }
+        };
+        tx.send(listener.join().unwrap().expect("accept"))
+            .expect("chan send");
+
+        let mut buf = [0u8; 1024];
+        let b2 = super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+        let test_msg = TestMsg(String::from("hello, world"));
+        let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+        b2.sender(())
+            .send_msg(&test_msg_buf[..])
+            .expect("send message");
+    });
+
+    let sk1 = rx.recv().expect("chan rcv");
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    match b1.next().expect("receive message") {
+        (Msg::Other(r), ()) => {
+            assert_eq!(r.typ, 0xff);
+            assert_eq!(r.get_bytes().unwrap(), "hello, world".as_bytes());
+        }
+        _ => unreachable!(),
+    }
+
+    // the peer closed the connection when its backend was dropped
+    c2.join().expect("join sender thread");
+    assert!(b1.next().is_none());
+    assert_eq!(
+        b1.take_error(),
+        Some(super::Error::from(crate::DatapathGoneError))
+    );
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize");
+    let sender = b1.sender(());
+    let res = (0..10).try_for_each(|_| {
+        sender.send_msg(&msg[..])?;
+        thread::sleep(std::time::Duration::from_millis(10));
+        Ok::<(), super::Error>(())
+    });
+    assert_eq!(res, Err(super::Error::from(crate::DatapathGoneError)));
+}
+
+#[test]
+fn test_unix_recv_timeout() {
+    let timeout = std::time::Duration::from_millis(100);
+    let mut sk =
+        super::unix::Socket::<Blocking>::new("portus-test-unix-timeout").expect("init socket");
+    sk.set_recv_timeout(timeout).expect("set timeout");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    for _ in 0..3 {
+        let start = std::time::Instant::now();
+        assert!(b.try_next().expect("no error").is_none());
+        assert!(start.elapsed() < 2 * timeout);
+    }
+}
+
+// CPU time this thread has used so far
+#[cfg(target_os = "linux")]
+fn thread_cpu_time() -> std::time::Duration {
+    let mut ru: libc::rusage = unsafe { std::mem::zeroed() };
+    assert_eq!(unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut ru) }, 0);
+    let secs = (ru.ru_utime.tv_sec + ru.ru_stime.tv_sec) as u64;
+    let usecs = (ru.ru_utime.tv_usec + ru.ru_stime.tv_usec) as u64;
+    std::time::Duration::from_secs(secs) + std::time::Duration::from_micros(usecs)
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_nonblocking_idle() {
+    use super::Nonblocking;
+    let sk = super::unix::Socket::<Nonblocking>::new_with_skbuf("portus-test-idle", None, None)
+        .expect("init socket");
+    let continue_listening = Arc::new(atomic::AtomicBool::new(true));
+    let stop = continue_listening.clone();
+    let listener = thread::spawn(move || {
+        let mut buf = [0u8; 1024];
+        let mut b = super::Backend::new(sk, continue_listening, &mut buf[..]);
+        let cpu = thread_cpu_time();
+        let start = std::time::Instant::now();
+        assert!(b.next().is_none());
+        (thread_cpu_time() - cpu, start.elapsed())
+    });
+
+    thread::sleep(std::time::Duration::from_secs(1));
+    stop.store(false, atomic::Ordering::SeqCst);
+    let (cpu, elapsed) = listener.join().expect("join listener thread");
+    assert!(elapsed >= std::time::Duration::from_millis(900));
+    // spinning on the socket would take the whole second
+    assert!(cpu < std::time::Duration::from_millis(100), "{:?}", cpu);
+}
+
+#[test]
+fn test_split() {
+    let sk = super::unix::Socket::<Blocking>::new("portus-test-unix-split").expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+
+    // send to ourselves from another thread while this one waits to receive
+    let sender = b
+        .split()
+        .clone_with_dest(std::path::PathBuf::from("portus-test-unix-split").into());
+    let c = thread::spawn(move || {
+        thread::sleep(std::time::Duration::from_millis(100));
+        let test_msg = TestMsg(String::from("hello, world"));
+        let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+        sender.send_msg(&test_msg_buf[..]).expect("send message");
+    });
+
+    match b.next().expect("receive message") {
+        (Msg::Other(r), _) => assert_eq!(r.get_bytes().unwrap(), "hello, world".as_bytes()),
+        _ => unreachable!(),
+    }
+
+    c.join().expect("join sender thread");
+    assert_eq!(b.stats().sent(), 1);
+}
+
+#[test]
+fn test_datapath_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<super::BackendSender<super::unix::Socket<Blocking>>>();
+    assert_send_sync::<crate::Datapath<super::unix::Socket<Blocking>>>();
+}
+
+#[test]
+fn test_unix_shutdown() {
+    use std::time::Duration;
+
+    let (handle_tx, handle_rx) = crossbeam::channel::bounded(1);
+    let (done_tx, done_rx) = crossbeam::channel::bounded(1);
+    let listener = thread::spawn(move || {
+        let mut sk =
+            super::unix::Socket::<Blocking>::new("portus-test-unix-shutdown").expect("init socket");
+        // long enough that only the shutdown can stop the wait in time
+        sk.set_recv_timeout(Duration::from_secs(60))
+            .expect("set timeout");
+        let mut buf = [0u8; 1024];
+        let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+        handle_tx.send(b.shutdown_handle()).expect("send handle");
+        while b.next().is_some() {}
+        drop(b);
+        done_tx.send(()).expect("send done");
+    });
+
+    let handle = handle_rx.recv().expect("receive handle");
+    // let the listener block in recv
+    thread::sleep(Duration::from_millis(100));
+    handle.shutdown();
+    done_rx
+        .recv_timeout(Duration::from_secs(1))
+        .expect("listener stops promptly");
+    listener.join().expect("join listener thread");
+    assert!(!std::path::Path::new("/tmp/ccp/portus-test-unix-shutdown").exists());
+    // the backend is gone, so this must not touch its (possibly reused) fd
+    handle.shutdown();
+}
+
+#[test]
+fn test_unix_send_timeout() {
+    use std::time::{Duration, Instant};
+
+    // bound, but never reads, so its receive queue fills up
+    let _peer = super::unix::Socket::<Blocking>::new("portus-test-send-timeout-peer")
+        .expect("init peer socket");
+    let mut sk =
+        super::unix::Socket::<Blocking>::new("portus-test-send-timeout").expect("init socket");
+    let timeout = Duration::from_millis(100);
+    sk.set_send_timeout(timeout).expect("set timeout");
+    let mut buf = [0u8; 1024];
+    let b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let sender = b.sender(std::path::PathBuf::from("portus-test-send-timeout-peer").into());
+
+    let msg = [0u8; 512];
+    let err = loop {
+        let start = Instant::now();
+        match sender.send_msg(&msg[..]) {
+            Ok(_) => assert!(start.elapsed() < timeout),
+            Err(e) => {
+                assert!(start.elapsed() < 10 * timeout);
+                break e;
+            }
+        }
+    };
+    assert_eq!(err, super::send_timed_out());
+    assert_eq!(b.stats().send_errors(), 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_unix_recv_timestamped() {
+    use std::path::PathBuf;
+    use std::time::SystemTime;
+
+    let sk1 = super::unix::Socket::<Blocking>::new("portus-test-timestamp-1").expect("init socket");
+    let sk2 = super::unix::Socket::<Blocking>::new("portus-test-timestamp-2").expect("init socket");
+    let to = PathBuf::from("portus-test-timestamp-1").into();
+    let before = SystemTime::now();
+    sk2.send(b"first", &to).expect("send");
+    sk2.send(b"second", &to).expect("send");
+
+    let mut buf = [0u8; 16];
+    let (len, _, t1) = sk1.recv_timestamped(&mut buf).expect("recv");
+    assert_eq!(&buf[..len], b"first");
+    let (len, _, t2) = sk1.recv_timestamped(&mut buf).expect("recv");
+    assert_eq!(&buf[..len], b"second");
+
+    // the kernel stamped each message as it arrived
+    let (t1, t2) = (t1.expect("timestamp"), t2.expect("timestamp"));
+    assert!(before <= t1 && t1 <= t2 && t2 <= SystemTime::now());
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_unix_allowed_peers() {
+    use super::unix::{AllowedPeers, Socket, SocketKind};
+    use std::path::PathBuf;
+
+    let me = AllowedPeers {
+        uids: vec![nix::unistd::getuid().as_raw()],
+        gids: vec![],
+    };
+    let sk1 = Socket::<Blocking>::new_with_allowed_peers(
+        "portus-test-allowed-1",
+        SocketKind::Datagram,
+        me,
+    )
+    .expect("init socket");
+    let sk2 = Socket::<Blocking>::new("portus-test-allowed-2").expect("init socket");
+    sk2.send(b"hello", &PathBuf::from("portus-test-allowed-1").into())
+        .expect("send");
+    let mut buf = [0u8; 16];
+    let (len, _) = sk1.recv(&mut buf).expect("recv");
+    assert_eq!(&buf[..len], b"hello");
+    assert_eq!(sk1.rejected(), 0);
+
+    let nobody = AllowedPeers {
+        uids: vec![libc::uid_t::MAX - 1],
+        gids: vec![],
+    };
+    let sk3 = Socket::<Blocking>::new_with_allowed_peers(
+        "portus-test-allowed-3",
+        SocketKind::Datagram,
+        nobody,
+    )
+    .expect("init socket");
+    sk2.send(b"hello", &PathBuf::from("portus-test-allowed-3").into())
+        .expect("send");
+    let (len, _) = sk3.recv(&mut buf).expect("recv");
+    assert_eq!(len, 0);
+    assert_eq!(sk3.rejected(), 1);
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn test_unix_seqpacket_allowed_peers() {
+    use super::unix::{AllowedPeers, Socket, SocketKind, UnixAddr};
+
+    let nobody = AllowedPeers {
+        uids: vec![libc::uid_t::MAX - 1],
+        gids: vec![libc::gid_t::MAX - 1],
+    };
+    let sk1 = Socket::<Blocking>::new_with_allowed_peers(
+        "portus-test-allowed-seqpacket",
+        SocketKind::SeqPacket,
+        nobody,
+    )
+    .expect("init socket");
+    let to = UnixAddr::Path(std::path::PathBuf::from(
+        "/tmp/ccp/portus-test-allowed-seqpacket",
+    ));
+    let sk2 = Socket::<Blocking>::connect_seqpacket(&to).expect("connect");
+    sk2.send(b"hello", &Default::default()).expect("send");
+
+    let mut buf = [0u8; 16];
+    let (len, _) = sk1.recv(&mut buf).expect("recv");
+    assert_eq!(len, 0);
+    assert_eq!(sk1.rejected(), 1);
+    assert_eq!(sk1.reconnects(), 0);
+}
+
+#[test]
+fn test_backend_poll_fd() {
+    use super::Nonblocking;
+    use nix::poll::{poll, PollFd, PollFlags};
+    use std::path::PathBuf;
+
+    let sk1 =
+        super::unix::Socket::<Nonblocking>::new_with_skbuf("portus-test-pollfd-1", None, None)
+            .expect("init socket");
+    let sk2 = super::unix::Socket::<Blocking>::new("portus-test-pollfd-2").expect("init socket");
+    let mut rbuf = [0u8; 1024];
+    let mut b = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    let fd = b.poll_fd().expect("unix sockets have an fd");
+    let readable = |timeout_ms| {
+        let mut fds = [PollFd::new(fd, PollFlags::POLLIN)];
+        poll(&mut fds, timeout_ms).expect("poll") == 1
+    };
+
+    assert!(!readable(0));
+    assert!(b.try_recv().expect("recv").is_none());
+
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize test msg");
+    sk2.send(&msg, &PathBuf::from("portus-test-pollfd-1").into())
+        .expect("send");
+    assert!(readable(1000));
+    let (got, _) = b.try_recv().expect("recv").expect("message");
+    assert_eq!(&got[..], &msg[..]);
+    assert!(b.try_recv().expect("recv").is_none());
+    assert!(!readable(0));
+
+    let sk = FakeIpc::new();
+    let mut rbuf = [0u8; 1024];
+    let b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut rbuf[..]);
+    assert!(b.poll_fd().is_none());
+}
+
+// A message numbered `i`, padded so that a few fill a datagram socket's buffer.
+fn numbered(i: u32) -> Vec<u8> {
+    let mut msg = vec![0u8; 512];
+    msg[..4].copy_from_slice(&i.to_le_bytes());
+    msg
+}
+
+#[test]
+fn test_unix_pending_sends() {
+    use super::{Nonblocking, Priority, SendStatus};
+    use std::time::Duration;
+
+    // the datapath's end, which does not read until the socket has filled up
+    let mut peer =
+        super::unix::Socket::<Blocking>::new("portus-test-pending-peer").expect("init peer socket");
+    peer.set_recv_timeout(Duration::from_secs(1))
+        .expect("set timeout");
+    let sk = super::unix::Socket::<Nonblocking>::new_with_skbuf("portus-test-pending", None, None)
+        .expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    b.set_pending_budget(16 * 512);
+    let sender = b.sender(std::path::PathBuf::from("portus-test-pending-peer").into());
+    let send = |i| {
+        sender
+            .send_msg_with_priority(&numbered(i), Priority::Normal)
+            .expect("send")
+    };
+
+    let mut sent = 0;
+    while send(sent) == SendStatus::Sent {
+        sent += 1;
+    }
+
+    // once one message is held, later ones wait behind it
+    let total = sent + 10;
+    for i in sent + 1..total {
+        assert_eq!(send(i), SendStatus::Queued);
+    }
+    assert_eq!(b.pending_bytes(), 10 * 512);
+    assert_eq!(b.stats().sent(), u64::from(sent));
+
+    let mut rbuf = [0u8; 1024];
+    let mut recv = |i: u32| {
+        let (len, _) = peer.recv(&mut rbuf).expect("recv");
+        assert_eq!(&rbuf[..len], &numbered(i)[..]);
+    };
+    for i in 0..sent {
+        recv(i);
+    }
+
+    // the socket has room again, so waiting for messages sends the held ones
+    assert!(b.try_next().expect("try_next").is_none());
+    assert_eq!(b.pending_bytes(), 0);
+    assert_eq!(b.stats().sent(), u64::from(total));
+    assert_eq!(b.stats().send_errors(), 0);
+    for i in sent..total {
+        recv(i);
+    }
+}
+
+#[test]
+fn test_unix_pending_overflow() {
+    use super::{Nonblocking, SendStatus};
+
+    let _peer = super::unix::Socket::<Blocking>::new("portus-test-pending-overflow-peer")
+        .expect("init peer socket");
+    let sk = super::unix::Socket::<Nonblocking>::new_with_skbuf(
+        "portus-test-pending-overflow",
+        None,
+        None,
+    )
+    .expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(sk, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    b.set_pending_budget(2 * 512);
+    let sender = b.sender(std::path::PathBuf::from("portus-test-pending-overflow-peer").into());
+
+    let mut i = 0;
+    while sender
+        .send_msg_with_priority(&numbered(i), super::Priority::Normal)
+        .expect("send")
+        == SendStatus::Sent
+    {
+        i += 1;
+    }
+
+    assert!(sender.send_msg(&numbered(i + 1)).is_ok());
+    let err = sender.send_msg(&numbered(i + 2)).expect_err("over budget");
+    assert_eq!(err, crate::Error::from(crate::SendQueueFullError));
+    assert_eq!(b.pending_bytes(), 2 * 512);
+    assert_eq!(b.stats().send_errors(), 1);
+
+    // without a budget, a send which would block fails right away
+    b.set_pending_budget(0);
+    let err = sender.send_msg(&numbered(i + 3)).expect_err("would block");
+    assert_eq!(err, super::send_timed_out());
+}
+
+#[test]
+fn test_unix_reconnect() {
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    // nothing is bound to the peer's address until it "restarts"
+    std::fs::remove_file("/tmp/ccp/portus-test-reconnect-2").unwrap_or(());
+
+    let sk = super::unix::Socket::<Blocking>::new("portus-test-reconnect-0").expect("init socket");
+    assert!(sk
+        .send(b"hello", &PathBuf::from("portus-test-reconnect-2").into())
+        .is_err());
+
+    let policy = super::unix::ReconnectPolicy {
+        initial_backoff: Duration::from_millis(10),
+        max_backoff: Duration::from_millis(50),
+        max_attempts: 50,
+    };
+    let sk1 =
+        super::unix::Socket::<Blocking>::new_with_reconnect("portus-test-reconnect-1", policy)
+            .expect("init socket");
+    assert_eq!(sk1.reconnects(), 0);
+
+    let peer = thread::spawn(|| {
+        thread::sleep(Duration::from_millis(100));
+        let sk2 =
+            super::unix::Socket::<Blocking>::new("portus-test-reconnect-2").expect("init socket");
+        let mut buf = [0u8; 16];
+        let (len, _) = sk2.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"hello");
+    });
+
+    sk1.send(b"hello", &PathBuf::from("portus-test-reconnect-2").into())
+        .expect("send once the peer is back");
+    assert_eq!(sk1.reconnects(), 1);
+    peer.join().expect("join peer thread");
+}
+
+#[test]
+fn test_unix_paths() {
+    use std::path::{Path, PathBuf};
+
+    let root = std::env::temp_dir().join(format!("portus-test-paths-{}", std::process::id()));
+    let (dir1, dir2) = (root.join("one"), root.join("two"));
+
+    fn exchange(rx: PathBuf, tx_prefix: PathBuf, first: bool) {
+        let sk = super::unix::Socket::<Blocking>::new_with_paths(&rx, &tx_prefix).expect("init");
+        let mut buf = [0u8; 16];
+        let to = PathBuf::from("ccp").into();
+        if first {
+            // the other socket may not be bound yet
+            while sk.send(b"ping", &to).is_err() {
+                thread::sleep(std::time::Duration::from_millis(10));
+            }
+        }
+
+        let (len, from) = sk.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], if first { &b"pong"[..] } else { &b"ping"[..] });
+        if !first {
+            assert_eq!(from, tx_prefix.join("ccp").into());
+            sk.send(b"pong", &to).expect("send");
+        }
+    }
+
+    let (r1, t1) = (dir1.join("ccp"), dir2.clone());
+    let (r2, t2) = (dir2.join("ccp"), dir1.clone());
+    let c1 = thread::spawn(move || exchange(r1, t1, true));
+    let c2 = thread::spawn(move || exchange(r2, t2, false));
+    c1.join().expect("join first thread");
+    c2.join().expect("join second thread");
+
+    // the sockets clean up after themselves
+    assert!(!dir1.join("ccp").exists());
+    assert!(!dir2.join("ccp").exists());
+
+    // longer than a unix socket address can be
+    let long = root.join("x".repeat(200));
+    let err = super::unix::Socket::<Blocking>::new_with_paths(&long, Path::new("/tmp"))
+        .err()
+        .expect("bind should fail");
+    assert!(err.0.contains(long.to_str().unwrap()), "{}", err.0);
+
+    std::fs::remove_dir_all(&root).expect("remove test dirs");
+}
+
+#[test]
+fn test_unix_permissions() {
+    use super::unix::{Permissions, Socket, SocketKind};
+    use std::os::unix::fs::MetadataExt;
+
+    // giving the socket file away needs root
+    if !nix::unistd::Uid::effective().is_root() {
+        return;
+    }
+
+    let perms = Permissions {
+        mode: Some(0o666),
+        owner: Some((1, 1)),
+    };
+    for (name, kind) in &[
+        ("portus-test-perms-dgram", SocketKind::Datagram),
+        ("portus-test-perms-seqpacket", SocketKind::SeqPacket),
+    ] {
+        let _sk = Socket::<Blocking>::new_with_permissions(name, *kind, perms).expect("init");
+        let md = std::fs::metadata(std::path::Path::new("/tmp/ccp").join(name)).expect("stat");
+        assert_eq!(md.mode() & 0o777, 0o666, "{:?}", kind);
+        assert_eq!((md.uid(), md.gid()), (1, 1), "{:?}", kind);
+    }
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_unix_abstract() {
+    use super::unix::{Socket, UnixAddr};
+
+    let sk1 = Socket::<Blocking>::new_abstract("portus-test-abstract-1").expect("init socket");
+    let sk2 = Socket::<Blocking>::new_abstract("portus-test-abstract-2").expect("init socket");
+    assert!(!std::path::Path::new("/tmp/ccp/portus-test-abstract-1").exists());
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let test_msg = TestMsg(String::from("hello, world"));
+    let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+    sk2.send(
+        &test_msg_buf[..],
+        &UnixAddr::Abstract(String::from("portus-test-abstract-1")),
+    )
+    .expect("send message");
+    match b1.next().expect("receive message") {
+        (Msg::Other(r), from) => {
+            assert_eq!(r.get_bytes().unwrap(), "hello, world".as_bytes());
+            assert_eq!(
+                from,
+                UnixAddr::Abstract(String::from("portus-test-abstract-2"))
+            );
+        }
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_unix_seqpacket_stress() {
+    use super::unix::{Socket, SocketKind, UnixAddr};
+    const NUM_MSGS: u64 = 100_000;
+
+    let sk1 = Socket::<Blocking>::new_with_kind("portus-test-seqpacket", SocketKind::SeqPacket)
+        .expect("init socket");
+    let c2 = thread::spawn(|| {
+        let to = UnixAddr::Path(std::path::PathBuf::from("/tmp/ccp/portus-test-seqpacket"));
+        let sk2 = Socket::<Blocking>::connect_seqpacket(&to).expect("connect");
+        for sid in 0..NUM_MSGS {
+            let m = serialize::measure::Msg {
+                sid,
+                program_uid: 7,
+                num_fields: 1,
+                fields: vec![sid],
+                types: None,
+                timestamp: None,
+                strings: vec![],
+            };
+            let buf = serialize::serialize(&m).expect("serialize");
+            sk2.send(&buf[..], &Default::default()).expect("send");
+        }
+    });
+
+    // the sender outpaces us, so without backpressure some messages would be dropped
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(60);
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    let mut expected = 0;
+    while expected < NUM_MSGS {
+        assert!(
+            std::time::Instant::now() < deadline,
+            "got {} messages",
+            expected
+        );
+        match b1.try_next().expect("receive") {
+            Some((Msg::Ms(m), _)) => {
+                assert_eq!(m.sid, expected);
+                assert_eq!(m.fields, vec![expected]);
+                expected += 1;
+            }
+            Some(_) => unreachable!(),
+            None => (),
+        }
+    }
+
+    c2.join().expect("join sender thread");
+}
+
+#[test]
+fn test_unix_stream_fragmented() {
+    use super::unix::{Socket, SocketKind};
+    use std::io::Write;
+
+    let sk1 = Socket::<Blocking>::new_with_kind("portus-test-stream", SocketKind::Stream)
+        .expect("init socket");
+    let c2 = thread::spawn(|| {
+        let mut stream = std::os::unix::net::UnixStream::connect("/tmp/ccp/portus-test-stream")
+            .expect("connect");
+        let mut bytes = vec![];
+        for s in &["foo", "bar", "baz"] {
+            bytes.extend(serialize::serialize(&TestMsg(String::from(*s))).expect("serialize"));
+        }
+
+        // split the messages at odd places, so that reads end mid-header and mid-body
+        for chunk in bytes.chunks(5) {
+            stream.write_all(chunk).expect("write chunk");
+            thread::sleep(std::time::Duration::from_millis(2));
+        }
+    });
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    for expected in &["foo", "bar", "baz"] {
+        match b1.next().expect("receive message") {
+            (Msg::Other(r), _) => assert_eq!(r.get_bytes().unwrap(), expected.as_bytes()),
+            _ => unreachable!(),
+        }
+    }
+
+    c2.join().expect("join sender thread");
+}
+
+#[test]
+fn test_unix_stream_roundtrip() {
+    use super::unix::{Socket, SocketKind, UnixAddr};
+
+    let sk1 = Socket::<Blocking>::new_with_kind("portus-test-stream-rt", SocketKind::Stream)
+        .expect("init socket");
+    let to = UnixAddr::Path(std::path::PathBuf::from("/tmp/ccp/portus-test-stream-rt"));
+    let sk2 = Socket::<Blocking>::connect_stream(&to).expect("connect");
+    let msg = serialize::serialize(&TestMsg(String::from("hello"))).expect("serialize");
+    sk2.send(&msg[..], &Default::default()).expect("send");
+
+    let mut buf = [0u8; 1024];
+    let mut b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    match b1.next().expect("receive message") {
+        (Msg::Other(r), _) => assert_eq!(r.get_bytes().unwrap(), "hello".as_bytes()),
+        _ => unreachable!(),
+    }
+
+    // and back the other way, to the datapath
+    let reply = serialize::serialize(&TestMsg(String::from("world"))).expect("serialize");
+    b1.sender(Default::default())
+        .send_msg(&reply[..])
+        .expect("send reply");
+    let mut rbuf = [0u8; 1024];
+    let (len, _) = sk2.recv(&mut rbuf).expect("recv reply");
+    assert_eq!(&rbuf[..len], &reply[..]);
+
+    // a datapath hanging up mid-message leaves nothing behind for the next connection
+    drop(sk2);
+    let c3 = thread::spawn(move || {
+        use std::io::Write;
+        let mut partial = std::os::unix::net::UnixStream::connect("/tmp/ccp/portus-test-stream-rt")
+            .expect("connect");
+        partial.write_all(&msg[..5]).expect("write partial message");
+        drop(partial);
+
+        let sk3 = Socket::<Blocking>::connect_stream(&to).expect("connect");
+        sk3.send(&msg[..], &Default::default()).expect("send");
+        sk3
+    });
+    match b1.next().expect("receive message") {
+        (Msg::Other(r), _) => assert_eq!(r.get_bytes().unwrap(), "hello".as_bytes()),
+        _ => unreachable!(),
+    }
+
+    c3.join().expect("join sender thread");
+    assert_eq!(b1.stats().recv_errors(), 0);
+}
+
+#[test]
+fn test_unix_peer_table() {
+    use super::unix::{Socket, UnixAddr};
+    use std::path::PathBuf;
+
+    let ccp = Socket::<Blocking>::new("portus-test-peers-ccp").expect("init socket");
+    let peers = ccp.peers();
+    let app1 = Socket::<Blocking>::new("portus-test-peers-app1").expect("init socket");
+    let app2 = Socket::<Blocking>::new("portus-test-peers-app2").expect("init socket");
+    let to_ccp = UnixAddr::Path(PathBuf::from("portus-test-peers-ccp"));
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create")
+    };
+    app1.send(&create(1), &to_ccp).expect("send create");
+    app2.send(&create(2), &to_ccp).expect("send create");
+
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(ccp, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    for _ in 0..2 {
+        assert!(matches!(b.next(), Some((Msg::Cr(_), _))));
+    }
+    assert_eq!(
+        peers.get(1),
+        Some(UnixAddr::Path(PathBuf::from(
+            "/tmp/ccp/portus-test-peers-app1"
+        )))
+    );
+
+    // each application only gets the messages for its own flow
+    let sender = b.sender(UnixAddr::default());
+    let msg = |sid| {
+        serialize::serialize(&serialize::update_field::Msg {
+            sid,
+            num_fields: 0,
+            fields: vec![],
+            widths: None,
+        })
+        .expect("serialize update")
+    };
+    sender.send_msg(&msg(2)[..]).expect("send to app2");
+    sender.send_msg(&msg(1)[..]).expect("send to app1");
+    let mut rbuf = [0u8; 1024];
+    for (app, sid) in &[(&app1, 1u64), (&app2, 2u64)] {
+        let (len, _) = app.recv(&mut rbuf).expect("recv");
+        assert_eq!(&rbuf[..len], &msg(*sid)[..]);
+    }
+
+    let err = sender.send_msg(&msg(3)[..]).expect_err("no peer for sid 3");
+    assert!(err.0.contains("sock_id 3"), "{}", err.0);
+    peers.unregister(1);
+    assert!(sender.send_msg(&msg(1)[..]).is_err());
+}
+
+#[test]
+fn test_unix_send_msgs() {
+    use super::unix::{Socket, UnixAddr};
+    use std::path::PathBuf;
+
+    let ccp = Socket::<Blocking>::new("portus-test-batch-ccp").expect("init socket");
+    let peers = ccp.peers();
+    let app = Socket::<Blocking>::new("portus-test-batch-app").expect("init socket");
+    let mut buf = [0u8; 1024];
+    let b = super::Backend::new(ccp, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+
+    // messages of different lengths arrive one by one, as they were sent
+    let msgs: Vec<Vec<u8>> = ["a", "hello, world", "", "batched"]
+        .iter()
+        .map(|s| serialize::serialize(&TestMsg(String::from(*s))).expect("serialize test msg"))
+        .collect();
+    let batch: Vec<&[u8]> = msgs.iter().map(|m| &m[..]).collect();
+    let sender = b.sender(UnixAddr::Path(PathBuf::from("portus-test-batch-app")));
+    let (sent, res) = sender.send_msgs(&batch);
+    res.expect("send batch");
+    assert_eq!(sent, msgs.len());
+    let mut rbuf = [0u8; 1024];
+    for m in &msgs {
+        let (len, _) = app.recv(&mut rbuf).expect("recv");
+        assert_eq!(&rbuf[..len], &m[..]);
+    }
+
+    // the batch stops at the first message with nowhere to go
+    peers.register(1, UnixAddr::Path(PathBuf::from("portus-test-batch-app")));
+    let msg = |sid| {
+        serialize::serialize(&serialize::update_field::Msg {
+            sid,
+            num_fields: 0,
+            fields: vec![],
+            widths: None,
+        })
+        .expect("serialize update")
+    };
+    let (one, three) = (msg(1), msg(3));
+    let (sent, res) = b
+        .sender(UnixAddr::default())
+        .send_msgs(&[&one[..], &three[..], &one[..]]);
+    assert_eq!(sent, 1);
+    let err = res.expect_err("no peer for sid 3");
+    assert!(err.0.contains("sock_id 3"), "{}", err.0);
+    let (len, _) = app.recv(&mut rbuf).expect("recv");
+    assert_eq!(&rbuf[..len], &one[..]);
+
+    assert_eq!(b.stats().sent(), msgs.len() as u64 + 1);
+    assert_eq!(b.stats().send_errors(), 1);
+}
+
+#[test]
+fn test_unix_recv_batch() {
+    use super::unix::{Socket, UnixAddr};
+    use std::path::PathBuf;
+
+    let ccp = Socket::<Blocking>::new("portus-test-recvbatch-ccp").expect("init socket");
+    let app = Socket::<Blocking>::new("portus-test-recvbatch-app").expect("init socket");
+    let mut buf = [0u8; 1024];
+    let mut b = super::Backend::new(ccp, Arc::new(atomic::AtomicBool::new(true)), &mut buf[..]);
+    assert!(b.set_recv_batch(0, 512).is_err());
+    assert!(b.set_recv_batch(4, 2048).is_err());
+    // 128 bytes per message
+    b.set_recv_batch(4, 512).expect("batch");
+
+    let msgs: Vec<Vec<u8>> = (0..10)
+        .map(|i| serialize::serialize(&TestMsg("x".repeat(i * 10))).expect("serialize test msg"))
+        .collect();
+    let long = serialize::serialize(&TestMsg("x".repeat(200))).expect("serialize test msg");
+    let to = UnixAddr::Path(PathBuf::from("portus-test-recvbatch-ccp"));
+    for m in &msgs[..5] {
+        app.send(m, &to).expect("send");
+    }
+    app.send(&long, &to).expect("send");
+    for m in &msgs[5..] {
+        app.send(m, &to).expect("send");
+    }
+
+    let from = UnixAddr::Path(PathBuf::from("/tmp/ccp/portus-test-recvbatch-app"));
+    let mut got = vec![];
+    while got.len() < msgs.len() {
+        match b.try_recv() {
+            Ok(Some((m, addr))) => {
+                assert_eq!(addr, from);
+                got.push(m.into_vec());
+            }
+            Ok(None) => panic!("messages went missing"),
+            Err(e) => assert!(e.0.contains("truncated"), "{}", e.0),
+        }
+    }
+
+    assert_eq!(got, msgs);
+    assert_eq!(b.stats().recv_errors(), 1);
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_netlink_recv_batch() {
+    use super::netlink::{Socket, SocketOptions};
+
+    let opts = |portid, dest_portid| SocketOptions {
+        portid: Some(portid),
+        dest_portid,
+        ..Default::default()
+    };
+    // netlink sockets may not be available, e.g. in a container
+    let rx = match Socket::<Blocking>::with_options(opts(0x7c_c011, 0)) {
+        Ok(s) => s,
+        Err(_) => return,
+    };
+    let tx = Socket::<Blocking>::with_options(opts(0x7c_c012, 0x7c_c011)).expect("sender");
+
+    // the long one is split into fragments
+    let msgs = vec![vec![1u8; 10], vec![2u8; 3000], vec![3u8; 500]];
+    for m in &msgs {
+        tx.send(m, &()).expect("send");
+    }
+
+    let mut arena = vec![0u8; 4 * 4096];
+    let mut got = vec![];
+    while got.len() < msgs.len() {
+        let mut batch = vec![];
+        rx.recv_many(&mut arena, 4096, &mut batch).expect("recv");
+        assert!(!batch.is_empty(), "timed out");
+        got.extend(
+            batch
+                .iter()
+                .map(|m| arena[m.start..m.start + m.len].to_vec()),
+        );
+    }
+
+    assert_eq!(got, msgs);
+}
+
+#[cfg(feature = "mio")]
+#[test]
+fn test_poll_unix_and_udp() {
+    use super::Nonblocking;
+    let sk1 =
+        super::unix::Socket::<Nonblocking>::new("portus-test-poll-unix").expect("init socket");
+    let sk2 = super::udp::Socket::<Nonblocking>::new("127.0.0.1:0").expect("init socket");
+    let udp_addr = sk2.local_addr().expect("socket addr");
+    let mut ubuf = [0u8; 1024];
+    let mut u = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut ubuf[..]);
+    let mut dbuf = [0u8; 1024];
+    let mut d = super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut dbuf[..]);
+
+    let mut poller = super::poll::Poller::new().expect("init poller");
+    let ut = poller.register(&u).expect("register unix");
+    let dt = poller.register(&d).expect("register udp");
+    assert!(poller.register(&u).is_err());
+
+    let c2 = thread::spawn(move || {
+        let peer_u =
+            super::unix::Socket::<Blocking>::new("portus-test-poll-peer").expect("init socket");
+        let peer_d = super::udp::Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+        for s in &["a", "b"] {
+            let msg = serialize::serialize(&TestMsg(String::from(*s))).expect("serialize");
+            peer_u
+                .send(
+                    &msg[..],
+                    &std::path::PathBuf::from("portus-test-poll-unix").into(),
+                )
+                .expect("send unix");
+            peer_d.send(&msg[..], &Some(udp_addr)).expect("send udp");
+        }
+    });
+
+    let (mut from_unix, mut from_udp) = (vec![], vec![]);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while (from_unix.len() < 2 || from_udp.len() < 2) && std::time::Instant::now() < deadline {
+        for t in poller
+            .wait(Some(std::time::Duration::from_millis(100)))
+            .expect("wait")
+        {
+            // drain each ready backend, since readiness is edge-triggered
+            if t == ut {
+                while let Some((Msg::Other(r), _)) = u.try_next().expect("unix recv") {
+                    from_unix.push(r.get_bytes().unwrap().to_vec());
+                }
+            } else if t == dt {
+                while let Some((Msg::Other(r), _)) = d.try_next().expect("udp recv") {
+                    from_udp.push(r.get_bytes().unwrap().to_vec());
+                }
+            } else {
+                unreachable!();
+            }
+        }
+    }
+
+    c2.join().expect("join sender thread");
+    assert_eq!(from_unix, vec![b"a".to_vec(), b"b".to_vec()]);
+    assert_eq!(from_udp, vec![b"a".to_vec(), b"b".to_vec()]);
+}
+
+// Backends which become readable in the opposite order to their registration are still returned
+// in token order.
+#[cfg(feature = "mio")]
+#[test]
+fn test_poll_token_order() {
+    use super::Nonblocking;
+    let sk1 =
+        super::unix::Socket::<Nonblocking>::new("portus-test-poll-order-1").expect("init socket");
+    let sk2 =
+        super::unix::Socket::<Nonblocking>::new("portus-test-poll-order-2").expect("init socket");
+    let mut buf1 = [0u8; 1024];
+    let b1 = super::Backend::new(sk1, Arc::new(atomic::AtomicBool::new(true)), &mut buf1[..]);
+    let mut buf2 = [0u8; 1024];
+    let b2 = super::Backend::new(sk2, Arc::new(atomic::AtomicBool::new(true)), &mut buf2[..]);
+
+    let mut poller = super::poll::Poller::new().expect("init poller");
+    let t1 = poller.register(&b1).expect("register first");
+    let t2 = poller.register(&b2).expect("register second");
+
+    let peer =
+        super::unix::Socket::<Blocking>::new("portus-test-poll-order-peer").expect("init socket");
+    let msg = serialize::serialize(&TestMsg(String::from("a"))).expect("serialize");
+    for to in &["portus-test-poll-order-2", "portus-test-poll-order-1"] {
+        peer.send(&msg[..], &std::path::PathBuf::from(*to).into())
+            .expect("send");
+    }
+
+    let ready = poller
+        .wait(Some(std::time::Duration::from_secs(1)))
+        .expect("wait");
+    assert_eq!(ready, vec![t1, t2]);
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn test_tokio_unix() {
+    let rt = ::tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("build runtime");
+    rt.block_on(async {
+        let sk1 = super::tokio::Socket::new("portus-test-tokio-1").expect("init socket");
+        let sk2 = super::tokio::Socket::new("portus-test-tokio-2").expect("init socket");
+        let test_msg = TestMsg(String::from("hello, world"));
+        let test_msg_buf = serialize::serialize(&test_msg).expect("serialize test msg");
+
+        let mut buf = [0u8; 1024];
+        let recv = sk1.recv_msg(&mut buf);
+        let send = sk2.send_msg(
+            &test_msg_buf[..],
+            std::path::Path::new("portus-test-tokio-1"),
+        );
+        let (recv, send) = ::tokio::join!(recv, send);
+        send.expect("send message");
+        let (len, from) = recv.expect("receive message");
+        assert_eq!(&buf[..len], &test_msg_buf[..]);
+
+        // replies go back to the sender's full path
+        sk1.send_msg(&test_msg_buf[..], &from)
+            .await
+            .expect("send reply");
+        let (len, _) = sk2.recv_msg(&mut buf).await.expect("receive reply");
+        assert_eq!(&buf[..len], &test_msg_buf[..]);
+    });
+}
@@ -0,0 +1,113 @@
+//! A unix domain socket which can be awaited on from within a tokio runtime.
+//!
+//! Messages are exchanged with the datapath exactly as with [`unix::Socket`](../unix/struct.Socket.html):
+//! the socket binds under `/tmp/ccp`, and relative destination addresses are resolved there too.
+//!
+//! Use `recv_msg` and `send_msg` to talk to the datapath directly, or pass the socket to
+//! [`RunBuilder::run_async`](../../struct.RunBuilder.html#method.run_async) to run congestion
+//! control algorithms on it.
+
+use super::unix::{bind_error, dest_path, prepare_bind, SocketFile, DEFAULT_DIR};
+use super::{Error, Result};
+use ::tokio::net::UnixDatagram;
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tracing::trace;
+
+/// Clones share the same underlying socket.
+#[derive(Clone)]
+pub struct Socket {
+    sk: Arc<UnixDatagram>,
+    _file: Arc<SocketFile>,
+}
+
+impl Socket {
+    /// Bind to `bind_to` under `/tmp/ccp`.
+    ///
+    /// This must be called from within a tokio runtime.
+    pub fn new(bind_to: &str) -> Result<Self> {
+        let path = Path::new(DEFAULT_DIR).join(bind_to);
+        prepare_bind(&path)?;
+        let sk = UnixDatagram::bind(&path).map_err(|e| bind_error(&path, e))?;
+        Ok(Socket {
+            sk: Arc::new(sk),
+            _file: Arc::new(SocketFile::new(&path)?),
+        })
+    }
+
+    /// Wait for a message, and return its length and the address it came from.
+    pub async fn recv_msg(&self, buf: &mut [u8]) -> Result<(usize, PathBuf)> {
+        loop {
+            let (size, addr) = self.sk.recv_from(buf).await?;
+            match addr.as_pathname() {
+                Some(p) => return Ok((size, p.to_path_buf())),
+                None => trace!("dropping message with no recv addr"),
+            }
+        }
+    }
+
+    /// Send `msg` to `to`.
+    pub async fn send_msg(&self, msg: &[u8], to: &Path) -> Result<()> {
+        self.sk
+            .send_to(msg, dest_path(Path::new(DEFAULT_DIR), to))
+            .await?;
+        Ok(())
+    }
+
+    /// Wait until a message can be received without blocking.
+    pub async fn readable(&self) -> Result<()> {
+        self.sk.readable().await.map_err(Error::from)
+    }
+}
+
+// The synchronous interface never blocks: `recv` returns no message if none is waiting, so that
+// callers can await `readable()` in between.
+impl super::IpcSend for Socket {
+    type Addr = PathBuf;
+
+    fn name() -> String {
+        String::from("tokio-unix")
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        self.sk
+            .try_send_to(msg, dest_path(Path::new(DEFAULT_DIR), to))
+            .map(|_| ())
+            .map_err(super::send_error)
+    }
+}
+
+impl super::IpcRecv for Socket {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        match self.sk.try_recv_from(msg) {
+            Ok((size, addr)) => match addr.as_pathname() {
+                Some(p) => Ok((size, p.to_path_buf())),
+                None => {
+                    trace!("dropping message with no recv addr");
+                    Ok((0, Default::default()))
+                }
+            },
+            Err(e) if super::is_transient(&e) => Ok((0, Default::default())),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match self.sk.shutdown(std::net::Shutdown::Both) {
+            Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+            r => r.map_err(Error::from),
+        }
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl AsRawFd for Socket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sk.as_raw_fd()
+    }
+}
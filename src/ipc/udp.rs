@@ -0,0 +1,263 @@
+use super::{Error, Result};
+use crate::serialize::{u32_from_u8s, u32_to_u8s};
+use nix::sys::socket::{self, InetAddr, MsgFlags, SockAddr};
+use nix::sys::uio::IoVec;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::unix::io::AsRawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::debug;
+
+// Each datagram is preceded by a per-destination sequence number, so the receiver can detect
+// datagrams that were dropped or reordered in flight.
+//
+// |-----------|-------------------|
+// | Seq       | Message           |
+// | u32       |                   |
+// |-----------|-------------------|
+const SEQ_LEN: usize = 4;
+
+pub struct Socket<T> {
+    sk: UdpSocket,
+    // next sequence number to send to each destination
+    send_seq: Mutex<HashMap<SocketAddr, u32>>,
+    // last sequence number received from each source
+    recv_seq: Mutex<HashMap<SocketAddr, u32>>,
+    gaps: AtomicU64,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn __new<A: ToSocketAddrs>(bind_to: A) -> Result<Self> {
+        let sk = UdpSocket::bind(bind_to).map_err(|e| Error(format!("udp bind failed: {}", e)))?;
+        sk.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        Ok(Socket {
+            sk,
+            send_seq: Mutex::new(HashMap::new()),
+            recv_seq: Mutex::new(HashMap::new()),
+            gaps: AtomicU64::new(0),
+            _phantom: PhantomData,
+        })
+    }
+
+    /// The address this socket is bound to.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        self.sk.local_addr().map_err(Error::from)
+    }
+
+    // Compare the received sequence number against the last one seen from this source.
+    fn check_seq(&self, from: SocketAddr, seq: u32) {
+        let mut recv_seq = match self.recv_seq.lock() {
+            Ok(r) => r,
+            Err(_) => return,
+        };
+
+        let last = match recv_seq.insert(from, seq) {
+            Some(l) => l,
+            None => return,
+        };
+
+        let ahead = seq.wrapping_sub(last);
+        if ahead == 0 || ahead > u32::MAX / 2 {
+            // duplicate or reordered: this one arrived after a later one
+            debug!(?from, ?seq, ?last, "reordered datagram");
+            recv_seq.insert(from, last);
+            self.gaps.fetch_add(1, Ordering::SeqCst);
+        } else if ahead > 1 {
+            debug!(?from, ?seq, ?last, "dropped datagrams");
+            self.gaps.fetch_add(u64::from(ahead - 1), Ordering::SeqCst);
+        }
+    }
+
+    fn __recv(&self, msg: &mut [u8], flags: MsgFlags) -> Result<(usize, Option<SocketAddr>)> {
+        let mut seq_buf = [0u8; SEQ_LEN];
+        let r = match socket::recvmsg(
+            self.sk.as_raw_fd(),
+            &[
+                IoVec::from_mut_slice(&mut seq_buf[..]),
+                IoVec::from_mut_slice(msg),
+            ],
+            None,
+            flags,
+        ) {
+            Ok(r) => r,
+            Err(nix::errno::Errno::EAGAIN) | Err(nix::errno::Errno::EINTR) => return Ok((0, None)),
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        let from = match r.address {
+            Some(SockAddr::Inet(a)) => a.to_std(),
+            _ => return Ok((0, None)),
+        };
+
+        if r.bytes < SEQ_LEN {
+            debug!(?from, "dropping runt datagram");
+            return Ok((0, Some(from)));
+        }
+
+        self.check_seq(from, u32_from_u8s(&seq_buf));
+
+        // The kernel discards whatever did not fit in the buffer. Passing on the truncated
+        // remainder would be parsed as a corrupt message, so drop it entirely.
+        if r.flags.contains(MsgFlags::MSG_TRUNC) {
+            debug!(?from, buf_len = msg.len(), "dropping truncated datagram");
+            return Ok((0, Some(from)));
+        }
+
+        Ok((r.bytes - SEQ_LEN, Some(from)))
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
+    /// `None` is only used before any message is received; sends must name a destination.
+    type Addr = Option<SocketAddr>;
+
+    fn name() -> String {
+        String::from("udp")
+    }
+
+    fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
+        let to = to.ok_or_else(|| Error(String::from("udp send needs a destination address")))?;
+        let seq = {
+            let mut send_seq = self
+                .send_seq
+                .lock()
+                .map_err(|_| Error(String::from("udp sequence numbers poisoned")))?;
+            let next = send_seq.entry(to).or_insert(0);
+            let seq = *next;
+            *next = next.wrapping_add(1);
+            seq
+        };
+
+        let mut seq_buf = [0u8; SEQ_LEN];
+        u32_to_u8s(&mut seq_buf, seq);
+        socket::sendmsg(
+            self.sk.as_raw_fd(),
+            &[IoVec::from_slice(&seq_buf[..]), IoVec::from_slice(msg)],
+            &[],
+            MsgFlags::empty(),
+            Some(&SockAddr::new_inet(InetAddr::from_std(&to))),
+        )
+        .map(|_| ())
+        .map_err(|e| super::send_error(std::io::Error::from(e)))
+    }
+
+    fn set_send_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.sk
+            .set_write_timeout(Some(timeout))
+            .map_err(Error::from)
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        self.__recv(msg, MsgFlags::empty())
+    }
+
+    fn close(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        self.sk.set_read_timeout(Some(timeout)).map_err(Error::from)
+    }
+
+    fn gaps(&self) -> u64 {
+        self.gaps.load(Ordering::SeqCst)
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        self.sk.as_raw_fd()
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    pub fn new<A: ToSocketAddrs>(bind_to: A) -> Result<Self> {
+        Socket::__new(bind_to)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    pub fn new<A: ToSocketAddrs>(bind_to: A) -> Result<Self> {
+        let sk = Socket::__new(bind_to)?;
+        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        Ok(sk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use crate::ipc::{Blocking, IpcRecv};
+    use std::net::UdpSocket;
+
+    fn send_raw(from: &UdpSocket, to: std::net::SocketAddr, seq: u32, payload: &[u8]) {
+        let mut buf = seq.to_le_bytes().to_vec();
+        buf.extend_from_slice(payload);
+        from.send_to(&buf, to).expect("send raw datagram");
+    }
+
+    #[test]
+    fn count_gaps() {
+        let sk = Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+        let to = sk.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").expect("bind peer");
+
+        // 2 is dropped, 5 arrives late
+        for seq in &[0, 1, 3, 4, 6, 5, 7] {
+            send_raw(&peer, to, *seq, b"x");
+        }
+
+        let mut buf = [0u8; 64];
+        for _ in 0..7 {
+            let (len, from) = sk.recv(&mut buf).expect("recv");
+            assert_eq!(len, 1);
+            assert_eq!(from, Some(peer.local_addr().unwrap()));
+        }
+
+        // one for the drop of 2, one for skipping 5, one for 5 arriving out of order
+        assert_eq!(sk.gaps(), 3);
+    }
+
+    #[test]
+    fn seq_wraparound() {
+        let sk = Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+        let to = sk.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").expect("bind peer");
+        for seq in &[u32::MAX - 1, u32::MAX, 0, 1] {
+            send_raw(&peer, to, *seq, b"x");
+        }
+
+        let mut buf = [0u8; 64];
+        for _ in 0..4 {
+            sk.recv(&mut buf).expect("recv");
+        }
+
+        assert_eq!(sk.gaps(), 0);
+    }
+
+    #[test]
+    fn truncated() {
+        let sk = Socket::<Blocking>::new("127.0.0.1:0").expect("init socket");
+        let to = sk.local_addr().unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").expect("bind peer");
+        send_raw(&peer, to, 0, &[7u8; 128]);
+        send_raw(&peer, to, 1, &[7u8; 16]);
+
+        let mut buf = [0u8; 64];
+        let (len, _) = sk.recv(&mut buf).expect("recv");
+        assert_eq!(len, 0);
+        let (len, _) = sk.recv(&mut buf).expect("recv");
+        assert_eq!(len, 16);
+    }
+}
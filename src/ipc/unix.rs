@@ -1,37 +1,446 @@
 use super::{Error, Result};
+use crate::serialize::{self, create, HDR_LENGTH};
+use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::os::unix::{io::AsRawFd, net::UnixDatagram};
-use std::path::PathBuf;
-use tracing::trace;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::{
+    fs::MetadataExt,
+    io::AsRawFd,
+    net::{SocketAddr, UnixDatagram},
+};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tracing::{info, trace, warn};
+
+mod conn;
+use conn::Conn;
+use nix::sys::socket::SockType;
+
+/// The kind of unix socket to use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SocketKind {
+    /// `SOCK_DGRAM`: messages are dropped if the receiver's buffer is full.
+    Datagram,
+    /// `SOCK_SEQPACKET`: sends wait until the receiver has room (or, for `Nonblocking`
+    /// sockets, fail with `EAGAIN`), so no messages are lost. The socket listens at its address
+    /// for the datapath to connect, and serves one datapath at a time; the destination address of
+    /// sends is ignored. When the datapath disconnects, the socket waits for it to connect again.
+    SeqPacket,
+    /// `SOCK_STREAM`: like `SeqPacket`, but the datapath writes its messages to a byte stream,
+    /// and they are split back apart using the length in each message's header. Partial reads
+    /// and writes are finished before a message is passed on or a send returns.
+    Stream,
+}
+
+/// How a socket created with `new_with_reconnect` waits for a restarting datapath.
+///
+/// When a send fails because the datapath's socket is gone, the send is retried, first after
+/// `initial_backoff` and then doubling the wait each time up to `max_backoff`, until it succeeds
+/// or `max_attempts` retries have failed. Like any backoff, this blocks the caller, including for
+/// `Nonblocking` sockets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            max_attempts: 10,
+        }
+    }
+}
+
+/// Who may connect to a socket bound to a path, set on the socket file after it is bound and
+/// before the socket accepts anything, e.g. so that an unprivileged datapath can reach a CCP
+/// agent running as root.
+///
+/// The default leaves the file as `bind` created it: owned by this process's user, with the mode
+/// allowed by its umask.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Permissions {
+    /// The file's permission bits, e.g. `0o666`.
+    pub mode: Option<u32>,
+    /// The user and group to give the file to, which usually requires root.
+    pub owner: Option<(libc::uid_t, libc::gid_t)>,
+}
+
+/// Which users and groups may send to a socket created with `new_with_allowed_peers`, e.g. so
+/// that untrusted workloads on a shared host cannot send create messages for other flows.
+///
+/// A peer is allowed if its uid is in `uids` or its gid is in `gids`; the default allows no one.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllowedPeers {
+    pub uids: Vec<libc::uid_t>,
+    pub gids: Vec<libc::gid_t>,
+}
+
+impl AllowedPeers {
+    fn allows(&self, uid: libc::uid_t, gid: libc::gid_t) -> bool {
+        self.uids.contains(&uid) || self.gids.contains(&gid)
+    }
+}
+
+// Set `perms` on the socket file at `path`.
+pub(super) fn set_permissions(path: &Path, perms: Permissions) -> Result<()> {
+    if let Some((uid, gid)) = perms.owner {
+        nix::unistd::chown(
+            path,
+            Some(nix::unistd::Uid::from_raw(uid)),
+            Some(nix::unistd::Gid::from_raw(gid)),
+        )
+        .map_err(|e| {
+            Error(format!(
+                "could not chown unix socket {:?} to {}:{}: {}",
+                path, uid, gid, e
+            ))
+        })?;
+    }
+
+    if let Some(mode) = perms.mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).map_err(|e| {
+            Error(format!(
+                "could not chmod unix socket {:?} to {:o}: {}",
+                path, mode, e
+            ))
+        })?;
+    }
+
+    Ok(())
+}
+
+// Errors which mean that nothing is bound to the destination address (any more).
+fn is_peer_gone(e: &std::io::Error) -> bool {
+    // the datapath unlinks and re-binds its socket when it restarts, so the path can be missing
+    matches!(
+        e.raw_os_error(),
+        Some(libc::ECONNREFUSED) | Some(libc::ENOTCONN) | Some(libc::ENOENT)
+    )
+}
+
+/// Where `new` binds sockets, and where relative destination addresses are resolved.
+pub const DEFAULT_DIR: &str = "/tmp/ccp";
+
+/// The address of a unix socket.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum UnixAddr {
+    /// A socket file. Relative paths are resolved in the socket's destination directory
+    /// (`DEFAULT_DIR` unless set with `new_with_paths`).
+    Path(PathBuf),
+    /// A name in Linux's abstract socket namespace, without the leading NUL byte. No file is
+    /// created for these.
+    #[cfg(target_os = "linux")]
+    Abstract(String),
+}
+
+/// The default address, an empty path, sends each message to the peer registered for its
+/// sock_id in the socket's `PeerTable`.
+impl Default for UnixAddr {
+    fn default() -> Self {
+        UnixAddr::Path(PathBuf::new())
+    }
+}
+
+impl From<PathBuf> for UnixAddr {
+    fn from(p: PathBuf) -> Self {
+        UnixAddr::Path(p)
+    }
+}
+
+impl UnixAddr {
+    // `bind_to` under `DEFAULT_DIR`.
+    fn in_default_dir(bind_to: &str) -> Self {
+        UnixAddr::Path(Path::new(DEFAULT_DIR).join(bind_to))
+    }
+
+    // Linux has abstract addresses too, see `from_nix_addr`.
+    #[cfg(not(target_os = "linux"))]
+    fn from_socket_addr(addr: &SocketAddr) -> Option<Self> {
+        addr.as_pathname().map(|p| UnixAddr::Path(p.to_path_buf()))
+    }
+
+    #[cfg(target_os = "linux")]
+    fn from_nix_addr(addr: &nix::sys::socket::SockAddr) -> Option<Self> {
+        let addr = match addr {
+            nix::sys::socket::SockAddr::Unix(a) => a,
+            _ => return None,
+        };
+
+        if let Some(p) = addr.path() {
+            return Some(UnixAddr::Path(p.to_path_buf()));
+        }
+
+        addr.as_abstract()
+            .and_then(|n| std::str::from_utf8(n).ok())
+            .map(|n| UnixAddr::Abstract(n.to_owned()))
+    }
+}
+
+// A datagram received by `recv_datagram`.
+struct Datagram {
+    len: usize,
+    // `None` if the sender is not bound to an address
+    from: Option<UnixAddr>,
+    // from the kernel's timestamp
+    at: Option<SystemTime>,
+    // the sender's uid and gid, if the socket has `SO_PASSCRED` set
+    creds: Option<(libc::uid_t, libc::gid_t)>,
+}
+
+#[cfg(target_os = "linux")]
+fn recv_datagram(sk: &UnixDatagram, msg: &mut [u8]) -> std::io::Result<Datagram> {
+    use nix::sys::socket::UnixCredentials;
+    let mut cmsgs = nix::cmsg_space!(nix::sys::time::TimeSpec, UnixCredentials);
+    let r = nix::sys::socket::recvmsg(
+        sk.as_raw_fd(),
+        &[nix::sys::uio::IoVec::from_mut_slice(msg)],
+        Some(&mut cmsgs),
+        nix::sys::socket::MsgFlags::empty(),
+    )?;
+
+    Ok(Datagram::from_recv_msg(&r))
+}
+
+#[cfg(target_os = "linux")]
+impl Datagram {
+    fn from_recv_msg(r: &nix::sys::socket::RecvMsg<'_>) -> Self {
+        use nix::sys::socket::ControlMessageOwned;
+        let mut d = Datagram {
+            len: r.bytes,
+            from: r.address.as_ref().and_then(UnixAddr::from_nix_addr),
+            at: None,
+            creds: None,
+        };
+        for c in r.cmsgs() {
+            match c {
+                ControlMessageOwned::ScmCredentials(cr) => d.creds = Some((cr.uid(), cr.gid())),
+                c => d.at = d.at.or_else(|| super::kernel_timestamp(c)),
+            }
+        }
+
+        d
+    }
+}
+
+// Receive datagrams into the slots of `arena` with one `recvmmsg` call, see
+// `IpcRecv::recv_many`.
+#[cfg(target_os = "linux")]
+fn recv_datagrams(
+    sk: &UnixDatagram,
+    arena: &mut [u8],
+    slot_len: usize,
+) -> std::io::Result<Vec<Datagram>> {
+    use nix::sys::socket::UnixCredentials;
+    let mut got = Vec::with_capacity(arena.len() / slot_len);
+    super::recv_mmsg(
+        sk.as_raw_fd(),
+        arena,
+        slot_len,
+        nix::cmsg_space!(nix::sys::time::TimeSpec, UnixCredentials).capacity(),
+        nix::sys::socket::MsgFlags::empty(),
+        |_, r| got.push(Datagram::from_recv_msg(&r)),
+    )?;
+
+    Ok(got)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn recv_datagram(sk: &UnixDatagram, msg: &mut [u8]) -> std::io::Result<Datagram> {
+    let (len, addr) = sk.recv_from(msg)?;
+    Ok(Datagram {
+        len,
+        from: UnixAddr::from_socket_addr(&addr),
+        at: None,
+        creds: None,
+    })
+}
+
+/// Which peer each sock_id belongs to, so that one socket can serve several datapaths, e.g.
+/// independent applications each running their own userspace datapath.
+///
+/// The socket registers the sender of each create message it receives; entries can also be
+/// added, replaced, or removed by hand. Messages sent to `UnixAddr::default()` go to the peer
+/// registered for the sock_id in their header, and fail if there is none.
+///
+/// Being keyed by sock_id alone, the table can only route for datapaths whose sock_ids do not
+/// overlap. The runtime does not rely on it: each flow sends to the address its create message
+/// came from.
+#[derive(Clone, Debug, Default)]
+pub struct PeerTable(Arc<Mutex<HashMap<u64, UnixAddr>>>);
+
+impl PeerTable {
+    fn peers(&self) -> std::sync::MutexGuard<'_, HashMap<u64, UnixAddr>> {
+        // the table is only ever updated in one step, so it is never left inconsistent
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Register `peer` for `sock_id`, and return the peer previously registered, if any.
+    pub fn register(&self, sock_id: u64, peer: UnixAddr) -> Option<UnixAddr> {
+        self.peers().insert(sock_id, peer)
+    }
+
+    /// Remove and return the peer registered for `sock_id`.
+    pub fn unregister(&self, sock_id: u64) -> Option<UnixAddr> {
+        self.peers().remove(&sock_id)
+    }
+
+    /// The peer registered for `sock_id`.
+    pub fn get(&self, sock_id: u64) -> Option<UnixAddr> {
+        self.peers().get(&sock_id).cloned()
+    }
+
+    // Register `from` for each create message in `buf`.
+    fn learn(&self, buf: &[u8], from: &UnixAddr) {
+        let mut off = 0;
+        while let Some(hdr) = buf.get(off..off + HDR_LENGTH as usize) {
+            let len = serialize::claimed_len(&buf[off..]).unwrap_or(0);
+            if hdr[0] == create::CREATE {
+                if let Some(sid) = serialize::sid(&buf[off..]) {
+                    self.register(sid, from.clone());
+                }
+            }
+
+            if len == 0 {
+                break;
+            }
+
+            off += len;
+        }
+    }
+
+    // Where to send `msg`, which should go to the peer for its sock_id.
+    fn route(&self, msg: &[u8]) -> Result<UnixAddr> {
+        let sid = serialize::sid(msg)
+            .ok_or_else(|| Error(String::from("message too short to have a sock_id")))?;
+        self.get(sid)
+            .ok_or_else(|| Error(format!("no known peer for sock_id {}", sid)))
+    }
+}
+
+// Create the directory for a socket at `path`, and remove any stale socket an earlier run left
+// there.
+pub(super) fn prepare_bind(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::fs::create_dir_all(dir).map_err(|e| {
+            Error(format!(
+                "could not create socket directory {:?}: {}",
+                dir, e
+            ))
+        })?,
+        _ => (),
+    }
+
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(Error(format!(
+            "could not remove stale socket {:?}: {}",
+            path, e
+        ))),
+        _ => Ok(()),
+    }
+}
+
+pub(super) fn bind_error(path: &Path, e: std::io::Error) -> Error {
+    Error(format!("could not bind unix socket {:?}: {}", path, e))
+}
+
+// Where to send to for `to`. Absolute paths, such as the addresses `recv` returns, are used as-is.
+pub(super) fn dest_path(dir: &Path, to: &Path) -> PathBuf {
+    dir.join(to)
+}
+
+// The socket file a socket is bound to, which is removed on drop unless something else has
+// since been bound at the same path.
+pub(super) struct SocketFile {
+    path: PathBuf,
+    id: (u64, u64),
+}
+
+impl SocketFile {
+    pub(super) fn new(path: &Path) -> Result<Self> {
+        let md = std::fs::metadata(path)?;
+        Ok(SocketFile {
+            path: path.to_path_buf(),
+            id: (md.dev(), md.ino()),
+        })
+    }
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if let Ok(md) = std::fs::metadata(&self.path) {
+            if (md.dev(), md.ino()) == self.id {
+                std::fs::remove_file(&self.path).unwrap_or(());
+            }
+        }
+    }
+}
+
+enum Sk {
+    Datagram(UnixDatagram),
+    // `SeqPacket` and `Stream` sockets
+    Conn(Conn),
+}
 
 pub struct Socket<T> {
-    sk: UnixDatagram,
+    sk: Sk,
+    // `None` for abstract sockets
+    _file: Option<SocketFile>,
+    tx_dir: PathBuf,
+    peers: PeerTable,
+    reconnect: Option<ReconnectPolicy>,
+    reconnects: AtomicU64,
+    // for datagram sockets; `Conn` checks each connection as it is accepted
+    allowed: Option<AllowedPeers>,
+    rejected: AtomicU64,
     _phantom: PhantomData<T>,
 }
 
 impl<T> Socket<T> {
     fn __new(
-        bind_to: &str,
+        rx: &UnixAddr,
+        tx_dir: &Path,
         sndbuf_bytes: Option<usize>,
         rcvbuf_bytes: Option<usize>,
+        reconnect: Option<ReconnectPolicy>,
+        perms: Permissions,
     ) -> Result<Self> {
-        let bind_to_addr = format!("/tmp/ccp/{}", bind_to.to_string());
-        // create dir if not already exists
-        match std::fs::create_dir_all("/tmp/ccp/").err() {
-            Some(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(()),
-            Some(e) => Err(e),
-            None => Ok(()),
-        }?;
-
-        // unlink before bind
-        match std::fs::remove_file(&bind_to_addr).err() {
-            Some(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
-            Some(e) => Err(e),
-            None => Ok(()),
-        }?;
-
-        let sock = UnixDatagram::bind(bind_to_addr)?;
+        let (sock, file) = match rx {
+            UnixAddr::Path(rx) => {
+                prepare_bind(rx)?;
+                let sock = UnixDatagram::bind(rx).map_err(|e| bind_error(rx, e))?;
+                let file = SocketFile::new(rx)?;
+                set_permissions(rx, perms)?;
+                (sock, Some(file))
+            }
+            #[cfg(target_os = "linux")]
+            UnixAddr::Abstract(name) => {
+                let sock = SocketAddr::from_abstract_name(name.as_bytes())
+                    .and_then(|addr| UnixDatagram::bind_addr(&addr))
+                    .map_err(|e| {
+                        Error(format!(
+                            "could not bind abstract unix socket {:?}: {}",
+                            name, e
+                        ))
+                    })?;
+                (sock, None)
+            }
+        };
         sock.set_read_timeout(Some(std::time::Duration::from_secs(1)))?;
+        // so that `recv_timestamped` can tell when each message arrived
+        #[cfg(target_os = "linux")]
+        nix::sys::socket::setsockopt(
+            sock.as_raw_fd(),
+            nix::sys::socket::sockopt::ReceiveTimestampns,
+            &true,
+        )?;
 
         if let Some(sb) = sndbuf_bytes {
             let snd_res = nix::sys::socket::setsockopt(
@@ -51,51 +460,521 @@ impl<T> Socket<T> {
             trace!(?rcvbuf_bytes, is_ok=?rcv_res.is_ok(), "set rcv buf sockopt");
         }
 
-        Ok(Socket {
-            sk: sock,
+        Ok(Self::from_parts(
+            Sk::Datagram(sock),
+            file,
+            tx_dir,
+            reconnect,
+        ))
+    }
+
+    fn __new_conn(
+        rx: &UnixAddr,
+        ty: SockType,
+        nonblocking: bool,
+        perms: Permissions,
+    ) -> Result<Self> {
+        if let UnixAddr::Path(p) = rx {
+            prepare_bind(p)?;
+        }
+
+        let sk = Conn::listen(rx, ty, nonblocking, perms)?;
+        let file = match rx {
+            UnixAddr::Path(p) => Some(SocketFile::new(p)?),
+            #[cfg(target_os = "linux")]
+            UnixAddr::Abstract(_) => None,
+        };
+        Ok(Self::from_parts(
+            Sk::Conn(sk),
+            file,
+            Path::new(DEFAULT_DIR),
+            None,
+        ))
+    }
+
+    fn __new_with_kind(
+        bind_to: &str,
+        kind: SocketKind,
+        nonblocking: bool,
+        perms: Permissions,
+    ) -> Result<Self> {
+        let sk = match kind {
+            SocketKind::Datagram => Self::__new(
+                &UnixAddr::in_default_dir(bind_to),
+                Path::new(DEFAULT_DIR),
+                None,
+                None,
+                None,
+                perms,
+            )?,
+            SocketKind::SeqPacket => {
+                return Self::__new_conn(
+                    &UnixAddr::in_default_dir(bind_to),
+                    SockType::SeqPacket,
+                    nonblocking,
+                    perms,
+                )
+            }
+            SocketKind::Stream => {
+                return Self::__new_conn(
+                    &UnixAddr::in_default_dir(bind_to),
+                    SockType::Stream,
+                    nonblocking,
+                    perms,
+                )
+            }
+        };
+
+        if nonblocking {
+            sk.set_nonblocking()?;
+        }
+
+        Ok(sk)
+    }
+
+    fn from_parts(
+        sk: Sk,
+        file: Option<SocketFile>,
+        tx_dir: &Path,
+        reconnect: Option<ReconnectPolicy>,
+    ) -> Self {
+        Socket {
+            sk,
+            _file: file,
+            tx_dir: tx_dir.to_path_buf(),
+            peers: PeerTable::default(),
+            reconnect,
+            reconnects: AtomicU64::new(0),
+            allowed: None,
+            rejected: AtomicU64::new(0),
             _phantom: PhantomData,
-        })
+        }
+    }
+
+    // Only accept messages from `allowed` peers from now on.
+    #[cfg(target_os = "linux")]
+    fn allow_only(mut self, allowed: AllowedPeers) -> Result<Self> {
+        match self.sk {
+            // messages which arrived before this have no credentials, so they are rejected too
+            Sk::Datagram(ref sk) => nix::sys::socket::setsockopt(
+                sk.as_raw_fd(),
+                nix::sys::socket::sockopt::PassCred,
+                &true,
+            )?,
+            Sk::Conn(ref mut sk) => sk.allow_only(allowed.clone()),
+        }
+
+        self.allowed = Some(allowed);
+        Ok(self)
+    }
+
+    /// How many messages (or, for `SeqPacket` and `Stream` sockets, connections) this socket has
+    /// rejected
+    /// because they came from a peer its `AllowedPeers` do not allow.
+    pub fn rejected(&self) -> u64 {
+        match self.sk {
+            Sk::Datagram(_) => self.rejected.load(Ordering::SeqCst),
+            Sk::Conn(ref sk) => sk.rejected(),
+        }
+    }
+
+    // Whether a datagram from a peer with `creds` should be passed on.
+    fn accepts(&self, creds: Option<(libc::uid_t, libc::gid_t)>, from: &UnixAddr) -> bool {
+        let allowed = match self.allowed {
+            Some(ref a) => a,
+            None => return true,
+        };
+
+        match creds {
+            Some((uid, gid)) if allowed.allows(uid, gid) => true,
+            _ => {
+                self.rejected.fetch_add(1, Ordering::SeqCst);
+                warn!(
+                    ?creds,
+                    ?from,
+                    "rejecting message from peer that is not allowed"
+                );
+                false
+            }
+        }
+    }
+
+    // What `recv` returns for datagram `d`, received into `msg`, unless it should be dropped.
+    fn accept_datagram(
+        &self,
+        d: Datagram,
+        msg: &[u8],
+    ) -> Option<(usize, UnixAddr, Option<SystemTime>)> {
+        match d {
+            Datagram {
+                from: Some(ref a),
+                creds,
+                ..
+            } if !self.accepts(creds, a) => None,
+            Datagram {
+                len,
+                from: Some(a),
+                at,
+                ..
+            } => {
+                self.peers.learn(&msg[..len], &a);
+                Some((len, a, at))
+            }
+            Datagram { from: None, .. } => {
+                trace!("dropping message with no recv addr");
+                None
+            }
+        }
+    }
+
+    /// The peers this socket has seen create messages from. The returned table is shared with
+    /// the socket, so it stays up to date once the socket is passed to a `Backend`.
+    pub fn peers(&self) -> PeerTable {
+        self.peers.clone()
+    }
+
+    fn set_nonblocking(&self) -> Result<()> {
+        match self.sk {
+            Sk::Datagram(ref sk) => sk.set_nonblocking(true).map_err(Error::from),
+            // set when the socket is created
+            Sk::Conn(_) => Ok(()),
+        }
+    }
+
+    fn send_once(&self, sk: &UnixDatagram, msg: &[u8], to: &UnixAddr) -> std::io::Result<usize> {
+        match to {
+            UnixAddr::Path(p) => sk.send_to(msg, dest_path(&self.tx_dir, p)),
+            #[cfg(target_os = "linux")]
+            UnixAddr::Abstract(name) => {
+                sk.send_to_addr(msg, &SocketAddr::from_abstract_name(name.as_bytes())?)
+            }
+        }
+    }
+
+    // Retry a send which failed because the datapath is gone, according to `policy`.
+    fn resend(
+        &self,
+        sk: &UnixDatagram,
+        msg: &[u8],
+        to: &UnixAddr,
+        err: std::io::Error,
+        policy: ReconnectPolicy,
+    ) -> Result<()> {
+        warn!(?to, %err, "datapath socket is gone, waiting for it to come back");
+        let mut backoff = policy.initial_backoff;
+        let mut err = err;
+        for attempt in 1..=policy.max_attempts {
+            std::thread::sleep(backoff);
+            backoff = std::cmp::min(backoff * 2, policy.max_backoff);
+            match self.send_once(sk, msg, to) {
+                Ok(_) => {
+                    self.reconnects.fetch_add(1, Ordering::SeqCst);
+                    info!(?to, ?attempt, "reconnected to datapath");
+                    return Ok(());
+                }
+                Err(e) if is_peer_gone(&e) => err = e,
+                Err(e) => return Err(super::send_error(e)),
+            }
+        }
+
+        Err(Error::from(err))
+    }
+
+    // The address `sendmmsg` should send `msg` to, for `to`.
+    #[cfg(target_os = "linux")]
+    fn sock_addr(&self, msg: &[u8], to: &UnixAddr) -> Result<nix::sys::socket::SockAddr> {
+        use nix::sys::socket::{SockAddr, UnixAddr as NixUnixAddr};
+        let routed;
+        let to = if *to == UnixAddr::default() {
+            routed = self.peers.route(msg)?;
+            &routed
+        } else {
+            to
+        };
+
+        match to {
+            UnixAddr::Path(p) => NixUnixAddr::new(&dest_path(&self.tx_dir, p)),
+            UnixAddr::Abstract(name) => NixUnixAddr::new_abstract(name.as_bytes()),
+        }
+        .map(SockAddr::Unix)
+        .map_err(Error::from)
     }
 }
 
-impl<T: 'static + Sync + Send> super::Ipc for Socket<T> {
-    type Addr = PathBuf;
+// Send `msgs` to `addrs` with one `sendmmsg` call, and return how many were sent.
+#[cfg(target_os = "linux")]
+fn send_mmsg(
+    sk: &UnixDatagram,
+    msgs: &[&[u8]],
+    addrs: &[nix::sys::socket::SockAddr],
+) -> nix::Result<usize> {
+    use nix::sys::socket::{sendmmsg, ControlMessage, MsgFlags, SendMmsgData};
+    use nix::sys::uio::IoVec;
+    let data: Vec<SendMmsgData<_, [ControlMessage; 0]>> = msgs
+        .iter()
+        .zip(addrs)
+        .map(|(msg, addr)| SendMmsgData {
+            iov: [IoVec::from_slice(msg)],
+            cmsgs: [],
+            addr: Some(*addr),
+            _lt: PhantomData,
+        })
+        .collect();
+    let lens = sendmmsg(sk.as_raw_fd(), &data, MsgFlags::empty())?;
+
+    // the lengths of the messages after the last one sent are left at 0
+    Ok(lens
+        .iter()
+        .zip(msgs)
+        .take_while(|&(&len, msg)| len == msg.len())
+        .count())
+}
+
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
+    type Addr = UnixAddr;
 
     fn name() -> String {
         String::from("unix")
     }
 
     fn send(&self, msg: &[u8], to: &Self::Addr) -> Result<()> {
-        let to = format!(
-            "/tmp/ccp/{}",
-            to.as_path()
-                .as_os_str()
-                .to_str()
-                .ok_or_else(|| Error("invalid addrress".to_owned()))?
-        );
-        self.sk.send_to(msg, to).map(|_| ()).map_err(Error::from)
+        let sk = match self.sk {
+            Sk::Datagram(ref sk) => sk,
+            Sk::Conn(ref sk) => return sk.send(msg),
+        };
+
+        let routed;
+        let to = if *to == UnixAddr::default() {
+            routed = self.peers.route(msg)?;
+            &routed
+        } else {
+            to
+        };
+
+        match (self.send_once(sk, msg, to), self.reconnect) {
+            (Ok(_), _) => Ok(()),
+            (Err(e), Some(policy)) if is_peer_gone(&e) => self.resend(sk, msg, to, e, policy),
+            (Err(e), _) => Err(super::send_error(e)),
+        }
+    }
+
+    /// Datagram sockets send the messages with `sendmmsg`. Each message still goes to the peer
+    /// `send` would send it to, and a message `sendmmsg` fails to send is retried with `send`, so
+    /// that it is retried according to the reconnect policy and fails as `send` would.
+    #[cfg(target_os = "linux")]
+    fn send_many(&self, msgs: &[&[u8]], to: &Self::Addr) -> (usize, Result<()>) {
+        let sk = match self.sk {
+            Sk::Datagram(ref sk) => sk,
+            Sk::Conn(_) => return super::send_each(self, msgs, to),
+        };
+
+        // a message with nowhere to go ends the batch
+        let mut addrs = Vec::with_capacity(msgs.len());
+        let mut route_err = Ok(());
+        for msg in msgs {
+            match self.sock_addr(msg, to) {
+                Ok(a) => addrs.push(a),
+                Err(e) => {
+                    route_err = Err(e);
+                    break;
+                }
+            }
+        }
+
+        let mut sent = 0;
+        while sent < addrs.len() {
+            match send_mmsg(sk, &msgs[sent..addrs.len()], &addrs[sent..]) {
+                Ok(n) if n > 0 => sent += n,
+                _ => match self.send(msgs[sent], to) {
+                    Ok(_) => sent += 1,
+                    Err(e) => return (sent, Err(e)),
+                },
+            }
+        }
+
+        (sent, route_err)
     }
 
+    fn set_send_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        match self.sk {
+            Sk::Datagram(ref sk) => sk.set_write_timeout(Some(timeout)).map_err(Error::from),
+            Sk::Conn(ref mut sk) => sk.set_send_timeout(timeout),
+        }
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
     fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
-        self.sk
-            .recv_from(msg)
-            .map_err(Error::from)
-            .and_then(|(size, addr)| match addr.as_pathname() {
-                Some(p) => Ok((size, p.to_path_buf())),
-                None => Err(Error(String::from("no recv addr"))),
-            })
+        let (len, from, _) = self.recv_timestamped(msg)?;
+        Ok((len, from))
+    }
+
+    /// On Linux, datagram sockets return the kernel's receive timestamp.
+    fn recv_timestamped(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr, Option<SystemTime>)> {
+        let sk = match self.sk {
+            Sk::Datagram(ref sk) => sk,
+            // there is only one peer
+            Sk::Conn(ref sk) => return sk.recv(msg).map(|len| (len, Default::default(), None)),
+        };
+
+        match recv_datagram(sk, msg) {
+            Ok(d) => Ok(self
+                .accept_datagram(d, msg)
+                .unwrap_or((0, Default::default(), None))),
+            Err(e) if super::is_transient(&e) => Ok((0, Default::default(), None)),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    /// On Linux, datagram sockets receive the messages with one `recvmmsg` call.
+    #[cfg(target_os = "linux")]
+    fn recv_many(
+        &self,
+        arena: &mut [u8],
+        slot_len: usize,
+        got: &mut Vec<super::Received<Self::Addr>>,
+    ) -> Result<()> {
+        let sk = match self.sk {
+            Sk::Datagram(ref sk) => sk,
+            Sk::Conn(_) => return super::recv_one(self, arena, slot_len, got),
+        };
+
+        let datagrams = match recv_datagrams(sk, arena, slot_len) {
+            Ok(d) => d,
+            Err(e) if super::is_transient(&e) => return Ok(()),
+            Err(e) => return Err(Error::from(e)),
+        };
+
+        for (i, d) in datagrams.into_iter().enumerate() {
+            let start = i * slot_len;
+            if let Some((len, from, at)) = self.accept_datagram(d, &arena[start..]) {
+                got.push(super::Received {
+                    start,
+                    len,
+                    from,
+                    at,
+                });
+            }
+        }
+
+        Ok(())
     }
 
     fn close(&mut self) -> Result<()> {
         use std::net::Shutdown;
-        self.sk.shutdown(Shutdown::Both).map_err(Error::from)
+        match self.sk {
+            Sk::Datagram(ref sk) => sk.shutdown(Shutdown::Both).map_err(Error::from),
+            Sk::Conn(ref sk) => sk.close(),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        match self.sk {
+            Sk::Datagram(ref sk) => sk.set_read_timeout(Some(timeout)).map_err(Error::from),
+            Sk::Conn(ref mut sk) => sk.set_recv_timeout(timeout),
+        }
+    }
+
+    fn reconnects(&self) -> u64 {
+        match self.sk {
+            Sk::Datagram(_) => self.reconnects.load(Ordering::SeqCst),
+            Sk::Conn(ref sk) => sk.reconnects(),
+        }
+    }
+
+    /// For `SeqPacket` and `Stream` sockets, this is the datapath's connection once it connects,
+    /// and the listening socket before that, so it changes when the datapath connects.
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    /// For `SeqPacket` and `Stream` sockets, this is the datapath's connection once it connects,
+    /// and the listening socket before that.
+    fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+        match self.sk {
+            Sk::Datagram(ref sk) => sk.as_raw_fd(),
+            Sk::Conn(ref sk) => sk.as_raw_fd(),
+        }
     }
 }
 
 use super::Blocking;
 impl Socket<Blocking> {
+    /// Bind a socket of the given `kind` to `bind_to` under `DEFAULT_DIR`. `new` makes a
+    /// `Datagram` socket.
+    pub fn new_with_kind(bind_to: &str, kind: SocketKind) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, false, Permissions::default())
+    }
+
+    /// Like `new_with_kind`, but set `perms` on the socket file before any datapath can connect.
+    pub fn new_with_permissions(
+        bind_to: &str,
+        kind: SocketKind,
+        perms: Permissions,
+    ) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, false, perms)
+    }
+
+    /// Like `new_with_kind`, but only accept messages from `allowed` peers, going by the
+    /// credentials the kernel attaches to each datagram, or for `SeqPacket` and `Stream`
+    /// sockets, by `SO_PEERCRED` when the datapath connects. Other messages and connections are
+    /// logged, dropped, and counted in `rejected`.
+    #[cfg(target_os = "linux")]
+    pub fn new_with_allowed_peers(
+        bind_to: &str,
+        kind: SocketKind,
+        allowed: AllowedPeers,
+    ) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, false, Permissions::default())?.allow_only(allowed)
+    }
+
+    /// Connect to a `SeqPacket` socket listening at `to`, e.g. to act as the datapath.
+    pub fn connect_seqpacket(to: &UnixAddr) -> Result<Self> {
+        let sk = Conn::connect(to, SockType::SeqPacket, false)?;
+        Ok(Socket::from_parts(
+            Sk::Conn(sk),
+            None,
+            Path::new(DEFAULT_DIR),
+            None,
+        ))
+    }
+
+    /// Connect to a `Stream` socket listening at `to`, e.g. to act as the datapath.
+    pub fn connect_stream(to: &UnixAddr) -> Result<Self> {
+        let sk = Conn::connect(to, SockType::Stream, false)?;
+        Ok(Socket::from_parts(
+            Sk::Conn(sk),
+            None,
+            Path::new(DEFAULT_DIR),
+            None,
+        ))
+    }
+
+    /// Bind to `bind_to` under `DEFAULT_DIR`.
     pub fn new(bind_to: &str) -> Result<Self> {
-        Socket::__new(bind_to, None, None)
+        Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )
+    }
+
+    /// Like `new`, but if the datapath goes away, e.g. because it restarted, wait for it to come
+    /// back according to `policy` instead of failing sends.
+    pub fn new_with_reconnect(bind_to: &str, policy: ReconnectPolicy) -> Result<Self> {
+        Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            Some(policy),
+            Permissions::default(),
+        )
     }
 
     pub fn new_with_skbuf(
@@ -103,14 +982,120 @@ impl Socket<Blocking> {
         sndbuf_bytes: Option<usize>,
         rcvbuf_bytes: Option<usize>,
     ) -> Result<Self> {
-        Socket::__new(bind_to, sndbuf_bytes, rcvbuf_bytes)
+        Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            sndbuf_bytes,
+            rcvbuf_bytes,
+            None,
+            Permissions::default(),
+        )
+    }
+
+    /// Bind to `rx`, and resolve relative destination addresses in `tx_prefix` rather than
+    /// `DEFAULT_DIR`. The socket file at `rx` is removed when the socket is dropped.
+    pub fn new_with_paths(rx: &Path, tx_prefix: &Path) -> Result<Self> {
+        Socket::__new(
+            &UnixAddr::Path(rx.to_path_buf()),
+            tx_prefix,
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )
+    }
+
+    /// Bind to `name` in the abstract socket namespace, so no socket file is created.
+    #[cfg(target_os = "linux")]
+    pub fn new_abstract(name: &str) -> Result<Self> {
+        Socket::__new(
+            &UnixAddr::Abstract(name.to_owned()),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )
     }
 }
 
 use super::Nonblocking;
 impl Socket<Nonblocking> {
+    /// Bind a socket of the given `kind` to `bind_to` under `DEFAULT_DIR`. `new` makes a
+    /// `Datagram` socket.
+    pub fn new_with_kind(bind_to: &str, kind: SocketKind) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, true, Permissions::default())
+    }
+
+    /// Like `new_with_kind`, but set `perms` on the socket file before any datapath can connect.
+    pub fn new_with_permissions(
+        bind_to: &str,
+        kind: SocketKind,
+        perms: Permissions,
+    ) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, true, perms)
+    }
+
+    /// Like `new_with_kind`, but only accept messages from `allowed` peers, going by the
+    /// credentials the kernel attaches to each datagram, or for `SeqPacket` and `Stream`
+    /// sockets, by `SO_PEERCRED` when the datapath connects. Other messages and connections are
+    /// logged, dropped, and counted in `rejected`.
+    #[cfg(target_os = "linux")]
+    pub fn new_with_allowed_peers(
+        bind_to: &str,
+        kind: SocketKind,
+        allowed: AllowedPeers,
+    ) -> Result<Self> {
+        Socket::__new_with_kind(bind_to, kind, true, Permissions::default())?.allow_only(allowed)
+    }
+
+    /// Connect to a `SeqPacket` socket listening at `to`, e.g. to act as the datapath.
+    pub fn connect_seqpacket(to: &UnixAddr) -> Result<Self> {
+        let sk = Conn::connect(to, SockType::SeqPacket, true)?;
+        Ok(Socket::from_parts(
+            Sk::Conn(sk),
+            None,
+            Path::new(DEFAULT_DIR),
+            None,
+        ))
+    }
+
+    /// Connect to a `Stream` socket listening at `to`, e.g. to act as the datapath.
+    pub fn connect_stream(to: &UnixAddr) -> Result<Self> {
+        let sk = Conn::connect(to, SockType::Stream, true)?;
+        Ok(Socket::from_parts(
+            Sk::Conn(sk),
+            None,
+            Path::new(DEFAULT_DIR),
+            None,
+        ))
+    }
+
+    /// Bind to `bind_to` under `DEFAULT_DIR`.
     pub fn new(bind_to: &str) -> Result<Self> {
-        Socket::__new(bind_to, None, None)
+        Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )
+    }
+
+    /// Like `new`, but if the datapath goes away, e.g. because it restarted, wait for it to come
+    /// back according to `policy` instead of failing sends.
+    pub fn new_with_reconnect(bind_to: &str, policy: ReconnectPolicy) -> Result<Self> {
+        let sk = Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            Some(policy),
+            Permissions::default(),
+        )?;
+        sk.set_nonblocking()?;
+        Ok(sk)
     }
 
     pub fn new_with_skbuf(
@@ -118,8 +1103,45 @@ impl Socket<Nonblocking> {
         sndbuf_bytes: Option<usize>,
         rcvbuf_bytes: Option<usize>,
     ) -> Result<Self> {
-        let sk = Socket::__new(bind_to, sndbuf_bytes, rcvbuf_bytes)?;
-        sk.sk.set_nonblocking(true).map_err(Error::from)?;
+        let sk = Socket::__new(
+            &UnixAddr::in_default_dir(bind_to),
+            Path::new(DEFAULT_DIR),
+            sndbuf_bytes,
+            rcvbuf_bytes,
+            None,
+            Permissions::default(),
+        )?;
+        sk.set_nonblocking()?;
+        Ok(sk)
+    }
+
+    /// Bind to `rx`, and resolve relative destination addresses in `tx_prefix` rather than
+    /// `DEFAULT_DIR`. The socket file at `rx` is removed when the socket is dropped.
+    pub fn new_with_paths(rx: &Path, tx_prefix: &Path) -> Result<Self> {
+        let sk = Socket::__new(
+            &UnixAddr::Path(rx.to_path_buf()),
+            tx_prefix,
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )?;
+        sk.set_nonblocking()?;
+        Ok(sk)
+    }
+
+    /// Bind to `name` in the abstract socket namespace, so no socket file is created.
+    #[cfg(target_os = "linux")]
+    pub fn new_abstract(name: &str) -> Result<Self> {
+        let sk = Socket::__new(
+            &UnixAddr::Abstract(name.to_owned()),
+            Path::new(DEFAULT_DIR),
+            None,
+            None,
+            None,
+            Permissions::default(),
+        )?;
+        sk.set_nonblocking()?;
         Ok(sk)
     }
 }
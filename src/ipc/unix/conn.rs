@@ -0,0 +1,389 @@
+//! Connection-oriented unix sockets, where a full receive buffer makes the sender wait instead
+//! of dropping messages. `SOCK_SEQPACKET` sockets keep message boundaries like datagram sockets;
+//! on `SOCK_STREAM` sockets, messages are split back apart using the length in their header.
+
+use super::super::tcp::{send_all, SEND_FLAGS};
+use super::{AllowedPeers, Permissions, UnixAddr};
+use crate::serialize::{self, ParseError};
+use crate::{DatapathGoneError, Error, Result};
+use nix::errno::Errno;
+use nix::poll::{PollFd, PollFlags};
+use nix::sys::socket::{self, AddressFamily, MsgFlags, SockAddr, SockFlag, SockType};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicI32, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{info, warn};
+
+const NO_CONN: RawFd = -1;
+
+// A listening socket serves one datapath connection at a time, and waits for the datapath to
+// connect again when it hangs up. A connected socket is done once the other end hangs up.
+pub(super) struct Conn {
+    ty: SockType,
+    listener: Option<RawFd>,
+    conn: AtomicI32,
+    // for stream sockets, bytes read off the connection which do not yet form a whole message
+    pending: Mutex<Vec<u8>>,
+    accepted: AtomicU64,
+    allowed: Option<AllowedPeers>,
+    rejected: AtomicU64,
+    nonblocking: bool,
+    recv_timeout: Duration,
+    send_timeout: Option<Duration>,
+}
+
+fn sock_addr(addr: &UnixAddr) -> Result<SockAddr> {
+    let addr = match addr {
+        UnixAddr::Path(p) => socket::UnixAddr::new(p.as_path())?,
+        #[cfg(target_os = "linux")]
+        UnixAddr::Abstract(name) => socket::UnixAddr::new_abstract(name.as_bytes())?,
+    };
+    Ok(SockAddr::Unix(addr))
+}
+
+fn timeval(d: Duration) -> TimeVal {
+    TimeVal::microseconds(d.as_micros() as i64)
+}
+
+// Pop the first whole message off `pending` into `msg`, if there is one.
+fn take_msg(pending: &mut Vec<u8>, msg: &mut [u8]) -> Result<Option<usize>> {
+    let len = match serialize::frame_len(pending) {
+        Ok(len) => len,
+        Err(e) => match ParseError::of(&e) {
+            Some(ParseError::Incomplete { .. }) => return Ok(None),
+            _ => {
+                // there is no telling where the next message starts
+                pending.clear();
+                return Err(Error(format!("invalid message on stream: {}", e.0)));
+            }
+        },
+    };
+
+    let res = if len > msg.len() {
+        Err(Error(format!(
+            "message of {} bytes does not fit in {} byte buffer",
+            len,
+            msg.len()
+        )))
+    } else {
+        msg[..len].copy_from_slice(&pending[..len]);
+        Ok(Some(len))
+    };
+
+    pending.drain(..len);
+    res
+}
+
+impl Conn {
+    fn new(ty: SockType, listener: Option<RawFd>, conn: RawFd, nonblocking: bool) -> Self {
+        Conn {
+            ty,
+            listener,
+            conn: AtomicI32::new(conn),
+            pending: Mutex::new(Vec::new()),
+            accepted: AtomicU64::new(0),
+            allowed: None,
+            rejected: AtomicU64::new(0),
+            nonblocking,
+            recv_timeout: Duration::from_secs(1),
+            send_timeout: None,
+        }
+    }
+
+    fn socket(ty: SockType, nonblocking: bool) -> Result<RawFd> {
+        let mut flags = SockFlag::SOCK_CLOEXEC;
+        if nonblocking {
+            flags |= SockFlag::SOCK_NONBLOCK;
+        }
+
+        socket::socket(AddressFamily::Unix, ty, flags, None)
+            .map_err(|e| Error(format!("unix {:?} socket creation failed: {}", ty, e)))
+    }
+
+    // Listen at `addr` for the datapath to connect, once `perms` are set on its socket file.
+    pub(super) fn listen(
+        addr: &UnixAddr,
+        ty: SockType,
+        nonblocking: bool,
+        perms: Permissions,
+    ) -> Result<Self> {
+        let fd = Self::socket(ty, nonblocking)?;
+        // so the fd is closed if the setup below fails
+        let sk = Self::new(ty, Some(fd), NO_CONN, nonblocking);
+        socket::bind(fd, &sock_addr(addr)?)
+            .map_err(|e| Error(format!("could not bind unix socket {:?}: {}", addr, e)))?;
+        if let UnixAddr::Path(p) = addr {
+            super::set_permissions(p, perms)?;
+        }
+
+        socket::listen(fd, 1)
+            .map_err(|e| Error(format!("could not listen on unix socket {:?}: {}", addr, e)))?;
+        Ok(sk)
+    }
+
+    // Connect to a socket listening at `addr`.
+    pub(super) fn connect(addr: &UnixAddr, ty: SockType, nonblocking: bool) -> Result<Self> {
+        let fd = Self::socket(ty, false)?;
+        let sk = Self::new(ty, None, fd, nonblocking);
+        socket::connect(fd, &sock_addr(addr)?).map_err(|e| {
+            Error(format!(
+                "could not connect to unix socket {:?}: {}",
+                addr, e
+            ))
+        })?;
+        if nonblocking {
+            nix::fcntl::fcntl(fd, nix::fcntl::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK))?;
+        } else {
+            socket::setsockopt(
+                fd,
+                socket::sockopt::ReceiveTimeout,
+                &timeval(sk.recv_timeout),
+            )?;
+        }
+
+        Ok(sk)
+    }
+
+    // Blocks until the receiver has room for `msg`, unless the socket is nonblocking.
+    pub(super) fn send(&self, msg: &[u8]) -> Result<()> {
+        let conn = match self.conn.load(Ordering::SeqCst) {
+            NO_CONN => return Err(Error(String::from("no datapath is connected"))),
+            fd => fd,
+        };
+
+        if self.ty == SockType::Stream {
+            // a short write must be finished, or the stream is corrupted
+            return send_all(conn, msg, self.send_timeout);
+        }
+
+        let res = unsafe {
+            libc::send(
+                conn,
+                msg.as_ptr() as *const libc::c_void,
+                msg.len(),
+                SEND_FLAGS,
+            )
+        };
+        if res >= 0 {
+            return Ok(());
+        }
+
+        match Errno::last() {
+            Errno::EPIPE | Errno::ECONNRESET | Errno::ENOTCONN => {
+                Err(Error::from(DatapathGoneError))
+            }
+            e => Err(super::super::send_error(std::io::Error::from(e))),
+        }
+    }
+
+    pub(super) fn recv(&self, msg: &mut [u8]) -> Result<usize> {
+        let conn = match self.conn.load(Ordering::SeqCst) {
+            NO_CONN => match self.accept()? {
+                Some(fd) => fd,
+                None => return Ok(0),
+            },
+            fd => fd,
+        };
+
+        if self.ty == SockType::Stream {
+            return self.recv_stream(conn, msg);
+        }
+
+        match socket::recv(conn, msg, MsgFlags::empty()) {
+            Ok(0) | Err(Errno::ECONNRESET) => self.hang_up(conn).map(|_| 0),
+            Ok(len) => Ok(len),
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) => Ok(0),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    // Read until a whole message has arrived, or the read times out.
+    fn recv_stream(&self, conn: RawFd, msg: &mut [u8]) -> Result<usize> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error(String::from("unix stream receive buffer poisoned")))?;
+        loop {
+            if let Some(len) = take_msg(&mut pending, msg)? {
+                return Ok(len);
+            }
+
+            // use the caller's buffer as scratch space for the read
+            match socket::recv(conn, msg, MsgFlags::empty()) {
+                Ok(0) | Err(Errno::ECONNRESET) => {
+                    if !pending.is_empty() {
+                        warn!(
+                            bytes = pending.len(),
+                            "datapath disconnected in the middle of a message"
+                        );
+                        pending.clear();
+                    }
+
+                    return self.hang_up(conn).map(|_| 0);
+                }
+                Ok(n) => pending.extend_from_slice(&msg[..n]),
+                Err(Errno::EINTR) => continue,
+                Err(Errno::EAGAIN) => return Ok(0),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+    }
+
+    // The datapath hung up.
+    fn hang_up(&self, conn: RawFd) -> Result<()> {
+        if self.listener.is_none() {
+            return Err(Error::from(DatapathGoneError));
+        }
+
+        info!("datapath disconnected, waiting for it to connect again");
+        self.conn.store(NO_CONN, Ordering::SeqCst);
+        nix::unistd::close(conn).unwrap_or(());
+        Ok(())
+    }
+
+    // Wait up to the receive timeout for the datapath to connect.
+    fn accept(&self) -> Result<Option<RawFd>> {
+        let listener = self
+            .listener
+            .ok_or_else(|| Error::from(DatapathGoneError))?;
+        let timeout_ms = if self.nonblocking {
+            0
+        } else {
+            self.recv_timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int
+        };
+        match nix::poll::poll(&mut [PollFd::new(listener, PollFlags::POLLIN)], timeout_ms) {
+            Ok(0) | Err(Errno::EINTR) => return Ok(None),
+            Ok(_) => (),
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        let mut flags = SockFlag::SOCK_CLOEXEC;
+        if self.nonblocking {
+            flags |= SockFlag::SOCK_NONBLOCK;
+        }
+
+        let conn = match socket::accept4(listener, flags) {
+            Ok(fd) => fd,
+            Err(Errno::EAGAIN) | Err(Errno::EINTR) | Err(Errno::ECONNABORTED) => return Ok(None),
+            Err(e) => return Err(Error::from(e)),
+        };
+        if !self.peer_allowed(conn) {
+            self.rejected.fetch_add(1, Ordering::SeqCst);
+            nix::unistd::close(conn).unwrap_or(());
+            return Ok(None);
+        }
+
+        if !self.nonblocking {
+            socket::setsockopt(
+                conn,
+                socket::sockopt::ReceiveTimeout,
+                &timeval(self.recv_timeout),
+            )?;
+            if let Some(t) = self.send_timeout {
+                socket::setsockopt(conn, socket::sockopt::SendTimeout, &timeval(t))?;
+            }
+        }
+
+        info!("datapath connected");
+        self.conn.store(conn, Ordering::SeqCst);
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        Ok(Some(conn))
+    }
+
+    // Whether the peer on `conn` is allowed to connect, going by its `SO_PEERCRED`.
+    #[cfg(target_os = "linux")]
+    fn peer_allowed(&self, conn: RawFd) -> bool {
+        let allowed = match self.allowed {
+            Some(ref a) => a,
+            None => return true,
+        };
+
+        match socket::getsockopt(conn, socket::sockopt::PeerCredentials) {
+            Ok(cr) if allowed.allows(cr.uid(), cr.gid()) => true,
+            Ok(cr) => {
+                warn!(
+                    uid = cr.uid(),
+                    gid = cr.gid(),
+                    "rejecting connection from peer that is not allowed"
+                );
+                false
+            }
+            Err(e) => {
+                warn!(err = %e, "rejecting connection from peer with unknown credentials");
+                false
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn peer_allowed(&self, _conn: RawFd) -> bool {
+        self.allowed.is_none()
+    }
+
+    // Only accept connections from `allowed` peers from now on.
+    #[cfg(target_os = "linux")]
+    pub(super) fn allow_only(&mut self, allowed: AllowedPeers) {
+        self.allowed = Some(allowed);
+    }
+
+    pub(super) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::SeqCst)
+    }
+
+    pub(super) fn close(&self) -> Result<()> {
+        match self.conn.load(Ordering::SeqCst) {
+            NO_CONN => Ok(()),
+            fd => match socket::shutdown(fd, socket::Shutdown::Both) {
+                Err(Errno::ENOTCONN) | Ok(_) => Ok(()),
+                Err(e) => Err(Error::from(e)),
+            },
+        }
+    }
+
+    pub(super) fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        match self.conn.load(Ordering::SeqCst) {
+            NO_CONN => Ok(()),
+            fd => socket::setsockopt(fd, socket::sockopt::ReceiveTimeout, &timeval(timeout))
+                .map_err(Error::from),
+        }
+    }
+
+    // Applies to the current connection, and to later ones as they are accepted.
+    pub(super) fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.send_timeout = Some(timeout);
+        match self.conn.load(Ordering::SeqCst) {
+            NO_CONN => Ok(()),
+            fd => socket::setsockopt(fd, socket::sockopt::SendTimeout, &timeval(timeout))
+                .map_err(Error::from),
+        }
+    }
+
+    // Every connection after the first is the datapath coming back.
+    pub(super) fn reconnects(&self) -> u64 {
+        self.accepted.load(Ordering::SeqCst).saturating_sub(1)
+    }
+
+    // The connection, if there is one, or else the listening socket.
+    pub(super) fn as_raw_fd(&self) -> RawFd {
+        match (self.conn.load(Ordering::SeqCst), self.listener) {
+            (NO_CONN, Some(l)) => l,
+            (fd, _) => fd,
+        }
+    }
+}
+
+impl Drop for Conn {
+    fn drop(&mut self) {
+        let conn = self.conn.load(Ordering::SeqCst);
+        if conn != NO_CONN {
+            nix::unistd::close(conn).unwrap_or(());
+        }
+
+        if let Some(l) = self.listener {
+            nix::unistd::close(l).unwrap_or(());
+        }
+    }
+}
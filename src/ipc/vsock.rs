@@ -0,0 +1,176 @@
+use super::tcp::{send_frame, take_frame};
+use super::{Error, Result};
+use crate::DatapathGoneError;
+use nix::errno::Errno;
+use nix::sys::socket::{self, AddressFamily, SockAddr, SockFlag, SockType, VsockAddr};
+use nix::sys::time::{TimeVal, TimeValLike};
+use std::marker::PhantomData;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+
+/// Connect to or listen on any CID.
+pub const CID_ANY: u32 = libc::VMADDR_CID_ANY;
+/// The host, as seen from inside a VM.
+pub const CID_HOST: u32 = libc::VMADDR_CID_HOST;
+/// The local machine, for testing without a VM.
+pub const CID_LOCAL: u32 = libc::VMADDR_CID_LOCAL;
+
+// Messages are framed as in the tcp backend: a little-endian u32 length followed by the message.
+pub struct Socket<T> {
+    fd: RawFd,
+    // bytes read off the stream which do not yet form a complete frame
+    pending: Mutex<Vec<u8>>,
+    send_timeout: Option<std::time::Duration>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn stream() -> Result<RawFd> {
+        socket::socket(
+            AddressFamily::Vsock,
+            SockType::Stream,
+            SockFlag::SOCK_CLOEXEC,
+            None,
+        )
+        .map_err(|e| Error(format!("vsock socket creation failed: {}", e)))
+    }
+
+    fn __new(fd: RawFd, nonblocking: bool) -> Result<Self> {
+        // so the fd is closed if the setup below fails
+        let sk = Socket {
+            fd,
+            pending: Mutex::new(Vec::new()),
+            send_timeout: None,
+            _phantom: PhantomData,
+        };
+
+        socket::setsockopt(fd, socket::sockopt::ReceiveTimeout, &TimeVal::seconds(1))?;
+        if nonblocking {
+            nix::fcntl::fcntl(fd, nix::fcntl::F_SETFL(nix::fcntl::OFlag::O_NONBLOCK))?;
+        }
+
+        Ok(sk)
+    }
+
+    fn __connect(cid: u32, port: u32) -> Result<RawFd> {
+        let fd = Self::stream()?;
+        socket::connect(fd, &SockAddr::Vsock(VsockAddr::new(cid, port))).map_err(|e| {
+            nix::unistd::close(fd).unwrap_or(());
+            Error(format!("vsock connect to {}:{} failed: {}", cid, port, e))
+        })?;
+        Ok(fd)
+    }
+
+    fn __accept(port: u32) -> Result<RawFd> {
+        let lfd = Self::stream()?;
+        let res = socket::bind(lfd, &SockAddr::Vsock(VsockAddr::new(CID_ANY, port)))
+            .and_then(|_| socket::listen(lfd, 1))
+            .and_then(|_| socket::accept4(lfd, SockFlag::SOCK_CLOEXEC));
+        nix::unistd::close(lfd).unwrap_or(());
+        res.map_err(|e| Error(format!("vsock accept on port {} failed: {}", port, e)))
+    }
+
+    fn __recv(&self, msg: &mut [u8]) -> Result<(usize, ())> {
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|_| Error(String::from("vsock receive buffer poisoned")))?;
+        loop {
+            if let Some(len) = take_frame(&mut pending, msg)? {
+                return Ok((len, ()));
+            }
+
+            // use the caller's buffer as scratch space for the read
+            match nix::unistd::read(self.fd, msg) {
+                // the VM shut down or rebooted
+                Ok(0) | Err(Errno::ECONNRESET) | Err(Errno::ENOTCONN) => {
+                    return Err(Error::from(DatapathGoneError))
+                }
+                Ok(n) => pending.extend_from_slice(&msg[..n]),
+                Err(Errno::EINTR) => continue,
+                Err(Errno::EAGAIN) => return Ok((0, ())),
+                Err(e) => return Err(Error::from(e)),
+            }
+        }
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("vsock")
+    }
+
+    /// Returns `DatapathGoneError` if the connection was reset, e.g. because the guest rebooted.
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        send_frame(self.fd, msg, self.send_timeout)
+    }
+
+    fn set_send_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        let tv = TimeVal::microseconds(timeout.as_micros() as i64);
+        socket::setsockopt(self.fd, socket::sockopt::SendTimeout, &tv)?;
+        self.send_timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        self.__recv(msg)
+    }
+
+    fn close(&mut self) -> Result<()> {
+        match socket::shutdown(self.fd, socket::Shutdown::Both) {
+            Err(Errno::ENOTCONN) | Ok(_) => Ok(()),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    fn set_recv_timeout(&mut self, timeout: std::time::Duration) -> Result<()> {
+        let tv = TimeVal::microseconds(timeout.as_micros() as i64);
+        socket::setsockopt(self.fd, socket::sockopt::ReceiveTimeout, &tv).map_err(Error::from)
+    }
+
+    fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        Some(self.as_raw_fd())
+    }
+}
+
+impl<T> Drop for Socket<T> {
+    fn drop(&mut self) {
+        nix::unistd::close(self.fd).unwrap_or(());
+    }
+}
+
+impl<T> AsRawFd for Socket<T> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Connect to a datapath listening on vsock `port` of the VM with context id `cid`.
+    pub fn new(cid: u32, port: u32) -> Result<Self> {
+        Socket::__new(Self::__connect(cid, port)?, false)
+    }
+
+    /// Wait for a datapath to connect to `port`, from any CID.
+    pub fn accept(port: u32) -> Result<Self> {
+        Socket::__new(Self::__accept(port)?, false)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Connect to a datapath listening on vsock `port` of the VM with context id `cid`.
+    pub fn new(cid: u32, port: u32) -> Result<Self> {
+        Socket::__new(Self::__connect(cid, port)?, true)
+    }
+
+    /// Wait for a datapath to connect to `port`, from any CID.
+    pub fn accept(port: u32) -> Result<Self> {
+        Socket::__new(Self::__accept(port)?, true)
+    }
+}
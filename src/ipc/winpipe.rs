@@ -0,0 +1,476 @@
+//! Named pipes in message mode keep message boundaries like unix datagram sockets, and are
+//! connection-oriented like `SOCK_SEQPACKET` ones: a full pipe makes the sender wait instead of
+//! dropping messages.
+//!
+//! CCP creates the pipe and waits for the datapath to connect, serving one datapath at a time;
+//! when the datapath disconnects, the pipe waits for it to connect again.
+//!
+//! ```no_run
+//! use portus::ipc::{winpipe, BackendBuilder, Blocking};
+//!
+//! let sock = winpipe::Socket::<Blocking>::new("portus").unwrap();
+//! let b = BackendBuilder { sock };
+//! ```
+
+use super::{Error, Result};
+use crate::DatapathGoneError;
+use std::ffi::OsStr;
+use std::marker::PhantomData;
+use std::os::windows::ffi::OsStrExt;
+use std::os::windows::io::{AsRawHandle, RawHandle};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tracing::info;
+use windows_sys::Win32::Foundation::{
+    CloseHandle, GetLastError, BOOL, ERROR_BROKEN_PIPE, ERROR_IO_PENDING, ERROR_MORE_DATA,
+    ERROR_NO_DATA, ERROR_OPERATION_ABORTED, ERROR_PIPE_CONNECTED, ERROR_PIPE_NOT_CONNECTED,
+    GENERIC_READ, GENERIC_WRITE, HANDLE, INVALID_HANDLE_VALUE, WAIT_OBJECT_0, WAIT_TIMEOUT,
+};
+use windows_sys::Win32::Storage::FileSystem::{
+    CreateFileW, ReadFile, WriteFile, FILE_FLAG_FIRST_PIPE_INSTANCE, FILE_FLAG_OVERLAPPED,
+    OPEN_EXISTING, PIPE_ACCESS_DUPLEX,
+};
+use windows_sys::Win32::System::Pipes::{
+    ConnectNamedPipe, CreateNamedPipeW, DisconnectNamedPipe, SetNamedPipeHandleState,
+    PIPE_READMODE_MESSAGE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_MESSAGE, PIPE_WAIT,
+};
+use windows_sys::Win32::System::Threading::{CreateEventW, WaitForSingleObject, INFINITE};
+use windows_sys::Win32::System::IO::{CancelIoEx, GetOverlappedResult, OVERLAPPED};
+
+/// Where `new` creates pipes, and `connect` looks for them.
+pub const DEFAULT_PREFIX: &str = r"\\.\pipe\ccp\";
+
+// The size of the pipe's buffer in each direction; a full buffer makes the sender wait.
+const PIPE_BUF_SIZE: u32 = 1 << 16;
+
+fn wide(name: &str) -> Vec<u16> {
+    OsStr::new(name)
+        .encode_wide()
+        .chain(std::iter::once(0))
+        .collect()
+}
+
+fn last_error() -> std::io::Error {
+    std::io::Error::from_raw_os_error(unsafe { GetLastError() } as i32)
+}
+
+fn is_error(e: &std::io::Error, code: u32) -> bool {
+    e.raw_os_error() == Some(code as i32)
+}
+
+// Errors which mean that the other end of the pipe has gone away.
+fn is_peer_gone(e: &std::io::Error) -> bool {
+    is_error(e, ERROR_BROKEN_PIPE)
+        || is_error(e, ERROR_NO_DATA)
+        || is_error(e, ERROR_PIPE_NOT_CONNECTED)
+}
+
+// Start an overlapped operation on `pipe` with `start`, and wait up to `timeout` (or, if `None`,
+// as long as it takes) for it to finish. Returns the number of bytes transferred, or `None` if
+// the operation timed out and was cancelled.
+fn overlapped<F>(pipe: HANDLE, timeout: Option<Duration>, start: F) -> std::io::Result<Option<u32>>
+where
+    F: FnOnce(*mut OVERLAPPED) -> BOOL,
+{
+    let event = unsafe { CreateEventW(std::ptr::null(), 1, 0, std::ptr::null()) };
+    if event == 0 {
+        return Err(last_error());
+    }
+
+    let mut ov: OVERLAPPED = unsafe { std::mem::zeroed() };
+    ov.hEvent = event;
+    let res = (|| {
+        if start(&mut ov) == 0 {
+            let e = last_error();
+            if !is_error(&e, ERROR_IO_PENDING) {
+                return Err(e);
+            }
+
+            let ms = timeout.map_or(INFINITE, |t| {
+                t.as_millis().min(u128::from(INFINITE - 1)) as u32
+            });
+            match unsafe { WaitForSingleObject(event, ms) } {
+                WAIT_OBJECT_0 => (),
+                WAIT_TIMEOUT => unsafe {
+                    CancelIoEx(pipe, &ov);
+                },
+                _ => return Err(last_error()),
+            }
+        }
+
+        // the operation is done, or cancelled, so `ov` is no longer in use once this returns
+        let mut transferred = 0u32;
+        if unsafe { GetOverlappedResult(pipe, &ov, &mut transferred, 1) } == 0 {
+            let e = last_error();
+            return if is_error(&e, ERROR_OPERATION_ABORTED) {
+                Ok(None)
+            } else {
+                Err(e)
+            };
+        }
+
+        Ok(Some(transferred))
+    })();
+
+    unsafe { CloseHandle(event) };
+    res
+}
+
+pub struct Socket<T> {
+    pipe: HANDLE,
+    // whether this end created the pipe, and so accepts connections on it
+    server: bool,
+    connected: AtomicBool,
+    accepted: AtomicU64,
+    nonblocking: bool,
+    recv_timeout: Duration,
+    send_timeout: Option<Duration>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Socket<T> {
+    fn from_handle(pipe: HANDLE, server: bool, nonblocking: bool) -> Self {
+        Socket {
+            pipe,
+            server,
+            connected: AtomicBool::new(!server),
+            accepted: AtomicU64::new(0),
+            nonblocking,
+            recv_timeout: Duration::from_secs(1),
+            send_timeout: None,
+            _phantom: PhantomData,
+        }
+    }
+
+    fn __new(bind_to: &str, nonblocking: bool) -> Result<Self> {
+        let name = format!("{}{}", DEFAULT_PREFIX, bind_to);
+        let pipe = unsafe {
+            CreateNamedPipeW(
+                wide(&name).as_ptr(),
+                PIPE_ACCESS_DUPLEX | FILE_FLAG_OVERLAPPED | FILE_FLAG_FIRST_PIPE_INSTANCE,
+                PIPE_TYPE_MESSAGE | PIPE_READMODE_MESSAGE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS,
+                1,
+                PIPE_BUF_SIZE,
+                PIPE_BUF_SIZE,
+                0,
+                std::ptr::null(),
+            )
+        };
+        if pipe == INVALID_HANDLE_VALUE {
+            return Err(Error(format!(
+                "could not create named pipe {}: {}",
+                name,
+                last_error()
+            )));
+        }
+
+        Ok(Self::from_handle(pipe, true, nonblocking))
+    }
+
+    fn __connect(to: &str, nonblocking: bool) -> Result<Self> {
+        let name = format!("{}{}", DEFAULT_PREFIX, to);
+        let pipe = unsafe {
+            CreateFileW(
+                wide(&name).as_ptr(),
+                GENERIC_READ | GENERIC_WRITE,
+                0,
+                std::ptr::null(),
+                OPEN_EXISTING,
+                FILE_FLAG_OVERLAPPED,
+                0,
+            )
+        };
+        if pipe == INVALID_HANDLE_VALUE {
+            return Err(Error(format!(
+                "could not connect to named pipe {}: {}",
+                name,
+                last_error()
+            )));
+        }
+
+        // so the handle is closed if this fails
+        let sk = Self::from_handle(pipe, false, nonblocking);
+        let mode = PIPE_READMODE_MESSAGE;
+        if unsafe { SetNamedPipeHandleState(pipe, &mode, std::ptr::null(), std::ptr::null()) } == 0
+        {
+            return Err(Error(format!(
+                "could not set message mode on named pipe {}: {}",
+                name,
+                last_error()
+            )));
+        }
+
+        Ok(sk)
+    }
+
+    fn recv_wait(&self) -> Option<Duration> {
+        Some(if self.nonblocking {
+            Duration::from_secs(0)
+        } else {
+            self.recv_timeout
+        })
+    }
+
+    fn send_wait(&self) -> Option<Duration> {
+        if self.nonblocking {
+            Some(Duration::from_secs(0))
+        } else {
+            self.send_timeout
+        }
+    }
+
+    // Wait up to the receive timeout for the datapath to connect.
+    fn accept(&self) -> Result<bool> {
+        let pipe = self.pipe;
+        match overlapped(pipe, self.recv_wait(), |ov| unsafe {
+            ConnectNamedPipe(pipe, ov)
+        }) {
+            Ok(Some(_)) => (),
+            Ok(None) => return Ok(false),
+            // the datapath connected before we started waiting
+            Err(e) if is_error(&e, ERROR_PIPE_CONNECTED) => (),
+            // and it may have hung up again already
+            Err(e) if is_error(&e, ERROR_NO_DATA) => {
+                unsafe { DisconnectNamedPipe(pipe) };
+                return Ok(false);
+            }
+            Err(e) => return Err(Error::from(e)),
+        }
+
+        info!("datapath connected");
+        self.connected.store(true, Ordering::SeqCst);
+        self.accepted.fetch_add(1, Ordering::SeqCst);
+        Ok(true)
+    }
+
+    // The datapath hung up.
+    fn hang_up(&self) -> Result<()> {
+        if !self.server {
+            return Err(Error::from(DatapathGoneError));
+        }
+
+        info!("datapath disconnected, waiting for it to connect again");
+        self.connected.store(false, Ordering::SeqCst);
+        unsafe { DisconnectNamedPipe(self.pipe) };
+        Ok(())
+    }
+
+    // Read and drop the rest of a message too long for the receive buffer, as a datagram socket
+    // would.
+    fn discard_rest(&self) -> Result<()> {
+        let pipe = self.pipe;
+        let mut scrap = [0u8; 256];
+        loop {
+            // the rest of the message has already arrived, so this does not wait
+            match overlapped(pipe, None, |ov| unsafe {
+                ReadFile(
+                    pipe,
+                    scrap.as_mut_ptr() as *mut _,
+                    scrap.len() as u32,
+                    std::ptr::null_mut(),
+                    ov,
+                )
+            }) {
+                Err(e) if is_error(&e, ERROR_MORE_DATA) => continue,
+                Err(e) => return Err(Error::from(e)),
+                Ok(_) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcSend for Socket<T> {
+    /// The pipe has only one peer at a time.
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("winpipe")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> Result<()> {
+        if !self.connected.load(Ordering::SeqCst) {
+            return Err(Error(String::from("no datapath is connected")));
+        }
+
+        let pipe = self.pipe;
+        match overlapped(pipe, self.send_wait(), |ov| unsafe {
+            WriteFile(
+                pipe,
+                msg.as_ptr(),
+                msg.len() as u32,
+                std::ptr::null_mut(),
+                ov,
+            )
+        }) {
+            Ok(Some(_)) => Ok(()),
+            Ok(None) => Err(super::send_timed_out()),
+            Err(e) if is_peer_gone(&e) => Err(Error::from(DatapathGoneError)),
+            Err(e) => Err(super::send_error(e)),
+        }
+    }
+
+    fn set_send_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.send_timeout = Some(timeout);
+        Ok(())
+    }
+}
+
+impl<T: 'static + Sync + Send> super::IpcRecv for Socket<T> {
+    fn recv(&self, msg: &mut [u8]) -> Result<(usize, Self::Addr)> {
+        if !self.connected.load(Ordering::SeqCst) && !self.accept()? {
+            return Ok((0, ()));
+        }
+
+        let pipe = self.pipe;
+        match overlapped(pipe, self.recv_wait(), |ov| unsafe {
+            ReadFile(
+                pipe,
+                msg.as_mut_ptr() as *mut _,
+                msg.len() as u32,
+                std::ptr::null_mut(),
+                ov,
+            )
+        }) {
+            Ok(Some(len)) => Ok((len as usize, ())),
+            Ok(None) => Ok((0, ())),
+            Err(e) if is_error(&e, ERROR_MORE_DATA) => {
+                self.discard_rest()?;
+                Ok((msg.len(), ()))
+            }
+            Err(e) if is_peer_gone(&e) => self.hang_up().map(|_| (0, ())),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
+    fn close(&mut self) -> Result<()> {
+        if self.server && self.connected.swap(false, Ordering::SeqCst) {
+            unsafe { DisconnectNamedPipe(self.pipe) };
+        }
+
+        Ok(())
+    }
+
+    fn set_recv_timeout(&mut self, timeout: Duration) -> Result<()> {
+        self.recv_timeout = timeout;
+        Ok(())
+    }
+
+    // Every connection after the first is the datapath coming back.
+    fn reconnects(&self) -> u64 {
+        self.accepted.load(Ordering::SeqCst).saturating_sub(1)
+    }
+}
+
+impl<T> AsRawHandle for Socket<T> {
+    fn as_raw_handle(&self) -> RawHandle {
+        self.pipe as RawHandle
+    }
+}
+
+impl<T> Drop for Socket<T> {
+    fn drop(&mut self) {
+        unsafe { CloseHandle(self.pipe) };
+    }
+}
+
+use super::Blocking;
+impl Socket<Blocking> {
+    /// Create the pipe `bind_to` under `DEFAULT_PREFIX`, and wait there for the datapath to
+    /// connect.
+    pub fn new(bind_to: &str) -> Result<Self> {
+        Socket::__new(bind_to, false)
+    }
+
+    /// Connect to the pipe `to` under `DEFAULT_PREFIX`, e.g. to act as the datapath.
+    pub fn connect(to: &str) -> Result<Self> {
+        Socket::__connect(to, false)
+    }
+}
+
+use super::Nonblocking;
+impl Socket<Nonblocking> {
+    /// Create the pipe `bind_to` under `DEFAULT_PREFIX`, and wait there for the datapath to
+    /// connect.
+    pub fn new(bind_to: &str) -> Result<Self> {
+        Socket::__new(bind_to, true)
+    }
+
+    /// Connect to the pipe `to` under `DEFAULT_PREFIX`, e.g. to act as the datapath.
+    pub fn connect(to: &str) -> Result<Self> {
+        Socket::__connect(to, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Socket;
+    use crate::ipc::{Blocking, IpcRecv, IpcSend};
+    use crate::{DatapathGoneError, Error};
+    use std::time::Duration;
+
+    #[test]
+    fn message_boundaries() {
+        let ccp = Socket::<Blocking>::new("portus-test-winpipe").expect("create pipe");
+        let dp = Socket::<Blocking>::connect("portus-test-winpipe").expect("connect");
+        for msg in &[&b"a"[..], b"hello, world", b"winpipe"] {
+            dp.send(msg, &()).expect("send");
+        }
+
+        let mut buf = [0u8; 64];
+        for msg in &[&b"a"[..], b"hello, world", b"winpipe"] {
+            let (len, _) = ccp.recv(&mut buf).expect("recv");
+            assert_eq!(&buf[..len], *msg);
+        }
+
+        ccp.send(b"reply", &()).expect("send");
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"reply");
+    }
+
+    #[test]
+    fn truncated() {
+        let ccp = Socket::<Blocking>::new("portus-test-winpipe-trunc").expect("create pipe");
+        let dp = Socket::<Blocking>::connect("portus-test-winpipe-trunc").expect("connect");
+        dp.send(&[7u8; 1000], &()).expect("send");
+        dp.send(&[8u8; 16], &()).expect("send");
+
+        // the rest of the long message is dropped, and the next one is intact
+        let mut buf = [0u8; 64];
+        let (len, _) = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], &[7u8; 64][..]);
+        let (len, _) = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], &[8u8; 16][..]);
+    }
+
+    #[test]
+    fn reconnect() {
+        let mut ccp = Socket::<Blocking>::new("portus-test-winpipe-reconn").expect("create pipe");
+        ccp.set_recv_timeout(Duration::from_millis(100))
+            .expect("set timeout");
+        let mut buf = [0u8; 64];
+
+        // nobody has connected yet
+        assert_eq!(ccp.recv(&mut buf).expect("recv").0, 0);
+        assert!(ccp.send(b"x", &()).is_err());
+
+        let dp = Socket::<Blocking>::connect("portus-test-winpipe-reconn").expect("connect");
+        dp.send(b"first", &()).expect("send");
+        let (len, _) = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"first");
+        drop(dp);
+        assert_eq!(ccp.recv(&mut buf).expect("recv after hang up").0, 0);
+
+        let dp = Socket::<Blocking>::connect("portus-test-winpipe-reconn").expect("reconnect");
+        dp.send(b"second", &()).expect("send");
+        let (len, _) = ccp.recv(&mut buf).expect("recv");
+        assert_eq!(&buf[..len], b"second");
+        assert_eq!(ccp.reconnects(), 1);
+
+        // the datapath's end fails once CCP is gone
+        drop(ccp);
+        assert_eq!(
+            dp.recv(&mut buf).expect_err("pipe is gone"),
+            Error::from(DatapathGoneError)
+        );
+    }
+}
@@ -7,6 +7,7 @@ pub enum Prim {
     Bool(bool),
     Name(String),
     Num(u64),
+    Int(i64),
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
@@ -14,16 +15,26 @@ pub enum Op {
     Add,     // (add a b) return a+b
     And,     // (and a b) return a && b
     Bind,    // (bind a b) assign variable a to value b
-    Div,     // (div a b) return a/b (integer division)
+    BitAnd,  // (bitand a b) return a & b
+    BitOr,   // (bitor a b) return a | b
+    Div,     // (div a b) return a/b (integer division), or 0 if b is 0
     Equiv,   // (eq a b) return a == b
+    Ge,      // (>= a b) return a >= b
     Gt,      // (> a b) return a > b
+    Le,      // (<= a b) return a <= b
     Lt,      // (< a b) return a < b
     Max,     // (max a b) return max(a,b)
     MaxWrap, // (max a b) return max(a,b) with integer wraparound
     Min,     // (min a b) return min(a,b)
+    Mod,     // (mod a b) return a%b, or 0 if b is 0
     Mul,     // (mul a b) return a * b
+    Ne,      // (ne a b) return a != b
     Or,      // (or a b) return a || b
+    Shl,     // (shl a b) return a << b, with b taken mod 64
+    Shr,     // (shr a b) return a >> b, with b taken mod 64
+    SSub,    // (ssub a b) return a - b as a signed number
     Sub,     // (sub a b) return a - b
+    Xor,     // (xor a b) return a ^ b
 
     // SPECIAL: cannot be called by user, only generated
     Def, // top of prog: (def (Foo 0) (Bar 100000000) ...)
@@ -33,13 +44,90 @@ pub enum Op {
     NotIf, // (!if a b) if a == False, evaluate b (write return register), otherwise don't write return register
 
     // SPECIAL: reads return register
-    Ewma, // (ewma a b) ret * a/10 + b * (10-a)/10.
+    Ewma,          // (ewma a b) ret * a/10 + b * (10-a)/10.
+    EwmaShift(u8), // (ewma s a b) a - (a >> s) + (b >> s), written to ret, which a is moved to
+
+    // SPECIAL: cannot be called by user, only generated for signed operands of `>` and `<`
+    SGt, // (> a b) return a > b, as signed numbers
+    SLt, // (< a b) return a < b, as signed numbers
+}
+
+/// The operator as programs write it, e.g. `+` or `ewma`.
+impl std::fmt::Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(match self {
+            Op::Add => "+",
+            Op::And => "&&",
+            Op::Bind => ":=",
+            Op::BitAnd => "&",
+            Op::BitOr => "|",
+            Op::Div => "/",
+            Op::Equiv => "==",
+            Op::Ge => ">=",
+            Op::Gt => ">",
+            Op::Le => "<=",
+            Op::Lt => "<",
+            Op::Max => "max",
+            Op::MaxWrap => "wrapped_max",
+            Op::Min => "min",
+            Op::Mod => "%",
+            Op::Mul => "*",
+            Op::Ne => "!=",
+            Op::Or => "||",
+            Op::Shl => "<<",
+            Op::Shr => ">>",
+            Op::SSub => "ssub",
+            Op::Sub => "-",
+            Op::Xor => "^",
+            Op::Def => "def",
+            Op::If => "if",
+            Op::NotIf => "!if",
+            Op::Ewma | Op::EwmaShift(_) => "ewma",
+            Op::SGt => "signed >",
+            Op::SLt => "signed <",
+        })
+    }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[cfg(feature = "serde")]
+serde_enum!(Op {
+    Add,
+    And,
+    Bind,
+    BitAnd,
+    BitOr,
+    Div,
+    Equiv,
+    Ge,
+    Gt,
+    Le,
+    Lt,
+    Max,
+    MaxWrap,
+    Min,
+    Mod,
+    Mul,
+    Ne,
+    Or,
+    Shl,
+    Shr,
+    SSub,
+    Sub,
+    Xor,
+    Def,
+    If,
+    NotIf,
+    Ewma,
+    EwmaShift(shift),
+    SGt,
+    SLt,
+});
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum Command {
     Fallthrough, // Continue and evaluate the next `when` clause. desugars to `(:= shouldContinue true)`
     Report,      // Send a report. desugars to `(bind shouldReport true)`
+    ReportIf(Box<Expr>), // Send a report if cond. desugars to `(bind shouldReport (if cond true))`
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -47,6 +135,9 @@ pub enum Expr {
     Atom(Prim),
     Cmd(Command),
     Sexp(Op, Box<Expr>, Box<Expr>),
+    /// `(if cond then else)`: `then` if `cond` is true, otherwise `else`. Either both are values,
+    /// or both bind variables (or are nested `if`s which do), only in the branch taken.
+    IfElse(Box<Expr>, Box<Expr>, Box<Expr>),
     None,
 }
 
@@ -56,19 +147,29 @@ named_complete!(
     alt!(
         alt!(tag!("+") | tag!("add"))   => { |_| Ok(Op::Add) }     |
         alt!(tag!("&&") | tag!("and"))  => { |_| Ok(Op::And) }     |
+        alt!(tag!("&") | tag!("bitand")) => { |_| Ok(Op::BitAnd) } |
         alt!(tag!(":=") | tag!("bind")) => { |_| Ok(Op::Bind) }    |
         tag!("if")                      => { |_| Ok(Op::If) }      |
         alt!(tag!("/") | tag!("div"))   => { |_| Ok(Op::Div) }     |
         alt!(tag!("==") | tag!("eq"))   => { |_| Ok(Op::Equiv) }   |
         tag!("ewma")                    => { |_| Ok(Op::Ewma) }    |
+        alt!(tag!(">=") | tag!("ge"))   => { |_| Ok(Op::Ge) }      |
+        alt!(tag!("<=") | tag!("le"))   => { |_| Ok(Op::Le) }      |
+        alt!(tag!(">>") | tag!("shr"))  => { |_| Ok(Op::Shr) }     |
+        alt!(tag!("<<") | tag!("shl"))  => { |_| Ok(Op::Shl) }     |
         alt!(tag!(">") | tag!("gt"))    => { |_| Ok(Op::Gt) }      |
         alt!(tag!("<") | tag!("lt"))    => { |_| Ok(Op::Lt) }      |
         tag!("wrapped_max")             => { |_| Ok(Op::MaxWrap) } |
         tag!("max")                     => { |_| Ok(Op::Max) }     |
         tag!("min")                     => { |_| Ok(Op::Min) }     |
+        alt!(tag!("%") | tag!("mod"))   => { |_| Ok(Op::Mod) }     |
         alt!(tag!("*") | tag!("mul"))   => { |_| Ok(Op::Mul) }     |
         alt!(tag!("||") | tag!("or"))   => { |_| Ok(Op::Or) }      |
+        alt!(tag!("|") | tag!("bitor")) => { |_| Ok(Op::BitOr) }   |
+        alt!(tag!("^") | tag!("xor"))   => { |_| Ok(Op::Xor) }     |
+        alt!(tag!("!=") | tag!("ne"))   => { |_| Ok(Op::Ne) }      |
         tag!("!if")                     => { |_| Ok(Op::NotIf) }   |
+        tag!("ssub")                    => { |_| Ok(Op::SSub) }    |
         alt!(tag!("-") | tag!("sub"))   => { |_| Ok(Op::Sub) }     |
         atom => { |f: Result<Expr>| Err(Error::from(format!("unexpected token {:?}", f))) }
     )
@@ -91,13 +192,11 @@ fn check_expr(op: Op, left: Expr, right: Expr) -> Result<Expr> {
 
 named_complete!(
     sexp<Result<Expr>>,
-    ws!(delimited!(
+    ws_comments!(delimited!(
         tag!("("),
         do_parse!(
             first: op
-                >> opt!(multispace)
                 >> second: expr
-                >> opt!(multispace)
                 >> third: expr
                 >> (first.and_then(|opr| second
                     .and_then(|left| third.and_then(|right| check_expr(opr, left, right)))))
@@ -106,6 +205,78 @@ named_complete!(
     ))
 );
 
+// (if cond then else), or with more branches, (if cond1 then1 cond2 then2 ... else), which is
+// (if cond1 then1 (if cond2 then2 ... else)). (if cond then), with no else, is `Op::If`.
+named_complete!(
+    if_sexp<Result<Expr>>,
+    ws_comments!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("if")
+                >> cond: expr
+                >> then: expr
+                >> otherwise: expr
+                >> more: many0!(expr)
+                >> (if_else(
+                    vec![cond, then, otherwise]
+                        .into_iter()
+                        .chain(more)
+                        .collect()
+                ))
+        ),
+        tag!(")")
+    ))
+);
+
+fn if_else(args: Vec<Result<Expr>>) -> Result<Expr> {
+    let mut args = args.into_iter().collect::<Result<Vec<_>>>()?;
+    if args.len() % 2 == 0 {
+        return Err(Error::from(format!(
+            "if takes a condition and a value for each branch, then a value for none: got {} \
+             arguments",
+            args.len()
+        )));
+    }
+    if let Some(cond) = args
+        .iter()
+        .find(|a| matches!(a, Expr::Sexp(Op::If, _, _) | Expr::Sexp(Op::NotIf, _, _)))
+    {
+        return Err(Error::from(format!(
+            "Conditional cannot be bound to temp register: {:?}",
+            cond
+        )));
+    }
+
+    let mut e = args.pop().unwrap();
+    while let (Some(then), Some(cond)) = (args.pop(), args.pop()) {
+        e = Expr::IfElse(Box::new(cond), Box::new(then), Box::new(e));
+    }
+
+    Ok(e)
+}
+
+// (ewma s old new), which has an operand more than other expressions
+named_complete!(
+    ewma_sexp<Result<Expr>>,
+    ws_comments!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("ewma")
+                >> shift: num
+                >> old: expr
+                >> new: expr
+                >> (old.and_then(|old| new.and_then(|new| match shift {
+                    1..=63 => check_expr(Op::EwmaShift(shift as u8), old, new),
+                    _ => Err(Error::from(format!(
+                        "ewma shift must be from 1 to 63: {}",
+                        shift
+                    ))),
+                })))
+        ),
+        tag!(")")
+    ))
+);
+
 use std::str::FromStr;
 named_complete!(
     pub num<u64>,
@@ -118,6 +289,18 @@ named_complete!(
     )
 );
 
+// A signed number is written with its sign, e.g. `-5` or `+0`.
+named_complete!(
+    pub int<i64>,
+    map_res!(
+        recognize!(pair!(alt!(tag!("-") | tag!("+")), digit)),
+        |d: CompleteByteSlice| {
+            let st = str::from_utf8(d.0)?;
+            FromStr::from_str(st).map_err(Error::from)
+        }
+    )
+);
+
 named_complete!(
     pub name<String>,
     map_res!(
@@ -136,11 +319,12 @@ named_complete!(
 
 named_complete!(
     pub atom<Result<Expr>>,
-    ws!(do_parse!(
+    ws_comments!(do_parse!(
         val: alt!(
             tag!("true")  => { |_| Ok(Prim::Bool(true)) }  |
             tag!("false") => { |_| Ok(Prim::Bool(false)) } |
             tag!("+infinity") => { |_| Ok(Prim::Num(u64::max_value())) } |
+            int => { |n: i64| Ok(Prim::Int(n)) } |
             num => { |n: u64| Ok(Prim::Num(n)) } |
             name => { |n: String| Ok(Prim::Name(n)) }
         ) >>
@@ -150,7 +334,7 @@ named_complete!(
 
 named_complete!(
     command<Result<Expr>>,
-    ws!(delimited!(
+    ws_comments!(delimited!(
         tag!("("),
         map!(
             alt!(
@@ -163,18 +347,42 @@ named_complete!(
     ))
 );
 
+// (report-if cond), the only command with an operand
 named_complete!(
-    pub comment<Result<Expr>>,
-    ws!(do_parse!(
-        tag!("#") >>
-        take_until!("\n") >>
-        (Ok(Expr::None))
+    report_if<Result<Expr>>,
+    ws_comments!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("report-if")
+                >> cond: expr
+                >> (cond.and_then(|c| match c {
+                    Expr::Sexp(Op::If, _, _) | Expr::Sexp(Op::NotIf, _, _) => Err(Error::from(
+                        format!("Conditional cannot be bound to temp register: {:?}", c)
+                    )),
+                    c => Ok(Expr::Cmd(Command::ReportIf(Box::new(c)))),
+                }))
+        ),
+        tag!(")")
     ))
 );
 
+// Whitespace, and comments, which are whitespace too: `#` or `;` to the end of the line, or
+// `#|` to `|#`.
+named_complete!(
+    pub whitespace<CompleteByteSlice>,
+    recognize!(many0!(alt!(
+        multispace |
+        recognize!(delimited!(tag!("#|"), take_until!("|#"), tag!("|#"))) |
+        recognize!(preceded!(
+            alt!(tag!(";") | terminated!(tag!("#"), not!(tag!("|")))),
+            take_while!(|c: u8| c != b'\n')
+        ))
+    )))
+);
+
 named_complete!(
     pub expr<Result<Expr>>,
-    alt_complete!(comment | sexp | command | atom)
+    alt_complete!(if_sexp | ewma_sexp | report_if | sexp | command | atom)
 );
 
 named_complete!(
@@ -190,13 +398,7 @@ impl Expr {
                 "compile error: \"{}\"",
                 str::from_utf8(rest.0)?
             ))),
-            Ok((_, me)) => me
-                .into_iter()
-                .filter(|e| match e {
-                    Ok(Expr::None) => false,
-                    _ => true,
-                })
-                .collect(),
+            Ok((_, me)) => me.into_iter().collect(),
             Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
             Err(nom::Err::Incomplete(Needed::Unknown)) => Err(Error::from("need more src")),
             Err(nom::Err::Incomplete(Needed::Size(s))) => {
@@ -221,12 +423,30 @@ impl Expr {
                     Box::new(Expr::Atom(Prim::Bool(true))),
                 )
             }
+            Expr::Cmd(Command::ReportIf(ref mut cond)) => {
+                let mut cond = std::mem::replace(cond, Box::new(Expr::None));
+                cond.desugar();
+                *self = Expr::Sexp(
+                    Op::Bind,
+                    Box::new(Expr::Atom(Prim::Name(String::from("__shouldReport")))),
+                    Box::new(Expr::Sexp(
+                        Op::If,
+                        cond,
+                        Box::new(Expr::Atom(Prim::Bool(true))),
+                    )),
+                )
+            }
             Expr::None => {}
             Expr::Atom(_) => {}
             Expr::Sexp(_, ref mut left, ref mut right) => {
                 left.desugar();
                 right.desugar();
             }
+            Expr::IfElse(ref mut cond, ref mut then, ref mut otherwise) => {
+                cond.desugar();
+                then.desugar();
+                otherwise.desugar();
+            }
         }
     }
 }
@@ -317,6 +537,84 @@ mod tests {
         );
     }
 
+    #[test]
+    fn signed_atoms() {
+        for (src, n) in &[
+            ("-5", -5),
+            ("+0", 0),
+            ("+42", 42),
+            ("-9223372036854775808", i64::MIN),
+        ] {
+            let e = Expr::new(src.as_bytes()).unwrap();
+            assert_eq!(e, vec![Expr::Atom(Prim::Int(*n))], "{}", src);
+        }
+
+        let e = Expr::new(b"(- 5 -3)").unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Sexp(
+                Op::Sub,
+                Box::new(Expr::Atom(Prim::Num(5))),
+                Box::new(Expr::Atom(Prim::Int(-3)))
+            )]
+        );
+        assert!(Expr::new(b"-9223372036854775809").is_err());
+    }
+
+    #[test]
+    fn ewma_shift() {
+        let e = Expr::new(b"(ewma 3 Report.srtt Flow.rtt_sample_us)").unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Sexp(
+                Op::EwmaShift(3),
+                Box::new(Expr::Atom(Prim::Name(String::from("Report.srtt")))),
+                Box::new(Expr::Atom(Prim::Name(String::from("Flow.rtt_sample_us"))))
+            )]
+        );
+
+        // without old, it is the ewma by tenths
+        let e = Expr::new(b"(ewma 2 Flow.rate_outgoing)").unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Sexp(
+                Op::Ewma,
+                Box::new(Expr::Atom(Prim::Num(2))),
+                Box::new(Expr::Atom(Prim::Name(String::from("Flow.rate_outgoing"))))
+            )]
+        );
+
+        for src in &["(ewma 0 a b)", "(ewma 64 a b)", "(ewma x a b)"] {
+            assert!(Expr::new(src.as_bytes()).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn if_else() {
+        let name = |n: &str| Box::new(Expr::Atom(Prim::Name(String::from(n))));
+        let e = Expr::new(b"(if a b c)").unwrap();
+        assert_eq!(e, vec![Expr::IfElse(name("a"), name("b"), name("c"))]);
+
+        // more branches nest
+        let e = Expr::new(b"(if a b c d e)").unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::IfElse(
+                name("a"),
+                name("b"),
+                Box::new(Expr::IfElse(name("c"), name("d"), name("e")))
+            )]
+        );
+
+        // without else, it is the conditional
+        let e = Expr::new(b"(if a b)").unwrap();
+        assert_eq!(e, vec![Expr::Sexp(Op::If, name("a"), name("b"))]);
+
+        for src in &["(if a b c d)", "(if a (if b c) d)"] {
+            assert!(Expr::new(src.as_bytes()).is_err(), "{}", src);
+        }
+    }
+
     #[test]
     fn simple_exprs() {
         let foo = b"(+ 10 20)";
@@ -518,6 +816,38 @@ mod tests {
         );
     }
 
+    #[test]
+    fn report_if() {
+        let cond = Expr::Sexp(
+            Op::Gt,
+            Box::new(Expr::Atom(Prim::Name(String::from("Ack.lost_pkts_sample")))),
+            Box::new(Expr::Atom(Prim::Num(0))),
+        );
+        let mut e = Expr::new(b"(report-if (> Ack.lost_pkts_sample 0))").unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Cmd(Command::ReportIf(Box::new(cond.clone())))]
+        );
+
+        e[0].desugar();
+        assert_eq!(
+            e[0],
+            Expr::Sexp(
+                Op::Bind,
+                Box::new(Expr::Atom(Prim::Name(String::from("__shouldReport")))),
+                Box::new(Expr::Sexp(
+                    Op::If,
+                    Box::new(cond),
+                    Box::new(Expr::Atom(Prim::Bool(true))),
+                )),
+            )
+        );
+
+        for src in &["(report-if)", "(report-if a b)", "(report-if (if a b))"] {
+            assert!(Expr::new(src.as_bytes()).is_err(), "{}", src);
+        }
+    }
+
     #[test]
     fn partial() {
         let foo = b"
@@ -540,6 +870,52 @@ mod tests {
         assert_eq!(e, vec![Expr::Cmd(Command::Report),]);
     }
 
+    #[test]
+    fn comments_anywhere() {
+        let foo = b"; first
+            (:= #| a block
+            comment |# Report.foo ; mid-expression
+                (+ # nested
+                    Report.foo ;;
+                    #|x|# 1
+                ) #||#)
+            ; no newline at the end";
+
+        let e = Expr::new(foo).unwrap();
+        assert_eq!(
+            e,
+            vec![Expr::Sexp(
+                Op::Bind,
+                Box::new(Expr::Atom(Prim::Name(String::from("Report.foo")))),
+                Box::new(Expr::Sexp(
+                    Op::Add,
+                    Box::new(Expr::Atom(Prim::Name(String::from("Report.foo")))),
+                    Box::new(Expr::Atom(Prim::Num(1))),
+                )),
+            )]
+        );
+
+        assert_eq!(
+            Expr::new(b"(report);").unwrap(),
+            vec![Expr::Cmd(Command::Report)]
+        );
+        assert_eq!(
+            Expr::new(b"(report)#").unwrap(),
+            vec![Expr::Cmd(Command::Report)]
+        );
+
+        // errors still point at the code after the comments
+        assert_eq!(
+            Expr::new(b"(report) ; fine\n #| also fine |# (reprot) ; oops")
+                .unwrap_err()
+                .0,
+            "compile error: \"(reprot) ; oops\""
+        );
+        // an unterminated block comment is not a comment to the end of the line
+        Expr::new(b"(report) #| oops").unwrap_err();
+        Expr::new(b"(+ 1 #| 2)").unwrap_err();
+    }
+
     #[test]
     fn old_syntax() {
         let foo = b"(reset)";
@@ -1,21 +1,42 @@
 use super::ast::{Expr, Op, Prim};
 use super::prog::Prog;
 use super::{Error, Result};
+use std::convert::TryFrom;
+use std::fmt;
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Type {
     Bool(Option<bool>),
     Name(String),
     Num(Option<u64>),
+    /// A signed number, e.g. a change in RTT, declared with a signed initial value such as
+    /// `(rtt_delta +0)`. It cannot be mixed with a `Num`, but an unsigned literal small enough to
+    /// be signed can be either.
+    Int(Option<i64>),
+    /// A short string, which only the datapath sets, e.g. in a report variable declared as
+    /// `(ca_state "")` (see `serialize::measure::FieldType::Str`). Programs cannot compute with
+    /// it.
+    Str,
     None,
 }
 
+#[cfg(feature = "serde")]
+serde_enum!(Type {
+    Bool(b),
+    Name(name),
+    Num(n),
+    Int(n),
+    Str,
+    None,
+});
+
 pub(crate) fn check_atom_type(e: &Expr) -> Result<Type> {
     match *e {
         Expr::Atom(ref t) => match *t {
             Prim::Bool(t) => Ok(Type::Bool(Some(t))),
             Prim::Name(ref name) => Ok(Type::Name(name.clone())),
             Prim::Num(n) => Ok(Type::Num(Some(n))),
+            Prim::Int(n) => Ok(Type::Int(Some(n))),
         },
         _ => Err(Error::from(format!("not an atom: {:?}", e))),
     }
@@ -26,6 +47,7 @@ pub(crate) fn check_atom_type(e: &Expr) -> Result<Type> {
 pub enum Reg {
     Control(u8, Type, bool),
     ImmNum(u64),
+    ImmInt(i64),
     ImmBool(bool),
     Implicit(u8, Type),
     Local(u8, Type),
@@ -35,10 +57,25 @@ pub enum Reg {
     None,
 }
 
+#[cfg(feature = "serde")]
+serde_enum!(Reg {
+    Control(idx, t, volatile),
+    ImmNum(n),
+    ImmInt(n),
+    ImmBool(b),
+    Implicit(idx, t),
+    Local(idx, t),
+    Primitive(idx, t),
+    Report(idx, t, volatile),
+    Tmp(idx, t),
+    None,
+});
+
 impl Reg {
     fn get_type(&self) -> Result<Type> {
         match *self {
             Reg::ImmNum(n) => Ok(Type::Num(Some(n))),
+            Reg::ImmInt(n) => Ok(Type::Int(Some(n))),
             Reg::ImmBool(b) => Ok(Type::Bool(Some(b))),
             Reg::Control(_, ref t, _)
             | Reg::Implicit(_, ref t)
@@ -51,6 +88,24 @@ impl Reg {
     }
 }
 
+/// A register's kind and index, e.g. `ctl0` or `tmp2`, or an immediate's value.
+impl fmt::Display for Reg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Reg::Control(i, _, _) => write!(f, "ctl{}", i),
+            Reg::ImmNum(n) => write!(f, "{}", n),
+            Reg::ImmInt(n) => write!(f, "{:+}", n),
+            Reg::ImmBool(b) => write!(f, "{}", b),
+            Reg::Implicit(i, _) => write!(f, "imp{}", i),
+            Reg::Local(i, _) => write!(f, "loc{}", i),
+            Reg::Primitive(i, _) => write!(f, "prim{}", i),
+            Reg::Report(i, _, _) => write!(f, "rep{}", i),
+            Reg::Tmp(i, _) => write!(f, "tmp{}", i),
+            Reg::None => write!(f, "_"),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// A single event to handle in the datapath, if the flag instruction evaluates truthily.
 pub struct Event {
@@ -60,6 +115,14 @@ pub struct Event {
     pub num_body_instrs: u32,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(Event {
+    flag_idx,
+    num_flag_instrs,
+    body_idx,
+    num_body_instrs,
+});
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// A single instruction to execute in the datapath.
 pub struct Instr {
@@ -69,6 +132,26 @@ pub struct Instr {
     pub right: Reg,
 }
 
+/// The register the instruction writes, and the expression it writes there in the program
+/// language's own syntax, e.g. `tmp0 <- (+ rep0 prim1)`.
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} <- ({} {} {})",
+            self.res, self.op, self.left, self.right
+        )
+    }
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Instr {
+    res,
+    op,
+    left,
+    right,
+});
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 /// Instruction-level representation of a datapath program.
 pub struct Bin {
@@ -76,6 +159,36 @@ pub struct Bin {
     pub instrs: Vec<Instr>,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(Bin { events, instrs });
+
+/// One line for each event, saying which instructions are its condition and which its body,
+/// then one for each instruction.
+impl fmt::Display for Bin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut sep = "";
+        for (i, ev) in self.events.iter().enumerate() {
+            write!(
+                f,
+                "{}event {}: when [{}..{}) do [{}..{})",
+                sep,
+                i,
+                ev.flag_idx,
+                ev.flag_idx + ev.num_flag_instrs,
+                ev.body_idx,
+                ev.body_idx + ev.num_body_instrs
+            )?;
+            sep = "\n";
+        }
+        for (i, instr) in self.instrs.iter().enumerate() {
+            write!(f, "{}{}: {}", sep, i, instr)?;
+            sep = "\n";
+        }
+
+        Ok(())
+    }
+}
+
 impl IntoIterator for Bin {
     type Item = Instr;
     type IntoIter = ::std::vec::IntoIter<Instr>;
@@ -85,84 +198,122 @@ impl IntoIterator for Bin {
     }
 }
 
+/// An error compiling a `Prog`, and where it is, to point at it in the source.
+#[derive(Debug)]
+pub(crate) struct CompileError {
+    /// The event the error is in, and which of its expressions, counting its flag as the first.
+    pub(crate) at: Option<(usize, usize)>,
+    /// The name which the error is about, e.g. an unknown variable, and which of the uses of it
+    /// in the expression, in the order of the source.
+    pub(crate) name: Option<(String, usize)>,
+    pub(crate) err: Error,
+}
+
+type CompileResult<T> = std::result::Result<T, CompileError>;
+
+impl From<Error> for CompileError {
+    fn from(err: Error) -> Self {
+        CompileError {
+            at: None,
+            name: None,
+            err,
+        }
+    }
+}
+
 impl Bin {
     /// Take a `Prog`, which is a `Vec<portus::lang::prog::Event>`, and turn it into
     /// a `Bin`, which is a `Vec<portus::lang::datapath::Event>` and a `Vec<Instr>`.
-    pub fn compile_prog(p: &Prog, mut scope: &mut Scope) -> Result<Self> {
+    pub fn compile_prog(p: &Prog, scope: &mut Scope) -> Result<Self> {
+        Bin::compile_located(p, scope).map_err(|e| e.err)
+    }
+
+    /// Like `compile_prog`, with which expression of `p` an error is in.
+    pub(crate) fn compile_located(p: &Prog, scope: &mut Scope) -> CompileResult<Self> {
         let def_instrs = scope.clone().into_iter().collect::<Vec<Instr>>();
         let mut curr_idx = def_instrs.len() as u32;
 
         // this is ugly
         // there might be some way to do this without all the intermediate `.collect()`
         // to turn Vec<Result<_>> into Result<Vec<_>>.
-        let ls: Result<Vec<(Event, Vec<Instr>)>> =
-            p.0.iter()
-                .map(|ev| {
-                    scope.clear_tmps();
-                    let flag_instrs = compile_expr(&ev.flag, &mut scope).and_then(|t| {
-                        let (mut instrs, res) = t;
-                        // assign the flag value to the EventFlag reg.
-                        let flag_reg = scope.get("__eventFlag").unwrap();
-                        match res {
-                            Reg::Tmp(_, Type::Bool(_)) => {
-                                if let Some(last) = instrs.last_mut() {
-                                    (*last).res = flag_reg.clone();
-                                } else {
-                                    return Err(Error(String::from("Empty instruction list")));
-                                }
-
-                                Ok(instrs)
-                            }
-                            Reg::ImmBool(_) => {
-                                instrs.push(Instr {
-                                    res: flag_reg.clone(),
-                                    op: Op::Bind,
-                                    left: flag_reg.clone(),
-                                    right: res,
-                                });
-
-                                Ok(instrs)
-                            }
-                            Reg::Report(_, _, _) => unreachable!(),
-                            x => Err(Error::from(format!(
-                                "Flag expression must result in bool: {:?}",
-                                x
-                            ))),
-                        }
-                    })?;
-                    let num_flag_instrs = flag_instrs.len() as u32;
-
-                    let body_instrs_nested: Result<Vec<Vec<Instr>>> = ev
-                        .body
-                        .iter()
-                        .map(|expr| {
-                            scope.clear_tmps();
-                            compile_expr(expr, &mut scope).map(|t| t.0) // Result<Vec<Instr>>
-                        })
-                        .collect(); // do this intermediate collect to go from Vec<Result<Vec<Instr>>> -> Result<Vec<Vec<Instr>>>
-
-                    // flatten the Vec<Vec<Instr>>
-                    let body_instrs: Vec<Instr> = body_instrs_nested?
-                        .into_iter()
-                        .flat_map(std::iter::IntoIterator::into_iter)
-                        .collect();
+        let ls: CompileResult<Vec<(Event, Vec<Instr>)>> = p
+            .0
+            .iter()
+            .enumerate()
+            .map(|(i, ev)| {
+                let located = |j: usize| {
+                    move |err| CompileError {
+                        at: Some((i, j)),
+                        name: None,
+                        err,
+                    }
+                };
+
+                scope.clear_tmps();
+                check_names(&ev.flag, &ev.flag, scope, &mut vec![]).map_err(|e| CompileError {
+                    at: Some((i, 0)),
+                    ..e
+                })?;
+                let flag_instrs = compile_flag(&ev.flag, scope).map_err(located(0))?;
+                let num_flag_instrs = flag_instrs.len() as u32;
+
+                let body_instrs_nested: CompileResult<Vec<Vec<Instr>>> = ev
+                    .body
+                    .iter()
+                    .enumerate()
+                    .map(|(j, expr)| {
+                        scope.clear_tmps();
+                        check_names(expr, expr, scope, &mut vec![]).map_err(|e| CompileError {
+                            at: Some((i, j + 1)),
+                            ..e
+                        })?;
+                        compile_expr(expr, scope)
+                            .map(|t| t.0) // Result<Vec<Instr>>
+                            .map_err(located(j + 1))
+                    })
+                    .collect(); // do this intermediate collect to go from Vec<Result<Vec<Instr>>> -> Result<Vec<Vec<Instr>>>
+
+                // flatten the Vec<Vec<Instr>>
+                let body_instrs: Vec<Instr> = body_instrs_nested?
+                    .into_iter()
+                    .flat_map(std::iter::IntoIterator::into_iter)
+                    .collect();
+
+                let new_event = Event {
+                    flag_idx: curr_idx,
+                    num_flag_instrs,
+                    body_idx: curr_idx + num_flag_instrs,
+                    num_body_instrs: body_instrs.len() as u32,
+                };
+
+                curr_idx += new_event.num_flag_instrs + new_event.num_body_instrs;
+                Ok((
+                    new_event,
+                    flag_instrs.into_iter().chain(body_instrs).collect(),
+                ))
+            })
+            .collect();
 
-                    let new_event = Event {
-                        flag_idx: curr_idx,
-                        num_flag_instrs,
-                        body_idx: curr_idx + num_flag_instrs,
-                        num_body_instrs: body_instrs.len() as u32,
-                    };
+        let (evs, instrs): (Vec<_>, Vec<_>) = ls?.into_iter().unzip();
 
-                    curr_idx += new_event.num_flag_instrs + new_event.num_body_instrs;
-                    Ok((
-                        new_event,
-                        flag_instrs.into_iter().chain(body_instrs).collect(),
-                    ))
-                })
-                .collect();
+        // the datapath sends a report, and resets volatile report variables, after an evaluation
+        // which set __shouldReport. An instruction which clears it would drop a report an earlier
+        // one asked for, keeping its volatile variables, so programs only ever set it.
+        let report_reg = scope.get("__shouldReport").unwrap().clone();
+        let reports: Vec<&Instr> = instrs
+            .iter()
+            .flatten()
+            .filter(|i| i.res == report_reg)
+            .collect();
+        if let Some(i) = reports.iter().find(|i| i.right != Reg::ImmBool(true)) {
+            return Err(CompileError::from(Error::from(format!(
+                "only (report) and (report-if cond) can bind __shouldReport: {}",
+                i
+            ))));
+        }
+        scope.event_triggered =
+            !reports.is_empty() && reports.iter().all(|i| matches!(i.op, Op::If | Op::NotIf));
 
-        let (evs, instrs): (Vec<_>, Vec<_>) = ls?.into_iter().unzip();
         Ok(Bin {
             events: evs,
             instrs: def_instrs
@@ -177,6 +328,49 @@ impl Bin {
     }
 }
 
+// Compile an event's flag, which writes whether the event's body runs.
+fn compile_flag(flag: &Expr, scope: &mut Scope) -> Result<Vec<Instr>> {
+    compile_expr(flag, scope).and_then(|t| {
+        let (mut instrs, res) = t;
+        // assign the flag value to the EventFlag reg.
+        let flag_reg = scope.get("__eventFlag").unwrap();
+        match res {
+            // an if's value is written by both its predicated instructions
+            Reg::Tmp(_, Type::Bool(_)) if matches!(instrs.last(), Some(i) if i.op == Op::NotIf) => {
+                for instr in instrs.iter_mut().filter(|i| i.res == res) {
+                    instr.res = flag_reg.clone();
+                }
+
+                Ok(instrs)
+            }
+            Reg::Tmp(_, Type::Bool(_)) => {
+                if let Some(last) = instrs.last_mut() {
+                    last.res = flag_reg.clone();
+                } else {
+                    return Err(Error(String::from("Empty instruction list")));
+                }
+
+                Ok(instrs)
+            }
+            Reg::ImmBool(_) => {
+                instrs.push(Instr {
+                    res: flag_reg.clone(),
+                    op: Op::Bind,
+                    left: flag_reg.clone(),
+                    right: res,
+                });
+
+                Ok(instrs)
+            }
+            Reg::Report(_, _, _) => unreachable!(),
+            x => Err(Error::from(format!(
+                "Flag expression must result in bool: {:?}",
+                x
+            ))),
+        }
+    })
+}
+
 // TODO make iterative instead of recursive, and return impl Iterator<Instr>
 /// Given a single Expr, return
 /// a Vec<Instr> that evaluates that Expr
@@ -200,21 +394,114 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                 }
             }
             Prim::Num(n) => Ok((vec![], Reg::ImmNum(n as u64))),
+            Prim::Int(n) => Ok((vec![], Reg::ImmInt(n))),
         },
         Expr::Cmd(_) | Expr::None => unreachable!(),
+        Expr::IfElse(ref cond, ref then, ref otherwise) if binds(then) || binds(otherwise) => {
+            compile_if_binds(cond, then, otherwise, None, scope).map(|instrs| (instrs, Reg::None))
+        }
+        Expr::IfElse(ref cond_expr, ref then, ref otherwise) => {
+            // (if c a b): a value, so compute both and write the one c picks to a tmp
+            let (mut instrs, cond) = compile_cond(cond_expr, scope)?;
+            let (mut then_instrs, a) = compile_expr(then, scope)?;
+            let (mut else_instrs, b) = compile_expr(otherwise, scope)?;
+            instrs.append(&mut then_instrs);
+            instrs.append(&mut else_instrs);
+            let typ = match (a.get_type(), b.get_type()) {
+                (Ok(Type::Bool(_)), Ok(Type::Bool(_))) => Type::Bool(None),
+                (Ok(Type::Num(_)), Ok(Type::Num(_)))
+                | (Ok(Type::Num(_)), Ok(Type::Int(_)))
+                | (Ok(Type::Int(_)), Ok(Type::Num(_)))
+                | (Ok(Type::Int(_)), Ok(Type::Int(_))) => {
+                    if is_signed(Op::If, &a, &b)? {
+                        Type::Int(None)
+                    } else {
+                        Type::Num(None)
+                    }
+                }
+                (x, y) => {
+                    return Err(Error::from(format!(
+                        "if's branches must have the same type, got {:?} and {:?}",
+                        x, y
+                    )))
+                }
+            };
+
+            let res = scope.new_tmp(typ);
+            instrs.push(Instr {
+                res: res.clone(),
+                op: Op::If,
+                left: cond.clone(),
+                right: a,
+            });
+            instrs.push(Instr {
+                res: res.clone(),
+                op: Op::NotIf,
+                left: cond,
+                right: b,
+            });
+
+            Ok((instrs, res))
+        }
         Expr::Sexp(ref o, ref left_expr, ref right_expr) => {
+            for e in &[left_expr, right_expr] {
+                if matches!(***e, Expr::IfElse(_, _, _)) && binds(e) {
+                    return Err(Error::from(format!(
+                        "{:?} cannot take an if which binds variables, since it has no value: \
+                         {:?}",
+                        o, e
+                    )));
+                }
+            }
+
             let (mut instrs, mut left) = compile_expr(left_expr, &mut scope)?;
             let (mut right_instrs, right) = compile_expr(right_expr, &mut scope)?;
             instrs.append(&mut right_instrs);
+            // only the datapath sets strings, so programs can neither compute with nor assign them
+            if matches!(left.get_type(), Ok(Type::Str)) || matches!(right.get_type(), Ok(Type::Str))
+            {
+                return Err(Error::from(format!(
+                    "{:?} cannot take a string, which only the datapath sets",
+                    o
+                )));
+            }
+
             match *o {
-                Op::Add | Op::Div | Op::Max | Op::MaxWrap | Op::Min | Op::Mul | Op::Sub => {
+                Op::Add
+                | Op::BitAnd
+                | Op::BitOr
+                | Op::Div
+                | Op::Max
+                | Op::MaxWrap
+                | Op::Min
+                | Op::Mod
+                | Op::Mul
+                | Op::Shl
+                | Op::Shr
+                | Op::SSub
+                | Op::Sub
+                | Op::Xor => {
+                    // a variable declared without a number has no value until it is bound, and
+                    // the min or max of whatever the datapath left in its register is no use
+                    if matches!(o, Op::Max | Op::Min) {
+                        for reg in &[&left, &right] {
+                            if matches!(reg.get_type(), Ok(Type::None)) {
+                                return Err(Error::from(format!(
+                                    "{:?} of {:?}, which has no initial value: declare it with \
+                                     the identity, +infinity for min or 0 for max",
+                                    o, reg
+                                )));
+                            }
+                        }
+                    }
+
                     // left and right should have type num
                     match left.get_type() {
-                        Ok(Type::Num(_)) => (),
+                        Ok(Type::Num(_)) | Ok(Type::Int(_)) => (),
                         x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
                     }
                     match right.get_type() {
-                        Ok(Type::Num(_)) => (),
+                        Ok(Type::Num(_)) | Ok(Type::Int(_)) => (),
                         x => {
                             return Err(Error::from(format!(
                                 "{:?} expected Num, got {:?}: {:?}",
@@ -222,11 +509,43 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                             )));
                         }
                     }
+                    // the datapath takes shifts mod 64, so a constant past 63 is surely a mistake
+                    match (o, &right) {
+                        (Op::Shl, Reg::ImmNum(n)) | (Op::Shr, Reg::ImmNum(n)) if *n > 63 => {
+                            return Err(Error::from(format!(
+                                "{:?} by {}: shifts are at most 63 bits",
+                                o, n
+                            )));
+                        }
+                        _ => (),
+                    }
 
-                    let res = scope.new_tmp(Type::Num(None));
+                    // two's complement adds, subtracts, multiplies and shifts left as unsigned
+                    // numbers do, but the datapath has no signed division, min or max
+                    let signed = is_signed(*o, &left, &right)?;
+                    if signed
+                        && matches!(
+                            o,
+                            Op::Div | Op::Max | Op::MaxWrap | Op::Min | Op::Mod | Op::Shr
+                        )
+                    {
+                        return Err(Error::from(format!(
+                            "{:?} cannot take signed numbers: {:?} and {:?}",
+                            o, left, right
+                        )));
+                    }
+
+                    let res = scope.new_tmp(if signed || *o == Op::SSub {
+                        Type::Int(None)
+                    } else {
+                        Type::Num(None)
+                    });
                     instrs.push(Instr {
                         res: res.clone(),
-                        op: *o,
+                        op: match *o {
+                            Op::SSub => Op::Sub,
+                            o => o,
+                        },
                         left,
                         right,
                     });
@@ -262,24 +581,41 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, res))
                 }
-                Op::Equiv | Op::Gt | Op::Lt => {
+                Op::Equiv | Op::Ge | Op::Gt | Op::Le | Op::Lt | Op::Ne => {
                     // left and right should have type num
                     match left.get_type() {
-                        Ok(Type::Num(_)) => (),
+                        Ok(Type::Num(_)) | Ok(Type::Int(_)) => (),
                         x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
                     }
                     match right.get_type() {
-                        Ok(Type::Num(_)) => (),
+                        Ok(Type::Num(_)) | Ok(Type::Int(_)) => (),
                         x => return Err(Error::from(format!("{:?} expected Num, got {:?}", o, x))),
                     }
 
+                    // the datapath only has ==, > and <: the others are the negation of one
+                    let signed = is_signed(*o, &left, &right)?;
                     let res = scope.new_tmp(Type::Bool(None));
                     instrs.push(Instr {
                         res: res.clone(),
-                        op: *o,
+                        op: match (*o, signed) {
+                            (Op::Gt, true) | (Op::Le, true) => Op::SGt,
+                            (Op::Lt, true) | (Op::Ge, true) => Op::SLt,
+                            (Op::Le, false) => Op::Gt,
+                            (Op::Ge, false) => Op::Lt,
+                            (Op::Ne, _) => Op::Equiv,
+                            (o, _) => o,
+                        },
                         left,
                         right,
                     });
+                    if matches!(o, Op::Ge | Op::Le | Op::Ne) {
+                        instrs.push(Instr {
+                            res: res.clone(),
+                            op: Op::Equiv,
+                            left: res.clone(),
+                            right: Reg::ImmBool(false),
+                        });
+                    }
 
                     Ok((instrs, res))
                 }
@@ -290,13 +626,23 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                     if let Ok(Type::Name(s)) = left.get_type() {
                         let right_type = right.get_type().unwrap();
                         left = scope.update_type(&s, &right_type)?;
+                    } else {
+                        check_bind_types(&left, &right)?;
                     }
 
                     // left must be a mutable register
                     // and if right is a Reg::None, we have to replace it
                     match (&left, &right) {
                         (&Reg::Report(_, _, _), &Reg::None)
-                        | (&Reg::Control(_, _, _), &Reg::None) => {
+                        | (&Reg::Control(_, _, _), &Reg::None)
+                            if matches!(instrs.last(), Some(i) if matches!(i.op, Op::EwmaShift(_))) =>
+                        {
+                            place_ewma_shift(&mut instrs, &left, scope);
+                            Ok((instrs, left))
+                        }
+                        (&Reg::Report(_, _, _), &Reg::None)
+                        | (&Reg::Control(_, _, _), &Reg::None)
+                        | (&Reg::Implicit(_, _), &Reg::None) => {
                             let last_instr = instrs.last_mut().map(|last| {
                                 // Double-check that the instruction being replaced
                                 // actually is a Reg::None before we go replace it
@@ -317,6 +663,22 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                             "cannot bind stateful instruction to Reg::Tmp: {:?}",
                             right_expr,
                         ))),
+                        // (bind a (if c x y)): the if can write a itself, unless it reads a
+                        // again after writing it
+                        (&Reg::Implicit(_, _), &Reg::Tmp(_, _))
+                        | (&Reg::Control(_, _, _), &Reg::Tmp(_, _))
+                        | (&Reg::Local(_, _), &Reg::Tmp(_, _))
+                        | (&Reg::Report(_, _, _), &Reg::Tmp(_, _))
+                            if matches!(**right_expr, Expr::IfElse(_, _, _))
+                                && instrs[instrs.len() - 1].left != left =>
+                        {
+                            let n = instrs.len();
+                            for instr in &mut instrs[n - 2..] {
+                                instr.res = left.clone();
+                            }
+
+                            Ok((instrs, left))
+                        }
                         (&Reg::Implicit(_, _), _)
                         | (&Reg::Control(_, _, _), _)
                         | (&Reg::Local(_, _), _)
@@ -337,6 +699,48 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
                         ))),
                     }
                 }
+                Op::Ewma
+                    if matches!(left.get_type(), Ok(Type::Int(_)))
+                        || matches!(right.get_type(), Ok(Type::Int(_))) =>
+                {
+                    Err(Error::from(format!(
+                        "{:?} cannot take signed numbers: {:?} and {:?}",
+                        o, left, right
+                    )))
+                }
+                Op::EwmaShift(shift) => {
+                    // (ewma s old new): SPECIAL: like ewma, writes and reads return register,
+                    // which old is moved to first, so is also bound by the parent Expr node
+                    for reg in &[&left, &right] {
+                        match reg.get_type() {
+                            Ok(Type::Num(_)) => (),
+                            x => {
+                                return Err(Error::from(format!(
+                                    "{:?} expected Num, got {:?}",
+                                    o, x
+                                )))
+                            }
+                        }
+                    }
+
+                    instrs.push(Instr {
+                        res: Reg::None,
+                        op: Op::Bind,
+                        left: Reg::None,
+                        right: left,
+                    });
+                    instrs.push(Instr {
+                        res: Reg::None,
+                        op: *o,
+                        left: Reg::ImmNum(u64::from(shift)),
+                        right,
+                    });
+
+                    Ok((instrs, Reg::None))
+                }
+                Op::If | Op::NotIf if !matches!(left.get_type(), Ok(Type::Bool(_))) => Err(
+                    Error::from(format!("{:?} expected Bool, got {:?}", o, left.get_type())),
+                ),
                 Op::Ewma | Op::If | Op::NotIf => {
                     // ewma: SPECIAL: reads return register
                     // (ewma a b) ret * a/10 + b * (10-a)/10.
@@ -355,9 +759,300 @@ fn compile_expr(e: &Expr, mut scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
 
                     Ok((instrs, Reg::None))
                 }
-                Op::Def => unreachable!(),
+                Op::Def | Op::SGt | Op::SLt => unreachable!(),
+            }
+        }
+    }
+}
+
+// Check that every name `e`, which is in `root`, reads is in scope, or bound earlier in `root`,
+// which declares it as a local. One which is not is most likely a misspelling of one which is.
+fn check_names(e: &Expr, root: &Expr, scope: &Scope, bound: &mut Vec<String>) -> CompileResult<()> {
+    match *e {
+        Expr::Atom(Prim::Name(ref name)) if !scope.has(name) && !bound.contains(name) => {
+            let mut uses = 0;
+            uses_before(root, e, name, &mut uses);
+            Err(CompileError {
+                at: None,
+                name: Some((name.clone(), uses)),
+                err: Error::from(match suggest(name, scope, bound) {
+                    Some(s) => format!("unknown variable '{}' (did you mean '{}'?)", name, s),
+                    None => format!("unknown variable '{}'", name),
+                }),
+            })
+        }
+        Expr::Sexp(Op::Bind, ref left, ref right) => {
+            check_names(right, root, scope, bound)?;
+            match **left {
+                Expr::Atom(Prim::Name(ref name)) => {
+                    bound.push(name.clone());
+                    Ok(())
+                }
+                ref left => check_names(left, root, scope, bound),
             }
         }
+        Expr::Sexp(_, ref left, ref right) => {
+            check_names(left, root, scope, bound)?;
+            check_names(right, root, scope, bound)
+        }
+        Expr::IfElse(ref cond, ref then, ref otherwise) => {
+            check_names(cond, root, scope, bound)?;
+            check_names(then, root, scope, bound)?;
+            check_names(otherwise, root, scope, bound)
+        }
+        Expr::Atom(_) | Expr::Cmd(_) | Expr::None => Ok(()),
+    }
+}
+
+// Count the uses of `name` in `e` before `at`, in the order of the source, and whether `at` is in
+// `e`.
+fn uses_before(e: &Expr, at: &Expr, name: &str, uses: &mut usize) -> bool {
+    if std::ptr::eq(e, at) {
+        return true;
+    }
+
+    match *e {
+        Expr::Atom(Prim::Name(ref n)) if n == name => {
+            *uses += 1;
+            false
+        }
+        Expr::Sexp(_, ref left, ref right) => {
+            uses_before(left, at, name, uses) || uses_before(right, at, name, uses)
+        }
+        Expr::IfElse(ref cond, ref then, ref otherwise) => {
+            uses_before(cond, at, name, uses)
+                || uses_before(then, at, name, uses)
+                || uses_before(otherwise, at, name, uses)
+        }
+        _ => false,
+    }
+}
+
+// The variable nearest to `name`, if one is at most 2 edits from it. A report variable is also
+// near its name without `Report.`.
+fn suggest<'a>(name: &str, scope: &'a Scope, bound: &'a [String]) -> Option<&'a str> {
+    scope
+        .named
+        .0
+        .iter()
+        .map(|(n, _)| n)
+        .chain(bound)
+        .filter(|n| !n.starts_with("__"))
+        .map(|n| {
+            let short = n.trim_start_matches("Report.");
+            (edit_distance(name, n).min(edit_distance(name, short)), n)
+        })
+        .filter(|&(d, _)| d <= 2)
+        .min_by_key(|&(d, _)| d)
+        .map(|(_, n)| n.as_str())
+}
+
+// How many characters must be inserted, deleted or replaced to turn `a` into `b`.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            curr.push(
+                (prev[j] + (ca != *cb) as usize)
+                    .min(prev[j + 1] + 1)
+                    .min(curr[j] + 1),
+            );
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+// Whether `e` binds variables, rather than having a value. An if does if either branch does.
+fn binds(e: &Expr) -> bool {
+    match *e {
+        Expr::Sexp(Op::Bind, _, _) => true,
+        Expr::IfElse(_, ref then, ref otherwise) => binds(then) || binds(otherwise),
+        _ => false,
+    }
+}
+
+// Compile an if's condition, which must be a Bool.
+fn compile_cond(cond_expr: &Expr, scope: &mut Scope) -> Result<(Vec<Instr>, Reg)> {
+    let (instrs, cond) = compile_expr(cond_expr, scope)?;
+    match cond.get_type() {
+        Ok(Type::Bool(_)) => Ok((instrs, cond)),
+        x => Err(Error::from(format!(
+            "if's condition must be a Bool, got {:?}: {:?}",
+            x, cond_expr
+        ))),
+    }
+}
+
+// Compile (if c then otherwise) whose branches bind variables. Every value is computed, but each
+// bind is predicated to write only if its branch is taken: under `guard`, which is a register
+// and whether the bind is `If` it or `NotIf` it, the branch of the if this one is nested in.
+fn compile_if_binds(
+    cond_expr: &Expr,
+    then: &Expr,
+    otherwise: &Expr,
+    guard: Option<(Reg, Op)>,
+    scope: &mut Scope,
+) -> Result<Vec<Instr>> {
+    let (mut instrs, cond) = compile_cond(cond_expr, scope)?;
+    let mut not = |instrs: &mut Vec<Instr>, r: Reg| {
+        let res = scope.new_tmp(Type::Bool(None));
+        instrs.push(Instr {
+            res: res.clone(),
+            op: Op::Equiv,
+            left: r,
+            right: Reg::ImmBool(false),
+        });
+        res
+    };
+
+    let (then_guard, else_guard) = match guard {
+        None => ((cond.clone(), Op::If), (cond, Op::NotIf)),
+        Some((g, op)) => {
+            let g = if op == Op::NotIf {
+                not(&mut instrs, g)
+            } else {
+                g
+            };
+            let not_cond = not(&mut instrs, cond.clone());
+            let mut and = |instrs: &mut Vec<Instr>, r: Reg| {
+                let res = scope.new_tmp(Type::Bool(None));
+                instrs.push(Instr {
+                    res: res.clone(),
+                    op: Op::Mul,
+                    left: g.clone(),
+                    right: r,
+                });
+                (res, Op::If)
+            };
+            let then_guard = and(&mut instrs, cond);
+            (then_guard, and(&mut instrs, not_cond))
+        }
+    };
+
+    for (branch, guard) in [(then, then_guard), (otherwise, else_guard)] {
+        let mut branch_instrs = compile_branch(branch, guard, scope)?;
+        instrs.append(&mut branch_instrs);
+    }
+
+    Ok(instrs)
+}
+
+// Compile a branch of an if which binds variables, binding only under `guard`.
+fn compile_branch(e: &Expr, guard: (Reg, Op), scope: &mut Scope) -> Result<Vec<Instr>> {
+    match *e {
+        Expr::IfElse(ref cond, ref then, ref otherwise) if binds(e) => {
+            compile_if_binds(cond, then, otherwise, Some(guard), scope)
+        }
+        Expr::Sexp(Op::Bind, ref target, ref value) => {
+            let (mut instrs, right) = compile_expr(value, scope)?;
+            let (_, mut left) = compile_expr(target, scope)?;
+            match (left.get_type(), right.get_type()) {
+                (_, Ok(Type::None)) => {
+                    return Err(Error::from(format!(
+                        "cannot bind a stateful instruction in a branch of an if: {:?}",
+                        value
+                    )))
+                }
+                (Ok(Type::Str), _) | (_, Ok(Type::Str)) => {
+                    return Err(Error::from(format!(
+                        "Bind cannot take a string, which only the datapath sets: {:?}",
+                        e
+                    )))
+                }
+                (Ok(Type::Name(s)), Ok(t)) => left = scope.update_type(&s, &t)?,
+                _ => check_bind_types(&left, &right)?,
+            }
+
+            match left {
+                Reg::Implicit(_, _)
+                | Reg::Control(_, _, _)
+                | Reg::Local(_, _)
+                | Reg::Report(_, _, _) => {
+                    instrs.push(Instr {
+                        res: left,
+                        op: guard.1,
+                        left: guard.0,
+                        right,
+                    });
+
+                    Ok(instrs)
+                }
+                _ => Err(Error::from(format!(
+                    "expected mutable register in bind, found {:?}",
+                    left
+                ))),
+            }
+        }
+        _ => Err(Error::from(format!(
+            "if's branches must both bind variables, or both be values: {:?}",
+            e
+        ))),
+    }
+}
+
+// Write the `(ewma s old new)` at the end of `instrs` to `target`, moving old there first unless it
+// is there already. If new is there instead, it is copied out before old overwrites it.
+fn place_ewma_shift(instrs: &mut Vec<Instr>, target: &Reg, scope: &mut Scope) {
+    let mut ewma = instrs.pop().unwrap();
+    let mut mv = instrs.pop().unwrap();
+    ewma.res = target.clone();
+    if mv.right != *target {
+        if ewma.right == *target {
+            let tmp = scope.new_tmp(Type::Num(None));
+            instrs.push(Instr {
+                res: tmp.clone(),
+                op: Op::Bind,
+                left: tmp.clone(),
+                right: target.clone(),
+            });
+            ewma.right = tmp;
+        }
+
+        mv.res = target.clone();
+        mv.left = target.clone();
+        instrs.push(mv);
+    }
+
+    instrs.push(ewma);
+}
+
+// Check that `right` can be bound to `left`: a boolean only to a boolean, and a number only to a
+// number of the same kind.
+fn check_bind_types(left: &Reg, right: &Reg) -> Result<()> {
+    match (left.get_type(), right.get_type()) {
+        (Ok(Type::Bool(_)), Ok(Type::Num(_)))
+        | (Ok(Type::Bool(_)), Ok(Type::Int(_)))
+        | (Ok(Type::Num(_)), Ok(Type::Bool(_)))
+        | (Ok(Type::Int(_)), Ok(Type::Bool(_))) => Err(Error::from(format!(
+            "Bind cannot mix booleans and numbers: {:?} and {:?}",
+            left, right
+        ))),
+        _ => is_signed(Op::Bind, left, right).map(|_| ()),
+    }
+}
+
+// Whether `o` takes `left` and `right` as signed numbers, which it can only if both are: mixing
+// signed and unsigned numbers is an error. An unsigned literal small enough to be signed is
+// either. Operands which are not numbers are neither.
+fn is_signed(o: Op, left: &Reg, right: &Reg) -> Result<bool> {
+    let signedness = |r: &Reg| match (r, r.get_type()) {
+        (&Reg::ImmNum(n), _) if i64::try_from(n).is_ok() => None,
+        (_, Ok(Type::Int(_))) => Some(true),
+        (_, Ok(Type::Num(_))) => Some(false),
+        _ => None,
+    };
+
+    match (signedness(left), signedness(right)) {
+        (Some(l), Some(r)) if l != r => Err(Error::from(format!(
+            "{:?} cannot mix signed and unsigned numbers: {:?} and {:?}",
+            o, left, right
+        ))),
+        (Some(s), _) | (_, Some(s)) => Ok(s),
+        (None, None) => Ok(false),
     }
 }
 
@@ -407,6 +1102,7 @@ pub struct Scope {
     pub(crate) num_control: u8,
     pub(crate) num_local: u8,
     pub(crate) num_perm: u8,
+    pub(crate) event_triggered: bool,
     tmp: Vec<Reg>,
 }
 
@@ -467,6 +1163,7 @@ impl Scope {
             num_control: 0,
             num_local: 0,
             num_perm: 0,
+            event_triggered: false,
             tmp: vec![],
         };
 
@@ -516,6 +1213,62 @@ impl Scope {
         self.named.get(name)
     }
 
+    /// Whether the program reports only when a condition holds, e.g. with `(report-if cond)`,
+    /// rather than on every evaluation of an event, e.g. every ack or every interval. Known once
+    /// the program is compiled.
+    pub fn is_event_triggered(&self) -> bool {
+        self.event_triggered
+    }
+
+    /// The value report or control variable `name` starts at, as `Report::get_field` gives it, if
+    /// it was declared with one, e.g. `(minrtt +infinity)`, or given one by `lang::compile`'s
+    /// updates. The datapath sets it when it installs the program, and resets a volatile variable
+    /// to it after each report.
+    pub fn initial_value(&self, name: &str) -> Option<u64> {
+        match self.get(name)? {
+            Reg::Report(_, t, _) | Reg::Control(_, t, _) => match *t {
+                Type::Num(Some(n)) => Some(n),
+                Type::Int(Some(n)) => Some(n as u64),
+                Type::Bool(Some(b)) => Some(u64::from(b)),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// The names of the program's report variables, in the order of their registers, i.e. of
+    /// the fields of its measurements.
+    pub fn report_names(&self) -> Vec<String> {
+        let mut reports: Vec<(u8, &String)> = self
+            .named
+            .0
+            .iter()
+            .filter_map(|(name, reg)| match *reg {
+                Reg::Report(idx, _, _) => Some((idx, name)),
+                _ => None,
+            })
+            .collect();
+        reports.sort();
+        reports.into_iter().map(|(_, name)| name.clone()).collect()
+    }
+
+    /// The scope of program `program_uid` as far as a datapath can tell, e.g. from an install
+    /// message with a name table (see `serialize::install::Msg::scope`): the variables every
+    /// program has, and report variables named `reports`, with whether each is volatile, in the
+    /// order of their registers. Their types are unknown, so they hold numbers.
+    pub fn with_reports<I: IntoIterator<Item = (String, bool)>>(
+        program_uid: u32,
+        reports: I,
+    ) -> Self {
+        let mut sc = Scope::new();
+        sc.program_uid = program_uid;
+        for (name, is_volatile) in reports {
+            sc.new_report(is_volatile, name, Type::Num(None));
+        }
+
+        sc
+    }
+
     pub(crate) fn new_tmp(&mut self, t: Type) -> Reg {
         let id = self.tmp.len() as u8;
         let r = Reg::Tmp(id, t);
@@ -603,6 +1356,14 @@ impl Iterator for ScopeDefInstrIter {
                         right: Reg::ImmNum(n),
                     });
                 }
+                Reg::Report(_, Type::Int(Some(n)), _) | Reg::Control(_, Type::Int(Some(n)), _) => {
+                    return Some(Instr {
+                        res: reg.clone(),
+                        op: Op::Def,
+                        left: reg.clone(),
+                        right: Reg::ImmInt(n),
+                    });
+                }
                 Reg::Report(_, Type::Bool(Some(b)), _)
                 | Reg::Control(_, Type::Bool(Some(b)), _) => {
                     return Some(Instr {
@@ -809,6 +1570,180 @@ mod tests {
         assert_eq!(b2, b);
     }
 
+    #[test]
+    fn str_report() {
+        let foo = b"
+        (def (Report (volatile acked 0) (ca_state \"\")))
+        (when true
+            (bind Report.acked Ack.bytes_acked)
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            sc.get("Report.ca_state"),
+            Some(&Reg::Report(1, Type::Str, false))
+        );
+        // the datapath sets it, so no instruction does
+        let s = Reg::Report(1, Type::Str, false);
+        assert!(b
+            .instrs
+            .iter()
+            .all(|i| i.res != s && i.left != s && i.right != s));
+        assert_eq!(sc.report_names(), vec!["Report.acked", "Report.ca_state"]);
+
+        for body in &[
+            "(bind Report.ca_state 1)",
+            "(bind Report.acked Report.ca_state)",
+            "(bind Report.acked (+ Report.ca_state 1))",
+        ] {
+            let src = format!(
+                "(def (Report (volatile acked 0) (ca_state \"\")))\n(when true\n{}\n)",
+                body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            assert!(Bin::compile_prog(&p, &mut sc).is_err(), "{}", body);
+        }
+
+        // only report variables may be strings
+        assert!(Prog::new_with_scope(b"(def (foo \"\"))\n(when true\n(bind foo 1)\n)").is_err());
+    }
+
+    #[test]
+    fn div_mod() {
+        let foo = b"
+        (def (Report (volatile loss 0) (volatile phase 0)))
+        (when true
+            (bind Report.loss (/ Ack.lost_pkts_sample Ack.packets_acked))
+            (bind Report.phase (% Report.phase 8))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let prim = |name: &str| sc.get(name).unwrap().clone();
+
+        // a register divisor
+        assert!(b.instrs.iter().any(|i| i.op == Op::Div
+            && i.left == prim("Ack.lost_pkts_sample")
+            && i.right == prim("Ack.packets_acked")));
+        // a constant divisor
+        assert!(b.instrs.iter().any(|i| i.op == Op::Mod
+            && i.left == prim("Report.phase")
+            && i.right == Reg::ImmNum(8)));
+
+        // dividing by zero is the datapath's to handle, so it compiles
+        let foo = b"
+        (def (Report.loss 0))
+        (when true
+            (bind Report.loss (% (/ Report.loss 0) 0))
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        Bin::compile_prog(&p, &mut sc).unwrap();
+
+        let foo = b"
+        (def (Report.loss 0))
+        (when true
+            (bind Report.loss (% Report.loss true))
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        assert!(Bin::compile_prog(&p, &mut sc).is_err());
+    }
+
+    #[test]
+    fn bit_ops() {
+        let foo = b"
+        (def (Report (volatile ece 0) (volatile sacked 0)))
+        (when true
+            (bind Report.ece (& (>> Ack.ecn_packets 2) 1))
+            (bind Report.sacked (| Report.sacked (^ (<< 1 Report.ece) 6)))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let ops: Vec<Op> = b.instrs.iter().map(|i| i.op).collect();
+        for op in &[Op::BitAnd, Op::BitOr, Op::Xor, Op::Shl, Op::Shr] {
+            assert!(ops.contains(op), "{:?} in {:?}", op, ops);
+        }
+
+        let compile = |body: &str| {
+            let src = format!("(def (Report.foo 0) (on false))\n(when true\n{}\n)", body);
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+        assert!(compile("(bind Report.foo (<< Report.foo 63))").is_ok());
+        assert!(compile("(bind Report.foo (>> Report.foo Report.foo))").is_ok());
+        // a constant shift must fit, but one the datapath computes is its to take mod 64
+        assert!(compile("(bind Report.foo (<< Report.foo 64))").is_err());
+        assert!(compile("(bind Report.foo (shr Report.foo 100))").is_err());
+        // bits of numbers only
+        assert!(compile("(bind Report.foo (& Report.foo on))").is_err());
+        assert!(compile("(bind Report.foo (xor on 1))").is_err());
+    }
+
+    #[test]
+    fn signed() {
+        let foo = b"
+        (def (Report (volatile delta +0) (volatile rising false)) (last_rtt 0))
+        (when true
+            (bind Report.delta (ssub Flow.rtt_sample_us last_rtt))
+            (bind Report.rising (> Report.delta 2))
+            (bind Report.delta (- Report.delta 5))
+            (bind last_rtt Flow.rtt_sample_us)
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let delta = sc.get("Report.delta").unwrap().clone();
+        assert_eq!(delta, Reg::Report(0, Type::Int(Some(0)), true));
+        assert!(b.instrs.contains(&Instr {
+            res: delta.clone(),
+            op: Op::Def,
+            left: delta.clone(),
+            right: Reg::ImmInt(0),
+        }));
+        let ops: Vec<Op> = b.instrs.iter().map(|i| i.op).collect();
+        // ssub is a subtraction with a signed result, and comparing signed numbers is signed
+        assert!(!ops.contains(&Op::SSub) && !ops.contains(&Op::Gt));
+        assert!(ops.contains(&Op::SGt));
+        assert!(b
+            .instrs
+            .iter()
+            .any(|i| i.op == Op::Sub && i.res == Reg::Tmp(0, Type::Int(None))));
+
+        let compile = |body: &str| {
+            let src = format!(
+                "(def (Report (volatile delta +0)) (last_rtt 0))\n(when true\n{}\n)",
+                body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+        assert!(compile("(bind Report.delta (+ Report.delta -1))").is_ok());
+        assert!(compile("(bind Report.delta (ssub Report.delta -1))").is_ok());
+        assert!(compile("(bind Report.delta (* Report.delta 3))").is_ok());
+        // unsigned subtraction wraps past zero, so it cannot be bound to a signed variable
+        let err = compile("(bind Report.delta (- Flow.rtt_sample_us last_rtt))").unwrap_err();
+        assert!(err.0.contains("cannot mix signed and unsigned"), "{}", err);
+        for body in &[
+            "(bind Report.delta (+ Report.delta last_rtt))",
+            "(bind last_rtt (> Report.delta Flow.rtt_sample_us))",
+            "(bind last_rtt -1)",
+            "(bind Report.delta 18446744073709551615)",
+            "(bind Report.delta (/ Report.delta 2))",
+            "(bind Report.delta (max Report.delta +0))",
+            "(bind Report.delta (>> Report.delta 1))",
+            "(bind Report.delta (ewma 2 Report.delta))",
+        ] {
+            assert!(compile(body).is_err(), "{}", body);
+        }
+    }
+
     #[test]
     fn ewma() {
         let foo = b"
@@ -908,6 +1843,485 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ewma_shift() {
+        let foo = b"
+        (def (Report (srtt 0)))
+        (when true
+            (bind Report.srtt (ewma 3 Report.srtt Flow.rtt_sample_us))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [1..2) do [2..3)
+0: rep0 <- (def rep0 0)
+1: imp0 <- (:= imp0 true)
+2: rep0 <- (ewma 3 prim13)"
+        );
+        assert_eq!(b.instrs[2].op, Op::EwmaShift(3));
+
+        let buf = b.serialize().expect("serialize");
+        assert_eq!(
+            &buf[16 + 2 * 16..],
+            &[
+                0x17, // ewma shift
+                0x06, 0x00, 0x00, 0x00, 0x00, // rep0, non-volatile
+                0x01, 0x03, 0x00, 0x00, 0x00, // shift 3
+                0x04, 0x0d, 0x00, 0x00, 0x00, // Flow.rtt_sample_us
+            ][..]
+        );
+        let parsed = Bin::deserialize(&buf, 1, 3).expect("deserialize");
+        assert_eq!(parsed.instrs[2].op, Op::EwmaShift(3));
+        assert_eq!(parsed.serialize().expect("serialize"), buf);
+        let parsed = Bin::deserialize_varint(&b.serialize_varint().expect("serialize"), 1, 3)
+            .expect("deserialize");
+        assert_eq!(parsed.instrs[2].op, Op::EwmaShift(3));
+
+        // smoothing something else moves it in first
+        let foo = b"
+        (def (Report (srtt 0)) (rtt_var 0))
+        (when true
+            (bind Report.srtt (ewma 2 rtt_var Report.srtt))
+        )
+        ";
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [2..3) do [3..6)
+0: rep0 <- (def rep0 0)
+1: ctl0 <- (def ctl0 0)
+2: imp0 <- (:= imp0 true)
+3: tmp0 <- (:= tmp0 rep0)
+4: rep0 <- (:= rep0 ctl0)
+5: rep0 <- (ewma 2 tmp0)"
+        );
+
+        let compile = |body: &str| {
+            let src = format!(
+                "(def (Report (srtt 0)) (on false))\n(when true\n{}\n)",
+                body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+        assert!(
+            compile("(bind Report.srtt (ewma 1 Report.srtt (+ Flow.rtt_sample_us 1)))").is_ok()
+        );
+        assert!(compile("(bind Report.srtt (ewma 1 Report.srtt on))").is_err());
+        assert!(compile("(bind Report.srtt (ewma 1 Report.srtt +0))").is_err());
+    }
+
+    #[test]
+    fn windowed_min_rtt() {
+        let foo = b"
+        (def (Report (volatile minrtt +infinity) (volatile maxrtt 0)))
+        (when true
+            (bind Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (bind Report.maxrtt (max Flow.rtt_sample_us Report.maxrtt))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let minrtt = sc.get("Report.minrtt").unwrap().clone();
+        let maxrtt = sc.get("Report.maxrtt").unwrap().clone();
+        let rtt = sc.get("Flow.rtt_sample_us").unwrap().clone();
+
+        assert_eq!(
+            b,
+            Bin {
+                events: vec![Event {
+                    flag_idx: 2,
+                    num_flag_instrs: 1,
+                    body_idx: 3,
+                    num_body_instrs: 4,
+                }],
+                instrs: vec![
+                    // each report starts from the identity
+                    Instr {
+                        res: maxrtt.clone(),
+                        op: Op::Def,
+                        left: maxrtt.clone(),
+                        right: Reg::ImmNum(0),
+                    },
+                    Instr {
+                        res: minrtt.clone(),
+                        op: Op::Def,
+                        left: minrtt.clone(),
+                        right: Reg::ImmNum(u64::MAX),
+                    },
+                    Instr {
+                        res: sc.get("__eventFlag").unwrap().clone(),
+                        op: Op::Bind,
+                        left: sc.get("__eventFlag").unwrap().clone(),
+                        right: Reg::ImmBool(true),
+                    },
+                    Instr {
+                        res: Reg::Tmp(0, Type::Num(None)),
+                        op: Op::Min,
+                        left: minrtt.clone(),
+                        right: rtt.clone(),
+                    },
+                    Instr {
+                        res: minrtt.clone(),
+                        op: Op::Bind,
+                        left: minrtt.clone(),
+                        right: Reg::Tmp(0, Type::Num(None)),
+                    },
+                    Instr {
+                        res: Reg::Tmp(0, Type::Num(None)),
+                        op: Op::Max,
+                        left: rtt.clone(),
+                        right: maxrtt.clone(),
+                    },
+                    Instr {
+                        res: maxrtt.clone(),
+                        op: Op::Bind,
+                        left: maxrtt.clone(),
+                        right: Reg::Tmp(0, Type::Num(None)),
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn min_max_uninitialized() {
+        let compile = |body: &str| {
+            let src = format!(
+                "(def (Report (volatile minrtt unset) (volatile maxrtt 0)))\n(when true\n{}\n)",
+                body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+
+        for body in &[
+            "(bind Report.minrtt (min Report.minrtt Flow.rtt_sample_us))",
+            "(bind Report.maxrtt (max Flow.rtt_sample_us Report.minrtt))",
+        ] {
+            let err = compile(body).unwrap_err();
+            assert!(err.0.contains("no initial value"), "{}", err);
+        }
+
+        // it can still be bound, as the other can be compared
+        assert!(compile("(bind Report.minrtt Flow.rtt_sample_us)").is_ok());
+        assert!(compile("(bind Report.maxrtt (max Report.maxrtt Flow.rtt_sample_us))").is_ok());
+    }
+
+    #[test]
+    fn if_else() {
+        let foo = b"
+        (def (Report (volatile cwnd 0)))
+        (when true
+            (bind Report.cwnd (if (> Flow.packets_in_flight 10) (* Flow.packets_in_flight 2) 20))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [1..2) do [2..6)
+0: rep0 <- (def rep0 0)
+1: imp0 <- (:= imp0 true)
+2: tmp0 <- (> prim10 10)
+3: tmp1 <- (* prim10 2)
+4: rep0 <- (if tmp0 tmp1)
+5: rep0 <- (!if tmp0 20)"
+        );
+
+        let compile = |flag: &str, body: &str| {
+            let src = format!(
+                "(def (Report (volatile cwnd 0)))\n(when {}\n{}\n)",
+                flag, body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+
+        // as a flag, both predicated instructions write the event flag
+        let b = compile(
+            "(if Flow.was_timeout true (> Flow.rtt_sample_us 1000))",
+            "(report)",
+        )
+        .unwrap();
+        assert_eq!(b.events[0].num_flag_instrs, 3);
+        let flag_reg = Reg::Implicit(0, Type::Bool(None));
+        assert!(b.instrs[2..4].iter().all(|i| i.res == flag_reg));
+
+        for body in &[
+            // the condition must be a Bool
+            "(bind Report.cwnd (if Flow.packets_in_flight 1 2))",
+            // the branches must have the same type
+            "(bind Report.cwnd (if Flow.was_timeout 1 true))",
+            "(bind Report.cwnd (if Flow.was_timeout +1 2))",
+            // both branches bind, or neither does
+            "(if Flow.was_timeout (bind Report.cwnd 1) 2)",
+            // an if which binds has no value
+            "(bind Report.cwnd (if Flow.was_timeout (bind Report.cwnd 1) (report)))",
+            // nor can a branch bind something stateful, which reads what it writes
+            "(if Flow.was_timeout (bind Report.cwnd (ewma 2 Flow.rate_outgoing)) (report))",
+        ] {
+            assert!(compile("true", body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn comparisons() {
+        let foo = b"
+        (def (Report (volatile slow false) (volatile fast false) (volatile changed false)))
+        (when (!= Flow.rate_outgoing 0)
+            (:= Report.slow (>= Flow.rtt_sample_us 1000))
+            (:= Report.fast (<= (ssub Flow.rtt_sample_us 1000) -1))
+            (:= Report.changed (!= Flow.rate_outgoing Flow.rate_incoming))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [3..5) do [5..15)
+0: rep2 <- (def rep2 false)
+1: rep1 <- (def rep1 false)
+2: rep0 <- (def rep0 false)
+3: tmp0 <- (== prim12 0)
+4: imp0 <- (== tmp0 false)
+5: tmp0 <- (< prim13 1000)
+6: tmp0 <- (== tmp0 false)
+7: rep0 <- (:= rep0 tmp0)
+8: tmp0 <- (- prim13 1000)
+9: tmp1 <- (signed > tmp0 -1)
+10: tmp1 <- (== tmp1 false)
+11: rep1 <- (:= rep1 tmp1)
+12: tmp0 <- (== prim12 prim11)
+13: tmp0 <- (== tmp0 false)
+14: rep2 <- (:= rep2 tmp0)"
+        );
+
+        let compile = |body: &str| {
+            let src = format!(
+                "(def (Report (volatile slow false) (volatile rtt 0)))\n(when true\n{}\n)",
+                body
+            );
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes()).unwrap();
+            Bin::compile_prog(&p, &mut sc)
+        };
+
+        for body in &[
+            // comparisons take numbers
+            "(:= Report.slow (>= Flow.was_timeout 1))",
+            "(:= Report.slow (!= Report.slow false))",
+            // and give booleans, which arithmetic does not take, nor numbers bind
+            "(:= Report.rtt (+ (> Flow.rtt_sample_us 1000) 1))",
+            "(:= Report.rtt (<= Flow.rtt_sample_us 1000))",
+            "(:= Report.slow (<= +0 Flow.rtt_sample_us))",
+        ] {
+            assert!(compile(body).is_err(), "{}", body);
+        }
+    }
+
+    #[test]
+    fn loss_classification() {
+        let foo = b"
+        (def (Report
+            (volatile acked 0)
+            (volatile timeouts 0)
+            (volatile losses 0)
+            (volatile reorders 0)
+            (volatile clean 0)
+        ))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (if Flow.was_timeout
+                (:= Report.timeouts (+ Report.timeouts 1))
+                (> Ack.lost_pkts_sample 0)
+                (:= Report.losses (+ Report.losses Ack.lost_pkts_sample))
+                (> Ack.packets_misordered 0)
+                (:= Report.reorders (+ Report.reorders 1))
+                (:= Report.clean (+ Report.clean 1))
+            )
+            (if (|| Flow.was_timeout (> Ack.lost_pkts_sample 0)) (report) (fallthrough))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [5..6) do [6..29)
+0: rep0 <- (def rep0 0)
+1: rep4 <- (def rep4 0)
+2: rep2 <- (def rep2 0)
+3: rep3 <- (def rep3 0)
+4: rep1 <- (def rep1 0)
+5: imp0 <- (:= imp0 true)
+6: tmp0 <- (+ rep0 prim0)
+7: rep0 <- (:= rep0 tmp0)
+8: tmp0 <- (+ rep1 1)
+9: rep1 <- (if prim14 tmp0)
+10: tmp1 <- (> prim4 0)
+11: tmp2 <- (== prim14 false)
+12: tmp3 <- (== tmp1 false)
+13: tmp4 <- (* tmp2 tmp1)
+14: tmp5 <- (* tmp2 tmp3)
+15: tmp6 <- (+ rep2 prim4)
+16: rep2 <- (if tmp4 tmp6)
+17: tmp7 <- (> prim7 0)
+18: tmp8 <- (== tmp7 false)
+19: tmp9 <- (* tmp5 tmp7)
+20: tmp10 <- (* tmp5 tmp8)
+21: tmp11 <- (+ rep3 1)
+22: rep3 <- (if tmp9 tmp11)
+23: tmp12 <- (+ rep4 1)
+24: rep4 <- (if tmp10 tmp12)
+25: tmp0 <- (> prim4 0)
+26: tmp1 <- (+ prim14 tmp0)
+27: imp2 <- (if tmp1 true)
+28: imp1 <- (!if tmp1 true)"
+        );
+
+        // a datapath which runs the 50 instructions the tests' capabilities allow, and only the
+        // instructions the oldest datapaths do, runs it
+        let caps = crate::serialize::capabilities::Msg {
+            ops: 0x7fff,
+            primitives: u32::MAX,
+            max_instrs: 50,
+        };
+        caps.check("loss_classification", &b, &sc).unwrap();
+    }
+
+    #[test]
+    fn report_if() {
+        let foo = b"
+        (def (Report (volatile acked 0) (volatile lost 0)))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (:= Report.lost (+ Report.lost Ack.lost_pkts_sample))
+            (report-if (> Ack.lost_pkts_sample 0))
+            (report-if (> Report.acked (/ Cwnd 2)))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [2..3) do [3..12)
+0: rep0 <- (def rep0 0)
+1: rep1 <- (def rep1 0)
+2: imp0 <- (:= imp0 true)
+3: tmp0 <- (+ rep0 prim0)
+4: rep0 <- (:= rep0 tmp0)
+5: tmp0 <- (+ rep1 prim4)
+6: rep1 <- (:= rep1 tmp0)
+7: tmp0 <- (> prim4 0)
+8: imp2 <- (if tmp0 true)
+9: tmp0 <- (/ imp4 2)
+10: tmp1 <- (> rep0 tmp0)
+11: imp2 <- (if tmp1 true)"
+        );
+        assert!(sc.is_event_triggered());
+
+        // a report only sets __shouldReport, so an evaluation which does not report does not
+        // undo one which did, nor reset volatile variables
+        let report_reg = sc.get("__shouldReport").unwrap().clone();
+        let reports: Vec<_> = b.instrs.iter().filter(|i| i.res == report_reg).collect();
+        assert_eq!(reports.len(), 2);
+        for i in reports {
+            assert_eq!((i.op, &i.right), (Op::If, &Reg::ImmBool(true)));
+            assert_eq!(crate::lang::serialize::serialize_op(i.op), 7);
+        }
+
+        let compile = |src: &str| {
+            let (p, mut sc) = Prog::new_with_scope(src.as_bytes())?;
+            Bin::compile_prog(&p, &mut sc).map(|_| sc.is_event_triggered())
+        };
+
+        // reporting on every ack, or every interval, is not triggered by an event
+        let every = "(def (Report (volatile acked 0)))
+            (when true (:= Report.acked (+ Report.acked Ack.bytes_acked)) (fallthrough))
+            (when (> Micros 1000) (report))";
+        assert!(!compile(every).unwrap());
+        let both = "(def (Report (volatile acked 0)))
+            (when true (report-if Flow.was_timeout) (fallthrough))
+            (when (> Micros 1000) (report))";
+        assert!(!compile(both).unwrap());
+        let branch = "(def (Report (volatile acked 0)))
+            (when true (if Flow.was_timeout (report) (fallthrough)))";
+        assert!(compile(branch).unwrap());
+
+        for src in &[
+            // the condition is a Bool
+            "(def (Report.acked 0)) (when true (report-if Ack.lost_pkts_sample))",
+            // and nothing else binds __shouldReport, which programs cannot even name
+            "(def (Report.acked 0)) (when true (:= __shouldReport false))",
+            "(def (Report.acked 0)) (when true (:= __shouldReport Flow.was_timeout))",
+        ] {
+            assert!(compile(src).is_err(), "{}", src);
+        }
+    }
+
+    #[test]
+    fn initial_values() {
+        let foo = b"
+        (def
+            (gain 1)
+            (Report (volatile minrtt +infinity) (volatile delta -1) (ecn true) (volatile acked 0))
+            (last_rtt unset)
+        )
+        (when true
+            (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+            (:= Report.acked (* Ack.bytes_acked gain))
+        )
+        ";
+
+        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        assert_eq!(
+            b.to_string(),
+            "event 0: when [5..6) do [6..10)
+0: rep3 <- (def rep3 0)
+1: rep1 <- (def rep1 -1)
+2: rep2 <- (def rep2 true)
+3: rep0 <- (def rep0 18446744073709551615)
+4: ctl0 <- (def ctl0 1)
+5: imp0 <- (:= imp0 true)
+6: tmp0 <- (min rep0 prim13)
+7: rep0 <- (:= rep0 tmp0)
+8: tmp0 <- (* prim0 ctl0)
+9: rep3 <- (:= rep3 tmp0)"
+        );
+
+        for (name, val) in &[
+            ("gain", Some(1)),
+            ("Report.minrtt", Some(u64::MAX)),
+            ("Report.delta", Some(u64::MAX)),
+            ("Report.ecn", Some(1)),
+            ("Report.acked", Some(0)),
+            ("last_rtt", None),
+            ("Flow.rtt_sample_us", None),
+            ("nonexistent", None),
+        ] {
+            assert_eq!(sc.initial_value(name), *val, "{}", name);
+        }
+
+        // updates replace them
+        let (b, sc) = crate::lang::compile(foo, &[("gain", 3)]).unwrap();
+        assert_eq!(sc.initial_value("gain"), Some(3));
+        assert!(b.instrs.contains(&Instr {
+            res: sc.get("gain").unwrap().clone(),
+            op: Op::Def,
+            left: sc.get("gain").unwrap().clone(),
+            right: Reg::ImmNum(3),
+        }));
+    }
+
     #[test]
     fn control_def() {
         let foo = b"
@@ -1427,4 +2841,65 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn error_positions() {
+        let err = |src: &str| crate::lang::compile(src.as_bytes(), &[]).unwrap_err().0;
+        let prog = |body: &str| {
+            format!(
+                "(def (acked 0) (Report (volatile lost 0)))\n(when true\n{}\n    (report)\n)",
+                body
+            )
+        };
+
+        assert_eq!(
+            err(&prog(
+                "    (:= acked (+ acked Ack.bytes_acked))\n    (:= acked (* ackd 2))"
+            )),
+            "line 4, col 18: unknown variable 'ackd' (did you mean 'acked'?)"
+        );
+        // a report variable is near its name without `Report.`, and comments do not count
+        assert_eq!(
+            err(&prog(
+                "    (:= Report.lost #| lots |# (+ lots Ack.lost_pkts_sample))"
+            )),
+            "line 3, col 35: unknown variable 'lots' (did you mean 'Report.lost'?)"
+        );
+        assert_eq!(
+            err(&prog(
+                "    (:= Report.lost (+ Report.lost Ack.lost_packets))"
+            )),
+            "line 3, col 36: unknown variable 'Ack.lost_packets'"
+        );
+        // a variable may be read once it is bound, and not before
+        assert!(
+            crate::lang::compile(prog("    (:= x 1)\n    (:= acked x)").as_bytes(), &[]).is_ok()
+        );
+        assert_eq!(
+            err(&prog("    (:= x (+ x 1))")),
+            "line 3, col 14: unknown variable 'x'"
+        );
+        // other errors are at the start of the expression they are in
+        assert_eq!(
+            err(&prog("    (:= acked 1)\n    (:= Report.lost (> acked 2))")),
+            "line 4, col 5: Bind cannot mix booleans and numbers: Report(0, Num(Some(0)), true) and \
+             Tmp(0, Bool(None))"
+        );
+        assert_eq!(
+            err("(def (foo 0))\n(when (+ foo 1)\n    (report)\n)"),
+            "line 2, col 7: Flag expression must result in bool: Tmp(0, Num(None))"
+        );
+        assert_eq!(
+            err("(def (foo 0))\n(when (> Micros 10)\n    (report)\n)\n(when (== fooo 1)\n    (report)\n)"),
+            "line 5, col 11: unknown variable 'fooo' (did you mean 'foo'?)"
+        );
+
+        // several programs' errors are where in the whole source they are
+        let src = b"(program a (def (foo 0)) (when true (:= foo 1)))
+            (program b (def (bar 0)) (when true (:= bar (+ baz 1))))";
+        assert_eq!(
+            crate::lang::compile_programs(src).unwrap_err().0,
+            "line 2, col 60: unknown variable 'baz' (did you mean 'bar'?)"
+        );
+    }
 }
@@ -15,6 +15,11 @@
 //! a variable counting the number of cumulatively acknowledged packets would be declared volatile
 //! to prevent double-counting these values in the CCP algorithm logic.
 //!
+//! Each variable is declared with the value it starts at, e.g. `+infinity` for a minimum or 1
+//! for a multiplier. The datapath sets it when it installs the program, and a volatile variable
+//! goes back to it after each report. `lang::compile`'s updates replace these values, and
+//! `Scope::initial_value` says what they are.
+//!
 //! ### Example
 //! ```text
 //! (def
@@ -46,6 +51,114 @@
 //! )
 //! ```
 //!
+//! `(report-if cond)` reports only if `cond` is true, e.g. when a loss is detected, rather than
+//! on every ack or interval. The datapath sends a report, and resets volatile report variables,
+//! after an evaluation which asked for one: an evaluation which does not leaves them
+//! accumulating, and does not undo an earlier `(report)` or `(report-if cond)` in the same
+//! evaluation. A program which only reports so is event-triggered (see
+//! `Scope::is_event_triggered`).
+//!
+//! ### Example
+//! ```text
+//! (when true
+//!     (:= Report.acked (+ Report.acked Ack.bytes_acked))
+//!     (report-if (> Ack.lost_pkts_sample 0))
+//!     (report-if (> Report.acked (/ Cwnd 2)))
+//! )
+//! ```
+//!
+//! Arithmetic
+//! ----------
+//!
+//! Numbers are 64-bit integers, unsigned unless signed (see below). `+`, `-`, `*`, `/` and `%`
+//! (or `add`, `sub`, `mul`, `div` and `mod`) take two of them; `/` and `%` are integer division
+//! and its remainder. The datapath does not fault on dividing by zero: both give 0 instead, so a
+//! ratio such as `(/ Report.lost Report.acked)` needs no guard before the first ack.
+//!
+//! `&`, `|`, `^`, `<<` and `>>` (or `bitand`, `bitor`, `xor`, `shl` and `shr`) work on the
+//! bits of numbers, e.g. to pick flags out of a primitive which packs several. `and` and `or`
+//! are the boolean operators, as `&&` and `||` are. The datapath takes shift amounts mod 64; a
+//! constant amount past 63 does not compile.
+//!
+//! `==`, `!=`, `<`, `<=`, `>` and `>=` (or `eq`, `ne`, `lt`, `le`, `gt` and `ge`) compare two
+//! numbers and give a boolean, e.g. `(>= Flow.rtt_sample_us 1000)`. Booleans are not numbers:
+//! arithmetic does not take them, and only a variable declared `true` or `false` holds one. A
+//! report variable sends it to CCP as 1 or 0, which `Report::get_field_bool` reads back.
+//!
+//! `min` and `max` take the lesser or greater of two numbers, e.g. to clamp a window with
+//! `(max Cwnd (* 2 mss))`. Their identities are `+infinity` and 0: a volatile report variable
+//! declared with one, e.g. `(volatile minrtt +infinity)`, holds the extreme since the last
+//! report. A variable declared without a number has no value until it is bound, so `min` and
+//! `max` do not take it.
+//!
+//! `(ewma s old new)` smooths `new` into `old` with a weight of 2^-s on `new`, as
+//! `old - (old >> s) + (new >> s)` in one instruction, e.g. `(ewma 3 Report.srtt
+//! Flow.rtt_sample_us)` for TCP's smoothed RTT. `s` is a literal from 1 to 63. `ewma` binds
+//! only into a report or control variable, and `(ewma a new)`, without `old`, smooths into that
+//! variable by tenths instead: `old * a/10 + new * (10-a)/10`.
+//!
+//! A number written with a sign, e.g. `-1` or `+0`, is signed, as is a variable declared with
+//! one, e.g. `(rtt_delta +0)`. Signed and unsigned numbers cannot be mixed, except for literals
+//! without a sign, which are either. `ssub` subtracts two numbers of either kind and gives a
+//! signed result, e.g. the change in RTT since the last sample, where `-` would wrap around past
+//! zero. Comparisons compare signed numbers as such; `/`, `%`, `max`, `min`, `>>` and `ewma` do
+//! not take them. Literals in instructions are at most 31 bits, or -1, so subtract rather than
+//! add a negative literal.
+//!
+//! ### Example
+//! ```text
+//! (when true
+//!     (:= Report.avg_rtt (/ rtt_sum num_samples))
+//!     (:= Report.phase (% Report.rounds 8))
+//!     (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+//!     (:= Report.srtt (ewma 3 Report.srtt Flow.rtt_sample_us))
+//!     (:= Report.ece (& (>> packed_flags 2) 1))
+//!     (:= Report.rtt_delta (ssub Flow.rtt_sample_us last_rtt))
+//!     (:= Report.rtt_rising (> Report.rtt_delta +0))
+//!     (:= Report.slow (>= Flow.rtt_sample_us 1000))
+//! )
+//! ```
+//!
+//! Conditionals
+//! ------------
+//!
+//! `(if cond then else)` is `then` if `cond`, which must be a boolean, is true, and `else`
+//! otherwise; more branches, `(if cond1 then1 cond2 then2 ... else)`, try each condition in
+//! turn. Either every branch is a value, of the same type, or every branch binds a variable
+//! (or reports, falls through, or is such an `if` itself). The datapath does not branch: it
+//! computes every branch's value, which has no side effects, and only the branch taken writes
+//! its variable. So an `if` costs an instruction or two for each branch besides its values.
+//! `(if cond then)`, without `else`, binds only if `cond` is true, as in `(:= Report.minrtt
+//! (if (< Flow.rtt_sample_us Report.minrtt) Flow.rtt_sample_us))`.
+//!
+//! ### Example
+//! ```text
+//! (when true
+//!     (:= Report.cwnd (if (> Report.lost 0) (/ Cwnd 2) (+ Cwnd 1)))
+//!     (if Flow.was_timeout
+//!         (:= Report.timeouts (+ Report.timeouts 1))
+//!         (> Ack.lost_pkts_sample 0)
+//!         (:= Report.losses (+ Report.losses Ack.lost_pkts_sample))
+//!         (fallthrough)
+//!     )
+//! )
+//! ```
+//!
+//! Comments
+//! --------
+//!
+//! Comments may go anywhere whitespace may, even within an expression: `;` or `#` to the end of
+//! the line, or `#|` to `|#`, which may span lines.
+//!
+//! ### Example
+//! ```text
+//! ; count acks
+//! (when true
+//!     (:= Report.acked (+ Report.acked #| in-order only |# Ack.packets_acked))
+//!     (fallthrough) # keep going
+//! )
+//! ```
+//!
 //! Compiling
 //! ---------
 //!
@@ -76,6 +189,31 @@
 //! }
 //! ```
 //!
+//! A program which does not compile is an error which says where in the source the problem is,
+//! e.g. `line 12, col 7: unknown variable 'ackd' (did you mean 'acked'?)`. A variable must be
+//! declared in `def`, or bound with `:=` as a local, before it is read.
+//!
+//! `lang::compile_programs()` compiles a source with several programs, each named with
+//! `(program name ...)`, into a `Bin` and `Scope` for each, e.g. one for each phase of an
+//! algorithm:
+//!
+//! ```
+//! use portus::lang;
+//!
+//! let phases = b"
+//!     (program slow_start
+//!         (def (Report (volatile acked 0)))
+//!         (when true (:= Report.acked Ack.bytes_acked) (report))
+//!     )
+//!     (program cong_avoid
+//!         (def (Report (volatile rtt 0)))
+//!         (when true (:= Report.rtt Flow.rtt_sample_us) (report))
+//!     )
+//! ";
+//! let programs = lang::compile_programs(phases).unwrap();
+//! assert_eq!(programs[1].0, "cong_avoid");
+//! ```
+//!
 //! Available Primitives
 //! --------------------
 //!
@@ -183,6 +321,20 @@ macro_rules! named_complete {
     )
 }
 
+/// Like nom's `ws!`, but skipping comments as well as whitespace between, before, and after the
+/// parsers it wraps.
+macro_rules! ws_comments {
+    ($i:expr, $($args:tt)*) => {{
+        match sep!($i, crate::lang::ast::whitespace, $($args)*) {
+            Err(e) => Err(e),
+            Ok((i1, o)) => match crate::lang::ast::whitespace(i1) {
+                Err(e) => Err(nom::Err::convert(e)),
+                Ok((i2, _)) => Ok((i2, o)),
+            },
+        }
+    }};
+}
+
 mod ast;
 mod datapath;
 mod prog;
@@ -193,6 +345,7 @@ pub use self::datapath::Reg;
 pub use self::datapath::Scope;
 pub use self::datapath::Type;
 pub use self::prog::Prog;
+pub(crate) use self::serialize::{deserialize_reg, serialize_op, serialize_reg};
 
 /// `compile()` uses 5 passes to yield Instrs.
 ///
@@ -202,19 +355,58 @@ pub use self::prog::Prog;
 /// 3. The ASTs are desugared to support (report) and (fallthrough).
 /// 4. The list of runtime updates (from `updates`) for values is applied to the Scope.
 /// 5. `Bin::compile_prog()` turns a `Prog` into a `Bin`, which is a `Vec` of datapath `Instr`
+///
+/// An error says where in `src` it is, e.g. `line 12, col 7: unknown variable 'ackd' (did you
+/// mean 'acked'?)`.
 pub fn compile(src: &[u8], updates: &[(&str, u32)]) -> Result<(Bin, Scope)> {
-    Prog::new_with_scope(src).and_then(|(p, mut s)| {
+    Prog::new_with_offsets(src).and_then(|(p, mut s, offsets)| {
         for &(name, new_val) in updates {
-            match s.update_type(name, &Type::Num(Some(new_val as u64))) {
+            let new_type = match s.get(name) {
+                Some(Reg::Control(_, Type::Int(_), _)) | Some(Reg::Report(_, Type::Int(_), _)) => {
+                    Type::Int(Some(i64::from(new_val)))
+                }
+                _ => Type::Num(Some(new_val as u64)),
+            };
+            match s.update_type(name, &new_type) {
                 Ok(_) => {}
                 Err(e) => println!("err: {}", e),
             }
         }
 
-        Ok((Bin::compile_prog(&p, &mut s)?, s))
+        let b = Bin::compile_located(&p, &mut s).map_err(|e| locate(src, &offsets, e))?;
+        Ok((b, s))
     })
 }
 
+/// `compile_programs()` compiles a source with several programs, each `(program name (def ...)
+/// (when ...) ...)`, as `compile()` does each one with no updates. Each has its own `Scope`, e.g.
+/// for `Report::get_field`. A source with one program need not name it, and it is then named `""`.
+pub fn compile_programs(src: &[u8]) -> Result<Vec<(String, Bin, Scope)>> {
+    Prog::new_named_with_offsets(src)?
+        .into_iter()
+        .map(|(name, p, mut s, offsets)| {
+            let b = Bin::compile_located(&p, &mut s).map_err(|e| locate(src, &offsets, e))?;
+            Ok((name, b, s))
+        })
+        .collect()
+}
+
+// Say where in `src` the error is: at the name it is about, or else the start of the expression
+// it is in, which `offsets` says for each event.
+fn locate(src: &[u8], offsets: &[Vec<usize>], e: datapath::CompileError) -> Error {
+    match e.at {
+        Some((event, expr)) => {
+            let start = offsets[event][expr];
+            let at = e
+                .name
+                .and_then(|(n, skip)| prog::find_name(src, start, &n, skip))
+                .unwrap_or(start);
+            prog::error_at(src, at, e.err)
+        }
+        None => e.err,
+    }
+}
+
 /// `compile_and_serialize()` adds a fourth pass.
 /// The resulting bytes can be passed to the datapath.
 ///
@@ -1,7 +1,7 @@
 use nom::types::CompleteByteSlice;
 use nom::*;
 
-use super::ast::{atom, comment, expr, exprs, name, Expr};
+use super::ast::{atom, expr, name, whitespace, Expr};
 use super::datapath::{check_atom_type, Scope, Type};
 use super::{Error, Result};
 
@@ -20,28 +20,36 @@ pub struct Event {
 #[derive(Debug, PartialEq)]
 pub struct Prog(pub Vec<Event>);
 
+// Where each event's expressions are in the source, as byte offsets: its flag's, then each of its
+// body's.
+type Offsets = Vec<Vec<usize>>;
+
 // ------------------------------------------
 // (def (decl)...) grammar
 // ------------------------------------------
 
 // Declare a state variable and provide an initial value
 // Optionally declare the variable "volatile", meaning it gets reset on "(report)"
+// A report variable may instead be a string the datapath sets, declared with `""`
 named_complete!(
     decl<(bool, Type, Type)>,
-    ws!(delimited!(
+    ws_comments!(delimited!(
         tag!("("),
         tuple!(
             map!(opt!(tag!("volatile")), |v: Option<CompleteByteSlice>| v
                 .is_some()),
             map!(name, Type::Name),
-            map_res!(atom, |a: Result<Expr>| a.and_then(|i| check_atom_type(&i)))
+            alt!(
+                tag!("\"\"") => { |_| Type::Str } |
+                map_res!(atom, |a: Result<Expr>| a.and_then(|i| check_atom_type(&i)))
+            )
         ),
         tag!(")")
     ))
 );
 named_complete!(
     report_struct<Vec<(bool, Type, Type)>>,
-    ws!(delimited!(
+    ws_comments!(delimited!(
         tag!("("),
         do_parse!(tag!("Report") >> d: many1!(decl) >> (d)),
         tag!(")")
@@ -52,7 +60,7 @@ named_complete!(
 // (def (decl) ...)
 named_complete!(
     defs<Vec<(bool, Type, Type)>>,
-    ws!(delimited!(
+    ws_comments!(delimited!(
         tag!("("),
         do_parse!(
             tag!("def")
@@ -67,7 +75,9 @@ named_complete!(
                         _ => None,
                     }
                     .map(|full_name| match init_val {
-                        x @ Type::Num(_) | x @ Type::Bool(_) => (is_volatile, full_name, x),
+                        x @ Type::Num(_) | x @ Type::Int(_) | x @ Type::Bool(_) | x @ Type::Str => {
+                            (is_volatile, full_name, x)
+                        }
                         _ => (is_volatile, full_name, Type::None),
                     }))
                     .chain(
@@ -75,7 +85,10 @@ named_complete!(
                             .into_iter()
                             .chain(defs2)
                             .map(|(is_volatile, name, init_val)| match init_val {
-                                x @ Type::Num(_) | x @ Type::Bool(_) => (is_volatile, name, x),
+                                x @ Type::Num(_)
+                                | x @ Type::Int(_)
+                                | x @ Type::Bool(_)
+                                | x @ Type::Str => (is_volatile, name, x),
                                 _ => (is_volatile, name, Type::None),
                             })
                     )
@@ -89,44 +102,176 @@ named_complete!(
 // (when (bool expr) (body)...) grammar
 // ------------------------------------------
 
-// (when (single expr) (expr)...)
+// an expression, and where it starts
+fn located_expr(
+    i: CompleteByteSlice,
+) -> IResult<CompleteByteSlice, (CompleteByteSlice, Result<Expr>), u32> {
+    let (i, _) = whitespace(i)?;
+    expr(i).map(|(rest, e)| (rest, (i, e)))
+}
+
+// (when (single expr) (expr)...): its flag then its body, each with where it starts
 named_complete!(
-    event<Result<Event>>,
-    ws!(delimited!(
+    located_event<Vec<(CompleteByteSlice, Result<Expr>)>>,
+    ws_comments!(delimited!(
         tag!("("),
         do_parse!(
             tag!("when")
-                >> c: expr
-                >> body: exprs
-                >> (c.and_then(|cond| {
-                    let exps: Result<Vec<Expr>> = body.into_iter().collect();
-                    Ok(Event {
-                        flag: cond,
-                        body: exps?,
-                    })
-                }))
+                >> c: located_expr
+                >> body: many1!(located_expr)
+                >> (Some(c).into_iter().chain(body).collect())
         ),
         tag!(")")
     ))
 );
 named_complete!(
-    events<Vec<Result<Event>>>,
-    many1!(do_parse!(opt!(comment) >> e: event >> (e)))
+    event<Result<Event>>,
+    map!(located_event, |exps| {
+        let mut exps = exps
+            .into_iter()
+            .map(|(_, e)| e)
+            .collect::<Result<Vec<Expr>>>()?;
+        let flag = exps.remove(0);
+        Ok(Event { flag, body: exps })
+    })
+);
+named_complete!(events<Vec<Result<Event>>>, many1!(event));
+
+// ------------------------------------------
+// (program name (def ...) (when ...)...) grammar
+// ------------------------------------------
+
+// one of several programs in a source, and its source
+named_complete!(
+    program<(String, CompleteByteSlice)>,
+    ws_comments!(delimited!(
+        tag!("("),
+        do_parse!(
+            tag!("program")
+                >> n: name
+                >> src: recognize!(pair!(defs, events))
+                >> ((n, src))
+        ),
+        tag!(")")
+    ))
+);
+// the start of one, up to its definitions
+named_complete!(
+    program_name<String>,
+    ws_comments!(do_parse!(tag!("(") >> tag!("program") >> n: name >> (n)))
+);
+named_complete!(
+    program_start<()>,
+    map!(ws_comments!(pair!(tag!("("), tag!("program"))), |_| ())
 );
 
-fn get_error(src: CompleteByteSlice) -> Error {
-    match events(src) {
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Error::from(e),
-        _ => Error::from("none"),
+/// Where `offset` is in `source`: its line and column, each counting from 1.
+pub(crate) fn line_col(source: &[u8], offset: usize) -> (usize, usize) {
+    let before = &source[..offset];
+    let line_start = before
+        .iter()
+        .rposition(|&c| c == b'\n')
+        .map_or(0, |i| i + 1);
+    (
+        before.iter().filter(|&&c| c == b'\n').count() + 1,
+        String::from_utf8_lossy(&before[line_start..])
+            .chars()
+            .count()
+            + 1,
+    )
+}
+
+/// An error at `offset` in `source`, e.g. `line 12, col 7: unknown variable 'ackd'`.
+pub(crate) fn error_at<D: std::fmt::Display>(source: &[u8], offset: usize, msg: D) -> Error {
+    let (line, col) = line_col(source, offset);
+    Error::from(format!("line {}, col {}: {}", line, col, msg))
+}
+
+/// Where in `source`, from `offset`, `name` is used after `skip` other uses, not counting comments
+/// or other names it is part of.
+pub(crate) fn find_name(source: &[u8], offset: usize, name: &str, skip: usize) -> Option<usize> {
+    let is_name = |c: &u8| is_alphanumeric(*c) || *c == b'.' || *c == b'_';
+    let mut i = offset;
+    let mut skip = skip;
+    while i < source.len() {
+        match whitespace(CompleteByteSlice(&source[i..])) {
+            Ok((rest, skipped)) if !skipped.is_empty() => i = source.len() - rest.len(),
+            _ if is_name(&source[i]) => {
+                let len = source[i..]
+                    .iter()
+                    .position(|c| !is_name(c))
+                    .unwrap_or(source.len() - i);
+                if &source[i..i + len] == name.as_bytes() {
+                    if skip == 0 {
+                        return Some(i);
+                    }
+
+                    skip -= 1;
+                }
+
+                i += len;
+            }
+            _ => i += 1,
+        }
+    }
+
+    None
+}
+
+// The token which `rest` starts with, with the paren before it if any, e.g. `(reprot`.
+fn token(rest: &[u8]) -> String {
+    let paren = (rest.first() == Some(&b'(')) as usize;
+    let len = rest[paren..]
+        .iter()
+        .position(|c| c.is_ascii_whitespace() || *c == b'(' || *c == b')')
+        .unwrap_or(rest.len() - paren);
+    String::from_utf8_lossy(&rest[..paren + len.max(1 - paren)]).into_owned()
+}
+
+// The parse error for `source`, which could not be parsed from the start of `rest`.
+fn parse_error(source: &[u8], rest: CompleteByteSlice) -> Error {
+    let offset = source.offset(rest.0);
+    if rest.is_empty() {
+        error_at(source, offset, "unexpected end of source")
+    } else {
+        error_at(source, offset, format!("cannot parse '{}'", token(rest.0)))
+    }
+}
+
+// Where the parser which started at `i` and returned `e` failed.
+fn failed_at<'a>(
+    e: nom::Err<CompleteByteSlice<'a>, u32>,
+    i: CompleteByteSlice<'a>,
+) -> CompleteByteSlice<'a> {
+    match e {
+        nom::Err::Error(c) | nom::Err::Failure(c) => match c {
+            nom::Context::Code(at, _) => at,
+            #[allow(unreachable_patterns)]
+            #[cfg(feature = "lang-verbose-errors")]
+            nom::Context::List(ks) => ks.first().map_or(i, |&(at, _)| at),
+        },
+        nom::Err::Incomplete(_) => i,
     }
 }
 
 impl Prog {
     /// Turn raw bytes into an AST representation, including implementing syntactic sugar features
-    /// such as `(report)` and `(fallthrough)`.
+    /// such as `(report)` and `(fallthrough)`. A source which cannot be parsed is an error which
+    /// says where, e.g. `line 3, col 9: cannot parse '(reprot'`.
     pub fn new_with_scope(source: &[u8]) -> Result<(Self, Scope)> {
+        Prog::new_with_offsets(source).map(|(p, sc, _)| (p, sc))
+    }
+
+    /// Like `new_with_scope`, and where each event's expressions are in the source, as byte
+    /// offsets: its flag's, then each of its body's.
+    pub(crate) fn new_with_offsets(source: &[u8]) -> Result<(Self, Scope, Offsets)> {
+        Prog::parse(source, CompleteByteSlice(source))
+    }
+
+    // Parse `src`, which is all or part of `source`, with where its expressions are in `source`.
+    fn parse(source: &[u8], src: CompleteByteSlice) -> Result<(Self, Scope, Offsets)> {
         let mut scope = Scope::new();
-        let body = match defs(CompleteByteSlice(source)) {
+        let mut body = match defs(src) {
             Ok((rest, flow_state)) => {
                 let (reports, controls): (Vec<(bool, String, Type)>, Vec<(bool, String, Type)>) =
                     flow_state
@@ -142,42 +287,112 @@ impl Prog {
                 }
 
                 for (is_volatile, var, typ) in controls {
+                    if typ == Type::Str {
+                        return Err(Error::from(format!(
+                            "{} cannot be a string: only report variables can",
+                            var
+                        )));
+                    }
+
                     scope.new_control(is_volatile, var, typ);
                 }
 
                 Ok(rest)
             }
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
-            Err(nom::Err::Incomplete(Needed::Unknown)) => {
-                Err(Error::from(String::from("need more src")))
-            }
-            Err(nom::Err::Incomplete(Needed::Size(s))) => {
-                Err(Error::from(format!("need {} more bytes", s)))
-            }
+            Err(e) => Err(parse_error(source, failed_at(e, src))),
         }?;
 
-        let evs = match events(body) {
-            Ok((rest, _)) if !rest.is_empty() => {
-                let e = get_error(rest);
-                Err(Error::from(format!(
-                    "compile error: \"{:?}\" in \"{}\"",
-                    e,
-                    std::str::from_utf8(rest.0)?
-                )))
+        let mut evs = vec![];
+        let mut offsets = vec![];
+        loop {
+            if let Ok((rest, _)) = whitespace(body) {
+                body = rest;
             }
-            Ok((_, me)) => me.into_iter().collect(),
-            Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => Err(Error::from(e)),
-            Err(nom::Err::Incomplete(Needed::Unknown)) => Err(Error::from("need more src")),
-            Err(nom::Err::Incomplete(Needed::Size(s))) => {
-                Err(Error::from(format!("need {} more bytes", s)))
+            if body.is_empty() && !evs.is_empty() {
+                break;
             }
-        }?;
+
+            let (rest, exps) =
+                located_event(body).map_err(|e| parse_error(source, failed_at(e, body)))?;
+            let (starts, exps): (Vec<usize>, Vec<Result<Expr>>) = exps
+                .into_iter()
+                .map(|(at, e)| (source.offset(at.0), e))
+                .unzip();
+            let mut exps = starts
+                .iter()
+                .zip(exps)
+                .map(|(&at, e)| e.map_err(|e| error_at(source, at, e)))
+                .collect::<Result<Vec<Expr>>>()?;
+            let flag = exps.remove(0);
+            evs.push(Event { flag, body: exps });
+            offsets.push(starts);
+            body = rest;
+        }
 
         let mut p = Prog(evs);
         p.desugar();
 
         // TODO make Expr::new return Iter, make self wrap an iter also
-        Ok((p, scope))
+        Ok((p, scope, offsets))
+    }
+
+    /// Like `new_with_scope`, for a source with several programs, each `(program name (def ...)
+    /// (when ...) ...)`: a `Prog` and its own `Scope` for each, with its name, in the order of the
+    /// source. A source with only one program need not name it, and it is then named `""`.
+    pub fn new_named_with_scopes(source: &[u8]) -> Result<Vec<(String, Self, Scope)>> {
+        Prog::new_named_with_offsets(source).map(|progs| {
+            progs
+                .into_iter()
+                .map(|(name, p, sc, _)| (name, p, sc))
+                .collect()
+        })
+    }
+
+    /// Like `new_named_with_scopes`, and where each program's expressions are in the source, as
+    /// `new_with_offsets` says.
+    pub(crate) fn new_named_with_offsets(
+        source: &[u8],
+    ) -> Result<Vec<(String, Self, Scope, Offsets)>> {
+        if program_start(CompleteByteSlice(source)).is_err() {
+            return Prog::new_with_offsets(source)
+                .map(|(p, sc, offsets)| vec![(String::new(), p, sc, offsets)]);
+        }
+
+        let mut named: Vec<(String, Self, Scope, Offsets)> = vec![];
+        let mut i = CompleteByteSlice(source);
+        loop {
+            if let Ok((rest, _)) = whitespace(i) {
+                i = rest;
+            }
+            if i.is_empty() && !named.is_empty() {
+                break;
+            }
+
+            let (rest, (name, src)) = match program(i) {
+                Ok(p) => p,
+                Err(e) => {
+                    // say what in the program could not be parsed, rather than that it could not
+                    if let Ok((body, _)) = program_name(i) {
+                        Prog::parse(source, body)?;
+                    }
+
+                    return Err(parse_error(source, failed_at(e, i)));
+                }
+            };
+            if named.iter().any(|(n, _, _, _)| *n == name) {
+                return Err(error_at(
+                    source,
+                    source.offset(i.0),
+                    format!("program {:?} is defined twice", name),
+                ));
+            }
+
+            let (p, sc, offsets) = Prog::parse(source, src)?;
+            named.push((name, p, sc, offsets));
+            i = rest;
+        }
+
+        Ok(named)
     }
 
     fn desugar(&mut self) {
@@ -250,6 +465,31 @@ mod tests {
         }
     }
 
+    #[test]
+    fn def_str() {
+        let foo = b"(def (Report (volatile State \"\") (Foo 0)))";
+        match super::defs(CompleteByteSlice(foo)) {
+            Ok((r, me)) => {
+                assert_eq!(r, CompleteByteSlice(&[]));
+                assert_eq!(
+                    me,
+                    vec![
+                        (true, Type::Name(String::from("Report.State")), Type::Str),
+                        (
+                            false,
+                            Type::Name(String::from("Report.Foo")),
+                            Type::Num(Some(0))
+                        ),
+                    ]
+                );
+            }
+            Err(e) => panic!("{:?}", e),
+        }
+
+        // strings are the datapath's to set, so only ever declared empty
+        assert!(super::defs(CompleteByteSlice(b"(def (Report (State \"Open\")))")).is_err());
+    }
+
     #[test]
     fn reserved_names() {
         use nom::Needed;
@@ -292,6 +532,157 @@ mod tests {
         }
     }
 
+    #[test]
+    fn div_mod() {
+        let foo = b"(when true (/ 7 (% 5 Ack.bytes_acked)) (mod 3 (div 2 0)))";
+        match super::event(CompleteByteSlice(foo)) {
+            Ok((r, Ok(me))) => {
+                assert_eq!(r, CompleteByteSlice(&[]));
+                assert_eq!(
+                    me.body,
+                    vec![
+                        Expr::Sexp(
+                            Op::Div,
+                            Box::new(Expr::Atom(Prim::Num(7))),
+                            Box::new(Expr::Sexp(
+                                Op::Mod,
+                                Box::new(Expr::Atom(Prim::Num(5))),
+                                Box::new(Expr::Atom(Prim::Name(String::from("Ack.bytes_acked")))),
+                            )),
+                        ),
+                        Expr::Sexp(
+                            Op::Mod,
+                            Box::new(Expr::Atom(Prim::Num(3))),
+                            Box::new(Expr::Sexp(
+                                Op::Div,
+                                Box::new(Expr::Atom(Prim::Num(2))),
+                                Box::new(Expr::Atom(Prim::Num(0))),
+                            )),
+                        ),
+                    ]
+                );
+            }
+            Ok((_, Err(me))) => panic!("{}", me),
+            Err(e) => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn bit_ops() {
+        for (src, op) in &[
+            ("&", Op::BitAnd),
+            ("bitand", Op::BitAnd),
+            ("&&", Op::And),
+            ("and", Op::And),
+            ("|", Op::BitOr),
+            ("bitor", Op::BitOr),
+            ("||", Op::Or),
+            ("or", Op::Or),
+            ("^", Op::Xor),
+            ("xor", Op::Xor),
+            ("<<", Op::Shl),
+            ("shl", Op::Shl),
+            ("<", Op::Lt),
+            (">>", Op::Shr),
+            ("shr", Op::Shr),
+            (">", Op::Gt),
+        ] {
+            let foo = format!("(when true ({} 12 2))", src);
+            match super::event(CompleteByteSlice(foo.as_bytes())) {
+                Ok((_, Ok(me))) => assert_eq!(
+                    me.body,
+                    vec![Expr::Sexp(
+                        *op,
+                        Box::new(Expr::Atom(Prim::Num(12))),
+                        Box::new(Expr::Atom(Prim::Num(2))),
+                    )],
+                    "{}",
+                    src
+                ),
+                Ok((_, Err(me))) => panic!("{}: {}", src, me),
+                Err(e) => panic!("{}: {:?}", src, e),
+            }
+        }
+    }
+
+    #[test]
+    fn named_programs() {
+        let foo = b"
+            # slow start
+            (program slow_start
+                (def (Report (volatile acked 0)))
+                (when true (:= Report.acked Ack.bytes_acked) (report))
+            )
+            (program cong_avoid
+                (def (Report (volatile rtt 0) (volatile acked 0)))
+                (when true (:= Report.rtt Flow.rtt_sample_us) (fallthrough))
+                (when (> Micros 1000) (report))
+            )
+        ";
+
+        let progs = Prog::new_named_with_scopes(foo).unwrap();
+        let names: Vec<_> = progs.iter().map(|(n, _, _)| n.as_str()).collect();
+        assert_eq!(names, vec!["slow_start", "cong_avoid"]);
+        let (_, p, sc) = &progs[1];
+        assert_eq!(p.0.len(), 2);
+        // each program has its own scope
+        assert_ne!(progs[0].2.program_uid, sc.program_uid);
+        assert_eq!(progs[0].2.report_names(), vec!["Report.acked"]);
+        assert_eq!(sc.report_names(), vec!["Report.rtt", "Report.acked"]);
+
+        // a single program is named ""
+        let progs = Prog::new_named_with_scopes(b"(def (Report.foo 0)) (when true (report))");
+        assert_eq!(progs.unwrap()[0].0, "");
+
+        for src in &[
+            "(program a (def (Report.foo 0)) (when true (report))) \
+             (program a (def (Report.foo 0)) (when true (report)))",
+            "(program a (def (Report.foo 0)) (when true (report))) (when true (report))",
+            "(program a (def (Report.foo 0)))",
+            "(program (def (Report.foo 0)) (when true (report)))",
+        ] {
+            assert!(
+                Prog::new_named_with_scopes(src.as_bytes()).is_err(),
+                "{}",
+                src
+            );
+        }
+    }
+
+    #[test]
+    fn comparisons() {
+        for (src, op) in &[
+            ("==", Op::Equiv),
+            ("eq", Op::Equiv),
+            ("!=", Op::Ne),
+            ("ne", Op::Ne),
+            ("<", Op::Lt),
+            ("lt", Op::Lt),
+            ("<=", Op::Le),
+            ("le", Op::Le),
+            (">", Op::Gt),
+            ("gt", Op::Gt),
+            (">=", Op::Ge),
+            ("ge", Op::Ge),
+        ] {
+            let foo = format!("(when true ({} 12 2))", src);
+            match super::event(CompleteByteSlice(foo.as_bytes())) {
+                Ok((_, Ok(me))) => assert_eq!(
+                    me.body,
+                    vec![Expr::Sexp(
+                        *op,
+                        Box::new(Expr::Atom(Prim::Num(12))),
+                        Box::new(Expr::Atom(Prim::Num(2))),
+                    )],
+                    "{}",
+                    src
+                ),
+                Ok((_, Err(me))) => panic!("{}: {}", src, me),
+                Err(e) => panic!("{}: {:?}", src, e),
+            }
+        }
+    }
+
     #[test]
     fn event() {
         let foo = b"
@@ -460,7 +851,6 @@ mod tests {
                                 Box::new(Expr::Atom(Prim::Num(1))),
                             )),
                         ),
-                        Expr::None,
                         Expr::Sexp(
                             Op::Bind,
                             Box::new(Expr::Atom(Prim::Name(String::from("foo")))),
@@ -491,6 +881,100 @@ mod tests {
         );
     }
 
+    #[test]
+    fn comments() {
+        let foo = b"; a comment before the definitions
+            #| and a block
+               comment |#
+            (def ; within them
+                (Report #| here |# (volatile acked 0)) # and here
+            )
+            (when ; mid-expression
+                true #|before the body|# (:= Report.acked (+ Report.acked ; operand
+                    Ack.bytes_acked))
+            ) ; no newline at the end";
+        let (ast, sc) = Prog::new_with_scope(foo).unwrap();
+        assert_eq!(sc.initial_value("Report.acked"), Some(0));
+        assert_eq!(
+            ast,
+            Prog(vec![Event {
+                flag: Expr::Atom(Prim::Bool(true)),
+                body: vec![Expr::Sexp(
+                    Op::Bind,
+                    Box::new(Expr::Atom(Prim::Name(String::from("Report.acked")))),
+                    Box::new(Expr::Sexp(
+                        Op::Add,
+                        Box::new(Expr::Atom(Prim::Name(String::from("Report.acked")))),
+                        Box::new(Expr::Atom(Prim::Name(String::from("Ack.bytes_acked")))),
+                    )),
+                )],
+            }]),
+        );
+
+        let named = b"; phases
+            (program one (def (foo 0)) (when true (:= foo 1))) ; first
+            #| second |# (program two (def (foo 0)) (when true (:= foo 2)))";
+        let progs = Prog::new_named_with_scopes(named).unwrap();
+        assert_eq!(
+            progs.iter().map(|(n, _, _)| n.as_str()).collect::<Vec<_>>(),
+            vec!["one", "two"]
+        );
+
+        // the error is in the code after the comments
+        let bad = b"(def (foo 0)) ; fine
+            (when true (:= foo 1)) #| fine |#
+            (when true (:= foo ; oops
+            ";
+        let err = Prog::new_with_scope(bad).unwrap_err();
+        assert_eq!(err.0, "line 3, col 24: cannot parse '(:='");
+    }
+
+    #[test]
+    fn error_positions() {
+        let err = |src: &str| Prog::new_with_scope(src.as_bytes()).unwrap_err().0;
+        assert_eq!(
+            err("(def (Report (acked 0)))\n(when true\n    (reprot)\n)"),
+            "line 3, col 5: cannot parse '(reprot'"
+        );
+        assert_eq!(
+            err("(def (Report (acked 0)))\n(when true\n    (report)\n    (:= Report.acked)\n)"),
+            "line 4, col 5: cannot parse '(:='"
+        );
+        assert_eq!(
+            err("(def (foo 0))\n(when true\n    (:= foo 1)\n"),
+            "line 4, col 1: unexpected end of source"
+        );
+        assert_eq!(
+            err("(def (foo 0) (bar))"),
+            "line 1, col 14: cannot parse '(bar'"
+        );
+        assert_eq!(
+            err("(def (foo 0))"),
+            "line 1, col 14: unexpected end of source"
+        );
+        assert_eq!(
+            err("(def (foo 0))\n(when true\n  (:= foo (ewma 64 foo 1)))"),
+            "line 3, col 3: ewma shift must be from 1 to 63: 64"
+        );
+        // columns count characters, not bytes
+        assert_eq!(
+            err("(def (foo 0)) ; \u{e9}t\u{e9}\n(when true (:= foo 1)) ; \u{e9}\n\u{e9} (when"),
+            "line 3, col 1: cannot parse '\u{e9}'"
+        );
+
+        let err = |src: &str| Prog::new_named_with_scopes(src.as_bytes()).unwrap_err().0;
+        assert_eq!(
+            err("(program a (def (foo 0)) (when true (:= foo 1)))\n\
+                 (program b (def (foo 0)) (when true (:= foo 1) (fallthru)))"),
+            "line 2, col 48: cannot parse '(fallthru'"
+        );
+        assert_eq!(
+            err("(program a (def (foo 0)) (when true (:= foo 1)))\n\
+                 (program a (def (foo 0)) (when true (:= foo 2)))"),
+            "line 2, col 1: program \"a\" is defined twice"
+        );
+    }
+
     #[test]
     fn test_complete_failure_fails() {
         let foo = b"
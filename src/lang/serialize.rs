@@ -1,7 +1,8 @@
 use super::ast::Op;
-use super::datapath::{Bin, Event, Instr, Reg};
+use super::datapath::{Bin, Event, Instr, Reg, Type};
 use super::{Error, Result};
-use crate::serialize::u32_to_u8s;
+use crate::serialize::codes::{Code, Detailed};
+use crate::serialize::{read_varint, u32_from_u8s, u32_to_u8s, write_varint};
 
 /// Serialize a Bin to bytes for transfer to the datapath
 impl Bin {
@@ -17,6 +18,136 @@ impl Bin {
             .chain(ists)
             .collect()
     }
+
+    /// Parse `num_events` events and then `num_instrs` instructions serialized as by
+    /// `serialize`, e.g. in a userspace datapath. The serialization does not say what type each
+    /// register holds, so registers come back as described for `deserialize_reg`; serializing
+    /// the result gives the same bytes.
+    pub fn deserialize(buf: &[u8], num_events: u32, num_instrs: u32) -> Result<Self> {
+        let need = (u64::from(num_events) + u64::from(num_instrs)) * 16;
+        if buf.len() as u64 != need {
+            return Err(Error::from(format!(
+                "{} events and {} instructions take {} bytes, not {}",
+                num_events,
+                num_instrs,
+                need,
+                buf.len()
+            )));
+        }
+
+        let (events, instrs) = buf.split_at(num_events as usize * 16);
+        Ok(Bin {
+            events: events.chunks(16).map(deserialize_event).collect(),
+            instrs: instrs
+                .chunks(16)
+                .map(deserialize_instr)
+                .collect::<Result<_>>()?,
+        })
+    }
+
+    /// Serialize as `serialize` does, but with each number as a LEB128 varint rather than in 4
+    /// bytes: each event's fields, and each register's index or immediate. Nearly all of them
+    /// are below 128, so an instruction usually takes 7 bytes rather than 16.
+    pub fn serialize_varint(&self) -> Result<Vec<u8>> {
+        let mut buf = vec![];
+        for ev in &self.events {
+            for x in &[
+                ev.flag_idx,
+                ev.num_flag_instrs,
+                ev.body_idx,
+                ev.num_body_instrs,
+            ] {
+                write_varint(&mut buf, *x);
+            }
+        }
+        for instr in &self.instrs {
+            buf.push(serialize_op(instr.op));
+            for reg in &[&instr.res, &instr.left, &instr.right] {
+                let reg = serialize_reg(reg)?;
+                buf.push(reg[0]);
+                write_varint(&mut buf, u32_from_u8s(&reg[1..5]));
+            }
+        }
+
+        Ok(buf)
+    }
+
+    /// Parse `num_events` events and then `num_instrs` instructions serialized as by
+    /// `serialize_varint`. Registers come back as they do from `deserialize`.
+    pub fn deserialize_varint(buf: &[u8], num_events: u32, num_instrs: u32) -> Result<Self> {
+        let (bin, len) = Bin::deserialize_varint_prefix(buf, num_events, num_instrs)?;
+        if len != buf.len() {
+            return Err(Error::from(format!(
+                "{} events and {} instructions take {} bytes, not {}",
+                num_events,
+                num_instrs,
+                len,
+                buf.len()
+            )));
+        }
+
+        Ok(bin)
+    }
+
+    // Like `deserialize_varint`, for a program at the start of `buf` with something else after
+    // it, e.g. an install message's name table. Also return how many bytes the program took.
+    pub(crate) fn deserialize_varint_prefix(
+        buf: &[u8],
+        num_events: u32,
+        num_instrs: u32,
+    ) -> Result<(Self, usize)> {
+        let mut r = Varints { buf, at: 0 };
+        let events = (0..num_events)
+            .map(|_| {
+                Ok(Event {
+                    flag_idx: r.varint()?,
+                    num_flag_instrs: r.varint()?,
+                    body_idx: r.varint()?,
+                    num_body_instrs: r.varint()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        let instrs = (0..num_instrs)
+            .map(|_| {
+                with_shift(Instr {
+                    op: deserialize_op(r.byte()?)?,
+                    res: r.reg()?,
+                    left: r.reg()?,
+                    right: r.reg()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+
+        Ok((Bin { events, instrs }, r.at))
+    }
+}
+
+// Reads the numbers and registers of a program serialized with varints, in order.
+struct Varints<'a> {
+    buf: &'a [u8],
+    at: usize,
+}
+
+impl Varints<'_> {
+    fn byte(&mut self) -> Result<u8> {
+        let b = self.buf.get(self.at).copied();
+        self.at += 1;
+        b.ok_or_else(|| Error::from(String::from("program cut short")))
+    }
+
+    fn varint(&mut self) -> Result<u32> {
+        let (num, len) = read_varint(&self.buf[self.at.min(self.buf.len())..])
+            .ok_or_else(|| Error::from(format!("invalid varint at offset {}", self.at)))?;
+        self.at += len;
+        Ok(num)
+    }
+
+    // A register as its type and a varint, parsed as `deserialize_reg` does the fixed form.
+    fn reg(&mut self) -> Result<Reg> {
+        let mut fixed = [self.byte()?, 0, 0, 0, 0];
+        u32_to_u8s(&mut fixed[1..5], self.varint()?);
+        deserialize_reg(&fixed)
+    }
 }
 /// pub struct Event {
 ///     flag_idx: u32,
@@ -35,6 +166,15 @@ impl Bin {
 /// | flag instr idx | num flag instrs | body instr idx | num body instrs |
 /// | u32            | u32             | u32            | u32             |
 /// |----------------|-----------------|----------------|-----------------|
+fn deserialize_event(buf: &[u8]) -> Event {
+    Event {
+        flag_idx: u32_from_u8s(&buf[0..4]),
+        num_flag_instrs: u32_from_u8s(&buf[4..8]),
+        body_idx: u32_from_u8s(&buf[8..12]),
+        num_body_instrs: u32_from_u8s(&buf[12..16]),
+    }
+}
+
 impl IntoIterator for Event {
     type Item = Result<u8>;
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
@@ -79,7 +219,28 @@ impl IntoIterator for Instr {
     }
 }
 
-fn serialize_op(o: Op) -> u8 {
+fn deserialize_instr(buf: &[u8]) -> Result<Instr> {
+    with_shift(Instr {
+        op: deserialize_op(buf[0])?,
+        res: deserialize_reg(&buf[1..6])?,
+        left: deserialize_reg(&buf[6..11])?,
+        right: deserialize_reg(&buf[11..16])?,
+    })
+}
+
+// An ewma's shift is not in its opcode but its left operand, so put it back in its `Op`.
+fn with_shift(mut instr: Instr) -> Result<Instr> {
+    if let Op::EwmaShift(_) = instr.op {
+        match instr.left {
+            Reg::ImmNum(shift @ 1..=63) => instr.op = Op::EwmaShift(shift as u8),
+            _ => return Err(Error::from(format!("invalid ewma shift: {:?}", instr.left))),
+        }
+    }
+
+    Ok(instr)
+}
+
+pub(crate) fn serialize_op(o: Op) -> u8 {
     match o {
         Op::Add => 0,
         Op::And => unreachable!(),
@@ -88,114 +249,221 @@ fn serialize_op(o: Op) -> u8 {
         Op::Div => 3,
         Op::Equiv => 4,
         Op::Ewma => 5,
+        Op::Ge => unreachable!(),
         Op::Gt => 6,
         Op::If => 7,
+        Op::Le => unreachable!(),
         Op::Lt => 8,
         Op::Max => 9,
         Op::MaxWrap => 10,
         Op::Min => 11,
         Op::Mul => 12,
+        Op::Ne => unreachable!(),
         Op::NotIf => 13,
         Op::Or => unreachable!(),
         Op::Sub => 14,
+        Op::Mod => 15,
+        Op::BitAnd => 16,
+        Op::BitOr => 17,
+        Op::Xor => 18,
+        Op::Shl => 19,
+        Op::Shr => 20,
+        Op::SGt => 21,
+        Op::SLt => 22,
+        Op::EwmaShift(_) => 23,
+        Op::SSub => unreachable!(),
     }
 }
 
+fn deserialize_op(o: u8) -> Result<Op> {
+    Ok(match o {
+        0 => Op::Add,
+        1 => Op::Bind,
+        2 => Op::Def,
+        3 => Op::Div,
+        4 => Op::Equiv,
+        5 => Op::Ewma,
+        6 => Op::Gt,
+        7 => Op::If,
+        8 => Op::Lt,
+        9 => Op::Max,
+        10 => Op::MaxWrap,
+        11 => Op::Min,
+        12 => Op::Mul,
+        13 => Op::NotIf,
+        14 => Op::Sub,
+        15 => Op::Mod,
+        16 => Op::BitAnd,
+        17 => Op::BitOr,
+        18 => Op::Xor,
+        19 => Op::Shl,
+        20 => Op::Shr,
+        21 => Op::SGt,
+        22 => Op::SLt,
+        // the shift is the instruction's left operand
+        23 => Op::EwmaShift(0),
+        _ => {
+            return Err(Error::from(Detailed(
+                Code::UnsupportedOp,
+                format!("unknown opcode: {:?}", o),
+            )))
+        }
+    })
+}
+
 impl IntoIterator for Reg {
     type Item = Result<u8>;
     type IntoIter = ::std::vec::IntoIter<Result<u8>>;
 
     fn into_iter(self) -> Self::IntoIter {
-        let reg = match self {
-            Reg::Control(i, _, is_volatile) => {
-                if i > 15 {
-                    Err(Error::from(format!(
-                        "Control Register index too big (max 15): {:?}",
-                        i
-                    )))
-                } else {
-                    // VOLATILE_CONTROL_REG 8
-                    // NONVOLATILE_CONTROL_REG 0
-                    Ok((if is_volatile { 8u8 } else { 0u8 }, u32::from(i)))
-                }
+        serialize_reg(&self)
+            .map(|v| v.iter().map(|u| Ok(*u)).collect())
+            .unwrap_or_else(|e| vec![Err(e)])
+            .into_iter()
+    }
+}
+
+/// Serialize a register as its type and index, 5 bytes in all, without allocating as its
+/// `IntoIterator` does.
+pub(crate) fn serialize_reg(reg: &Reg) -> Result<[u8; 5]> {
+    let reg = match *reg {
+        Reg::Control(i, _, is_volatile) => {
+            if i > 15 {
+                Err(Error::from(format!(
+                    "Control Register index too big (max 15): {:?}",
+                    i
+                )))
+            } else {
+                // VOLATILE_CONTROL_REG 8
+                // NONVOLATILE_CONTROL_REG 0
+                Ok((if is_volatile { 8u8 } else { 0u8 }, u32::from(i)))
             }
-            Reg::ImmBool(bl) => Ok((1u8, bl as u32)),
-            Reg::ImmNum(num) => {
-                if num == u64::max_value() || num < (1 << 31) {
-                    Ok((1u8, num as u32))
-                } else {
-                    Err(Error::from(format!(
-                        "ImmNum too big (max 32 bits): {:?}",
-                        num
-                    )))
-                }
+        }
+        Reg::ImmBool(bl) => Ok((1u8, bl as u32)),
+        // the datapath takes all ones as -1, and otherwise zero-extends
+        Reg::ImmInt(num) => {
+            if num == -1 || (0..(1 << 31)).contains(&num) {
+                Ok((1u8, num as u32))
+            } else {
+                Err(Error::from(format!(
+                    "ImmInt out of range (-1, or at most 31 bits): {:?}",
+                    num
+                )))
             }
-            Reg::Implicit(i, _) => {
-                if i > 5 {
-                    Err(Error::from(format!(
-                        "Implicit Register index too big (max 5): {:?}",
-                        i
-                    )))
-                } else {
-                    Ok((2u8, u32::from(i)))
-                }
+        }
+        Reg::ImmNum(num) => {
+            if num == u64::max_value() || num < (1 << 31) {
+                Ok((1u8, num as u32))
+            } else {
+                Err(Error::from(format!(
+                    "ImmNum too big (max 32 bits): {:?}",
+                    num
+                )))
             }
-            Reg::Local(i, _) => {
-                if i > 5 {
-                    Err(Error::from(format!(
-                        "Local Register index too big (max 5): {:?}",
-                        i
-                    )))
-                } else {
-                    Ok((3u8, u32::from(i)))
-                }
+        }
+        Reg::Implicit(i, _) => {
+            if i > 5 {
+                Err(Error::from(format!(
+                    "Implicit Register index too big (max 5): {:?}",
+                    i
+                )))
+            } else {
+                Ok((2u8, u32::from(i)))
             }
-            Reg::Primitive(i, _) => {
-                if i > 15 {
-                    Err(Error::from(format!(
-                        "Primitive Register index too big (max 15): {:?}",
-                        i
-                    )))
-                } else {
-                    Ok((4u8, u32::from(i)))
-                }
+        }
+        Reg::Local(i, _) => {
+            if i > 5 {
+                Err(Error::from(format!(
+                    "Local Register index too big (max 5): {:?}",
+                    i
+                )))
+            } else {
+                Ok((3u8, u32::from(i)))
             }
-            Reg::Report(i, _, is_volatile) => {
-                if i > 15 {
-                    Err(Error::from(format!(
-                        "Report Register index too big (max 15): {:?}",
-                        i
-                    )))
-                } else {
-                    // in libccp:
-                    // VOLATILE_REPORT_REG is type #5
-                    // NONVOLATILE_REPORT_REG is typ #6
-                    // so, here, we differentiate between variables marked by the volatile keyword.
-                    Ok((if is_volatile { 5u8 } else { 6u8 }, u32::from(i)))
-                }
+        }
+        Reg::Primitive(i, _) => {
+            if i > 15 {
+                Err(Error::from(format!(
+                    "Primitive Register index too big (max 15): {:?}",
+                    i
+                )))
+            } else {
+                Ok((4u8, u32::from(i)))
             }
-            Reg::Tmp(i, _) => {
-                if i > 15 {
-                    Err(Error::from(format!(
-                        "Tmp Register index too big (max 15): {:?}",
-                        i
-                    )))
-                } else {
-                    Ok((7u8, u32::from(i)))
-                }
+        }
+        Reg::Report(i, _, is_volatile) => {
+            if i > 15 {
+                Err(Error::from(format!(
+                    "Report Register index too big (max 15): {:?}",
+                    i
+                )))
+            } else {
+                // in libccp:
+                // VOLATILE_REPORT_REG is type #5
+                // NONVOLATILE_REPORT_REG is typ #6
+                // so, here, we differentiate between variables marked by the volatile keyword.
+                Ok((if is_volatile { 5u8 } else { 6u8 }, u32::from(i)))
             }
-            Reg::None => unreachable!(),
-        };
+        }
+        Reg::Tmp(i, _) => {
+            if i > 15 {
+                Err(Error::from(format!(
+                    "Tmp Register index too big (max 15): {:?}",
+                    i
+                )))
+            } else {
+                Ok((7u8, u32::from(i)))
+            }
+        }
+        Reg::None => unreachable!(),
+    };
 
-        reg.map(|(typ, idx)| {
-            let v = &mut [typ, 0, 0, 0, 0];
-            u32_to_u8s(&mut v[1..5], idx);
-            v.iter()
-                .map(|u| Ok(*u))
-                .collect::<Vec<Result<u8>>>()
-                .into_iter()
-        })
-        .unwrap_or_else(|e| vec![Err(e)].into_iter())
+    reg.map(|(typ, idx)| {
+        let mut v = [typ, 0, 0, 0, 0];
+        u32_to_u8s(&mut v[1..5], idx);
+        v
+    })
+}
+
+/// Parse a register serialized as above. The serialization does not say what type the register
+/// holds, so it comes back as a number, and an immediate comes back as `Reg::ImmNum`.
+pub(crate) fn deserialize_reg(buf: &[u8]) -> Result<Reg> {
+    if buf.len() < 5 {
+        return Err(Error::from(format!(
+            "register too short: {} bytes",
+            buf.len()
+        )));
+    }
+
+    let idx = u32_from_u8s(&buf[1..5]);
+    if buf[0] == 1 {
+        return Ok(Reg::ImmNum(if idx == u32::MAX {
+            u64::MAX
+        } else {
+            u64::from(idx)
+        }));
+    }
+
+    let i = match idx {
+        0..=15 => idx as u8,
+        _ => {
+            return Err(Error::from(Detailed(
+                Code::RegisterOutOfRange,
+                format!("register index too big (max 15): {:?}", idx),
+            )))
+        }
+    };
+    let num = Type::Num(None);
+    match buf[0] {
+        0 => Ok(Reg::Control(i, num, false)),
+        8 => Ok(Reg::Control(i, num, true)),
+        2 => Ok(Reg::Implicit(i, num)),
+        3 => Ok(Reg::Local(i, num)),
+        4 => Ok(Reg::Primitive(i, num)),
+        5 => Ok(Reg::Report(i, num, true)),
+        6 => Ok(Reg::Report(i, num, false)),
+        7 => Ok(Reg::Tmp(i, num)),
+        t => Err(Error::from(format!("unknown register type: {:?}", t))),
     }
 }
 
@@ -259,6 +527,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn do_ser_initial_values() {
+        let (b, _) = lang::compile(
+            b"(def (gain 3) (Report (volatile minrtt +infinity) (volatile delta -1)))
+            (when true (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us)))",
+            &[],
+        )
+        .expect("compile");
+
+        let v = b.serialize().expect("serialize");
+        assert_eq!(
+            &v[16..16 + 3 * 16],
+            &[
+                // def reg::report(1) <- -1
+                0x02, 0x05, 0x01, 0x00, 0x00, 0x00, 0x05, 0x01, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff,
+                0xff, 0xff, // def reg::report(0) <- +infinity
+                0x02, 0x05, 0x00, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00, 0x00, 0x01, 0xff, 0xff,
+                0xff, 0xff, // def reg::control(0) <- 3
+                0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01, 0x03, 0x00,
+                0x00, 0x00,
+            ][..]
+        );
+
+        let parsed = Bin::deserialize(&v, 1, b.instrs.len() as u32).expect("deserialize");
+        assert_eq!(parsed.instrs[1].right, Reg::ImmNum(u64::MAX));
+        assert_eq!(parsed.instrs[2].right, Reg::ImmNum(3));
+    }
+
     #[test]
     fn do_ser_max_imm() {
         // make an InstrBytes to serialize
@@ -282,6 +578,55 @@ mod tests {
         );
     }
 
+    #[test]
+    fn ser_ops() {
+        for opcode in 0..24 {
+            let op = super::deserialize_op(opcode).expect("known opcode");
+            assert_eq!(super::serialize_op(op), opcode);
+        }
+        assert_eq!(super::serialize_op(Op::Div), 3);
+        assert_eq!(super::serialize_op(Op::Mod), 15);
+        assert_eq!(super::serialize_op(Op::BitAnd), 16);
+        assert_eq!(super::serialize_op(Op::Shr), 20);
+        assert_eq!(super::serialize_op(Op::SLt), 22);
+        assert_eq!(super::serialize_op(Op::EwmaShift(3)), 23);
+        assert!(super::deserialize_op(24).is_err());
+    }
+
+    #[test]
+    fn do_ser_imm_int() {
+        assert_eq!(
+            super::serialize_reg(&Reg::ImmInt(-1)).expect("serialize"),
+            [0x01, 0xff, 0xff, 0xff, 0xff]
+        );
+        assert_eq!(
+            super::serialize_reg(&Reg::ImmInt(5)).expect("serialize"),
+            [0x01, 0x05, 0x00, 0x00, 0x00]
+        );
+        // which the datapath would zero-extend, not sign-extend
+        assert!(super::serialize_reg(&Reg::ImmInt(-2)).is_err());
+        assert!(super::serialize_reg(&Reg::ImmInt(1 << 31)).is_err());
+    }
+
+    #[test]
+    fn do_ser_shift() {
+        let (bin, _) = lang::compile(
+            b"(def (Report (volatile ece 0)))
+            (when true
+                (:= Report.ece (& (>> Ack.ecn_packets 2) 1))
+            )",
+            &[],
+        )
+        .expect("compile");
+        let v = bin.serialize().expect("serialize");
+        let ops: Vec<u8> = v[16 * bin.events.len()..]
+            .chunks(16)
+            .map(|instr| instr[0])
+            .collect();
+        // def, the event flag, shr, bitand, bind
+        assert_eq!(ops, vec![2, 1, 20, 16, 1]);
+    }
+
     #[test]
     fn do_ser_def_max_imm() {
         // make a Bin to serialize
@@ -58,14 +58,20 @@
 //!     }
 //! }
 //! impl Flow for MyCongestionControlAlgorithm {
-//!     fn on_report(&mut self, sock_id: u32, m: Report) {
+//!     fn on_report(&mut self, sock_id: u64, m: Report) {
 //!         println!("minrtt: {:?}", m.get_field("Report.minrtt", &self.0).unwrap());
 //!     }
 //! }
 //! ```
 
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+#[cfg(feature = "serde")]
+#[macro_use]
+mod serde_impls;
 
 pub mod ipc;
 pub mod lang;
@@ -79,76 +85,203 @@ pub use portus_export::register_ccp_alg;
 
 use crate::ipc::BackendSender;
 use crate::ipc::Ipc;
-use crate::lang::{Reg, Scope};
+use crate::ipc::Priority;
+use crate::ipc::SendStatus;
+use crate::lang::{Bin, Reg, Scope, Type};
+use crate::serialize::measure::FieldType;
+use tracing::{debug, warn};
 
 /// A collection of methods to interact with the datapath.
 pub trait DatapathTrait {
-    fn get_sock_id(&self) -> u32;
-    /// Tell datapath to use a preinstalled program.
+    fn get_sock_id(&self) -> u64;
+    /// Tell datapath to use a preinstalled program. Its scope is the one to read the program's
+    /// reports with, until the flow switches programs again.
     fn set_program(
         &mut self,
         program_name: &'static str,
         fields: Option<&[(&str, u32)]>,
     ) -> Result<Scope>;
     /// Update the value of a register in an already-installed fold function.
-    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()>;
+    ///
+    /// Returns whether the update was sent, or is queued to be sent (see
+    /// [`ipc::SendStatus`](ipc/enum.SendStatus.html)).
+    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<SendStatus>;
 }
 
 /// A collection of methods to interact with the datapath.
+///
+/// This is `Send` and `Sync` if the IPC socket is, so flows can hand it to worker threads.
 #[derive(Clone)]
 pub struct Datapath<T: Ipc> {
-    sock_id: u32,
+    sock_id: u64,
     sender: BackendSender<T>,
-    programs: Rc<HashMap<String, Scope>>,
+    programs: Arc<HashMap<String, Program>>,
+    priority: Priority,
+    capabilities: Option<serialize::capabilities::Msg>,
+    // the minor version of the messages the datapath speaks, if it said before this flow was
+    // created
+    minor: Option<u32>,
+    acks: Option<Arc<AwaitingAcks>>,
+    torn_down: Option<Arc<TornDown>>,
+    scratch: Scratch,
 }
 
-impl<T: Ipc> DatapathTrait for Datapath<T> {
-    fn get_sock_id(&self) -> u32 {
-        self.sock_id
+// A datapath program, compiled.
+struct Program {
+    scope: Scope,
+    bin: Bin,
+}
+
+// The programs a datapath's flows have switched to, which it has yet to acknowledge, by socket id,
+// and when each was set. Shared by the flows and the execution loop, which gives up on them.
+#[derive(Default)]
+struct AwaitingAcks(Mutex<HashMap<u64, (u32, Instant)>>);
+
+impl AwaitingAcks {
+    fn expect(&self, sock_id: u64, program_uid: u32) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(sock_id, (program_uid, Instant::now()));
     }
 
-    fn set_program(
-        &mut self,
-        program_name: &'static str,
+    fn ack(&self, sock_id: u64, program_uid: u32) {
+        let mut awaiting = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        if matches!(awaiting.get(&sock_id), Some((uid, _)) if *uid == program_uid) {
+            awaiting.remove(&sock_id);
+        }
+    }
+
+    // Stop waiting for the acknowledgements set more than `timeout` ago, and return them.
+    fn expired(&self, timeout: Duration) -> Vec<(u64, u32)> {
+        let mut awaiting = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let expired: Vec<_> = awaiting
+            .iter()
+            .filter(|(_, (_, at))| at.elapsed() >= timeout)
+            .map(|(sid, (uid, _))| (*sid, *uid))
+            .collect();
+        for (sid, _) in &expired {
+            awaiting.remove(sid);
+        }
+
+        expired
+    }
+}
+
+// What a `Datapath` builds its messages in, kept from message to message so that, once it has
+// grown to fit them, sending does not allocate. Each clone of a `Datapath` gets its own.
+#[derive(Default)]
+struct Scratch(Mutex<ScratchBufs>);
+
+#[derive(Default)]
+struct ScratchBufs {
+    msg: Vec<u8>,
+    fields: Vec<(Reg, u64)>,
+    widths: Vec<serialize::update_field::Width>,
+}
+
+impl Clone for Scratch {
+    fn clone(&self) -> Self {
+        Scratch::default()
+    }
+}
+
+// The flows of a datapath which have asked to be torn down, by socket id. Shared by the flows and
+// the execution loop, which closes them once the call they asked from returns.
+#[derive(Default)]
+struct TornDown(Mutex<Vec<u64>>);
+
+impl TornDown {
+    fn push(&self, sock_id: u64) {
+        self.0
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(sock_id);
+    }
+
+    fn take(&self) -> Vec<u64> {
+        std::mem::take(&mut *self.0.lock().unwrap_or_else(|e| e.into_inner()))
+    }
+}
+
+impl<T: Ipc> Datapath<T> {
+    /// Send this flow's control messages at `priority` rather than the default,
+    /// `Priority::Urgent`, so that they go out ahead of other queued messages when the `Backend`
+    /// has a send queue.
+    pub fn set_priority(&mut self, priority: Priority) {
+        self.priority = priority;
+    }
+
+    /// What the datapath advertised it can run, if it did so before this flow was created.
+    /// `set_program` checks programs against this, and fails rather than switching to a program
+    /// the datapath cannot run.
+    pub fn capabilities(&self) -> Option<&serialize::capabilities::Msg> {
+        self.capabilities.as_ref()
+    }
+
+    /// Start a batch of control messages for this flow, to send together with `send_batch`.
+    pub fn batch(&self) -> Batch<'_, T> {
+        Batch {
+            dp: self,
+            msgs: vec![],
+            program: None,
+        }
+    }
+
+    /// Send the messages in `batch`, with as few system calls as the IPC socket allows (see
+    /// `IpcSend::send_many`). The datapath still gets them as separate messages, in the order
+    /// they were added.
+    ///
+    /// If a message fails to send, the messages before it were sent, and neither it nor any after
+    /// it were. The error is that message's.
+    pub fn send_batch(&self, batch: Batch<'_, T>) -> Result<()> {
+        let msgs: Vec<&[u8]> = batch.msgs.iter().map(|m| &m[..]).collect();
+        let (sent, res) = self.sender.send_msgs_with_priority(&msgs, self.priority);
+        if let (Some(acks), Some((i, uid))) = (&self.acks, batch.program) {
+            if i < sent {
+                acks.expect(self.sock_id, uid);
+            }
+        }
+        if let Err(ref e) = res {
+            debug!(sid = self.sock_id, sent, batch_len = msgs.len(), err = ?e, "batched send failed");
+        }
+
+        res
+    }
+
+    // Build the message `set_program` sends, and hand it to `f`, with the program's scope.
+    fn with_set_program_msg<R>(
+        &self,
+        program_name: &str,
         fields: Option<&[(&str, u32)]>,
-    ) -> Result<Scope> {
+        f: impl FnOnce(&[u8]) -> Result<R>,
+    ) -> Result<(R, Scope)> {
         // if the program with this key exists, return it; otherwise return nothing
         match self.programs.get(program_name) {
-            Some(sc) => {
+            Some(Program { scope: sc, bin }) => {
+                if let Some(ref caps) = self.capabilities {
+                    caps.check(program_name, bin, sc)?;
+                }
+
+                let mut scratch = self.scratch.0.lock().unwrap_or_else(|e| e.into_inner());
+                let ScratchBufs {
+                    msg: buf,
+                    fields: regs,
+                    ..
+                } = &mut *scratch;
+
                 // apply optional updates to values of registers in this scope
-                let fields: Vec<(Reg, u64)> = fields
-                    .unwrap_or_else(|| &[])
-                    .iter()
-                    .map(|&(reg_name, new_value)| {
-                        if reg_name.starts_with("__") {
-                            return Err(Error(format!(
-                                "Cannot update reserved field: {:?}",
-                                reg_name
-                            )));
-                        }
-
-                        sc.get(reg_name)
-                            .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))
-                            .and_then(|reg| match *reg {
-                                Reg::Control(idx, ref t, v) => {
-                                    Ok((Reg::Control(idx, t.clone(), v), u64::from(new_value)))
-                                }
-                                Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                                    Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                                }
-                                _ => Err(Error(format!("Cannot update field: {:?}", reg_name))),
-                            })
-                    })
-                    .collect::<Result<_>>()?;
-                let msg = serialize::changeprog::Msg {
-                    sid: self.sock_id,
-                    program_uid: sc.program_uid,
-                    num_fields: fields.len() as u32,
-                    fields,
-                };
-                let buf = serialize::serialize(&msg)?;
-                self.sender.send_msg(&buf[..])?;
-                Ok(sc.clone())
+                resolve_fields(sc, fields.unwrap_or(&[]), regs)?;
+                let msg = serialize::changeprog::Msg::new(
+                    self.sock_id,
+                    sc.program_uid,
+                    std::mem::take(regs),
+                );
+                buf.clear();
+                let res = serialize::serialize_into(&msg, buf);
+                *regs = msg.fields;
+                res?;
+                Ok((f(&buf[..])?, sc.clone()))
             }
             _ => Err(Error(format!(
                 "Map does not contain datapath program with key: {:?}",
@@ -157,80 +290,327 @@ impl<T: Ipc> DatapathTrait for Datapath<T> {
         }
     }
 
-    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<()> {
-        let fields: Vec<(Reg, u64)> = update
-            .iter()
-            .map(|&(reg_name, new_value)| {
-                if reg_name.starts_with("__") {
-                    return Err(Error(format!(
-                        "Cannot update reserved field: {:?}",
-                        reg_name
-                    )));
-                }
+    /// Stop the datapath running this flow's program, the one `sc` is the scope of, or whichever
+    /// it is running if `sc` is `None`, e.g. before switching the flow back to the datapath's
+    /// own congestion control. This is harmless if the flow is not running that program.
+    pub fn uninstall_program(&self, sc: Option<&Scope>) -> Result<SendStatus> {
+        let buf = self.uninstall_program_msg(sc)?;
+        self.sender.send_msg_with_priority(&buf[..], self.priority)
+    }
 
-                sc.get(reg_name)
-                    .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))
-                    .and_then(|reg| match *reg {
-                        Reg::Control(idx, ref t, v) => {
-                            Ok((Reg::Control(idx, t.clone(), v), u64::from(new_value)))
-                        }
-                        Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
-                            Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
-                        }
-                        _ => Err(Error(format!("Cannot update field: {:?}", reg_name))),
-                    })
-            })
-            .collect::<Result<_>>()?;
+    // The message `uninstall_program` sends.
+    fn uninstall_program_msg(&self, sc: Option<&Scope>) -> Result<Vec<u8>> {
+        serialize::serialize(&serialize::uninstall::Msg {
+            sid: self.sock_id,
+            program_uid: sc.map_or(serialize::uninstall::ANY_PROGRAM, |sc| sc.program_uid),
+        })
+    }
 
-        let msg = serialize::update_field::Msg {
+    /// Tell the datapath to stop CCP controlling this flow, e.g. to fall back to its own
+    /// congestion control after repeated errors. `reason` is in the algorithm's own numbering.
+    ///
+    /// Once the call this is made from returns, the flow is closed with `Flow::close`, and gets
+    /// no more reports. If the message fails to send, the flow carries on.
+    pub fn teardown(&self, reason: u32) -> Result<SendStatus> {
+        let buf = serialize::serialize(&serialize::teardown::Msg {
             sid: self.sock_id,
-            num_fields: fields.len() as u8,
+            reason,
+        })?;
+        let status = self
+            .sender
+            .send_msg_with_priority(&buf[..], self.priority)?;
+        if let Some(ref torn_down) = self.torn_down {
+            torn_down.push(self.sock_id);
+        }
+
+        Ok(status)
+    }
+
+    /// Like `DatapathTrait::update_field`, sending each value in 4 bytes rather than 8 if the
+    /// datapath speaks a version of the messages with field widths (see
+    /// `serialize::update_field::WIDTHS`), which saves 3 bytes a field. Otherwise it sends the
+    /// same message as `update_field`.
+    pub fn update_field_u32(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<SendStatus> {
+        let narrow = matches!(self.minor, Some(m) if m >= serialize::update_field::WIDTHS_MINOR);
+        self.with_update_field_msg(sc, update, narrow, |buf| {
+            self.sender.send_msg_with_priority(buf, self.priority)
+        })
+    }
+
+    // Build the message `update_field` sends, with widths if `narrow`, and hand it to `f`.
+    fn with_update_field_msg<R>(
+        &self,
+        sc: &Scope,
+        update: &[(&str, u32)],
+        narrow: bool,
+        f: impl FnOnce(&[u8]) -> Result<R>,
+    ) -> Result<R> {
+        let mut scratch = self.scratch.0.lock().unwrap_or_else(|e| e.into_inner());
+        let ScratchBufs {
+            msg: buf,
             fields,
+            widths,
+        } = &mut *scratch;
+        resolve_fields(sc, update, fields)?;
+        widths.clear();
+        widths.resize(fields.len(), serialize::update_field::Width::U32);
+        let msg = serialize::update_field::Msg {
+            widths: if narrow {
+                Some(std::mem::take(widths))
+            } else {
+                None
+            },
+            ..serialize::update_field::Msg::new(self.sock_id, std::mem::take(fields))
         };
+        buf.clear();
+        let res = serialize::serialize_into(&msg, buf);
+        *fields = msg.fields;
+        if let Some(w) = msg.widths {
+            *widths = w;
+        }
+        res?;
+        f(&buf[..])
+    }
+}
+
+// The registers in `sc` to set for each (name, value) in `update`, in place of what was in
+// `fields`.
+fn resolve_fields(sc: &Scope, update: &[(&str, u32)], fields: &mut Vec<(Reg, u64)>) -> Result<()> {
+    fields.clear();
+    for &(reg_name, new_value) in update {
+        fields.push(resolve_field(sc, reg_name, new_value)?);
+    }
+
+    Ok(())
+}
+
+// The register in `sc` to set to `new_value` for `reg_name`.
+fn resolve_field(sc: &Scope, reg_name: &str, new_value: u32) -> Result<(Reg, u64)> {
+    if reg_name.starts_with("__") {
+        return Err(Error(format!(
+            "Cannot update reserved field: {:?}",
+            reg_name
+        )));
+    }
+
+    sc.get(reg_name)
+        .ok_or_else(|| Error(format!("Unknown field: {:?}", reg_name)))
+        .and_then(|reg| match *reg {
+            Reg::Control(idx, ref t, v) => {
+                Ok((Reg::Control(idx, t.clone(), v), u64::from(new_value)))
+            }
+            Reg::Implicit(idx, ref t) if idx == 4 || idx == 5 => {
+                Ok((Reg::Implicit(idx, t.clone()), u64::from(new_value)))
+            }
+            _ => Err(Error(format!("Cannot update field: {:?}", reg_name))),
+        })
+}
 
-        let buf = serialize::serialize(&msg)?;
-        self.sender.send_msg(&buf[..])?;
+impl<T: Ipc> DatapathTrait for Datapath<T> {
+    fn get_sock_id(&self) -> u64 {
+        self.sock_id
+    }
+
+    fn set_program(
+        &mut self,
+        program_name: &'static str,
+        fields: Option<&[(&str, u32)]>,
+    ) -> Result<Scope> {
+        let (_, sc) = self.with_set_program_msg(program_name, fields, |buf| {
+            self.sender.send_msg_with_priority(buf, self.priority)
+        })?;
+        if let Some(ref acks) = self.acks {
+            acks.expect(self.sock_id, sc.program_uid);
+        }
+        Ok(sc)
+    }
+
+    fn update_field(&self, sc: &Scope, update: &[(&str, u32)]) -> Result<SendStatus> {
+        self.with_update_field_msg(sc, update, false, |buf| {
+            self.sender.send_msg_with_priority(buf, self.priority)
+        })
+    }
+}
+
+/// Control messages for one flow, collected with the same methods as `DatapathTrait` and sent
+/// together with [`Datapath::send_batch`](struct.Datapath.html#method.send_batch), e.g. to
+/// switch programs and set several fields with one system call.
+pub struct Batch<'a, T: Ipc> {
+    dp: &'a Datapath<T>,
+    msgs: Vec<Vec<u8>>,
+    // which message switches program, if one does, and to which
+    program: Option<(usize, u32)>,
+}
+
+impl<'a, T: Ipc> Batch<'a, T> {
+    /// Add a message switching to a preinstalled program, like `DatapathTrait::set_program`.
+    pub fn set_program(
+        &mut self,
+        program_name: &'static str,
+        fields: Option<&[(&str, u32)]>,
+    ) -> Result<Scope> {
+        let (buf, sc) = self
+            .dp
+            .with_set_program_msg(program_name, fields, |buf| Ok(buf.to_vec()))?;
+        self.program = Some((self.msgs.len(), sc.program_uid));
+        self.msgs.push(buf);
+        Ok(sc)
+    }
+
+    /// Add a message updating registers, like `DatapathTrait::update_field`.
+    pub fn update_field(&mut self, sc: &Scope, update: &[(&str, u32)]) -> Result<()> {
+        let buf = self
+            .dp
+            .with_update_field_msg(sc, update, false, |buf| Ok(buf.to_vec()))?;
+        self.msgs.push(buf);
+        Ok(())
+    }
+
+    /// Add a message stopping the flow's program, like `Datapath::uninstall_program`.
+    pub fn uninstall_program(&mut self, sc: Option<&Scope>) -> Result<()> {
+        let buf = self.dp.uninstall_program_msg(sc)?;
+        self.msgs.push(buf);
         Ok(())
     }
+
+    /// The number of messages in the batch.
+    pub fn len(&self) -> usize {
+        self.msgs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.msgs.is_empty()
+    }
 }
 
 /// The set of information passed by the datapath to CCP
 /// when a connection starts. It includes a unique 5-tuple (CCP socket id + source and destination
 /// IP and port), the initial congestion window (`init_cwnd`), and flow MSS.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DatapathInfo {
-    pub sock_id: u32,
+    pub sock_id: u64,
     pub init_cwnd: u32,
     pub mss: u32,
     pub src_ip: u32,
     pub src_port: u32,
     pub dst_ip: u32,
     pub dst_port: u32,
+    /// The datapath the flow belongs to, formatted as in `Report::from`. One CCP can serve
+    /// several datapaths over one socket, whose flows may have the same `sock_id`; the runtime
+    /// keeps them apart by this address, and each flow's `Datapath` sends to its own datapath.
+    pub peer: String,
+    /// The congestion control the flow's socket asked the datapath for, e.g. "cubic", if the
+    /// datapath said (see `serialize::create::Msg::cong_alg`). It is also how the runtime picks
+    /// the flow's algorithm, if CCP runs several.
+    pub requested_alg: Option<String>,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(DatapathInfo {
+    sock_id,
+    init_cwnd,
+    mss,
+    src_ip,
+    src_port,
+    dst_ip,
+    dst_port,
+    peer,
+    requested_alg,
+});
+
 /// Contains the values of the pre-defined Report struct from the fold function.
 /// Use `get_field` to query its values using the names defined in the fold function.
 pub struct Report {
     pub program_uid: u32,
     pub from: String,
     fields: Vec<u64>,
+    types: Option<Vec<FieldType>>,
+    timestamp: Option<u64>,
+    strings: Vec<String>,
 }
 
 impl Report {
     /// Uses the `Scope` returned by `lang::compile` (or `install`) to query
-    /// the `Report` for its values.
+    /// the `Report` for its values. A signed field comes back as its two's complement bits, and a
+    /// boolean as 0 or 1; `get_field_i64` and `get_field_bool` check the field's type instead. A
+    /// string comes back as its index among the report's strings; see `get_field_str`.
     pub fn get_field(&self, field: &str, sc: &Scope) -> Result<u64> {
+        self.report_reg(field, sc).map(|(idx, _)| self.fields[idx])
+    }
+
+    /// Like `get_field`, for a number which may be negative: one signed in `sc` (see
+    /// `lang::Type::Int`) or the report. It is an error, `FieldTypeError`, if `field` is not a
+    /// number in `sc` or the report, or is an unsigned number too large for an `i64`.
+    pub fn get_field_i64(&self, field: &str, sc: &Scope) -> Result<i64> {
+        let (idx, typ) = self.report_reg(field, sc)?;
+        let val = self.fields[idx];
+        match (typ, self.field_type(idx)) {
+            (Type::Int(_), None)
+            | (Type::Int(_), Some(FieldType::Int))
+            | (Type::Int(_), Some(FieldType::Num))
+            | (Type::Int(_), Some(FieldType::Num32))
+            | (Type::Num(_), Some(FieldType::Int)) => Ok(val as i64),
+            (Type::Num(_), None)
+            | (Type::Num(_), Some(FieldType::Num))
+            | (Type::Num(_), Some(FieldType::Num32)) => {
+                i64::try_from(val).map_err(|_| Error::from(FieldTypeError))
+            }
+            _ => Err(Error::from(FieldTypeError)),
+        }
+    }
+
+    /// Like `get_field`, for a boolean. It is an error, `FieldTypeError`, if `field` is not a
+    /// boolean in `sc` or the report.
+    pub fn get_field_bool(&self, field: &str, sc: &Scope) -> Result<bool> {
+        let (idx, typ) = self.report_reg(field, sc)?;
+        match (typ, self.field_type(idx)) {
+            (Type::Bool(_), None) | (Type::Bool(_), Some(FieldType::Bool)) => {
+                Ok(self.fields[idx] != 0)
+            }
+            _ => Err(Error::from(FieldTypeError)),
+        }
+    }
+
+    /// Like `get_field`, for a string the datapath set (see `lang::Type::Str`). It is an error,
+    /// `FieldTypeError`, if `field` is not a string in `sc` or the report.
+    pub fn get_field_str(&self, field: &str, sc: &Scope) -> Result<&str> {
+        let (idx, typ) = self.report_reg(field, sc)?;
+        match (typ, self.field_type(idx)) {
+            (Type::Str, Some(FieldType::Str)) => self
+                .strings
+                .get(self.fields[idx] as usize)
+                .map(String::as_str)
+                .ok_or_else(|| Error::from(FieldTypeError)),
+            _ => Err(Error::from(FieldTypeError)),
+        }
+    }
+
+    /// When the datapath generated the report, by the datapath's clock (e.g. nanoseconds since
+    /// boot), if it said. Compare it with other reports' timestamps, not with CCP's clock; for
+    /// when the report arrived, see `Flow::on_report_at`.
+    pub fn timestamp(&self) -> Option<u64> {
+        self.timestamp
+    }
+
+    /// The report's values in the order of their registers, each as `get_field` gives it, e.g.
+    /// to log a report without its program's `Scope`.
+    pub fn field_iter(&self) -> impl Iterator<Item = u64> + '_ {
+        self.fields.iter().copied()
+    }
+
+    // The index of `field` in the report, and its type in `sc`.
+    fn report_reg<'a>(&self, field: &str, sc: &'a Scope) -> Result<(usize, &'a Type)> {
         if sc.program_uid != self.program_uid {
             return Err(Error::from(StaleProgramError));
         }
 
         match sc.get(field) {
             Some(r) => match *r {
-                Reg::Report(idx, _, _) => {
+                Reg::Report(idx, ref typ, _) => {
                     if idx as usize >= self.fields.len() {
                         Err(Error::from(InvalidReportError))
                     } else {
-                        Ok(self.fields[idx as usize])
+                        Ok((idx as usize, typ))
                     }
                 }
                 _ => Err(Error::from(InvalidRegTypeError)),
@@ -238,6 +618,13 @@ impl Report {
             None => Err(Error::from(FieldNotFoundError)),
         }
     }
+
+    // The type the datapath sent the field at `idx` as, if it sent types.
+    fn field_type(&self, idx: usize) -> Option<FieldType> {
+        self.types
+            .as_ref()
+            .and_then(|types| types.get(idx).copied())
+    }
 }
 
 /// Implement this trait, [`portus::CongAlg`](./trait.CongAlg.html), and
@@ -251,25 +638,100 @@ impl Report {
 pub trait Flow {
     /// This callback specifies the algorithm's behavior when it receives a report
     /// of measurements from the datapath.
-    fn on_report(&mut self, sock_id: u32, m: Report);
+    fn on_report(&mut self, sock_id: u64, m: Report);
+
+    /// Like `on_report`, with when the report arrived at CCP's socket: the kernel's receive
+    /// timestamp where the socket has one (unix and netlink sockets on Linux), and otherwise when
+    /// portus read it from the socket. This is the wall-clock time, as the kernel stamps it.
+    /// The default implementation calls `on_report`.
+    fn on_report_at(&mut self, sock_id: u64, m: Report, _at: SystemTime) {
+        self.on_report(sock_id, m)
+    }
 
     /// Optionally specify what the algorithm should do when the flow ends,
     /// e.g., clean up any external resources.
     /// The default implementation does nothing.
     fn close(&mut self) {}
+
+    /// Like `close`, for a flow the datapath said has ended with a fin message (see
+    /// [`serialize::fin`](./serialize/fin/index.html)), with how many bytes and packets the flow
+    /// sent in all. A datapath which does not send fin messages closes flows with `close`.
+    /// The default implementation calls `close`.
+    fn close_with_counts(&mut self, _bytes: u64, _packets: u64) {
+        self.close()
+    }
+
+    /// Optionally do periodic work, e.g. to drive timers. Called for each active flow once per
+    /// tick interval, if one was given with
+    /// [`RunBuilder::with_tick`](./struct.RunBuilder.html#method.with_tick).
+    /// The default implementation does nothing.
+    fn on_tick(&mut self) {}
+
+    /// Optionally set the flow's datapath program again after the datapath serving it was
+    /// replaced by a standby (see [`ipc::failover`](./ipc/failover/index.html)). The datapath
+    /// programs have been installed on the standby by the time this is called, but it does not
+    /// know which program each flow was using.
+    /// The default implementation does nothing.
+    fn on_failover(&mut self) {}
+
+    /// Optionally handle the datapath reporting that it could not do something for this flow,
+    /// e.g. run the program the flow set: `code` says what went wrong (see `serialize::codes`).
+    /// The default implementation logs the error.
+    fn on_error(&mut self, sock_id: u64, code: serialize::codes::Code, msg: &str) {
+        warn!(sid = sock_id, %code, msg, "datapath reported an error");
+    }
+
+    /// Optionally handle the datapath acknowledging the switch to the program `set_program`
+    /// returned the scope of: `ok` is whether the datapath is now running it. Only called for
+    /// datapaths which send acknowledgements, or if
+    /// [`RunBuilder::with_program_ack_timeout`](./struct.RunBuilder.html#method.with_program_ack_timeout)
+    /// is set, in which case a switch not acknowledged in time fails.
+    /// The default implementation logs failures.
+    fn on_program_installed(&mut self, sock_id: u64, program_uid: u32, ok: bool) {
+        if !ok {
+            warn!(
+                sid = sock_id,
+                program_uid, "datapath did not switch to the program"
+            );
+        }
+    }
 }
 
 impl<T> Flow for Box<T>
 where
     T: Flow + ?Sized,
 {
-    fn on_report(&mut self, sock_id: u32, m: Report) {
+    fn on_report(&mut self, sock_id: u64, m: Report) {
         T::on_report(self, sock_id, m)
     }
 
+    fn on_report_at(&mut self, sock_id: u64, m: Report, at: SystemTime) {
+        T::on_report_at(self, sock_id, m, at)
+    }
+
     fn close(&mut self) {
         T::close(self)
     }
+
+    fn close_with_counts(&mut self, bytes: u64, packets: u64) {
+        T::close_with_counts(self, bytes, packets)
+    }
+
+    fn on_tick(&mut self) {
+        T::on_tick(self)
+    }
+
+    fn on_failover(&mut self) {
+        T::on_failover(self)
+    }
+
+    fn on_error(&mut self, sock_id: u64, code: serialize::codes::Code, msg: &str) {
+        T::on_error(self, sock_id, code, msg)
+    }
+
+    fn on_program_installed(&mut self, sock_id: u64, program_uid: u32, ok: bool) {
+        T::on_program_installed(self, sock_id, program_uid, ok)
+    }
 }
 
 /// implement this trait, [`portus::CongAlgBuilder`](./trait.CongAlgBuilder.html) and
@@ -303,11 +765,22 @@ pub trait CongAlg<I: Ipc> {
     /// h.insert("prog1", "...(program)...".to_string());
     /// h.insert("prog2", "...(program)...".to_string());
     /// ```
+    ///
+    /// The code may instead hold several programs, each `(program name ...)` (see
+    /// [`lang::compile_programs`](./lang/fn.compile_programs.html)), e.g. one for each phase of
+    /// the algorithm. Each is installed as its own program, named `"prog1.name"`, which
+    /// `set_program` switches a flow to without installing it again.
     fn datapath_programs(&self) -> HashMap<&'static str, String>;
 
     /// Create a new instance of the CongAlg to manage a new flow.
     /// Optionally copy any configuration parameters from `&self`.
     fn new_flow(&self, control: Datapath<I>, info: DatapathInfo) -> Self::Flow;
+
+    /// Optionally set up a datapath as soon as it connects, before it creates any flows, e.g. to
+    /// set a default program. Called each time a datapath sends a ready message, once the
+    /// datapath programs are installed on it. `control` is not tied to a flow: its socket id is 0.
+    /// The default implementation does nothing.
+    fn datapath_ready(&self, _control: Datapath<I>) {}
 }
 
 /// Tell `portus` how to construct instances of your `impl` [`portus::CongAlg`].
@@ -332,5 +805,5 @@ pub trait CongAlgBuilder<'a, 'b> {
 mod run;
 pub use run::*;
 
-#[cfg(test)]
+#[cfg(all(test, unix))]
 mod test;
@@ -1,16 +1,18 @@
 //! Utilities to start a CCP processing worker.
 
-use crate::ipc::BackendBuilder;
 use crate::ipc::Ipc;
-use crate::lang::Scope;
+use crate::ipc::{Backend, BackendBuilder, BackendSender, Priority, SendRateLimit};
 use crate::serialize;
 use crate::serialize::Msg;
-use crate::{lang, CongAlg, Datapath, DatapathInfo, Error, Flow, Report, Result};
-use std::collections::HashMap;
-use std::rc::Rc;
+use crate::{
+    lang, AwaitingAcks, CongAlg, Datapath, DatapathInfo, Error, Flow, Program, Report, Result,
+    TornDown,
+};
+use std::collections::{HashMap, HashSet};
 use std::sync::{atomic, Arc};
 use std::thread;
-use tracing::{debug, info};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{debug, info, trace, warn};
 
 /// A handle to manage running instances of the CCP execution loop.
 #[derive(Debug)]
@@ -42,6 +44,7 @@ impl CCPHandle {
 mod sealed {
     use crate::{ipc::Ipc, CongAlg, Datapath, DatapathInfo, Flow, Report};
     use std::collections::HashMap;
+    use std::time::SystemTime;
 
     pub struct AlgList<Head, Tail> {
         pub head_name: String,
@@ -61,7 +64,7 @@ mod sealed {
         L: Flow,
         R: Flow,
     {
-        fn on_report(&mut self, sock_id: u32, m: Report) {
+        fn on_report(&mut self, sock_id: u64, m: Report) {
             use Either::*;
             match self {
                 Left(l) => l.on_report(sock_id, m),
@@ -69,6 +72,14 @@ mod sealed {
             }
         }
 
+        fn on_report_at(&mut self, sock_id: u64, m: Report, at: SystemTime) {
+            use Either::*;
+            match self {
+                Left(l) => l.on_report_at(sock_id, m, at),
+                Right(r) => r.on_report_at(sock_id, m, at),
+            }
+        }
+
         fn close(&mut self) {
             use Either::*;
             match self {
@@ -76,6 +87,38 @@ mod sealed {
                 Right(r) => r.close(),
             }
         }
+
+        fn close_with_counts(&mut self, bytes: u64, packets: u64) {
+            use Either::*;
+            match self {
+                Left(l) => l.close_with_counts(bytes, packets),
+                Right(r) => r.close_with_counts(bytes, packets),
+            }
+        }
+
+        fn on_tick(&mut self) {
+            use Either::*;
+            match self {
+                Left(l) => l.on_tick(),
+                Right(r) => r.on_tick(),
+            }
+        }
+
+        fn on_error(&mut self, sock_id: u64, code: crate::serialize::codes::Code, msg: &str) {
+            use Either::*;
+            match self {
+                Left(l) => l.on_error(sock_id, code, msg),
+                Right(r) => r.on_error(sock_id, code, msg),
+            }
+        }
+
+        fn on_program_installed(&mut self, sock_id: u64, program_uid: u32, ok: bool) {
+            use Either::*;
+            match self {
+                Left(l) => l.on_program_installed(sock_id, program_uid, ok),
+                Right(r) => r.on_program_installed(sock_id, program_uid, ok),
+            }
+        }
     }
 
     impl<L, R, I> CongAlg<I> for Either<L, R>
@@ -105,6 +148,14 @@ mod sealed {
                 Right(r) => Right(r.new_flow(control, info)),
             }
         }
+
+        fn datapath_ready(&self, control: Datapath<I>) {
+            use Either::*;
+            match self {
+                Left(l) => l.datapath_ready(control),
+                Right(r) => r.datapath_ready(control),
+            }
+        }
     }
 
     impl<T, I> CongAlg<I> for &T
@@ -125,6 +176,10 @@ mod sealed {
         fn new_flow(&self, control: Datapath<I>, info: DatapathInfo) -> Self::Flow {
             T::new_flow(self, control, info)
         }
+
+        fn datapath_ready(&self, control: Datapath<I>) {
+            T::datapath_ready(self, control)
+        }
     }
 
     pub trait Pick<'a, I: Ipc> {
@@ -174,8 +229,10 @@ mod sealed {
         }
     }
 
-    pub trait CollectDps<I> {
+    pub trait CollectDps<I: Ipc> {
         fn datapath_programs(&self) -> HashMap<&'static str, String>;
+        // tell every algorithm that a datapath is ready, each with its own handle from `control`
+        fn datapath_ready(&self, control: &dyn Fn() -> Datapath<I>);
     }
 
     impl<I: Ipc, T> CollectDps<I> for AlgListNil<T>
@@ -185,6 +242,10 @@ mod sealed {
         fn datapath_programs(&self) -> HashMap<&'static str, String> {
             self.0.datapath_programs()
         }
+
+        fn datapath_ready(&self, control: &dyn Fn() -> Datapath<I>) {
+            self.0.datapath_ready(control())
+        }
     }
 
     impl<'a, I: Ipc, T> CollectDps<I> for &'a AlgListNil<T>
@@ -194,6 +255,10 @@ mod sealed {
         fn datapath_programs(&self) -> HashMap<&'static str, String> {
             self.0.datapath_programs()
         }
+
+        fn datapath_ready(&self, control: &dyn Fn() -> Datapath<I>) {
+            self.0.datapath_ready(control())
+        }
     }
 
     impl<H, T, I> CollectDps<I> for AlgList<Option<H>, T>
@@ -210,6 +275,13 @@ mod sealed {
                 .chain(self.tail.datapath_programs().into_iter())
                 .collect()
         }
+
+        fn datapath_ready(&self, control: &dyn Fn() -> Datapath<I>) {
+            if let Some(ref head) = self.head {
+                head.datapath_ready(control());
+            }
+            self.tail.datapath_ready(control);
+        }
     }
 
     impl<'a, H, T, I> CollectDps<I> for &'a AlgList<Option<H>, T>
@@ -226,6 +298,13 @@ mod sealed {
                 .chain(self.tail.datapath_programs().into_iter())
                 .collect()
         }
+
+        fn datapath_ready(&self, control: &dyn Fn() -> Datapath<I>) {
+            if let Some(ref head) = self.head {
+                head.datapath_ready(control());
+            }
+            self.tail.datapath_ready(control);
+        }
     }
 }
 
@@ -288,7 +367,7 @@ use sealed::*;
 /// }
 ///
 /// impl Flow for AlgOne {
-///     fn on_report(&mut self, sock_id: u32, m: Report) {
+///     fn on_report(&mut self, sock_id: u64, m: Report) {
 ///         println!("alg1 minrtt: {:?}", m.get_field("Report.minrtt", &self.0).unwrap());
 ///     }
 /// }
@@ -313,7 +392,7 @@ use sealed::*;
 /// }
 ///
 /// impl Flow for AlgTwo {
-///     fn on_report(&mut self, sock_id: u32, m: Report) {
+///     fn on_report(&mut self, sock_id: u64, m: Report) {
 ///         println!("alg2 minrtt: {:?}", m.get_field("Report.minrtt", &self.0).unwrap());
 ///     }
 /// }
@@ -333,11 +412,89 @@ pub struct RunBuilder<I: Ipc, U, Spawnness> {
     backend_builder: BackendBuilder<I>,
     alg: U,
     stop_handle: Option<*const atomic::AtomicBool>,
+    tick: Option<Duration>,
+    receive_buf_size: usize,
+    send_timeout: Option<Duration>,
+    backend_options: BackendOptions<I>,
     _phantom: std::marker::PhantomData<Spawnness>,
 }
 
+// Starts the backend's receive thread. `with_receive_thread` checks that the socket can be
+// shared with the thread, so the execution loop need not.
+type SpawnReceiver<I> = Box<dyn for<'a> FnOnce(&mut Backend<'a, I>) -> Result<()> + Send>;
+
+type ParseErrorHandler = Box<dyn FnMut(&[u8], &Error) + Send>;
+
+type GapHandler<I> = Box<dyn FnMut(&<I as crate::ipc::IpcSend>::Addr, u64) + Send>;
+
+type MsgHandler<I> =
+    Box<dyn for<'a> FnMut(&<I as crate::ipc::IpcSend>::Addr, serialize::RawMsg<'a>) + Send>;
+
+// What to set up on the `Backend` once the execution loop has built it, how long the loop waits
+// for the datapath to acknowledge program switches, and who handles messages of types portus
+// does not know.
+struct BackendOptions<I: Ipc> {
+    heartbeat: Option<(Duration, u32)>,
+    receive_thread: Option<SpawnReceiver<I>>,
+    on_parse_error: Option<ParseErrorHandler>,
+    send_rate_limit: Option<SendRateLimit>,
+    announce_version: bool,
+    sequence_numbers: bool,
+    on_gap: Option<GapHandler<I>>,
+    program_ack_timeout: Option<Duration>,
+    msg_handlers: HashMap<u8, MsgHandler<I>>,
+    parse_mode: serialize::ParseMode,
+    #[cfg(feature = "compression")]
+    compression: Option<usize>,
+}
+
+impl<I: Ipc> Default for BackendOptions<I> {
+    fn default() -> Self {
+        BackendOptions {
+            heartbeat: None,
+            receive_thread: None,
+            on_parse_error: None,
+            send_rate_limit: None,
+            announce_version: false,
+            sequence_numbers: false,
+            on_gap: None,
+            program_ack_timeout: None,
+            msg_handlers: HashMap::new(),
+            parse_mode: serialize::DEFAULT_PARSE_MODE,
+            #[cfg(feature = "compression")]
+            compression: None,
+        }
+    }
+}
+
+impl<I: Ipc> BackendOptions<I> {
+    fn apply(self, b: &mut Backend<'_, I>) -> Result<()> {
+        if let Some((interval, max_missed)) = self.heartbeat {
+            b.set_heartbeat(interval, max_missed);
+        }
+        if let Some(handler) = self.on_parse_error {
+            b.on_parse_error(handler);
+        }
+        b.set_send_rate_limit(self.send_rate_limit)?;
+        b.set_announce_version(self.announce_version)?;
+        b.set_sequence_numbers(self.sequence_numbers);
+        b.set_parse_mode(self.parse_mode);
+        if let Some(handler) = self.on_gap {
+            b.on_gap(handler);
+        }
+        #[cfg(feature = "compression")]
+        b.set_compression(self.compression);
+        if let Some(spawn_receiver) = self.receive_thread {
+            spawn_receiver(b)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub struct Spawn;
 pub struct NoSpawn;
+pub struct Pump;
 
 impl<I: Ipc> RunBuilder<I, (), NoSpawn> {
     pub fn new(backend_builder: BackendBuilder<I>) -> Self {
@@ -345,6 +502,10 @@ impl<I: Ipc> RunBuilder<I, (), NoSpawn> {
             backend_builder,
             alg: (),
             stop_handle: None,
+            tick: None,
+            receive_buf_size: crate::ipc::DEFAULT_RECV_BUF_SIZE,
+            send_timeout: None,
+            backend_options: Default::default(),
             _phantom: Default::default(),
         }
     }
@@ -360,6 +521,10 @@ impl<I: Ipc, S> RunBuilder<I, (), S> {
             alg: AlgListNil(alg),
             backend_builder: self.backend_builder,
             stop_handle: self.stop_handle,
+            tick: self.tick,
+            receive_buf_size: self.receive_buf_size,
+            send_timeout: self.send_timeout,
+            backend_options: self.backend_options,
             _phantom: Default::default(),
         }
     }
@@ -381,6 +546,10 @@ impl<I: Ipc, U, S> RunBuilder<I, U, S> {
             },
             backend_builder: self.backend_builder,
             stop_handle: self.stop_handle,
+            tick: self.tick,
+            receive_buf_size: self.receive_buf_size,
+            send_timeout: self.send_timeout,
+            backend_options: self.backend_options,
             _phantom: Default::default(),
         }
     }
@@ -397,10 +566,228 @@ impl<I: Ipc, U, S> RunBuilder<I, U, S> {
             },
             backend_builder: self.backend_builder,
             stop_handle: self.stop_handle,
+            tick: self.tick,
+            receive_buf_size: self.receive_buf_size,
+            send_timeout: self.send_timeout,
+            backend_options: self.backend_options,
             _phantom: Default::default(),
         }
     }
 
+    /// Call [`Flow::on_tick`](./trait.Flow.html#method.on_tick) on every flow each time
+    /// `interval` passes, whether or not messages arrive. This sets the IPC socket's receive
+    /// timeout to `interval`, so it fails if the socket does not support one.
+    pub fn with_tick(self, interval: Duration) -> Self {
+        Self {
+            tick: Some(interval),
+            ..self
+        }
+    }
+
+    /// Read messages from the datapath into a buffer of `size` bytes, instead of
+    /// `ipc::DEFAULT_RECV_BUF_SIZE`. Messages larger than this, e.g. reports with many fields,
+    /// cause `run` to return an error saying how large a buffer they need.
+    pub fn with_receive_buf_size(self, size: usize) -> Self {
+        Self {
+            receive_buf_size: size,
+            ..self
+        }
+    }
+
+    /// Give up on sends to the datapath which wait longer than `timeout` for it to make room,
+    /// instead of blocking the execution loop until it does. The failed send returns
+    /// `Error("send timed out")` to the flow, e.g. from
+    /// [`DatapathTrait::set_program`](./trait.DatapathTrait.html#tymethod.set_program), which
+    /// can decide whether to retry. This fails to run if the IPC socket does not support a send
+    /// timeout.
+    pub fn with_send_timeout(self, timeout: Duration) -> Self {
+        Self {
+            send_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Send the datapath a heartbeat every `interval`, and stop with `DatapathGoneError` once
+    /// `max_missed` in a row have gone unanswered, closing all the flows. See
+    /// [`Backend::set_heartbeat`](./ipc/struct.Backend.html#method.set_heartbeat). The datapath
+    /// must echo heartbeat messages back, so only enable this for datapaths which do.
+    pub fn with_heartbeat(self, interval: Duration, max_missed: u32) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                heartbeat: Some((interval, max_missed)),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Read messages from the IPC socket on a separate thread, into a queue of `capacity`
+    /// messages handled according to `overflow`, so that the socket keeps draining while flows
+    /// handle reports. See
+    /// [`Backend::spawn_receiver`](./ipc/struct.Backend.html#method.spawn_receiver).
+    pub fn with_receive_thread(self, capacity: usize, overflow: crate::ipc::queue::Overflow) -> Self
+    where
+        I: Sync,
+        I::Addr: Send,
+    {
+        Self {
+            backend_options: BackendOptions {
+                receive_thread: Some(Box::new(move |b: &mut Backend<'_, I>| {
+                    b.spawn_receiver(capacity, overflow)
+                })),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Call `handler` with each message from the datapath which cannot be parsed, along with
+    /// why, e.g. to keep a copy of what a buggy datapath sent. The execution loop skips such
+    /// messages either way, and counts them in
+    /// [`BackendStats::malformed`](./ipc/struct.BackendStats.html#method.malformed). See
+    /// [`Backend::on_parse_error`](./ipc/struct.Backend.html#method.on_parse_error).
+    pub fn on_parse_error<F>(self, handler: F) -> Self
+    where
+        F: FnMut(&[u8], &Error) + Send + 'static,
+    {
+        Self {
+            backend_options: BackendOptions {
+                on_parse_error: Some(Box::new(handler)),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Parse the messages from the datapath in `mode`, e.g. `ParseMode::Strict` in tests, so that
+    /// messages which are not exactly what this portus expects are counted as malformed. See
+    /// [`Backend::set_parse_mode`](./ipc/struct.Backend.html#method.set_parse_mode).
+    pub fn parse_mode(self, mode: serialize::ParseMode) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                parse_mode: mode,
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Limit how fast flows send control messages to the datapath, either delaying or rejecting
+    /// those over the limit. See
+    /// [`Backend::set_send_rate_limit`](./ipc/struct.Backend.html#method.set_send_rate_limit).
+    pub fn with_send_rate_limit(self, limit: SendRateLimit) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                send_rate_limit: Some(limit),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Tell each datapath which version of the messages this portus speaks when it first gets
+    /// in touch. See
+    /// [`Backend::set_announce_version`](./ipc/struct.Backend.html#method.set_announce_version).
+    ///
+    /// Whether or not this is set, a datapath which says it speaks a different major version
+    /// has its flows closed and its messages ignored, while other datapaths carry on.
+    pub fn with_version_announce(self) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                announce_version: true,
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Stamp the control messages sent to each datapath with sequence numbers, so that it can
+    /// tell when one is lost, e.g. over a datagram socket. The datapath must understand them.
+    /// See
+    /// [`Backend::set_sequence_numbers`](./ipc/struct.Backend.html#method.set_sequence_numbers).
+    pub fn with_sequence_numbers(self) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                sequence_numbers: true,
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Call `handler` with the datapath and the number of messages missing each time the
+    /// sequence numbers of the messages from a datapath show that some were lost or reordered.
+    /// They count towards [`BackendStats::gaps`](./ipc/struct.BackendStats.html#method.gaps)
+    /// either way. See [`Backend::on_gap`](./ipc/struct.Backend.html#method.on_gap).
+    pub fn on_gap<F>(self, handler: F) -> Self
+    where
+        F: FnMut(&I::Addr, u64) + Send + 'static,
+    {
+        Self {
+            backend_options: BackendOptions {
+                on_gap: Some(Box::new(handler)),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Call `handler` with each message of type `typ` from the datapath, along with which
+    /// datapath sent it, e.g. for a datapath's own statistics messages. The handler gets the
+    /// message's socket id and its payload, from `RawMsg::get_bytes`. Without a handler, the
+    /// execution loop drops messages of types it does not know.
+    ///
+    /// It is an error if `typ` is one of the predefined types (see
+    /// [`serialize::is_predefined_type`](./serialize/fn.is_predefined_type.html)). A later
+    /// handler for the same type replaces an earlier one.
+    pub fn on_msg<F>(mut self, typ: u8, handler: F) -> Result<Self>
+    where
+        F: for<'a> FnMut(&I::Addr, serialize::RawMsg<'a>) + Send + 'static,
+    {
+        if serialize::is_predefined_type(typ) {
+            return Err(Error(format!(
+                "message type {} is predefined, and cannot be handled",
+                typ
+            )));
+        }
+
+        self.backend_options
+            .msg_handlers
+            .insert(typ, Box::new(handler));
+        Ok(self)
+    }
+
+    /// Expect the datapath to acknowledge each switch of a flow's program within `timeout`, and
+    /// call [`Flow::on_program_installed`](./trait.Flow.html#method.on_program_installed) with
+    /// `ok` false for a switch it has not acknowledged by then, e.g. because it never installed
+    /// the program. Without this, flows only hear of the acknowledgements the datapath sends.
+    ///
+    /// Timeouts are noticed when the execution loop wakes up, so `with_tick` should be set to
+    /// an interval shorter than `timeout`.
+    pub fn with_program_ack_timeout(self, timeout: Duration) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                program_ack_timeout: Some(timeout),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
+    /// Compress control messages to the datapath which are at least `threshold` bytes long, e.g.
+    /// large programs. The datapath must be able to decompress them. See
+    /// [`Backend::set_compression`](./ipc/struct.Backend.html#method.set_compression).
+    #[cfg(feature = "compression")]
+    pub fn with_compression(self, threshold: usize) -> Self {
+        Self {
+            backend_options: BackendOptions {
+                compression: Some(threshold),
+                ..self.backend_options
+            },
+            ..self
+        }
+    }
+
     /// Pass an `AtomicBool` stop handle.
     pub fn with_stop_handle(self, handle: Arc<atomic::AtomicBool>) -> Self {
         Self {
@@ -423,6 +810,15 @@ impl<I: Ipc, U, S> RunBuilder<I, U, S> {
         }
     }
 
+    // Apply the socket options which can fail before starting, so that the caller sees the error.
+    fn configure_sock(&mut self) -> Result<()> {
+        if let Some(timeout) = self.send_timeout {
+            self.backend_builder.sock.set_send_timeout(timeout)?;
+        }
+
+        Ok(())
+    }
+
     fn stop_handle(&self) -> Result<Arc<atomic::AtomicBool>> {
         if let Some(ptr) = self.stop_handle {
             if ptr.is_null() {
@@ -451,6 +847,28 @@ impl<I: Ipc, U> RunBuilder<I, U, NoSpawn> {
             backend_builder: self.backend_builder,
             stop_handle: self.stop_handle,
             alg: self.alg,
+            tick: self.tick,
+            receive_buf_size: self.receive_buf_size,
+            send_timeout: self.send_timeout,
+            backend_options: self.backend_options,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<I: Ipc, U> RunBuilder<I, U, NoSpawn> {
+    /// Let the caller drive the CCP execution loop, e.g. from an event loop it already runs,
+    /// instead of having `run` block waiting for messages. See
+    /// [`CCPPump`](./struct.CCPPump.html).
+    pub fn pumped(self) -> RunBuilder<I, U, Pump> {
+        RunBuilder {
+            backend_builder: self.backend_builder,
+            stop_handle: self.stop_handle,
+            alg: self.alg,
+            tick: self.tick,
+            receive_buf_size: self.receive_buf_size,
+            send_timeout: self.send_timeout,
+            backend_options: self.backend_options,
             _phantom: Default::default(),
         }
     }
@@ -461,9 +879,17 @@ where
     I: Ipc,
     for<'a> &'a U: Pick<'a, I> + CollectDps<I>,
 {
-    pub fn run(self) -> Result<()> {
+    pub fn run(mut self) -> Result<()> {
         let h = self.stop_handle()?;
-        run_inner(h, self.backend_builder, self.alg)
+        self.configure_sock()?;
+        run_inner(
+            h,
+            self.backend_builder,
+            self.alg,
+            self.tick,
+            self.receive_buf_size,
+            self.backend_options,
+        )
     }
 }
 
@@ -473,83 +899,533 @@ where
     U: Send + 'static,
     for<'a> &'a U: Pick<'a, I> + CollectDps<I>,
 {
-    pub fn run(self) -> Result<CCPHandle> {
+    pub fn run(mut self) -> Result<CCPHandle> {
         let stop_signal = self.stop_handle()?;
+        self.configure_sock()?;
         let bb = self.backend_builder;
         let alg = self.alg;
+        let tick = self.tick;
+        let size = self.receive_buf_size;
+        let backend_options = self.backend_options;
         Ok(CCPHandle {
             continue_listening: stop_signal.clone(),
-            join_handle: thread::spawn(move || run_inner(stop_signal, bb, alg)),
+            join_handle: thread::spawn(move || {
+                run_inner(stop_signal, bb, alg, tick, size, backend_options)
+            }),
         })
     }
 }
 
-// Main execution inner loop of ccp.
-// Blocks "forever", or until the iterator stops iterating.
-//
-// `run_inner()`:
-// 1. listens for messages from the datapath
-// 2. call the appropriate message in `U: impl CongAlg`
-// The function can return for two reasons: an error, or the iterator returned None.
-// The latter should only happen for spawn(), and not for run().
-// It returns any error, either from:
-// 1. the IPC channel failing
-// 2. Receiving an install control message (only the datapath should receive these).
-fn run_inner<I, U>(
-    continue_listening: Arc<atomic::AtomicBool>,
-    backend_builder: BackendBuilder<I>,
-    algs: U,
-) -> Result<()>
+impl<I, U> RunBuilder<I, U, Pump>
 where
     I: Ipc,
     for<'a> &'a U: Pick<'a, I> + CollectDps<I>,
 {
-    let mut receive_buf = [0u8; 1024];
-    let mut b = backend_builder.build(continue_listening.clone(), &mut receive_buf[..]);
-    // the borrow has to before the HashMap, to guarantee that the HashMap is dropped first
-    let algs2 = &algs;
-    let mut dp_to_flowmap = HashMap::<
-        I::Addr,
-        HashMap<u32, <<&'_ U as Pick<'_, I>>::Picked as CongAlg<I>>::Flow>,
-    >::new();
+    /// Set up the execution loop, and call `f` with a `CCPPump` to drive it: `f` calls
+    /// `CCPPump::dispatch_ready` whenever the socket has messages waiting, e.g. when the
+    /// application's event loop sees the socket's fd become readable. Returns what `f` returns.
+    ///
+    /// Messages are only read from the socket in `dispatch_ready`, so this cannot be combined
+    /// with `with_receive_thread`.
+    pub fn run<F, R>(mut self, f: F) -> Result<R>
+    where
+        F: for<'p> FnOnce(&mut CCPPump<'p, I, U>) -> Result<R>,
+    {
+        if self.backend_options.receive_thread.is_some() {
+            return Err(Error(String::from(
+                "a pumped execution loop cannot use a receive thread",
+            )));
+        }
 
-    info!(ipc = ?I::name(), "starting CCP");
+        let continue_listening = self.stop_handle()?;
+        self.configure_sock()?;
+        if let Some(interval) = self.tick {
+            self.backend_builder.sock.set_recv_timeout(interval)?;
+        }
 
-    let mut scope_map = Rc::new(HashMap::<String, Scope>::default());
-    let mut install_msgs = vec![];
-
-    let programs = algs2.datapath_programs();
-    for (program_name, program) in programs.iter() {
-        match lang::compile(program.as_bytes(), &[]) {
-            Ok((bin, sc)) => {
-                let msg = serialize::install::Msg {
-                    sid: 0,
-                    program_uid: sc.program_uid,
-                    num_events: bin.events.len() as u32,
-                    num_instrs: bin.instrs.len() as u32,
-                    instrs: bin,
-                };
-                let buf = serialize::serialize(&msg)?;
-                install_msgs.push(buf);
+        let mut receive_buf = vec![0u8; self.receive_buf_size];
+        let mut backend = self
+            .backend_builder
+            .build(continue_listening.clone(), &mut receive_buf[..]);
+        let ack_timeout = self.backend_options.program_ack_timeout;
+        let msg_handlers = std::mem::take(&mut self.backend_options.msg_handlers);
+        self.backend_options.apply(&mut backend)?;
+        let algs1 = &self.alg;
+        let algs2 = &algs1;
+
+        info!(ipc = ?I::name(), "starting CCP");
+        let dispatcher = Dispatcher::new(
+            algs2,
+            backend.sender(Default::default()),
+            ack_timeout,
+            msg_handlers,
+        )?;
+        let mut pump = CCPPump {
+            backend,
+            dispatcher,
+            continue_listening,
+            tick: self.tick,
+            last_tick: Instant::now(),
+        };
+        f(&mut pump)
+    }
+}
 
-                Rc::get_mut(&mut scope_map)
-                    .unwrap()
-                    .insert(program_name.to_string(), sc.clone());
+/// The CCP execution loop, driven by the caller rather than by `RunBuilder::run`.
+///
+/// ```rust,no_run
+/// # use portus::ipc::{unix, BackendBuilder, Nonblocking};
+/// # use portus::RunBuilder;
+/// # fn wait_readable() -> bool { false }
+/// # #[derive(Default)]
+/// # struct MyAlg;
+/// # impl<I: portus::ipc::Ipc> portus::CongAlg<I> for MyAlg {
+/// #     type Flow = Self;
+/// #     fn name() -> &'static str { "my-alg" }
+/// #     fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+/// #         Default::default()
+/// #     }
+/// #     fn new_flow(&self, _: portus::Datapath<I>, _: portus::DatapathInfo) -> Self { MyAlg }
+/// # }
+/// # impl portus::Flow for MyAlg {
+/// #     fn on_report(&mut self, _: u64, _: portus::Report) {}
+/// # }
+/// let sock = unix::Socket::<Nonblocking>::new("portus").unwrap();
+/// RunBuilder::new(BackendBuilder { sock })
+///     .default_alg(MyAlg)
+///     .pumped()
+///     .run(|pump| {
+///         // e.g. poll the socket's fd along with the application's own
+///         while wait_readable() {
+///             pump.dispatch_ready()?;
+///         }
+///
+///         Ok(())
+///     })
+///     .unwrap();
+/// ```
+pub struct CCPPump<'p, I, U>
+where
+    I: Ipc,
+    &'p U: Pick<'p, I>,
+{
+    backend: Backend<'p, I>,
+    dispatcher: Dispatcher<'p, I, U>,
+    continue_listening: Arc<atomic::AtomicBool>,
+    tick: Option<Duration>,
+    last_tick: Instant,
+}
+
+impl<'p, I, U> CCPPump<'p, I, U>
+where
+    I: Ipc,
+    &'p U: Pick<'p, I> + CollectDps<I>,
+{
+    /// Handle the messages which can be read now, and return how many there were. With a
+    /// `Nonblocking` socket, this returns as soon as no more messages are waiting; a blocking
+    /// socket waits up to its receive timeout for one first. If the interval set with
+    /// `with_tick` has passed, this first calls `Flow::on_tick` on every flow.
+    ///
+    /// Fails, closing the flows, when `run` would have. Once stopped with the stop handle, this
+    /// handles no more messages.
+    pub fn dispatch_ready(&mut self) -> Result<usize> {
+        if let Some(interval) = self.tick {
+            if self.last_tick.elapsed() >= interval {
+                self.last_tick = Instant::now();
+                self.dispatcher.tick();
+            }
+        }
+
+        let mut handled = 0;
+        loop {
+            self.dispatcher.check_reconnects(self.backend.reconnects());
+            self.dispatcher.check_failovers(self.backend.failovers());
+            self.dispatcher.check_program_acks();
+            match self.backend.try_next_at() {
+                Ok(Some((msg, recv_addr, recv_at))) => {
+                    if let Err(e) = self.dispatcher.handle(msg, recv_addr, recv_at) {
+                        return exit_status(
+                            &self.continue_listening,
+                            &mut self.backend,
+                            &mut self.dispatcher,
+                            e,
+                        )
+                        .map(|_| handled);
+                    }
+
+                    handled += 1;
+                }
+                Ok(None) => return Ok(handled),
+                Err(e) if e == Error::from(crate::MalformedMsgError) => (),
+                Err(e) => {
+                    return exit_status(
+                        &self.continue_listening,
+                        &mut self.backend,
+                        &mut self.dispatcher,
+                        e,
+                    )
+                    .map(|_| handled)
+                }
+            }
+        }
+    }
+
+    /// The `Backend` the loop reads from, e.g. to check its `stats()`.
+    pub fn backend(&self) -> &Backend<'p, I> {
+        &self.backend
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<U> RunBuilder<crate::ipc::tokio::Socket, U, NoSpawn>
+where
+    for<'a> &'a U: Pick<'a, crate::ipc::tokio::Socket> + CollectDps<crate::ipc::tokio::Socket>,
+{
+    /// Like `run`, but awaits messages instead of blocking the thread, so it can run alongside
+    /// other tasks in a tokio runtime.
+    ///
+    /// The returned future is not `Send`, since flows need not be: await it directly, e.g.
+    /// from `#[tokio::main]`, or spawn it on a `tokio::task::LocalSet`.
+    ///
+    /// This waits for the socket itself to become readable, so it cannot be combined with
+    /// `with_receive_thread`.
+    pub async fn run_async(mut self) -> Result<()> {
+        if self.backend_options.receive_thread.is_some() {
+            return Err(Error(String::from("run_async cannot use a receive thread")));
+        }
+
+        let h = self.stop_handle()?;
+        self.configure_sock()?;
+        run_inner_async(
+            h,
+            self.backend_builder,
+            self.alg,
+            self.tick,
+            self.receive_buf_size,
+            self.backend_options,
+        )
+        .await
+    }
+}
+
+// The messages which install the datapath programs: with the programs serialized both ways, and
+// compactly with name tables; and the minor version of each datapath which said it speaks one.
+#[derive(Default)]
+struct InstallMsgs<A> {
+    fixed: Vec<Vec<u8>>,
+    compact: Vec<Vec<u8>>,
+    named: Vec<Vec<u8>>,
+    minors: HashMap<A, u32>,
+}
+
+impl<A: Eq + std::hash::Hash> InstallMsgs<A> {
+    // `msg` has the program's names; only the named messages keep them.
+    fn push(&mut self, msg: &serialize::install::Msg) -> Result<()> {
+        let unnamed = serialize::install::Msg {
+            names: None,
+            ..msg.clone()
+        };
+        self.fixed.push(serialize::serialize(&unnamed)?);
+        let compact = serialize::install::Compact::new(&unnamed)?;
+        self.compact.push(serialize::serialize(&compact)?);
+        let named = serialize::install::Compact::new(msg)?;
+        self.named.push(serialize::serialize(&named)?);
+        Ok(())
+    }
+
+    // The messages to send the datapath at `addr`.
+    fn to(&self, addr: &A) -> &[Vec<u8>] {
+        match self.minors.get(addr) {
+            Some(&m) if m >= serialize::install::NAMED_MINOR => &self.named,
+            Some(&m) if m >= serialize::install::VARINT_MINOR => &self.compact,
+            _ => &self.fixed,
+        }
+    }
+
+    // The datapath at `addr` speaks version `v` of the messages.
+    fn set_version(&mut self, addr: A, v: &serialize::version::Msg) {
+        if v.major == serialize::version::MAJOR {
+            self.minors.insert(addr, v.minor);
+        } else {
+            self.minors.remove(&addr);
+        }
+    }
+}
+
+type FlowOf<'u, I, U> = <<&'u U as Pick<'u, I>>::Picked as CongAlg<I>>::Flow;
+
+// The state of the execution loop: the compiled datapath programs, and the flows of each
+// datapath. `handle()` dispatches each message from the datapath to the appropriate flow.
+struct Dispatcher<'u, I, U>
+where
+    I: Ipc,
+    &'u U: Pick<'u, I>,
+{
+    algs: &'u &'u U,
+    sender: BackendSender<I>,
+    install_msgs: InstallMsgs<I::Addr>,
+    programs: Arc<HashMap<String, Program>>,
+    dp_to_flowmap: HashMap<I::Addr, HashMap<u64, FlowOf<'u, I, U>>>,
+    // what each datapath that advertised its capabilities can run
+    dp_caps: HashMap<I::Addr, serialize::capabilities::Msg>,
+    // the datapaths which speak an incompatible protocol version, whose messages are ignored
+    incompatible: HashSet<I::Addr>,
+    // the program switches each datapath has yet to acknowledge, if they time out
+    ack_timeout: Option<Duration>,
+    awaiting_acks: HashMap<I::Addr, Arc<AwaitingAcks>>,
+    // who handles messages of types portus does not know
+    msg_handlers: HashMap<u8, MsgHandler<I>>,
+    // the flows of each datapath which have asked to be torn down
+    torn_down: HashMap<I::Addr, Arc<TornDown>>,
+    reconnects: u64,
+    failovers: u64,
+}
+
+impl<'u, I, U> Dispatcher<'u, I, U>
+where
+    I: Ipc,
+    &'u U: Pick<'u, I> + CollectDps<I>,
+{
+    // `sender` can have any destination; `handle()` replaces it with the sender of each message.
+    fn new(
+        algs: &'u &'u U,
+        sender: BackendSender<I>,
+        ack_timeout: Option<Duration>,
+        msg_handlers: HashMap<u8, MsgHandler<I>>,
+    ) -> Result<Self> {
+        let mut compiled = HashMap::<String, Program>::default();
+        let mut install_msgs = InstallMsgs::default();
+
+        let programs = algs.datapath_programs();
+        for (program_name, program) in programs.iter() {
+            // a source with several programs installs each as "program_name.name"
+            match lang::compile_programs(program.as_bytes()) {
+                Ok(named) => {
+                    for (name, bin, sc) in named {
+                        let name = if name.is_empty() {
+                            program_name.to_string()
+                        } else {
+                            format!("{}.{}", program_name, name)
+                        };
+                        let msg = serialize::install::Msg {
+                            names: Some(sc.report_names()),
+                            ..serialize::install::Msg::new(0, sc.program_uid, bin.clone())
+                        };
+                        install_msgs.push(&msg)?;
+                        debug!(
+                            program = %name,
+                            event_triggered = sc.is_event_triggered(),
+                            "compiled datapath program"
+                        );
+                        compiled.insert(name, Program { scope: sc, bin });
+                    }
+                }
+                Err(e) => {
+                    return Err(Error(format!(
+                        "Datapath program \"{}\" failed to compile: {:?}",
+                        program_name, e
+                    )));
+                }
+            }
+        }
+
+        debug!(programs = %format!("{:#?}", programs.keys()), "compiled all datapath programs, ccp ready");
+        Ok(Dispatcher {
+            algs,
+            sender,
+            install_msgs,
+            programs: Arc::new(compiled),
+            dp_to_flowmap: HashMap::new(),
+            dp_caps: HashMap::new(),
+            incompatible: HashSet::new(),
+            ack_timeout,
+            awaiting_acks: HashMap::new(),
+            msg_handlers,
+            torn_down: HashMap::new(),
+            reconnects: 0,
+            failovers: 0,
+        })
+    }
+
+    fn tick(&mut self) {
+        self.dp_to_flowmap
+            .values_mut()
+            .flat_map(HashMap::values_mut)
+            .for_each(Flow::on_tick);
+        self.close_torn_down();
+    }
+
+    // If the socket has reconnected since the last call, the datapath lost its state, so close
+    // all the flows. A new ready or create message will re-install the programs.
+    fn check_reconnects(&mut self, reconnects: u64) {
+        if reconnects == self.reconnects {
+            return;
+        }
+
+        self.reconnects = reconnects;
+        info!(?reconnects, "datapath reconnected, closing its old flows");
+        self.close_flows();
+    }
+
+    // If the socket has failed over to a standby datapath since the last call, install the
+    // programs there, and let each flow set its program again. The flows themselves carry on.
+    fn check_failovers(&mut self, failovers: u64) {
+        if failovers == self.failovers {
+            return;
+        }
+
+        self.failovers = failovers;
+        info!(
+            ?failovers,
+            "failed over to a standby datapath, installing programs"
+        );
+        for addr in self.dp_to_flowmap.keys() {
+            let backend = self.sender.clone_with_dest(addr.clone());
+            for buf in self.install_msgs.to(addr) {
+                if let Err(e) = backend.send_msg(&buf[..]) {
+                    warn!(err = %e.0, "could not install programs on the standby datapath");
+                }
+            }
+        }
+
+        self.dp_to_flowmap
+            .values_mut()
+            .flat_map(HashMap::values_mut)
+            .for_each(Flow::on_failover);
+        self.close_torn_down();
+    }
+
+    // Fail the program switches the datapaths have taken too long to acknowledge.
+    fn check_program_acks(&mut self) {
+        let timeout = match self.ack_timeout {
+            Some(t) => t,
+            None => return,
+        };
+
+        for (addr, acks) in &self.awaiting_acks {
+            for (sid, program_uid) in acks.expired(timeout) {
+                if let Some(flow) = self
+                    .dp_to_flowmap
+                    .get_mut(addr)
+                    .and_then(|fm| fm.get_mut(&sid))
+                {
+                    debug!(sid, program_uid, "program switch not acknowledged in time");
+                    flow.on_program_installed(sid, program_uid, false);
+                }
             }
-            Err(e) => {
-                return Err(Error(format!(
-                    "Datapath program \"{}\" failed to compile: {:?}",
-                    program_name, e
-                )));
+        }
+
+        self.close_torn_down();
+    }
+
+    // Close the flows which have asked to be torn down since the last call.
+    fn close_torn_down(&mut self) {
+        for (addr, torn_down) in &self.torn_down {
+            for sid in torn_down.take() {
+                if let Some(mut flow) = self
+                    .dp_to_flowmap
+                    .get_mut(addr)
+                    .and_then(|fm| fm.remove(&sid))
+                {
+                    debug!(sid, "flow torn down");
+                    flow.close();
+                }
             }
         }
     }
 
-    debug!(programs = %format!("{:#?}", programs.keys()), "compiled all datapath programs, ccp ready");
-    while let Some((msg, recv_addr)) = b.next() {
+    // Report `m`, from `recv_addr`, to its flow, or close the flow if `m` has no fields and the
+    // datapath is one which ends flows so rather than with fin messages.
+    fn measurement(
+        &mut self,
+        m: serialize::measure::Msg,
+        recv_addr: &I::Addr,
+        recv_at: SystemTime,
+    ) {
+        let sends_fin = matches!(
+            self.install_msgs.minors.get(recv_addr),
+            Some(&minor) if minor >= serialize::fin::FIN_MINOR
+        );
+        let flowmap = match self.dp_to_flowmap.get_mut(recv_addr) {
+            Some(fm) => fm,
+            None => {
+                info!(addr = %format!("{:#?}", recv_addr), "received measurement from unknown datapath, ignoring");
+                return;
+            }
+        };
+
+        if flowmap.contains_key(&m.sid) {
+            if m.num_fields == 0 && !sends_fin {
+                let mut flow = flowmap.remove(&m.sid).unwrap();
+                flow.close();
+            } else {
+                let flow = flowmap.get_mut(&m.sid).unwrap();
+                flow.on_report_at(
+                    m.sid,
+                    Report {
+                        program_uid: m.program_uid,
+                        from: format!("{:#?}", recv_addr),
+                        fields: m.fields,
+                        types: m.types,
+                        timestamp: m.timestamp,
+                        strings: m.strings,
+                    },
+                    recv_at,
+                )
+            }
+        } else {
+            debug!(sid = m.sid, "measurement for unknown flow");
+        }
+    }
+
+    // Stop serving the datapath at `addr`: close its flows, and ignore its messages until it
+    // speaks a compatible protocol version. Other datapaths carry on.
+    fn reject(&mut self, addr: I::Addr) {
+        self.dp_caps.remove(&addr);
+        self.awaiting_acks.remove(&addr);
+        self.torn_down.remove(&addr);
+        if let Some(flowmap) = self.dp_to_flowmap.remove(&addr) {
+            for (_, mut flow) in flowmap {
+                flow.close();
+            }
+        }
+
+        self.incompatible.insert(addr);
+    }
+
+    fn close_flows(&mut self) {
+        self.awaiting_acks.clear();
+        self.torn_down.clear();
+        for (_, flowmap) in self.dp_to_flowmap.drain() {
+            for (_, mut flow) in flowmap {
+                flow.close();
+            }
+        }
+    }
+
+    // Handle `msg` from `recv_addr`, which arrived at `recv_at`, and then close the flows which
+    // asked to be torn down while handling it.
+    fn handle(&mut self, msg: Msg<'_>, recv_addr: I::Addr, recv_at: SystemTime) -> Result<()> {
+        trace!(msg = %msg, "received");
+        let res = self.handle_msg(msg, recv_addr, recv_at);
+        self.close_torn_down();
+        res
+    }
+
+    fn handle_msg(&mut self, msg: Msg<'_>, recv_addr: I::Addr, recv_at: SystemTime) -> Result<()> {
+        // until it says it speaks a compatible version, e.g. once it is upgraded
+        if self.incompatible.contains(&recv_addr) && !matches!(msg, Msg::Ver(_)) {
+            debug!(addr = %format!("{:#?}", recv_addr), "ignoring message from incompatible datapath");
+            return Ok(());
+        }
+
         match msg {
             Msg::Rdy(_r) => {
-                if dp_to_flowmap.remove(&recv_addr).is_some() {
+                // a restarted datapath advertises its capabilities again
+                self.dp_caps.remove(&recv_addr);
+                self.awaiting_acks.remove(&recv_addr);
+                self.torn_down.remove(&recv_addr);
+                if self.dp_to_flowmap.remove(&recv_addr).is_some() {
                     info!(
                         "new ready from old datapath, clearing old flows and installing programs"
                     );
@@ -557,28 +1433,39 @@ where
                     info!(addr = %format!("{:#?}", recv_addr), "found new datapath, installing programs");
                 }
 
-                dp_to_flowmap.insert(
-                    recv_addr.clone(),
-                    HashMap::<u32, <<&'_ U as Pick<'_, I>>::Picked as CongAlg<I>>::Flow>::default(),
-                );
+                self.dp_to_flowmap
+                    .insert(recv_addr.clone(), HashMap::default());
 
-                let backend = b.sender(recv_addr);
-                for buf in &install_msgs {
+                let backend = self.sender.clone_with_dest(recv_addr.clone());
+                for buf in self.install_msgs.to(&recv_addr) {
                     backend.send_msg(&buf[..])?;
                 }
+
+                self.algs.datapath_ready(&|| Datapath {
+                    sock_id: 0,
+                    sender: backend.clone(),
+                    programs: self.programs.clone(),
+                    priority: Priority::Urgent,
+                    capabilities: None,
+                    minor: self.install_msgs.minors.get(&recv_addr).copied(),
+                    // not a flow's, so there is no flow to tell or close
+                    acks: None,
+                    torn_down: None,
+                    scratch: Default::default(),
+                });
             }
             Msg::Cr(c) => {
                 let mut need_install = false;
-                let flowmap = dp_to_flowmap.entry(recv_addr.clone()).or_insert_with_key(|recv_addr| {
+                let flowmap = self.dp_to_flowmap.entry(recv_addr.clone()).or_insert_with_key(|recv_addr| {
                     debug!(addr = %format!("{:#?}", recv_addr), "received create from unknown datapath, initializing");
                     need_install = true;
-                    HashMap::<u32, <<&'_ U as Pick<'_, I>>::Picked as CongAlg<I>>::Flow>::default()
+                    HashMap::default()
                 });
 
                 if need_install {
                     debug!(addr = %format!("{:#?}", recv_addr), "installing programs");
-                    let backend = b.sender(recv_addr.clone());
-                    for buf in &install_msgs {
+                    let backend = self.sender.clone_with_dest(recv_addr.clone());
+                    for buf in self.install_msgs.to(&recv_addr) {
                         backend.send_msg(&buf[..])?;
                     }
                 }
@@ -599,12 +1486,28 @@ where
                     "creating new flow"
                 );
 
-                let alg = algs2.pick(c.cong_alg.as_ref().map(String::as_str).unwrap_or(""));
+                let alg = Pick::pick(self.algs, c.cong_alg.as_deref().unwrap_or(""));
+                let peer = format!("{:#?}", recv_addr);
+                let capabilities = self.dp_caps.get(&recv_addr).copied();
+                let minor = self.install_msgs.minors.get(&recv_addr).copied();
+                let acks = match self.ack_timeout {
+                    Some(_) => Some(Arc::clone(
+                        self.awaiting_acks.entry(recv_addr.clone()).or_default(),
+                    )),
+                    None => None,
+                };
+                let torn_down = Arc::clone(self.torn_down.entry(recv_addr.clone()).or_default());
                 let f = alg.new_flow(
                     Datapath {
                         sock_id: c.sid,
-                        sender: b.sender(recv_addr),
-                        programs: scope_map.clone(),
+                        sender: self.sender.clone_with_dest(recv_addr),
+                        programs: self.programs.clone(),
+                        priority: Priority::Urgent,
+                        capabilities,
+                        minor,
+                        acks,
+                        torn_down: Some(torn_down),
+                        scratch: Default::default(),
                     },
                     DatapathInfo {
                         sock_id: c.sid,
@@ -614,60 +1517,291 @@ where
                         src_port: c.src_port,
                         dst_ip: c.dst_ip,
                         dst_port: c.dst_port,
+                        peer,
+                        requested_alg: c.cong_alg,
                     },
                 );
                 flowmap.insert(c.sid, f);
             }
-            Msg::Ms(m) => {
-                let flowmap = match dp_to_flowmap.get_mut(&recv_addr) {
-                    Some(fm) => fm,
+            Msg::Ms(m) => self.measurement(m, &recv_addr, recv_at),
+            Msg::MsBatch(mb) => {
+                debug!(count = mb.measurements.len(), "unpacking measurement batch");
+                for m in mb.measurements {
+                    self.measurement(m, &recv_addr, recv_at);
+                }
+            }
+            Msg::Ins(m) => {
+                // only the datapath acts on these
+                debug!(sid = m.sid, addr = %format!("{:#?}", recv_addr), "got install message, ignoring");
+            }
+            Msg::Hb(_) => {
+                // the backend keeps track of heartbeats
+            }
+            Msg::Uf(m) => {
+                // only the datapath acts on these
+                debug!(sid = m.sid, addr = %format!("{:#?}", recv_addr), "got update field message, ignoring");
+            }
+            Msg::Cp(m) => {
+                // only the datapath acts on these
+                debug!(sid = m.sid, addr = %format!("{:#?}", recv_addr), "got change program message, ignoring");
+            }
+            Msg::Ver(v) => {
+                self.install_msgs.set_version(recv_addr.clone(), &v);
+                let ours = serialize::version::Msg::current();
+                if v.major != ours.major {
+                    warn!(addr = %format!("{:#?}", recv_addr), theirs = %v, %ours, "datapath speaks an incompatible protocol version, ignoring it");
+                    self.reject(recv_addr);
+                    return Ok(());
+                }
+
+                self.incompatible.remove(&recv_addr);
+
+                if v.minor != ours.minor {
+                    warn!(addr = %format!("{:#?}", recv_addr), theirs = %v, %ours, "datapath speaks a different minor protocol version");
+                } else {
+                    info!(addr = %format!("{:#?}", recv_addr), version = %v, "datapath speaks our protocol version");
+                }
+            }
+            Msg::Err(e) => {
+                match self
+                    .dp_to_flowmap
+                    .get_mut(&recv_addr)
+                    .and_then(|fm| fm.get_mut(&e.sid))
+                {
+                    Some(flow) => flow.on_error(e.sid, e.code, &e.msg),
                     None => {
-                        info!(addr = %format!("{:#?}", recv_addr), "received measurement from unknown datapath, ignoring");
-                        continue;
+                        warn!(sid = e.sid, code = %e.code, msg = %e.msg, addr = %format!("{:#?}", recv_addr), "datapath reported an error");
                     }
-                };
+                }
+            }
+            Msg::Un(m) => {
+                // only the datapath acts on these
+                debug!(sid = m.sid, addr = %format!("{:#?}", recv_addr), "got uninstall message, ignoring");
+            }
+            Msg::Td(m) => {
+                // only the datapath acts on these
+                debug!(sid = m.sid, addr = %format!("{:#?}", recv_addr), "got teardown message, ignoring");
+            }
+            Msg::Fin(m) => match self
+                .dp_to_flowmap
+                .get_mut(&recv_addr)
+                .and_then(|fm| fm.remove(&m.sid))
+            {
+                Some(mut flow) => {
+                    debug!(
+                        sid = m.sid,
+                        bytes = m.bytes,
+                        packets = m.packets,
+                        "flow ended"
+                    );
+                    flow.close_with_counts(m.bytes, m.packets);
+                }
+                None => debug!(sid = m.sid, "fin for unknown flow"),
+            },
+            Msg::InsAck(ack) => {
+                if let Some(acks) = self.awaiting_acks.get(&recv_addr) {
+                    acks.ack(ack.sid, ack.program_uid);
+                }
 
-                if flowmap.contains_key(&m.sid) {
-                    if m.num_fields == 0 {
-                        let mut flow = flowmap.remove(&m.sid).unwrap();
-                        flow.close();
-                    } else {
-                        let flow = flowmap.get_mut(&m.sid).unwrap();
-                        flow.on_report(
-                            m.sid,
-                            Report {
-                                program_uid: m.program_uid,
-                                from: format!("{:#?}", recv_addr),
-                                fields: m.fields,
-                            },
-                        )
-                    }
-                } else {
-                    debug!(sid = m.sid, "measurement for unknown flow");
+                match self
+                    .dp_to_flowmap
+                    .get_mut(&recv_addr)
+                    .and_then(|fm| fm.get_mut(&ack.sid))
+                {
+                    Some(flow) => flow.on_program_installed(ack.sid, ack.program_uid, ack.ok()),
+                    None => debug!(sid = ack.sid, "program acknowledged for unknown flow"),
                 }
             }
-            Msg::Ins(_) => {
-                // The start() listener should never receive an install message, since it is on the CCP side.
-                unreachable!()
+            Msg::Caps(caps) => {
+                info!(addr = %format!("{:#?}", recv_addr), ?caps, "datapath advertised capabilities");
+                for (name, p) in self.programs.iter() {
+                    if let Err(e) = caps.check(name, &p.bin, &p.scope) {
+                        warn!(err = %e.0, "datapath cannot run program");
+                    }
+                }
+
+                self.dp_caps.insert(recv_addr, caps);
             }
-            Msg::Other(m) => {
-                debug!(
+            Msg::Other(m) => match self.msg_handlers.get_mut(&m.typ) {
+                Some(handler) => handler(&recv_addr, m),
+                None => debug!(
                     size = ?m.len,
                     msg_type = ?m.typ,
                     sid = ?m.sid,
                     addr = %format!("{:#?}", recv_addr),
                     "got unknown message"
-                );
-                continue;
+                ),
+            },
+        }
+
+        Ok(())
+    }
+}
+
+// Main execution inner loop of ccp.
+// Blocks "forever", or until the iterator stops iterating.
+//
+// `run_inner()`:
+// 1. listens for messages from the datapath
+// 2. call the appropriate message in `U: impl CongAlg`
+// The function can return for two reasons: an error, or the iterator returned None.
+// The latter should only happen for spawn(), and not for run().
+// It returns any error, either from:
+// 1. the IPC channel failing
+fn run_inner<I, U>(
+    continue_listening: Arc<atomic::AtomicBool>,
+    mut backend_builder: BackendBuilder<I>,
+    algs: U,
+    tick: Option<Duration>,
+    receive_buf_size: usize,
+    mut backend_options: BackendOptions<I>,
+) -> Result<()>
+where
+    I: Ipc,
+    for<'a> &'a U: Pick<'a, I> + CollectDps<I>,
+{
+    if let Some(interval) = tick {
+        backend_builder.sock.set_recv_timeout(interval)?;
+    }
+
+    let mut receive_buf = vec![0u8; receive_buf_size];
+    let mut b = backend_builder.build(continue_listening.clone(), &mut receive_buf[..]);
+    let ack_timeout = backend_options.program_ack_timeout;
+    let msg_handlers = std::mem::take(&mut backend_options.msg_handlers);
+    backend_options.apply(&mut b)?;
+    // the borrow has to before the Dispatcher, to guarantee that the Dispatcher's flows are dropped first
+    let algs1 = &algs;
+    let algs2 = &algs1;
+
+    info!(ipc = ?I::name(), "starting CCP");
+    let mut dispatcher = Dispatcher::new(
+        algs2,
+        b.sender(Default::default()),
+        ack_timeout,
+        msg_handlers,
+    )?;
+
+    let mut last_tick = Instant::now();
+    loop {
+        if let Some(interval) = tick {
+            if last_tick.elapsed() >= interval {
+                last_tick = Instant::now();
+                dispatcher.tick();
             }
         }
+
+        dispatcher.check_reconnects(b.reconnects());
+        dispatcher.check_failovers(b.failovers());
+        dispatcher.check_program_acks();
+        match b.try_next_at() {
+            Ok(Some((msg, recv_addr, recv_at))) => {
+                if let Err(e) = dispatcher.handle(msg, recv_addr, recv_at) {
+                    return exit_status(&continue_listening, &mut b, &mut dispatcher, e);
+                }
+            }
+            // don't spin on a nonblocking socket, but don't wait past the next tick either
+            Ok(None) => b.idle(tick.map(|interval| interval.saturating_sub(last_tick.elapsed()))),
+            // the backend skipped it and reported it to `on_parse_error`
+            Err(e) if e == Error::from(crate::MalformedMsgError) => (),
+            Err(e) => return exit_status(&continue_listening, &mut b, &mut dispatcher, e),
+        }
     }
+}
 
+// Why the execution loop stopped, once the backend returned `err` instead of a message, or the
+// dispatcher could not handle one, e.g. it could not install programs on a new datapath.
+// If the loop failed, the flows are done, so close them.
+fn exit_status<'u, I, U>(
+    continue_listening: &atomic::AtomicBool,
+    b: &mut Backend<I>,
+    dispatcher: &mut Dispatcher<'u, I, U>,
+    err: Error,
+) -> Result<()>
+where
+    I: Ipc,
+    &'u U: Pick<'u, I> + CollectDps<I>,
+{
     // if the thread has been killed, return that as error
-    if !continue_listening.load(atomic::Ordering::SeqCst) {
+    let res = if !continue_listening.load(atomic::Ordering::SeqCst) {
         info!("portus shutting down");
         Ok(())
+    } else if let Some(e) = b.take_error() {
+        // pass on why the socket failed, e.g. `DatapathGoneError` if the datapath went away
+        info!(err = %e.0, "IPC socket failed, shutting down");
+        Err(e)
     } else {
-        Err(Error(String::from("The IPC channel has closed.")))
+        // the socket is fine, but the message could not be handled, e.g. it was too long for
+        // the receive buffer
+        info!(err = %err.0, "invalid message, shutting down");
+        Err(err)
+    };
+
+    if res.is_err() {
+        dispatcher.close_flows();
+    }
+
+    res
+}
+
+// As `run_inner()`, but waits for the socket to become readable asynchronously.
+#[cfg(feature = "tokio")]
+async fn run_inner_async<U>(
+    continue_listening: Arc<atomic::AtomicBool>,
+    backend_builder: BackendBuilder<crate::ipc::tokio::Socket>,
+    algs: U,
+    tick: Option<Duration>,
+    receive_buf_size: usize,
+    mut backend_options: BackendOptions<crate::ipc::tokio::Socket>,
+) -> Result<()>
+where
+    for<'a> &'a U: Pick<'a, crate::ipc::tokio::Socket> + CollectDps<crate::ipc::tokio::Socket>,
+{
+    let sock = backend_builder.sock.clone();
+    let mut receive_buf = vec![0u8; receive_buf_size];
+    let mut b = backend_builder.build(continue_listening.clone(), &mut receive_buf[..]);
+    let ack_timeout = backend_options.program_ack_timeout;
+    let msg_handlers = std::mem::take(&mut backend_options.msg_handlers);
+    backend_options.apply(&mut b)?;
+    let algs1 = &algs;
+    let algs2 = &algs1;
+
+    info!(ipc = ?<crate::ipc::tokio::Socket as crate::ipc::IpcSend>::name(), "starting CCP");
+    let mut dispatcher = Dispatcher::new(
+        algs2,
+        b.sender(Default::default()),
+        ack_timeout,
+        msg_handlers,
+    )?;
+
+    // wake up at least this often to check whether we have been stopped
+    let interval = tick.unwrap_or_else(|| Duration::from_secs(1));
+    let mut last_tick = Instant::now();
+    loop {
+        if tick.is_some() && last_tick.elapsed() >= interval {
+            last_tick = Instant::now();
+            dispatcher.tick();
+        }
+
+        let wait = interval.saturating_sub(last_tick.elapsed());
+        if let Ok(ready) = ::tokio::time::timeout(wait, sock.readable()).await {
+            ready?;
+        }
+
+        // `try_next()` also notices if we have been stopped
+        loop {
+            dispatcher.check_reconnects(b.reconnects());
+            dispatcher.check_failovers(b.failovers());
+            dispatcher.check_program_acks();
+            match b.try_next_at() {
+                Ok(Some((msg, recv_addr, recv_at))) => {
+                    if let Err(e) = dispatcher.handle(msg, recv_addr, recv_at) {
+                        return exit_status(&continue_listening, &mut b, &mut dispatcher, e);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) if e == Error::from(crate::MalformedMsgError) => (),
+                Err(e) => return exit_status(&continue_listening, &mut b, &mut dispatcher, e),
+            }
+        }
     }
 }
@@ -0,0 +1,345 @@
+//! With the `serde` feature, messages, datapath programs, and `DatapathInfo` implement
+//! `serde::Serialize` and `serde::Deserialize`, e.g. to log control messages as JSON, or to load
+//! test fixtures from YAML.
+//!
+//! Structs are maps of their fields. Enums are tagged with their variant's name, and a variant
+//! with fields holds them as a sequence, so the tags do not change as variants are added:
+//!
+//! ```text
+//! {"Control": [0, {"Num": null}, true]}
+//! ```
+//!
+//! is a volatile `lang::Reg::Control` holding a number. `serialize::Msg` and `RawMsg` only
+//! implement `Serialize`, since they may borrow the buffer they were parsed from.
+//!
+//! The types implement them with `serde_struct!` and `serde_enum!`, next to their definitions.
+
+// Implement `Serialize` and `Deserialize` for a struct, as a map of the named fields. Any other
+// fields in the map are ignored.
+macro_rules! serde_struct {
+    (@ser $ty:ty { $($field:ident),* $(,)? }) => {
+        impl ::serde::Serialize for $ty {
+            fn serialize<S: ::serde::Serializer>(
+                &self,
+                s: S,
+            ) -> ::std::result::Result<S::Ok, S::Error> {
+                use ::serde::ser::SerializeStruct;
+                let mut st = s.serialize_struct(stringify!($ty), [$(stringify!($field)),*].len())?;
+                $(st.serialize_field(stringify!($field), &self.$field)?;)*
+                st.end()
+            }
+        }
+    };
+    ($ty:ident { $($field:ident),* $(,)? }) => {
+        serde_struct!(@ser $ty { $($field),* });
+
+        impl<'de> ::serde::Deserialize<'de> for $ty {
+            fn deserialize<D: ::serde::Deserializer<'de>>(
+                d: D,
+            ) -> ::std::result::Result<Self, D::Error> {
+                const FIELDS: &[&str] = &[$(stringify!($field)),*];
+                struct V;
+
+                impl<'de> ::serde::de::Visitor<'de> for V {
+                    type Value = $ty;
+
+                    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        write!(f, "struct {}", stringify!($ty))
+                    }
+
+                    fn visit_map<A: ::serde::de::MapAccess<'de>>(
+                        self,
+                        mut __map: A,
+                    ) -> ::std::result::Result<$ty, A::Error> {
+                        $(let mut $field = None;)*
+                        while let Some(__key) = __map.next_key::<::std::string::String>()? {
+                            match __key.as_str() {
+                                $(stringify!($field) => $field = Some(__map.next_value()?),)*
+                                _ => {
+                                    __map.next_value::<::serde::de::IgnoredAny>()?;
+                                }
+                            }
+                        }
+
+                        Ok($ty {
+                            $($field: $field.ok_or_else(|| {
+                                <A::Error as ::serde::de::Error>::missing_field(stringify!($field))
+                            })?,)*
+                        })
+                    }
+                }
+
+                d.deserialize_struct(stringify!($ty), FIELDS, V)
+            }
+        }
+    };
+}
+
+// Implement `Serialize` and `Deserialize` for an enum, tagged with the variant's name. Each
+// variant is listed with a name for each of its fields, if it has any.
+macro_rules! serde_enum {
+    (@ser $ty:ty { $($var:ident $(($($f:ident),+))?),+ $(,)? }) => {
+        impl ::serde::Serialize for $ty {
+            fn serialize<S: ::serde::Serializer>(
+                &self,
+                s: S,
+            ) -> ::std::result::Result<S::Ok, S::Error> {
+                const VARIANTS: &[&str] = &[$(stringify!($var)),+];
+                let idx = |var| VARIANTS.iter().position(|v| *v == var).unwrap_or_default() as u32;
+                match self {
+                    $(Self::$var $(($($f),+))? => serde_enum!(
+                        @ser_variant s, stringify!($ty), idx(stringify!($var)), stringify!($var)
+                        $(, $($f),+)?
+                    ),)+
+                }
+            }
+        }
+    };
+    (@ser_variant $s:ident, $name:expr, $idx:expr, $var:expr) => {
+        $s.serialize_unit_variant($name, $idx, $var)
+    };
+    (@ser_variant $s:ident, $name:expr, $idx:expr, $var:expr, $f:ident) => {
+        $s.serialize_newtype_variant($name, $idx, $var, $f)
+    };
+    (@ser_variant $s:ident, $name:expr, $idx:expr, $var:expr, $($f:ident),+) => {
+        $s.serialize_newtype_variant($name, $idx, $var, &($($f,)+))
+    };
+    (@de_variant $ty:ident, $access:ident, $var:ident) => {{
+        $access.unit_variant()?;
+        Ok($ty::$var)
+    }};
+    (@de_variant $ty:ident, $access:ident, $var:ident, $f:ident) => {
+        Ok($ty::$var($access.newtype_variant()?))
+    };
+    (@de_variant $ty:ident, $access:ident, $var:ident, $($f:ident),+) => {{
+        let ($($f,)+) = $access.newtype_variant()?;
+        Ok($ty::$var($($f),+))
+    }};
+    ($ty:ident { $($var:ident $(($($f:ident),+))?),+ $(,)? }) => {
+        serde_enum!(@ser $ty { $($var $(($($f),+))?),+ });
+
+        impl<'de> ::serde::Deserialize<'de> for $ty {
+            fn deserialize<D: ::serde::Deserializer<'de>>(
+                d: D,
+            ) -> ::std::result::Result<Self, D::Error> {
+                const VARIANTS: &[&str] = &[$(stringify!($var)),+];
+                struct V;
+
+                impl<'de> ::serde::de::Visitor<'de> for V {
+                    type Value = $ty;
+
+                    fn expecting(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+                        write!(f, "enum {}", stringify!($ty))
+                    }
+
+                    fn visit_enum<A: ::serde::de::EnumAccess<'de>>(
+                        self,
+                        data: A,
+                    ) -> ::std::result::Result<$ty, A::Error> {
+                        use ::serde::de::VariantAccess;
+                        let (__var, __access) = data.variant::<::std::string::String>()?;
+                        match __var.as_str() {
+                            $(stringify!($var) => serde_enum!(
+                                @de_variant $ty, __access, $var $(, $($f),+)?
+                            ),)+
+                            var => Err(<A::Error as ::serde::de::Error>::unknown_variant(
+                                var, VARIANTS,
+                            )),
+                        }
+                    }
+                }
+
+                d.deserialize_enum(stringify!($ty), VARIANTS, V)
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lang::{Reg, Type};
+    use crate::serialize;
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fmt::Debug;
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(x: T) {
+        let json = json(&x);
+        let got: T = serde_json::from_str(&json).expect("from json");
+        assert_eq!(got, x, "{}", json);
+    }
+
+    fn json<T: Serialize>(x: &T) -> String {
+        serde_json::to_string(x).expect("to json")
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let (bin, sc) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)) (target 0))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )
+            (when (> Micros 1000)
+                (:= Cwnd (ewma 2 Cwnd))
+                (:= Micros 0)
+            )",
+            &[],
+        )
+        .expect("compile");
+        round_trip(bin.clone());
+        for instr in bin.instrs.iter().cloned() {
+            round_trip(instr);
+        }
+
+        let measure = serialize::measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 4242],
+            types: Some(vec![
+                serialize::measure::FieldType::Num,
+                serialize::measure::FieldType::Int,
+            ]),
+            timestamp: Some(1_600_000_000_000),
+            strings: vec![],
+        };
+        round_trip(measure.clone());
+        round_trip(serialize::measure_batch::Msg {
+            measurements: vec![measure],
+        });
+        round_trip(serialize::create::Msg {
+            sid: 1 << 40,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 1,
+            src_port: 4242,
+            dst_ip: 2,
+            dst_port: 4243,
+            cong_alg: Some(String::from("reno")),
+        });
+        round_trip(serialize::install::Msg {
+            sid: 0,
+            program_uid: sc.program_uid,
+            num_events: bin.events.len() as u32,
+            num_instrs: bin.instrs.len() as u32,
+            instrs: bin,
+            names: None,
+        });
+        round_trip(serialize::update_field::Msg {
+            sid: 1,
+            num_fields: 2,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 14480),
+                (Reg::Control(0, Type::Num(Some(0)), false), 7),
+            ],
+            widths: Some(vec![
+                serialize::update_field::Width::U32,
+                serialize::update_field::Width::U64,
+            ]),
+        });
+        round_trip(serialize::changeprog::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 0,
+            fields: vec![],
+        });
+        round_trip(serialize::ready::Msg { id: 3 });
+        round_trip(serialize::heartbeat::Msg { seq: 9 });
+        round_trip(serialize::capabilities::Msg {
+            ops: 0x7fff,
+            primitives: 0x3,
+            max_instrs: 50,
+        });
+        round_trip(serialize::version::Msg::current());
+        round_trip(serialize::install_ack::Msg {
+            sid: 1,
+            program_uid: 7,
+            status: serialize::codes::Code::UnknownProgram,
+        });
+        round_trip(serialize::uninstall::Msg {
+            sid: 1,
+            program_uid: 7,
+        });
+        round_trip(serialize::teardown::Msg { sid: 1, reason: 2 });
+        round_trip(serialize::fin::Msg {
+            sid: 1,
+            bytes: 14480,
+            packets: 10,
+        });
+        round_trip(serialize::error::Msg {
+            sid: 1,
+            code: serialize::codes::Code::ProgramTooLarge,
+            msg: String::from("too many instructions"),
+        });
+        round_trip(crate::DatapathInfo {
+            sock_id: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 1,
+            src_port: 4242,
+            dst_ip: 2,
+            dst_port: 4243,
+            peer: String::from("()"),
+            requested_alg: Some(String::from("cubic")),
+        });
+    }
+
+    #[test]
+    fn enums_round_trip() {
+        for t in [
+            Type::Bool(Some(true)),
+            Type::Name(String::from("Cwnd")),
+            Type::Num(None),
+            Type::None,
+        ] {
+            round_trip(t.clone());
+            round_trip(Reg::Control(1, t.clone(), true));
+            round_trip(Reg::Implicit(2, t.clone()));
+            round_trip(Reg::Local(3, t.clone()));
+            round_trip(Reg::Primitive(4, t.clone()));
+            round_trip(Reg::Report(5, t.clone(), false));
+            round_trip(Reg::Tmp(6, t));
+        }
+        round_trip(Reg::ImmNum(u64::MAX));
+        round_trip(Reg::ImmBool(false));
+        round_trip(Reg::None);
+    }
+
+    // Enums are tagged with their variants' names, not their positions.
+    #[test]
+    fn stable_tags() {
+        assert_eq!(
+            json(&Reg::Control(0, Type::Num(None), true)),
+            r#"{"Control":[0,{"Num":null},true]}"#
+        );
+        assert_eq!(json(&Reg::ImmNum(3)), r#"{"ImmNum":3}"#);
+        assert_eq!(json(&Reg::None), r#""None""#);
+        assert_eq!(json(&serialize::measure::FieldType::Num32), r#""Num32""#);
+        assert_eq!(
+            json(&serialize::Msg::Hb(serialize::heartbeat::Msg { seq: 9 })),
+            r#"{"Hb":{"seq":9}}"#
+        );
+    }
+
+    // A message received, as it is logged.
+    #[test]
+    fn received_msg_to_json() {
+        let buf = serialize::serialize(&serialize::teardown::Msg { sid: 1, reason: 2 })
+            .expect("serialize");
+        let (msg, _) = serialize::Msg::from_buf(&buf).expect("parse");
+        assert_eq!(json(&msg), r#"{"Td":{"sid":1,"reason":2}}"#);
+    }
+
+    #[test]
+    fn fields_by_name() {
+        // fields may come in any order, and unknown ones are ignored
+        let got: serialize::teardown::Msg =
+            serde_json::from_str(r#"{"reason":2,"comment":"x","sid":1}"#).expect("from json");
+        assert_eq!(got, serialize::teardown::Msg { sid: 1, reason: 2 });
+
+        assert!(serde_json::from_str::<serialize::teardown::Msg>(r#"{"sid":1}"#).is_err());
+        assert!(serde_json::from_str::<Reg>(r#"{"Nonesuch":1}"#).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Message sent from datapath to CCP after it starts up, advertising which datapath programs it
+//! can run. Datapaths which do not send one are assumed to run anything.
+
+use super::codes::Code;
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::lang::{Bin, Reg, Scope};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of capabilities messages.
+pub const CAPABILITIES: u8 = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    /// Bit `i` is set if the datapath can execute the instruction with opcode `i`, e.g. bit 5
+    /// for `ewma`.
+    pub ops: u32,
+    /// Bit `i` is set if the datapath measures primitive `i`, numbering the `Ack.*` and `Flow.*`
+    /// primitives in alphabetical order from 0, e.g. bit 2 for `Ack.ecn_bytes`.
+    pub primitives: u32,
+    /// The most instructions a program can have.
+    pub max_instrs: u32,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    ops,
+    primitives,
+    max_instrs,
+});
+
+impl Msg {
+    /// Check that the datapath can run `bin`, the program `name` compiled with scope `sc`, and
+    /// if not, say why.
+    pub fn check(&self, name: &str, bin: &Bin, sc: &Scope) -> Result<()> {
+        if bin.instrs.len() > self.max_instrs as usize {
+            return Err(Code::ProgramTooLarge.error(format_args!(
+                "program {:?} has {} instructions, but the datapath runs at most {}",
+                name,
+                bin.instrs.len(),
+                self.max_instrs
+            )));
+        }
+
+        for instr in &bin.instrs {
+            if self.ops & (1 << crate::lang::serialize_op(instr.op)) == 0 {
+                return Err(Code::UnsupportedOp.error(format_args!(
+                    "program {:?} uses {:?}, which the datapath does not support",
+                    name, instr.op
+                )));
+            }
+
+            for reg in &[&instr.res, &instr.left, &instr.right] {
+                match reg {
+                    Reg::Primitive(idx, _) if self.primitives & (1 << idx) == 0 => {
+                        return Err(Code::UnsupportedPrimitive.error(format_args!(
+                            "program {:?} uses {}, which the datapath does not measure",
+                            name,
+                            primitive_name(sc, *idx)
+                        )))
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn primitive_name(sc: &Scope, idx: u8) -> String {
+    sc.named
+        .0
+        .iter()
+        .find(|(_, r)| matches!(r, Reg::Primitive(i, _) if *i == idx))
+        .map_or_else(|| format!("primitive {}", idx), |(n, _)| n.clone())
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "capabilities ops={:#x} primitives={:#x} max_instrs={}",
+            self.ops, self.primitives, self.max_instrs
+        )
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        CAPABILITIES
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (CAPABILITIES, HDR_LENGTH + 3 * 4, 0)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        for x in &[self.ops, self.primitives, self.max_instrs] {
+            u32_to_u8s(&mut buf, *x);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 3 * 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 3 * 4,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(3 * 4)?;
+
+        Ok(Msg {
+            ops: u32_from_u8s(&b[0..4]),
+            primitives: u32_from_u8s(&b[4..8]),
+            max_instrs: u32_from_u8s(&b[8..12]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Code;
+
+    check_msg!(
+        test_capabilities_1,
+        super::Msg,
+        super::Msg {
+            ops: 0x7fff,
+            primitives: 0x3,
+            max_instrs: 50,
+        },
+        crate::serialize::Msg::Caps(cm),
+        cm
+    );
+
+    #[test]
+    fn check_program() {
+        let (bin, sc) = crate::lang::compile(
+            b"(def (Report (volatile ecn 0)))
+            (when true
+                (:= Report.ecn (+ Report.ecn Ack.ecn_bytes))
+            )",
+            &[],
+        )
+        .expect("compile");
+        let all = super::Msg {
+            ops: 0x7fff,
+            primitives: 0x7fff,
+            max_instrs: 100,
+        };
+        assert_eq!(all.check("ecn", &bin, &sc), Ok(()));
+
+        let no_ecn = super::Msg {
+            primitives: !(1 << 2),
+            ..all
+        };
+        let err = no_ecn
+            .check("ecn", &bin, &sc)
+            .expect_err("ecn is not measured");
+        assert!(err.0.contains("Ack.ecn_bytes"), "{}", err.0);
+        assert_eq!(Code::of(&err), Some(Code::UnsupportedPrimitive));
+
+        let no_add = super::Msg { ops: !1, ..all };
+        let err = no_add
+            .check("ecn", &bin, &sc)
+            .expect_err("add is not supported");
+        assert!(err.0.contains("Add"), "{}", err.0);
+        assert_eq!(Code::of(&err), Some(Code::UnsupportedOp));
+
+        let short = super::Msg {
+            max_instrs: 1,
+            ..all
+        };
+        let err = short
+            .check("ecn", &bin, &sc)
+            .expect_err("too many instructions");
+        assert_eq!(Code::of(&err), Some(Code::ProgramTooLarge));
+    }
+
+    #[test]
+    fn check_mod() {
+        let (bin, sc) = crate::lang::compile(
+            b"(def (Report (volatile phase 0)))
+            (when true
+                (:= Report.phase (% Report.phase 8))
+            )",
+            &[],
+        )
+        .expect("compile");
+        // datapaths which predate `mod` support opcodes 0 through 14
+        let old = super::Msg {
+            ops: 0x7fff,
+            primitives: 0x7fff,
+            max_instrs: 100,
+        };
+        let err = old.check("mod", &bin, &sc).expect_err("mod is opcode 15");
+        assert_eq!(Code::of(&err), Some(Code::UnsupportedOp));
+
+        let new = super::Msg { ops: 0xffff, ..old };
+        assert_eq!(new.check("mod", &bin, &sc), Ok(()));
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_capabilities_msg() {
+        let m = super::Msg {
+            ops: 0x7fff,
+            primitives: 0x0102_0304,
+            max_instrs: 50,
+        };
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                7, 0, // CAPABILITIES
+                20, 0, // length = 20
+                0, 0, 0, 0, // sock_id = 0
+                0xff, 0x7f, 0, 0, // ops = 0x7fff
+                4, 3, 2, 1, // primitives = 0x01020304
+                50, 0, 0, 0, // max_instrs = 50
+            ],
+        );
+    }
+}
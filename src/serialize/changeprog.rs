@@ -1,29 +1,82 @@
 //! CCP sends this message to change the datapath program currently in use.
 
-use super::{u32_to_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use super::{
+    check_count, msg_len, u32_to_u8s, u64_from_u8s, u64_to_u8s, AsRawMsg, ParseError, RawMsg,
+};
 use crate::lang::Reg;
 use crate::{Error, Result};
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const CHANGEPROG: u8 = 4;
+/// The type of change program messages.
+pub const CHANGEPROG: u8 = 4;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
-    pub sid: u32,
+    pub sid: u64,
     pub program_uid: u32,
     pub num_fields: u32,
     pub fields: Vec<(Reg, u64)>,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    program_uid,
+    num_fields,
+    fields,
+});
+
+impl Msg {
+    /// A message switching to the program `program_uid`, setting `fields`, with its field count
+    /// to match.
+    pub fn new(sid: u64, program_uid: u32, fields: Vec<(Reg, u64)>) -> Self {
+        Msg {
+            sid,
+            program_uid,
+            num_fields: fields.len() as u32,
+            fields,
+        }
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "changeprog sid={} program_uid={} fields={}",
+            self.sid, self.program_uid, self.num_fields
+        )?;
+        for (reg, val) in &self.fields {
+            write!(f, " {}={}", reg, val)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        CHANGEPROG
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (
             CHANGEPROG,
-            HDR_LENGTH + 4 + 4 + self.num_fields * 13, // Reg size = 5, u64 size = 8
+            msg_len(4 + 4, self.num_fields as usize, 13), // Reg size = 5, u64 size = 8
             self.sid,
         )
     }
 
+    fn check_counts(&self) -> Result<()> {
+        check_count(
+            "change program",
+            "fields",
+            self.num_fields as usize,
+            self.fields.len(),
+        )
+    }
+
     fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 4];
         u32_to_u8s(&mut buf, self.program_uid);
@@ -36,29 +89,80 @@ impl AsRawMsg for Msg {
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 8];
         for f in &self.fields {
-            let reg =
-                f.0.clone()
-                    .into_iter()
-                    .map(|e| e.map_err(Error::from))
-                    .collect::<Result<Vec<u8>>>()?;
-            w.write_all(&reg[..])?;
+            w.write_all(&crate::lang::serialize_reg(&f.0)?)?;
             u64_to_u8s(&mut buf, f.1);
             w.write_all(&buf[..])?;
         }
         Ok(())
     }
 
-    // at least for now, portus does not have to worry about deserializing this message
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+    // CCP only sends these, but a datapath written in Rust parses them
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let program_uid = msg.get_u32(0)?;
+        let num_fields = msg.get_u32(1)?;
+        let fields = msg.get_bytes()?;
+        if fields.len() as u64 != u64::from(num_fields) * 13 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: num_fields as usize * 13,
+                actual: fields.len(),
+            }));
+        }
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid,
+            num_fields,
+            fields: fields
+                .chunks(13)
+                .map(|f| {
+                    Ok((
+                        crate::lang::deserialize_reg(&f[0..5])?,
+                        u64_from_u8s(&f[5..]),
+                    ))
+                })
+                .collect::<Result<_>>()?,
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lang::Reg;
+    use crate::lang::{Reg, Type};
+
+    check_msg!(
+        test_changeprog_none,
+        super::Msg,
+        super::Msg::new(7, 3, vec![]),
+        crate::serialize::Msg::Cp(cpm),
+        cpm
+    );
+
+    check_msg!(
+        test_changeprog_many,
+        super::Msg,
+        super::Msg::new(
+            7,
+            3,
+            vec![
+                (Reg::Implicit(4, Type::Num(None)), 14480),
+                (Reg::Control(2, Type::Num(None), true), u64::MAX),
+            ],
+        ),
+        crate::serialize::Msg::Cp(cpm),
+        cpm
+    );
+
+    #[test]
+    fn changeprog_short() {
+        let m = super::Msg::new(1, 7, vec![(Reg::Implicit(4, Type::Num(None)), 42)]);
+        let mut buf = crate::serialize::serialize(&m).expect("serialize");
+        // says it has more fields than it does
+        buf[12] = 2;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
 
     #[test]
+    #[cfg(not(feature = "checksum"))]
     fn serialize_changeprog_msg() {
         let m = super::Msg {
             sid: 1,
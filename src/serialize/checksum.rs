@@ -0,0 +1,284 @@
+//! Checksums over messages, to catch frames corrupted on the way, e.g. over a character device or
+//! a lossy network, before an algorithm acts on absurd values.
+//!
+//! The header has no room to spare, so a checksummed message has `CHECKSUMMED` set in its type,
+//! and a 16-bit internet checksum (RFC 1071) of everything before it, header included, appended.
+//! The header's length counts the checksum:
+//!
+//! ```text
+//! ----------------------------------------------------------
+//! | Msg Type      | Len (B)  | Uint32    | Body | Checksum |
+//! | | CHECKSUMMED | (2 B)    | (32 bits) |      | (2 B)    |
+//! ----------------------------------------------------------
+//! ```
+//!
+//! `Msg::from_buf` checks the checksum of any message which has one, and rejects it with
+//! `CorruptMsgError` if it does not match. With the `checksum` feature, `serialize` adds a
+//! checksum to every message, and `Msg::from_buf` rejects messages without one too, so both
+//! peers must be built with it.
+
+use super::{set_len, u16_from_u8s, u16_to_u8s, HDR_LENGTH};
+use crate::{CorruptMsgError, Error, Result};
+
+/// Set in the type of a message with a checksum.
+pub const CHECKSUMMED: u16 = 0x4000;
+
+pub(crate) const CHECKSUM_LENGTH: usize = 2;
+
+/// Whether the message at the start of `buf` has a checksum.
+pub fn is_checksummed(buf: &[u8]) -> bool {
+    buf.len() >= HDR_LENGTH as usize && u16_from_u8s(&buf[0..2]) & CHECKSUMMED != 0
+}
+
+/// The internet checksum of `buf`: the ones' complement of the ones' complement sum of its
+/// 16-bit little-endian words, with an odd last byte padded with 0.
+pub fn checksum(buf: &[u8]) -> u16 {
+    let mut sum = Sum::default();
+    sum.add(buf);
+    sum.finish()
+}
+
+// The internet checksum of bytes added a piece at a time, e.g. as a message is written out.
+#[derive(Default)]
+pub(crate) struct Sum {
+    sum: u64,
+    len: usize,
+}
+
+impl Sum {
+    pub(crate) fn add(&mut self, buf: &[u8]) {
+        for b in buf {
+            // the byte's place in its 16-bit word
+            self.sum += u64::from(*b) << (8 * (self.len % 2));
+            self.len += 1;
+        }
+    }
+
+    pub(crate) fn finish(&self) -> u16 {
+        let mut sum = self.sum;
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+
+        !(sum as u16)
+    }
+}
+
+// Mark `msg`, a serialized message or just its header, as checksummed, and set its length to
+// `len` plus the checksum. It is an error if that is too long for the header.
+pub(crate) fn mark(msg: &mut [u8], len: usize) -> Result<()> {
+    set_len(msg, len + CHECKSUM_LENGTH)?;
+    let typ = u16_from_u8s(&msg[0..2]) | CHECKSUMMED;
+    u16_to_u8s(&mut msg[0..2], typ);
+    Ok(())
+}
+
+// Mark `msg`, a serialized message, as checksummed, and append its checksum. It is an error if
+// the message is then too long for its header.
+pub(crate) fn append(msg: &mut Vec<u8>) -> Result<()> {
+    let len = msg.len();
+    mark(msg, len)?;
+    let mut sum = [0u8; CHECKSUM_LENGTH];
+    u16_to_u8s(&mut sum, checksum(msg));
+    msg.extend_from_slice(&sum);
+    Ok(())
+}
+
+// Check the checksum at the end of `msg`, a whole checksummed message, and return the length of
+// the message without it.
+pub(crate) fn verify(msg: &[u8]) -> Result<usize> {
+    if msg.len() < HDR_LENGTH as usize + CHECKSUM_LENGTH {
+        return Err(Error::from(CorruptMsgError));
+    }
+
+    let len = msg.len() - CHECKSUM_LENGTH;
+    if checksum(&msg[..len]) != u16_from_u8s(&msg[len..]) {
+        return Err(Error::from(CorruptMsgError));
+    }
+
+    Ok(len)
+}
+
+// With the `checksum` feature, checksum `msg`, a message a test built or changed by hand, as
+// `serialize` would: replace the checksum it has, or append one.
+#[cfg(test)]
+pub(crate) fn fix_up(mut msg: Vec<u8>) -> Vec<u8> {
+    if cfg!(feature = "checksum") {
+        if is_checksummed(&msg) {
+            msg.truncate(msg.len() - CHECKSUM_LENGTH);
+        }
+        append(&mut msg).expect("append checksum");
+    }
+
+    msg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, checksum, is_checksummed, verify, Sum};
+
+    #[test]
+    fn rfc1071_example() {
+        // the example from RFC 1071 section 3, whose words are big-endian
+        let buf = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        let le: Vec<u8> = buf.chunks(2).flat_map(|w| vec![w[1], w[0]]).collect();
+        assert_eq!(checksum(&le), !0xddf2);
+        assert_eq!(checksum(&[]), 0xffff);
+    }
+
+    #[test]
+    fn sum_in_pieces() {
+        let buf: Vec<u8> = (0..=254).collect();
+        for &split in &[0, 1, 2, 7, 128, 254, 255] {
+            let mut sum = Sum::default();
+            sum.add(&buf[..split]);
+            sum.add(&buf[split..]);
+            assert_eq!(sum.finish(), checksum(&buf), "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn append_and_verify() {
+        let mut msg = crate::serialize::serialize(&crate::serialize::heartbeat::Msg { seq: 7 })
+            .expect("serialize heartbeat");
+        if !is_checksummed(&msg) {
+            append(&mut msg).expect("append checksum");
+        }
+        assert!(is_checksummed(&msg));
+        assert_eq!(u16::from_le_bytes([msg[2], msg[3]]) as usize, msg.len());
+        assert_eq!(verify(&msg), Ok(msg.len() - 2));
+
+        let last = msg.len() - 1;
+        msg[last] ^= 1;
+        assert_eq!(
+            verify(&msg),
+            Err(crate::Error::from(crate::CorruptMsgError))
+        );
+    }
+
+    // Every single flipped bit in every type of message is caught: the message either fails its
+    // checksum or no longer has a valid header.
+    #[test]
+    fn flipped_bits() {
+        use crate::serialize::{self, Msg};
+
+        let (bin, sc) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        let msgs = vec![
+            serialize::serialize(&serialize::create::Msg {
+                sid: 1,
+                init_cwnd: 14480,
+                mss: 1448,
+                src_ip: 1,
+                src_port: 4242,
+                dst_ip: 2,
+                dst_port: 4243,
+                cong_alg: None,
+            }),
+            serialize::serialize(&serialize::measure::Msg {
+                sid: 1,
+                program_uid: 7,
+                num_fields: 2,
+                fields: vec![42, 4242],
+                types: None,
+                timestamp: None,
+                strings: vec![],
+            }),
+            serialize::serialize(&serialize::measure_batch::Msg {
+                measurements: vec![serialize::measure::Msg {
+                    sid: 2,
+                    program_uid: 7,
+                    num_fields: 1,
+                    fields: vec![42],
+                    types: None,
+                    timestamp: None,
+                    strings: vec![],
+                }],
+            }),
+            serialize::serialize(&serialize::install::Msg {
+                sid: 0,
+                program_uid: sc.program_uid,
+                num_events: bin.events.len() as u32,
+                num_instrs: bin.instrs.len() as u32,
+                instrs: bin,
+                names: None,
+            }),
+            serialize::serialize(&serialize::update_field::Msg {
+                sid: 1,
+                num_fields: 1,
+                fields: vec![(
+                    crate::lang::Reg::Implicit(4, crate::lang::Type::Num(None)),
+                    14480,
+                )],
+                widths: None,
+            }),
+            serialize::serialize(&serialize::changeprog::Msg {
+                sid: 1,
+                program_uid: 7,
+                num_fields: 0,
+                fields: vec![],
+            }),
+            serialize::serialize(&serialize::ready::Msg { id: 3 }),
+            serialize::serialize(&serialize::heartbeat::Msg { seq: 9 }),
+            serialize::serialize(&serialize::capabilities::Msg {
+                ops: 0x7fff,
+                primitives: 0x3,
+                max_instrs: 50,
+            }),
+            serialize::serialize(&serialize::version::Msg::current()),
+            serialize::serialize(&serialize::install_ack::Msg {
+                sid: 1,
+                program_uid: 7,
+                status: serialize::codes::Code::UnknownProgram,
+            }),
+            serialize::serialize(&serialize::uninstall::Msg {
+                sid: 1,
+                program_uid: 7,
+            }),
+            serialize::serialize(&serialize::teardown::Msg { sid: 1, reason: 2 }),
+            serialize::serialize(&serialize::fin::Msg {
+                sid: 1,
+                bytes: 1 << 40,
+                packets: 42,
+            }),
+            serialize::serialize(&serialize::error::Msg {
+                sid: 1,
+                code: serialize::codes::Code::ProgramTooLarge,
+                msg: String::from("too many instructions"),
+            }),
+        ];
+
+        for msg in msgs {
+            let mut msg = msg.expect("serialize");
+            if !is_checksummed(&msg) {
+                append(&mut msg).expect("append checksum");
+            }
+
+            for bit in 0..msg.len() * 8 {
+                // without the checksum feature, a message which loses its checksum flag is taken
+                // for one which never had a checksum
+                if !cfg!(feature = "checksum") && bit == 14 {
+                    continue;
+                }
+
+                msg[bit / 8] ^= 1 << (bit % 8);
+                match Msg::from_buf(&msg) {
+                    Err(e) if e == crate::Error::from(crate::CorruptMsgError) => (),
+                    Ok((Msg::Other(m), _)) if m.typ == 255 => (),
+                    Err(e) => panic!("bit {} of message type {}: {}", bit, msg[0], e.0),
+                    Ok((m, _)) => panic!("bit {} of message type {}: parsed {:?}", bit, msg[0], m),
+                }
+                msg[bit / 8] ^= 1 << (bit % 8);
+            }
+
+            assert!(Msg::from_buf(&msg).is_ok());
+        }
+    }
+}
@@ -0,0 +1,214 @@
+//! Codes for what went wrong, or went right, which CCP and datapaths agree on: the `code` of an
+//! error message, and the `status` of an install acknowledgement.
+//!
+//! Errors from parsing and checking messages which one of these describes carry it too, so an
+//! algorithm can match on what went wrong rather than on the error's text, and a datapath which
+//! fails to parse a message from CCP can send it back in an error message:
+//!
+//! ```
+//! use portus::serialize::codes::Code;
+//!
+//! let err = Code::ProgramTooLarge.error("80 instructions, at most 50");
+//! match Code::of(&err) {
+//!     Some(Code::ProgramTooLarge) => (),
+//!     _ => unreachable!(),
+//! }
+//! ```
+
+use crate::Error;
+use std::fmt;
+
+/// A code one side sends the other. Codes this portus does not know, e.g. from a newer peer, or
+/// a datapath's own numbering, are `Unknown`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Code {
+    /// Nothing went wrong, e.g. the datapath switched the flow's program.
+    Ok,
+    /// The message was for a flow the datapath does not know.
+    UnknownFlow,
+    /// The message named a program the datapath does not have installed.
+    UnknownProgram,
+    /// The program has more instructions or events than the datapath runs.
+    ProgramTooLarge,
+    /// The program uses an event the datapath does not support.
+    UnsupportedEvent,
+    /// The program uses an instruction the datapath does not support.
+    UnsupportedOp,
+    /// The program reads a primitive the datapath does not measure.
+    UnsupportedPrimitive,
+    /// The program uses a register past the last of its kind.
+    RegisterOutOfRange,
+    /// The message could not be parsed.
+    MalformedMsg,
+    /// A code this portus does not know.
+    Unknown(u32),
+}
+
+#[cfg(feature = "serde")]
+serde_enum!(Code {
+    Ok,
+    UnknownFlow,
+    UnknownProgram,
+    ProgramTooLarge,
+    UnsupportedEvent,
+    UnsupportedOp,
+    UnsupportedPrimitive,
+    RegisterOutOfRange,
+    MalformedMsg,
+    Unknown(code),
+});
+
+impl Code {
+    /// Every code but `Unknown`, in order.
+    pub const KNOWN: [Code; 9] = [
+        Code::Ok,
+        Code::UnknownFlow,
+        Code::UnknownProgram,
+        Code::ProgramTooLarge,
+        Code::UnsupportedEvent,
+        Code::UnsupportedOp,
+        Code::UnsupportedPrimitive,
+        Code::RegisterOutOfRange,
+        Code::MalformedMsg,
+    ];
+
+    /// An error for this code, saying what in particular went wrong. `Code::of` finds the code
+    /// again; `Error::from(code)` is the error without any detail.
+    pub fn error<D: fmt::Display>(self, detail: D) -> Error {
+        Error::from(Detailed(self, detail.to_string()))
+    }
+
+    /// The code of `err`, if it was made with one, by `Code::error` or `Error::from(code)`.
+    pub fn of(err: &Error) -> Option<Code> {
+        Code::KNOWN.iter().copied().find(|code| {
+            let bare = Error::from(*code).0;
+            err.0 == bare
+                || err
+                    .0
+                    .strip_prefix(&bare)
+                    .is_some_and(|rest| rest.starts_with(": "))
+        })
+    }
+}
+
+impl From<u32> for Code {
+    fn from(code: u32) -> Self {
+        Code::KNOWN
+            .get(code as usize)
+            .copied()
+            .unwrap_or(Code::Unknown(code))
+    }
+}
+
+impl From<Code> for u32 {
+    fn from(code: Code) -> Self {
+        match code {
+            Code::Ok => 0,
+            Code::UnknownFlow => 1,
+            Code::UnknownProgram => 2,
+            Code::ProgramTooLarge => 3,
+            Code::UnsupportedEvent => 4,
+            Code::UnsupportedOp => 5,
+            Code::UnsupportedPrimitive => 6,
+            Code::RegisterOutOfRange => 7,
+            Code::MalformedMsg => 8,
+            Code::Unknown(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for Code {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Code::Ok => write!(f, "ok"),
+            Code::UnknownFlow => write!(f, "unknown flow"),
+            Code::UnknownProgram => write!(f, "unknown program"),
+            Code::ProgramTooLarge => write!(f, "program too large"),
+            Code::UnsupportedEvent => write!(f, "unsupported event"),
+            Code::UnsupportedOp => write!(f, "unsupported instruction"),
+            Code::UnsupportedPrimitive => write!(f, "unsupported primitive"),
+            Code::RegisterOutOfRange => write!(f, "register out of range"),
+            Code::MalformedMsg => write!(f, "malformed message"),
+            Code::Unknown(code) => write!(f, "code {}", code),
+        }
+    }
+}
+
+impl std::error::Error for Code {}
+
+// A code, and what in particular went wrong.
+#[derive(Debug)]
+pub(crate) struct Detailed(pub(crate) Code, pub(crate) String);
+
+impl fmt::Display for Detailed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.0, self.1)
+    }
+}
+
+impl std::error::Error for Detailed {}
+
+// For the parse layer's errors in `lang`, which become `Error`s as `Code::error` makes them.
+impl From<Detailed> for crate::lang::Error {
+    fn from(d: Detailed) -> Self {
+        crate::lang::Error(d.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Code;
+    use crate::Error;
+
+    #[test]
+    fn codes_round_trip() {
+        for (i, code) in Code::KNOWN.iter().enumerate() {
+            assert_eq!(u32::from(*code), i as u32);
+            assert_eq!(Code::from(i as u32), *code);
+        }
+
+        for n in &[Code::KNOWN.len() as u32, 100, u32::MAX] {
+            assert_eq!(Code::from(*n), Code::Unknown(*n));
+            assert_eq!(u32::from(Code::Unknown(*n)), *n);
+        }
+
+        // a new code does not compile here until it is in `KNOWN` too
+        for code in Code::KNOWN.iter().chain(&[Code::Unknown(100)]) {
+            let known = match code {
+                Code::Ok
+                | Code::UnknownFlow
+                | Code::UnknownProgram
+                | Code::ProgramTooLarge
+                | Code::UnsupportedEvent
+                | Code::UnsupportedOp
+                | Code::UnsupportedPrimitive
+                | Code::RegisterOutOfRange
+                | Code::MalformedMsg => true,
+                Code::Unknown(_) => false,
+            };
+            assert_eq!(Code::KNOWN.contains(code), known);
+        }
+    }
+
+    #[test]
+    fn codes_of_errors() {
+        for code in Code::KNOWN.iter() {
+            assert_eq!(Code::of(&Error::from(*code)), Some(*code));
+            assert_eq!(Code::of(&code.error("details")), Some(*code));
+        }
+
+        let err = Code::ProgramTooLarge.error(80);
+        assert_eq!(err.0, "portus err: program too large: 80");
+        // errors made without a code have none, even if they say one
+        assert_eq!(Code::of(&Error(String::from("program too large"))), None);
+        assert_eq!(Code::of(&Error::from(Code::Unknown(100))), None);
+        assert_eq!(
+            Code::of(&Code::UnknownFlow.error("")),
+            Some(Code::UnknownFlow)
+        );
+        assert_eq!(
+            Code::of(&Error(String::from("portus err: program too larger"))),
+            None
+        );
+    }
+}
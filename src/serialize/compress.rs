@@ -0,0 +1,219 @@
+//! Compression of long messages, e.g. to install large datapath programs over a remote socket
+//! (see [`Backend::set_compression`](../../ipc/struct.Backend.html#method.set_compression)).
+//!
+//! A compressed message keeps the header of the message it replaces, wide socket id included,
+//! except that its type has `COMPRESSED` set and its length is that of the compressed message.
+//! The original message's length follows the header, and then its body, compressed with LZ4:
+//!
+//! ```text
+//! ----------------------------------------------------------------
+//! | Msg Type     | Len (B)  | Uint32    | Orig Len (B) | LZ4 body |
+//! | | COMPRESSED | (2 B)    | (32 bits) | (4 B)        |          |
+//! ----------------------------------------------------------------
+//! ```
+//!
+//! Peers which never send compressed messages do not need to understand them. Compressing and
+//! decompressing messages needs the `compression` feature; without it, compressed messages are
+//! received as malformed.
+
+#[cfg(feature = "compression")]
+use super::{hdr_len, is_extended, set_len, u16_to_u8s, u32_from_u8s, u32_to_u8s};
+use super::{u16_from_u8s, HDR_LENGTH};
+use crate::Result;
+
+/// Set in the type of a compressed message.
+pub const COMPRESSED: u16 = 0x8000;
+
+// The original length follows the header.
+#[cfg(feature = "compression")]
+const ORIG_LEN_LENGTH: usize = 4;
+
+// The longest message with an extended length to decompress, so that a corrupt original length
+// does not allocate without bound.
+#[cfg(feature = "compression")]
+const MAX_EXTENDED_LEN: usize = 1 << 24;
+
+/// Whether the message at the start of `buf` is compressed.
+pub fn is_compressed(buf: &[u8]) -> bool {
+    buf.len() >= HDR_LENGTH as usize && u16_from_u8s(&buf[0..2]) & COMPRESSED != 0
+}
+
+/// Compress `msg`, a serialized message, if that makes it shorter, and otherwise return `None`.
+#[cfg(feature = "compression")]
+pub fn compress(msg: &[u8]) -> Option<Vec<u8>> {
+    let hdr_len = hdr_len(msg);
+    if msg.len() < hdr_len || is_compressed(msg) {
+        return None;
+    }
+
+    let body = lz4_flex::block::compress(&msg[hdr_len..]);
+    let len = hdr_len + ORIG_LEN_LENGTH + body.len();
+    if len >= msg.len() {
+        return None;
+    }
+
+    let mut out = vec![0u8; hdr_len + ORIG_LEN_LENGTH];
+    out[..hdr_len].copy_from_slice(&msg[..hdr_len]);
+    u16_to_u8s(&mut out[0..2], u16_from_u8s(&msg[0..2]) | COMPRESSED);
+    set_len(&mut out, len).ok()?;
+    u32_to_u8s(&mut out[hdr_len..], msg.len() as u32);
+    out.extend_from_slice(&body);
+    Some(out)
+}
+
+/// Decompress the compressed message at the start of `buf` into `out`, replacing what it held,
+/// and return the compressed message's length.
+#[cfg(feature = "compression")]
+pub fn decompress(buf: &[u8], out: &mut Vec<u8>) -> Result<usize> {
+    use crate::Error;
+
+    let hdr_len = hdr_len(buf);
+    let len = super::frame_len(buf)?;
+    if len < hdr_len + ORIG_LEN_LENGTH {
+        return Err(Error(format!(
+            "compressed message too short: {} bytes",
+            len
+        )));
+    }
+
+    let orig_len = u32_from_u8s(&buf[hdr_len..hdr_len + ORIG_LEN_LENGTH]) as usize;
+    let max_len = if is_extended(buf) {
+        MAX_EXTENDED_LEN
+    } else {
+        usize::from(u16::MAX)
+    };
+    if orig_len < hdr_len || orig_len > max_len {
+        return Err(Error(format!(
+            "nonsensical decompressed length: {} bytes",
+            orig_len
+        )));
+    }
+
+    out.clear();
+    out.extend_from_slice(&buf[..hdr_len]);
+    out.resize(orig_len, 0);
+    u16_to_u8s(&mut out[0..2], u16_from_u8s(&buf[0..2]) & !COMPRESSED);
+    set_len(out, orig_len)?;
+    let body_len =
+        lz4_flex::block::decompress_into(&buf[hdr_len + ORIG_LEN_LENGTH..len], &mut out[hdr_len..])
+            .map_err(|e| Error(format!("could not decompress message: {}", e)))?;
+    if body_len != orig_len - hdr_len {
+        return Err(Error(format!(
+            "decompressed message is {} bytes, but its header says {}",
+            hdr_len + body_len,
+            orig_len
+        )));
+    }
+
+    Ok(len)
+}
+
+/// Without the `compression` feature, compressed messages cannot be decompressed.
+#[cfg(not(feature = "compression"))]
+pub fn decompress(_buf: &[u8], _out: &mut Vec<u8>) -> Result<usize> {
+    Err(crate::Error(String::from(
+        "received a compressed message, but portus was built without the compression feature",
+    )))
+}
+
+#[cfg(all(test, feature = "compression"))]
+mod tests {
+    use super::{compress, decompress, is_compressed};
+    use crate::serialize::{self, install};
+
+    // a long program, as a complex algorithm's might be
+    fn install_msg(sid: u64) -> Vec<u8> {
+        let mut src = String::from("(def (Report (volatile acked 0) (volatile rtt 0)))\n");
+        for i in 0..40 {
+            src.push_str(&format!(
+                "(when (> Flow.rtt_sample_us {})\n\
+                 (:= Report.acked (+ Report.acked Ack.bytes_acked))\n\
+                 (:= Report.rtt Flow.rtt_sample_us)\n)\n",
+                i * 10
+            ));
+        }
+        let (bin, _) = crate::lang::compile(src.as_bytes(), &[]).expect("compile");
+        serialize::serialize(&install::Msg {
+            sid,
+            program_uid: 7,
+            num_events: bin.events.len() as u32,
+            num_instrs: bin.instrs.len() as u32,
+            instrs: bin,
+            names: None,
+        })
+        .expect("serialize install")
+    }
+
+    #[test]
+    fn round_trip() {
+        let msg = install_msg(1);
+        let compressed = compress(&msg).expect("compress");
+        assert!(is_compressed(&compressed));
+        assert!(!is_compressed(&msg));
+        assert!(
+            compressed.len() * 2 < msg.len(),
+            "{} bytes compressed to {}",
+            msg.len(),
+            compressed.len()
+        );
+
+        let mut out = vec![];
+        assert_eq!(decompress(&compressed, &mut out), Ok(compressed.len()));
+        assert_eq!(out, msg);
+    }
+
+    #[test]
+    fn round_trip_wide_sid() {
+        let sid = 0x1234_5678_9abc_def0;
+        let msg = install_msg(sid);
+        let compressed = compress(&msg).expect("compress");
+        assert_eq!(serialize::sid(&compressed), Some(sid));
+
+        let mut out = vec![];
+        assert_eq!(decompress(&compressed, &mut out), Ok(compressed.len()));
+        assert_eq!(out, msg);
+    }
+
+    #[test]
+    fn round_trip_extended_len() {
+        let (mut bin, _) = crate::lang::compile(
+            b"(def (Report.acked 0))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            )",
+            &[],
+        )
+        .expect("compile");
+        bin.instrs = bin.instrs.iter().cycle().take(5000).cloned().collect();
+        let msg = serialize::serialize(&install::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_events: bin.events.len() as u32,
+            num_instrs: bin.instrs.len() as u32,
+            instrs: bin,
+            names: None,
+        })
+        .expect("serialize install");
+        assert!(serialize::is_extended(&msg));
+
+        let compressed = compress(&msg).expect("compress");
+        assert!(serialize::is_extended(&compressed));
+        let mut out = vec![];
+        assert_eq!(decompress(&compressed, &mut out), Ok(compressed.len()));
+        assert_eq!(out, msg);
+    }
+
+    #[test]
+    fn incompressible() {
+        let msg = serialize::serialize(&serialize::heartbeat::Msg { seq: 3 }).expect("serialize");
+        assert_eq!(compress(&msg), None);
+    }
+
+    #[test]
+    fn corrupt() {
+        let mut compressed = compress(&install_msg(1)).expect("compress");
+        let n = compressed.len();
+        compressed[n - 8..].copy_from_slice(&[0xff; 8]);
+        assert!(decompress(&compressed, &mut vec![]).is_err());
+    }
+}
@@ -1,25 +1,71 @@
 //! Message sent from datapath to CCP when a new flow starts.
+//!
+//! After the flow's numbers, it has 64 bytes for the name of the congestion control the flow's
+//! socket asked for, nul-terminated. A datapath which does not know sends no name, or older ones
+//! none of the 64 bytes at all; either parses, without one.
 
 use super::{u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
 use crate::{Error, Result};
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const CREATE: u8 = 0;
+/// The type of create messages.
+pub const CREATE: u8 = 0;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
-    pub sid: u32,
+    pub sid: u64,
     pub init_cwnd: u32,
     pub mss: u32,
     pub src_ip: u32,
     pub src_port: u32,
     pub dst_ip: u32,
     pub dst_port: u32,
+    /// The congestion control the flow's socket asked for, e.g. an application's
+    /// `setsockopt(TCP_CONGESTION, "cubic")`, at most 63 bytes of UTF-8. A name received which is
+    /// not UTF-8 is empty.
     pub cong_alg: Option<String>,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    init_cwnd,
+    mss,
+    src_ip,
+    src_port,
+    dst_ip,
+    dst_port,
+    cong_alg,
+});
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "create sid={} init_cwnd={} mss={} src_ip={} src_port={} dst_ip={} dst_port={}",
+            self.sid,
+            self.init_cwnd,
+            self.mss,
+            self.src_ip,
+            self.src_port,
+            self.dst_ip,
+            self.dst_port
+        )?;
+        if let Some(ref alg) = self.cong_alg {
+            write!(f, " cong_alg={}", alg)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        CREATE
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (CREATE, HDR_LENGTH + 6 * 4 + 64, self.sid)
     }
 
@@ -46,7 +92,7 @@ impl AsRawMsg for Msg {
             if c.len() > 63 {
                 return Err(Error(String::from("Cong alg name too long")));
             } else {
-                buf.copy_from_slice(c.as_bytes());
+                buf[..c.len()].copy_from_slice(c.as_bytes());
             }
         }
 
@@ -55,26 +101,30 @@ impl AsRawMsg for Msg {
     }
 
     fn from_raw_msg(msg: RawMsg) -> Result<Self> {
-        let u32s = unsafe { msg.get_u32s() }?;
         let b = msg.get_bytes()?;
-        let cong_alg = if b[0] == 0 {
-            None
-        } else {
-            let end = b.iter().position(|&c| c == b'\0').unwrap_or(b.len());
-            if let Ok(s) = std::ffi::CStr::from_bytes_with_nul(&b[..end+1]) {
-                Some(s.to_str()?.to_owned())
-            } else {
-                None
-            }
+        // a name without its terminating nul is no name
+        let cong_alg = match b.iter().position(|&c| c == b'\0') {
+            None | Some(0) => None,
+            Some(end) => Some(
+                std::str::from_utf8(&b[..end])
+                    .map(str::to_owned)
+                    .unwrap_or_default(),
+            ),
         };
+        // a datapath which sends no name may leave out the name's bytes
+        if !b.is_empty() && b.len() < 64 {
+            msg.inconsistent(|| format!("{} bytes of a 64 byte name", b.len()))?;
+        }
+        msg.check_len(6 * 4 + 64)?;
+
         Ok(Msg {
             sid: msg.sid,
-            init_cwnd: u32s[0],
-            mss: u32s[1],
-            src_ip: u32s[2],
-            src_port: u32s[3],
-            dst_ip: u32s[4],
-            dst_port: u32s[5],
+            init_cwnd: msg.get_u32(0)?,
+            mss: msg.get_u32(1)?,
+            src_ip: msg.get_u32(2)?,
+            src_port: msg.get_u32(3)?,
+            dst_ip: msg.get_u32(4)?,
+            dst_port: msg.get_u32(5)?,
             cong_alg,
         })
     }
@@ -101,4 +151,107 @@ mod tests {
             cong_alg: None,
         }
     );
+
+    check_create_msg!(
+        test_create_cong_alg,
+        super::Msg {
+            sid: 15,
+            init_cwnd: 1448 * 10,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4242,
+            cong_alg: Some(String::from("reno")),
+        }
+    );
+
+    // the 24 bytes of a create message's numbers, as serialized
+    fn create_body() -> Vec<u8> {
+        crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 1,
+            src_port: 4242,
+            dst_ip: 2,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize")[8..32]
+            .to_vec()
+    }
+
+    fn parse(body: &[u8]) -> super::Msg {
+        let mut buf = vec![0, 0, 8 + body.len() as u8, 0, 1, 0, 0, 0];
+        buf.extend_from_slice(body);
+        let buf = crate::serialize::checksum::fix_up(buf);
+        match crate::serialize::Msg::from_buf(&buf) {
+            Ok((crate::serialize::Msg::Cr(m), _)) => m,
+            m => panic!("expected a create message, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn create_without_name() {
+        let m = parse(&create_body());
+        assert_eq!((m.sid, m.init_cwnd, m.dst_port), (1, 14480, 4243));
+        assert_eq!(m.cong_alg, None);
+    }
+
+    #[test]
+    fn create_name() {
+        let mut body = create_body();
+        body.extend_from_slice(b"cubic");
+        body.resize(24 + 64, 0);
+        assert_eq!(parse(&body).cong_alg, Some(String::from("cubic")));
+
+        body[24..29].copy_from_slice(b"cu\xffic");
+        assert_eq!(parse(&body).cong_alg, Some(String::new()));
+    }
+
+    #[test]
+    fn create_name_too_long() {
+        let m = super::Msg {
+            sid: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 1,
+            src_port: 4242,
+            dst_ip: 2,
+            dst_port: 4243,
+            cong_alg: Some("x".repeat(64)),
+        };
+        assert!(crate::serialize::serialize(&m).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_create_msg() {
+        let m = super::Msg {
+            sid: 0x0102_0304,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0x0a00_0001,
+            src_port: 4242,
+            dst_ip: 0x0a00_0002,
+            dst_port: 4243,
+            cong_alg: None,
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        let mut want = vec![
+            0, 0, // CREATE
+            96, 0, // length = 96
+            4, 3, 2, 1, // sock_id = 0x01020304
+            0x90, 0x38, 0, 0, // init_cwnd = 14480
+            0xa8, 0x05, 0, 0, // mss = 1448
+            1, 0, 0, 0x0a, // src_ip = 10.0.0.1
+            0x92, 0x10, 0, 0, // src_port = 4242
+            2, 0, 0, 0x0a, // dst_ip = 10.0.0.2
+            0x93, 0x10, 0, 0, // dst_port = 4243
+        ];
+        want.extend_from_slice(&[0u8; 64]); // no cong_alg
+        assert_eq!(buf, want);
+    }
 }
@@ -0,0 +1,163 @@
+//! Message sent from datapath to CCP when it cannot do something CCP asked of it, e.g. install a
+//! program with more instructions than it runs, or run a program using an event it does not
+//! support. The header's socket id is the flow's, or 0 for errors which are not about a flow.
+
+use super::codes::Code;
+use super::{msg_len, u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of error messages.
+pub const ERROR: u8 = 9;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u64,
+    /// What went wrong.
+    pub code: Code,
+    /// A description of the error for people. It is sent as UTF-8; invalid sequences received
+    /// are replaced with `U+FFFD`.
+    pub msg: String,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { sid, code, msg });
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "error sid={} code={:?} {:?}",
+            self.sid, self.code, self.msg
+        )
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        ERROR
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (ERROR, msg_len(4, self.msg.len(), 1), self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, u32::from(self.code));
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(self.msg.as_bytes())?;
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 4,
+                actual: b.len(),
+            }));
+        }
+
+        Ok(Msg {
+            sid: msg.sid,
+            code: Code::from(u32_from_u8s(&b[0..4])),
+            msg: String::from_utf8_lossy(&b[4..]).into_owned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_error_1,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            code: super::Code::ProgramTooLarge,
+            msg: String::from("program has 80 instructions, at most 50 allowed"),
+        },
+        crate::serialize::Msg::Err(em),
+        em
+    );
+
+    check_msg!(
+        test_error_empty,
+        super::Msg,
+        super::Msg {
+            sid: 0,
+            code: super::Code::UnknownFlow,
+            msg: String::new(),
+        },
+        crate::serialize::Msg::Err(em),
+        em
+    );
+
+    check_msg!(
+        test_error_unicode,
+        super::Msg,
+        super::Msg {
+            sid: 3,
+            code: super::Code::Unknown(100),
+            msg: String::from("événement non pris en charge ✗"),
+        },
+        crate::serialize::Msg::Err(em),
+        em
+    );
+
+    #[test]
+    fn error_invalid_utf8() {
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            code: super::Code::MalformedMsg,
+            msg: String::from("bad: xx"),
+        })
+        .expect("serialize");
+        let at = buf.windows(2).position(|w| w == b"xx").expect("msg");
+        buf[at..at + 2].copy_from_slice(&[0xc3, 0x28]);
+        let buf = crate::serialize::checksum::fix_up(buf);
+        match crate::serialize::Msg::from_buf(&buf) {
+            Ok((crate::serialize::Msg::Err(em), _)) => {
+                assert_eq!(em.msg, "bad: \u{fffd}(");
+            }
+            m => panic!("expected an error message, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn error_too_long() {
+        let m = super::Msg {
+            sid: 1,
+            code: super::Code::MalformedMsg,
+            msg: "x".repeat(usize::from(u16::MAX)),
+        };
+        assert!(crate::serialize::serialize(&m).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_error_msg() {
+        let m = super::Msg {
+            sid: 1,
+            code: super::Code::Unknown(0x0102_0304),
+            msg: String::from("ab"),
+        };
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                9, 0, // ERROR
+                14, 0, // length = 14
+                1, 0, 0, 0, // sock_id = 1
+                4, 3, 2, 1, // code = 0x01020304
+                b'a', b'b', // msg = "ab"
+            ],
+        );
+    }
+}
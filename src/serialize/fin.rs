@@ -0,0 +1,136 @@
+//! Message sent from datapath to CCP when a flow ends, with how much the flow sent in all. CCP
+//! then closes the flow (see `Flow::close_with_counts`), and ignores any further measurements for
+//! it.
+//!
+//! Older datapaths instead end a flow with a measurement with no fields, which CCP still takes
+//! to mean the same from them. Fin messages arrived in minor version 9 (see
+//! [`version`](../version/index.html)), so datapaths should send them only to a CCP which
+//! announced that; from such a datapath, a measurement with no fields is just that.
+
+use super::{u64_from_u8s, u64_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of fin messages.
+pub const FIN: u8 = 14;
+
+// The minor version which introduced fin messages.
+pub(crate) const FIN_MINOR: u32 = 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u64,
+    /// How many bytes the flow sent in all, as the datapath counts them.
+    pub bytes: u64,
+    /// How many packets the flow sent in all, as the datapath counts them.
+    pub packets: u64,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    bytes,
+    packets,
+});
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "fin sid={} bytes={} packets={}",
+            self.sid, self.bytes, self.packets
+        )
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        FIN
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (FIN, HDR_LENGTH + 2 * 8, self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 8];
+        for x in &[self.bytes, self.packets] {
+            u64_to_u8s(&mut buf, *x);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 2 * 8 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 2 * 8,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(2 * 8)?;
+
+        Ok(Msg {
+            sid: msg.sid,
+            bytes: u64_from_u8s(&b[0..8]),
+            packets: u64_from_u8s(&b[8..16]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_fin_1,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            bytes: 1 << 40,
+            packets: 42,
+        },
+        crate::serialize::Msg::Fin(fm),
+        fm
+    );
+
+    #[test]
+    fn fin_short() {
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            bytes: 2,
+            packets: 3,
+        })
+        .expect("serialize");
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_fin_msg() {
+        let m = super::Msg {
+            sid: 1,
+            bytes: 0x0102_0304_0506,
+            packets: 2,
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                14, 0, // FIN
+                24, 0, // length = 24
+                1, 0, 0, 0, // sock_id = 1
+                6, 5, 4, 3, 2, 1, 0, 0, // bytes = 0x010203040506
+                2, 0, 0, 0, 0, 0, 0, 0, // packets = 2
+            ],
+        );
+    }
+}
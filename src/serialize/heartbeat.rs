@@ -0,0 +1,77 @@
+//! Message CCP sends to the datapath periodically when heartbeats are enabled. The datapath
+//! echoes it back unchanged to show that it is still alive.
+
+use super::{u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use crate::Result;
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of heartbeat messages.
+pub const HEARTBEAT: u8 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub seq: u32,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { seq });
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "heartbeat seq={}", self.seq)
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        HEARTBEAT
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (HEARTBEAT, HDR_LENGTH + 4, 0)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.seq);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let seq = msg.get_u32(0)?;
+        msg.check_len(4)?;
+        Ok(Msg { seq })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_heartbeat_1,
+        super::Msg,
+        super::Msg { seq: 42 },
+        crate::serialize::Msg::Hb(hbm),
+        hbm
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_heartbeat_msg() {
+        let buf = crate::serialize::serialize(&super::Msg { seq: 0x0a0b_0c0d }).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                6, 0, // HEARTBEAT
+                12, 0, // length = 12
+                0, 0, 0, 0, // sock_id = 0
+                0x0d, 0x0c, 0x0b, 0x0a, // seq = 0x0a0b0c0d
+            ],
+        );
+    }
+}
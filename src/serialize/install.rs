@@ -1,35 +1,216 @@
 //! CCP sends this message containing a datapath program.
+//!
+//! Each event and instruction takes 16 bytes, unless the message sets `VARINT` in its
+//! instruction count. Then the program is serialized with varints (see
+//! `lang::Bin::serialize_varint`), which takes about half as many bytes; `Compact` serializes a
+//! message so. Compact programs arrived in minor version 6 (see
+//! [`version`](../version/index.html)), so CCP sends them only to a datapath which announced
+//! that. Either parses as a `Msg`.
+//!
+//! A message which sets `NAMED` in its event count has a name table after its program: the names
+//! of the program's report variables, in the order of their registers, so that whoever sees the
+//! message can label the fields of the program's measurements, and rebuild its `Scope` (see
+//! `Msg::scope`). The table is a byte counting the names, then each name as a byte giving its
+//! length and its UTF-8. CCP sends names to datapaths which speak minor version 7.
 
-use super::{u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
-use crate::lang::Bin;
-use crate::Result;
+use super::codes::Code;
+use super::{
+    check_count, msg_len, u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH,
+};
+use crate::lang::{Bin, Reg, Scope};
+use crate::{Error, Result};
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const INSTALL: u8 = 2;
+/// The type of install messages.
+pub const INSTALL: u8 = 2;
+
+/// Set in the instruction count of a message whose program is serialized with varints.
+pub const VARINT: u32 = 1 << 31;
+
+// The minor version which introduced `VARINT`.
+pub(crate) const VARINT_MINOR: u32 = 6;
+
+/// Set in the event count of a message with a name table.
+pub const NAMED: u32 = 1 << 31;
+
+// The minor version which introduced `NAMED`.
+pub(crate) const NAMED_MINOR: u32 = 7;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
-    pub sid: u32,
+    pub sid: u64,
     pub program_uid: u32,
     pub num_events: u32,
     pub num_instrs: u32,
     pub instrs: Bin,
+    /// The names of the program's report variables, in the order of their registers (see
+    /// `Scope::report_names`), if the message has a name table.
+    pub names: Option<Vec<String>>,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    program_uid,
+    num_events,
+    num_instrs,
+    instrs,
+    names,
+});
+
+impl Msg {
+    /// A message installing `instrs` as the program `program_uid`, with its counts to match.
+    pub fn new(sid: u64, program_uid: u32, instrs: Bin) -> Self {
+        Msg {
+            sid,
+            program_uid,
+            num_events: instrs.events.len() as u32,
+            num_instrs: instrs.instrs.len() as u32,
+            instrs,
+            names: None,
+        }
+    }
+
+    /// The program's scope as far as the message tells, if it has a name table: its report
+    /// variables, whose types are unknown, so they hold numbers (see `Scope::with_reports`).
+    pub fn scope(&self) -> Option<Scope> {
+        let names = self.names.as_ref()?;
+        let volatile = |idx: usize| {
+            self.instrs
+                .instrs
+                .iter()
+                .flat_map(|i| vec![&i.res, &i.left, &i.right])
+                .any(|r| matches!(*r, Reg::Report(i, _, true) if usize::from(i) == idx))
+        };
+
+        Some(Scope::with_reports(
+            self.program_uid,
+            names
+                .iter()
+                .enumerate()
+                .map(|(idx, name)| (name.clone(), volatile(idx))),
+        ))
+    }
+}
+
+// The event count to send, with `NAMED` set if there is a name table.
+fn named(num_events: u32, names: &Option<Vec<String>>) -> u32 {
+    if names.is_some() {
+        num_events | NAMED
+    } else {
+        num_events
+    }
+}
+
+// The length of a name table of `names`.
+fn names_len(names: &Option<Vec<String>>) -> usize {
+    names.as_ref().map_or(0, |names| {
+        1 + names.iter().map(|n| 1 + n.len()).sum::<usize>()
+    })
+}
+
+fn write_names<W: Write>(w: &mut W, names: &Option<Vec<String>>) -> Result<()> {
+    let names = match names {
+        Some(names) => names,
+        None => return Ok(()),
+    };
+    if names.len() > usize::from(u8::MAX) {
+        return Err(Error(format!("too many report names: {}", names.len())));
+    }
+
+    w.write_all(&[names.len() as u8])?;
+    for name in names {
+        if name.len() > usize::from(u8::MAX) {
+            return Err(Error(format!("report name too long: {:?}", name)));
+        }
+
+        w.write_all(&[name.len() as u8])?;
+        w.write_all(name.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Parse a name table which takes all of `buf`.
+fn read_names(buf: &[u8]) -> Result<Vec<String>> {
+    let too_short = || {
+        Error::from(ParseError::BadPayload(String::from(
+            "install message's name table cut short",
+        )))
+    };
+    let (&count, mut rest) = buf.split_first().ok_or_else(too_short)?;
+    let mut names = Vec::with_capacity(usize::from(count));
+    for _ in 0..count {
+        let (&len, r) = rest.split_first().ok_or_else(too_short)?;
+        if r.len() < usize::from(len) {
+            return Err(too_short());
+        }
+
+        let (name, r) = r.split_at(usize::from(len));
+        let name = std::str::from_utf8(name)
+            .map_err(|e| Error::from(ParseError::BadPayload(e.to_string())))?;
+        names.push(name.to_owned());
+        rest = r;
+    }
+    if !rest.is_empty() {
+        return Err(Error::from(ParseError::BadPayload(format!(
+            "{} bytes after the install message's name table",
+            rest.len()
+        ))));
+    }
+
+    Ok(names)
+}
+
+/// The program's events and instructions follow, one per line (see `lang::Bin`).
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "install sid={} program_uid={} events={} instrs={}",
+            self.sid, self.program_uid, self.num_events, self.num_instrs
+        )?;
+        if let Some(ref names) = self.names {
+            write!(f, " names={}", names.join(","))?;
+        }
+        if !self.instrs.events.is_empty() || !self.instrs.instrs.is_empty() {
+            write!(f, "\n{}", self.instrs)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        INSTALL
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        let len = msg_len(12, self.num_events as usize + self.num_instrs as usize, 16);
         (
             INSTALL,
-            HDR_LENGTH + 12 + (self.num_events * 16 + self.num_instrs * 16),
+            len.saturating_add(names_len(&self.names) as u32),
             self.sid,
         )
     }
 
+    // large programs may not fit in the 16-bit length
+    const EXTENDABLE: bool = true;
+
+    fn check_counts(&self) -> Result<()> {
+        let events = self.instrs.events.len();
+        check_count("install", "events", self.num_events as usize, events)?;
+        let instrs = self.instrs.instrs.len();
+        check_count("install", "instructions", self.num_instrs as usize, instrs)
+    }
+
     fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 4];
         u32_to_u8s(&mut buf, self.program_uid);
         w.write_all(&buf[..])?;
-        u32_to_u8s(&mut buf, self.num_events);
+        u32_to_u8s(&mut buf, named(self.num_events, &self.names));
         w.write_all(&buf[..])?;
         u32_to_u8s(&mut buf, self.num_instrs);
         w.write_all(&buf[..])?;
@@ -39,20 +220,514 @@ impl AsRawMsg for Msg {
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
         let buf = self.instrs.serialize()?;
         w.write_all(&buf[..])?;
+        write_names(w, &self.names)
+    }
+
+    /// Registers in the parsed program do not have their types (see `Bin::deserialize`).
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.bytes;
+        if b.len() < 12 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 12,
+                actual: b.len(),
+            }));
+        }
+
+        let num_events = u32_from_u8s(&b[4..8]);
+        let num_instrs = u32_from_u8s(&b[8..12]);
+        let has_names = num_events & NAMED != 0;
+        let (num_events, program) = (num_events & !NAMED, &b[12..]);
+        let (instrs, names) = match (num_instrs & VARINT != 0, has_names) {
+            (true, false) => (
+                Bin::deserialize_varint(program, num_events, num_instrs & !VARINT)?,
+                None,
+            ),
+            (true, true) => {
+                let (bin, len) =
+                    Bin::deserialize_varint_prefix(program, num_events, num_instrs & !VARINT)?;
+                (bin, Some(read_names(&program[len..])?))
+            }
+            (false, false) => (Bin::deserialize(program, num_events, num_instrs)?, None),
+            (false, true) => {
+                let len = (u64::from(num_events) + u64::from(num_instrs)) * 16;
+                if (program.len() as u64) < len {
+                    return Err(Error::from(ParseError::BadLength {
+                        declared: len as usize,
+                        actual: program.len(),
+                    }));
+                }
+
+                let (program, names) = program.split_at(len as usize);
+                (
+                    Bin::deserialize(program, num_events, num_instrs)?,
+                    Some(read_names(names)?),
+                )
+            }
+        };
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32_from_u8s(&b[0..4]),
+            num_events,
+            num_instrs: num_instrs & !VARINT,
+            instrs,
+            names,
+        })
+    }
+}
+
+/// An install message with its program serialized with varints, for a datapath which speaks
+/// minor version 6.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Compact {
+    sid: u64,
+    program_uid: u32,
+    num_events: u32,
+    num_instrs: u32,
+    program: Vec<u8>,
+    names: Option<Vec<String>>,
+}
+
+impl Compact {
+    /// Serialize `m`'s program compactly. It is an error if the program does not serialize, or
+    /// has so many events or instructions that the counts collide with `NAMED` or `VARINT`.
+    pub fn new(m: &Msg) -> Result<Self> {
+        m.check_counts()?;
+        let too_many =
+            |what, n| Code::ProgramTooLarge.error(format_args!("too many {}: {}", what, n));
+        if m.num_instrs & VARINT != 0 {
+            return Err(too_many("instructions", m.num_instrs));
+        }
+        if m.num_events & NAMED != 0 {
+            return Err(too_many("events", m.num_events));
+        }
+
+        Ok(Compact {
+            sid: m.sid,
+            program_uid: m.program_uid,
+            num_events: m.num_events,
+            num_instrs: m.num_instrs,
+            program: m.instrs.serialize_varint()?,
+            names: m.names.clone(),
+        })
+    }
+}
+
+impl AsRawMsg for Compact {
+    fn typ() -> u8 {
+        INSTALL
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (
+            INSTALL,
+            (HDR_LENGTH as usize + 12 + self.program.len() + names_len(&self.names))
+                .min(u32::MAX as usize) as u32,
+            self.sid,
+        )
+    }
+
+    const EXTENDABLE: bool = true;
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        for x in &[
+            self.program_uid,
+            named(self.num_events, &self.names),
+            self.num_instrs | VARINT,
+        ] {
+            u32_to_u8s(&mut buf, *x);
+            w.write_all(&buf[..])?;
+        }
+
         Ok(())
     }
 
-    // at least for now, portus doesn't have to worry about deserializing this
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!();
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        w.write_all(&self.program)?;
+        write_names(w, &self.names)
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        Compact::new(&Msg::from_raw_msg(msg)?)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lang::{Bin, Prog};
+    use crate::lang::{Bin, Reg};
+    use crate::serialize::codes::Code;
+    use crate::serialize::{self, checksum, Msg, ParseError};
+
+    fn install_msg(bin: Bin) -> super::Msg {
+        super::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_events: bin.events.len() as u32,
+            num_instrs: bin.instrs.len() as u32,
+            instrs: bin,
+            names: None,
+        }
+    }
+
+    fn compile(src: &[u8]) -> super::Msg {
+        let (bin, _) = crate::lang::compile(src, &[]).expect("compile");
+        install_msg(bin)
+    }
+
+    // Registers lose their types on the wire, so compare the parsed message's serialization.
+    fn check_round_trip(m: super::Msg) {
+        let buf = serialize::serialize(&m).expect("serialize");
+        let got = match Msg::from_buf(&buf[..]) {
+            Ok((Msg::Ins(got), len)) => {
+                assert_eq!(len, buf.len());
+                got
+            }
+            other => panic!("expected an install message, got {:?}", other),
+        };
+
+        assert_eq!((got.sid, got.program_uid), (m.sid, m.program_uid));
+        assert_eq!(got.instrs.events, m.instrs.events);
+        let ops = |b: &Bin| b.instrs.iter().map(|i| i.op).collect::<Vec<_>>();
+        assert_eq!(ops(&got.instrs), ops(&m.instrs));
+        assert_eq!(serialize::serialize(&got).expect("serialize again"), buf);
+
+        let typed: super::Msg = serialize::deserialize(&buf).expect("deserialize");
+        assert_eq!(serialize::serialize(&typed).expect("serialize again"), buf);
+        let compact: super::Compact = serialize::deserialize(&buf).expect("deserialize compact");
+        assert_eq!(compact, super::Compact::new(&got).expect("compact"));
+    }
+
+    #[test]
+    fn deserialize_install_msg() {
+        check_round_trip(install_msg(Bin {
+            events: vec![],
+            instrs: vec![],
+        }));
+        check_round_trip(compile(
+            b"(def (Report.acked 0) (Control.state 0))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (fallthrough)
+            )
+            (when (&& (> Micros 3000000) (== Control.state 0))
+                (:= Control.state 1)
+                (report)
+            )",
+        ));
+        check_round_trip(compile(
+            b"(def (Report.minrtt +infinity) (Report.rate 0) (Control.target 0))
+            (when true
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+                (:= Report.rate (ewma 2 Flow.rate_outgoing))
+                (:= Cwnd (max (* Cwnd 2) (/ Flow.bytes_in_flight 2)))
+                (:= Control.target (- Cwnd 1))
+                (fallthrough)
+            )
+            (when (< Flow.rtt_sample_us Control.target)
+                (report)
+            )",
+        ));
+    }
+
+    #[test]
+    fn deserialize_invalid_install_msg() {
+        let m = compile(
+            b"(def (Report.foo 0))
+            (when true
+                (:= Report.foo Flow.rtt_sample_us)
+            )",
+        );
+        let buf = serialize::serialize(&m).expect("serialize");
+        let first_instr = 8 + 12 + 16 * m.num_events as usize;
+        let parse_err = |buf: &[u8]| match Msg::from_buf(buf) {
+            Err(e) => e,
+            Ok((msg, _)) => panic!("expected an error, got {:?}", msg),
+        };
+
+        let mut bad_op = buf.clone();
+        bad_op[first_instr] = 99;
+        let bad_op = checksum::fix_up(bad_op);
+        let err = parse_err(&bad_op);
+        assert!(err.0.contains("unknown opcode"), "{}", err.0);
+        assert_eq!(Code::of(&err), Some(Code::UnsupportedOp));
+
+        let mut bad_reg = buf.clone();
+        bad_reg[first_instr + 1] = 42;
+        let bad_reg = checksum::fix_up(bad_reg);
+        let err = parse_err(&bad_reg);
+        assert!(err.0.contains("unknown register type"), "{}", err.0);
+        assert_eq!(Code::of(&err), None);
+
+        let mut far_reg = buf.clone();
+        far_reg[first_instr + 2] = 16;
+        let far_reg = checksum::fix_up(far_reg);
+        let err = parse_err(&far_reg);
+        assert_eq!(Code::of(&err), Some(Code::RegisterOutOfRange), "{}", err.0);
+
+        let mut miscounted = buf.clone();
+        miscounted[16] += 1;
+        let miscounted = checksum::fix_up(miscounted);
+        parse_err(&miscounted);
+
+        // a frame cut short has no checksum to check
+        if cfg!(feature = "checksum") {
+            return;
+        }
+        let mut short = buf[..12].to_vec();
+        short[2] = 12;
+        let err = parse_err(&short);
+        assert_eq!(
+            ParseError::of(&err),
+            Some(ParseError::BadLength {
+                declared: 12,
+                actual: 4,
+            })
+        );
+    }
+
+    // A program too long for the header's 16-bit length has an extended length, after the wide
+    // socket id if there is one.
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn install_extended_len() {
+        let mut m = compile(
+            b"(def (Report.acked 0))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            )",
+        );
+        m.instrs.instrs = m.instrs.instrs.iter().cycle().take(5000).cloned().collect();
+        m.num_instrs = 5000;
+        check_round_trip(m.clone());
+        let buf = serialize::serialize(&m).expect("serialize");
+        assert!(serialize::is_extended(&buf));
+        assert_eq!(&buf[0..4], &[2, 0x08, 0, 0]); // INSTALL | EXTENDED_LEN, length in the header = 0
+        assert_eq!(&buf[8..12], &(buf.len() as u32).to_le_bytes());
+        assert!(buf.len() > usize::from(u16::MAX));
+
+        m.sid = 1 << 40;
+        check_round_trip(m.clone());
+        let buf = serialize::serialize(&m).expect("serialize");
+        assert_eq!(&buf[0..2], &[2, 0x18]); // INSTALL | WIDE_SID | EXTENDED_LEN
+        assert_eq!(&buf[8..12], &[0, 1, 0, 0]); // sock_id, high 32 bits
+        assert_eq!(&buf[12..16], &(buf.len() as u32).to_le_bytes());
+    }
+
+    #[test]
+    fn deserialize_compact_install_msg() {
+        let m = compile(
+            b"(def (Report.minrtt +infinity) (Report.rate 0) (Control.target 0))
+            (when true
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+                (:= Report.rate (ewma 2 Flow.rate_outgoing))
+                (:= Cwnd (max (* Cwnd 2) (/ Flow.bytes_in_flight 2)))
+                (fallthrough)
+            )
+            (when (< Flow.rtt_sample_us Control.target)
+                (report)
+            )",
+        );
+        let fixed = serialize::serialize(&m).expect("serialize");
+        let buf = serialize::serialize(&super::Compact::new(&m).expect("compact"))
+            .expect("serialize compact");
+        match Msg::from_buf(&buf[..]) {
+            Ok((Msg::Ins(got), len)) => {
+                assert_eq!(len, buf.len());
+                assert_eq!(got.num_instrs, m.num_instrs);
+                assert_eq!(serialize::serialize(&got).expect("serialize again"), fixed);
+            }
+            other => panic!("expected an install message, got {:?}", other),
+        }
+
+        let short = 8 + 12 + 4 * m.num_events as usize;
+        let mut cut = buf[..buf.len() - 1].to_vec();
+        cut[2] -= 1;
+        assert!(Msg::from_buf(&cut).is_err());
+        let mut cut = buf[..short].to_vec();
+        cut[2] = short as u8;
+        assert!(Msg::from_buf(&cut).is_err());
+    }
+
+    // A program of a hundred instructions, as a complex algorithm might have.
+    #[test]
+    fn compact_install_smaller() {
+        let mut m = compile(
+            b"(def
+                (Report (volatile acked 0) (volatile lost 0) (volatile minrtt +infinity))
+                (Control.state 0)
+            )
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (:= Report.lost (+ Report.lost Ack.lost_pkts_sample))
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+                (fallthrough)
+            )
+            (when (&& (> Micros Report.minrtt) (== Control.state 0))
+                (:= Cwnd (max (/ (* Cwnd 7) 10) 2896))
+                (:= Control.state 1)
+                (report)
+            )",
+        );
+        m.instrs.instrs = m.instrs.instrs.iter().cycle().take(100).cloned().collect();
+        m.num_instrs = 100;
+
+        let fixed = serialize::serialize(&m).expect("serialize");
+        let compact = serialize::serialize(&super::Compact::new(&m).expect("compact"))
+            .expect("serialize compact");
+        assert!(
+            compact.len() * 10 <= fixed.len() * 7,
+            "{} bytes compact, {} fixed",
+            compact.len(),
+            fixed.len()
+        );
+    }
+
+    // A monitor which knows only the install message names each field of the program's
+    // measurements as CCP does.
+    #[test]
+    fn names_round_trip() {
+        let (bin, sc) = crate::lang::compile(
+            b"(def (Report
+                (acked 0)
+                (volatile minrtt +infinity)
+                (rate 0)
+                (volatile loss 0)
+                (timeout false)
+            ))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (:= Report.minrtt (min Report.minrtt Flow.rtt_sample_us))
+                (:= Report.rate Flow.rate_outgoing)
+                (:= Report.loss (+ Report.loss Ack.lost_pkts_sample))
+                (:= Report.timeout Flow.was_timeout)
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        let m = super::Msg {
+            names: Some(sc.report_names()),
+            ..install_msg(bin)
+        };
+        assert_eq!(m.names.as_ref().map(Vec::len), Some(5));
+
+        let fixed = serialize::serialize(&m).expect("serialize");
+        let compact =
+            serialize::serialize(&super::Compact::new(&m).expect("compact")).expect("serialize");
+        for buf in &[fixed, compact] {
+            let got = match Msg::from_buf(buf) {
+                Ok((Msg::Ins(got), len)) => {
+                    assert_eq!(len, buf.len());
+                    got
+                }
+                other => panic!("expected an install message, got {:?}", other),
+            };
+            assert_eq!(got.names, m.names);
+            assert_eq!(got.num_events, m.num_events);
+
+            let got_sc = got.scope().expect("scope");
+            assert_eq!(got_sc.program_uid, m.program_uid);
+            assert_eq!(got_sc.report_names(), sc.report_names());
+            for name in sc.report_names() {
+                match (sc.get(&name), got_sc.get(&name)) {
+                    (
+                        Some(&Reg::Report(idx, _, volatile)),
+                        Some(&Reg::Report(got_idx, _, got_volatile)),
+                    ) => assert_eq!((got_idx, got_volatile), (idx, volatile), "{}", name),
+                    regs => panic!("{}: {:?}", name, regs),
+                }
+            }
+            assert!(got_sc.get("Flow.rtt_sample_us").is_some());
+        }
+
+        assert!(install_msg(Bin {
+            events: vec![],
+            instrs: vec![],
+        })
+        .scope()
+        .is_none());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn invalid_names() {
+        let m = super::Msg {
+            names: Some(vec![String::from("Report.a"), String::from("Report.bc")]),
+            ..compile(b"(def (Report.a 0) (Report.bc 0)) (when true (report))")
+        };
+        let buf = serialize::serialize(&m).expect("serialize");
+        let table = buf.len() - 20;
+        let parse_err = |buf: &[u8]| match Msg::from_buf(buf) {
+            Err(e) => e.0,
+            Ok((msg, _)) => panic!("expected an error, got {:?}", msg),
+        };
+
+        for cut in table..buf.len() {
+            let mut short = buf[..cut].to_vec();
+            short[2] = cut as u8;
+            parse_err(&short);
+        }
+
+        let mut long = buf.clone();
+        long.push(0);
+        long[2] += 1;
+        let err = parse_err(&long);
+        assert!(err.contains("after"), "{}", err);
+
+        let mut bad_utf8 = buf.clone();
+        bad_utf8[table + 2] = 0xff;
+        parse_err(&bad_utf8);
+
+        let long_name = super::Msg {
+            names: Some(vec!["x".repeat(256)]),
+            ..m.clone()
+        };
+        assert!(serialize::serialize(&long_name).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_compact_install_msg() {
+        let foo = b"
+        (def (Report (volatile foo 0)))
+        (when true
+            (bind Report.foo 4)
+        )
+        ";
+
+        let (p, mut sc) = crate::lang::Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_events: 1,
+            num_instrs: 3,
+            instrs: b,
+            names: None,
+        };
+
+        let buf =
+            serialize::serialize(&super::Compact::new(&m).expect("compact")).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                2, 0, // INSTALL
+                45, 0, // length = 45
+                1, 0, 0, 0, // sock_id = 1
+                7, 0, 0, 0, // program_uid = 7
+                1, 0, 0, 0, // num_events = 1
+                3, 0, 0, 0x80, // num_instrs = 3 | VARINT
+                1, 1, 2, 1, // event { flag-idx=1, num-flag=1, body-idx=2, num-body=1 }
+                2, 5, 0, 5, 0, 1, 0, // (def (Report.foo 0))
+                1, 2, 0, 2, 0, 1, 1, // (when true
+                1, 5, 0, 5, 0, 1, 4, //     (bind Report.foo 4))
+            ],
+        );
+    }
 
     #[test]
+    #[cfg(not(feature = "checksum"))]
     fn serialize_install_msg() {
         let foo = b"
         (def (Report (volatile foo 0)))
@@ -61,7 +736,7 @@ mod tests {
         )
         ";
 
-        let (p, mut sc) = Prog::new_with_scope(foo).unwrap();
+        let (p, mut sc) = crate::lang::Prog::new_with_scope(foo).unwrap();
         let b = Bin::compile_prog(&p, &mut sc).unwrap();
         let m = super::Msg {
             sid: 1,
@@ -69,6 +744,7 @@ mod tests {
             num_events: 1,
             num_instrs: 3,
             instrs: b,
+            names: None,
         };
 
         let buf: Vec<u8> =
@@ -90,4 +766,47 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_named_install_msg() {
+        let foo = b"
+        (def (Report (volatile foo 0)))
+        (when true
+            (bind Report.foo 4)
+        )
+        ";
+
+        let (p, mut sc) = crate::lang::Prog::new_with_scope(foo).unwrap();
+        let b = Bin::compile_prog(&p, &mut sc).unwrap();
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_events: 1,
+            num_instrs: 3,
+            instrs: b,
+            names: Some(sc.report_names()),
+        };
+
+        let buf =
+            serialize::serialize(&super::Compact::new(&m).expect("compact")).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                2, 0, // INSTALL
+                57, 0, // length = 57
+                1, 0, 0, 0, // sock_id = 1
+                7, 0, 0, 0, // program_uid = 7
+                1, 0, 0, 0x80, // num_events = 1 | NAMED
+                3, 0, 0, 0x80, // num_instrs = 3 | VARINT
+                1, 1, 2, 1, // event { flag-idx=1, num-flag=1, body-idx=2, num-body=1 }
+                2, 5, 0, 5, 0, 1, 0, // (def (Report.foo 0))
+                1, 2, 0, 2, 0, 1, 1, // (when true
+                1, 5, 0, 5, 0, 1, 4,  //     (bind Report.foo 4))
+                1,  // 1 name
+                10, // its length
+                b'R', b'e', b'p', b'o', b'r', b't', b'.', b'f', b'o', b'o', // "Report.foo"
+            ],
+        );
+    }
 }
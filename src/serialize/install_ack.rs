@@ -0,0 +1,133 @@
+//! Message sent from datapath to CCP once it has switched a flow to the program CCP asked for (see
+//! `DatapathTrait::set_program`), or failed to, e.g. because the program was never installed.
+//! Datapaths which do not send one are not required to.
+
+use super::codes::Code;
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of install acknowledgement messages.
+pub const INSTALL_ACK: u8 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u64,
+    pub program_uid: u32,
+    /// `Code::Ok` if the switch succeeded, and otherwise why it failed.
+    pub status: Code,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    program_uid,
+    status,
+});
+
+impl Msg {
+    pub fn ok(&self) -> bool {
+        self.status == Code::Ok
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "install_ack sid={} program_uid={} status={:?}",
+            self.sid, self.program_uid, self.status
+        )
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        INSTALL_ACK
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (INSTALL_ACK, HDR_LENGTH + 2 * 4, self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        for x in &[self.program_uid, u32::from(self.status)] {
+            u32_to_u8s(&mut buf, *x);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 2 * 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 2 * 4,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(2 * 4)?;
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32_from_u8s(&b[0..4]),
+            status: Code::from(u32_from_u8s(&b[4..8])),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_install_ack_1,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 3,
+            status: super::Code::Ok,
+        },
+        crate::serialize::Msg::InsAck(am),
+        am
+    );
+
+    check_msg!(
+        test_install_ack_failed,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 3,
+            status: super::Code::UnknownProgram,
+        },
+        crate::serialize::Msg::InsAck(am),
+        am
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_install_ack_msg() {
+        let m = super::Msg {
+            sid: 0x0102_0304,
+            program_uid: 7,
+            status: super::Code::Unknown(0x0a0b_0c0d),
+        };
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                10, 0, // INSTALL_ACK
+                16, 0, // length = 16
+                4, 3, 2, 1, // sock_id = 0x01020304
+                7, 0, 0, 0, // program_uid = 7
+                0x0d, 0x0c, 0x0b, 0x0a, // status = 0x0a0b0c0d
+            ],
+        );
+    }
+}
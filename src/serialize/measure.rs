@@ -1,72 +1,649 @@
 //! When the datapath program specifies, the datapath sends a Report message containing
 //! measurements to CCP. Use the `Scope` returned from compiling the program to query the values.
+//!
+//! Each field is 8 bytes, unless the message sets `TYPED_FIELDS` in its field count. Then each
+//! field is prefixed by a 1-byte `FieldType` tag, and takes only as many bytes as its type needs:
+//!
+//! ```text
+//! ---------------------------------------------------------------------------
+//! | Program uid | Num fields     | Tag   | Value        | Tag   | Value | ...
+//! | (32 bits)   | | TYPED_FIELDS | (1 B) | (`len()` B)  | (1 B) |       |
+//! ---------------------------------------------------------------------------
+//! ```
+//!
+//! A typed field may be a `Str`, a short string the datapath sets, e.g. the name of the flow's
+//! congestion avoidance state, whose value is a 1-byte length, at most `MAX_STR_LEN`, and then
+//! that many bytes of UTF-8.
+//!
+//! A message which sets `TIMESTAMPED` in its field count has, between the field count and the
+//! fields, a 64-bit timestamp of when the datapath generated it, by the datapath's clock (e.g.
+//! nanoseconds since boot).
+//!
+//! Typed fields arrived in minor version 1, timestamps in minor version 2, and string fields in
+//! minor version 10 (see [`version`](../version/index.html)), so datapaths should send them only
+//! to a CCP which announced that. CCP reads either.
+//!
+//! Parsing a measurement reads all its fields; `Msg::lazy` instead reads each field from the
+//! message's bytes only when it is asked for.
 
-use super::{u32_to_u8s, u64_from_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use super::{
+    check_count, u32_to_u8s, u64_from_u8s, u64_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH,
+};
 use crate::{Error, Result};
+use std::convert::TryFrom;
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const MEASURE: u8 = 1;
+/// The type of measurement messages.
+pub const MEASURE: u8 = 1;
+
+/// Set in the field count of a measurement whose fields are typed.
+pub const TYPED_FIELDS: u32 = 1 << 31;
+/// Set in the field count of a measurement with a timestamp.
+pub const TIMESTAMPED: u32 = 1 << 30;
+
+/// The longest string a `Str` field may hold, in bytes.
+pub const MAX_STR_LEN: usize = 32;
+
+/// The type of a field in a typed measurement, which says how many bytes its value takes. Each is
+/// a `lang::Type::Num`, `lang::Type::Int`, `lang::Type::Bool` or `lang::Type::Str` in the
+/// program's `Scope`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldType {
+    /// A `Num`, in 8 bytes.
+    Num,
+    /// A `Num` which fits in 4 bytes, e.g. a packet counter.
+    Num32,
+    /// A `Bool`, in 1 byte.
+    Bool,
+    /// An `Int`, or a signed `Num`, e.g. a rate delta, in 8 bytes of two's complement.
+    Int,
+    /// A `Str`, in a 1-byte length and then the string.
+    Str,
+}
+
+#[cfg(feature = "serde")]
+serde_enum!(FieldType {
+    Num,
+    Num32,
+    Bool,
+    Int,
+    Str,
+});
+
+impl FieldType {
+    fn tag(self) -> u8 {
+        match self {
+            FieldType::Num => 0,
+            FieldType::Num32 => 1,
+            FieldType::Bool => 2,
+            FieldType::Int => 3,
+            FieldType::Str => 4,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            0 => Ok(FieldType::Num),
+            1 => Ok(FieldType::Num32),
+            2 => Ok(FieldType::Bool),
+            3 => Ok(FieldType::Int),
+            4 => Ok(FieldType::Str),
+            _ => Err(Error::from(ParseError::UnknownType(tag))),
+        }
+    }
+
+    /// How many bytes a value of this type takes, after its tag. A `Str`'s is its length's,
+    /// after which the string takes as many more as it says.
+    pub fn wire_len(self) -> usize {
+        match self {
+            FieldType::Num | FieldType::Int => 8,
+            FieldType::Num32 => 4,
+            FieldType::Bool | FieldType::Str => 1,
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
-    pub sid: u32,
+    pub sid: u64,
     pub program_uid: u32,
     // This is actually a u32 in libccp for struct alignment purposes. It *should* be a u8
     // (as it is here), to help enforce the maximum number of fields, but it's much easier
     // to keep everything 4-byte-aligned for de-serialization.
     pub num_fields: u8,
+    /// The values of the fields. A `Bool` is 0 or 1, an `Int` is its two's complement bits, and a
+    /// `Str` is its string's index in `strings`.
     pub fields: Vec<u64>,
+    /// The type of each field, if the measurement is typed.
+    pub types: Option<Vec<FieldType>>,
+    /// When the datapath generated the measurement, by its clock, if it said.
+    pub timestamp: Option<u64>,
+    /// The strings of the `Str` fields, in order.
+    pub strings: Vec<String>,
 }
 
-fn deserialize_fields(buf: &[u8]) -> Result<Vec<u64>> {
-    buf.chunks(8)
-        .map(|sl| {
-            if sl.len() < 8 {
-                Err(Error(format!("not long enough: {:?}", sl)))
-            } else {
-                Ok(u64_from_u8s(sl))
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    program_uid,
+    num_fields,
+    fields,
+    types,
+    timestamp,
+    strings,
+});
+
+impl Msg {
+    /// An untyped measurement of `fields` without a timestamp, with its field count to match.
+    pub fn new(sid: u64, program_uid: u32, fields: Vec<u64>) -> Self {
+        Msg {
+            sid,
+            program_uid,
+            num_fields: fields.len() as u8,
+            fields,
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        }
+    }
+}
+
+impl Msg {
+    /// Parse the measurement at the start of `buf`, but read its fields only as they are asked
+    /// for, e.g. to read a few fields of a long report, rather than all at once as
+    /// `deserialize` does. Errors are as for `deserialize`: it is one if the fields are cut
+    /// short or have unknown types.
+    pub fn lazy(buf: &[u8]) -> Result<Lazy<'_>> {
+        let msg = super::parse_typed(buf, MEASURE)?;
+        let (program_uid, flags, timestamp, b) = split(&msg)?;
+        let num_fields = flags as u8;
+        let fields = Fields {
+            buf: b,
+            typed: flags & TYPED_FIELDS != 0,
+        };
+        // check up front that every field can be read, so reading them later cannot fail
+        let len = if fields.typed {
+            let mut buf = b;
+            let mut len = 0;
+            while !buf.is_empty() {
+                let (_, _, field_len) = typed_field(buf)?;
+                buf = &buf[field_len..];
+                len += 1;
+            }
+
+            len
+        } else {
+            if b.len() % 8 != 0 {
+                return Err(Error::from(ParseError::BadLength {
+                    declared: 8,
+                    actual: b.len() % 8,
+                }));
             }
+
+            b.len() / 8
+        };
+        if len != usize::from(num_fields) {
+            msg.inconsistent(|| format!("{} fields, but says it has {}", len, num_fields))?;
+        }
+
+        Ok(Lazy {
+            sid: msg.sid,
+            program_uid,
+            num_fields,
+            timestamp,
+            fields,
         })
-        .collect()
+    }
+}
+
+/// A measurement whose fields are read from the message's bytes as they are asked for (see
+/// `Msg::lazy`). Reading a field of a typed measurement takes a walk over those before it; an
+/// untyped one's are each found directly.
+#[derive(Clone, Copy, Debug)]
+pub struct Lazy<'a> {
+    pub sid: u64,
+    pub program_uid: u32,
+    pub num_fields: u8,
+    pub timestamp: Option<u64>,
+    pub fields: Fields<'a>,
+}
+
+impl Lazy<'_> {
+    /// The measurement with all its fields read, as `deserialize` gives it.
+    pub fn to_msg(&self) -> Msg {
+        let mut fields = Vec::with_capacity(usize::from(self.num_fields));
+        let mut types = Vec::new();
+        let mut strings = Vec::new();
+        let mut it = self.fields.iter();
+        while let Some((bytes, typ)) = it.next_raw() {
+            if typ == Some(FieldType::Str) {
+                fields.push(strings.len() as u64);
+                strings.push(String::from_utf8_lossy(bytes).into_owned());
+            } else {
+                fields.push(value(bytes));
+            }
+            types.extend(typ);
+        }
+
+        Msg {
+            sid: self.sid,
+            program_uid: self.program_uid,
+            num_fields: self.num_fields,
+            fields,
+            types: if self.fields.typed { Some(types) } else { None },
+            timestamp: self.timestamp,
+            strings,
+        }
+    }
+}
+
+// The program uid, field count and flags, timestamp, and fields' bytes of the measurement `msg`.
+fn split<'a>(msg: &RawMsg<'a>) -> Result<(u32, u32, Option<u64>, &'a [u8])> {
+    let program_uid = msg.get_u32(0)?;
+    let flags = msg.get_u32(1)?;
+    let mut b = msg.get_bytes()?;
+    let timestamp = if flags & TIMESTAMPED != 0 {
+        if b.len() < 8 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 8,
+                actual: b.len(),
+            }));
+        }
+
+        let ts = u64_from_u8s(&b[0..8]);
+        b = &b[8..];
+        Some(ts)
+    } else {
+        None
+    };
+
+    Ok((program_uid, flags, timestamp, b))
+}
+
+/// A measurement's fields, read from its bytes as they are asked for (see `Msg::lazy`).
+#[derive(Clone, Copy, Debug)]
+pub struct Fields<'a> {
+    buf: &'a [u8],
+    typed: bool,
+}
+
+impl<'a> Fields<'a> {
+    /// The number of fields.
+    pub fn len(&self) -> usize {
+        if self.typed {
+            self.iter().count()
+        } else {
+            self.buf.len() / 8
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// The `idx`th field's value, as `Msg::fields` has it, if there is one.
+    pub fn get(&self, idx: usize) -> Option<u64> {
+        if self.typed {
+            self.iter().nth(idx)
+        } else {
+            self.buf.get(idx * 8..idx * 8 + 8).map(u64_from_u8s)
+        }
+    }
+
+    /// The `idx`th field's type, if the measurement is typed and has the field.
+    pub fn field_type(&self, idx: usize) -> Option<FieldType> {
+        self.raw(idx).and_then(|(_, typ)| typ)
+    }
+
+    /// The `idx`th field's string, if it is a `Str`.
+    pub fn get_str(&self, idx: usize) -> Option<&'a str> {
+        match self.raw(idx)? {
+            (bytes, Some(FieldType::Str)) => std::str::from_utf8(bytes).ok(),
+            _ => None,
+        }
+    }
+
+    // The `idx`th field's bytes, and its type if typed.
+    fn raw(&self, idx: usize) -> Option<(&'a [u8], Option<FieldType>)> {
+        let mut it = self.iter();
+        for _ in 0..idx {
+            it.next_raw()?;
+        }
+
+        it.next_raw()
+    }
+
+    /// The fields' values in order.
+    pub fn iter(&self) -> FieldIter<'a> {
+        FieldIter {
+            buf: self.buf,
+            typed: self.typed,
+            strings: 0,
+        }
+    }
+
+    /// All the fields' values, as parsing the measurement gives them.
+    pub fn to_vec(&self) -> Vec<u64> {
+        let mut fields = Vec::with_capacity(if self.typed {
+            self.buf.len() / 2
+        } else {
+            self.buf.len() / 8
+        });
+        fields.extend(self.iter());
+        fields
+    }
+}
+
+impl<'a> IntoIterator for Fields<'a> {
+    type Item = u64;
+    type IntoIter = FieldIter<'a>;
+
+    fn into_iter(self) -> FieldIter<'a> {
+        self.iter()
+    }
+}
+
+/// An iterator over the values of a measurement's fields, reading each from the message's bytes
+/// as it goes.
+#[derive(Clone, Debug)]
+pub struct FieldIter<'a> {
+    buf: &'a [u8],
+    typed: bool,
+    // how many `Str` fields it has passed
+    strings: usize,
+}
+
+impl<'a> FieldIter<'a> {
+    // The next field's bytes, a `Str`'s without its length, and its type if typed.
+    fn next_raw(&mut self) -> Option<(&'a [u8], Option<FieldType>)> {
+        if !self.typed {
+            let bytes = self.buf.get(..8)?;
+            self.buf = &self.buf[8..];
+            return Some((bytes, None));
+        }
+
+        // `Msg::lazy` checked the fields
+        self.buf.first()?;
+        let (typ, bytes, len) = typed_field(self.buf).ok()?;
+        self.buf = &self.buf[len..];
+        if typ == FieldType::Str {
+            self.strings += 1;
+        }
+
+        Some((bytes, Some(typ)))
+    }
+
+    // The next field's value, as `Msg::fields` has it, and its type if typed.
+    fn next_typed(&mut self) -> Option<(u64, Option<FieldType>)> {
+        let (bytes, typ) = self.next_raw()?;
+        match typ {
+            Some(FieldType::Str) => Some((self.strings as u64 - 1, typ)),
+            _ => Some((value(bytes), typ)),
+        }
+    }
+}
+
+impl Iterator for FieldIter<'_> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        self.next_typed().map(|(val, _)| val)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        if self.typed {
+            (self.buf.len() / (2 + MAX_STR_LEN), Some(self.buf.len() / 2))
+        } else {
+            (self.buf.len() / 8, Some(self.buf.len() / 8))
+        }
+    }
+}
+
+// Reports arrive at a high rate, so allocate the fields exactly once: collecting into a
+// `Result<Vec<_>>` cannot size the `Vec` up front.
+fn deserialize_fields(buf: &[u8]) -> Result<Vec<u64>> {
+    let mut fields = Vec::with_capacity(buf.len() / 8);
+    for sl in buf.chunks(8) {
+        if sl.len() < 8 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 8,
+                actual: sl.len(),
+            }));
+        }
+
+        fields.push(u64_from_u8s(sl));
+    }
+
+    Ok(fields)
+}
+
+// The fields, their types, and the strings of the `Str` ones.
+type TypedFields = (Vec<u64>, Vec<FieldType>, Vec<String>);
+
+fn deserialize_typed_fields(mut buf: &[u8], num_fields: u8) -> Result<TypedFields> {
+    let mut fields = Vec::with_capacity(usize::from(num_fields));
+    let mut types = Vec::with_capacity(usize::from(num_fields));
+    let mut strings = Vec::new();
+    while !buf.is_empty() {
+        let (typ, bytes, len) = typed_field(buf)?;
+        if typ == FieldType::Str {
+            fields.push(strings.len() as u64);
+            strings.push(String::from_utf8_lossy(bytes).into_owned());
+        } else {
+            fields.push(value(bytes));
+        }
+        types.push(typ);
+        buf = &buf[len..];
+    }
+
+    Ok((fields, types, strings))
+}
+
+// The typed field at the start of `buf`, which is not empty: its type, its value's bytes (a
+// `Str`'s without its length), and how many bytes it takes in all. A `Str` is an error unless it
+// is at most `MAX_STR_LEN` bytes of UTF-8.
+fn typed_field(buf: &[u8]) -> Result<(FieldType, &[u8], usize)> {
+    let typ = FieldType::from_tag(buf[0])?;
+    let (at, len) = match typ {
+        FieldType::Str => (2, buf.get(1).map_or(0, |&len| usize::from(len))),
+        _ => (1, typ.wire_len()),
+    };
+    if buf.len() < at + len {
+        return Err(Error::from(ParseError::BadLength {
+            declared: at + len,
+            actual: buf.len(),
+        }));
+    }
+
+    let bytes = &buf[at..at + len];
+    if typ == FieldType::Str {
+        if len > MAX_STR_LEN {
+            return Err(Error::from(ParseError::BadPayload(format!(
+                "string field of {} bytes, longer than {}",
+                len, MAX_STR_LEN
+            ))));
+        }
+
+        std::str::from_utf8(bytes)
+            .map_err(|e| Error::from(ParseError::BadPayload(e.to_string())))?;
+    }
+
+    Ok((typ, bytes, at + len))
+}
+
+// The value of a field of up to 8 bytes, little-endian.
+fn value(bytes: &[u8]) -> u64 {
+    let mut val = [0u8; 8];
+    val[..bytes.len()].copy_from_slice(bytes);
+    u64_from_u8s(&val)
+}
+
+/// The measurement's fields by index, with their types if it has them, e.g.
+/// `measure sid=1 program_uid=7 fields=2 [0]=42 [1]=1:Bool`.
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "measure sid={} program_uid={} fields={}",
+            self.sid, self.program_uid, self.num_fields
+        )?;
+        if let Some(ts) = self.timestamp {
+            write!(f, " at={}", ts)?;
+        }
+        for (i, field) in self.fields.iter().enumerate() {
+            let typ = self.types.as_ref().and_then(|types| types.get(i));
+            match self.strings.get(*field as usize) {
+                Some(s) if typ == Some(&FieldType::Str) => write!(f, " [{}]={:?}", i, s)?,
+                _ => write!(f, " [{}]={}", i, field)?,
+            }
+            if let Some(typ) = typ {
+                write!(f, ":{:?}", typ)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        MEASURE
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        let len = match self.types {
+            Some(ref types) => types
+                .iter()
+                .zip(&self.fields)
+                .map(|(t, f)| match t {
+                    FieldType::Str => 2 + self.strings.get(*f as usize).map_or(0, |s| s.len()),
+                    _ => 1 + t.wire_len(),
+                })
+                .map(|len| u32::try_from(len).unwrap_or(u32::MAX))
+                .fold(0, u32::saturating_add),
+            None => u32::from(self.num_fields) * 8,
+        };
+
+        let timestamp_len = if self.timestamp.is_some() { 8 } else { 0 };
         (
             MEASURE,
-            HDR_LENGTH + 8 + u32::from(self.num_fields) * 8,
+            (HDR_LENGTH + 8 + timestamp_len).saturating_add(len),
             self.sid,
         )
     }
 
+    fn check_counts(&self) -> Result<()> {
+        let fields = self.fields.len();
+        check_count("measure", "fields", usize::from(self.num_fields), fields)?;
+        let mut strings = 0;
+        if let Some(ref types) = self.types {
+            check_count("measure", "field types", fields, types.len())?;
+            strings = types.iter().filter(|t| **t == FieldType::Str).count();
+        }
+
+        check_count("measure", "strings", strings, self.strings.len())
+    }
+
     fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 4];
         u32_to_u8s(&mut buf, self.program_uid);
         w.write_all(&buf[..])?;
-        u32_to_u8s(&mut buf, u32::from(self.num_fields));
+        let typed = if self.types.is_some() {
+            TYPED_FIELDS
+        } else {
+            0
+        };
+        let timestamped = if self.timestamp.is_some() {
+            TIMESTAMPED
+        } else {
+            0
+        };
+        u32_to_u8s(&mut buf, u32::from(self.num_fields) | typed | timestamped);
         w.write_all(&buf[..])?;
         Ok(())
     }
 
+    fn get_u64s<W: Write>(&self, w: &mut W) -> Result<()> {
+        if let Some(ts) = self.timestamp {
+            let mut buf = [0u8; 8];
+            u64_to_u8s(&mut buf, ts);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 8];
-        for f in &self.fields {
+        let types = match self.types {
+            Some(ref types) => types,
+            None => {
+                for f in &self.fields {
+                    u64_to_u8s(&mut buf, *f);
+                    w.write_all(&buf[..])?;
+                }
+
+                return Ok(());
+            }
+        };
+
+        for (typ, f) in types.iter().zip(&self.fields) {
+            if *typ == FieldType::Str {
+                let s = match self.strings.get(*f as usize) {
+                    Some(s) if s.len() <= MAX_STR_LEN => s,
+                    _ => {
+                        return Err(Error(format!(
+                            "{} is not the index of a string of at most {} bytes",
+                            f, MAX_STR_LEN
+                        )))
+                    }
+                };
+                w.write_all(&[typ.tag(), s.len() as u8])?;
+                w.write_all(s.as_bytes())?;
+                continue;
+            }
+
+            let fits = match typ {
+                FieldType::Num32 => *f <= u64::from(u32::MAX),
+                FieldType::Bool => *f <= 1,
+                FieldType::Num | FieldType::Int | FieldType::Str => true,
+            };
+            if !fits {
+                return Err(Error(format!("{} is not a {:?}", f, typ)));
+            }
+
             u64_to_u8s(&mut buf, *f);
-            w.write_all(&buf[..])?;
+            w.write_all(&[typ.tag()])?;
+            w.write_all(&buf[..typ.wire_len()])?;
         }
 
         Ok(())
     }
 
     fn from_raw_msg(msg: RawMsg) -> Result<Self> {
-        let u32s = unsafe { msg.get_u32s() }?;
-        let b = msg.get_bytes()?;
+        let (program_uid, flags, timestamp, b) = split(&msg)?;
+        let num_fields = flags as u8;
+        let (fields, types, strings) = if flags & TYPED_FIELDS != 0 {
+            let (fields, types, strings) = deserialize_typed_fields(b, num_fields)?;
+            (fields, Some(types), strings)
+        } else {
+            (deserialize_fields(b)?, None, vec![])
+        };
+        if fields.len() != usize::from(num_fields) {
+            msg.inconsistent(|| {
+                format!("{} fields, but says it has {}", fields.len(), num_fields)
+            })?;
+        }
 
         Ok(Msg {
             sid: msg.sid,
-            program_uid: u32s[0],
-            num_fields: u32s[1] as u8,
-            fields: deserialize_fields(b)?,
+            program_uid,
+            num_fields,
+            fields,
+            types,
+            timestamp,
+            strings,
         })
     }
 }
@@ -83,6 +660,9 @@ mod tests {
                     program_uid: $program_uid,
                     num_fields: $fields.len() as u8,
                     fields: $fields,
+                    types: None,
+                    timestamp: None,
+                    strings: vec![],
                 },
                 crate::serialize::Msg::Ms(mes),
                 mes
@@ -113,4 +693,470 @@ mod tests {
             42424242, 42424242, 42424242, 42424242, 42424242, 42424242, 42424242, 42424242
         ]
     );
+
+    #[test]
+    fn test_measure_fields_allocated_once() {
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 13,
+            fields: (0..13).collect(),
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        };
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        match crate::serialize::Msg::from_buf(&buf[..]).expect("deserialize") {
+            (crate::serialize::Msg::Ms(mes), _) => {
+                assert_eq!(mes, m);
+                assert_eq!(mes.fields.capacity(), 13);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    check_msg!(
+        test_measure_typed,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 72,
+            num_fields: 5,
+            fields: vec![424242, 65535, 1, (-1500i64) as u64, 0],
+            types: Some(vec![
+                super::FieldType::Num,
+                super::FieldType::Num32,
+                super::FieldType::Bool,
+                super::FieldType::Int,
+                super::FieldType::Bool,
+            ]),
+            timestamp: None,
+            strings: vec![],
+        },
+        crate::serialize::Msg::Ms(mes),
+        mes
+    );
+
+    #[test]
+    fn lazy_measure() {
+        use super::FieldType;
+
+        let untyped = super::Msg {
+            timestamp: Some(7),
+            ..super::Msg::new(1, 2, (0..30).map(|i| i << 33).collect())
+        };
+        let typed = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 4,
+            fields: vec![14480, 1448, (-1i64) as u64, 1],
+            types: Some(vec![
+                FieldType::Num,
+                FieldType::Num32,
+                FieldType::Int,
+                FieldType::Bool,
+            ]),
+            timestamp: None,
+            strings: vec![],
+        };
+        for m in &[untyped, typed, super::Msg::new(1, 2, vec![])] {
+            let buf = crate::serialize::serialize(m).expect("serialize");
+            let lazy = super::Msg::lazy(&buf).expect("lazy");
+            assert_eq!(lazy.to_msg(), *m);
+            assert_eq!((lazy.sid, lazy.timestamp), (m.sid, m.timestamp));
+            assert_eq!(lazy.fields.len(), m.fields.len());
+            assert_eq!(lazy.fields.iter().collect::<Vec<_>>(), m.fields);
+            for (i, val) in m.fields.iter().enumerate() {
+                assert_eq!(lazy.fields.get(i), Some(*val));
+                assert_eq!(
+                    lazy.fields.field_type(i),
+                    m.types.as_ref().map(|types| types[i])
+                );
+            }
+            assert_eq!(lazy.fields.get(m.fields.len()), None);
+            assert_eq!(lazy.fields.field_type(m.fields.len()), None);
+        }
+    }
+
+    #[test]
+    fn lazy_measure_malformed() {
+        let m = super::Msg {
+            types: Some(vec![super::FieldType::Bool]),
+            ..super::Msg::new(1, 2, vec![1])
+        };
+        let mut buf = crate::serialize::serialize(&m).expect("serialize");
+        buf[16] = 9; // an unknown type
+        assert!(super::Msg::lazy(&buf).is_err());
+        buf[16] = 0; // a truncated value
+        assert!(super::Msg::lazy(&buf).is_err());
+
+        let mut buf =
+            crate::serialize::serialize(&super::Msg::new(1, 2, vec![1, 2])).expect("serialize");
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        assert!(super::Msg::lazy(&buf).is_err());
+
+        let buf = crate::serialize::serialize(&crate::serialize::uninstall::Msg {
+            sid: 1,
+            program_uid: 2,
+        })
+        .expect("serialize");
+        assert_eq!(
+            super::Msg::lazy(&buf).unwrap_err(),
+            crate::Error::from(crate::WrongMsgTypeError)
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_measure_msg() {
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 0x0102_0304,
+            num_fields: 2,
+            fields: vec![0x0102_0304_0506_0708, 1],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                1, 0, // MEASURE
+                32, 0, // length = 32
+                1, 0, 0, 0, // sock_id = 1
+                4, 3, 2, 1, // program_uid = 0x01020304
+                2, 0, 0, 0, // num_fields = 2
+                8, 7, 6, 5, 4, 3, 2, 1, // field 0 = 0x0102030405060708
+                1, 0, 0, 0, 0, 0, 0, 0, // field 1 = 1
+            ],
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_typed_measure_msg() {
+        use super::FieldType;
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 3,
+            fields: vec![7, 1, (-2i64) as u64],
+            types: Some(vec![FieldType::Num32, FieldType::Bool, FieldType::Int]),
+            timestamp: None,
+            strings: vec![],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                1, 0, // MEASURE
+                32, 0, // length = 32
+                1, 0, 0, 0, // sock_id = 1
+                2, 0, 0, 0, // program_uid = 2
+                3, 0, 0, 0x80, // num_fields = 3, typed
+                1, 7, 0, 0, 0, // Num32 7
+                2, 1, // Bool true
+                3, 0xfe, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, // Int -2
+            ],
+        );
+    }
+
+    #[test]
+    fn typed_measure_does_not_fit() {
+        use super::FieldType;
+        let mut m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 1,
+            fields: vec![2],
+            types: Some(vec![FieldType::Bool]),
+            timestamp: None,
+            strings: vec![],
+        };
+        assert!(crate::serialize::serialize(&m).is_err());
+
+        m.fields = vec![u64::from(u32::MAX) + 1];
+        m.types = Some(vec![FieldType::Num32]);
+        assert!(crate::serialize::serialize(&m).is_err());
+
+        m.types = Some(vec![]);
+        assert!(crate::serialize::serialize(&m).is_err());
+    }
+
+    #[test]
+    fn typed_measure_bad_tag() {
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 1,
+            fields: vec![1],
+            types: Some(vec![super::FieldType::Bool]),
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize");
+        buf[16] = 9;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+
+        // a truncated value
+        buf[16] = 0;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
+
+    check_msg!(
+        test_measure_str,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 72,
+            num_fields: 4,
+            fields: vec![424242, 0, 1, 1],
+            types: Some(vec![
+                super::FieldType::Num,
+                super::FieldType::Str,
+                super::FieldType::Bool,
+                super::FieldType::Str,
+            ]),
+            timestamp: None,
+            strings: vec![String::from("Recovery"), String::new()],
+        },
+        crate::serialize::Msg::Ms(mes),
+        mes
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_str_measure_msg() {
+        use super::FieldType;
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 2,
+            fields: vec![0, 7],
+            types: Some(vec![FieldType::Str, FieldType::Num32]),
+            timestamp: None,
+            strings: vec![String::from("Open")],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                1, 0, // MEASURE
+                27, 0, // length = 27
+                1, 0, 0, 0, // sock_id = 1
+                2, 0, 0, 0, // program_uid = 2
+                2, 0, 0, 0x80, // num_fields = 2, typed
+                4, 4, b'O', b'p', b'e', b'n', // Str "Open"
+                1, 7, 0, 0, 0, // Num32 7
+            ],
+        );
+
+        let lazy = super::Msg::lazy(&buf).expect("lazy");
+        assert_eq!(lazy.fields.get_str(0), Some("Open"));
+        assert_eq!(lazy.fields.get(0), Some(0));
+        assert_eq!(lazy.fields.get_str(1), None);
+        assert_eq!(lazy.fields.get(1), Some(7));
+        assert_eq!(lazy.to_msg(), m);
+    }
+
+    #[test]
+    fn str_measure_invalid_utf8() {
+        use crate::serialize::ParseError;
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 1,
+            fields: vec![0],
+            types: Some(vec![super::FieldType::Str]),
+            timestamp: None,
+            strings: vec![String::from("ok")],
+        })
+        .expect("serialize");
+        buf[18] = 0xff;
+        let buf = crate::serialize::checksum::fix_up(buf);
+
+        let err = crate::serialize::Msg::from_buf(&buf).unwrap_err();
+        match ParseError::of(&err) {
+            Some(ParseError::BadPayload(_)) => (),
+            e => panic!("expected a bad payload, got {:?}", e),
+        }
+        let err = super::Msg::lazy(&buf).unwrap_err();
+        match ParseError::of(&err) {
+            Some(ParseError::BadPayload(_)) => (),
+            e => panic!("expected a bad payload, got {:?}", e),
+        }
+    }
+
+    #[test]
+    fn str_measure_too_long() {
+        let mut m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 1,
+            fields: vec![0],
+            types: Some(vec![super::FieldType::Str]),
+            timestamp: None,
+            strings: vec!["x".repeat(super::MAX_STR_LEN)],
+        };
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            crate::serialize::Msg::from_buf(&buf)
+                .expect("deserialize")
+                .0,
+            crate::serialize::Msg::Ms(m.clone())
+        );
+
+        m.strings = vec!["x".repeat(super::MAX_STR_LEN + 1)];
+        assert!(crate::serialize::serialize(&m).is_err());
+        // nor can a field index past the strings
+        m.strings = vec![String::new()];
+        m.fields = vec![1];
+        assert!(crate::serialize::serialize(&m).is_err());
+
+        // a datapath which sends one anyway
+        let mut buf = buf;
+        buf[17] = super::MAX_STR_LEN as u8 + 1;
+        buf.push(b'x');
+        buf[2] = buf.len() as u8;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+        assert!(super::Msg::lazy(&buf).is_err());
+    }
+
+    check_msg!(
+        test_measure_timestamped,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 72,
+            num_fields: 2,
+            fields: vec![424242, 65535],
+            types: None,
+            timestamp: Some(1_234_567_890_123),
+            strings: vec![],
+        },
+        crate::serialize::Msg::Ms(mes),
+        mes
+    );
+
+    check_msg!(
+        test_measure_typed_timestamped,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 72,
+            num_fields: 2,
+            fields: vec![424242, 1],
+            types: Some(vec![super::FieldType::Num, super::FieldType::Bool]),
+            timestamp: Some(u64::MAX),
+            strings: vec![],
+        },
+        crate::serialize::Msg::Ms(mes),
+        mes
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_timestamped_measure_msg() {
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 1,
+            fields: vec![7],
+            types: None,
+            timestamp: Some(0x0102_0304_0506_0708),
+            strings: vec![],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                1, 0, // MEASURE
+                32, 0, // length = 32
+                1, 0, 0, 0, // sock_id = 1
+                2, 0, 0, 0, // program_uid = 2
+                1, 0, 0, 0x40, // num_fields = 1, timestamped
+                8, 7, 6, 5, 4, 3, 2, 1, // timestamp
+                7, 0, 0, 0, 0, 0, 0, 0, // field 0 = 7
+            ],
+        );
+    }
+
+    #[test]
+    fn measure_without_timestamp() {
+        // a measurement in the old format, as libccp sends it
+        let buf = crate::serialize::checksum::fix_up(vec![
+            1, 0, // MEASURE
+            24, 0, // length = 24
+            1, 0, 0, 0, // sock_id = 1
+            2, 0, 0, 0, // program_uid = 2
+            1, 0, 0, 0, // num_fields = 1
+            7, 0, 0, 0, 0, 0, 0, 0, // field 0 = 7
+        ]);
+        match crate::serialize::Msg::from_buf(&buf).expect("deserialize") {
+            (crate::serialize::Msg::Ms(mes), _) => {
+                assert_eq!(mes.timestamp, None);
+                assert_eq!(mes.fields, vec![7]);
+            }
+            m => panic!("expected a measurement, got {:?}", m),
+        }
+    }
+
+    #[test]
+    fn timestamp_truncated() {
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            sid: 1,
+            program_uid: 2,
+            num_fields: 0,
+            fields: vec![],
+            types: None,
+            timestamp: Some(7),
+            strings: vec![],
+        })
+        .expect("serialize");
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
+
+    // Parsing borrows the message from the buffer it is in: only the fields, and their types,
+    // are copied out of it.
+    #[test]
+    fn parse_allocs() {
+        use crate::ipc::test::allocs;
+        use crate::serialize::{self, Msg};
+
+        let mut m = super::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 3,
+            fields: vec![1, 2, 1],
+            types: None,
+            timestamp: Some(1),
+            strings: vec![],
+        };
+        let buf = serialize::serialize(&m).expect("serialize");
+        let before = allocs();
+        let parsed = Msg::from_buf(&buf).expect("deserialize");
+        let used = allocs() - before;
+        assert_eq!(parsed, (Msg::Ms(m.clone()), buf.len()));
+        assert_eq!(used, 1);
+
+        m.types = Some(vec![
+            super::FieldType::Num,
+            super::FieldType::Num32,
+            super::FieldType::Bool,
+        ]);
+        let buf = serialize::serialize(&m).expect("serialize");
+        let before = allocs();
+        let parsed = Msg::from_buf(&buf).expect("deserialize");
+        let used = allocs() - before;
+        assert_eq!(parsed, (Msg::Ms(m), buf.len()));
+        assert_eq!(used, 2);
+    }
 }
@@ -0,0 +1,322 @@
+//! Many flows' measurements in one message, e.g. from a kernel datapath reporting thousands of
+//! flows at once. CCP handles each as if it had arrived in a measurement message of its own.
+//!
+//! The header's socket id is 0. The body is the number of measurements, and then each
+//! measurement's socket id, program uid, field count, and fields:
+//!
+//! ```text
+//! ---------------------------------------------------------------------------------
+//! | Count     | Sid       | Program uid | Num fields | Fields         | Sid | ...
+//! | (32 bits) | (32 bits) | (32 bits)   | (32 bits)  | (64 bits each) |     |
+//! ---------------------------------------------------------------------------------
+//! ```
+//!
+//! If any measurement's socket id does not fit in 32 bits, the count has `WIDE_SIDS` set, and
+//! every measurement's socket id is 64 bits.
+//!
+//! The measurements are untyped and have no timestamps (see `measure`).
+
+use super::{measure, msg_len, u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s};
+use super::{AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of measurement batch messages.
+pub const MEASURE_BATCH: u8 = 13;
+
+/// Set in the count of a batch whose socket ids are 64 bits.
+pub const WIDE_SIDS: u32 = 1 << 31;
+
+// sid, program uid, and field count
+const RECORD_HDR_LENGTH: u32 = 3 * 4;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Msg {
+    pub measurements: Vec<measure::Msg>,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { measurements });
+
+impl Msg {
+    fn wide(&self) -> bool {
+        self.measurements
+            .iter()
+            .any(|m| m.sid > u64::from(u32::MAX))
+    }
+
+    fn record_hdr_len(&self) -> u32 {
+        if self.wide() {
+            RECORD_HDR_LENGTH + 4
+        } else {
+            RECORD_HDR_LENGTH
+        }
+    }
+
+    fn len(&self) -> u32 {
+        let record_hdr_len = self.record_hdr_len();
+        self.measurements
+            .iter()
+            .map(|m| msg_len(record_hdr_len, m.fields.len(), 8) - HDR_LENGTH)
+            .fold(HDR_LENGTH + 4, u32::saturating_add)
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "measure_batch measurements={}", self.measurements.len())?;
+        for m in &self.measurements {
+            write!(f, " {{{}}}", m)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        MEASURE_BATCH
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (MEASURE_BATCH, self.len(), 0)
+    }
+
+    fn check_counts(&self) -> Result<()> {
+        self.measurements.iter().try_for_each(|m| m.check_counts())
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        let flags = if self.wide() { WIDE_SIDS } else { 0 };
+        u32_to_u8s(&mut buf, self.measurements.len() as u32 | flags);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
+        let wide = self.wide();
+        let mut buf = [0u8; 8];
+        for m in &self.measurements {
+            if m.types.is_some() || m.timestamp.is_some() {
+                return Err(Error(format!(
+                    "batched measurements are untyped and have no timestamps: {:?}",
+                    m
+                )));
+            }
+
+            if wide {
+                u64_to_u8s(&mut buf, m.sid);
+                w.write_all(&buf[..])?;
+            } else {
+                u32_to_u8s(&mut buf[..4], m.sid as u32);
+                w.write_all(&buf[..4])?;
+            }
+
+            for x in &[m.program_uid, m.fields.len() as u32] {
+                u32_to_u8s(&mut buf[..4], *x);
+                w.write_all(&buf[..4])?;
+            }
+
+            for f in &m.fields {
+                u64_to_u8s(&mut buf, *f);
+                w.write_all(&buf[..])?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let mut b = msg.get_bytes()?;
+        if b.len() < 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 4,
+                actual: b.len(),
+            }));
+        }
+
+        let count = u32_from_u8s(&b[0..4]);
+        let (count, sid_len) = if count & WIDE_SIDS != 0 {
+            ((count & !WIDE_SIDS) as usize, 8)
+        } else {
+            (count as usize, 4)
+        };
+        let record_hdr_len = sid_len + 8;
+        b = &b[4..];
+        // a corrupt count should not allocate more than the message could hold
+        let mut measurements = Vec::with_capacity(count.min(b.len() / record_hdr_len));
+        for _ in 0..count {
+            if b.len() < record_hdr_len {
+                return Err(Error::from(ParseError::BadLength {
+                    declared: record_hdr_len,
+                    actual: b.len(),
+                }));
+            }
+
+            let num_fields = u32_from_u8s(&b[sid_len + 4..record_hdr_len]) as usize;
+            let len = record_hdr_len + 8 * num_fields;
+            if b.len() < len {
+                return Err(Error::from(ParseError::BadLength {
+                    declared: len,
+                    actual: b.len(),
+                }));
+            }
+
+            measurements.push(measure::Msg {
+                sid: if sid_len == 8 {
+                    u64_from_u8s(&b[0..8])
+                } else {
+                    u64::from(u32_from_u8s(&b[0..4]))
+                },
+                program_uid: u32_from_u8s(&b[sid_len..sid_len + 4]),
+                num_fields: num_fields as u8,
+                fields: b[record_hdr_len..len].chunks(8).map(u64_from_u8s).collect(),
+                types: None,
+                timestamp: None,
+                strings: vec![],
+            });
+            b = &b[len..];
+        }
+        if !b.is_empty() {
+            msg.inconsistent(|| format!("{} bytes after its last measurement", b.len()))?;
+        }
+
+        Ok(Msg { measurements })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    fn measurement(sid: u64, fields: Vec<u64>) -> crate::serialize::measure::Msg {
+        crate::serialize::measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: fields.len() as u8,
+            fields,
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        }
+    }
+
+    check_msg!(
+        test_measure_batch_1,
+        super::Msg,
+        super::Msg {
+            measurements: vec![
+                measurement(1, vec![424242, 65535]),
+                measurement(2, vec![]),
+                measurement(3, vec![42; 5]),
+            ],
+        },
+        crate::serialize::Msg::MsBatch(mb),
+        mb
+    );
+
+    check_msg!(
+        test_measure_batch_wide_sids,
+        super::Msg,
+        super::Msg {
+            measurements: vec![
+                measurement(1, vec![424242, 65535]),
+                measurement(0x1234_5678_9abc_def0, vec![]),
+                measurement(u64::MAX, vec![42; 5]),
+            ],
+        },
+        crate::serialize::Msg::MsBatch(mb),
+        mb
+    );
+
+    check_msg!(
+        test_measure_batch_empty,
+        super::Msg,
+        super::Msg {
+            measurements: vec![],
+        },
+        crate::serialize::Msg::MsBatch(mb),
+        mb
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_measure_batch_msg() {
+        let m = super::Msg {
+            measurements: vec![measurement(1, vec![7]), measurement(2, vec![])],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                13, 0, // MEASURE_BATCH
+                44, 0, // length = 44
+                0, 0, 0, 0, // sock_id = 0
+                2, 0, 0, 0, // count = 2
+                1, 0, 0, 0, // sid = 1
+                7, 0, 0, 0, // program_uid = 7
+                1, 0, 0, 0, // num_fields = 1
+                7, 0, 0, 0, 0, 0, 0, 0, // field 0 = 7
+                2, 0, 0, 0, // sid = 2
+                7, 0, 0, 0, // program_uid = 7
+                0, 0, 0, 0, // num_fields = 0
+            ],
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_measure_batch_wide_msg() {
+        let m = super::Msg {
+            measurements: vec![measurement(1 << 32 | 1, vec![7])],
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                13, 0, // MEASURE_BATCH
+                36, 0, // length = 36
+                0, 0, 0, 0, // sock_id = 0
+                1, 0, 0, 0x80, // count = 1 | WIDE_SIDS
+                1, 0, 0, 0, 1, 0, 0, 0, // sid = 1 << 32 | 1
+                7, 0, 0, 0, // program_uid = 7
+                1, 0, 0, 0, // num_fields = 1
+                7, 0, 0, 0, 0, 0, 0, 0, // field 0 = 7
+            ],
+        );
+    }
+
+    #[test]
+    fn measure_batch_truncated() {
+        let mut buf = crate::serialize::serialize(&super::Msg {
+            measurements: vec![measurement(1, vec![7, 8])],
+        })
+        .expect("serialize");
+        // claim one more measurement than there is
+        buf[8] = 2;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+
+        // and cut the last field short
+        buf[8] = 1;
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
+
+    #[test]
+    fn measure_batch_unserializable() {
+        let m = super::Msg {
+            measurements: vec![measurement(1, vec![7; 200]); 50],
+        };
+        assert!(crate::serialize::serialize(&m).is_err());
+
+        let mut typed = measurement(1, vec![7]);
+        typed.types = Some(vec![crate::serialize::measure::FieldType::Num]);
+        let m = super::Msg {
+            measurements: vec![typed],
+        };
+        assert!(crate::serialize::serialize(&m).is_err());
+    }
+}
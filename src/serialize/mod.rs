@@ -10,10 +10,39 @@
 //! total: 8 Bytes
 //! ```
 //!
-//! Message types 0-3 are reserved for predefined message types. All other types are treated as
+//! A socket id too wide for the header's 32 bits, e.g. a userspace datapath's 64-bit flow hash,
+//! is sent with `WIDE_SID` set in the type and the id's top 32 bits after the header, which the
+//! header's length counts:
+//!
+//! ```text
+//! -------------------------------------------------------
+//! | Msg Type   | Len (B)  | Sid low   | Sid high  | ... |
+//! | | WIDE_SID | (2 B)    | (32 bits) | (32 bits) |     |
+//! -------------------------------------------------------
+//! ```
+//!
+//! Socket ids which fit in 32 bits are always sent in the narrow header, so peers which never
+//! use wide ids (before minor version 3) see no change.
+//!
+//! Every number is little-endian on the wire (see `WireOrder`), whatever the host's.
+//!
+//! Message types 0-14 are reserved for predefined message types (see `is_predefined_type`), each
+//! with its type's constant in its module, e.g. `create::CREATE`. All other types are treated as
 //! "unknown" - the header will be parsed, and raw access to the remaining bytes is available
 //! through `RawMsg::get_bytes()`.
 //!
+//! A message longer than the header's 16-bit length allows, e.g. an install message for a large
+//! program, has `EXTENDED_LEN` set in its type, 0 in the header's length, and its length in the
+//! 32 bits after the rest of the header (after any wide socket id). Only message types which may
+//! need it have it (see `AsRawMsg::EXTENDABLE`); peers before minor version 4 take it for a
+//! malformed message.
+//!
+//! The high byte of the message type holds flags (see `FLAGS`): `compress::COMPRESSED`,
+//! `checksum::CHECKSUMMED`, `sequence::SEQUENCED`, `WIDE_SID`, and `EXTENDED_LEN`. A flag in its
+//! top six bits changes how the message is read, so a message with one this portus does not
+//! know is malformed; one in its bottom two bits may be ignored by a peer which does not know it
+//! (since minor version 5).
+//!
 //! A message type has 4 components, always in the following order.
 //! 1. CCP Header
 //! 2. u32s
@@ -23,98 +52,609 @@
 //! For convenience, the predefined message types define a number of u32s and u64s.
 //! External message types can implement `get_bytes()` to pass custom types in the message payload.
 //! In these cases, there is little deserialization overhead from the u32 and u64 parts of the message.
+//!
+//! # Datapaths
+//!
+//! A datapath written in Rust can speak to CCP with this module alone: `serialize` the messages
+//! it sends, e.g. `create::Msg` when a flow starts, and parse those CCP sends it with
+//! `Msg::from_buf`. `parse_header` tells the type and length of a message without parsing the
+//! rest of it. For example, receiving from CCP over a unix datagram socket, with each datagram
+//! holding whole messages:
+//!
+//! ```no_run
+//! use portus::serialize::{self, heartbeat, Msg};
+//! use std::os::unix::net::UnixDatagram;
+//!
+//! fn recv_loop(sock: &UnixDatagram) -> portus::Result<()> {
+//!     let mut buf = vec![0u8; 65536];
+//!     loop {
+//!         let n = sock.recv(&mut buf)?;
+//!         let mut rest = &buf[..n];
+//!         while !rest.is_empty() {
+//!             let (typ, len, _sid) = serialize::parse_header(rest)?;
+//!             let msg = rest
+//!                 .get(..len as usize)
+//!                 .ok_or_else(|| portus::Error(String::from("message cut short")))?;
+//!             rest = &rest[msg.len()..];
+//!
+//!             // CCP checks the datapath is alive: echo its heartbeats as they are
+//!             if typ == heartbeat::HEARTBEAT {
+//!                 sock.send(msg)?;
+//!                 continue;
+//!             }
+//!
+//!             match Msg::from_buf(msg)?.0 {
+//!                 Msg::Ins(m) => println!("install program {}:\n{}", m.program_uid, m.instrs),
+//!                 Msg::Cp(m) => println!("flow {} runs program {}", m.sid, m.program_uid),
+//!                 Msg::Uf(m) => {
+//!                     for (reg, val) in &m.fields {
+//!                         println!("flow {} sets {} to {}", m.sid, reg, val);
+//!                     }
+//!                 }
+//!                 Msg::Un(m) => println!("flow {} stops running program {}", m.sid, m.program_uid),
+//!                 Msg::Td(m) => println!("flow {} falls back to the datapath's own", m.sid),
+//!                 other => println!("ignoring {}", other),
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let sock = UnixDatagram::bind("/tmp/ccp/0/out")?;
+//! sock.connect("/tmp/ccp/0/in")?;
+//! let ready = serialize::serialize(&serialize::ready::Msg { id: 0 })?;
+//! sock.send(&ready)?;
+//! recv_loop(&sock)?;
+//! # Ok::<(), portus::Error>(())
+//! ```
 
 use super::Result;
-use byteorder::{ByteOrder, LittleEndian};
+use byteorder::ByteOrder;
+use std::convert::TryFrom;
+use std::fmt;
 use std::io::prelude::*;
 use std::io::Cursor;
 use std::vec::Vec;
 use tracing::debug;
 
-fn u16_to_u8s(buf: &mut [u8], num: u16) {
-    LittleEndian::write_u16(buf, num);
+/// The byte order of every number in a message, header included, whatever the host's.
+pub type WireOrder = byteorder::LittleEndian;
+
+pub(crate) fn u16_to_u8s(buf: &mut [u8], num: u16) {
+    WireOrder::write_u16(buf, num);
 }
 
 pub(crate) fn u32_to_u8s(buf: &mut [u8], num: u32) {
-    LittleEndian::write_u32(buf, num);
+    WireOrder::write_u32(buf, num);
 }
 
 pub(crate) fn u64_to_u8s(buf: &mut [u8], num: u64) {
-    LittleEndian::write_u64(buf, num);
+    WireOrder::write_u64(buf, num);
 }
 
-fn u16_from_u8s(buf: &[u8]) -> u16 {
-    LittleEndian::read_u16(buf)
+pub(crate) fn u16_from_u8s(buf: &[u8]) -> u16 {
+    WireOrder::read_u16(buf)
 }
 
 pub(crate) fn u32_from_u8s(buf: &[u8]) -> u32 {
-    LittleEndian::read_u32(buf)
+    WireOrder::read_u32(buf)
 }
 
 pub(crate) fn u64_from_u8s(buf: &[u8]) -> u64 {
-    LittleEndian::read_u64(buf)
+    WireOrder::read_u64(buf)
 }
 
+// Append `num` to `buf` as an unsigned LEB128 varint: 7 bits to a byte, least significant first,
+// with the top bit set in every byte but the last.
+pub(crate) fn write_varint(buf: &mut Vec<u8>, mut num: u32) {
+    while num >= 0x80 {
+        buf.push(num as u8 | 0x80);
+        num >>= 7;
+    }
+
+    buf.push(num as u8);
+}
+
+// Read a varint written by `write_varint` from the start of `buf`, and return it with how many
+// bytes it took, unless it is cut short or too big for 32 bits.
+pub(crate) fn read_varint(buf: &[u8]) -> Option<(u32, usize)> {
+    let mut num = 0u64;
+    for (i, b) in buf.iter().take(5).enumerate() {
+        num |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return u32::try_from(num).ok().map(|num| (num, i + 1));
+        }
+    }
+
+    None
+}
+
+/// The length of a message's header, unless it has a wide socket id (see `WIDE_HDR_LENGTH`) or an
+/// extended length.
 pub const HDR_LENGTH: u32 = 8;
-fn serialize_header(typ: u8, len: u32, sid: u32) -> Vec<u8> {
-    let mut hdr = [0u8; 8];
-    u16_to_u8s(&mut hdr[0..2], u16::from(typ));
-    u16_to_u8s(&mut hdr[2..4], len as u16);
-    u32_to_u8s(&mut hdr[4..], sid);
-    hdr.to_vec()
+
+/// The flags in a message's type: its high byte, above the 8-bit type itself.
+pub const FLAGS: u16 = 0xff00;
+
+/// The flags a peer must understand to read a message.
+pub const MANDATORY_FLAGS: u16 = 0xfc00;
+
+/// The flags a peer which does not know them ignores, and which a message may set itself (see
+/// `AsRawMsg::get_flags`).
+pub const OPTIONAL_FLAGS: u16 = 0x0300;
+
+// The mandatory flags this portus understands.
+const KNOWN_FLAGS: u16 =
+    compress::COMPRESSED | checksum::CHECKSUMMED | sequence::SEQUENCED | WIDE_SID | EXTENDED_LEN;
+
+/// Set in the type of a message whose socket id does not fit in 32 bits.
+pub const WIDE_SID: u16 = 0x1000;
+
+/// The length of the header of a message with `WIDE_SID` set.
+pub const WIDE_HDR_LENGTH: u32 = HDR_LENGTH + 4;
+
+/// Set in the type of a message too long for the header's 16-bit length, whose length is then
+/// the 32 bits after the rest of the header.
+pub const EXTENDED_LEN: u16 = 0x0800;
+
+const EXTENDED_LEN_LENGTH: usize = 4;
+
+// The longest header: a wide socket id and an extended length.
+const MAX_HDR_LENGTH: usize = WIDE_HDR_LENGTH as usize + EXTENDED_LEN_LENGTH;
+
+// A serialized header, the first `len` bytes of `buf`, so that serializing a message does not
+// allocate for it.
+struct Header {
+    buf: [u8; MAX_HDR_LENGTH],
+    len: usize,
+}
+
+impl std::ops::Deref for Header {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
 }
 
-fn deserialize_header<R: Read>(buf: &mut R) -> Result<(u8, u32, u32)> {
-    let mut hdr = [0u8; 8];
+impl std::ops::DerefMut for Header {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len]
+    }
+}
+
+// `len` is the message's length with a narrow header; a wide socket id, or an extended length,
+// adds to it. A message longer than the 16-bit length allows is an error unless `extendable`.
+// `flags` are the message's optional ones.
+fn serialize_header(typ: u8, flags: u16, len: u32, sid: u64, extendable: bool) -> Result<Header> {
+    if flags & !OPTIONAL_FLAGS != 0 {
+        return Err(super::Error(format!(
+            "message type {} sets flags {:#06x}, which are not optional",
+            typ,
+            flags & !OPTIONAL_FLAGS
+        )));
+    }
+
+    let mut typ = u16::from(typ) | flags;
+    let mut hdr = Header {
+        buf: [0u8; MAX_HDR_LENGTH],
+        len: HDR_LENGTH as usize,
+    };
+    u32_to_u8s(&mut hdr.buf[4..8], sid as u32);
+    if sid > u64::from(u32::MAX) {
+        typ |= WIDE_SID;
+        u32_to_u8s(&mut hdr.buf[hdr.len..hdr.len + 4], (sid >> 32) as u32);
+        hdr.len += 4;
+    }
+
+    let len = u64::from(len) + (hdr.len - HDR_LENGTH as usize) as u64;
+    if len > u64::from(u16::MAX) {
+        let len = len + EXTENDED_LEN_LENGTH as u64;
+        if !extendable || len > u64::from(u32::MAX) {
+            return Err(super::Error(format!(
+                "message type {} too long: {} bytes",
+                typ as u8, len
+            )));
+        }
+
+        typ |= EXTENDED_LEN;
+        u32_to_u8s(
+            &mut hdr.buf[hdr.len..hdr.len + EXTENDED_LEN_LENGTH],
+            len as u32,
+        );
+        hdr.len += EXTENDED_LEN_LENGTH;
+    } else {
+        u16_to_u8s(&mut hdr.buf[2..4], len as u16);
+    }
+
+    u16_to_u8s(&mut hdr.buf[0..2], typ);
+    Ok(hdr)
+}
+
+fn deserialize_header<R: Read>(buf: &mut R) -> Result<(u8, u32, u64)> {
+    let mut hdr = [0u8; HDR_LENGTH as usize];
     buf.read_exact(&mut hdr)?;
     let typ = u16_from_u8s(&hdr[0..2]);
-    let len = u16_from_u8s(&hdr[2..4]);
-    let sid = u32_from_u8s(&hdr[4..]);
+    let mut len = u32::from(u16_from_u8s(&hdr[2..4]));
+    let mut sid = u64::from(u32_from_u8s(&hdr[4..8]));
+    let mut word = [0u8; 4];
+    if typ & WIDE_SID != 0 {
+        buf.read_exact(&mut word)?;
+        sid |= u64::from(u32_from_u8s(&word)) << 32;
+    }
+    if typ & EXTENDED_LEN != 0 {
+        buf.read_exact(&mut word)?;
+        len = u32_from_u8s(&word);
+    }
+
+    Ok((typ as u8, len, sid))
+}
+
+/// Whether the message at the start of `buf` has a wide socket id.
+pub fn is_wide(buf: &[u8]) -> bool {
+    buf.len() >= HDR_LENGTH as usize && u16_from_u8s(&buf[0..2]) & WIDE_SID != 0
+}
+
+/// Whether the message at the start of `buf` has an extended length.
+pub fn is_extended(buf: &[u8]) -> bool {
+    buf.len() >= HDR_LENGTH as usize && u16_from_u8s(&buf[0..2]) & EXTENDED_LEN != 0
+}
+
+// The length of the header of the message at the start of `buf`.
+pub(crate) fn hdr_len(buf: &[u8]) -> usize {
+    let mut len = HDR_LENGTH as usize;
+    if is_wide(buf) {
+        len += (WIDE_HDR_LENGTH - HDR_LENGTH) as usize;
+    }
+    if is_extended(buf) {
+        len += EXTENDED_LEN_LENGTH;
+    }
+
+    len
+}
+
+// The length the header at the start of `buf` gives its message, if `buf` holds enough of the
+// header to tell, e.g. while reading a stream. Unlike `frame_len`, it does not check the header.
+pub(crate) fn claimed_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < 4 {
+        return None;
+    }
+    if u16_from_u8s(&buf[0..2]) & EXTENDED_LEN == 0 {
+        return Some(usize::from(u16_from_u8s(&buf[2..4])));
+    }
+
+    let at = hdr_len(buf) - EXTENDED_LEN_LENGTH;
+    buf.get(at..at + EXTENDED_LEN_LENGTH)
+        .map(|b| u32_from_u8s(b) as usize)
+}
+
+// Set the length in the header of `msg`, a serialized message, to `len`. A message without an
+// extended length cannot grow past the 16-bit length.
+pub(crate) fn set_len(msg: &mut [u8], len: usize) -> Result<()> {
+    if is_extended(msg) {
+        let at = hdr_len(msg) - EXTENDED_LEN_LENGTH;
+        if len > u32::MAX as usize {
+            return Err(super::Error(format!("message too long: {} bytes", len)));
+        }
+        u32_to_u8s(&mut msg[at..at + EXTENDED_LEN_LENGTH], len as u32);
+    } else {
+        if len > usize::from(u16::MAX) {
+            return Err(super::Error(format!("message too long: {} bytes", len)));
+        }
+        u16_to_u8s(&mut msg[2..4], len as u16);
+    }
+
+    Ok(())
+}
+
+// Check that a `msg` message which says it has `declared` `what`s has `actual` of them.
+pub(crate) fn check_count(msg: &str, what: &str, declared: usize, actual: usize) -> Result<()> {
+    if declared != actual {
+        return Err(super::Error(format!(
+            "{} message has {} {}, but says it has {}",
+            msg, actual, what, declared
+        )));
+    }
+
+    Ok(())
+}
+
+// The length of a message with a narrow header, `fixed` more bytes, and `n` items of `each`
+// bytes, saturating rather than wrapping around so that `serialize` rejects it.
+pub(crate) fn msg_len(fixed: u32, n: usize, each: u32) -> u32 {
+    let n = u32::try_from(n).unwrap_or(u32::MAX);
+    n.saturating_mul(each).saturating_add(HDR_LENGTH + fixed)
+}
+
+/// The socket id in the header of the message at the start of `buf`, if it has a whole header.
+pub fn sid(buf: &[u8]) -> Option<u64> {
+    deserialize_header(&mut Cursor::new(buf))
+        .ok()
+        .map(|(_, _, sid)| sid)
+}
 
-    Ok((typ as u8, u32::from(len), sid))
+/// Parse the header of the message at the start of `buf`: its type, without flags (see `FLAGS`),
+/// its length, header included, and its socket id. `buf` need only hold the header, e.g. to find
+/// how much of a stream to read before the whole message is there for `Msg::from_buf`.
+///
+/// It is an error, `ParseError::Incomplete`, if `buf` is too short for the header, and
+/// `ParseError::BadLength` if the header's length is too short for the header itself.
+pub fn parse_header(buf: &[u8]) -> Result<(u8, u32, u64)> {
+    if buf.len() < hdr_len(buf) {
+        return Err(super::Error::from(ParseError::Incomplete {
+            needed: hdr_len(buf) - buf.len(),
+        }));
+    }
+
+    let (typ, len, sid) = deserialize_header(&mut Cursor::new(buf))?;
+    if (len as usize) < hdr_len(buf) {
+        return Err(super::Error::from(ParseError::BadLength {
+            declared: len as usize,
+            actual: hdr_len(buf),
+        }));
+    }
+
+    Ok((typ, len, sid))
+}
+
+/// Whether messages of type `typ` are predefined ones, which portus parses itself, rather than
+/// leaving them as `Msg::Other`. Type 255 is taken too: `Msg::from_buf` gives it to messages it
+/// cannot parse.
+pub fn is_predefined_type(typ: u8) -> bool {
+    matches!(
+        typ,
+        create::CREATE
+            | measure::MEASURE
+            | install::INSTALL
+            | update_field::UPDATE_FIELD
+            | changeprog::CHANGEPROG
+            | ready::READY
+            | heartbeat::HEARTBEAT
+            | capabilities::CAPABILITIES
+            | version::VERSION
+            | error::ERROR
+            | install_ack::INSTALL_ACK
+            | uninstall::UNINSTALL
+            | teardown::TEARDOWN
+            | measure_batch::MEASURE_BATCH
+            | fin::FIN
+            | 255
+    )
+}
+
+/// How strictly `Msg::from_buf_with` holds messages to their format.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseMode {
+    /// A message which is not exactly as its type says is an error, e.g. one with bytes after
+    /// its last field, or with a count which does not match what follows. For tests, and peers
+    /// which should speak exactly this portus's version.
+    Strict,
+    /// A message which can still be read despite such inconsistencies is, and they are logged.
+    /// A newer peer may add fields to a message, which an older CCP then ignores.
+    Lenient,
 }
 
+/// The mode `Msg::from_buf` parses in.
+pub const DEFAULT_PARSE_MODE: ParseMode = ParseMode::Lenient;
+
+impl Default for ParseMode {
+    fn default() -> Self {
+        DEFAULT_PARSE_MODE
+    }
+}
+
+/// What kind of thing is wrong with a message which does not parse. Parsing returns it as an
+/// `Error`, as `Error::from` makes one, for compatibility; `ParseError::of` finds it again:
+///
+/// ```
+/// use portus::serialize::{parse_header, ParseError};
+///
+/// // half a header, as a stream may deliver it
+/// let err = parse_header(&[1, 0, 24, 0]).unwrap_err();
+/// assert_eq!(ParseError::of(&err), Some(ParseError::Incomplete { needed: 4 }));
+/// ```
+///
+/// Errors with a `codes::Code`, e.g. from reading a program (see `lang`), keep it instead, and a
+/// checksum which does not match is `CorruptMsgError`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// The buffer ends before the message does, which it would not with `needed` more bytes, as
+    /// far as the header, or what there is of it, tells. A stream should read more and try again.
+    Incomplete { needed: usize },
+    /// The message has a type tag this portus does not know, e.g. of a measurement's field.
+    /// Messages of types it does not know are not an error: `Msg::from_buf` leaves them as
+    /// `Msg::Other`.
+    UnknownType(u8),
+    /// The message, by its header, its type, or a count in it, declares a length, `declared`,
+    /// which does not fit the `actual` length of what is there.
+    BadLength { declared: usize, actual: usize },
+    /// The message's bytes are otherwise not a message of its type, as described.
+    BadPayload(String),
+}
+
+impl ParseError {
+    /// The parse error `err` was made from, if it was made from one by `Error::from`.
+    pub fn of(err: &super::Error) -> Option<ParseError> {
+        let after = |lead: &str| err.0.find(lead).map(|at| &err.0[at + lead.len()..]);
+        let candidates = [
+            after("incomplete message: needs ")
+                .and_then(|rest| rest.strip_suffix(" more bytes")?.parse().ok())
+                .map(|needed| ParseError::Incomplete { needed }),
+            after("unknown type tag: ")
+                .and_then(|rest| rest.parse().ok())
+                .map(ParseError::UnknownType),
+            after("bad message length: ").and_then(|rest| {
+                let (declared, actual) = rest.strip_suffix(" actual")?.split_once(" declared, ")?;
+                Some(ParseError::BadLength {
+                    declared: declared.parse().ok()?,
+                    actual: actual.parse().ok()?,
+                })
+            }),
+            after("bad message payload: ").map(|rest| ParseError::BadPayload(rest.to_owned())),
+        ];
+
+        // an error which only quotes one, e.g. in its detail, was not made from it
+        candidates
+            .iter()
+            .flatten()
+            .find(|p| super::Error::from((*p).clone()) == *err)
+            .cloned()
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Incomplete { needed } => {
+                write!(f, "incomplete message: needs {} more bytes", needed)
+            }
+            ParseError::UnknownType(typ) => write!(f, "unknown type tag: {}", typ),
+            ParseError::BadLength { declared, actual } => write!(
+                f,
+                "bad message length: {} declared, {} actual",
+                declared, actual
+            ),
+            ParseError::BadPayload(what) => write!(f, "bad message payload: {}", what),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
 #[derive(Clone, Debug, PartialEq)]
-/// A raw messge buffer with a parsed CCP header.
+/// A raw messge buffer with a parsed CCP header. The rest of the message is borrowed from the
+/// buffer it was parsed from, so nothing is copied until `from_raw_msg` builds a message from it.
 pub struct RawMsg<'a> {
     pub typ: u8,
+    /// The flags set in the message's type (see `FLAGS`).
+    pub flags: u16,
     pub len: u32,
-    pub sid: u32,
+    pub sid: u64,
     bytes: &'a [u8],
+    mode: ParseMode,
 }
 
-impl<'a> RawMsg<'a> {
-    /// For predefined messages, get u32s separately for convenience
-    pub(crate) unsafe fn get_u32s(&self) -> Result<&'a [u32]> {
-        use std::mem;
-        match self.typ {
-            create::CREATE => Ok(mem::transmute(&self.bytes[0..(4 * 6)])),
-            measure::MEASURE => Ok(mem::transmute(&self.bytes[0..8])),
-            update_field::UPDATE_FIELD => Ok(mem::transmute(&self.bytes[0..4])),
-            ready::READY => Ok(mem::transmute(&self.bytes[0..(4 * 1)])),
-            _ => Ok(&[]),
+#[cfg(feature = "serde")]
+serde_struct!(@ser RawMsg<'_> {
+    typ,
+    flags,
+    len,
+    sid,
+    bytes,
+});
+
+/// The header, then the rest of the message in hex.
+impl fmt::Display for RawMsg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "type {} sid={} len={} bytes=",
+            self.typ, self.sid, self.len
+        )?;
+        for b in self.bytes {
+            write!(f, "{:02x}", b)?;
         }
+
+        Ok(())
+    }
+}
+
+impl<'a> RawMsg<'a> {
+    /// For predefined messages, get the `idx`th u32 separately for convenience. It is an error if
+    /// the message is too short to have it.
+    pub(crate) fn get_u32(&self, idx: usize) -> Result<u32> {
+        let at = 4 * idx;
+        self.bytes
+            .get(at..at + 4)
+            .map(u32_from_u8s)
+            .ok_or_else(|| self.too_short(at, 4))
     }
 
     /// For predefined messages, bytes blob is whatever's left (may be nothing)
     /// For other message types, just return the bytes blob
     pub fn get_bytes(&self) -> Result<&'a [u8]> {
-        match self.typ {
-            create::CREATE => Ok(&self.bytes[(4 * 6)..(self.len as usize - HDR_LENGTH as usize)]),
-            measure::MEASURE => Ok(&self.bytes[8..(self.len as usize - HDR_LENGTH as usize)]),
-            update_field::UPDATE_FIELD => {
-                Ok(&self.bytes[4..(self.len as usize - HDR_LENGTH as usize)])
+        let u32s_len = match self.typ {
+            create::CREATE => 4 * 6,
+            measure::MEASURE => 8,
+            changeprog::CHANGEPROG => 8,
+            update_field::UPDATE_FIELD => 4,
+            _ => 0,
+        };
+        self.bytes
+            .get(u32s_len..)
+            .ok_or_else(|| self.too_short(0, u32s_len))
+    }
+
+    /// How strictly the message is being parsed, e.g. for `from_raw_msg` to check a message
+    /// type's own format.
+    pub fn mode(&self) -> ParseMode {
+        self.mode
+    }
+
+    // An inconsistency in the message which does not stop it being read, described by `what`:
+    // an error when parsing strictly, and otherwise logged.
+    pub(crate) fn inconsistent<F: FnOnce() -> String>(&self, what: F) -> Result<()> {
+        let what = what();
+        match self.mode {
+            ParseMode::Strict => Err(super::Error::from(ParseError::BadPayload(format!(
+                "message type {}: {}",
+                self.typ, what
+            )))),
+            ParseMode::Lenient => {
+                debug!(typ = self.typ, sid = self.sid, %what, "accepting inconsistent message");
+                Ok(())
             }
-            _ => Ok(self.bytes),
         }
     }
+
+    // Check that the message ends `len` bytes after its header (see `inconsistent`).
+    pub(crate) fn check_len(&self, len: usize) -> Result<()> {
+        if self.bytes.len() > len {
+            let extra = self.bytes.len() - len;
+            return self.inconsistent(|| format!("{} bytes after its last field", extra));
+        }
+
+        Ok(())
+    }
+
+    // The error for a message too short to have `len` bytes at `at`, counting from after the
+    // header.
+    fn too_short(&self, at: usize, len: usize) -> super::Error {
+        super::Error::from(ParseError::BadLength {
+            declared: at + len,
+            actual: self.bytes.len(),
+        })
+    }
 }
 
-/// Types that can be serialized.
-// Message types wanting to become "predefined" (and as such take advantage of `get_u32s()` and
+/// Types that can be serialized, and parsed from a `RawMsg`: the predefined messages, and a
+/// datapath's or algorithm's own (see `RunBuilder::on_msg`).
+// Message types wanting to become "predefined" (and as such take advantage of `get_u32()` and
 // `get_u64s()` below) should edit this file accordingly (see `impl RawMsg`)
 pub trait AsRawMsg {
-    fn get_hdr(&self) -> (u8, u32, u32);
+    /// The type of these messages, e.g. `create::CREATE`, which `deserialize` expects of a
+    /// buffer it parses as one.
+    fn typ() -> u8;
+
+    /// The message's type, length with a narrow header, and socket id.
+    fn get_hdr(&self) -> (u8, u32, u64);
+
+    /// Whether the message may be longer than the header's 16-bit length allows, in which case it
+    /// has an extended length (see `EXTENDED_LEN`). Otherwise such a message does not serialize.
+    const EXTENDABLE: bool = false;
+
+    /// Flags to set in the message's type, out of `OPTIONAL_FLAGS`. Serializing sets the others
+    /// as needed; a message which sets one itself does not serialize.
+    fn get_flags(&self) -> u16 {
+        0
+    }
+
+    /// Check that the counts the message declares, e.g. its number of fields, match what it
+    /// has. Serializing checks this before writing anything, rather than writing a message whose
+    /// counts disagree with its body.
+    fn check_counts(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn get_u32s<W: Write>(&self, _: &mut W) -> Result<()> {
         Ok(())
     }
@@ -131,8 +671,8 @@ pub trait AsRawMsg {
 
 #[macro_use]
 mod test_helper {
-    /// Generates a test which serializes and deserializes a message
-    /// and verifies the message is unchanged.
+    /// Generates a test which serializes and deserializes a message, both as a `Msg` and as
+    /// its own type, and verifies the message is unchanged.
     #[macro_export]
     macro_rules! check_msg {
         ($id: ident, $typ: ty, $m: expr, $got: pat, $x: ident) => {
@@ -147,53 +687,229 @@ mod test_helper {
                     $got => assert_eq!($x, m),
                     _ => panic!("wrong type for message"),
                 }
+                let typed: $typ =
+                    $crate::serialize::deserialize(&buf[..]).expect("deserialize: check_msg");
+                assert_eq!(typed, m);
             }
         };
     }
 }
 
+pub mod capabilities;
 pub mod changeprog;
+pub mod checksum;
+pub mod codes;
+pub mod compress;
 pub mod create;
+pub mod error;
+pub mod fin;
+pub mod heartbeat;
 pub mod install;
+pub mod install_ack;
 pub mod measure;
+pub mod measure_batch;
 pub mod ready;
+pub mod sequence;
+pub mod teardown;
 mod testmsg;
+pub mod uninstall;
 pub mod update_field;
+pub mod version;
 
-/// Serialize a serializable message.
+/// Serialize a serializable message. It is an error if the message is too long for its header,
+/// or is not as long as its header says.
 pub fn serialize<T: AsRawMsg>(m: &T) -> Result<Vec<u8>> {
-    let (a, b, c) = m.get_hdr();
-    let mut msg = serialize_header(a, b, c);
-    m.get_u32s(&mut msg)?;
-    m.get_u64s(&mut msg)?;
-    m.get_bytes(&mut msg)?;
+    let mut msg = Vec::new();
+    serialize_into(m, &mut msg)?;
     Ok(msg)
 }
 
-fn deserialize(buf: &[u8]) -> Result<RawMsg> {
-    let mut buf = Cursor::new(buf);
-    let (typ, len, sid) = deserialize_header(&mut buf)?;
-    if len < 8 {
+/// Like `serialize`, but write the message to `w`, e.g. a buffer reused from message to message,
+/// and return its length. If it fails, part of the message may have been written.
+pub fn serialize_into<T: AsRawMsg, W: Write>(m: &T, w: &mut W) -> Result<usize> {
+    let (hdr, len) = header(m)?;
+    write_msg(m, &hdr, len, w)
+}
+
+/// Like `serialize`, but write the message to the start of `buf`, and return its length. It is an
+/// error, and nothing is written, if `buf` is too short for the message.
+pub fn serialize_into_slice<T: AsRawMsg>(m: &T, buf: &mut [u8]) -> Result<usize> {
+    let (hdr, len) = header(m)?;
+    if buf.len() < len {
         return Err(super::Error(format!(
-            "nonsensical len in header: ({}, {}, {})",
-            typ, len, sid
+            "message type {} is {} bytes, too long for a {} byte buffer",
+            hdr[0],
+            len,
+            buf.len()
         )));
     }
-    if len > buf.get_ref().len() as u32 {
+
+    write_msg(m, &hdr, len, &mut &mut buf[..])
+}
+
+// The header of `m`, and the length of the whole message, with any checksum.
+fn header<T: AsRawMsg>(m: &T) -> Result<(Header, usize)> {
+    m.check_counts()?;
+    let (typ, len, sid) = m.get_hdr();
+    let mut hdr = serialize_header(typ, m.get_flags(), len, sid, T::EXTENDABLE)?;
+    let len = claimed_len(&hdr).unwrap_or_default();
+    if cfg!(feature = "checksum") {
+        checksum::mark(&mut hdr, len)?;
+    }
+
+    let len = claimed_len(&hdr).unwrap_or_default();
+    Ok((hdr, len))
+}
+
+// Write `m`, whose header is `hdr` and which is `len` bytes long, to `w`.
+fn write_msg<T: AsRawMsg, W: Write>(m: &T, hdr: &[u8], len: usize, w: &mut W) -> Result<usize> {
+    let mut w = Tally::new(w);
+    w.write_all(hdr)?;
+    m.get_u32s(&mut w)?;
+    m.get_u64s(&mut w)?;
+    m.get_bytes(&mut w)?;
+    #[cfg(feature = "checksum")]
+    {
+        let mut sum = [0u8; checksum::CHECKSUM_LENGTH];
+        u16_to_u8s(&mut sum, w.sum.finish());
+        w.write_all(&sum)?;
+    }
+    if w.len != len {
         return Err(super::Error(format!(
-            "header len claims {} but buf size is {}",
-            len,
-            buf.get_ref().len()
+            "message type {} is {} bytes, but its header says {}",
+            hdr[0], w.len, len
         )));
     }
 
-    let i = buf.position();
-    Ok(RawMsg {
-        typ,
-        len,
-        sid,
-        bytes: &buf.into_inner()[i as usize..(len as usize)],
-    })
+    Ok(w.len)
+}
+
+// Counts the bytes written through it, and with the `checksum` feature, sums them.
+struct Tally<'a, W: Write> {
+    w: &'a mut W,
+    len: usize,
+    #[cfg(feature = "checksum")]
+    sum: checksum::Sum,
+}
+
+impl<'a, W: Write> Tally<'a, W> {
+    fn new(w: &'a mut W) -> Self {
+        Tally {
+            w,
+            len: 0,
+            #[cfg(feature = "checksum")]
+            sum: checksum::Sum::default(),
+        }
+    }
+}
+
+impl<'a, W: Write> Write for Tally<'a, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.w.write(buf)?;
+        #[cfg(feature = "checksum")]
+        self.sum.add(&buf[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.w.flush()
+    }
+}
+
+/// The length of the message at the start of `buf`, from its header, or an error if the header
+/// is not a valid one for a message which fits in `buf`: `ParseError::Incomplete` if the message
+/// may yet, with more of it.
+pub(crate) fn frame_len(buf: &[u8]) -> Result<usize> {
+    frame_header(buf).map(|(_, len, _)| len as usize)
+}
+
+// The header of the message at the start of `buf`, if it is a valid one for a message which fits
+// in `buf`.
+fn frame_header(buf: &[u8]) -> Result<(u8, u32, u64)> {
+    let (typ, len, sid) = parse_header(buf)?;
+    if len as usize > buf.len() {
+        return Err(super::Error::from(ParseError::Incomplete {
+            needed: len as usize - buf.len(),
+        }));
+    }
+
+    Ok((typ, len, sid))
+}
+
+// Parse the message at the start of `buf`, checking its checksum if it has one, and return it
+// with its length in `buf`. The message leaves out its sequence number, if it has one.
+fn parse_raw(buf: &[u8], mode: ParseMode) -> Result<(RawMsg<'_>, usize)> {
+    let (typ, frame_len, sid) = frame_header(buf)?;
+    let frame = &buf[..frame_len as usize];
+    let len = if checksum::is_checksummed(frame) {
+        checksum::verify(frame)?
+    } else if cfg!(feature = "checksum") {
+        return Err(super::Error::from(super::CorruptMsgError));
+    } else {
+        frame.len()
+    };
+    let len = if sequence::is_sequenced(frame) {
+        sequence::strip(&frame[..len])?
+    } else {
+        len
+    };
+    // a wide socket id or extended length may not leave room for a checksum or sequence number
+    if len < hdr_len(frame) {
+        return Err(super::Error::from(ParseError::BadLength {
+            declared: len,
+            actual: hdr_len(frame),
+        }));
+    }
+
+    Ok((
+        RawMsg {
+            typ,
+            flags: u16_from_u8s(&frame[0..2]) & FLAGS,
+            len: len as u32,
+            sid,
+            bytes: &frame[hdr_len(frame)..len],
+            mode,
+        },
+        frame.len(),
+    ))
+}
+
+// A message with mandatory flags this portus does not know cannot be read.
+fn check_flags(m: &RawMsg) -> Result<()> {
+    let unknown = m.flags & MANDATORY_FLAGS & !KNOWN_FLAGS;
+    if unknown != 0 {
+        return Err(super::Error::from(ParseError::BadPayload(format!(
+            "message type {} has unknown mandatory flags {:#06x}",
+            m.typ, unknown
+        ))));
+    }
+
+    Ok(())
+}
+
+/// Parse the message at the start of `buf` as a `T`, the inverse of `serialize`. A message of
+/// another type than `T::typ()` is an error, `WrongMsgTypeError`; otherwise errors are as for
+/// `Msg::from_buf`, except that a header which does not fit in `buf` is one too, e.g.
+/// `ParseError::Incomplete`.
+///
+/// Messages are parsed in `DEFAULT_PARSE_MODE`.
+pub fn deserialize<T: AsRawMsg>(buf: &[u8]) -> Result<T> {
+    T::from_raw_msg(parse_typed(buf, T::typ())?)
+}
+
+// Parse the header of the message at the start of `buf`, which is an error unless it is of type
+// `typ`, as `deserialize` does.
+pub(crate) fn parse_typed(buf: &[u8], typ: u8) -> Result<RawMsg<'_>> {
+    let (got, _, _) = parse_header(buf)?;
+    if got != typ {
+        debug!(got, want = typ, "deserialize wrong message type");
+        return Err(super::Error::from(super::WrongMsgTypeError));
+    }
+
+    let (m, _) = parse_raw(buf, DEFAULT_PARSE_MODE)?;
+    check_flags(&m)?;
+    Ok(m)
 }
 
 /// Message type for deserialization.
@@ -204,44 +920,131 @@ fn deserialize(buf: &[u8]) -> Result<RawMsg> {
 pub enum Msg<'a> {
     Cr(create::Msg),
     Ms(measure::Msg),
+    MsBatch(measure_batch::Msg),
     Ins(install::Msg),
     Rdy(ready::Msg),
+    Hb(heartbeat::Msg),
+    Caps(capabilities::Msg),
+    Uf(update_field::Msg),
+    Cp(changeprog::Msg),
+    Ver(version::Msg),
+    Err(error::Msg),
+    InsAck(install_ack::Msg),
+    Un(uninstall::Msg),
+    Td(teardown::Msg),
+    Fin(fin::Msg),
     Other(RawMsg<'a>),
 }
 
+#[cfg(feature = "serde")]
+serde_enum!(@ser Msg<'_> {
+    Cr(m),
+    Ms(m),
+    MsBatch(m),
+    Ins(m),
+    Rdy(m),
+    Hb(m),
+    Caps(m),
+    Uf(m),
+    Cp(m),
+    Ver(m),
+    Err(m),
+    InsAck(m),
+    Un(m),
+    Td(m),
+    Fin(m),
+    Other(m),
+});
+
+/// A line for people debugging a datapath, e.g. `teardown sid=1 reason=2`: the type of message,
+/// its socket id if it has one, then what it says.
+impl fmt::Display for Msg<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Msg::Cr(m) => m.fmt(f),
+            Msg::Ms(m) => m.fmt(f),
+            Msg::MsBatch(m) => m.fmt(f),
+            Msg::Ins(m) => m.fmt(f),
+            Msg::Rdy(m) => m.fmt(f),
+            Msg::Hb(m) => m.fmt(f),
+            Msg::Caps(m) => m.fmt(f),
+            Msg::Uf(m) => m.fmt(f),
+            Msg::Cp(m) => m.fmt(f),
+            Msg::Ver(m) => write!(f, "version {}", m),
+            Msg::Err(m) => m.fmt(f),
+            Msg::InsAck(m) => m.fmt(f),
+            Msg::Un(m) => m.fmt(f),
+            Msg::Td(m) => m.fmt(f),
+            Msg::Fin(m) => m.fmt(f),
+            Msg::Other(m) => m.fmt(f),
+        }
+    }
+}
+
 impl<'a> Msg<'a> {
     fn from_raw_msg(m: RawMsg) -> Result<Msg> {
         match m.typ {
             create::CREATE => Ok(Msg::Cr(create::Msg::from_raw_msg(m)?)),
             measure::MEASURE => Ok(Msg::Ms(measure::Msg::from_raw_msg(m)?)),
+            measure_batch::MEASURE_BATCH => Ok(Msg::MsBatch(measure_batch::Msg::from_raw_msg(m)?)),
             install::INSTALL => Ok(Msg::Ins(install::Msg::from_raw_msg(m)?)),
             ready::READY => Ok(Msg::Rdy(ready::Msg::from_raw_msg(m)?)),
-            update_field::UPDATE_FIELD => unimplemented!(),
+            heartbeat::HEARTBEAT => Ok(Msg::Hb(heartbeat::Msg::from_raw_msg(m)?)),
+            capabilities::CAPABILITIES => Ok(Msg::Caps(capabilities::Msg::from_raw_msg(m)?)),
+            update_field::UPDATE_FIELD => Ok(Msg::Uf(update_field::Msg::from_raw_msg(m)?)),
+            changeprog::CHANGEPROG => Ok(Msg::Cp(changeprog::Msg::from_raw_msg(m)?)),
+            version::VERSION => Ok(Msg::Ver(version::Msg::from_raw_msg(m)?)),
+            error::ERROR => Ok(Msg::Err(error::Msg::from_raw_msg(m)?)),
+            install_ack::INSTALL_ACK => Ok(Msg::InsAck(install_ack::Msg::from_raw_msg(m)?)),
+            uninstall::UNINSTALL => Ok(Msg::Un(uninstall::Msg::from_raw_msg(m)?)),
+            teardown::TEARDOWN => Ok(Msg::Td(teardown::Msg::from_raw_msg(m)?)),
+            fin::FIN => Ok(Msg::Fin(fin::Msg::from_raw_msg(m)?)),
             _ => Ok(Msg::Other(m)),
         }
     }
 
+    /// Parse the message at the start of `buf`, and return it with its length. A message whose
+    /// checksum does not match (see [`checksum`](checksum/index.html)) is an error,
+    /// `CorruptMsgError`.
+    ///
+    /// The length is the whole message's, trailers included, from its header, so the next
+    /// message in `buf`, e.g. read from a stream, starts there. A header whose length does not
+    /// fit in `buf` is not a valid one: the message is taken for one of unknown type, as long as
+    /// `buf`.
+    ///
+    /// A message with mandatory flags this portus does not know (see `MANDATORY_FLAGS`) is an
+    /// error, since it cannot be read; unknown optional flags are ignored.
+    ///
+    /// Other errors are made from a `ParseError`, which `ParseError::of` tells.
+    ///
+    /// Messages are parsed in `DEFAULT_PARSE_MODE`.
     pub fn from_buf(buf: &[u8]) -> Result<(Msg, usize)> {
-        deserialize(buf)
-            .map_or_else(
-                |e| {
-                    debug!(err = %format!("{:#?}", e), "failed deserialization");
-                    Ok((
-                        RawMsg {
-                            typ: 255,
-                            len: 0,
-                            sid: 0,
-                            bytes: &buf,
-                        },
-                        buf.len(),
-                    ))
-                },
-                |m| {
-                    let len = m.len;
-                    Ok((m, len as usize))
-                },
-            )
-            .and_then(|(m, l)| Ok((Msg::from_raw_msg(m)?, l)))
+        Msg::from_buf_with(buf, DEFAULT_PARSE_MODE)
+    }
+
+    /// Like `from_buf`, parsing in `mode`.
+    pub fn from_buf_with(buf: &[u8], mode: ParseMode) -> Result<(Msg<'_>, usize)> {
+        let (m, l) = match parse_raw(buf, mode) {
+            Ok(parsed) => parsed,
+            Err(e) if e == super::Error::from(super::CorruptMsgError) => return Err(e),
+            Err(e) => {
+                debug!(err = %format!("{:#?}", e), "failed deserialization");
+                (
+                    RawMsg {
+                        typ: 255,
+                        flags: 0,
+                        len: 0,
+                        sid: 0,
+                        bytes: buf,
+                        mode,
+                    },
+                    buf.len(),
+                )
+            }
+        };
+
+        check_flags(&m)?;
+        Ok((Msg::from_raw_msg(m)?, l))
     }
 }
 
@@ -325,6 +1128,726 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_wide_sid() {
+        let m = super::uninstall::Msg {
+            sid: 0x0102_0304_0506_0708,
+            program_uid: 7,
+        };
+        let buf = super::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                11, 0x10, // UNINSTALL | WIDE_SID
+                16, 0, // length = 16
+                8, 7, 6, 5, // sock_id, low 32 bits
+                4, 3, 2, 1, // sock_id, high 32 bits
+                7, 0, 0, 0, // program_uid = 7
+            ],
+        );
+        assert_eq!(super::sid(&buf), Some(m.sid));
+    }
+
+    #[test]
+    fn parse_header() {
+        use super::{install, parse_header, uninstall, ParseError};
+
+        let buf = super::serialize(&uninstall::Msg {
+            sid: 42,
+            program_uid: 7,
+        })
+        .expect("serialize");
+        let len = buf.len() as u32;
+        assert_eq!(parse_header(&buf).unwrap(), (uninstall::UNINSTALL, len, 42));
+        // the header alone is enough
+        assert_eq!(
+            parse_header(&buf[..8]).unwrap(),
+            (uninstall::UNINSTALL, len, 42)
+        );
+        assert_eq!(
+            ParseError::of(&parse_header(&buf[..7]).unwrap_err()),
+            Some(ParseError::Incomplete { needed: 1 })
+        );
+
+        let buf = super::serialize(&uninstall::Msg {
+            sid: 0x0102_0304_0506_0708,
+            program_uid: 7,
+        })
+        .expect("serialize");
+        let len = buf.len() as u32;
+        assert_eq!(
+            parse_header(&buf[..12]).unwrap(),
+            (uninstall::UNINSTALL, len, 0x0102_0304_0506_0708)
+        );
+        // the rest of a wide header is only known to be missing once its flags are there
+        assert_eq!(
+            ParseError::of(&parse_header(&buf[..8]).unwrap_err()),
+            Some(ParseError::Incomplete { needed: 4 })
+        );
+
+        let buf = vec![
+            2, 0x08, // INSTALL | EXTENDED_LEN
+            0, 0, // length, extended
+            1, 0, 0, 0, // sock_id = 1
+            0, 0, 1, 0, // length = 65536
+        ];
+        assert_eq!(parse_header(&buf).unwrap(), (install::INSTALL, 65536, 1));
+
+        // a length shorter than the header
+        assert_eq!(
+            ParseError::of(&parse_header(&[11, 0, 4, 0, 1, 0, 0, 0]).unwrap_err()),
+            Some(ParseError::BadLength {
+                declared: 4,
+                actual: 8,
+            })
+        );
+    }
+
+    #[test]
+    fn deserialize_wrong_type() {
+        use super::{deserialize, heartbeat, teardown, uninstall};
+
+        let buf = super::serialize(&uninstall::Msg {
+            sid: 1,
+            program_uid: 7,
+        })
+        .expect("serialize");
+        let wrong = crate::Error::from(crate::WrongMsgTypeError);
+        // teardown messages are as long as uninstall ones, so would otherwise parse
+        assert_eq!(deserialize::<teardown::Msg>(&buf), Err(wrong.clone()));
+        assert_eq!(deserialize::<heartbeat::Msg>(&buf), Err(wrong.clone()));
+        assert_eq!(
+            deserialize::<crate::test_helper::TestMsg>(&buf),
+            Err(wrong.clone())
+        );
+        assert!(deserialize::<uninstall::Msg>(&buf).is_ok());
+
+        // a message cut short is some other error
+        let err = deserialize::<uninstall::Msg>(&buf[..buf.len() - 1]).unwrap_err();
+        assert_ne!(err, wrong);
+        assert!(deserialize::<uninstall::Msg>(&buf[..4]).is_err());
+
+        let buf = super::serialize(&crate::test_helper::TestMsg(String::from("hello")))
+            .expect("serialize");
+        assert_eq!(
+            deserialize::<crate::test_helper::TestMsg>(&buf),
+            Ok(crate::test_helper::TestMsg(String::from("hello")))
+        );
+        assert_eq!(deserialize::<uninstall::Msg>(&buf), Err(wrong));
+    }
+
+    fn parse_error(buf: &[u8]) -> Option<super::ParseError> {
+        let err = Msg::from_buf_with(buf, super::ParseMode::Strict).unwrap_err();
+        super::ParseError::of(&err)
+    }
+
+    #[test]
+    fn parse_error_incomplete() {
+        use super::{deserialize, frame_len, heartbeat, ParseError};
+
+        let buf = super::serialize(&heartbeat::Msg { seq: 9 }).expect("serialize");
+        for cut in 0..buf.len() {
+            // until the header is there, only the rest of it is known to be needed
+            let needed = if cut < 8 { 8 - cut } else { buf.len() - cut };
+            let incomplete = Some(ParseError::Incomplete { needed });
+            assert_eq!(
+                ParseError::of(&frame_len(&buf[..cut]).unwrap_err()),
+                incomplete
+            );
+            let err = deserialize::<heartbeat::Msg>(&buf[..cut]).unwrap_err();
+            assert_eq!(ParseError::of(&err), incomplete);
+        }
+        assert_eq!(frame_len(&buf), Ok(buf.len()));
+    }
+
+    #[test]
+    fn parse_error_unknown_type() {
+        use super::{measure, ParseError};
+
+        let mut buf = super::serialize(&measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 1,
+            fields: vec![42],
+            types: Some(vec![measure::FieldType::Int]),
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize");
+        buf[16] = 9; // the field's type
+        let buf = super::checksum::fix_up(buf);
+        assert_eq!(parse_error(&buf), Some(ParseError::UnknownType(9)));
+        assert_eq!(
+            ParseError::of(&measure::Msg::lazy(&buf).unwrap_err()),
+            Some(ParseError::UnknownType(9))
+        );
+    }
+
+    #[test]
+    fn parse_error_bad_length() {
+        use super::{fin, ParseError};
+
+        let mut buf = super::serialize(&fin::Msg {
+            sid: 1,
+            bytes: 2,
+            packets: 3,
+        })
+        .expect("serialize");
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        let buf = super::checksum::fix_up(buf);
+        assert_eq!(
+            parse_error(&buf),
+            Some(ParseError::BadLength {
+                declared: 16,
+                actual: 12,
+            })
+        );
+
+        // a ready message without its id
+        assert_eq!(
+            parse_error(&super::checksum::fix_up(vec![5, 0, 8, 0, 0, 0, 0, 0])),
+            Some(ParseError::BadLength {
+                declared: 4,
+                actual: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn parse_error_bad_payload() {
+        use super::{heartbeat, ParseError};
+
+        let mut buf = super::serialize(&heartbeat::Msg { seq: 9 }).expect("serialize");
+        buf.push(0);
+        buf[2] += 1;
+        let mut buf = super::checksum::fix_up(buf);
+        assert_eq!(
+            parse_error(&buf),
+            Some(ParseError::BadPayload(format!(
+                "message type {}: 1 bytes after its last field",
+                heartbeat::HEARTBEAT
+            )))
+        );
+
+        buf[1] |= 0x04; // an unknown mandatory flag
+        let buf = super::checksum::fix_up(buf);
+        assert!(matches!(parse_error(&buf), Some(ParseError::BadPayload(_))));
+    }
+
+    #[test]
+    fn parse_errors_of() {
+        use super::ParseError;
+        use crate::Error;
+
+        let errs = [
+            ParseError::Incomplete { needed: 3 },
+            ParseError::UnknownType(200),
+            ParseError::BadLength {
+                declared: 12,
+                actual: 4,
+            },
+            ParseError::BadPayload(String::from("bad message length: 1 declared, 2 actual")),
+            ParseError::BadPayload(String::new()),
+        ];
+        for err in errs.iter() {
+            assert_eq!(ParseError::of(&Error::from(err.clone())), Some(err.clone()));
+        }
+
+        assert_eq!(ParseError::of(&Error::from(crate::CorruptMsgError)), None);
+        // errors which only say what one would are not made from one
+        assert_eq!(
+            ParseError::of(&Error(String::from(
+                "incomplete message: needs 3 more bytes"
+            ))),
+            None
+        );
+        let quoted = Error(format!("portus err: {}", Error::from(errs[0].clone()).0));
+        assert_eq!(ParseError::of(&quoted), None);
+    }
+
+    #[test]
+    fn serialize_into_reused_buf() {
+        let msgs = [
+            super::uninstall::Msg {
+                sid: 1,
+                program_uid: 7,
+            },
+            super::uninstall::Msg {
+                sid: 0x0102_0304_0506_0708,
+                program_uid: 8,
+            },
+        ];
+
+        let mut buf = Vec::new();
+        for m in &msgs {
+            buf.clear();
+            let len = super::serialize_into(m, &mut buf).expect("serialize into vec");
+            assert_eq!(len, buf.len());
+            assert_eq!(buf, super::serialize(m).expect("serialize"));
+        }
+    }
+
+    #[test]
+    fn serialize_into_slice() {
+        let m = super::teardown::Msg { sid: 1, reason: 2 };
+        let want = super::serialize(&m).expect("serialize");
+
+        let mut buf = [0xffu8; 64];
+        let len = super::serialize_into_slice(&m, &mut buf).expect("serialize into slice");
+        assert_eq!(&buf[..len], &want[..]);
+        assert!(buf[len..].iter().all(|b| *b == 0xff));
+
+        let mut buf = vec![0u8; want.len()];
+        assert_eq!(super::serialize_into_slice(&m, &mut buf), Ok(want.len()));
+        assert_eq!(buf, want);
+
+        // too short a buffer is an error, and is left as it was
+        let mut buf = vec![0u8; want.len() - 1];
+        assert!(super::serialize_into_slice(&m, &mut buf).is_err());
+        assert!(buf.iter().all(|b| *b == 0));
+    }
+
+    // Every message with a socket id keeps it, and is otherwise unchanged, whether it fits in the
+    // narrow header or needs the wide one.
+    #[test]
+    fn sid_widths_round_trip() {
+        use super::*;
+        use crate::lang::{Reg, Type};
+
+        let (bin, _) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        for &sid in &[
+            0,
+            42,
+            u64::from(u32::MAX),
+            u64::from(u32::MAX) + 1,
+            0x1234_5678_9abc_def0,
+            u64::MAX,
+        ] {
+            let msgs = vec![
+                serialize(&create::Msg {
+                    sid,
+                    init_cwnd: 14480,
+                    mss: 1448,
+                    src_ip: 1,
+                    src_port: 4242,
+                    dst_ip: 2,
+                    dst_port: 4243,
+                    cong_alg: None,
+                }),
+                serialize(&measure::Msg {
+                    sid,
+                    program_uid: 7,
+                    num_fields: 2,
+                    fields: vec![42, 4242],
+                    types: None,
+                    timestamp: None,
+                    strings: vec![],
+                }),
+                serialize(&measure::Msg {
+                    sid,
+                    program_uid: 7,
+                    num_fields: 2,
+                    fields: vec![42, 1],
+                    types: Some(vec![measure::FieldType::Int, measure::FieldType::Bool]),
+                    timestamp: Some(1_000_000),
+                    strings: vec![],
+                }),
+                serialize(&measure_batch::Msg {
+                    measurements: vec![measure::Msg {
+                        sid,
+                        program_uid: 7,
+                        num_fields: 1,
+                        fields: vec![42],
+                        types: None,
+                        timestamp: None,
+                        strings: vec![],
+                    }],
+                }),
+                serialize(&install::Msg {
+                    sid,
+                    program_uid: 7,
+                    num_events: bin.events.len() as u32,
+                    num_instrs: bin.instrs.len() as u32,
+                    instrs: bin.clone(),
+                    names: None,
+                }),
+                serialize(&update_field::Msg {
+                    sid,
+                    num_fields: 1,
+                    fields: vec![(Reg::Implicit(4, Type::Num(None)), 14480)],
+                    widths: None,
+                }),
+                serialize(&changeprog::Msg::new(
+                    sid,
+                    7,
+                    vec![(Reg::Implicit(4, Type::Num(None)), 14480)],
+                )),
+                serialize(&error::Msg {
+                    sid,
+                    code: codes::Code::ProgramTooLarge,
+                    msg: String::from("too many instructions"),
+                }),
+                serialize(&install_ack::Msg {
+                    sid,
+                    program_uid: 7,
+                    status: codes::Code::Ok,
+                }),
+                serialize(&uninstall::Msg {
+                    sid,
+                    program_uid: 7,
+                }),
+                serialize(&teardown::Msg { sid, reason: 2 }),
+                serialize(&fin::Msg {
+                    sid,
+                    bytes: 1 << 40,
+                    packets: 42,
+                }),
+            ];
+
+            for buf in msgs {
+                let buf = buf.expect("serialize");
+                let wide = sid > u64::from(u32::MAX);
+                assert_eq!(
+                    is_wide(&buf),
+                    wide && buf[0] != measure_batch::MEASURE_BATCH
+                );
+                let (msg, len) = Msg::from_buf(&buf).expect("deserialize");
+                assert_eq!(len, buf.len());
+                let (got_sid, again) = match msg {
+                    Msg::Cr(m) => (m.sid, serialize(&m)),
+                    Msg::Ms(m) => (m.sid, serialize(&m)),
+                    Msg::MsBatch(m) => (m.measurements[0].sid, serialize(&m)),
+                    Msg::Ins(m) => (m.sid, serialize(&m)),
+                    Msg::Uf(m) => (m.sid, serialize(&m)),
+                    Msg::Cp(m) => (m.sid, serialize(&m)),
+                    Msg::Err(m) => (m.sid, serialize(&m)),
+                    Msg::InsAck(m) => (m.sid, serialize(&m)),
+                    Msg::Un(m) => (m.sid, serialize(&m)),
+                    Msg::Td(m) => (m.sid, serialize(&m)),
+                    Msg::Fin(m) => (m.sid, serialize(&m)),
+                    m => panic!("unexpected message {:?}", m),
+                };
+                assert_eq!(got_sid, sid, "message type {}", buf[0]);
+                assert_eq!(again.expect("serialize again"), buf);
+            }
+        }
+    }
+
+    // Regression buffers: truncated, inconsistent, and malicious messages, each of which is an
+    // error (or, with an invalid header, an unknown message) rather than a panic.
+    #[test]
+    fn malformed_corpus() {
+        let corpus: &[(&str, &[u8])] = &[
+            ("empty", &[]),
+            ("half a header", &[1, 0, 8, 0]),
+            ("create without a body", &[0, 0, 8, 0, 1, 0, 0, 0]),
+            (
+                "create cut off in its u32s",
+                &[0, 0, 12, 0, 1, 0, 0, 0, 0xa0, 0x38, 0, 0],
+            ),
+            ("measure without a body", &[1, 0, 8, 0, 1, 0, 0, 0]),
+            (
+                "measure without its field count",
+                &[1, 0, 12, 0, 1, 0, 0, 0, 7, 0, 0, 0],
+            ),
+            (
+                "measure with part of a field",
+                &[1, 0, 20, 0, 1, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0, 42, 0, 0, 0],
+            ),
+            (
+                "typed measure with an unknown type",
+                &[1, 0, 18, 0, 1, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0x80, 9, 0],
+            ),
+            (
+                "timestamped measure without its timestamp",
+                &[
+                    1, 0, 20, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0x40, 1, 2, 3, 4,
+                ],
+            ),
+            (
+                "install without its counts",
+                &[2, 0, 16, 0, 1, 0, 0, 0, 7, 0, 0, 0, 1, 0, 0, 0],
+            ),
+            (
+                "install with more instructions than bytes",
+                &[
+                    2, 0, 20, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff,
+                ],
+            ),
+            (
+                "update field with part of a register",
+                &[3, 0, 17, 0, 1, 0, 0, 0, 1, 0, 0, 0, 2, 4, 0, 0, 0],
+            ),
+            ("ready without an id", &[5, 0, 8, 0, 0, 0, 0, 0]),
+            ("heartbeat cut short", &[6, 0, 10, 0, 0, 0, 0, 0, 1, 0]),
+            (
+                "capabilities cut short",
+                &[7, 0, 12, 0, 0, 0, 0, 0, 0xff, 0x7f, 0, 0],
+            ),
+            (
+                "version without a minor",
+                &[8, 0, 12, 0, 0, 0, 0, 0, 1, 0, 0, 0],
+            ),
+            ("error without a code", &[9, 0, 10, 0, 1, 0, 0, 0, 1, 0]),
+            (
+                "install ack without a status",
+                &[10, 0, 12, 0, 1, 0, 0, 0, 7, 0, 0, 0],
+            ),
+            ("uninstall without a program", &[11, 0, 8, 0, 1, 0, 0, 0]),
+            ("teardown without a reason", &[12, 0, 8, 0, 1, 0, 0, 0]),
+            (
+                "fin without a packet count",
+                &[14, 0, 16, 0, 1, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                "batch of more measurements than it has",
+                &[13, 0, 12, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0x7f],
+            ),
+            (
+                "batch with a huge field count",
+                &[
+                    13, 0, 24, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 7, 0, 0, 0, 0xff, 0xff, 0xff,
+                    0xff,
+                ],
+            ),
+            ("wide sid in a narrow length", &[11, 0x10, 8, 0, 1, 0, 0, 0]),
+            ("wide sid cut off", &[11, 0x10, 10, 0, 1, 0, 0, 0, 1, 0]),
+            (
+                "extended length of 0",
+                &[2, 0x08, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                "extended length past the buffer",
+                &[2, 0x08, 0, 0, 1, 0, 0, 0, 0xff, 0xff, 0, 0],
+            ),
+            ("length past the buffer", &[11, 0, 0xff, 0xff, 1, 0, 0, 0]),
+            ("length inside the header", &[11, 0, 4, 0, 1, 0, 0, 0]),
+            (
+                "sequenced without a sequence number",
+                &[6, 0x20, 8, 0, 0, 0, 0, 0],
+            ),
+            (
+                "sequenced wide sid without room for the sequence number",
+                &[6, 0x30, 12, 0, 0, 0, 0, 0, 0, 0, 0, 0],
+            ),
+            (
+                "checksummed without a checksum",
+                &[6, 0x40, 8, 0, 0, 0, 0, 0],
+            ),
+        ];
+
+        for (name, buf) in corpus {
+            match Msg::from_buf(buf) {
+                Err(_) => (),
+                Ok((Msg::Other(m), _)) if m.typ == 255 => (),
+                Ok((m, _)) => panic!("{}: parsed {:?}", name, m),
+            }
+        }
+    }
+
+    // Minimized inputs on which the fuzz targets (see `fuzz/`) panicked. Each parses, and
+    // serializes back to the bytes it was parsed from.
+    #[test]
+    fn fuzz_corpus() {
+        use super::serialize;
+
+        let mut create_named = vec![0, 0, 96, 0, 1, 0, 0, 0];
+        create_named.extend_from_slice(&[0; 24]);
+        create_named.push(b'a');
+        create_named.extend_from_slice(&[0; 63]);
+        let create_named = super::checksum::fix_up(create_named);
+
+        let corpus: &[(&str, &[u8])] = &[("create with a congestion control name", &create_named)];
+
+        for (name, buf) in corpus {
+            let got = match Msg::from_buf(buf) {
+                Ok((Msg::Cr(m), _)) => serialize(&m),
+                Ok((Msg::Ms(m), _)) => serialize(&m),
+                Ok((Msg::MsBatch(m), _)) => serialize(&m),
+                Ok((Msg::Ins(m), _)) => serialize(&m),
+                Ok((Msg::Uf(m), _)) => serialize(&m),
+                Ok((Msg::Cp(m), _)) => serialize(&m),
+                Ok((Msg::Rdy(m), _)) => serialize(&m),
+                Ok((Msg::Hb(m), _)) => serialize(&m),
+                Ok((Msg::Caps(m), _)) => serialize(&m),
+                Ok((Msg::Ver(m), _)) => serialize(&m),
+                Ok((Msg::Err(m), _)) => serialize(&m),
+                Ok((Msg::InsAck(m), _)) => serialize(&m),
+                Ok((Msg::Un(m), _)) => serialize(&m),
+                Ok((Msg::Td(m), _)) => serialize(&m),
+                Ok((Msg::Fin(m), _)) => serialize(&m),
+                m => panic!("{}: parsed {:?}", name, m),
+            };
+            assert_eq!(got.as_deref(), Ok(*buf), "{}", name);
+        }
+    }
+
+    // Every prefix of every type of message parses without panicking, whether its header's length
+    // is left as it was or cut to match.
+    #[test]
+    fn truncated_msgs() {
+        use super::*;
+        use crate::lang::{Reg, Type};
+
+        let (bin, _) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        let measure = |sid| measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 1],
+            types: Some(vec![measure::FieldType::Int, measure::FieldType::Bool]),
+            timestamp: Some(1_000_000),
+            strings: vec![],
+        };
+        let msgs = vec![
+            serialize(&create::Msg {
+                sid: 1,
+                init_cwnd: 14480,
+                mss: 1448,
+                src_ip: 1,
+                src_port: 4242,
+                dst_ip: 2,
+                dst_port: 4243,
+                cong_alg: None,
+            }),
+            serialize(&measure(1)),
+            serialize(&measure(1 << 40)),
+            serialize(&measure_batch::Msg {
+                measurements: vec![
+                    measure::Msg {
+                        sid: 1,
+                        program_uid: 7,
+                        num_fields: 1,
+                        fields: vec![42],
+                        types: None,
+                        timestamp: None,
+                        strings: vec![],
+                    };
+                    3
+                ],
+            }),
+            serialize(&install::Msg {
+                sid: 1,
+                program_uid: 7,
+                num_events: bin.events.len() as u32,
+                num_instrs: bin.instrs.len() as u32,
+                instrs: bin,
+                names: None,
+            }),
+            serialize(&update_field::Msg {
+                sid: 1,
+                num_fields: 1,
+                fields: vec![(Reg::Implicit(4, Type::Num(None)), 14480)],
+                widths: None,
+            }),
+            serialize(&ready::Msg { id: 3 }),
+            serialize(&heartbeat::Msg { seq: 9 }),
+            serialize(&capabilities::Msg {
+                ops: 0x7fff,
+                primitives: 0x3,
+                max_instrs: 50,
+            }),
+            serialize(&version::Msg::current()),
+            serialize(&error::Msg {
+                sid: 1,
+                code: codes::Code::ProgramTooLarge,
+                msg: String::from("too many instructions"),
+            }),
+            serialize(&install_ack::Msg {
+                sid: 1,
+                program_uid: 7,
+                status: codes::Code::UnknownProgram,
+            }),
+            serialize(&uninstall::Msg {
+                sid: 1 << 40,
+                program_uid: 7,
+            }),
+            serialize(&teardown::Msg { sid: 1, reason: 2 }),
+            serialize(&fin::Msg {
+                sid: 1,
+                bytes: 1 << 40,
+                packets: 42,
+            }),
+        ];
+
+        for msg in msgs {
+            let msg = msg.expect("serialize");
+            for cut in 0..msg.len() {
+                let _ = Msg::from_buf(&msg[..cut]);
+                let mut short = msg[..cut].to_vec();
+                if cut >= hdr_len(&short) && set_len(&mut short, cut).is_ok() {
+                    let _ = Msg::from_buf(&short);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_multi_predefined_msg() {
+        use crate::lang::{Reg, Type};
+
+        let create = super::create::Msg {
+            sid: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 1,
+            src_port: 4242,
+            dst_ip: 2,
+            dst_port: 4243,
+            cong_alg: None,
+        };
+        let measure = super::measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 4242],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        };
+        let update = super::update_field::Msg {
+            sid: 1,
+            num_fields: 1,
+            fields: vec![(Reg::Implicit(4, Type::Num(None)), 14480)],
+            widths: None,
+        };
+        let mut buf = super::serialize(&create).expect("serialize create");
+        buf.extend(super::serialize(&measure).expect("serialize measure"));
+        buf.extend(super::serialize(&update).expect("serialize update"));
+
+        let mut got = vec![];
+        let mut off = 0;
+        while off < buf.len() {
+            let (msg, len) = Msg::from_buf(&buf[off..]).expect("deserialize");
+            got.push(msg);
+            off += len;
+        }
+
+        assert_eq!(off, buf.len());
+        assert_eq!(
+            got,
+            vec![Msg::Cr(create), Msg::Ms(measure), Msg::Uf(update)]
+        );
+    }
+
     #[test]
     fn test_multi_msg() {
         use super::testmsg;
@@ -355,4 +1878,404 @@ mod tests {
 
         assert_eq!(buf[len1 + len2..].len(), 0);
     }
+
+    // Each type of message, as it is traced when received.
+    #[test]
+    fn display_msgs() {
+        use super::*;
+
+        fn shown<T: AsRawMsg>(m: &T) -> String {
+            let buf = serialize(m).expect("serialize");
+            let (msg, _) = Msg::from_buf(&buf).expect("deserialize");
+            msg.to_string()
+        }
+
+        let (bin, _) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        let measure = measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 1],
+            types: Some(vec![measure::FieldType::Num, measure::FieldType::Bool]),
+            timestamp: Some(1000),
+            strings: vec![],
+        };
+
+        assert_eq!(
+            shown(&create::Msg {
+                sid: 1,
+                init_cwnd: 14480,
+                mss: 1448,
+                src_ip: 1,
+                src_port: 4242,
+                dst_ip: 2,
+                dst_port: 4243,
+                cong_alg: None,
+            }),
+            "create sid=1 init_cwnd=14480 mss=1448 src_ip=1 src_port=4242 dst_ip=2 dst_port=4243"
+        );
+        assert_eq!(
+            shown(&measure),
+            "measure sid=1 program_uid=7 fields=2 at=1000 [0]=42:Num [1]=1:Bool"
+        );
+        assert_eq!(
+            shown(&measure_batch::Msg {
+                measurements: vec![measure::Msg {
+                    sid: 2,
+                    program_uid: 7,
+                    num_fields: 1,
+                    fields: vec![42],
+                    types: None,
+                    timestamp: None,
+                    strings: vec![],
+                }],
+            }),
+            "measure_batch measurements=1 {measure sid=2 program_uid=7 fields=1 [0]=42}"
+        );
+        assert_eq!(
+            shown(&install::Msg {
+                sid: 0,
+                program_uid: 1,
+                num_events: bin.events.len() as u32,
+                num_instrs: bin.instrs.len() as u32,
+                instrs: bin,
+                names: None,
+            }),
+            "install sid=0 program_uid=1 events=1 instrs=5\n\
+             event 0: when [1..2) do [2..5)\n\
+             0: rep0 <- (def rep0 0)\n\
+             1: imp0 <- (:= imp0 1)\n\
+             2: tmp0 <- (+ rep0 prim0)\n\
+             3: rep0 <- (:= rep0 tmp0)\n\
+             4: imp2 <- (:= imp2 1)"
+        );
+        assert_eq!(
+            shown(&update_field::Msg {
+                sid: 1,
+                num_fields: 1,
+                fields: vec![(
+                    crate::lang::Reg::Implicit(4, crate::lang::Type::Num(None)),
+                    14480,
+                )],
+                widths: None,
+            }),
+            "update_field sid=1 fields=1 imp4=14480"
+        );
+        assert_eq!(
+            shown(&changeprog::Msg::new(1, 7, vec![])),
+            "changeprog sid=1 program_uid=7 fields=0"
+        );
+        assert_eq!(shown(&ready::Msg { id: 3 }), "ready id=3");
+        assert_eq!(shown(&heartbeat::Msg { seq: 9 }), "heartbeat seq=9");
+        assert_eq!(
+            shown(&capabilities::Msg {
+                ops: 0x7fff,
+                primitives: 0x3,
+                max_instrs: 50,
+            }),
+            "capabilities ops=0x7fff primitives=0x3 max_instrs=50"
+        );
+        assert_eq!(shown(&version::Msg { major: 1, minor: 4 }), "version 1.4");
+        assert_eq!(
+            shown(&error::Msg {
+                sid: 1,
+                code: codes::Code::ProgramTooLarge,
+                msg: String::from("too many instructions"),
+            }),
+            r#"error sid=1 code=ProgramTooLarge "too many instructions""#
+        );
+        assert_eq!(
+            shown(&install_ack::Msg {
+                sid: 1,
+                program_uid: 7,
+                status: codes::Code::Unknown(100),
+            }),
+            "install_ack sid=1 program_uid=7 status=Unknown(100)"
+        );
+        assert_eq!(
+            shown(&uninstall::Msg {
+                sid: 1,
+                program_uid: uninstall::ANY_PROGRAM,
+            }),
+            "uninstall sid=1 program_uid=any"
+        );
+        assert_eq!(
+            shown(&teardown::Msg { sid: 1, reason: 2 }),
+            "teardown sid=1 reason=2"
+        );
+        assert_eq!(
+            shown(&fin::Msg {
+                sid: 1,
+                bytes: 14480,
+                packets: 10,
+            }),
+            "fin sid=1 bytes=14480 packets=10"
+        );
+        assert_eq!(
+            shown(&testmsg::Msg(String::from("foo"))),
+            "type 255 sid=0 len=11 bytes=666f6f"
+        );
+    }
+
+    // A message of unknown type is the buffer it was received into.
+    #[test]
+    fn raw_msg_borrows() {
+        let buf = super::serialize(&super::testmsg::Msg(String::from("foo"))).expect("serialize");
+        let bytes = match Msg::from_buf(&buf).expect("deserialize") {
+            (Msg::Other(raw), _) => raw.get_bytes().expect("bytes"),
+            m => panic!("wrong message: {:?}", m),
+        };
+        assert_eq!(bytes, b"foo");
+        assert!(buf.as_ptr_range().contains(&bytes.as_ptr()));
+    }
+
+    #[test]
+    fn varints() {
+        for &x in &[0, 1, 127, 128, 300, 16383, 16384, u32::MAX] {
+            let mut buf = vec![];
+            super::write_varint(&mut buf, x);
+            assert_eq!(super::read_varint(&buf), Some((x, buf.len())), "{}", x);
+        }
+
+        let mut buf = vec![];
+        super::write_varint(&mut buf, 300);
+        assert_eq!(buf, vec![0xac, 0x02]);
+
+        // cut short, too long, and too big for 32 bits
+        assert_eq!(super::read_varint(&[0x80]), None);
+        assert_eq!(super::read_varint(&[0x80; 6]), None);
+        assert_eq!(super::read_varint(&[0xff, 0xff, 0xff, 0xff, 0x1f]), None);
+    }
+
+    // A message of the datapath's own, which sets an optional flag.
+    #[derive(Debug, PartialEq)]
+    struct FlaggedMsg(u16);
+
+    impl super::AsRawMsg for FlaggedMsg {
+        fn typ() -> u8 {
+            100
+        }
+
+        fn get_hdr(&self) -> (u8, u32, u64) {
+            (100, super::HDR_LENGTH, 1)
+        }
+
+        fn get_flags(&self) -> u16 {
+            self.0
+        }
+
+        fn get_bytes<W: std::io::Write>(&self, _: &mut W) -> super::Result<()> {
+            Ok(())
+        }
+
+        fn from_raw_msg(msg: super::RawMsg) -> super::Result<Self> {
+            Ok(FlaggedMsg(msg.flags))
+        }
+    }
+
+    #[test]
+    fn serialize_optional_flags() {
+        let buf = super::serialize(&FlaggedMsg(0x0200)).expect("serialize");
+        #[cfg(not(feature = "checksum"))]
+        assert_eq!(
+            buf,
+            vec![
+                100, 0x02, // type 100, with flag 0x0200
+                8, 0, // length = 8
+                1, 0, 0, 0, // sock_id = 1
+            ],
+        );
+        match Msg::from_buf(&buf).expect("deserialize") {
+            (Msg::Other(raw), len) if len == buf.len() => {
+                assert_eq!(raw.typ, 100);
+                assert_eq!(raw.flags & !super::checksum::CHECKSUMMED, 0x0200);
+            }
+            m => panic!("wrong message: {:?}", m),
+        }
+
+        // messages cannot set the flags serializing sets
+        assert!(super::serialize(&FlaggedMsg(super::WIDE_SID)).is_err());
+        assert!(super::serialize(&FlaggedMsg(0x0400)).is_err());
+    }
+
+    #[test]
+    fn unknown_optional_flags_ignored() {
+        let mut buf =
+            super::serialize(&super::heartbeat::Msg { seq: 9 }).expect("serialize heartbeat");
+        buf[1] |= 0x01;
+        let buf = super::checksum::fix_up(buf);
+
+        assert_eq!(
+            Msg::from_buf(&buf).expect("deserialize"),
+            (Msg::Hb(super::heartbeat::Msg { seq: 9 }), buf.len())
+        );
+    }
+
+    #[test]
+    fn unknown_mandatory_flags_rejected() {
+        let mut buf =
+            super::serialize(&super::heartbeat::Msg { seq: 9 }).expect("serialize heartbeat");
+        buf[1] |= 0x04;
+        let buf = super::checksum::fix_up(buf);
+
+        assert!(Msg::from_buf(&buf).is_err());
+    }
+
+    #[test]
+    fn parse_modes() {
+        use super::{measure, uninstall, ParseMode};
+
+        let with_len = |mut buf: Vec<u8>| {
+            buf[2] = buf.len() as u8;
+            super::checksum::fix_up(buf)
+        };
+        let mut trailing = super::serialize(&uninstall::Msg {
+            sid: 1,
+            program_uid: 7,
+        })
+        .expect("serialize uninstall");
+        trailing.extend_from_slice(&[0, 0, 0, 0]);
+        let trailing = with_len(trailing);
+
+        let measure = super::serialize(&measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 2,
+            fields: vec![42, 4242],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize measure");
+        let mut miscounted = measure.clone();
+        miscounted[12] = 3; // num_fields
+        let miscounted = super::checksum::fix_up(miscounted);
+
+        // a name cut short
+        let mut create = super::serialize(&super::create::Msg {
+            sid: 1,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: Some(String::from("cubic")),
+        })
+        .expect("serialize create");
+        create.truncate(8 + 6 * 4 + 16);
+        let create = with_len(create);
+
+        for buf in &[trailing, miscounted, create] {
+            let lenient = Msg::from_buf_with(buf, ParseMode::Lenient).expect("lenient");
+            assert_eq!(lenient.1, buf.len());
+            assert_eq!(Msg::from_buf(buf).expect("default"), lenient);
+            assert!(Msg::from_buf_with(buf, ParseMode::Strict).is_err());
+        }
+
+        assert_eq!(
+            Msg::from_buf_with(&measure, ParseMode::Strict).expect("strict"),
+            Msg::from_buf_with(&measure, ParseMode::Lenient).expect("lenient"),
+        );
+    }
+
+    #[test]
+    fn counts_checked() {
+        use super::{changeprog, install, measure, measure_batch, update_field};
+        use crate::lang::{Reg, Type};
+
+        // nothing is written for a message whose counts disagree with what it has
+        fn mismatched<T: super::AsRawMsg>(m: &T) -> String {
+            let mut buf = vec![];
+            let err = super::serialize_into(m, &mut buf).expect_err("mismatched counts");
+            assert!(buf.is_empty());
+            let mut slice = [0xffu8; 256];
+            assert!(super::serialize_into_slice(m, &mut slice).is_err());
+            assert!(slice.iter().all(|b| *b == 0xff));
+            err.0
+        }
+
+        let reg = Reg::Implicit(4, Type::Num(None));
+        let update = update_field::Msg::new(1, vec![(reg.clone(), 14480), (reg.clone(), 1)]);
+        assert_eq!(update.num_fields, 2);
+        assert!(super::serialize(&update).is_ok());
+        assert_eq!(
+            mismatched(&update_field::Msg {
+                num_fields: 1,
+                ..update.clone()
+            }),
+            "update field message has 2 fields, but says it has 1"
+        );
+        assert_eq!(
+            mismatched(&update_field::Msg {
+                widths: Some(vec![update_field::Width::U32]),
+                ..update
+            }),
+            "update field message has 1 widths, but says it has 2"
+        );
+
+        let change = changeprog::Msg::new(1, 7, vec![(reg, 14480)]);
+        assert_eq!(change.num_fields, 1);
+        assert!(super::serialize(&change).is_ok());
+        assert_eq!(
+            mismatched(&changeprog::Msg {
+                num_fields: 3,
+                ..change
+            }),
+            "change program message has 1 fields, but says it has 3"
+        );
+
+        let measure = measure::Msg::new(1, 7, vec![42, 4242]);
+        assert_eq!(measure.num_fields, 2);
+        assert!(super::serialize(&measure).is_ok());
+        let miscounted = measure::Msg {
+            num_fields: 3,
+            ..measure.clone()
+        };
+        assert_eq!(
+            mismatched(&miscounted),
+            "measure message has 2 fields, but says it has 3"
+        );
+        assert_eq!(
+            mismatched(&measure::Msg {
+                types: Some(vec![measure::FieldType::Num]),
+                ..measure
+            }),
+            "measure message has 1 field types, but says it has 2"
+        );
+        assert_eq!(
+            mismatched(&measure_batch::Msg {
+                measurements: vec![miscounted],
+            }),
+            "measure message has 2 fields, but says it has 3"
+        );
+
+        let (bin, _) = crate::lang::compile(
+            b"(def (Report (volatile acked 0)))
+            (when true
+                (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                (report)
+            )",
+            &[],
+        )
+        .expect("compile");
+        let install = install::Msg::new(0, 7, bin.clone());
+        assert_eq!(install.num_events as usize, bin.events.len());
+        assert_eq!(install.num_instrs as usize, bin.instrs.len());
+        assert!(super::serialize(&install).is_ok());
+        let miscounted = install::Msg {
+            num_instrs: install.num_instrs + 1,
+            ..install
+        };
+        assert!(mismatched(&miscounted).starts_with("install message has"));
+        assert!(install::Compact::new(&miscounted).is_err());
+    }
 }
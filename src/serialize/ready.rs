@@ -2,17 +2,32 @@
 
 use super::{u32_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
 use crate::Result;
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const READY: u8 = 5;
+/// The type of ready messages.
+pub const READY: u8 = 5;
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Msg {
     pub id: u32,
 }
 
+#[cfg(feature = "serde")]
+serde_struct!(Msg { id });
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ready id={}", self.id)
+    }
+}
+
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        READY
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (READY, HDR_LENGTH + 1 * 4, 0)
     }
 
@@ -28,8 +43,9 @@ impl AsRawMsg for Msg {
     }
 
     fn from_raw_msg(msg: RawMsg) -> Result<Self> {
-        let u32s = unsafe { msg.get_u32s() }?;
-        Ok(Msg { id: u32s[0] })
+        let id = msg.get_u32(0)?;
+        msg.check_len(4)?;
+        Ok(Msg { id })
     }
 }
 
@@ -48,4 +64,19 @@ mod tests {
     }
 
     check_ready_msg!(test_ready_1, super::Msg { id: 7 });
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_ready_msg() {
+        let buf = crate::serialize::serialize(&super::Msg { id: 0x0102_0304 }).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                5, 0, // READY
+                12, 0, // length = 12
+                0, 0, 0, 0, // sock_id = 0
+                4, 3, 2, 1, // id = 0x01020304
+            ],
+        );
+    }
 }
@@ -0,0 +1,165 @@
+//! Sequence numbers on messages, so that the receiver can tell when messages were lost or
+//! reordered on the way, e.g. over a datagram socket, where a lost update_field message would
+//! otherwise leave the datapath with a stale cwnd for good.
+//!
+//! The header has no room to spare, so a sequenced message has `SEQUENCED` set in its type, and
+//! a 16-bit sequence number appended, before the checksum if it has one (see `checksum`). The
+//! header's length counts it:
+//!
+//! ```text
+//! ---------------------------------------------------------------------
+//! | Msg Type    | Len (B)  | Uint32    | Body | Seq   | Checksum       |
+//! | | SEQUENCED | (2 B)    | (32 bits) |      | (2 B) | (2 B, if any)  |
+//! ---------------------------------------------------------------------
+//! ```
+//!
+//! Each sender numbers the messages it sends each peer from 0, wrapping around after 65535 (see
+//! [`Backend::set_sequence_numbers`](../../ipc/struct.Backend.html#method.set_sequence_numbers)).
+//! Peers which never send sequenced messages do not need to understand them.
+
+use super::{checksum, compress, frame_len, set_len, u16_from_u8s, u16_to_u8s, HDR_LENGTH};
+use crate::Result;
+
+/// Set in the type of a message with a sequence number.
+pub const SEQUENCED: u16 = 0x2000;
+
+pub(crate) const SEQ_LENGTH: usize = 2;
+
+/// Whether the message at the start of `buf` has a sequence number.
+pub fn is_sequenced(buf: &[u8]) -> bool {
+    buf.len() >= HDR_LENGTH as usize && u16_from_u8s(&buf[0..2]) & SEQUENCED != 0
+}
+
+/// The sequence number of the message at the start of `buf`, if it has one.
+pub fn seq(buf: &[u8]) -> Option<u16> {
+    if !is_sequenced(buf) || compress::is_compressed(buf) {
+        return None;
+    }
+
+    let mut end = frame_len(buf).ok()?;
+    if checksum::is_checksummed(buf) {
+        end = end.checked_sub(checksum::CHECKSUM_LENGTH)?;
+    }
+
+    match end.checked_sub(SEQ_LENGTH) {
+        Some(at) if at >= HDR_LENGTH as usize => Some(u16_from_u8s(&buf[at..end])),
+        _ => None,
+    }
+}
+
+/// `msg`, a serialized message, with `seq` appended, or `None` if it cannot have one: it already
+/// has one, is compressed, or is as long as a message can be.
+pub(crate) fn stamp(msg: &[u8], seq: u16) -> Option<Vec<u8>> {
+    let len = frame_len(msg).ok()?;
+    if is_sequenced(msg) || compress::is_compressed(msg) {
+        return None;
+    }
+
+    let checksummed = checksum::is_checksummed(msg);
+    let body_len = if checksummed {
+        len - checksum::CHECKSUM_LENGTH
+    } else {
+        len
+    };
+    let mut out = Vec::with_capacity(len + SEQ_LENGTH);
+    out.extend_from_slice(&msg[..body_len]);
+    let mut seq_buf = [0u8; SEQ_LENGTH];
+    u16_to_u8s(&mut seq_buf, seq);
+    out.extend_from_slice(&seq_buf);
+    let out_len = out.len();
+    set_len(&mut out, out_len).ok()?;
+    let typ = u16_from_u8s(&out[0..2]) | SEQUENCED;
+    u16_to_u8s(&mut out[0..2], typ);
+    if checksummed {
+        checksum::append(&mut out).ok()?;
+    }
+
+    Some(out)
+}
+
+// The length of `msg`, a whole sequenced message without its checksum, without its sequence
+// number.
+pub(crate) fn strip(msg: &[u8]) -> Result<usize> {
+    match msg.len().checked_sub(SEQ_LENGTH) {
+        Some(len) if len >= HDR_LENGTH as usize => Ok(len),
+        _ => Err(crate::Error::from(super::ParseError::BadLength {
+            declared: msg.len(),
+            actual: HDR_LENGTH as usize + SEQ_LENGTH,
+        })),
+    }
+}
+
+/// How many messages went missing between the sequence numbers `last` and `seq`, received one
+/// after the other, or `None` if `seq` is not after `last`, i.e. arrived out of order or twice.
+/// Sequence numbers wrap around, so `seq` is after `last` if it is less than half the sequence
+/// space ahead.
+pub fn missed(last: u16, seq: u16) -> Option<u16> {
+    match seq.wrapping_sub(last) {
+        0 => None,
+        ahead if ahead > u16::MAX / 2 => None,
+        ahead => Some(ahead - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{missed, seq, stamp};
+
+    #[test]
+    fn stamp_and_parse() {
+        let m = crate::serialize::update_field::Msg {
+            sid: 3,
+            num_fields: 1,
+            fields: vec![(
+                crate::lang::Reg::Implicit(4, crate::lang::Type::Num(None)),
+                14480,
+            )],
+            widths: None,
+        };
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(seq(&buf), None);
+
+        let stamped = stamp(&buf, 513).expect("stamp");
+        assert_eq!(stamped.len(), buf.len() + 2);
+        assert_eq!(seq(&stamped), Some(513));
+        assert_eq!(stamp(&stamped, 514), None);
+        match crate::serialize::Msg::from_buf(&stamped).expect("parse") {
+            (crate::serialize::Msg::Uf(got), len) => {
+                assert_eq!(got, m);
+                assert_eq!(len, stamped.len());
+            }
+            got => panic!("expected an update field message, got {:?}", got),
+        }
+    }
+
+    #[test]
+    fn stamp_checksummed() {
+        let mut buf = crate::serialize::serialize(&crate::serialize::heartbeat::Msg { seq: 7 })
+            .expect("serialize");
+        if !crate::serialize::checksum::is_checksummed(&buf) {
+            crate::serialize::checksum::append(&mut buf).expect("append checksum");
+        }
+
+        let stamped = stamp(&buf, u16::MAX).expect("stamp");
+        assert_eq!(seq(&stamped), Some(u16::MAX));
+        assert_eq!(
+            crate::serialize::checksum::verify(&stamped),
+            Ok(stamped.len() - 2)
+        );
+        assert_eq!(
+            crate::serialize::Msg::from_buf(&stamped).expect("parse").0,
+            crate::serialize::Msg::Hb(crate::serialize::heartbeat::Msg { seq: 7 })
+        );
+    }
+
+    #[test]
+    fn missed_wraps_around() {
+        assert_eq!(missed(0, 1), Some(0));
+        assert_eq!(missed(0, 4), Some(3));
+        assert_eq!(missed(u16::MAX, 0), Some(0));
+        assert_eq!(missed(u16::MAX - 1, 1), Some(2));
+        assert_eq!(missed(5, 5), None);
+        assert_eq!(missed(5, 4), None);
+        assert_eq!(missed(1, u16::MAX), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! CCP sends this message when an algorithm gives up on a flow, e.g. after repeated errors, so
+//! that the datapath releases it back to its own congestion control. CCP sends the flow nothing
+//! more, and ignores any further measurements for it.
+
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of teardown messages.
+pub const TEARDOWN: u8 = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u64,
+    /// Why the algorithm gave up on the flow, in its own numbering.
+    pub reason: u32,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { sid, reason });
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "teardown sid={} reason={}", self.sid, self.reason)
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        TEARDOWN
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (TEARDOWN, HDR_LENGTH + 4, self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.reason);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 4,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(4)?;
+
+        Ok(Msg {
+            sid: msg.sid,
+            reason: u32_from_u8s(&b[0..4]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_teardown_1,
+        super::Msg,
+        super::Msg { sid: 15, reason: 3 },
+        crate::serialize::Msg::Td(tm),
+        tm
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_teardown_msg() {
+        let m = super::Msg { sid: 1, reason: 2 };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                12, 0, // TEARDOWN
+                12, 0, // length = 12
+                1, 0, 0, 0, // sock_id = 1
+                2, 0, 0, 0, // reason = 2
+            ],
+        );
+    }
+}
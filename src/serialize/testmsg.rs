@@ -6,7 +6,11 @@ use std::io::prelude::*;
 pub struct Msg(pub String);
 
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        0xff
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (0xff, HDR_LENGTH + self.0.len() as u32, 0)
     }
 
@@ -0,0 +1,116 @@
+//! CCP sends this message to stop the datapath running a flow's program, e.g. before the flow goes
+//! back to the datapath's own congestion control. A flow which is not running a program, or is
+//! running a different one than `program_uid`, is left as it is.
+
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of uninstall messages.
+pub const UNINSTALL: u8 = 11;
+
+/// The `program_uid` which stops whichever program the flow is running.
+pub const ANY_PROGRAM: u32 = 0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Msg {
+    pub sid: u64,
+    pub program_uid: u32,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { sid, program_uid });
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "uninstall sid={} program_uid=", self.sid)?;
+        if self.program_uid == ANY_PROGRAM {
+            write!(f, "any")
+        } else {
+            write!(f, "{}", self.program_uid)
+        }
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        UNINSTALL
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (UNINSTALL, HDR_LENGTH + 4, self.sid)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        u32_to_u8s(&mut buf, self.program_uid);
+        w.write_all(&buf[..])?;
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 4,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(4)?;
+
+        Ok(Msg {
+            sid: msg.sid,
+            program_uid: u32_from_u8s(&b[0..4]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_uninstall_1,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: 3,
+        },
+        crate::serialize::Msg::Un(um),
+        um
+    );
+
+    check_msg!(
+        test_uninstall_any,
+        super::Msg,
+        super::Msg {
+            sid: 15,
+            program_uid: super::ANY_PROGRAM,
+        },
+        crate::serialize::Msg::Un(um),
+        um
+    );
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_uninstall_msg() {
+        let m = super::Msg {
+            sid: 1,
+            program_uid: 7,
+        };
+
+        let buf: Vec<u8> = crate::serialize::serialize::<super::Msg>(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                11, 0, // UNINSTALL
+                12, 0, // length = 12
+                1, 0, 0, 0, // sock_id = 1
+                7, 0, 0, 0, // program_uid = 7
+            ],
+        );
+    }
+}
@@ -1,67 +1,446 @@
 //! CCP sends this message specifying that the datapath should set the values of the
 //! given fields to the given values.
+//!
+//! Each field is a 5-byte register and an 8-byte value, unless the message sets `WIDTHS` in its
+//! field count. Then each register is followed by a 1-byte `Width` tag, and the value takes only
+//! as many bytes as its width says, so a field which fits in 32 bits, e.g. a cwnd or a rate,
+//! takes 10 bytes rather than 13. Widths arrived in minor version 8 (see
+//! [`version`](../version/index.html)), so CCP sends them only to a datapath which announced
+//! that.
 
-use super::{u32_to_u8s, u64_to_u8s, AsRawMsg, RawMsg, HDR_LENGTH};
+use super::{
+    check_count, msg_len, u32_from_u8s, u32_to_u8s, u64_from_u8s, u64_to_u8s, AsRawMsg, ParseError,
+    RawMsg,
+};
 use crate::lang::Reg;
 use crate::{Error, Result};
+use std::fmt;
 use std::io::prelude::*;
 
-pub(crate) const UPDATE_FIELD: u8 = 3;
+/// The type of update field messages.
+pub const UPDATE_FIELD: u8 = 3;
+
+/// Set in the field count of a message whose fields have widths.
+pub const WIDTHS: u32 = 1 << 31;
+
+// The minor version which introduced `WIDTHS`.
+pub(crate) const WIDTHS_MINOR: u32 = 8;
+
+/// How many bytes a field's value takes, in a message with widths.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Width {
+    /// A value which fits in 4 bytes.
+    U32,
+    /// A value in 8 bytes.
+    U64,
+}
+
+#[cfg(feature = "serde")]
+serde_enum!(Width { U32, U64 });
+
+impl Width {
+    fn tag(self) -> u8 {
+        match self {
+            Width::U32 => 4,
+            Width::U64 => 8,
+        }
+    }
+
+    fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            4 => Ok(Width::U32),
+            8 => Ok(Width::U64),
+            _ => Err(Error::from(ParseError::BadPayload(format!(
+                "unknown update field width: {}",
+                tag
+            )))),
+        }
+    }
+
+    /// How many bytes a value of this width takes, after its tag.
+    pub fn wire_len(self) -> usize {
+        usize::from(self.tag())
+    }
+
+    /// The narrowest width which holds `val`.
+    pub fn of(val: u64) -> Self {
+        if val > u64::from(u32::MAX) {
+            Width::U64
+        } else {
+            Width::U32
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Msg {
-    pub sid: u32,
+    pub sid: u64,
     pub num_fields: u8,
     pub fields: Vec<(Reg, u64)>,
+    /// The width of each field's value, if the message has widths.
+    pub widths: Option<Vec<Width>>,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg {
+    sid,
+    num_fields,
+    fields,
+    widths,
+});
+
+impl Msg {
+    /// A message setting `fields`, with its field count to match.
+    pub fn new(sid: u64, fields: Vec<(Reg, u64)>) -> Self {
+        Msg {
+            sid,
+            num_fields: fields.len() as u8,
+            fields,
+            widths: None,
+        }
+    }
+
+    /// Give each field the narrowest width which holds its value.
+    pub fn narrow(self) -> Self {
+        let widths = self.fields.iter().map(|&(_, val)| Width::of(val)).collect();
+        Msg {
+            widths: Some(widths),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "update_field sid={} fields={}",
+            self.sid, self.num_fields
+        )?;
+        for (i, (reg, val)) in self.fields.iter().enumerate() {
+            write!(f, " {}={}", reg, val)?;
+            if let Some(width) = self.widths.as_ref().and_then(|widths| widths.get(i)) {
+                write!(f, ":{:?}", width)?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
 impl AsRawMsg for Msg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
-        (
-            UPDATE_FIELD,
-            HDR_LENGTH + 4 + u32::from(self.num_fields) * 13, // Reg size = 5, u64 size = 8
-            self.sid,
-        )
+    fn typ() -> u8 {
+        UPDATE_FIELD
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        let len = match self.widths {
+            // Reg size = 5, tag size = 1
+            Some(ref widths) => widths
+                .iter()
+                .map(|w| 6 + w.wire_len() as u32)
+                .fold(msg_len(4, 0, 0), u32::saturating_add),
+            None => msg_len(4, self.fields.len(), 13), // Reg size = 5, u64 size = 8
+        };
+
+        (UPDATE_FIELD, len, self.sid)
+    }
+
+    fn check_counts(&self) -> Result<()> {
+        let fields = self.fields.len();
+        check_count(
+            "update field",
+            "fields",
+            usize::from(self.num_fields),
+            fields,
+        )?;
+        if let Some(ref widths) = self.widths {
+            check_count("update field", "widths", fields, widths.len())?;
+        }
+
+        Ok(())
     }
 
     fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut num_fields = u32::from(self.num_fields);
+        if self.widths.is_some() {
+            num_fields |= WIDTHS;
+        }
+
         let mut buf = [0u8; 4];
-        u32_to_u8s(&mut buf, u32::from(self.num_fields));
+        u32_to_u8s(&mut buf, num_fields);
         w.write_all(&buf[..])?;
         Ok(())
     }
 
     fn get_bytes<W: Write>(&self, w: &mut W) -> Result<()> {
         let mut buf = [0u8; 8];
-        for f in &self.fields {
-            let reg =
-                f.0.clone()
-                    .into_iter()
-                    .map(|e| e.map_err(Error::from))
-                    .collect::<Result<Vec<u8>>>()?;
-            w.write_all(&reg[..])?;
+        for (i, f) in self.fields.iter().enumerate() {
+            w.write_all(&crate::lang::serialize_reg(&f.0)?)?;
             u64_to_u8s(&mut buf, f.1);
-            w.write_all(&buf[..])?;
+            match self.widths {
+                Some(ref widths) => {
+                    let width = widths[i];
+                    if Width::of(f.1) == Width::U64 && width == Width::U32 {
+                        return Err(Error(format!(
+                            "update field value {} does not fit in {:?}",
+                            f.1, width
+                        )));
+                    }
+
+                    w.write_all(&[width.tag()])?;
+                    w.write_all(&buf[..width.wire_len()])?;
+                }
+                None => w.write_all(&buf[..])?,
+            }
         }
 
         Ok(())
     }
 
-    fn from_raw_msg(_msg: RawMsg) -> Result<Self> {
-        unimplemented!()
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.bytes;
+        if b.len() < 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 4,
+                actual: b.len(),
+            }));
+        }
+
+        let flags = u32_from_u8s(&b[0..4]);
+        let num_fields = flags & !WIDTHS;
+        let fields = &b[4..];
+        let mismatch = || {
+            Error::from(ParseError::BadPayload(format!(
+                "update field message has {} bytes of fields, but says it has {} fields",
+                fields.len(),
+                num_fields
+            )))
+        };
+        if num_fields > u32::from(u8::MAX) {
+            return Err(mismatch());
+        }
+
+        if flags & WIDTHS == 0 {
+            if fields.len() != num_fields as usize * 13 {
+                return Err(Error::from(ParseError::BadLength {
+                    declared: num_fields as usize * 13,
+                    actual: fields.len(),
+                }));
+            }
+
+            return Ok(Msg {
+                sid: msg.sid,
+                num_fields: num_fields as u8,
+                fields: fields
+                    .chunks(13)
+                    .map(|f| {
+                        Ok((
+                            crate::lang::deserialize_reg(&f[0..5])?,
+                            u64_from_u8s(&f[5..]),
+                        ))
+                    })
+                    .collect::<Result<_>>()?,
+                widths: None,
+            });
+        }
+
+        let mut rest = fields;
+        let mut regs = Vec::with_capacity(num_fields as usize);
+        let mut widths = Vec::with_capacity(num_fields as usize);
+        for _ in 0..num_fields {
+            if rest.len() < 6 {
+                return Err(mismatch());
+            }
+
+            let width = Width::from_tag(rest[5])?;
+            if rest.len() < 6 + width.wire_len() {
+                return Err(mismatch());
+            }
+
+            let mut val = [0u8; 8];
+            val[..width.wire_len()].copy_from_slice(&rest[6..6 + width.wire_len()]);
+            regs.push((
+                crate::lang::deserialize_reg(&rest[0..5])?,
+                u64_from_u8s(&val),
+            ));
+            widths.push(width);
+            rest = &rest[6 + width.wire_len()..];
+        }
+        if !rest.is_empty() {
+            return Err(mismatch());
+        }
+
+        Ok(Msg {
+            sid: msg.sid,
+            num_fields: num_fields as u8,
+            fields: regs,
+            widths: Some(widths),
+        })
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::lang::Reg;
+    use super::Width;
+    use crate::lang::{Reg, Type};
+
+    check_msg!(
+        test_update_field_none,
+        super::Msg,
+        super::Msg {
+            sid: 7,
+            num_fields: 0,
+            fields: vec![],
+            widths: None,
+        },
+        crate::serialize::Msg::Uf(ufm),
+        ufm
+    );
+
+    check_msg!(
+        test_update_field_many,
+        super::Msg,
+        super::Msg {
+            sid: 7,
+            num_fields: 3,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 42),
+                (Reg::Report(2, Type::Num(None), true), u64::MAX),
+                (Reg::Control(0, Type::Num(None), false), 0),
+            ],
+            widths: None,
+        },
+        crate::serialize::Msg::Uf(ufm),
+        ufm
+    );
+
+    check_msg!(
+        test_update_field_widths,
+        super::Msg,
+        super::Msg {
+            sid: 7,
+            num_fields: 3,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 14480),
+                (Reg::Report(2, Type::Num(None), true), u64::MAX),
+                (Reg::Control(0, Type::Num(None), false), 42),
+            ],
+            widths: Some(vec![Width::U32, Width::U64, Width::U64]),
+        },
+        crate::serialize::Msg::Uf(ufm),
+        ufm
+    );
+
+    #[test]
+    fn update_field_narrow() {
+        let m = super::Msg {
+            sid: 1,
+            num_fields: 2,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 14480),
+                (
+                    Reg::Control(0, Type::Num(None), false),
+                    u64::from(u32::MAX) + 1,
+                ),
+            ],
+            widths: None,
+        }
+        .narrow();
+        assert_eq!(m.widths, Some(vec![Width::U32, Width::U64]));
+
+        // a value too large for its width
+        let wrong = super::Msg {
+            widths: Some(vec![Width::U32, Width::U32]),
+            ..m.clone()
+        };
+        assert!(crate::serialize::serialize(&wrong).is_err());
+
+        let few = super::Msg {
+            widths: Some(vec![Width::U32]),
+            ..m.clone()
+        };
+        assert!(crate::serialize::serialize(&few).is_err());
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        let parse = |buf: &[u8]| crate::serialize::Msg::from_buf(buf).map(|_| ());
+        assert_eq!(parse(&buf), Ok(()));
+
+        // cut off in the last field, with the header's length to match
+        let mut short = buf[..buf.len() - 3].to_vec();
+        short[2] -= 3;
+        assert!(parse(&short).is_err());
+
+        // an unknown width
+        let mut unknown = buf.clone();
+        unknown[12 + 5] = 2;
+        assert!(parse(&unknown).is_err());
+    }
 
     #[test]
+    fn update_field_short() {
+        let m = super::Msg {
+            sid: 1,
+            num_fields: 2,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 42),
+                (Reg::Implicit(3, Type::Num(None)), 43),
+            ],
+            widths: None,
+        };
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        let parse = |buf: &[u8]| crate::serialize::Msg::from_buf(buf).map(|_| ());
+
+        // cut off in the last field, with the header's length to match
+        let mut short = buf[..buf.len() - 3].to_vec();
+        short[2] -= 3;
+        assert!(parse(&short).is_err());
+
+        // says it has more fields than it does
+        let mut more = buf.clone();
+        more[8] = 3;
+        assert!(parse(&more).is_err());
+
+        // cut off before the number of fields
+        let mut empty = buf[..10].to_vec();
+        empty[2] = 10;
+        assert!(parse(&empty).is_err());
+        assert_eq!(parse(&buf), Ok(()));
+    }
+
+    // Too many fields is an error, not a message whose header disagrees with its body.
+    #[test]
+    fn update_field_too_long() {
+        let field = (Reg::Implicit(4, Type::Num(None)), 42);
+        let fields = vec![field.clone(); 300];
+        let m = super::Msg {
+            sid: 1,
+            num_fields: fields.len() as u8,
+            fields,
+            widths: None,
+        };
+        let err = crate::serialize::serialize(&m).expect_err("more fields than the count holds");
+        assert!(err.0.contains("300 fields"), "{}", err.0);
+
+        let m = super::Msg {
+            sid: 1,
+            num_fields: u8::MAX,
+            fields: vec![field; 6000],
+            widths: None,
+        };
+        // the count is checked before the length, which no message with a right count exceeds
+        let err = crate::serialize::serialize(&m).expect_err("longer than the header holds");
+        assert!(err.0.contains("6000 fields"), "{}", err.0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
     fn serialize_update_msg() {
         let m = super::Msg {
             sid: 1,
             num_fields: 1,
             fields: vec![(Reg::Implicit(4, crate::lang::Type::Num(None)), 42)],
+            widths: None,
         };
 
         let buf: Vec<u8> =
@@ -77,4 +456,36 @@ mod tests {
             ],
         );
     }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_narrow_update_msg() {
+        let m = super::Msg {
+            sid: 1,
+            num_fields: 2,
+            fields: vec![
+                (Reg::Implicit(4, Type::Num(None)), 14480),
+                (
+                    Reg::Control(0, Type::Num(None), false),
+                    0x0102_0304_0506_0708,
+                ),
+            ],
+            widths: Some(vec![Width::U32, Width::U64]),
+        };
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                3, 0, // UPDATE_FIELD
+                36, 0, // length = 36
+                1, 0, 0, 0, // sock_id = 1
+                2, 0, 0, 0x80, // num_fields = 2 | WIDTHS
+                2, 4, 0, 0, 0, // Reg::Implicit(4)
+                4, 0x90, 0x38, 0, 0, // <- 14480, in 4 bytes
+                0, 0, 0, 0, 0, // Reg::Control(0)
+                8, 8, 7, 6, 5, 4, 3, 2, 1, // <- 0x0102030405060708, in 8 bytes
+            ],
+        );
+    }
 }
@@ -0,0 +1,134 @@
+//! Message each side sends the other on first contact, saying which version of these messages it
+//! speaks. Peers with different major versions cannot understand each other; a newer minor
+//! version only adds messages or fields which an older peer can ignore.
+
+use super::{u32_from_u8s, u32_to_u8s, AsRawMsg, ParseError, RawMsg, HDR_LENGTH};
+use crate::{Error, Result};
+use std::fmt;
+use std::io::prelude::*;
+
+/// The type of version messages.
+pub const VERSION: u8 = 8;
+
+/// The major version of the messages this portus speaks.
+pub const MAJOR: u32 = 1;
+/// The minor version of the messages this portus speaks.
+///
+/// - 1: measurements may have typed fields (see `measure::TYPED_FIELDS`).
+/// - 2: measurements may have timestamps (see `measure::TIMESTAMPED`).
+/// - 3: socket ids may be 64 bits (see `serialize::WIDE_SID`).
+/// - 4: install messages may be longer than 16 bits allow (see `serialize::EXTENDED_LEN`).
+/// - 5: messages may set optional flags, which peers ignore if they do not know them (see
+///   `serialize::OPTIONAL_FLAGS`).
+/// - 6: install messages may serialize their programs with varints (see `install::VARINT`).
+/// - 7: install messages may name their programs' report variables (see `install::NAMED`).
+/// - 8: update field messages may send values in 4 bytes (see `update_field::WIDTHS`).
+/// - 9: datapaths may end flows with fin messages, rather than measurements with no fields (see
+///   `fin`).
+/// - 10: measurements may have string fields (see `measure::FieldType::Str`).
+pub const MINOR: u32 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Msg {
+    pub major: u32,
+    pub minor: u32,
+}
+
+#[cfg(feature = "serde")]
+serde_struct!(Msg { major, minor });
+
+impl Msg {
+    /// The version this portus speaks.
+    pub fn current() -> Self {
+        Msg {
+            major: MAJOR,
+            minor: MINOR,
+        }
+    }
+}
+
+impl fmt::Display for Msg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+impl AsRawMsg for Msg {
+    fn typ() -> u8 {
+        VERSION
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
+        (VERSION, HDR_LENGTH + 2 * 4, 0)
+    }
+
+    fn get_u32s<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut buf = [0u8; 4];
+        for x in &[self.major, self.minor] {
+            u32_to_u8s(&mut buf, *x);
+            w.write_all(&buf[..])?;
+        }
+
+        Ok(())
+    }
+
+    fn get_bytes<W: Write>(&self, _: &mut W) -> Result<()> {
+        Ok(())
+    }
+
+    fn from_raw_msg(msg: RawMsg) -> Result<Self> {
+        let b = msg.get_bytes()?;
+        if b.len() < 2 * 4 {
+            return Err(Error::from(ParseError::BadLength {
+                declared: 2 * 4,
+                actual: b.len(),
+            }));
+        }
+        msg.check_len(2 * 4)?;
+
+        Ok(Msg {
+            major: u32_from_u8s(&b[0..4]),
+            minor: u32_from_u8s(&b[4..8]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    check_msg!(
+        test_version_1,
+        super::Msg,
+        super::Msg { major: 1, minor: 7 },
+        crate::serialize::Msg::Ver(vm),
+        vm
+    );
+
+    #[test]
+    fn version_short() {
+        let mut buf = crate::serialize::serialize(&super::Msg::current()).expect("serialize");
+        buf.truncate(buf.len() - 4);
+        buf[2] = buf.len() as u8;
+        assert!(crate::serialize::Msg::from_buf(&buf).is_err());
+    }
+
+    #[test]
+    #[cfg(not(feature = "checksum"))]
+    fn serialize_version_msg() {
+        let m = super::Msg {
+            major: 1,
+            minor: 0x0102_0304,
+        };
+
+        let buf = crate::serialize::serialize(&m).expect("serialize");
+        assert_eq!(
+            buf,
+            vec![
+                8, 0, // VERSION
+                16, 0, // length = 16
+                0, 0, 0, 0, // sock_id = 0
+                1, 0, 0, 0, // major = 1
+                4, 3, 2, 1, // minor = 0x01020304
+            ],
+        );
+    }
+}
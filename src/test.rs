@@ -1,5 +1,6 @@
 use super::ipc;
 use super::serialize;
+use super::serialize::codes::Code;
 use std::sync::{atomic, Arc};
 use std::thread;
 
@@ -20,6 +21,9 @@ fn test_ser_over_ipc() {
                 program_uid: 7,
                 num_fields: 1,
                 fields: vec![0],
+                types: None,
+                timestamp: None,
+                strings: vec![],
             })
         );
     });
@@ -36,6 +40,9 @@ fn test_ser_over_ipc() {
             program_uid: 7,
             num_fields: 1,
             fields: vec![0],
+            types: None,
+            timestamp: None,
+            strings: vec![],
         };
 
         let buf = serialize::serialize(&m.clone()).expect("serialize");
@@ -45,3 +52,3035 @@ fn test_ser_over_ipc() {
     c2.join().expect("join sender thread");
     c1.join().expect("join rcvr thread");
 }
+
+struct GoneIpc;
+
+impl ipc::IpcSend for GoneIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("gone")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+        Err(crate::Error::from(crate::DatapathGoneError))
+    }
+}
+
+impl ipc::IpcRecv for GoneIpc {
+    fn recv(&self, _msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+        Err(crate::Error::from(crate::DatapathGoneError))
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct NopAlg;
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for NopAlg {
+    type Flow = Self;
+
+    fn name() -> &'static str {
+        "nop"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        NopAlg
+    }
+}
+
+impl crate::Flow for NopAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+}
+
+#[test]
+fn test_datapath_gone() {
+    let res = crate::RunBuilder::new(ipc::BackendBuilder { sock: GoneIpc })
+        .default_alg(NopAlg)
+        .run();
+    assert_eq!(
+        res.expect_err("run should fail"),
+        crate::Error::from(crate::DatapathGoneError)
+    );
+}
+
+// Delivers a create message, then reports that it reconnected, then fails.
+struct ReconnectIpc(atomic::AtomicUsize);
+
+impl ipc::IpcSend for ReconnectIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("reconnect")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+impl ipc::IpcRecv for ReconnectIpc {
+    fn recv(&self, msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+        match self.0.fetch_add(1, atomic::Ordering::SeqCst) {
+            0 => {
+                let buf = serialize::serialize(&serialize::create::Msg {
+                    sid: 1,
+                    init_cwnd: 14480,
+                    mss: 1448,
+                    src_ip: 0,
+                    src_port: 4242,
+                    dst_ip: 0,
+                    dst_port: 4243,
+                    cong_alg: None,
+                })?;
+                msg[..buf.len()].copy_from_slice(&buf);
+                Ok((buf.len(), ()))
+            }
+            1 => Ok((0, ())),
+            _ => Err(crate::Error::from(crate::DatapathGoneError)),
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn reconnects(&self) -> u64 {
+        if self.0.load(atomic::Ordering::SeqCst) >= 2 {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+struct CloseAlg(Arc<atomic::AtomicBool>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for CloseAlg {
+    type Flow = CloseAlg;
+
+    fn name() -> &'static str {
+        "close"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        CloseAlg(self.0.clone())
+    }
+}
+
+impl crate::Flow for CloseAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+
+    fn close(&mut self) {
+        self.0.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_reconnect_closes_flows() {
+    let closed = Arc::new(atomic::AtomicBool::new(false));
+    let res = crate::RunBuilder::new(ipc::BackendBuilder {
+        sock: ReconnectIpc(atomic::AtomicUsize::new(0)),
+    })
+    .default_alg(CloseAlg(closed.clone()))
+    .run();
+    assert!(res.is_err());
+    assert!(closed.load(atomic::Ordering::SeqCst));
+}
+
+// Delivers the messages in `pending`, and keeps the ones it is sent until `failing` is set, after
+// which sends fail.
+#[derive(Default)]
+struct FlakyIpc {
+    pending: std::sync::Mutex<Vec<Vec<u8>>>,
+    sent: Arc<std::sync::Mutex<Vec<Vec<u8>>>>,
+    failing: Arc<atomic::AtomicBool>,
+}
+
+impl ipc::IpcSend for FlakyIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("flaky")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+        if self.failing.load(atomic::Ordering::SeqCst) {
+            return Err(crate::Error::from(crate::DatapathGoneError));
+        }
+
+        self.sent.lock().unwrap().push(msg.to_vec());
+        Ok(())
+    }
+}
+
+impl ipc::IpcRecv for FlakyIpc {
+    fn recv(&self, msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+        match self.pending.lock().unwrap().pop() {
+            Some(buf) => {
+                msg[..buf.len()].copy_from_slice(&buf);
+                Ok((buf.len(), ()))
+            }
+            None => Ok((0, ())),
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+
+    fn set_recv_timeout(&mut self, _timeout: std::time::Duration) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+// Sets its program again on every tick, and after a failover.
+struct FailoverAlg {
+    closed: Arc<atomic::AtomicBool>,
+    failovers: Arc<atomic::AtomicUsize>,
+}
+
+struct FailoverFlow<I: ipc::Ipc> {
+    control: crate::Datapath<I>,
+    closed: Arc<atomic::AtomicBool>,
+    failovers: Arc<atomic::AtomicUsize>,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for FailoverAlg {
+    type Flow = FailoverFlow<I>;
+
+    fn name() -> &'static str {
+        "failover"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "acked",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        control.set_program("acked", None).expect("set program");
+        FailoverFlow {
+            control,
+            closed: self.closed.clone(),
+            failovers: self.failovers.clone(),
+        }
+    }
+}
+
+impl<I: ipc::Ipc> crate::Flow for FailoverFlow<I> {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+
+    fn on_tick(&mut self) {
+        use crate::DatapathTrait;
+        // fails while the primary is down
+        self.control.set_program("acked", None).ok();
+    }
+
+    fn on_failover(&mut self) {
+        use crate::DatapathTrait;
+        self.failovers.fetch_add(1, atomic::Ordering::SeqCst);
+        self.control
+            .set_program("acked", None)
+            .expect("set program on standby");
+    }
+
+    fn close(&mut self) {
+        self.closed.store(true, atomic::Ordering::SeqCst);
+    }
+}
+
+#[test]
+fn test_failover_to_standby() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let primary = FlakyIpc {
+        pending: std::sync::Mutex::new(vec![create]),
+        ..Default::default()
+    };
+    let primary_failing = primary.failing.clone();
+    let primary_sent = primary.sent.clone();
+    let standby = FlakyIpc::default();
+    let standby_sent = standby.sent.clone();
+    let sock = ipc::failover::Socket::new(
+        vec![primary, standby],
+        ipc::failover::FailoverPolicy {
+            max_send_errors: 1,
+            max_missed_heartbeats: 3,
+        },
+    )
+    .expect("failover socket");
+
+    let closed = Arc::new(atomic::AtomicBool::new(false));
+    let failovers = Arc::new(atomic::AtomicUsize::new(0));
+    let tick = std::time::Duration::from_millis(1);
+    crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(FailoverAlg {
+            closed: closed.clone(),
+            failovers: failovers.clone(),
+        })
+        .with_tick(tick)
+        .pumped()
+        .run(|pump| {
+            // the flow starts on the primary
+            pump.dispatch_ready()?;
+            primary_failing.store(true, atomic::Ordering::SeqCst);
+            // and the next tick's set_program fails over
+            thread::sleep(2 * tick);
+            pump.dispatch_ready()
+        })
+        .expect("pumped run");
+
+    let typs = |sent: &Arc<std::sync::Mutex<Vec<Vec<u8>>>>| -> Vec<u8> {
+        sent.lock().unwrap().iter().map(|m| m[0]).collect()
+    };
+    // install, then change program
+    assert_eq!(typs(&primary_sent), vec![2, 4]);
+    assert_eq!(typs(&standby_sent), vec![2, 4]);
+    assert_eq!(failovers.load(atomic::Ordering::SeqCst), 1);
+    assert!(!closed.load(atomic::Ordering::SeqCst));
+}
+
+// Delivers a create message, then echoes the first `echoes` heartbeats it is sent and ignores
+// the rest.
+struct HeartbeatIpc {
+    pending: std::sync::Mutex<Vec<Vec<u8>>>,
+    echoes: atomic::AtomicUsize,
+}
+
+impl ipc::IpcSend for HeartbeatIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("heartbeat")
+    }
+
+    fn send(&self, msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+        // install messages can't be parsed, so look at the type before parsing
+        let is_heartbeat = msg.first() == Some(&serialize::heartbeat::HEARTBEAT)
+            && matches!(
+                serialize::Msg::from_buf(msg),
+                Ok((serialize::Msg::Hb(_), _))
+            );
+        if is_heartbeat && self.echoes.load(atomic::Ordering::SeqCst) > 0 {
+            self.echoes.fetch_sub(1, atomic::Ordering::SeqCst);
+            self.pending.lock().unwrap().push(msg.to_vec());
+        }
+
+        Ok(())
+    }
+}
+
+impl ipc::IpcRecv for HeartbeatIpc {
+    fn recv(&self, msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+        match self.pending.lock().unwrap().pop() {
+            Some(buf) => {
+                msg[..buf.len()].copy_from_slice(&buf);
+                Ok((buf.len(), ()))
+            }
+            None => {
+                // as if the receive timed out
+                thread::sleep(std::time::Duration::from_millis(5));
+                Ok((0, ()))
+            }
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_missed_heartbeats_close_flows() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![create]),
+        echoes: atomic::AtomicUsize::new(3),
+    };
+    let closed = Arc::new(atomic::AtomicBool::new(false));
+    let start = std::time::Instant::now();
+    let res = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CloseAlg(closed.clone()))
+        .with_heartbeat(std::time::Duration::from_millis(20), 2)
+        .run();
+    assert_eq!(
+        res.expect_err("run should fail"),
+        crate::Error::from(crate::DatapathGoneError)
+    );
+    assert!(closed.load(atomic::Ordering::SeqCst));
+    // 3 echoed heartbeats, then 2 missed ones and one more interval
+    assert!(start.elapsed() >= std::time::Duration::from_millis(6 * 20));
+}
+
+// Counts the flows it creates.
+struct CountAlg(Arc<atomic::AtomicUsize>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for CountAlg {
+    type Flow = NopAlg;
+
+    fn name() -> &'static str {
+        "count"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        self.0.fetch_add(1, atomic::Ordering::SeqCst);
+        NopAlg
+    }
+}
+
+#[test]
+fn test_pumped_run() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![create]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let created = Arc::new(atomic::AtomicUsize::new(0));
+    let res = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CountAlg(created.clone()))
+        .pumped()
+        .run(|pump| {
+            // nothing happens until the caller dispatches
+            assert_eq!(created.load(atomic::Ordering::SeqCst), 0);
+            assert_eq!(pump.dispatch_ready()?, 1);
+            assert_eq!(created.load(atomic::Ordering::SeqCst), 1);
+            assert_eq!(pump.dispatch_ready()?, 0);
+            Ok(pump.backend().stats().received())
+        });
+    assert_eq!(res.expect("pumped run"), 1);
+    assert_eq!(created.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_malformed_msgs_skipped() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let truncated = create[..create.len() / 2].to_vec();
+    // a capabilities message needs a longer body than a heartbeat's
+    let mut wrong_type =
+        serialize::serialize(&serialize::heartbeat::Msg { seq: 1 }).expect("serialize heartbeat");
+    wrong_type[0] = serialize::capabilities::CAPABILITIES;
+    let wrong_type = serialize::checksum::fix_up(wrong_type);
+    // received last to first
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![create, wrong_type.clone(), truncated.clone()]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let created = Arc::new(atomic::AtomicUsize::new(0));
+    let bad = Arc::new(std::sync::Mutex::new(vec![]));
+    let bad1 = bad.clone();
+    let malformed = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CountAlg(created.clone()))
+        .on_parse_error(move |msg, _| bad1.lock().unwrap().push(msg.to_vec()))
+        .pumped()
+        .run(|pump| {
+            assert_eq!(pump.dispatch_ready()?, 1);
+            Ok(pump.backend().stats().malformed())
+        })
+        .expect("pumped run");
+    assert_eq!(malformed, 2);
+    assert_eq!(*bad.lock().unwrap(), vec![truncated, wrong_type]);
+    assert_eq!(created.load(atomic::Ordering::SeqCst), 1);
+}
+
+#[test]
+fn test_strict_parse_mode() {
+    let mut create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    // a field this portus does not know
+    create.extend_from_slice(&[0, 0, 0, 0]);
+    create[2] = create.len() as u8;
+    let create = serialize::checksum::fix_up(create);
+    let run = |mode| {
+        let sock = HeartbeatIpc {
+            pending: std::sync::Mutex::new(vec![create.clone()]),
+            echoes: atomic::AtomicUsize::new(0),
+        };
+        let created = Arc::new(atomic::AtomicUsize::new(0));
+        let malformed = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+            .default_alg(CountAlg(created.clone()))
+            .parse_mode(mode)
+            .pumped()
+            .run(|pump| {
+                pump.dispatch_ready()?;
+                Ok(pump.backend().stats().malformed())
+            })
+            .expect("pumped run");
+        (malformed, created.load(atomic::Ordering::SeqCst))
+    };
+
+    assert_eq!(run(serialize::ParseMode::Lenient), (0, 1));
+    assert_eq!(run(serialize::ParseMode::Strict), (1, 0));
+}
+
+#[test]
+fn test_msg_handler() {
+    let gso_stats = serialize::checksum::fix_up(vec![
+        100, 0, // a type of the datapath's own
+        12, 0, // length = 12
+        5, 0, 0, 0, // sock_id = 5
+        1, 2, 3, 4, // payload
+    ]);
+    let unhandled = serialize::checksum::fix_up(vec![
+        101, 0, // a type nothing handles
+        8, 0, // length = 8
+        5, 0, 0, 0, // sock_id = 5
+    ]);
+    // received last to first
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![unhandled, gso_stats]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let got = Arc::new(std::sync::Mutex::new(vec![]));
+    let got1 = got.clone();
+    let handled = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(NopAlg)
+        .on_msg(100, move |_, msg| {
+            let payload = msg.get_bytes().expect("payload").to_vec();
+            got1.lock().unwrap().push((msg.typ, msg.sid, payload));
+        })
+        .expect("register handler")
+        .pumped()
+        .run(|pump| pump.dispatch_ready())
+        .expect("pumped run");
+    assert_eq!(handled, 2);
+    assert_eq!(*got.lock().unwrap(), vec![(100, 5, vec![1, 2, 3, 4])]);
+}
+
+#[test]
+fn test_msg_handler_predefined_type() {
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    assert!(serialize::is_predefined_type(serialize::measure::MEASURE));
+    assert!(serialize::is_predefined_type(255));
+    assert!(!serialize::is_predefined_type(100));
+    assert!(crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(NopAlg)
+        .on_msg(serialize::measure::MEASURE, |_, _| ())
+        .is_err());
+}
+
+// Tries to switch each new flow to a program which needs ECN, and one which does not.
+struct EcnAlg(Arc<std::sync::Mutex<Vec<crate::Result<()>>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for EcnAlg {
+    type Flow = NopAlg;
+
+    fn name() -> &'static str {
+        "ecn"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "ecn",
+            "(def (Report (volatile ecn 0))) (when true (:= Report.ecn Ack.ecn_bytes) (report))"
+                .to_owned(),
+        );
+        h.insert(
+            "acked",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        assert!(control.capabilities().is_some());
+        let mut res = self.0.lock().unwrap();
+        res.push(control.set_program("ecn", None).map(|_| ()));
+        res.push(control.set_program("acked", None).map(|_| ()));
+        NopAlg
+    }
+}
+
+#[test]
+fn test_capabilities_checked() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    // everything but Ack.ecn_bytes
+    let caps = serialize::serialize(&serialize::capabilities::Msg {
+        ops: 0x7fff,
+        primitives: 0x7fff & !(1 << 2),
+        max_instrs: 100,
+    })
+    .expect("serialize capabilities");
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![create, caps]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let results = Arc::new(std::sync::Mutex::new(vec![]));
+    crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(EcnAlg(results.clone()))
+        .pumped()
+        .run(|pump| pump.dispatch_ready())
+        .expect("pumped run");
+
+    let results = results.lock().unwrap();
+    assert_eq!(results.len(), 2);
+    let err = results[0]
+        .clone()
+        .expect_err("the datapath does not measure ECN");
+    assert!(err.0.contains("Ack.ecn_bytes"), "{}", err.0);
+    assert_eq!(results[1], Ok(()));
+}
+
+// Runs a program on each new flow and sets its cwnd.
+struct CwndAlg;
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for CwndAlg {
+    type Flow = CwndAlg;
+
+    fn name() -> &'static str {
+        "cwnd"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "cwnd",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        let sc = control.set_program("cwnd", None).expect("set program");
+        control
+            .update_field(&sc, &[("Cwnd", 14480)])
+            .expect("update cwnd");
+        CwndAlg
+    }
+}
+
+impl crate::Flow for CwndAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_abstract_unix_flow() {
+    use crate::ipc::{unix, Blocking, IpcRecv, IpcSend};
+
+    let ccp = unix::Socket::<Blocking>::new_abstract("portus-test-abstract-ccp").expect("bind");
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock: ccp })
+        .default_alg(CwndAlg)
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let dp = unix::Socket::<Blocking>::new_abstract("portus-test-abstract-dp").expect("bind");
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 7,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    dp.send(
+        &create,
+        &unix::UnixAddr::Abstract(String::from("portus-test-abstract-ccp")),
+    )
+    .expect("send create");
+
+    // the flow's replies reach the abstract address the create came from: the program install,
+    // then the program change and field update for sid 7
+    let mut buf = [0u8; 1024];
+    let mut got = vec![];
+    while got.len() < 3 {
+        let (len, from) = dp.recv(&mut buf).expect("recv");
+        if len == 0 {
+            panic!("timed out after receiving message types {:?}", got);
+        }
+
+        assert_eq!(
+            from,
+            unix::UnixAddr::Abstract(String::from("portus-test-abstract-ccp"))
+        );
+        got.push(buf[0]);
+        if buf[0] != 2 {
+            assert_eq!(&buf[4..8], &7u32.to_le_bytes());
+        }
+    }
+    assert_eq!(got, vec![2, 4, 3]);
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+}
+
+// Sets each flow's cwnd according to which datapath it belongs to.
+struct PeerCwndAlg;
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for PeerCwndAlg {
+    type Flow = CwndAlg;
+
+    fn name() -> &'static str {
+        "peer-cwnd"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        <CwndAlg as crate::CongAlg<I>>::datapath_programs(&CwndAlg)
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        let cwnd = if info.peer.contains("portus-test-multipeer-a") {
+            10_000
+        } else {
+            20_000
+        };
+        let sc = control.set_program("cwnd", None).expect("set program");
+        control
+            .update_field(&sc, &[("Cwnd", cwnd)])
+            .expect("update cwnd");
+        CwndAlg
+    }
+}
+
+#[test]
+fn test_multiple_datapaths() {
+    use crate::ipc::{unix, Blocking, IpcRecv, IpcSend};
+    use std::path::PathBuf;
+
+    let ccp = unix::Socket::<Blocking>::new("portus-test-multipeer-ccp").expect("bind");
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock: ccp })
+        .default_alg(PeerCwndAlg)
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    // both datapaths create a flow with the same sock_id
+    let dps: Vec<_> = ["portus-test-multipeer-a", "portus-test-multipeer-b"]
+        .iter()
+        .map(|name| unix::Socket::<Blocking>::new(name).expect("bind"))
+        .collect();
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 7,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let to_ccp = unix::UnixAddr::Path(PathBuf::from("portus-test-multipeer-ccp"));
+    for dp in &dps {
+        dp.send(&create, &to_ccp).expect("send create");
+    }
+
+    // each gets its own programs, and the cwnd its flow chose for it
+    let mut buf = [0u8; 1024];
+    for (dp, cwnd) in dps.iter().zip(&[10_000u64, 20_000]) {
+        let mut got = vec![];
+        while got.len() < 3 {
+            let (len, _) = dp.recv(&mut buf).expect("recv");
+            if len == 0 {
+                panic!("timed out after receiving message types {:?}", got);
+            }
+
+            got.push(buf[0]);
+            if buf[0] == 3 {
+                assert_eq!(&buf[4..8], &7u32.to_le_bytes());
+                assert_eq!(&buf[17..25], &cwnd.to_le_bytes());
+            }
+        }
+        assert_eq!(got, vec![2, 4, 3]);
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+}
+
+// Records each flow's initial cwnd along with the cwnd it reports.
+struct CwndReportAlg(Arc<std::sync::Mutex<Vec<(u32, u64)>>>);
+
+struct CwndReportFlow {
+    sc: crate::lang::Scope,
+    init_cwnd: u32,
+    reports: Arc<std::sync::Mutex<Vec<(u32, u64)>>>,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for CwndReportAlg {
+    type Flow = CwndReportFlow;
+
+    fn name() -> &'static str {
+        "cwnd-report"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "cwnd-report",
+            "(def (Report (volatile cwnd 0))) (when true (:= Report.cwnd Cwnd) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        CwndReportFlow {
+            sc: control
+                .set_program("cwnd-report", None)
+                .expect("set program"),
+            init_cwnd: info.init_cwnd,
+            reports: self.0.clone(),
+        }
+    }
+}
+
+impl crate::Flow for CwndReportFlow {
+    fn on_report(&mut self, _sock_id: u64, m: crate::Report) {
+        let cwnd = m.get_field("Report.cwnd", &self.sc).expect("get cwnd");
+        self.reports.lock().unwrap().push((self.init_cwnd, cwnd));
+    }
+}
+
+// Create flow 1 with `init_cwnd`, wait for CCP to set its program, and report `init_cwnd` back.
+fn fake_unix_datapath(bind_to: &str, ccp: &str, init_cwnd: u32) {
+    use crate::ipc::{unix, Blocking};
+
+    let sk = unix::Socket::<Blocking>::new(bind_to).expect("bind");
+    fake_datapath(
+        &sk,
+        &unix::UnixAddr::Path(std::path::PathBuf::from(ccp)),
+        init_cwnd,
+    );
+}
+
+// Create a flow on `ccp`, wait for its program, and send one report of `init_cwnd`.
+fn fake_datapath<S: ipc::Ipc>(sk: &S, ccp: &S::Addr, init_cwnd: u32) {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    sk.send(&create, ccp).expect("send create");
+
+    // the change program message carries the program uid after the header
+    let mut buf = [0u8; 1024];
+    let program_uid = loop {
+        let (len, _) = sk.recv(&mut buf).expect("recv");
+        assert!(len > 0, "timed out waiting for the program");
+        if buf[0] == 4 {
+            break u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        }
+    };
+
+    let report = serialize::serialize(&serialize::measure::Msg {
+        sid: 1,
+        program_uid,
+        num_fields: 1,
+        fields: vec![u64::from(init_cwnd)],
+        types: None,
+        timestamp: None,
+        strings: vec![],
+    })
+    .expect("serialize report");
+    sk.send(&report, ccp).expect("send report");
+}
+
+#[test]
+fn test_multi_backend_isolation() {
+    use crate::ipc::{multi::Multi, unix, Blocking};
+
+    let sock = Multi::new(vec![
+        unix::Socket::<Blocking>::new("portus-test-multi-1").expect("bind"),
+        unix::Socket::<Blocking>::new("portus-test-multi-2").expect("bind"),
+    ]);
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CwndReportAlg(reports.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    // both datapaths create a flow with sid 1
+    let dp1 =
+        thread::spawn(|| fake_unix_datapath("portus-test-multi-dp1", "portus-test-multi-1", 10));
+    let dp2 =
+        thread::spawn(|| fake_unix_datapath("portus-test-multi-dp2", "portus-test-multi-2", 20));
+    dp1.join().expect("join first datapath");
+    dp2.join().expect("join second datapath");
+
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    while reports.lock().unwrap().len() < 2 && std::time::Instant::now() < deadline {
+        thread::sleep(std::time::Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    let mut reports = reports.lock().unwrap().clone();
+    reports.sort_unstable();
+    assert_eq!(reports, vec![(10, 10), (20, 20)]);
+}
+
+// Records when each report arrived.
+struct ReportTimeAlg(Arc<std::sync::Mutex<Vec<std::time::SystemTime>>>);
+
+struct ReportTimeFlow(Arc<std::sync::Mutex<Vec<std::time::SystemTime>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for ReportTimeAlg {
+    type Flow = ReportTimeFlow;
+
+    fn name() -> &'static str {
+        "report-time"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "cwnd-report",
+            "(def (Report (volatile cwnd 0))) (when true (:= Report.cwnd Cwnd) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        control
+            .set_program("cwnd-report", None)
+            .expect("set program");
+        ReportTimeFlow(self.0.clone())
+    }
+}
+
+impl crate::Flow for ReportTimeFlow {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {
+        panic!("on_report_at should be called instead");
+    }
+
+    fn on_report_at(&mut self, _sock_id: u64, _m: crate::Report, at: std::time::SystemTime) {
+        self.0.lock().unwrap().push(at);
+    }
+}
+
+// Run CCP on `sock`, and check that the report `datapath` sends arrives with a time between
+// when it was sent and when it was handled.
+fn check_report_receive_time<I: ipc::Ipc + 'static>(sock: I, datapath: impl FnOnce())
+where
+    I::Addr: Send,
+{
+    use std::time::{Duration, Instant, SystemTime};
+
+    let times = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(ReportTimeAlg(times.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let before = SystemTime::now();
+    datapath();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while times.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    let times = times.lock().unwrap().clone();
+    assert_eq!(times.len(), 1);
+    assert!(before <= times[0] && times[0] <= SystemTime::now());
+}
+
+#[test]
+fn test_report_receive_time() {
+    let sock = ipc::unix::Socket::<ipc::Blocking>::new("portus-test-report-time").expect("bind");
+    check_report_receive_time(sock, || {
+        fake_unix_datapath("portus-test-report-time-dp", "portus-test-report-time", 10)
+    });
+}
+
+// The same as `test_report_receive_time`, over an in-process socket pair.
+#[test]
+fn test_chan_report_receive_time() {
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    check_report_receive_time(sock, || fake_datapath(&dp, &(), 10));
+}
+
+// Run CCP, announcing its version, against a datapath which speaks `theirs` and then creates a
+// flow which reports once, or if CCP cannot understand it, tries to. Returns the reports CCP got,
+// and how its execution loop ended.
+fn run_with_datapath_version(
+    theirs: serialize::version::Msg,
+) -> (Vec<(u32, u64)>, crate::Result<()>) {
+    use crate::ipc::{IpcRecv, IpcSend};
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CwndReportAlg(reports.clone()))
+        .with_version_announce()
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let ver = serialize::serialize(&theirs).expect("serialize version");
+    dp.send(&ver, &()).expect("send version");
+    let mut buf = [0u8; 64];
+    let (len, _) = dp.recv(&mut buf).expect("recv version");
+    assert_eq!(
+        serialize::Msg::from_buf(&buf[..len])
+            .expect("parse version")
+            .0,
+        serialize::Msg::Ver(serialize::version::Msg::current())
+    );
+
+    if theirs.major == serialize::version::MAJOR {
+        fake_datapath(&dp, &(), 10);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while reports.lock().unwrap().is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    } else {
+        let create = serialize::serialize(&serialize::create::Msg {
+            sid: 1,
+            init_cwnd: 10,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create");
+        dp.send(&create, &()).expect("send create");
+        // CCP ignores it, rather than sending the program
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert_eq!(len, 0, "got message type {}", buf[0]);
+    }
+
+    handle.kill();
+    let res = handle.wait();
+    let reports = reports.lock().unwrap().clone();
+    (reports, res)
+}
+
+#[test]
+fn test_datapath_version_match() {
+    let (reports, res) = run_with_datapath_version(serialize::version::Msg::current());
+    assert_eq!(res, Ok(()));
+    assert_eq!(reports, vec![(10, 10)]);
+}
+
+#[test]
+fn test_datapath_version_minor_mismatch() {
+    let (reports, res) = run_with_datapath_version(serialize::version::Msg {
+        minor: serialize::version::MINOR + 1,
+        ..serialize::version::Msg::current()
+    });
+    assert_eq!(res, Ok(()));
+    assert_eq!(reports, vec![(10, 10)]);
+}
+
+#[test]
+fn test_datapath_version_major_mismatch() {
+    let (reports, res) = run_with_datapath_version(serialize::version::Msg {
+        major: serialize::version::MAJOR + 1,
+        minor: 0,
+    });
+    assert_eq!(res, Ok(()));
+    assert!(reports.is_empty());
+}
+
+// Records the datapath of each flow it creates, and of each flow it closes.
+struct PeerEventsAlg(Arc<std::sync::Mutex<Vec<(&'static str, String)>>>);
+
+struct PeerEventsFlow(Arc<std::sync::Mutex<Vec<(&'static str, String)>>>, String);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for PeerEventsAlg {
+    type Flow = PeerEventsFlow;
+
+    fn name() -> &'static str {
+        "peer-events"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        self.0.lock().unwrap().push(("create", info.peer.clone()));
+        PeerEventsFlow(self.0.clone(), info.peer)
+    }
+}
+
+impl crate::Flow for PeerEventsFlow {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+
+    fn close(&mut self) {
+        self.0.lock().unwrap().push(("close", self.1.clone()));
+    }
+}
+
+// A datapath which turns out to speak an incompatible protocol version has its flows closed and
+// its messages ignored, while CCP keeps serving the others.
+#[test]
+fn test_datapath_version_major_mismatch_rejects_only_that_datapath() {
+    use crate::ipc::{unix, Blocking, IpcSend};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant};
+
+    let ccp = unix::Socket::<Blocking>::new("portus-test-version-ccp").expect("bind");
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock: ccp })
+        .default_alg(PeerEventsAlg(events.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 10,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create")
+    };
+    let ver = serialize::serialize(&serialize::version::Msg {
+        major: serialize::version::MAJOR + 1,
+        minor: 0,
+    })
+    .expect("serialize version");
+    let to_ccp = unix::UnixAddr::Path(PathBuf::from("portus-test-version-ccp"));
+    let old = unix::Socket::<Blocking>::new("portus-test-version-old").expect("bind");
+    let new = unix::Socket::<Blocking>::new("portus-test-version-new").expect("bind");
+    old.send(&create(1), &to_ccp).expect("send create");
+    old.send(&ver, &to_ccp).expect("send version");
+    old.send(&create(2), &to_ccp).expect("send create");
+    new.send(&create(1), &to_ccp).expect("send create");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let events = loop {
+        let events = events.lock().unwrap().clone();
+        if events.len() >= 3 || Instant::now() > deadline {
+            break events;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+    };
+    let events: Vec<_> = events
+        .iter()
+        .map(|(what, peer)| (*what, peer.contains("portus-test-version-old")))
+        .collect();
+    assert_eq!(
+        events,
+        vec![("create", true), ("close", true), ("create", false)]
+    );
+
+    handle.kill();
+    assert_eq!(handle.wait(), Ok(()));
+}
+
+// An install message from the datapath's side is valid, if useless, input: CCP ignores it and
+// keeps running.
+#[test]
+fn test_install_from_datapath_ignored() {
+    use crate::ipc::IpcSend;
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CwndReportAlg(reports.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let (bin, _) = crate::lang::compile(
+        b"(def (Report (volatile foo 0))) (when true (bind Report.foo 4))",
+        &[],
+    )
+    .expect("compile");
+    let install = serialize::serialize(&serialize::install::Msg {
+        sid: 1,
+        program_uid: 7,
+        num_events: bin.events.len() as u32,
+        num_instrs: bin.instrs.len() as u32,
+        instrs: bin,
+        names: None,
+    })
+    .expect("serialize install");
+    dp.send(&install, &()).expect("send install");
+
+    fake_datapath(&dp, &(), 10);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while reports.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    assert_eq!(handle.wait(), Ok(()));
+    assert_eq!(*reports.lock().unwrap(), vec![(10, 10)]);
+}
+
+// Run CCP against a datapath which speaks `theirs` and is then ready, and return the install
+// message CCP sends it.
+fn install_for_datapath_version(theirs: serialize::version::Msg) -> Vec<u8> {
+    use crate::ipc::{IpcRecv, IpcSend};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(CwndReportAlg(Arc::new(std::sync::Mutex::new(vec![]))))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let ver = serialize::serialize(&theirs).expect("serialize version");
+    dp.send(&ver, &()).expect("send version");
+    let ready = serialize::serialize(&serialize::ready::Msg { id: 0 }).expect("serialize ready");
+    dp.send(&ready, &()).expect("send ready");
+    let mut buf = [0u8; 1024];
+    let (len, _) = dp.recv(&mut buf).expect("recv install");
+    handle.kill();
+    handle.wait().expect("ccp");
+    buf[..len].to_vec()
+}
+
+#[test]
+fn test_compact_install_negotiated() {
+    let compact = install_for_datapath_version(serialize::version::Msg::current());
+    let fixed = install_for_datapath_version(serialize::version::Msg {
+        minor: serialize::install::VARINT_MINOR - 1,
+        ..serialize::version::Msg::current()
+    });
+
+    // the instruction count follows the program uid and the event count
+    let num_instrs = |buf: &[u8]| u32::from_le_bytes([buf[16], buf[17], buf[18], buf[19]]);
+    assert_ne!(num_instrs(&compact) & serialize::install::VARINT, 0);
+    assert_eq!(num_instrs(&fixed) & serialize::install::VARINT, 0);
+    assert!(compact.len() < fixed.len());
+    match (
+        serialize::Msg::from_buf(&compact),
+        serialize::Msg::from_buf(&fixed),
+    ) {
+        (Ok((serialize::Msg::Ins(c), _)), Ok((serialize::Msg::Ins(f), _))) => {
+            assert_eq!(c.instrs, f.instrs)
+        }
+        msgs => panic!("expected install messages, got {:?}", msgs),
+    }
+}
+
+#[test]
+fn test_named_install_negotiated() {
+    let parse = |buf: &[u8]| match serialize::Msg::from_buf(buf) {
+        Ok((serialize::Msg::Ins(m), _)) => m,
+        msg => panic!("expected an install message, got {:?}", msg),
+    };
+
+    let named = parse(&install_for_datapath_version(
+        serialize::version::Msg::current(),
+    ));
+    assert_eq!(named.names, Some(vec![String::from("Report.cwnd")]));
+    let sc = named.scope().expect("scope");
+    assert_eq!(sc.program_uid, named.program_uid);
+    assert_eq!(
+        sc.get("Report.cwnd"),
+        Some(&crate::lang::Reg::Report(
+            0,
+            crate::lang::Type::Num(None),
+            true
+        ))
+    );
+
+    let unnamed = parse(&install_for_datapath_version(serialize::version::Msg {
+        minor: serialize::install::NAMED_MINOR - 1,
+        ..serialize::version::Msg::current()
+    }));
+    assert_eq!(unnamed.names, None);
+    assert!(unnamed.scope().is_none());
+}
+
+// Records the errors the datapath reports for its flows.
+struct ErrorAlg(Arc<std::sync::Mutex<Vec<(u64, Code, String)>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for ErrorAlg {
+    type Flow = ErrorAlg;
+
+    fn name() -> &'static str {
+        "error"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        ErrorAlg(self.0.clone())
+    }
+}
+
+impl crate::Flow for ErrorAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+
+    fn on_error(&mut self, sock_id: u64, code: Code, msg: &str) {
+        self.0.lock().unwrap().push((sock_id, code, msg.to_owned()));
+    }
+}
+
+#[test]
+fn test_datapath_error() {
+    use crate::ipc::IpcSend;
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let errors = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(ErrorAlg(errors.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 5,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    dp.send(&create, &()).expect("send create");
+    // one for a flow CCP does not know, which is only logged, and one for the flow
+    for sid in &[9, 5] {
+        let err = serialize::serialize(&serialize::error::Msg {
+            sid: *sid,
+            code: Code::ProgramTooLarge,
+            msg: String::from("too many instructions"),
+        })
+        .expect("serialize error");
+        dp.send(&err, &()).expect("send error");
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while errors.lock().unwrap().is_empty() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(
+        *errors.lock().unwrap(),
+        vec![(
+            5,
+            Code::ProgramTooLarge,
+            String::from("too many instructions")
+        )]
+    );
+}
+
+// The congestion control each flow asked the datapath for, by sid.
+type Requested = Arc<std::sync::Mutex<Vec<(u64, Option<String>)>>>;
+
+// Records the congestion control each flow asked the datapath for.
+struct RequestedAlg(Requested);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for RequestedAlg {
+    type Flow = RequestedAlg;
+
+    fn name() -> &'static str {
+        "requested"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        self.0
+            .lock()
+            .unwrap()
+            .push((info.sock_id, info.requested_alg));
+        RequestedAlg(self.0.clone())
+    }
+}
+
+impl crate::Flow for RequestedAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+}
+
+#[test]
+fn test_requested_alg() {
+    use crate::ipc::IpcSend;
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let flows = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(RequestedAlg(flows.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    for (sid, alg) in [(1, Some("cubic")), (2, None)] {
+        let create = serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: alg.map(String::from),
+        })
+        .expect("serialize create");
+        dp.send(&create, &()).expect("send create");
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while flows.lock().unwrap().len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(
+        *flows.lock().unwrap(),
+        vec![(1, Some(String::from("cubic"))), (2, None)]
+    );
+}
+
+// Records when datapaths become ready and flows are created, and sets a default program on each
+// datapath as it becomes ready.
+struct ReadyAlg(Arc<std::sync::Mutex<Vec<&'static str>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for ReadyAlg {
+    type Flow = NopAlg;
+
+    fn name() -> &'static str {
+        "ready"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "default",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        self.0.lock().unwrap().push("create");
+        NopAlg
+    }
+
+    fn datapath_ready(&self, mut control: crate::Datapath<I>) {
+        use crate::DatapathTrait;
+        self.0.lock().unwrap().push("ready");
+        control.set_program("default", None).expect("set program");
+    }
+}
+
+#[test]
+fn test_datapath_ready() {
+    use crate::ipc::{IpcRecv, IpcSend};
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(ReadyAlg(events.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let ready = serialize::serialize(&serialize::ready::Msg { id: 0 }).expect("serialize ready");
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    dp.send(&ready, &()).expect("send ready");
+    dp.send(&create, &()).expect("send create");
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while events.lock().unwrap().len() < 2 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(*events.lock().unwrap(), vec!["ready", "create"]);
+
+    // the program is installed, and then set for no flow in particular
+    let mut buf = [0u8; 1024];
+    let mut got = vec![];
+    // ends once CCP's side of the channel is gone
+    while let Ok((len, _)) = dp.recv(&mut buf) {
+        if len == 0 {
+            break;
+        }
+        got.push((buf[0], u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]])));
+    }
+    assert_eq!(got, vec![(2, 0), (4, 0)]);
+}
+
+// Sets a program on each new flow, and records the datapath's acknowledgements of it.
+struct AckAlg(Arc<std::sync::Mutex<Vec<(u64, u32, bool)>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for AckAlg {
+    type Flow = AckAlg;
+
+    fn name() -> &'static str {
+        "ack"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "acked",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        control.set_program("acked", None).expect("set program");
+        AckAlg(self.0.clone())
+    }
+}
+
+impl crate::Flow for AckAlg {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {}
+
+    fn on_program_installed(&mut self, sock_id: u64, program_uid: u32, ok: bool) {
+        self.0.lock().unwrap().push((sock_id, program_uid, ok));
+    }
+}
+
+#[test]
+fn test_program_acks() {
+    use crate::ipc::{IpcRecv, IpcSend};
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let acks = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(AckAlg(acks.clone()))
+        .with_tick(Duration::from_millis(10))
+        .with_program_ack_timeout(Duration::from_millis(200))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    // the datapath acknowledges flow 1's program, fails flow 2's, and ignores flow 3's
+    let mut buf = [0u8; 1024];
+    for sid in 1..=3 {
+        let create = serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create");
+        dp.send(&create, &()).expect("send create");
+
+        let program_uid = loop {
+            let (len, _) = dp.recv(&mut buf).expect("recv");
+            assert!(len > 0, "timed out waiting for the program");
+            if buf[0] == 4 {
+                break u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+            }
+        };
+
+        if sid < 3 {
+            let ack = serialize::serialize(&serialize::install_ack::Msg {
+                sid,
+                program_uid,
+                status: if sid == 1 {
+                    Code::Ok
+                } else {
+                    Code::UnknownProgram
+                },
+            })
+            .expect("serialize ack");
+            dp.send(&ack, &()).expect("send ack");
+        }
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while acks.lock().unwrap().len() < 3 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+    // the acknowledged switches do not time out as well
+    thread::sleep(Duration::from_millis(300));
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    let acks = acks.lock().unwrap().clone();
+    let results: Vec<_> = acks.iter().map(|&(sid, _, ok)| (sid, ok)).collect();
+    assert_eq!(results, vec![(1, true), (2, false), (3, false)]);
+    assert!(acks.iter().all(|&(_, uid, _)| uid == acks[0].1));
+}
+
+// Starts each flow in slow start, and switches it to congestion avoidance once a report says it
+// lost packets, reading each report with the scope of the program it switched to last.
+struct PhasesAlg(Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>);
+
+struct PhasesFlow<I: ipc::Ipc> {
+    control: crate::Datapath<I>,
+    sc: crate::lang::Scope,
+    phase: &'static str,
+    reports: Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for PhasesAlg {
+    type Flow = PhasesFlow<I>;
+
+    fn name() -> &'static str {
+        "phases"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "phases",
+            "(program slow_start
+                (def (Report (volatile acked 0) (volatile lost 0)))
+                (when true
+                    (:= Report.acked (+ Report.acked Ack.bytes_acked))
+                    (:= Report.lost (+ Report.lost Ack.lost_pkts_sample))
+                    (report)
+                )
+            )
+            (program cong_avoid
+                (def (Report (volatile rtt 0)))
+                (when true (:= Report.rtt Flow.rtt_sample_us) (report))
+            )"
+            .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        let sc = control
+            .set_program("phases.slow_start", None)
+            .expect("set program");
+        PhasesFlow {
+            control,
+            sc,
+            phase: "slow_start",
+            reports: self.0.clone(),
+        }
+    }
+}
+
+impl<I: ipc::Ipc> crate::Flow for PhasesFlow<I> {
+    fn on_report(&mut self, _sock_id: u64, m: crate::Report) {
+        use crate::DatapathTrait;
+        let field = match self.phase {
+            "slow_start" => "Report.acked",
+            _ => "Report.rtt",
+        };
+        match m.get_field(field, &self.sc) {
+            Ok(val) => self.reports.lock().unwrap().push((self.phase, val)),
+            Err(e) => {
+                assert_eq!(e, crate::Error::from(crate::StaleProgramError));
+                self.reports.lock().unwrap().push(("stale", 0));
+                return;
+            }
+        }
+
+        if self.phase == "slow_start" && m.get_field("Report.lost", &self.sc) != Ok(0) {
+            self.sc = self
+                .control
+                .set_program("phases.cong_avoid", None)
+                .expect("set program");
+            self.phase = "cong_avoid";
+        }
+    }
+}
+
+#[test]
+fn test_switch_named_programs() {
+    use crate::ipc::{IpcRecv, IpcSend};
+    use std::time::{Duration, Instant};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let reports = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(PhasesAlg(reports.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    dp.send(&create, &()).expect("send create");
+
+    // the programs the datapath is told to install, and then the one the flow switches to
+    let mut buf = [0u8; 1024];
+    let mut installed = vec![];
+    let mut next_program = |installed: &mut Vec<u32>| loop {
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert!(len > 0, "timed out waiting for the program");
+        let uid = u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        match buf[0] {
+            2 => installed.push(uid),
+            4 => break uid,
+            _ => (),
+        }
+    };
+    let measure = |program_uid: u32, fields: Vec<u64>| {
+        let m = serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid,
+            num_fields: fields.len() as u8,
+            fields,
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize measure");
+        dp.send(&m, &()).expect("send measure");
+    };
+
+    let slow_start = next_program(&mut installed);
+    measure(slow_start, vec![1448, 0]);
+    measure(slow_start, vec![2896, 1]);
+    let cong_avoid = next_program(&mut installed);
+    assert_ne!(slow_start, cong_avoid);
+    let mut both = vec![slow_start, cong_avoid];
+    both.sort_unstable();
+    installed.sort_unstable();
+    assert_eq!(installed, both);
+
+    // a report slow start sent before the datapath switched is not read as congestion
+    // avoidance's
+    measure(slow_start, vec![4344, 0]);
+    measure(cong_avoid, vec![20_000]);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while reports.lock().unwrap().len() < 4 && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(10));
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(
+        *reports.lock().unwrap(),
+        vec![
+            ("slow_start", 1448),
+            ("slow_start", 2896),
+            ("stale", 0),
+            ("cong_avoid", 20_000),
+        ]
+    );
+}
+
+// Sets a program on each new flow, and then uninstalls it, once by its scope and once as
+// whichever program is running.
+struct UninstallAlg;
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for UninstallAlg {
+    type Flow = NopAlg;
+
+    fn name() -> &'static str {
+        "uninstall"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "acked",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        let sc = control.set_program("acked", None).expect("set program");
+        control
+            .uninstall_program(Some(&sc))
+            .expect("uninstall program");
+        let mut batch = control.batch();
+        batch.uninstall_program(None).expect("batch uninstall");
+        control.send_batch(batch).expect("send batch");
+        NopAlg
+    }
+}
+
+#[test]
+fn test_uninstall_program() {
+    use crate::ipc::{IpcRecv, IpcSend};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(UninstallAlg)
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 7,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    dp.send(&create, &()).expect("send create");
+
+    // the install, the switch to the program, and the two uninstalls
+    let mut buf = [0u8; 1024];
+    let mut msgs = vec![];
+    while msgs.len() < 4 {
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert!(len > 0, "timed out after {} messages", msgs.len());
+        msgs.push(buf[..len].to_vec());
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    let types: Vec<_> = msgs.iter().map(|m| m[0]).collect();
+    assert_eq!(types, vec![2, 4, 11, 11]);
+    let program_uid = u32::from_le_bytes([msgs[1][8], msgs[1][9], msgs[1][10], msgs[1][11]]);
+    for (m, uid) in msgs[2..].iter().zip(&[program_uid, 0]) {
+        match serialize::Msg::from_buf(m).expect("parse uninstall").0 {
+            serialize::Msg::Un(un) => assert_eq!(
+                un,
+                serialize::uninstall::Msg {
+                    sid: 7,
+                    program_uid: *uid,
+                }
+            ),
+            m => panic!("expected an uninstall message, got {:?}", m),
+        }
+    }
+}
+
+// Tears each flow down on its first report, and records the reports and closes each flow gets.
+struct TeardownAlg(Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>);
+
+struct TeardownFlow<I: ipc::Ipc> {
+    control: crate::Datapath<I>,
+    events: Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>,
+    sock_id: u64,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for TeardownAlg {
+    type Flow = TeardownFlow<I>;
+
+    fn name() -> &'static str {
+        "teardown"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        TeardownFlow {
+            control,
+            events: self.0.clone(),
+            sock_id: info.sock_id,
+        }
+    }
+}
+
+impl<I: ipc::Ipc> crate::Flow for TeardownFlow<I> {
+    fn on_report(&mut self, sock_id: u64, _m: crate::Report) {
+        self.events.lock().unwrap().push(("report", sock_id));
+        self.control.teardown(7).expect("teardown");
+    }
+
+    fn close(&mut self) {
+        self.events.lock().unwrap().push(("close", self.sock_id));
+    }
+}
+
+#[test]
+fn test_teardown_closes_flow() {
+    use crate::ipc::{IpcRecv, IpcSend};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(TeardownAlg(events.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let mut buf = [0u8; 1024];
+    let measure = |sid| {
+        let report = serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: 1,
+            fields: vec![42],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize measure");
+        dp.send(&report, &()).expect("send measure");
+    };
+
+    for sid in 1..=2 {
+        let create = serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create");
+        dp.send(&create, &()).expect("send create");
+    }
+
+    // flow 1 is torn down by its first report, so it does not get its second; flow 2's teardown
+    // shows that CCP got that far
+    measure(1);
+    measure(1);
+    measure(2);
+    let mut teardowns = vec![];
+    while teardowns.len() < 2 {
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert!(len > 0, "timed out after {} teardowns", teardowns.len());
+        match serialize::Msg::from_buf(&buf[..len])
+            .expect("parse teardown")
+            .0
+        {
+            serialize::Msg::Td(td) => teardowns.push(td),
+            m => panic!("expected a teardown message, got {:?}", m),
+        }
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(
+        teardowns,
+        vec![
+            serialize::teardown::Msg { sid: 1, reason: 7 },
+            serialize::teardown::Msg { sid: 2, reason: 7 },
+        ]
+    );
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![("report", 1), ("close", 1), ("report", 2), ("close", 2)]
+    );
+}
+
+// Flows whose 64-bit sock ids agree in their low 32 bits are still separate flows, and the
+// datapath gets each one's messages under its whole id.
+#[test]
+fn test_wide_sock_ids() {
+    use crate::ipc::{IpcRecv, IpcSend};
+
+    let (sock, dp) = ipc::chan::Socket::<ipc::Blocking>::pair();
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(TeardownAlg(events.clone()))
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let wide = 1 << 32 | 1;
+    for &sid in &[1, wide] {
+        let create = serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create");
+        dp.send(&create, &()).expect("send create");
+    }
+
+    let mut buf = [0u8; 1024];
+    let mut teardowns = vec![];
+    for &sid in &[wide, 1] {
+        let report = serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: 1,
+            fields: vec![42],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize measure");
+        dp.send(&report, &()).expect("send measure");
+
+        let (len, _) = dp.recv(&mut buf).expect("recv");
+        assert!(len > 0, "timed out after {} teardowns", teardowns.len());
+        match serialize::Msg::from_buf(&buf[..len])
+            .expect("parse teardown")
+            .0
+        {
+            serialize::Msg::Td(td) => teardowns.push(td),
+            m => panic!("expected a teardown message, got {:?}", m),
+        }
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    assert_eq!(
+        teardowns,
+        vec![
+            serialize::teardown::Msg {
+                sid: wide,
+                reason: 7
+            },
+            serialize::teardown::Msg { sid: 1, reason: 7 },
+        ]
+    );
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![
+            ("report", wide),
+            ("close", wide),
+            ("report", 1),
+            ("close", 1)
+        ]
+    );
+}
+
+// Reads are interrupted twice, and then the socket's fd is found to be bad.
+struct BadFdIpc(Arc<atomic::AtomicUsize>);
+
+impl ipc::IpcSend for BadFdIpc {
+    type Addr = ();
+
+    fn name() -> String {
+        String::from("badfd")
+    }
+
+    fn send(&self, _msg: &[u8], _to: &Self::Addr) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+impl ipc::IpcRecv for BadFdIpc {
+    fn recv(&self, _msg: &mut [u8]) -> crate::Result<(usize, Self::Addr)> {
+        match self.0.fetch_add(1, atomic::Ordering::SeqCst) {
+            // as Ipc implementations report EINTR
+            0 | 1 => Ok((0, ())),
+            _ => Err(crate::Error::from(nix::errno::Errno::EBADF)),
+        }
+    }
+
+    fn close(&mut self) -> crate::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn test_recv_error_reaches_caller() {
+    let calls = Arc::new(atomic::AtomicUsize::new(0));
+    let res = crate::RunBuilder::new(ipc::BackendBuilder {
+        sock: BadFdIpc(calls.clone()),
+    })
+    .default_alg(NopAlg)
+    .run();
+    let err = res.expect_err("run should fail");
+    assert!(err.0.contains("EBADF"), "{}", err.0);
+    assert_eq!(calls.load(atomic::Ordering::SeqCst), 3);
+}
+
+// Grows each flow's cwnd by one packet per report.
+struct GrowAlg;
+
+struct GrowFlow<I: ipc::Ipc> {
+    control: crate::Datapath<I>,
+    sc: crate::lang::Scope,
+    cwnd: u32,
+    mss: u32,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for GrowAlg {
+    type Flow = GrowFlow<I>;
+
+    fn name() -> &'static str {
+        "grow"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        let mut h = std::collections::HashMap::new();
+        h.insert(
+            "grow",
+            "(def (Report (volatile acked 0))) (when true (:= Report.acked Ack.bytes_acked) (report))"
+                .to_owned(),
+        );
+        h
+    }
+
+    fn new_flow(&self, mut control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        use crate::DatapathTrait;
+        let sc = control.set_program("grow", None).expect("set program");
+        GrowFlow {
+            control,
+            sc,
+            cwnd: info.init_cwnd,
+            mss: info.mss,
+        }
+    }
+}
+
+impl<I: ipc::Ipc> crate::Flow for GrowFlow<I> {
+    fn on_report(&mut self, _sock_id: u64, _m: crate::Report) {
+        use crate::DatapathTrait;
+        self.cwnd += self.mss;
+        self.control
+            .update_field(&self.sc, &[("Cwnd", self.cwnd)])
+            .expect("update cwnd");
+    }
+}
+
+// The field updates CCP sent in a recording.
+fn recorded_updates(path: &std::path::Path) -> Vec<Vec<u8>> {
+    use crate::ipc::record::{read_frames, Direction};
+
+    read_frames(path)
+        .expect("read recording")
+        .into_iter()
+        .filter(|f| f.dir == Direction::Sent && f.msg[0] == 3)
+        .map(|f| f.msg)
+        .collect()
+}
+
+#[test]
+fn test_record_replay() {
+    use crate::ipc::{chan, record, replay, Blocking};
+    use crossbeam::channel;
+
+    let recording = std::env::temp_dir().join("portus-test-record-replay-1");
+    let rerecording = std::env::temp_dir().join("portus-test-record-replay-2");
+
+    let (to_dp, from_ccp) = channel::unbounded();
+    let (to_ccp, from_dp) = channel::unbounded();
+    let sock = record::Socket::new(chan::Socket::<Blocking>::new(to_dp, from_dp), &recording)
+        .expect("record");
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(GrowAlg)
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    // create a flow, then send a report for each field update
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    to_ccp.send(create).expect("send create");
+    let timeout = std::time::Duration::from_secs(5);
+    let program_uid = loop {
+        let msg = from_ccp.recv_timeout(timeout).expect("wait for program");
+        if msg[0] == 4 {
+            break u32::from_le_bytes([msg[8], msg[9], msg[10], msg[11]]);
+        }
+    };
+
+    for acked in 1..=3 {
+        let report = serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid,
+            num_fields: 1,
+            fields: vec![acked * 1448],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize report");
+        to_ccp.send(report).expect("send report");
+        let msg = from_ccp.recv_timeout(timeout).expect("wait for update");
+        assert_eq!(msg[0], 3);
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+    let recorded = recorded_updates(&recording);
+    assert_eq!(recorded.len(), 3);
+
+    // replaying the session, while recording it again, makes the same updates
+    let sock = record::Socket::new(
+        replay::Socket::new(&recording, true).expect("replay"),
+        &rerecording,
+    )
+    .expect("record replay");
+    let res = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(GrowAlg)
+        .run();
+    assert_eq!(
+        res.expect_err("replay ends"),
+        crate::Error::from(crate::DatapathGoneError)
+    );
+
+    let replayed = recorded_updates(&rerecording);
+    std::fs::remove_file(&recording).unwrap_or(());
+    std::fs::remove_file(&rerecording).unwrap_or(());
+    assert_eq!(replayed, recorded);
+}
+
+#[test]
+fn test_receive_thread() {
+    use crate::ipc::{chan, queue::Overflow, Blocking};
+    use crossbeam::channel;
+
+    let (to_dp, from_ccp) = channel::unbounded();
+    let (to_ccp, from_dp) = channel::unbounded();
+    let sock = chan::Socket::<Blocking>::new(to_dp, from_dp);
+    let handle = crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(GrowAlg)
+        .with_receive_thread(16, Overflow::Block)
+        .spawn_thread()
+        .run()
+        .expect("spawn ccp");
+
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    to_ccp.send(create).expect("send create");
+    let timeout = std::time::Duration::from_secs(5);
+    let program_uid = loop {
+        let msg = from_ccp.recv_timeout(timeout).expect("wait for program");
+        if msg[0] == 4 {
+            break u32::from_le_bytes([msg[8], msg[9], msg[10], msg[11]]);
+        }
+    };
+
+    // the reports queue up while the flow handles them
+    for acked in 1..=5 {
+        let report = serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid,
+            num_fields: 1,
+            fields: vec![acked * 1448],
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize report");
+        to_ccp.send(report).expect("send report");
+    }
+
+    for _ in 0..5 {
+        let msg = from_ccp.recv_timeout(timeout).expect("wait for update");
+        assert_eq!(msg[0], 3);
+    }
+
+    handle.kill();
+    handle.wait().expect("ccp exits cleanly");
+}
+
+// A report of `fields`, sent by the datapath with `types`, for the program compiled into `sc`.
+fn typed_report(
+    sc: &crate::lang::Scope,
+    fields: &[(&str, u64)],
+    types: Option<Vec<serialize::measure::FieldType>>,
+) -> crate::Report {
+    let mut vals = vec![0; fields.len()];
+    for &(name, val) in fields {
+        match sc.get(name) {
+            Some(crate::lang::Reg::Report(idx, _, _)) => vals[*idx as usize] = val,
+            r => panic!("{} is not a report field: {:?}", name, r),
+        }
+    }
+
+    sent_report(serialize::measure::Msg {
+        sid: 1,
+        program_uid: sc.program_uid,
+        num_fields: vals.len() as u8,
+        fields: vals,
+        types,
+        timestamp: None,
+        strings: vec![],
+    })
+}
+
+// The report CCP makes of `m`, once the datapath sends it.
+fn sent_report(m: serialize::measure::Msg) -> crate::Report {
+    let buf = serialize::serialize(&m).expect("serialize measure");
+    match serialize::Msg::from_buf(&buf).expect("parse measure").0 {
+        serialize::Msg::Ms(m) => crate::Report {
+            program_uid: m.program_uid,
+            from: String::new(),
+            fields: m.fields,
+            types: m.types,
+            timestamp: m.timestamp,
+            strings: m.strings,
+        },
+        m => panic!("expected a measurement, got {:?}", m),
+    }
+}
+
+fn typed_report_scope() -> crate::lang::Scope {
+    let (_, sc) = crate::lang::compile(
+        b"(def (Report (volatile acked 0) (volatile delta 0) (volatile lost false)))
+        (when true
+            (:= Report.acked Ack.bytes_acked)
+            (:= Report.lost (> Ack.lost_pkts_sample 0))
+            (report)
+        )",
+        &[],
+    )
+    .expect("compile");
+    sc
+}
+
+#[test]
+fn test_report_typed_fields() {
+    use serialize::measure::FieldType;
+
+    let sc = typed_report_scope();
+    let mut types = vec![FieldType::Num; 3];
+    for (name, typ) in &[
+        ("Report.acked", FieldType::Num32),
+        ("Report.delta", FieldType::Int),
+        ("Report.lost", FieldType::Bool),
+    ] {
+        match sc.get(name) {
+            Some(crate::lang::Reg::Report(idx, _, _)) => types[*idx as usize] = *typ,
+            r => panic!("{} is not a report field: {:?}", name, r),
+        }
+    }
+
+    let m = typed_report(
+        &sc,
+        &[
+            ("Report.acked", 1448),
+            ("Report.delta", (-1500i64) as u64),
+            ("Report.lost", 1),
+        ],
+        Some(types),
+    );
+    assert_eq!(m.get_field("Report.acked", &sc), Ok(1448));
+    assert_eq!(m.get_field_i64("Report.acked", &sc), Ok(1448));
+    assert_eq!(m.get_field_i64("Report.delta", &sc), Ok(-1500));
+    assert_eq!(m.get_field_bool("Report.lost", &sc), Ok(true));
+
+    // untyped reports take the scope's word for it
+    let m = typed_report(
+        &sc,
+        &[
+            ("Report.acked", 1448),
+            ("Report.delta", 12),
+            ("Report.lost", 0),
+        ],
+        None,
+    );
+    assert_eq!(m.get_field_i64("Report.delta", &sc), Ok(12));
+    assert_eq!(m.get_field_bool("Report.lost", &sc), Ok(false));
+    assert_eq!(m.field_iter().count(), 3);
+    assert_eq!(m.field_iter().sum::<u64>(), 1448 + 12);
+}
+
+#[test]
+fn test_report_field_type_mismatch() {
+    use serialize::measure::FieldType;
+
+    let sc = typed_report_scope();
+    let mismatch = crate::Error::from(crate::FieldTypeError);
+    let m = typed_report(
+        &sc,
+        &[("Report.acked", 1), ("Report.delta", 1), ("Report.lost", 1)],
+        Some(vec![FieldType::Bool; 3]),
+    );
+    // a boolean in the report, but a number in the scope
+    assert_eq!(m.get_field_i64("Report.acked", &sc), Err(mismatch.clone()));
+    // a boolean in both
+    assert_eq!(m.get_field_bool("Report.lost", &sc), Ok(true));
+
+    let m = typed_report(
+        &sc,
+        &[
+            ("Report.acked", u64::MAX),
+            ("Report.delta", 1),
+            ("Report.lost", 1),
+        ],
+        None,
+    );
+    // a number in the scope, but asked for as a boolean
+    assert_eq!(m.get_field_bool("Report.acked", &sc), Err(mismatch.clone()));
+    // a boolean in the scope, but asked for as a number
+    assert_eq!(m.get_field_i64("Report.lost", &sc), Err(mismatch.clone()));
+    // too large to be signed
+    assert_eq!(m.get_field_i64("Report.acked", &sc), Err(mismatch));
+}
+
+#[test]
+fn test_report_str_fields() {
+    use serialize::measure::FieldType;
+
+    let (_, sc) = crate::lang::compile(
+        b"(def (Report (volatile acked 0) (ca_state \"\")))
+        (when true
+            (:= Report.acked Ack.bytes_acked)
+            (report)
+        )",
+        &[],
+    )
+    .expect("compile");
+    let mismatch = crate::Error::from(crate::FieldTypeError);
+    let m = sent_report(serialize::measure::Msg {
+        sid: 1,
+        program_uid: sc.program_uid,
+        num_fields: 2,
+        fields: vec![1448, 0],
+        types: Some(vec![FieldType::Num, FieldType::Str]),
+        timestamp: None,
+        strings: vec![String::from("Recovery")],
+    });
+    assert_eq!(m.get_field_str("Report.ca_state", &sc), Ok("Recovery"));
+    assert_eq!(m.get_field("Report.acked", &sc), Ok(1448));
+    assert_eq!(m.get_field_str("Report.acked", &sc), Err(mismatch.clone()));
+    assert_eq!(
+        m.get_field_i64("Report.ca_state", &sc),
+        Err(mismatch.clone())
+    );
+
+    // a datapath which does not send strings cannot fill in the field
+    let m = sent_report(serialize::measure::Msg::new(
+        1,
+        sc.program_uid,
+        vec![1448, 0],
+    ));
+    assert_eq!(m.get_field_str("Report.ca_state", &sc), Err(mismatch));
+    assert_eq!(m.get_field("Report.acked", &sc), Ok(1448));
+}
+
+// A delay-based algorithm's RTT change, once the RTT has fallen from 20ms to 10ms: subtracting
+// unsigned numbers wraps past zero, where subtracting them as signed ones does not.
+#[test]
+fn test_report_signed_delta() {
+    let compile = |sub: &str| {
+        let src = format!(
+            "(def (Report (volatile delta {})) (last_rtt 20000))
+            (when true
+                (:= Report.delta ({} Flow.rtt_sample_us last_rtt))
+                (:= last_rtt Flow.rtt_sample_us)
+                (report)
+            )",
+            if sub == "-" { "0" } else { "+0" },
+            sub
+        );
+        crate::lang::compile(src.as_bytes(), &[])
+            .expect("compile")
+            .1
+    };
+    // the datapath computes 10000 - 20000 in 64 bits
+    let delta = 10_000u64.wrapping_sub(20_000);
+
+    let sc = compile("-");
+    let m = typed_report(&sc, &[("Report.delta", delta)], None);
+    assert_eq!(m.get_field("Report.delta", &sc), Ok(delta));
+    assert_eq!(
+        m.get_field_i64("Report.delta", &sc),
+        Err(crate::Error::from(crate::FieldTypeError))
+    );
+
+    let sc = compile("ssub");
+    for types in &[None, Some(vec![serialize::measure::FieldType::Int])] {
+        let m = typed_report(&sc, &[("Report.delta", delta)], types.clone());
+        assert_eq!(m.get_field_i64("Report.delta", &sc), Ok(-10_000));
+        assert_eq!(m.get_field("Report.delta", &sc), Ok(delta));
+    }
+}
+
+// A comparison bound to a report field arrives as 0 or 1, which `get_field_bool` reads back.
+#[test]
+fn test_report_comparisons() {
+    let (_, sc) = crate::lang::compile(
+        b"(def (Report (volatile slow false) (volatile changed false)))
+        (when true
+            (:= Report.slow (>= Flow.rtt_sample_us 1000))
+            (:= Report.changed (!= Flow.rate_outgoing Flow.rate_incoming))
+            (report)
+        )",
+        &[],
+    )
+    .expect("compile");
+
+    let types = vec![serialize::measure::FieldType::Bool; 2];
+    for types in &[None, Some(types)] {
+        let m = typed_report(
+            &sc,
+            &[("Report.slow", 1), ("Report.changed", 0)],
+            types.clone(),
+        );
+        assert_eq!(m.get_field_bool("Report.slow", &sc), Ok(true));
+        assert_eq!(m.get_field_bool("Report.changed", &sc), Ok(false));
+        assert_eq!(m.get_field("Report.slow", &sc), Ok(1));
+        assert_eq!(
+            m.get_field_i64("Report.slow", &sc),
+            Err(crate::Error::from(crate::FieldTypeError))
+        );
+    }
+}
+
+// Records the datapath timestamp of each report.
+struct TimestampAlg(Arc<std::sync::Mutex<Vec<Option<u64>>>>);
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for TimestampAlg {
+    type Flow = TimestampAlg;
+
+    fn name() -> &'static str {
+        "timestamp"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, _info: crate::DatapathInfo) -> Self::Flow {
+        TimestampAlg(self.0.clone())
+    }
+}
+
+impl crate::Flow for TimestampAlg {
+    fn on_report(&mut self, _sock_id: u64, m: crate::Report) {
+        self.0.lock().unwrap().push(m.timestamp());
+    }
+}
+
+#[test]
+fn test_report_timestamp() {
+    let create = serialize::serialize(&serialize::create::Msg {
+        sid: 1,
+        init_cwnd: 14480,
+        mss: 1448,
+        src_ip: 0,
+        src_port: 4242,
+        dst_ip: 0,
+        dst_port: 4243,
+        cong_alg: None,
+    })
+    .expect("serialize create");
+    let measure = |timestamp| {
+        serialize::serialize(&serialize::measure::Msg {
+            sid: 1,
+            program_uid: 7,
+            num_fields: 1,
+            fields: vec![42],
+            types: None,
+            timestamp,
+            strings: vec![],
+        })
+        .expect("serialize measure")
+    };
+
+    // received last first
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![measure(None), measure(Some(123_456_789)), create]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let timestamps = Arc::new(std::sync::Mutex::new(vec![]));
+    crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(TimestampAlg(timestamps.clone()))
+        .pumped()
+        .run(|pump| pump.dispatch_ready())
+        .expect("pumped run");
+    assert_eq!(*timestamps.lock().unwrap(), vec![Some(123_456_789), None]);
+}
+
+// Records the reports and closes each flow gets.
+struct BatchAlg(Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>);
+
+struct BatchFlow {
+    events: Arc<std::sync::Mutex<Vec<(&'static str, u64)>>>,
+    sock_id: u64,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for BatchAlg {
+    type Flow = BatchFlow;
+
+    fn name() -> &'static str {
+        "batch"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        BatchFlow {
+            events: self.0.clone(),
+            sock_id: info.sock_id,
+        }
+    }
+}
+
+impl crate::Flow for BatchFlow {
+    fn on_report(&mut self, sock_id: u64, _m: crate::Report) {
+        self.events.lock().unwrap().push(("report", sock_id));
+    }
+
+    fn close(&mut self) {
+        self.events.lock().unwrap().push(("close", self.sock_id));
+    }
+}
+
+#[test]
+fn test_measure_batch() {
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create")
+    };
+    let measurement = |sid, fields: Vec<u64>| serialize::measure::Msg {
+        sid,
+        program_uid: 7,
+        num_fields: fields.len() as u8,
+        fields,
+        types: None,
+        timestamp: None,
+        strings: vec![],
+    };
+    // flow 3 is unknown, and flow 2's empty measurement closes it
+    let batch = serialize::serialize(&serialize::measure_batch::Msg {
+        measurements: vec![
+            measurement(1, vec![42]),
+            measurement(3, vec![42]),
+            measurement(2, vec![42]),
+            measurement(2, vec![]),
+            measurement(2, vec![42]),
+            measurement(1, vec![43]),
+        ],
+    })
+    .expect("serialize batch");
+
+    // received last first
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(vec![batch, create(2), create(1)]),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(BatchAlg(events.clone()))
+        .pumped()
+        .run(|pump| pump.dispatch_ready())
+        .expect("pumped run");
+    assert_eq!(
+        events.lock().unwrap()[..],
+        [("report", 1), ("report", 2), ("close", 2), ("report", 1)]
+    );
+}
+
+// Records the reports, and how each flow is closed.
+struct FinAlg(Arc<std::sync::Mutex<Vec<String>>>);
+
+struct FinFlow {
+    events: Arc<std::sync::Mutex<Vec<String>>>,
+    sock_id: u64,
+}
+
+impl<I: ipc::Ipc> crate::CongAlg<I> for FinAlg {
+    type Flow = FinFlow;
+
+    fn name() -> &'static str {
+        "fin"
+    }
+
+    fn datapath_programs(&self) -> std::collections::HashMap<&'static str, String> {
+        std::collections::HashMap::new()
+    }
+
+    fn new_flow(&self, _control: crate::Datapath<I>, info: crate::DatapathInfo) -> Self::Flow {
+        FinFlow {
+            events: self.0.clone(),
+            sock_id: info.sock_id,
+        }
+    }
+}
+
+impl crate::Flow for FinFlow {
+    fn on_report(&mut self, sock_id: u64, m: crate::Report) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("report {} fields={}", sock_id, m.fields.len()));
+    }
+
+    fn close(&mut self) {
+        self.events
+            .lock()
+            .unwrap()
+            .push(format!("close {}", self.sock_id));
+    }
+
+    fn close_with_counts(&mut self, bytes: u64, packets: u64) {
+        self.events.lock().unwrap().push(format!(
+            "fin {} bytes={} packets={}",
+            self.sock_id, bytes, packets
+        ));
+    }
+}
+
+// Run CCP against a datapath which says it speaks `version`, if given, creates flows 1 and 2, and
+// then sends `msgs`, last first, and return what the flows saw.
+fn fin_events(version: Option<serialize::version::Msg>, msgs: Vec<Vec<u8>>) -> Vec<String> {
+    let create = |sid| {
+        serialize::serialize(&serialize::create::Msg {
+            sid,
+            init_cwnd: 14480,
+            mss: 1448,
+            src_ip: 0,
+            src_port: 4242,
+            dst_ip: 0,
+            dst_port: 4243,
+            cong_alg: None,
+        })
+        .expect("serialize create")
+    };
+    let mut pending = msgs;
+    pending.push(create(2));
+    pending.push(create(1));
+    if let Some(v) = version {
+        pending.push(serialize::serialize(&v).expect("serialize version"));
+    }
+
+    let sock = HeartbeatIpc {
+        pending: std::sync::Mutex::new(pending),
+        echoes: atomic::AtomicUsize::new(0),
+    };
+    let events = Arc::new(std::sync::Mutex::new(vec![]));
+    crate::RunBuilder::new(ipc::BackendBuilder { sock })
+        .default_alg(FinAlg(events.clone()))
+        .pumped()
+        .run(|pump| pump.dispatch_ready())
+        .expect("pumped run");
+    let events = events.lock().unwrap();
+    events.clone()
+}
+
+#[test]
+fn test_fin_closes_flow() {
+    let measurement = |sid, fields: Vec<u64>| {
+        serialize::serialize(&serialize::measure::Msg {
+            sid,
+            program_uid: 7,
+            num_fields: fields.len() as u8,
+            fields,
+            types: None,
+            timestamp: None,
+            strings: vec![],
+        })
+        .expect("serialize measurement")
+    };
+    let fin = serialize::serialize(&serialize::fin::Msg {
+        sid: 1,
+        bytes: 1 << 40,
+        packets: 42,
+    })
+    .expect("serialize fin");
+    // received last first
+    let msgs = vec![
+        measurement(1, vec![43]),
+        fin,
+        measurement(1, vec![]),
+        measurement(1, vec![42]),
+    ];
+
+    // a datapath which sends fin messages can send empty measurements, and flow 1 gets no more
+    // once it has ended
+    assert_eq!(
+        fin_events(Some(serialize::version::Msg::current()), msgs.clone()),
+        vec![
+            "report 1 fields=1",
+            "report 1 fields=0",
+            "fin 1 bytes=1099511627776 packets=42",
+        ],
+    );
+
+    // from older datapaths, an empty measurement still ends the flow
+    for version in [
+        None,
+        Some(serialize::version::Msg {
+            minor: serialize::fin::FIN_MINOR - 1,
+            ..serialize::version::Msg::current()
+        }),
+    ] {
+        assert_eq!(
+            fin_events(version, msgs.clone()),
+            vec!["report 1 fields=1", "close 1"],
+        );
+    }
+}
+
+// Once its scratch buffers have grown to fit, a datapath sends updates without allocating.
+#[test]
+fn test_update_field_no_allocs() {
+    use crate::DatapathTrait;
+
+    let (bin, sc) = crate::lang::compile(
+        b"(def (Report (volatile acked 0)) (target 0))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (report)
+        )",
+        &[],
+    )
+    .expect("compile");
+    let mut programs = std::collections::HashMap::new();
+    programs.insert(
+        String::from("prog"),
+        crate::Program {
+            scope: sc.clone(),
+            bin,
+        },
+    );
+
+    let mut buf = [0u8; 1024];
+    let b = ipc::Backend::new(
+        ipc::test::RepeatIpc(vec![]),
+        Arc::new(atomic::AtomicBool::new(true)),
+        &mut buf[..],
+    );
+    let mut dp = crate::Datapath {
+        sock_id: 1,
+        sender: b.sender(()),
+        programs: Arc::new(programs),
+        priority: ipc::Priority::Urgent,
+        capabilities: None,
+        minor: None,
+        acks: None,
+        torn_down: None,
+        scratch: Default::default(),
+    };
+    dp.set_program("prog", Some(&[("target", 1)]))
+        .expect("set program");
+
+    let update = [("target", 2), ("Cwnd", 14480)];
+    dp.update_field(&sc, &update).expect("update field");
+    let before = ipc::test::allocs();
+    for _ in 0..1000 {
+        assert_eq!(
+            dp.update_field(&sc, &update).expect("update field"),
+            ipc::SendStatus::Sent
+        );
+    }
+    assert_eq!(ipc::test::allocs() - before, 0);
+}
+
+// `update_field_u32` sends values in 4 bytes to datapaths which speak a version with widths.
+#[test]
+fn test_update_field_u32() {
+    let (bin, sc) = crate::lang::compile(
+        b"(def (Report (volatile acked 0)) (target 0))
+        (when true
+            (:= Report.acked (+ Report.acked Ack.bytes_acked))
+            (report)
+        )",
+        &[],
+    )
+    .expect("compile");
+    let mut programs = std::collections::HashMap::new();
+    programs.insert(
+        String::from("prog"),
+        crate::Program {
+            scope: sc.clone(),
+            bin,
+        },
+    );
+    let programs = Arc::new(programs);
+
+    let update_for = |minor| {
+        let sock = ipc::test::FakeIpc::new();
+        let mut buf = [0u8; 1024];
+        let b = ipc::Backend::new(
+            sock.clone(),
+            Arc::new(atomic::AtomicBool::new(true)),
+            &mut buf[..],
+        );
+        let dp = crate::Datapath {
+            sock_id: 1,
+            sender: b.sender(()),
+            programs: programs.clone(),
+            priority: ipc::Priority::Urgent,
+            capabilities: None,
+            minor,
+            acks: None,
+            torn_down: None,
+            scratch: Default::default(),
+        };
+        dp.update_field_u32(&sc, &[("target", 2), ("Cwnd", 14480)])
+            .expect("update field");
+        let sent = sock.sent();
+        match serialize::Msg::from_buf(&sent) {
+            Ok((serialize::Msg::Uf(m), len)) => {
+                assert_eq!(len, sent.len());
+                m
+            }
+            msg => panic!("expected an update field message, got {:?}", msg),
+        }
+    };
+
+    let narrow = update_for(Some(serialize::version::MINOR));
+    assert_eq!(
+        narrow.fields.iter().map(|f| f.1).collect::<Vec<_>>(),
+        vec![2, 14480]
+    );
+    assert_eq!(
+        narrow.widths,
+        Some(vec![serialize::update_field::Width::U32; 2])
+    );
+
+    let wide = update_for(Some(serialize::update_field::WIDTHS_MINOR - 1));
+    assert_eq!(wide.fields, narrow.fields);
+    assert_eq!(wide.widths, None);
+    assert_eq!(update_for(None).widths, None);
+}
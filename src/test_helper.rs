@@ -6,7 +6,11 @@ pub struct TestMsg(pub String);
 use super::serialize;
 use std::io::prelude::*;
 impl serialize::AsRawMsg for TestMsg {
-    fn get_hdr(&self) -> (u8, u32, u32) {
+    fn typ() -> u8 {
+        0xff
+    }
+
+    fn get_hdr(&self) -> (u8, u32, u64) {
         (0xff, serialize::HDR_LENGTH + self.0.len() as u32, 0)
     }
 
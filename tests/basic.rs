@@ -40,7 +40,7 @@ impl IntegrationTest for TestBasicSerialize {
         dp.set_program("TestBasicSerialize", None).ok()
     }
 
-    fn check_test(&mut self, sc: &Scope, _t: Instant, _sock_id: u32, m: &Report) -> bool {
+    fn check_test(&mut self, sc: &Scope, _t: Instant, _sock_id: u64, m: &Report) -> bool {
         let acked = m
             .get_field("Report.acked", sc)
             .expect("expected acked field in returned measurement") as u32;
@@ -14,7 +14,7 @@ pub trait IntegrationTest: Sized {
     fn new() -> Self;
     fn datapath_programs() -> HashMap<&'static str, String>;
     fn install_test<D: DatapathTrait>(&self, dp: &mut D) -> Option<Scope>;
-    fn check_test(&mut self, sc: &Scope, t: std::time::Instant, sock_id: u32, m: &Report) -> bool;
+    fn check_test(&mut self, sc: &Scope, t: std::time::Instant, sock_id: u64, m: &Report) -> bool;
 }
 
 pub struct TestBaseConfig<T: IntegrationTest>(mpsc::Sender<Result<(), ()>>, PhantomData<T>);
@@ -53,7 +53,7 @@ pub struct TestBase<I: Ipc, T: IntegrationTest> {
 }
 
 impl<I: Ipc, T: IntegrationTest> Flow for TestBase<I, T> {
-    fn on_report(&mut self, sock_id: u32, m: Report) {
+    fn on_report(&mut self, sock_id: u64, m: Report) {
         let sc = self.sc.as_ref().unwrap();
         let done = self.t.check_test(sc, self.test_start, sock_id, &m);
         if done {
@@ -39,7 +39,7 @@ impl IntegrationTest for TestTiming {
         dp.set_program("TestTiming", None).ok()
     }
 
-    fn check_test(&mut self, sc: &Scope, t: Instant, _sock_id: u32, m: &Report) -> bool {
+    fn check_test(&mut self, sc: &Scope, t: Instant, _sock_id: u64, m: &Report) -> bool {
         let acked = m
             .get_field("Report.acked", sc)
             .expect("expected acked field in returned measurement") as u32;
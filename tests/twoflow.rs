@@ -41,7 +41,7 @@ impl IntegrationTest for TestTwoFlows {
         Some(sc)
     }
 
-    fn check_test(&mut self, sc: &Scope, _t: Instant, sock_id: u32, m: &Report) -> bool {
+    fn check_test(&mut self, sc: &Scope, _t: Instant, sock_id: u64, m: &Report) -> bool {
         unsafe {
             let num = m
                 .get_field("Report.value", sc)
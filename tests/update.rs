@@ -48,7 +48,7 @@ impl IntegrationTest for TestUpdateFields {
         &mut self,
         sc: &Scope,
         _t: std::time::Instant,
-        _sock_id: u32,
+        _sock_id: u64,
         m: &Report,
     ) -> bool {
         let cwnd =
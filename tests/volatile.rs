@@ -51,7 +51,7 @@ impl IntegrationTest for TestVolatileVars {
         &mut self,
         sc: &Scope,
         _t: std::time::Instant,
-        _sock_id: u32,
+        _sock_id: u64,
         m: &Report,
     ) -> bool {
         let foo = m.get_field("Report.foo", sc).expect("get Report.foo");